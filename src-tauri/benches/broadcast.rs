@@ -0,0 +1,111 @@
+// End-to-end broadcast latency: from EmbeddedServer::broadcast() until every
+// connected client has the change (`cargo bench --bench broadcast`)
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use envmesh::client::{SyncMessage, WireMessage};
+use envmesh::events::EventBus;
+use envmesh::server::EmbeddedServer;
+use futures_util::stream::SplitStream;
+use futures_util::StreamExt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+const CLIENTS: &[usize] = &[1, 10, 100, 500];
+
+/// The server admits a burst of 20 connections per address, so clients are
+/// spread over loopback addresses
+const CLIENTS_PER_ADDR: usize = 20;
+
+type Incoming = SplitStream<WebSocketStream<TcpStream>>;
+
+async fn connect(port: u16, n: usize) -> Incoming {
+    let source = Ipv4Addr::new(127, 0, 1 + (n / CLIENTS_PER_ADDR) as u8, 1);
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from((source, 0))).unwrap();
+    let stream = socket
+        .connect(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        .await
+        .unwrap();
+    let (ws, _) = client_async(format!("ws://127.0.0.1:{}", port), stream)
+        .await
+        .unwrap();
+    let (_, mut incoming) = ws.split();
+
+    // Every connection opens with the server's hello
+    next_frame(&mut incoming).await;
+    incoming
+}
+
+async fn next_frame(incoming: &mut Incoming) -> WireMessage {
+    loop {
+        match incoming.next().await {
+            Some(Ok(Message::Text(text))) => return serde_json::from_str(&text).unwrap(),
+            Some(Ok(_)) => continue,
+            other => panic!("connection ended: {:?}", other),
+        }
+    }
+}
+
+fn change(i: u64) -> SyncMessage {
+    SyncMessage::new(
+        "BENCH_KEY".to_string(),
+        format!("value-{}", i),
+        i as i64,
+        "bench-server".to_string(),
+        false,
+    )
+}
+
+fn bench_broadcast(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("broadcast");
+    group.sample_size(20);
+    group.measurement_time(Duration::from_secs(5));
+
+    for &clients in CLIENTS {
+        group.bench_with_input(
+            BenchmarkId::new("all_clients_receive", clients),
+            &clients,
+            |b, &clients| {
+                let (server, incoming) = runtime.block_on(async {
+                    let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+                    let mut incoming = Vec::with_capacity(clients);
+                    for n in 0..clients {
+                        incoming.push(connect(server.port(), n).await);
+                    }
+                    while server.active_connections().await < clients {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                    (server, Mutex::new(incoming))
+                });
+
+                let mut sent = 0;
+                b.to_async(&runtime).iter_custom(|iters| {
+                    let (server, incoming) = (&server, &incoming);
+                    let first = sent;
+                    sent += iters;
+                    async move {
+                        let mut incoming = incoming.lock().await;
+                        let start = Instant::now();
+                        for i in first..first + iters {
+                            server.broadcast(&change(i)).await.unwrap();
+                            for stream in incoming.iter_mut() {
+                                next_frame(stream).await;
+                            }
+                        }
+                        start.elapsed()
+                    }
+                });
+
+                runtime.block_on(server.shutdown("benchmark done")).unwrap();
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_broadcast);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+// EnvStorage throughput at 10k to 1M variables: set, get and list
+// (`cargo bench --bench storage`; `-- '/10000$'` runs only the small store)
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use envmesh::storage::{EnvStorage, ListQuery};
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const SIZES: &[usize] = &[10_000, 100_000, 1_000_000];
+const MACHINE: &str = "bench-machine";
+
+fn key(i: usize) -> String {
+    format!("KEY_{:07}", i)
+}
+
+/// A store holding `size` variables, written in one transaction
+fn populated(size: usize) -> EnvStorage {
+    let storage = EnvStorage::new(PathBuf::from(":memory:")).unwrap();
+    let vars: Vec<(String, String)> = (0..size)
+        .map(|i| (key(i), format!("value-{:026}", i)))
+        .collect();
+    storage.import(&vars, MACHINE, false).unwrap();
+    storage
+}
+
+/// Walks every key in a scattered order, so lookups don't hit the same pages
+struct Keys {
+    size: usize,
+    next: usize,
+}
+
+impl Keys {
+    fn new(size: usize) -> Self {
+        Self { size, next: 0 }
+    }
+
+    fn next(&mut self) -> String {
+        self.next = (self.next + 7_919) % self.size;
+        key(self.next)
+    }
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("storage");
+    group.sample_size(20);
+    group.measurement_time(Duration::from_secs(5));
+
+    for &size in SIZES {
+        // Filled on first use, so filtered-out sizes cost nothing
+        let mut store = None;
+
+        group.bench_with_input(BenchmarkId::new("get", size), &size, |b, &size| {
+            let storage = store.get_or_insert_with(|| populated(size));
+            let mut keys = Keys::new(size);
+            b.iter(|| black_box(storage.get(&keys.next()).unwrap()));
+        });
+
+        group.bench_with_input(BenchmarkId::new("set", size), &size, |b, &size| {
+            let storage = store.get_or_insert_with(|| populated(size));
+            let mut keys = Keys::new(size);
+            b.iter(|| storage.set(&keys.next(), "updated-value", MACHINE).unwrap());
+        });
+
+        group.bench_with_input(BenchmarkId::new("list_page", size), &size, |b, &size| {
+            let storage = store.get_or_insert_with(|| populated(size));
+            let query = ListQuery {
+                limit: Some(100),
+                offset: size / 2,
+                ..Default::default()
+            };
+            b.iter(|| black_box(storage.list(&query).unwrap()));
+        });
+
+        group.bench_with_input(BenchmarkId::new("list_search", size), &size, |b, &size| {
+            let storage = store.get_or_insert_with(|| populated(size));
+            let query = ListQuery {
+                search: Some("key_00042".to_string()),
+                limit: Some(100),
+                ..Default::default()
+            };
+            b.iter(|| black_box(storage.list(&query).unwrap()));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_storage);
+criterion_main!(benches);
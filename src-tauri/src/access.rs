@@ -0,0 +1,140 @@
+// Which peers we deal with (`[security] allowed_peers` / `blocked_peers`),
+// by device key, and how clients prove theirs to a server
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::signing;
+
+/// Handshake header a client names its device key in
+pub const DEVICE_KEY_HEADER: &str = "x-envmesh-device-key";
+
+/// Sent by a client that can prove its device key; the server answers with
+/// a nonce in the same header, which the client signs in its first frame
+/// (`WireMessage::Proof`)
+pub const CHALLENGE_HEADER: &str = "x-envmesh-challenge";
+
+/// How long either side waits for the other's next step of the challenge
+pub const PROOF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Who a node is: its machine ID and public device key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    pub machine_id: String,
+    pub device_key: String,
+}
+
+impl PeerIdentity {
+    /// What allow and block lists match: the device key, as machine IDs
+    /// are new every time a daemon starts
+    pub fn ids(&self) -> Vec<String> {
+        vec![self.device_key.clone()]
+    }
+}
+
+/// A fresh nonce for `CHALLENGE_HEADER`
+pub fn new_challenge() -> String {
+    crate::session::new_token()
+}
+
+/// What a client signs to prove `device_key` is its own, for the server
+/// that challenged it with `nonce`
+pub fn challenge_payload(nonce: &str, device_key: &str) -> Vec<u8> {
+    format!("envmesh-handshake\n{}\n{}", nonce, device_key).into_bytes()
+}
+
+/// Check a client's answer to `nonce`: `signature` (base64) must be by
+/// `device_key`
+pub fn verify_proof(nonce: &str, device_key: &str, signature: &str) -> anyhow::Result<()> {
+    signing::verify_bytes(device_key, &challenge_payload(nonce, device_key), signature)
+}
+
+/// Whether `id` can name a peer on an allow or block list: only device keys
+/// can, as nothing proves a machine ID and it changes on every start
+pub fn is_device_key(id: &str) -> bool {
+    signing::parse_public_key(id).is_ok()
+}
+
+/// Allow and block lists. A peer is refused if any of its IDs is blocked,
+/// or, with an allowlist, if none of them is allowed. Anonymous peers are
+/// refused by either list, as a blocked one could just not prove its key.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerAccess {
+    pub allowed: Vec<String>,
+    pub blocked: Vec<String>,
+}
+
+impl PeerAccess {
+    /// Whether a peer known by `ids` (its proven device key, or nothing)
+    /// may connect or send us changes
+    pub fn permits(&self, ids: &[String]) -> bool {
+        if ids.iter().any(|id| self.blocked.contains(id)) {
+            return false;
+        }
+        if ids.is_empty() && !self.blocked.is_empty() {
+            return false;
+        }
+        self.allowed.is_empty() || ids.iter().any(|id| self.allowed.contains(id))
+    }
+
+    /// Block `id`; false if it already was
+    pub fn block(&mut self, id: &str) -> bool {
+        if self.blocked.iter().any(|blocked| blocked == id) {
+            return false;
+        }
+        self.blocked.push(id.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_peer_access() {
+        let open = PeerAccess::default();
+        assert!(open.permits(&[]));
+        assert!(open.permits(&ids(&["key-a"])));
+
+        let mut access = PeerAccess {
+            allowed: ids(&["key-a", "key-b"]),
+            blocked: Vec::new(),
+        };
+        assert!(access.permits(&ids(&["key-a"])));
+        assert!(access.permits(&ids(&["key-b"])));
+        assert!(!access.permits(&ids(&["key-c"])));
+        // Anonymous peers can't be on an allowlist
+        assert!(!access.permits(&[]));
+
+        // Blocking wins over allowing
+        assert!(access.block("key-a"));
+        assert!(!access.block("key-a"));
+        assert!(!access.permits(&ids(&["key-a"])));
+
+        // A blocked peer can't slip in by not proving its key
+        let mut blocking = PeerAccess::default();
+        blocking.block("key-a");
+        assert!(blocking.permits(&ids(&["key-b"])));
+        assert!(!blocking.permits(&[]));
+    }
+
+    #[test]
+    fn test_handshake_proof() {
+        let key = crate::signing::DeviceKey::generate();
+        let device_key = key.public_key();
+        let nonce = new_challenge();
+        let signature = key.sign_bytes(&challenge_payload(&nonce, &device_key));
+        assert!(verify_proof(&nonce, &device_key, &signature).is_ok());
+        // An answer to another server's challenge, or someone else's key
+        assert!(verify_proof(&new_challenge(), &device_key, &signature).is_err());
+        let other = crate::signing::DeviceKey::generate().public_key();
+        assert!(verify_proof(&nonce, &other, &signature).is_err());
+
+        assert!(is_device_key(&device_key));
+        assert!(!is_device_key(&uuid::Uuid::new_v4().to_string()));
+    }
+}
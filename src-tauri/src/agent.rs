@@ -0,0 +1,740 @@
+// Lease agent (`[agent] socket`): like ssh-agent, but for secrets. A process
+// asks the daemon for one variable at a time instead of being started with
+// every value in its environment. Each request is checked against
+// `[[agent.rules]]` (allow, prompt or deny, with a per-key rate limit),
+// logged, and answered with the value and how long the caller may keep it.
+//
+// The socket is only as private as our user: any process running as us can
+// connect, and can also reach the daemon socket that approves prompts. Rules
+// narrow who gets what by the caller's uid and executable (from the kernel,
+// not from anything the caller says), but they are not a sandbox.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::clock;
+use crate::events::{EventBus, MeshEvent};
+use crate::pattern;
+use crate::pool::StoragePool;
+
+/// Where `envmesh-cli agent get` looks for the socket before `[agent]`
+pub const AGENT_SOCK_ENV: &str = "ENVMESH_AGENT_SOCK";
+
+/// Decisions kept for `envmesh-cli agent status`
+const LOG_CAPACITY: usize = 500;
+
+/// The window `max_per_minute` counts requests in
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+fn default_lease_secs() -> u64 {
+    300
+}
+
+fn default_prompt_timeout_secs() -> u64 {
+    60
+}
+
+/// What a rule does with a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Allow,
+    /// Hold the request until `envmesh-cli agent approve` (or the timeout)
+    Prompt,
+    #[default]
+    Deny,
+}
+
+/// `[[agent.rules]]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentRule {
+    /// Keys the rule covers, e.g. `prod/*` or `AWS_*`
+    pub pattern: String,
+    /// Only callers running this executable, e.g. `/usr/bin/terraform` or
+    /// `/opt/deploy/*`; a caller whose executable can't be read never matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exe: Option<String>,
+    /// Only callers running as this user id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    pub action: RuleAction,
+    /// Requests per key per minute (0 = unlimited)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub max_per_minute: u32,
+    /// How long a granted value may be kept
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl AgentRule {
+    fn covers(&self, key: &str, peer: &Peer) -> bool {
+        pattern::matches(&self.pattern, key)
+            && self.uid.is_none_or(|uid| peer.uid == Some(uid))
+            && self.exe.as_ref().is_none_or(|exe| {
+                peer.exe
+                    .as_ref()
+                    .is_some_and(|path| pattern::matches(exe, &path.to_string_lossy()))
+            })
+    }
+}
+
+/// Who is asking, from the socket's peer credentials
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peer {
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    /// Read from `/proc/PID/exe`, so only known on Linux
+    pub exe: Option<PathBuf>,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.exe, self.pid) {
+            (Some(exe), Some(pid)) => write!(f, "{} (pid {})", exe.display(), pid),
+            (None, Some(pid)) => write!(f, "pid {}", pid),
+            _ => write!(f, "unknown process"),
+        }
+    }
+}
+
+/// `[agent]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentConfig {
+    /// Unix socket to serve leases on, relative to the data directory, e.g.
+    /// "agent.sock" (off when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+    /// What to do with keys no rule matches
+    #[serde(default)]
+    pub default: RuleAction,
+    /// How long a prompted request waits for an answer before it is denied
+    #[serde(default = "default_prompt_timeout_secs")]
+    pub prompt_timeout_secs: u64,
+    /// First match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<AgentRule>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            socket: None,
+            default: RuleAction::default(),
+            prompt_timeout_secs: default_prompt_timeout_secs(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl AgentConfig {
+    /// The socket path, if the agent is on
+    pub fn socket_path(&self, data_dir: &Path) -> Option<PathBuf> {
+        self.socket.as_ref().map(|socket| data_dir.join(socket))
+    }
+}
+
+/// One message from a client, one JSON line each
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AgentRequest {
+    /// Ask for the value of `key`
+    Lease { key: String },
+    /// Say a lease's value is no longer held
+    Release { lease: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AgentReply {
+    Granted {
+        lease: u64,
+        value: String,
+        /// Unix seconds
+        expires_at: i64,
+    },
+    Released,
+    Denied {
+        reason: String,
+    },
+}
+
+/// A request waiting for `envmesh-cli agent approve`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingLease {
+    pub id: u64,
+    pub key: String,
+    pub process: String,
+    pub requested_at: i64,
+}
+
+/// A value handed out and not yet released or expired. The daemon can't
+/// take a value back; the lease says how long the caller may keep it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub id: u64,
+    pub key: String,
+    pub process: String,
+    pub granted_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseLogEntry {
+    pub at: i64,
+    pub key: String,
+    pub process: String,
+    pub granted: bool,
+    /// Lease length, or why it was denied
+    pub detail: String,
+}
+
+/// Answer to `envmesh-cli agent status`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub pending: Vec<PendingLease>,
+    pub leases: Vec<Lease>,
+    /// Newest first
+    pub log: Vec<LeaseLogEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow { lease_secs: u64 },
+    Prompt { lease_secs: u64 },
+    Deny(String),
+}
+
+/// `[[agent.rules]]` plus the request history their rate limits count
+pub struct Policy {
+    config: AgentConfig,
+    requests: HashMap<String, VecDeque<Instant>>,
+}
+
+impl Policy {
+    pub fn new(config: AgentConfig) -> Self {
+        Self {
+            config,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Decide a request for `key` made by `peer` at `now`; every request
+    /// that isn't refused by its rule counts against the rate limit
+    pub fn decide(&mut self, key: &str, peer: &Peer, now: Instant) -> Decision {
+        let rule = self.config.rules.iter().find(|rule| rule.covers(key, peer));
+        let (action, max_per_minute, lease_secs) = match rule {
+            Some(rule) => (rule.action, rule.max_per_minute, rule.lease_secs),
+            None => (self.config.default, 0, default_lease_secs()),
+        };
+        if action == RuleAction::Deny {
+            return Decision::Deny(match rule {
+                Some(rule) => format!("denied by rule {}", rule.pattern),
+                None => "no rule allows it".to_string(),
+            });
+        }
+
+        if max_per_minute > 0 {
+            let recent = self.requests.entry(key.to_string()).or_default();
+            while recent
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW)
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= max_per_minute as usize {
+                return Decision::Deny(format!("rate limit of {} per minute", max_per_minute));
+            }
+            recent.push_back(now);
+        }
+
+        match action {
+            RuleAction::Prompt => Decision::Prompt { lease_secs },
+            _ => Decision::Allow { lease_secs },
+        }
+    }
+}
+
+/// The daemon side: decides, prompts, hands out values and remembers leases
+pub struct Agent {
+    prompt_timeout: Duration,
+    policy: Mutex<Policy>,
+    pending: Mutex<HashMap<u64, (PendingLease, oneshot::Sender<bool>)>>,
+    leases: Mutex<Vec<Lease>>,
+    log: Mutex<VecDeque<LeaseLogEntry>>,
+    next_id: AtomicU64,
+    storage: StoragePool,
+    events: EventBus,
+}
+
+impl Agent {
+    pub fn new(config: AgentConfig, storage: StoragePool, events: EventBus) -> Self {
+        Self {
+            prompt_timeout: Duration::from_secs(config.prompt_timeout_secs),
+            policy: Mutex::new(Policy::new(config)),
+            pending: Mutex::new(HashMap::new()),
+            leases: Mutex::new(Vec::new()),
+            log: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+            storage,
+            events,
+        }
+    }
+
+    pub async fn handle(&self, request: AgentRequest, peer: &Peer) -> AgentReply {
+        match request {
+            AgentRequest::Lease { key } => self.lease(key, peer).await,
+            AgentRequest::Release { lease } => {
+                let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+                match leases.iter().position(|held| held.id == lease) {
+                    Some(index) => {
+                        leases.remove(index);
+                        AgentReply::Released
+                    }
+                    None => AgentReply::Denied {
+                        reason: format!("No lease {}", lease),
+                    },
+                }
+            }
+        }
+    }
+
+    async fn lease(&self, key: String, peer: &Peer) -> AgentReply {
+        let process = &peer.to_string();
+        let decision = self
+            .policy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .decide(&key, peer, Instant::now());
+        let lease_secs = match decision {
+            Decision::Allow { lease_secs } => lease_secs,
+            Decision::Prompt { lease_secs } => match self.prompt(&key, process).await {
+                Ok(()) => lease_secs,
+                Err(reason) => return self.deny(key, process, reason),
+            },
+            Decision::Deny(reason) => return self.deny(key, process, reason),
+        };
+
+        let k = key.clone();
+        let value = match self.storage.read(move |s| s.get(&k)).await {
+            Ok(Some((value, ..))) => value,
+            Ok(None) => return self.deny(key, process, "not set".to_string()),
+            Err(e) => return self.deny(key, process, format!("failed to read: {}", e)),
+        };
+
+        let now = clock::now();
+        let lease = Lease {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            key: key.clone(),
+            process: process.to_string(),
+            granted_at: now,
+            expires_at: now + lease_secs as i64,
+        };
+        tracing::info!("Agent: leased {} to {} for {}s", key, process, lease_secs);
+        self.record(key, process, true, format!("{}s lease", lease_secs));
+        let reply = AgentReply::Granted {
+            lease: lease.id,
+            value,
+            expires_at: lease.expires_at,
+        };
+        let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        leases.retain(|held| held.expires_at > now);
+        leases.push(lease);
+        reply
+    }
+
+    /// Hold the request until it is answered, or deny it after the timeout
+    async fn prompt(&self, key: &str, process: &str) -> Result<(), String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let pending = PendingLease {
+            id,
+            key: key.to_string(),
+            process: process.to_string(),
+            requested_at: clock::now(),
+        };
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, (pending, tx));
+        self.events.emit(MeshEvent::LeaseRequested {
+            id,
+            key: key.to_string(),
+            process: process.to_string(),
+        });
+
+        let answer = tokio::time::timeout(self.prompt_timeout, rx).await;
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&id);
+        match answer {
+            Ok(Ok(true)) => Ok(()),
+            Ok(_) => Err("refused".to_string()),
+            Err(_) => Err(format!(
+                "not approved within {}s",
+                self.prompt_timeout.as_secs()
+            )),
+        }
+    }
+
+    fn deny(&self, key: String, process: &str, reason: String) -> AgentReply {
+        tracing::warn!("Agent: refused {} to {}: {}", key, process, reason);
+        self.record(key, process, false, reason.clone());
+        AgentReply::Denied { reason }
+    }
+
+    fn record(&self, key: String, process: &str, granted: bool, detail: String) {
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        if log.len() == LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(LeaseLogEntry {
+            at: clock::now(),
+            key,
+            process: process.to_string(),
+            granted,
+            detail,
+        });
+    }
+
+    /// Approve or refuse a prompted request
+    pub fn answer(&self, id: u64, allow: bool) -> Result<()> {
+        let (_, tx) = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&id)
+            .ok_or_else(|| anyhow!("No request {} is waiting", id))?;
+        // The requester may have hung up; then there is nothing to answer
+        let _ = tx.send(allow);
+        Ok(())
+    }
+
+    /// Waiting requests, unexpired leases and the newest `limit` decisions
+    pub fn status(&self, limit: usize) -> AgentStatus {
+        let now = clock::now();
+        let mut pending: Vec<PendingLease> = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|(pending, _)| pending.clone())
+            .collect();
+        pending.sort_by_key(|pending| pending.id);
+        let leases = {
+            let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+            leases.retain(|held| held.expires_at > now);
+            leases.clone()
+        };
+        let log = self
+            .log
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect();
+        AgentStatus {
+            pending,
+            leases,
+            log,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn peer_of(stream: &tokio::net::UnixStream) -> Peer {
+    let Ok(cred) = stream.peer_cred() else {
+        return Peer::default();
+    };
+    let pid = cred.pid();
+    Peer {
+        uid: Some(cred.uid()),
+        pid,
+        exe: pid.and_then(|pid| std::fs::read_link(format!("/proc/{}/exe", pid)).ok()),
+    }
+}
+
+/// Serve leases on `listener` until the daemon exits
+#[cfg(unix)]
+pub async fn serve(agent: std::sync::Arc<Agent>, listener: tokio::net::UnixListener) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::error!("Agent accept error: {}", e);
+                continue;
+            }
+        };
+        let agent = std::sync::Arc::clone(&agent);
+        tokio::spawn(async move {
+            let peer = peer_of(&stream);
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match serde_json::from_str::<AgentRequest>(&line) {
+                    Ok(request) => agent.handle(request, &peer).await,
+                    Err(e) => AgentReply::Denied {
+                        reason: format!("Invalid request: {}", e),
+                    },
+                };
+                let Ok(mut text) = serde_json::to_string(&reply) else {
+                    return;
+                };
+                text.push('\n');
+                if writer.write_all(text.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Send one request to the agent at `socket` and wait for its reply
+#[cfg(unix)]
+pub async fn request(socket: &Path, request: &AgentRequest) -> Result<AgentReply> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow!("No agent at {}: {}", socket.display(), e))?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).await?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+#[cfg(not(unix))]
+pub async fn request(socket: &Path, _request: &AgentRequest) -> Result<AgentReply> {
+    Err(anyhow!(
+        "The lease agent needs a Unix socket ({})",
+        socket.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_policy() {
+        let config: AgentConfig = toml::from_str(
+            r#"
+            socket = "agent.sock"
+            [[rules]]
+            pattern = "prod/*"
+            action = "prompt"
+            lease_secs = 60
+            [[rules]]
+            pattern = "*_TOKEN"
+            action = "allow"
+            max_per_minute = 2
+            [[rules]]
+            pattern = "*"
+            action = "deny"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.socket_path(Path::new("/data")),
+            Some(PathBuf::from("/data/agent.sock"))
+        );
+        let mut policy = Policy::new(config);
+        let start = Instant::now();
+        let anyone = Peer::default();
+
+        // First match wins
+        assert_eq!(
+            policy.decide("prod/API_TOKEN", &anyone, start),
+            Decision::Prompt { lease_secs: 60 }
+        );
+        assert!(matches!(
+            policy.decide("DB_URL", &anyone, start),
+            Decision::Deny(_)
+        ));
+
+        assert_eq!(
+            policy.decide("CI_TOKEN", &anyone, start),
+            Decision::Allow { lease_secs: 300 }
+        );
+        assert!(matches!(
+            policy.decide("CI_TOKEN", &anyone, start),
+            Decision::Allow { .. }
+        ));
+        assert!(matches!(
+            policy.decide("CI_TOKEN", &anyone, start),
+            Decision::Deny(_)
+        ));
+        // Counted per key, over the last minute
+        assert!(matches!(
+            policy.decide("GH_TOKEN", &anyone, start),
+            Decision::Allow { .. }
+        ));
+        let later = start + RATE_WINDOW;
+        assert!(matches!(
+            policy.decide("CI_TOKEN", &anyone, later),
+            Decision::Allow { .. }
+        ));
+
+        // Without rules, nothing is handed out unless `default` says so
+        let mut closed = Policy::new(AgentConfig::default());
+        assert!(matches!(
+            closed.decide("ANY", &anyone, start),
+            Decision::Deny(_)
+        ));
+
+        // Rules can name the caller; one whose executable is unknown never matches
+        let config: AgentConfig = toml::from_str(
+            r#"
+            [[rules]]
+            pattern = "prod/*"
+            action = "allow"
+            exe = "/usr/bin/terraform"
+            uid = 1000
+            "#,
+        )
+        .unwrap();
+        let mut named = Policy::new(config);
+        let terraform = Peer {
+            uid: Some(1000),
+            pid: Some(42),
+            exe: Some(PathBuf::from("/usr/bin/terraform")),
+        };
+        assert!(matches!(
+            named.decide("prod/DB_URL", &terraform, start),
+            Decision::Allow { .. }
+        ));
+        let python = Peer {
+            exe: Some(PathBuf::from("/usr/bin/python3")),
+            ..terraform.clone()
+        };
+        let other_user = Peer {
+            uid: Some(1001),
+            ..terraform.clone()
+        };
+        for peer in [&python, &other_user, &anyone] {
+            assert!(matches!(
+                named.decide("prod/DB_URL", peer, start),
+                Decision::Deny(_)
+            ));
+        }
+        assert_eq!(terraform.to_string(), "/usr/bin/terraform (pid 42)");
+
+        let request: AgentRequest =
+            serde_json::from_str(r#"{"op":"lease","key":"CI_TOKEN"}"#).unwrap();
+        assert_eq!(
+            request,
+            AgentRequest::Lease {
+                key: "CI_TOKEN".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lease_waits_for_an_answer() {
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        storage
+            .write(|s| s.set("prod/DB_URL", "postgres://db", "machine-a"))
+            .await
+            .unwrap();
+        let config: AgentConfig = toml::from_str(
+            r#"
+            prompt_timeout_secs = 1
+            [[rules]]
+            pattern = "prod/*"
+            action = "prompt"
+            lease_secs = 60
+            "#,
+        )
+        .unwrap();
+        let events = EventBus::new();
+        let mut requested = events.subscribe();
+        let agent = std::sync::Arc::new(Agent::new(config, storage, events));
+        let peer = Peer {
+            uid: Some(1000),
+            pid: Some(42),
+            exe: Some(PathBuf::from("/usr/bin/terraform")),
+        };
+        let lease = |agent: &std::sync::Arc<Agent>| {
+            let agent = std::sync::Arc::clone(agent);
+            let peer = peer.clone();
+            tokio::spawn(async move {
+                let key = "prod/DB_URL".to_string();
+                agent.handle(AgentRequest::Lease { key }, &peer).await
+            })
+        };
+        let next_prompt = |requested: &mut tokio::sync::broadcast::Receiver<MeshEvent>| {
+            match requested.try_recv() {
+                Ok(MeshEvent::LeaseRequested { id, process, .. }) => {
+                    assert_eq!(process, "/usr/bin/terraform (pid 42)");
+                    id
+                }
+                other => panic!("expected a lease request, got {:?}", other),
+            }
+        };
+
+        // Approved: the value comes back with a lease the status lists
+        let waiting = lease(&agent);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(agent.status(10).pending.len(), 1);
+        let id = next_prompt(&mut requested);
+        agent.answer(id, true).unwrap();
+        let AgentReply::Granted {
+            lease: granted,
+            value,
+            expires_at,
+        } = waiting.await.unwrap()
+        else {
+            panic!("approved lease was refused");
+        };
+        assert_eq!(value, "postgres://db");
+        let status = agent.status(10);
+        assert!(status.pending.is_empty());
+        assert_eq!(status.leases.len(), 1);
+        assert_eq!(
+            status.leases[0].expires_at - status.leases[0].granted_at,
+            60
+        );
+        assert_eq!(status.leases[0].expires_at, expires_at);
+        assert!(agent.answer(id, true).is_err());
+
+        // Refused, then unanswered
+        let waiting = lease(&agent);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        agent.answer(next_prompt(&mut requested), false).unwrap();
+        assert_eq!(
+            waiting.await.unwrap(),
+            AgentReply::Denied {
+                reason: "refused".to_string()
+            }
+        );
+        let reply = lease(&agent).await.unwrap();
+        assert!(matches!(reply, AgentReply::Denied { reason } if reason.contains("within 1s")));
+        assert!(agent.status(10).pending.is_empty());
+
+        assert_eq!(
+            agent
+                .handle(AgentRequest::Release { lease: granted }, &peer)
+                .await,
+            AgentReply::Released
+        );
+        let status = agent.status(10);
+        assert!(status.leases.is_empty());
+        let granted: Vec<bool> = status.log.iter().map(|entry| entry.granted).collect();
+        assert_eq!(granted, [false, false, true]);
+    }
+}
@@ -1,6 +1,21 @@
+use crate::autostart::{self, AutostartEntry};
+use crate::chunking;
 use crate::client::SyncMessage;
+use crate::config::{Settings, SettingsUpdate};
+use crate::events::MeshEvent;
+use crate::expiry;
+use crate::keys;
+use crate::merge;
+use crate::node::{ConnectionStatus, ServerMode};
+use crate::redact::ValueRedacted;
 use crate::state::AppState;
+use crate::storage::{
+    self, ConflictChoice, ConflictRecord, HistoryEntry, ImportAction, ImportChange, ListQuery,
+    SyncReport,
+};
+use crate::tfvars;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
 use tauri::State;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -9,13 +24,35 @@ pub struct EnvVar {
     pub value: String,
     pub timestamp: i64,
     pub machine_id: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvVarPage {
+    pub vars: Vec<EnvVar>,
+    /// Matches across all pages
+    pub total: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Peer {
-    pub id: String,
+    /// Device key the peer proved (None for servers we connect to, and for
+    /// clients that proved none)
+    pub id: Option<String>,
+    /// "cloud", "lan-server" or "lan-client"
+    pub kind: String,
     pub address: String,
-    pub last_seen: i64,
+    /// When the peer last sent us anything (Unix seconds)
+    pub last_seen: Option<i64>,
+    /// Smoothed round-trip time (None until a ping is answered)
+    pub rtt_ms: Option<u64>,
+    pub loss_percent: f64,
+    /// Last frame of ours the peer confirmed, and the last we sent
+    pub acked_seq: Option<u64>,
+    pub sent_seq: Option<u64>,
+    /// Frames the peer hasn't confirmed yet (None when unknown)
+    pub behind: Option<u64>,
 }
 
 #[tauri::command]
@@ -23,18 +60,23 @@ pub async fn get_env_var(
     key: String,
     state: State<'_, AppState>,
 ) -> Result<Option<EnvVar>, String> {
-    let storage = state.storage.lock().await;
-
-    match storage.get(&key) {
-        Ok(Some((value, timestamp, machine_id))) => Ok(Some(EnvVar {
-            key,
-            value,
-            timestamp,
-            machine_id,
-        })),
-        Ok(None) => Ok(None),
-        Err(e) => Err(format!("Failed to get env var: {}", e)),
-    }
+    let lookup = key.clone();
+    let found = state
+        .storage
+        .read(move |s| match s.get(&lookup)? {
+            Some(var) => Ok(Some((var, s.tags(&lookup)?))),
+            None => Ok(None),
+        })
+        .await
+        .map_err(|e| format!("Failed to get env var: {}", e))?;
+
+    Ok(found.map(|((value, timestamp, machine_id), tags)| EnvVar {
+        key,
+        value,
+        timestamp,
+        machine_id,
+        tags,
+    }))
 }
 
 #[tauri::command]
@@ -43,24 +85,31 @@ pub async fn set_env_var(
     value: String,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
-    let storage = state.storage.lock().await;
+    let (max_value_bytes, key_policy) = {
+        let config = state.config.lock().await;
+        (config.limits.max_value_bytes, config.keys.policy)
+    };
+    let key = keys::prepare(&key, key_policy).map_err(|e| e.to_string())?;
+    chunking::check_value_size(&key, &value, max_value_bytes).map_err(|e| e.to_string())?;
+    state.sync.check_write(&key).map_err(|e| e.to_string())?;
+    tracing::debug!("Setting {} = {}", key, ValueRedacted(&value));
 
-    storage
-        .set(&key, &value, &state.machine_id)
+    let (k, v, machine_id) = (key.clone(), value.clone(), state.machine_id.clone());
+    state
+        .storage
+        .write(move |s| s.set(&k, &v, &machine_id))
+        .await
         .map_err(|e| format!("Failed to set env var: {}", e))?;
-
-    // Send change to network
-    let timestamp = chrono::Utc::now().timestamp();
-    let msg = SyncMessage {
+    state.events.emit(MeshEvent::VarChanged {
         key: key.clone(),
-        value: value.clone(),
-        timestamp,
         machine_id: state.machine_id.clone(),
         deleted: false,
-    };
+    });
 
-    let mut node = state.node.lock().await;
-    node.send_update(&msg)
+    // Send change to network, stamped as stored
+    state
+        .sync
+        .publish_stored(&key)
         .await
         .map_err(|e| format!("Failed to send update: {}", e))?;
 
@@ -69,24 +118,23 @@ pub async fn set_env_var(
 
 #[tauri::command]
 pub async fn delete_env_var(key: String, state: State<'_, AppState>) -> Result<(), String> {
-    let storage = state.storage.lock().await;
-
-    storage
-        .delete(&key, &state.machine_id)
+    state.sync.check_write(&key).map_err(|e| e.to_string())?;
+    let (k, machine_id) = (key.clone(), state.machine_id.clone());
+    state
+        .storage
+        .write(move |s| s.delete(&k, &machine_id))
+        .await
         .map_err(|e| format!("Failed to delete env var: {}", e))?;
-
-    // Send deletion to network
-    let timestamp = chrono::Utc::now().timestamp();
-    let msg = SyncMessage {
+    state.events.emit(MeshEvent::VarChanged {
         key: key.clone(),
-        value: String::new(),
-        timestamp,
         machine_id: state.machine_id.clone(),
         deleted: true,
-    };
+    });
 
-    let mut node = state.node.lock().await;
-    node.send_update(&msg)
+    // Send deletion to network, stamped as stored
+    state
+        .sync
+        .publish_stored(&key)
         .await
         .map_err(|e| format!("Failed to send update: {}", e))?;
 
@@ -94,63 +142,355 @@ pub async fn delete_env_var(key: String, state: State<'_, AppState>) -> Result<(
 }
 
 #[tauri::command]
-pub async fn list_env_vars(state: State<'_, AppState>) -> Result<Vec<EnvVar>, String> {
-    let storage = state.storage.lock().await;
-
-    let vars = storage
-        .list_all()
+pub async fn list_env_vars(
+    query: Option<ListQuery>,
+    state: State<'_, AppState>,
+) -> Result<EnvVarPage, String> {
+    let query = query.unwrap_or_default();
+    let page = state
+        .storage
+        .read(move |s| s.list(&query))
+        .await
         .map_err(|e| format!("Failed to list env vars: {}", e))?;
 
-    Ok(vars
+    Ok(EnvVarPage {
+        vars: page
+            .vars
+            .into_iter()
+            .map(|var| EnvVar {
+                key: var.key,
+                value: var.value,
+                timestamp: var.timestamp,
+                machine_id: var.machine_id,
+                tags: var.tags,
+            })
+            .collect(),
+        total: page.total,
+    })
+}
+
+/// Parse pasted .env content into `namespace` (default namespace if None).
+/// With `dry_run` the result is a preview; otherwise all changes are applied
+/// in one transaction and sent to the network.
+#[tauri::command]
+pub async fn import_env_text(
+    text: String,
+    namespace: Option<String>,
+    dry_run: bool,
+    state: State<'_, AppState>,
+) -> Result<Vec<ImportChange>, String> {
+    let namespace = namespace.unwrap_or_else(|| storage::DEFAULT_NAMESPACE.to_string());
+    let vars: Vec<(String, String)> = crate::dotenv::parse(&text)
+        .map_err(|e| format!("Invalid .env content:\n{}", e))?
         .into_iter()
-        .map(|(key, value, timestamp, machine_id)| EnvVar {
-            key,
-            value,
-            timestamp,
-            machine_id,
-        })
-        .collect())
+        .map(|(key, value)| (storage::namespaced_key(&namespace, &key), value))
+        .collect();
+
+    let (max_value_bytes, key_policy) = {
+        let config = state.config.lock().await;
+        (config.limits.max_value_bytes, config.keys.policy)
+    };
+    for (key, value) in &vars {
+        keys::validate(key, key_policy).map_err(|e| e.to_string())?;
+        chunking::check_value_size(key, value, max_value_bytes).map_err(|e| e.to_string())?;
+        state.sync.check_write(key).map_err(|e| e.to_string())?;
+    }
+
+    let machine_id = state.machine_id.clone();
+    let changes = state
+        .storage
+        .write(move |s| s.import(&vars, &machine_id, dry_run))
+        .await
+        .map_err(|e| format!("Failed to import: {}", e))?;
+
+    if dry_run {
+        return Ok(changes);
+    }
+
+    for change in changes
+        .iter()
+        .filter(|c| c.action != ImportAction::Unchanged)
+    {
+        state.events.emit(MeshEvent::VarChanged {
+            key: change.key.clone(),
+            machine_id: state.machine_id.clone(),
+            deleted: false,
+        });
+
+        let lookup = change.key.clone();
+        let Ok(Some((value, timestamp, machine_id))) =
+            state.storage.read(move |s| s.get(&lookup)).await
+        else {
+            continue;
+        };
+        let msg = SyncMessage::new(change.key.clone(), value, timestamp, machine_id, false);
+        state
+            .sync
+            .publish(&msg)
+            .await
+            .map_err(|e| format!("Failed to send update: {}", e))?;
+    }
+
+    Ok(changes)
+}
+
+#[tauri::command]
+pub async fn set_env_var_tags(
+    key: String,
+    tags: Vec<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    merge::validate_tags(&tags)
+        .and(expiry::validate_tags(&tags))
+        .and(tfvars::validate_tags(&tags))
+        .map_err(|e| e.to_string())?;
+    let k = key.clone();
+    state
+        .storage
+        .write(move |s| s.set_tags(&k, &tags))
+        .await
+        .map_err(|e| format!("Failed to set tags: {}", e))?;
+    // Writers filtering by tag may now take the key or drop it
+    state.events.emit(MeshEvent::VarChanged {
+        key,
+        machine_id: state.machine_id.clone(),
+        deleted: false,
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_var_history(
+    key: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<HistoryEntry>, String> {
+    state
+        .storage
+        .read(move |s| s.history(&key))
+        .await
+        .map_err(|e| format!("Failed to get history: {}", e))
+}
+
+#[tauri::command]
+pub async fn get_conflicts(state: State<'_, AppState>) -> Result<Vec<ConflictRecord>, String> {
+    state
+        .storage
+        .read(|s| s.conflicts())
+        .await
+        .map_err(|e| format!("Failed to get conflicts: {}", e))
+}
+
+#[tauri::command]
+pub async fn resolve_conflict(
+    key: String,
+    chosen_version: ConflictChoice,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let (k, machine_id) = (key.clone(), state.machine_id.clone());
+    let resolved = state
+        .storage
+        .write(move |s| s.resolve_conflict(&k, chosen_version, &machine_id))
+        .await
+        .map_err(|e| format!("Failed to resolve conflict: {}", e))?;
+
+    let Some((key, value, timestamp, machine_id, deleted)) = resolved else {
+        return Err(format!("No unresolved conflict for {}", key));
+    };
+
+    // Send the winning version to the network so other machines converge
+    let msg = SyncMessage::new(key.clone(), value, timestamp, machine_id, deleted);
+    state.events.emit(MeshEvent::VarChanged {
+        key: key.clone(),
+        machine_id: msg.machine_id.clone(),
+        deleted: msg.deleted,
+    });
+    state.events.emit(MeshEvent::ConflictResolved { key });
+
+    state
+        .sync
+        .publish(&msg)
+        .await
+        .map_err(|e| format!("Failed to send update: {}", e))?;
+
+    Ok(())
 }
 
 #[tauri::command]
 pub async fn get_peers(state: State<'_, AppState>) -> Result<Vec<Peer>, String> {
     let node = state.node.lock().await;
 
-    let peers = node.get_peers();
+    let links = node.peer_links();
 
-    Ok(peers
+    Ok(links
         .into_iter()
-        .map(|(id, addr)| Peer {
-            id,
-            address: addr,
-            last_seen: chrono::Utc::now().timestamp(),
+        .map(|link| Peer {
+            loss_percent: link.loss_percent(),
+            behind: link.behind(),
+            rtt_ms: link.rtt_ms,
+            id: link.peer_id,
+            kind: link.kind.as_str().to_string(),
+            address: link.address,
+            last_seen: link.last_received_ms.map(|ms| ms / 1000),
+            acked_seq: link.acked_seq,
+            sent_seq: link.sent_seq,
         })
         .collect())
 }
 
 #[tauri::command]
-pub async fn trigger_sync(state: State<'_, AppState>) -> Result<(), String> {
-    let storage = state.storage.lock().await;
-    let changes = storage
-        .get_changes_since(0)
-        .map_err(|e| format!("Failed to get changes: {}", e))?;
+pub async fn connection_status(state: State<'_, AppState>) -> Result<ConnectionStatus, String> {
+    Ok(state.node.lock().await.connection_status())
+}
 
-    drop(storage);
+#[tauri::command]
+pub async fn force_failover_to_lan(state: State<'_, AppState>) -> Result<ConnectionStatus, String> {
+    let mut node = state.node.lock().await;
+    node.force_failover_to_lan()
+        .await
+        .map_err(|e| format!("Failed to fail over to LAN: {}", e))?;
+    Ok(node.connection_status())
+}
 
+#[tauri::command]
+pub async fn force_reconnect_cloud(state: State<'_, AppState>) -> Result<ConnectionStatus, String> {
     let mut node = state.node.lock().await;
-    for (key, value, timestamp, machine_id, deleted) in changes {
-        let msg = SyncMessage {
-            key,
-            value,
-            timestamp,
-            machine_id,
-            deleted,
-        };
+    node.force_reconnect_cloud()
+        .await
+        .map_err(|e| format!("Failed to reconnect to cloud: {}", e))?;
+    Ok(node.connection_status())
+}
 
-        node.send_update(&msg)
+/// Switch server mode now, and once that worked, keep it in the config for
+/// the next start
+#[tauri::command]
+pub async fn set_server_mode(
+    mode: ServerMode,
+    state: State<'_, AppState>,
+) -> Result<ConnectionStatus, String> {
+    let status = {
+        let mut node = state.node.lock().await;
+        node.set_server_mode(mode.clone())
             .await
-            .map_err(|e| format!("Failed to send update: {}", e))?;
-    }
+            .map_err(|e| format!("Failed to reconnect in new server mode: {}", e))?;
+        node.connection_status()
+    };
+
+    let mut config = state.config.lock().await;
+    config
+        .apply_settings(SettingsUpdate {
+            server_mode: Some(mode.as_str().to_string()),
+            ..Default::default()
+        })
+        .map_err(|e| format!("Invalid settings: {}", e))?;
+    config
+        .save(&state.config_path)
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+    Ok(status)
+}
+
+#[tauri::command]
+pub async fn trigger_sync(state: State<'_, AppState>) -> Result<(), String> {
+    state
+        .sync
+        .push_all()
+        .await
+        .map_err(|e| format!("Failed to sync: {}", e))?;
 
     Ok(())
 }
+
+/// Forget sync progress (and, with `overwrite_local`, local state) and pull
+/// the machine `from`'s whole state, as `envmesh-cli resync`
+#[tauri::command]
+pub async fn resync_from(
+    from: String,
+    overwrite_local: bool,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .sync
+        .resync_from(&from, overwrite_local)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to resync from {}: {}", from, e))
+}
+
+/// The newest `limit` sync reports, newest first
+#[tauri::command]
+pub async fn get_sync_reports(
+    limit: usize,
+    state: State<'_, AppState>,
+) -> Result<Vec<SyncReport>, String> {
+    state
+        .sync
+        .reports(limit)
+        .await
+        .map_err(|e| format!("Failed to read sync log: {}", e))
+}
+
+#[tauri::command]
+pub async fn set_notifications_enabled(
+    enabled: bool,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .notifications_enabled
+        .store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_autostart(enabled: bool) -> Result<(), String> {
+    let entry = AutostartEntry::current("envmesh").map_err(|e| e.to_string())?;
+
+    let result = if enabled {
+        autostart::enable(&entry)
+    } else {
+        autostart::disable(&entry)
+    };
+
+    result.map_err(|e| format!("Failed to update autostart: {}", e))
+}
+
+#[tauri::command]
+pub async fn get_settings(state: State<'_, AppState>) -> Result<Settings, String> {
+    Ok(state.config.lock().await.settings())
+}
+
+#[tauri::command]
+pub async fn update_settings(
+    update: SettingsUpdate,
+    state: State<'_, AppState>,
+) -> Result<Settings, String> {
+    let reconnect = update.affects_connection();
+
+    let mut config = state.config.lock().await;
+    config
+        .apply_settings(update)
+        .map_err(|e| format!("Invalid settings: {}", e))?;
+    config
+        .save(&state.config_path)
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+
+    // Apply what we can without a restart
+    state
+        .notifications_enabled
+        .store(config.notifications.enabled, Ordering::Relaxed);
+    state.sync.set_filter(config.sync_filter());
+    state.sync.set_key_policy(config.keys.policy);
+    state.sync.set_quarantine(config.quarantine.clone());
+    state.sync.set_rate_limit(config.limits.sync_rate);
+    state.sync.set_clock_config(config.clock);
+
+    if reconnect {
+        state
+            .node
+            .lock()
+            .await
+            .update_config(config.to_node_config())
+            .await
+            .map_err(|e| format!("Failed to reconnect with new settings: {}", e))?;
+    }
+
+    Ok(config.settings())
+}
@@ -0,0 +1,286 @@
+// Register the GUI or daemon with the OS login-items mechanism
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+
+/// Something to launch at login
+pub struct AutostartEntry {
+    /// Short name, also used for the desktop file / launch agent / registry value
+    pub name: String,
+    pub exec: PathBuf,
+    pub args: Vec<String>,
+}
+
+impl AutostartEntry {
+    /// The currently running executable (used by the GUI for itself)
+    pub fn current(name: &str) -> Result<Self> {
+        let exec = std::env::current_exe().context("Failed to locate current executable")?;
+        Ok(Self {
+            name: name.to_string(),
+            exec,
+            args: Vec::new(),
+        })
+    }
+
+    /// The daemon binary installed next to the running executable
+    pub fn daemon() -> Result<Self> {
+        let current = std::env::current_exe().context("Failed to locate current executable")?;
+        let dir = current
+            .parent()
+            .ok_or_else(|| anyhow!("Executable has no parent directory"))?;
+        let exec = dir.join(format!("envmesh-daemon{}", std::env::consts::EXE_SUFFIX));
+
+        if !exec.exists() {
+            return Err(anyhow!("Daemon binary not found at {}", exec.display()));
+        }
+
+        Ok(Self {
+            name: "envmesh-daemon".to_string(),
+            exec,
+            args: Vec::new(),
+        })
+    }
+
+    // Launch agents take an argument array instead of a command line
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    fn command_line(&self) -> String {
+        std::iter::once(quote(&self.exec.display().to_string()))
+            .chain(self.args.iter().map(|arg| quote(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// One `Exec=` argument, quoted as the Desktop Entry spec asks: `"`, `` ` ``,
+/// `$` and `\` escaped inside double quotes, and `%` doubled so it isn't
+/// read as a field code
+#[cfg(not(windows))]
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+fn quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '`' | '$' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// One argument in a registry Run command line (paths can't contain `"`)
+#[cfg(windows)]
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn entry_path(entry: &AutostartEntry) -> Result<PathBuf> {
+    let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("No config directory"))?;
+    Ok(config_dir
+        .join("autostart")
+        .join(format!("{}.desktop", entry.name)))
+}
+
+#[cfg(any(all(unix, not(target_os = "macos")), test))]
+fn desktop_file(entry: &AutostartEntry) -> String {
+    // Values get their own escaping before Exec's quoting is undone, so
+    // every backslash is written twice
+    format!(
+        "[Desktop Entry]\nType=Application\nName={}\nExec={}\nX-GNOME-Autostart-enabled=true\nNoDisplay=true\n",
+        entry.name,
+        entry.command_line().replace('\\', "\\\\")
+    )
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn enable(entry: &AutostartEntry) -> Result<()> {
+    let path = entry_path(entry)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, desktop_file(entry))
+        .context(format!("Failed to write {}", path.display()))?;
+    tracing::info!("Autostart enabled: {}", path.display());
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn entry_path(entry: &AutostartEntry) -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("No home directory"))?;
+    Ok(home
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{}.plist", launch_agent_label(entry))))
+}
+
+#[cfg(any(target_os = "macos", test))]
+fn launch_agent_label(entry: &AutostartEntry) -> String {
+    format!("com.jordanburke.{}", entry.name)
+}
+
+#[cfg(any(target_os = "macos", test))]
+fn launch_agent_plist(entry: &AutostartEntry) -> String {
+    let args: String = std::iter::once(entry.exec.display().to_string())
+        .chain(entry.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+        .collect();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+        xml_escape(&launch_agent_label(entry)),
+        args
+    )
+}
+
+#[cfg(any(target_os = "macos", test))]
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(target_os = "macos")]
+pub fn enable(entry: &AutostartEntry) -> Result<()> {
+    let path = entry_path(entry)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, launch_agent_plist(entry))
+        .context(format!("Failed to write {}", path.display()))?;
+    tracing::info!("Autostart enabled: {}", path.display());
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn disable(entry: &AutostartEntry) -> Result<()> {
+    let path = entry_path(entry)?;
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            tracing::info!("Autostart disabled: {}", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow!("Failed to remove {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(unix)]
+pub fn is_enabled(entry: &AutostartEntry) -> Result<bool> {
+    Ok(entry_path(entry)?.exists())
+}
+
+#[cfg(windows)]
+const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+
+#[cfg(windows)]
+fn reg(args: &[&str]) -> Result<std::process::Output> {
+    std::process::Command::new("reg")
+        .args(args)
+        .output()
+        .context("Failed to run reg.exe")
+}
+
+#[cfg(windows)]
+pub fn enable(entry: &AutostartEntry) -> Result<()> {
+    let output = reg(&[
+        "add",
+        RUN_KEY,
+        "/v",
+        &entry.name,
+        "/t",
+        "REG_SZ",
+        "/d",
+        &entry.command_line(),
+        "/f",
+    ])?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to register autostart: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn disable(entry: &AutostartEntry) -> Result<()> {
+    if is_enabled(entry)? {
+        let output = reg(&["delete", RUN_KEY, "/v", &entry.name, "/f"])?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Failed to remove autostart: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn is_enabled(entry: &AutostartEntry) -> Result<bool> {
+    Ok(reg(&["query", RUN_KEY, "/v", &entry.name])?
+        .status
+        .success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AutostartEntry {
+        AutostartEntry {
+            name: "envmesh-daemon".to_string(),
+            exec: PathBuf::from("/opt/envmesh/envmesh-daemon"),
+            args: vec!["--config".to_string(), "/etc/envmesh.toml".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_command_line_quotes_arguments() {
+        assert_eq!(
+            entry().command_line(),
+            "\"/opt/envmesh/envmesh-daemon\" \"--config\" \"/etc/envmesh.toml\""
+        );
+    }
+
+    #[test]
+    fn test_launch_agent_plist() {
+        let plist = launch_agent_plist(&entry());
+        assert!(plist.contains("<string>com.jordanburke.envmesh-daemon</string>"));
+        assert!(plist.contains("<string>/opt/envmesh/envmesh-daemon</string>"));
+        assert!(plist.contains("<key>RunAtLoad</key>"));
+    }
+
+    #[test]
+    fn test_odd_paths_survive_quoting() {
+        let entry = AutostartEntry {
+            name: "envmesh-daemon".to_string(),
+            exec: PathBuf::from(r#"/home/a "b" `c` $d \e 100% & <f>/envmesh-daemon"#),
+            args: Vec::new(),
+        };
+
+        #[cfg(not(windows))]
+        assert!(desktop_file(&entry)
+            .contains(r#"Exec="/home/a \\"b\\" \\`c\\` \\$d \\\\e 100%% & <f>/envmesh-daemon""#));
+        assert!(launch_agent_plist(&entry).contains(
+            "<string>/home/a \"b\" `c` $d \\e 100% &amp; &lt;f&gt;/envmesh-daemon</string>"
+        ));
+    }
+}
@@ -0,0 +1,479 @@
+// Encrypted backups: portable snapshots of variables, history and tags
+use aes_gcm::aead::OsRng;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::RngCore;
+use chrono::{Local, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::cron::Schedule;
+use crate::crypto::Crypto;
+use crate::pool::StoragePool;
+use crate::storage::{ChangeRecord, EnvStorage, HistoryEntry, ImportChange};
+
+/// Archive layout: MAGIC, then the Argon2 salt, then nonce + AES-GCM ciphertext
+/// of the snapshot as JSON
+const MAGIC: &[u8; 8] = b"EMBACKUP";
+const SALT_LEN: usize = 16;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Scheduled archives are named `envmesh-<UTC time>.bak`, so names sort by age
+const ARCHIVE_PREFIX: &str = "envmesh-";
+const ARCHIVE_SUFFIX: &str = ".bak";
+const ARCHIVE_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+fn default_schedule() -> String {
+    "0 3 * * *".to_string()
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+fn default_passphrase_env() -> String {
+    "ENVMESH_BACKUP_PASSPHRASE".to_string()
+}
+
+/// `[backup]`: automatic encrypted snapshots written by the daemon
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Directory for the archives (Windows paths are translated inside WSL)
+    pub dir: String,
+
+    /// Cron expression in local time, e.g. "0 3 * * *" or "@hourly"
+    #[serde(default = "default_schedule")]
+    pub schedule: String,
+
+    /// Number of archives to keep; older ones are deleted
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+
+    /// Environment variable holding the archive passphrase
+    #[serde(default = "default_passphrase_env")]
+    pub passphrase_env: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotVar {
+    pub key: String,
+    pub value: String,
+    pub timestamp: i64,
+    pub machine_id: String,
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: i64,
+    pub machine_id: String,
+    /// For incremental snapshots, when the backup they continue was taken
+    /// (None = full)
+    pub since: Option<i64>,
+    /// The newest local history entry captured; an incremental on top of
+    /// this snapshot holds what was written here after it (0 in archives
+    /// from before it was recorded)
+    #[serde(default)]
+    pub history_id: i64,
+    /// Variables including deletion tombstones
+    pub vars: Vec<SnapshotVar>,
+    pub history: Vec<HistoryEntry>,
+    /// Tags are small, so every snapshot carries all of them
+    pub tags: Vec<(String, String)>,
+}
+
+/// The backup an incremental one continues
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupBase {
+    pub machine_id: String,
+    pub created_at: i64,
+    pub history_id: i64,
+}
+
+impl Snapshot {
+    /// What an incremental backup on top of this one starts from
+    pub fn base(&self) -> Result<BackupBase> {
+        if self.history_id == 0 {
+            return Err(anyhow!(
+                "This backup predates incremental backups by history; take a full backup first"
+            ));
+        }
+        Ok(BackupBase {
+            machine_id: self.machine_id.clone(),
+            created_at: self.created_at,
+            history_id: self.history_id,
+        })
+    }
+
+    /// Capture the store, or for an incremental snapshot only what was
+    /// written to it after `since`. That is counted by local history entry,
+    /// not by timestamp: a change that arrives late with an older stamp
+    /// (an offline edit, a skewed clock) is still in the next incremental.
+    pub fn capture(
+        storage: &EnvStorage,
+        machine_id: &str,
+        since: Option<&BackupBase>,
+    ) -> Result<Self> {
+        if let Some(base) = since.filter(|base| base.machine_id != machine_id) {
+            return Err(anyhow!(
+                "That backup was taken by machine {}, not this one; take a full backup",
+                base.machine_id
+            ));
+        }
+        // Taken first: anything written meanwhile is in the next incremental too
+        let history_id = storage.latest_history_id()?;
+        let after = since.map_or(0, |base| base.history_id);
+
+        let vars = storage
+            .get_changes_after(after)?
+            .into_iter()
+            .map(|(key, value, timestamp, machine_id, deleted)| SnapshotVar {
+                key,
+                value,
+                timestamp,
+                machine_id,
+                deleted,
+            })
+            .collect();
+
+        Ok(Self {
+            version: FORMAT_VERSION,
+            created_at: Utc::now().timestamp(),
+            machine_id: machine_id.to_string(),
+            since: since.map(|base| base.created_at),
+            history_id,
+            vars,
+            history: storage.history_after(after)?,
+            tags: storage.all_tags()?,
+        })
+    }
+
+    /// Merge into the store; returns the number of variables written
+    pub fn restore(&self, storage: &EnvStorage) -> Result<usize> {
+        storage.restore(&self.records(), &self.history, &self.tags)
+    }
+
+    /// Replace everything the store shares with peers by this snapshot
+    /// (`fsck --repair`'s last resort: the rows come back in fresh tables);
+    /// sync progress, conflicts and held changes are dropped with them.
+    /// Returns the number of variables written.
+    pub fn rebuild(&self, storage: &EnvStorage) -> Result<usize> {
+        storage.clear_replicated()?;
+        self.restore(storage)
+    }
+
+    /// What `restore` would change in the store, without writing
+    pub fn preview(&self, storage: &EnvStorage) -> Result<Vec<ImportChange>> {
+        storage.preview_restore(&self.records())
+    }
+
+    fn records(&self) -> Vec<ChangeRecord> {
+        self.vars
+            .iter()
+            .map(|var| {
+                (
+                    var.key.clone(),
+                    var.value.clone(),
+                    var.timestamp,
+                    var.machine_id.clone(),
+                    var.deleted,
+                )
+            })
+            .collect()
+    }
+
+    pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let crypto = Crypto::with_salt(passphrase, &salt)?;
+        let ciphertext = crypto.encrypt(&serde_json::to_vec(self)?)?;
+
+        let mut archive = Vec::with_capacity(MAGIC.len() + SALT_LEN + ciphertext.len());
+        archive.extend_from_slice(MAGIC);
+        archive.extend_from_slice(&salt);
+        archive.extend_from_slice(&ciphertext);
+        Ok(archive)
+    }
+
+    pub fn decrypt(archive: &[u8], passphrase: &str) -> Result<Self> {
+        let body = archive
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| anyhow!("Not an EnvMesh backup archive"))?;
+        if body.len() < SALT_LEN {
+            return Err(anyhow!("Backup archive is truncated"));
+        }
+
+        let (salt, ciphertext) = body.split_at(SALT_LEN);
+        let plaintext = Crypto::with_salt(passphrase, salt)?
+            .decrypt(ciphertext)
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted backup"))?;
+
+        let snapshot: Self = serde_json::from_slice(&plaintext)?;
+        if snapshot.version > FORMAT_VERSION {
+            return Err(anyhow!(
+                "Backup format v{} is newer than this EnvMesh (v{})",
+                snapshot.version,
+                FORMAT_VERSION
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Scheduled archives in `dir`, oldest first
+fn scheduled_archives(dir: &Path) -> Result<Vec<(NaiveDateTime, PathBuf)>> {
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let time = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| {
+                name.strip_prefix(ARCHIVE_PREFIX)?
+                    .strip_suffix(ARCHIVE_SUFFIX)
+            })
+            .and_then(|stamp| NaiveDateTime::parse_from_str(stamp, ARCHIVE_TIME_FORMAT).ok());
+        if let Some(time) = time {
+            archives.push((time, path));
+        }
+    }
+
+    archives.sort();
+    Ok(archives)
+}
+
+/// Unix time of the newest scheduled archive in `dir`
+pub fn last_backup(dir: &Path) -> Option<i64> {
+    scheduled_archives(dir)
+        .ok()?
+        .last()
+        .map(|(time, _)| time.and_utc().timestamp())
+}
+
+/// Writes full snapshots to `[backup] dir` on schedule and prunes old ones
+#[derive(Clone)]
+pub struct BackupScheduler {
+    dir: PathBuf,
+    schedule: Schedule,
+    keep: usize,
+    passphrase: String,
+    storage: StoragePool,
+    machine_id: String,
+}
+
+impl BackupScheduler {
+    pub fn new(
+        config: &BackupConfig,
+        passphrase: String,
+        storage: StoragePool,
+        machine_id: String,
+    ) -> Result<Self> {
+        let dir = crate::wsl::translate_path(&config.dir);
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            schedule: Schedule::parse(&config.schedule)?,
+            keep: config.keep.max(1),
+            passphrase,
+            storage,
+            machine_id,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// When the next backup will run
+    pub fn next_run(&self) -> Option<chrono::DateTime<Local>> {
+        self.schedule.next_after(Local::now())
+    }
+
+    /// Back up on every scheduled time, forever
+    pub async fn run(self) {
+        while let Some(next) = self.next_run() {
+            let wait = (next - Local::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            match self.backup_now().await {
+                Ok(path) => tracing::info!("Scheduled backup written to {}", path.display()),
+                Err(e) => tracing::error!("Scheduled backup failed: {}", e),
+            }
+        }
+        tracing::warn!("Backup schedule never fires again; scheduled backups stopped");
+    }
+
+    async fn backup_now(&self) -> Result<PathBuf> {
+        let machine_id = self.machine_id.clone();
+        let snapshot = self
+            .storage
+            .read(move |s| Snapshot::capture(s, &machine_id, None))
+            .await?;
+        self.archive(&snapshot)
+    }
+
+    /// Write `snapshot` as the newest scheduled archive, then prune
+    pub fn archive(&self, snapshot: &Snapshot) -> Result<PathBuf> {
+        let name = format!(
+            "{}{}{}",
+            ARCHIVE_PREFIX,
+            Utc::now().format(ARCHIVE_TIME_FORMAT),
+            ARCHIVE_SUFFIX
+        );
+        let path = self.dir.join(name);
+
+        // Write then rename, so a crash never leaves a truncated archive
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, snapshot.encrypt(&self.passphrase)?)?;
+        std::fs::rename(&partial, &path)?;
+
+        self.prune()?;
+        Ok(path)
+    }
+
+    /// Delete all but the newest `keep` scheduled archives
+    fn prune(&self) -> Result<()> {
+        let archives = scheduled_archives(&self.dir)?;
+        let excess = archives.len().saturating_sub(self.keep);
+        for (_, path) in archives.into_iter().take(excess) {
+            std::fs::remove_file(&path)?;
+            tracing::info!("Removed old backup {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_storage() -> EnvStorage {
+        EnvStorage::new(PathBuf::from(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn test_encrypted_round_trip_into_fresh_store() {
+        let source = memory_storage();
+        source
+            .set("prod/DB_URL", "postgres://db", "machine-a")
+            .unwrap();
+        source.set("OLD", "gone", "machine-a").unwrap();
+        source.delete("OLD", "machine-a").unwrap();
+        source.set_tags("prod/DB_URL", &["db".to_string()]).unwrap();
+
+        let archive = Snapshot::capture(&source, "machine-a", None)
+            .unwrap()
+            .encrypt("correct horse")
+            .unwrap();
+        assert!(Snapshot::decrypt(&archive, "wrong horse").is_err());
+
+        let snapshot = Snapshot::decrypt(&archive, "correct horse").unwrap();
+        let target = memory_storage();
+        assert_eq!(snapshot.restore(&target).unwrap(), 2);
+        assert_eq!(
+            target.get("prod/DB_URL").unwrap().unwrap().0,
+            "postgres://db"
+        );
+        assert_eq!(target.history("OLD").unwrap().len(), 2);
+        assert_eq!(target.tags("prod/DB_URL").unwrap(), vec!["db".to_string()]);
+
+        // Replaying the same backup changes nothing
+        assert_eq!(snapshot.restore(&target).unwrap(), 0);
+        assert_eq!(target.history("OLD").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rebuild_keeps_every_machines_rows() {
+        let storage = memory_storage();
+        storage.set("MINE", "1", "machine-old-run").unwrap();
+        storage
+            .apply_remote("THEIRS", "2", 100, "machine-b", false)
+            .unwrap();
+        storage.set("GONE", "3", "machine-b").unwrap();
+        storage.delete("GONE", "machine-b").unwrap();
+        storage.set_tags("THEIRS", &["db".to_string()]).unwrap();
+
+        let snapshot = Snapshot::capture(&storage, "machine-new-run", None).unwrap();
+        assert_eq!(snapshot.rebuild(&storage).unwrap(), 3);
+        assert_eq!(storage.get("MINE").unwrap().unwrap().0, "1");
+        assert_eq!(storage.get("THEIRS").unwrap().unwrap().0, "2");
+        assert!(storage.is_deleted("GONE").unwrap());
+        assert_eq!(storage.history("GONE").unwrap().len(), 2);
+        assert_eq!(storage.tags("THEIRS").unwrap(), vec!["db".to_string()]);
+        assert!(storage.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_incremental_only_holds_newer_changes() {
+        let storage = memory_storage();
+        storage
+            .apply_remote("OLD", "1", 500, "machine-b", false)
+            .unwrap();
+        let full = Snapshot::capture(&storage, "machine-a", None).unwrap();
+        let base = full.base().unwrap();
+
+        // Arrives after the full backup, stamped long before it
+        storage
+            .apply_remote("LATE", "2", 100, "machine-b", false)
+            .unwrap();
+        let snapshot = Snapshot::capture(&storage, "machine-a", Some(&base)).unwrap();
+        let keys: Vec<_> = snapshot.vars.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, vec!["LATE"]);
+        assert_eq!(snapshot.history.len(), 1);
+        assert_eq!(snapshot.since, Some(full.created_at));
+
+        // Nothing since: an empty incremental that still chains on
+        let next = Snapshot::capture(&storage, "machine-a", Some(&snapshot.base().unwrap()));
+        assert!(next.unwrap().vars.is_empty());
+
+        // History IDs are this store's own
+        assert!(Snapshot::capture(&storage, "machine-b", Some(&base)).is_err());
+        let legacy = Snapshot {
+            history_id: 0,
+            ..full
+        };
+        assert!(legacy.base().is_err());
+    }
+
+    #[test]
+    fn test_retention_keeps_newest() {
+        let dir = std::env::temp_dir().join(format!("envmesh-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "envmesh-20260101-030000.bak",
+            "envmesh-20260102-030000.bak",
+            "envmesh-20260103-030000.bak",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let config = BackupConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            schedule: default_schedule(),
+            keep: 2,
+            passphrase_env: default_passphrase_env(),
+        };
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let scheduler =
+            BackupScheduler::new(&config, "pass".into(), storage, "machine-a".into()).unwrap();
+        scheduler.prune().unwrap();
+
+        assert!(!dir.join("envmesh-20260101-030000.bak").exists());
+        assert!(dir.join("envmesh-20260102-030000.bak").exists());
+        assert!(dir.join("notes.txt").exists());
+        assert_eq!(
+            last_backup(&dir),
+            Some(
+                NaiveDateTime::parse_from_str("20260103-030000", ARCHIVE_TIME_FORMAT)
+                    .unwrap()
+                    .and_utc()
+                    .timestamp()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+// LAN discovery by UDP broadcast, for networks that block mDNS: clients
+// broadcast a query on `[discovery] broadcast_port` and the LAN server answers
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::access::PeerIdentity;
+use crate::election::{PeerId, ServerInfo};
+use crate::version::PeerVersion;
+
+pub const DEFAULT_BEACON_PORT: u16 = 8766;
+
+/// How long a client waits for a server to answer
+pub const BEACON_WAIT: Duration = Duration::from_secs(1);
+
+/// Queries are repeated this often while waiting, as UDP may drop them
+const QUERY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Every beacon starts with this, so stray broadcasts on the port are ignored
+const MAGIC: &[u8] = b"ENVMESH1";
+
+/// `[discovery]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Look for LAN servers by UDP broadcast when mDNS finds none (and
+    /// answer such queries while we are the server)
+    pub broadcast: bool,
+    pub broadcast_port: u16,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            broadcast: false,
+            broadcast_port: DEFAULT_BEACON_PORT,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "beacon", rename_all = "snake_case")]
+enum Beacon {
+    Query,
+    Server {
+        peer_id: PeerId,
+        port: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<PeerIdentity>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<PeerVersion>,
+    },
+}
+
+impl Beacon {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut packet = MAGIC.to_vec();
+        serde_json::to_writer(&mut packet, self)?;
+        Ok(packet)
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        serde_json::from_slice(packet.strip_prefix(MAGIC)?).ok()
+    }
+}
+
+/// Answers queries for as long as it lives
+pub struct Responder {
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+impl Responder {
+    /// Answer queries on `port` with our peer ID, identity, LAN server port
+    /// and build
+    pub fn start(
+        port: u16,
+        peer_id: PeerId,
+        identity: Option<PeerIdentity>,
+        server_port: u16,
+    ) -> Result<Self> {
+        let socket =
+            bind(port).map_err(|e| anyhow!("Failed to bind beacon port {}: {}", port, e))?;
+        let port = socket.local_addr()?.port();
+        let answer = Beacon::Server {
+            peer_id,
+            port: server_port,
+            identity,
+            version: Some(PeerVersion::ours()),
+        }
+        .encode()?;
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::debug!("Beacon receive error: {}", e);
+                        continue;
+                    }
+                };
+                if Beacon::decode(&buf[..len]) != Some(Beacon::Query) {
+                    continue;
+                }
+                tracing::debug!("Answering beacon query from {}", from);
+                if let Err(e) = socket.send_to(&answer, from).await {
+                    tracing::debug!("Failed to answer beacon query from {}: {}", from, e);
+                }
+            }
+        });
+        tracing::info!("Answering broadcast discovery on UDP port {}", port);
+        Ok(Self { port, task })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Broadcast a query on `port` and return the first server to answer
+/// within `wait`
+pub async fn discover(port: u16, wait: Duration) -> Result<Option<ServerInfo>> {
+    discover_at(&broadcast_targets(port), wait).await
+}
+
+async fn discover_at(targets: &[SocketAddr], wait: Duration) -> Result<Option<ServerInfo>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let query = Beacon::Query.encode()?;
+
+    let search = async {
+        let mut resend = tokio::time::interval(QUERY_INTERVAL);
+        let mut buf = [0u8; 512];
+        loop {
+            tokio::select! {
+                _ = resend.tick() => {
+                    for target in targets {
+                        if let Err(e) = socket.send_to(&query, target).await {
+                            tracing::debug!("Failed to send beacon query to {}: {}", target, e);
+                        }
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    // Unanswered queries can come back as ICMP errors
+                    let Ok((len, from)) = received else {
+                        continue;
+                    };
+                    if let Some(Beacon::Server { peer_id, port, identity, version }) = Beacon::decode(&buf[..len]) {
+                        if let Some(warning) = version.as_ref().and_then(PeerVersion::warning) {
+                            tracing::warn!("LAN server {} {}", from, warning);
+                        }
+                        return ServerInfo {
+                            peer_id,
+                            address: from.ip(),
+                            port,
+                            identity,
+                        };
+                    }
+                }
+            }
+        }
+    };
+    Ok(tokio::time::timeout(wait, search).await.ok())
+}
+
+/// The limited broadcast address plus each interface's own: the former
+/// only leaves by the default route on multi-homed machines
+fn broadcast_targets(port: u16) -> Vec<SocketAddr> {
+    let mut targets = vec![SocketAddr::from((Ipv4Addr::BROADCAST, port))];
+    if let Ok(interfaces) = if_addrs::get_if_addrs() {
+        for iface in interfaces {
+            if let if_addrs::IfAddr::V4(v4) = iface.addr {
+                if let Some(broadcast) = v4.broadcast {
+                    let target = SocketAddr::new(IpAddr::V4(broadcast), port);
+                    if !targets.contains(&target) {
+                        targets.push(target);
+                    }
+                }
+            }
+        }
+    }
+    targets
+}
+
+fn bind(port: u16) -> std::io::Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // A restarted server gets the port straight back
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacons_need_the_magic() {
+        let query = Beacon::Query.encode().unwrap();
+        assert_eq!(Beacon::decode(&query), Some(Beacon::Query));
+        assert_eq!(Beacon::decode(br#"{"beacon":"query"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn test_discover_finds_responder() {
+        let identity = PeerIdentity {
+            machine_id: "machine-a".to_string(),
+            device_key: "key-a".to_string(),
+        };
+        let responder =
+            Responder::start(0, "server-peer".to_string(), Some(identity.clone()), 8765).unwrap();
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, responder.port()));
+
+        let server = discover_at(&[target], BEACON_WAIT).await.unwrap().unwrap();
+        assert_eq!(server.peer_id, "server-peer");
+        assert_eq!(server.address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(server.port, 8765);
+        assert_eq!(server.identity, Some(identity));
+
+        drop(responder);
+        tokio::task::yield_now().await;
+        let wait = Duration::from_millis(300);
+        assert!(discover_at(&[target], wait).await.unwrap().is_none());
+    }
+}
@@ -1,41 +1,62 @@
 // EnvMesh CLI - Command-line interface for interacting with daemon
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-
-#[cfg(unix)]
-use std::path::PathBuf;
-
-#[cfg(unix)]
-use tokio::net::UnixStream;
-
-#[cfg(windows)]
-use tokio::net::TcpStream;
-
-#[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Delete { key: String },
-    List,
-    Peers,
-    Sync,
-    Shutdown,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-enum Response {
-    Value(Option<String>),
-    Success,
-    Error(String),
-    List(Vec<(String, String)>),
-    Peers(Vec<(String, String)>),
-}
+use clap::{Args, Parser, Subcommand};
+use envmesh::agent::{self, AgentConfig, AgentReply, AgentRequest, AgentStatus};
+use envmesh::autostart::{self, AutostartEntry};
+use envmesh::backup::Snapshot;
+use envmesh::bundle::Bundle;
+use envmesh::ci::{self, CiFormat};
+use envmesh::clock;
+use envmesh::config;
+use envmesh::context;
+use envmesh::doctor::{self, Severity};
+use envmesh::dotenv;
+use envmesh::envfiles;
+use envmesh::files::{FileBlob, FileInfo};
+use envmesh::health::HealthStatus;
+use envmesh::ipc::{self, DaemonReader, DaemonWriter, Endpoint};
+use envmesh::keys;
+use envmesh::links::PeerLink;
+use envmesh::manifest::{self, ExportManifest};
+use envmesh::mask;
+use envmesh::protocol::{ChangePreview, Command, DaemonStatus, Response, PROTOCOL_VERSION};
+use envmesh::refs;
+use envmesh::relay;
+use envmesh::saas::{self, Vendor};
+use envmesh::scan::{self, Scanner};
+use envmesh::secretgen::{self, Charset};
+use envmesh::share::{self, Share};
+use envmesh::shellsession::{self, ShellSession};
+use envmesh::sops;
+use envmesh::storage::{self, FsckReport, ImportAction, ListQuery, StoreStats, SyncReport};
+use envmesh::tfvars::{self, TfType};
+use envmesh::users::{Role, UserConfig};
+use envmesh::version::Compatibility;
+use envmesh::wsl;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
 
 #[derive(Parser)]
 #[command(name = "envmesh-cli")]
 #[command(about = "P2P mesh network for environment variable sync", long_about = None)]
 struct Cli {
+    /// Daemon endpoint (unix:PATH, pipe:NAME, HOST:PORT, or windows-host:PORT from WSL)
+    #[arg(long, global = true)]
+    endpoint: Option<String>,
+
+    /// The daemon's data directory, to find its socket and database (else
+    /// ENVMESH_DATA_DIR, `[storage] path` or the platform default)
+    #[arg(long, global = true, conflicts_with = "instance")]
+    data_dir: Option<String>,
+
+    /// Talk to the daemon started with `envmesh-daemon --instance NAME`
+    #[arg(long, global = true)]
+    instance: Option<String>,
+
+    /// Use this `[[contexts]]` entry instead of the current one
+    #[arg(long, global = true)]
+    context: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -46,6 +67,10 @@ enum Commands {
     Get {
         /// The key to retrieve
         key: String,
+        /// If the mesh doesn't have it, look in the daemon's environment and
+        /// its `[fallback] dotenv` file
+        #[arg(long)]
+        fallback_env: bool,
     },
     /// Set an environment variable
     Set {
@@ -53,193 +78,1465 @@ enum Commands {
         key: String,
         /// The value to set (optional if using KEY=value format)
         value: Option<String>,
+        /// Type the value at a hidden prompt, keeping it out of shell history
+        /// and `ps`
+        #[arg(long, conflicts_with_all = ["value", "stdin"])]
+        prompt: bool,
+        /// Read the value from stdin (one trailing newline is dropped)
+        #[arg(long, conflicts_with = "value")]
+        stdin: bool,
+        /// Remove the key's lock first, even one set on another machine
+        #[arg(long)]
+        force_unlock: bool,
+        /// Only override KEY in this `envmesh-cli shell` session; nothing is
+        /// stored or synced
+        #[arg(long, conflicts_with = "force_unlock")]
+        session: bool,
+    },
+    /// Store a cryptographically random value under KEY and sync it
+    Generate {
+        /// The key to store it under
+        key: String,
+        /// Number of characters
+        #[arg(long, default_value_t = 32)]
+        length: usize,
+        /// alphanumeric, hex, base64 or base64url
+        #[arg(long, default_value = "alphanumeric")]
+        charset: Charset,
+        /// Replace the value if KEY already exists
+        #[arg(long)]
+        force: bool,
+        /// Also print the value
+        #[arg(long)]
+        show: bool,
     },
     /// Delete an environment variable
     Delete {
-        /// The key to delete
+        /// The key to delete (a wildcard pattern with --pattern)
         key: String,
+        /// Delete every variable matching KEY, e.g. 'staging/*'
+        #[arg(long)]
+        pattern: bool,
+        /// Show what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Remove the key's lock first, even one set on another machine
+        #[arg(long, conflicts_with = "pattern")]
+        force_unlock: bool,
+    },
+    /// Rename a variable, or move it to another namespace
+    Rename {
+        /// The key to rename
+        from: String,
+        /// Its new key
+        #[arg(required_unless_present = "namespace")]
+        to: Option<String>,
+        /// Move into this namespace under the same variable name
+        #[arg(short, long, conflicts_with = "to")]
+        namespace: Option<String>,
+    },
+    /// Copy variables from one namespace (profile) to another, e.g. to
+    /// promote dev configuration to staging
+    Copy {
+        /// Namespace to copy from
+        #[arg(long)]
+        from_profile: String,
+        /// Namespace to copy into (existing values are overwritten)
+        #[arg(long)]
+        to_profile: String,
+        /// Variable names to copy
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        keys: Vec<String>,
+        /// Copy every variable in the source namespace
+        #[arg(long)]
+        all: bool,
+        /// With --all, only variables whose names start with this
+        #[arg(long, requires = "all")]
+        prefix: Option<String>,
+        /// Show what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List environment variables
+    List(ListArgs),
+    /// Replace the tags on a variable (no tags clears them)
+    Tag {
+        /// The key to tag
+        key: String,
+        /// Tags to set
+        tags: Vec<String>,
+    },
+    /// Refuse changes to a variable, here and on every other machine, until
+    /// it is unlocked (only the machine that created it can lock it)
+    Lock { key: String },
+    /// Remove a variable's lock
+    Unlock {
+        key: String,
+        /// Remove a lock set on another machine
+        #[arg(long)]
+        force_unlock: bool,
+    },
+    /// Import variables from a .env file ("-" reads stdin)
+    Import {
+        /// Path to the .env file
+        file: String,
+        /// Namespace to import into
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// dotenv, sops for a SOPS-encrypted YAML, JSON or .env file
+        /// (decrypted with the `sops` binary), or doppler/infisical for their
+        /// JSON exports (`doppler secrets download --format json`,
+        /// `infisical export --format json`)
+        #[arg(long, default_value = "dotenv", value_parser = ["dotenv", "sops", "doppler", "infisical"])]
+        format: String,
+        /// Show what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Store files (certificates, kubeconfigs, SSH keys) alongside variables
+    File {
+        #[command(subcommand)]
+        action: FileAction,
+    },
+    /// Write an encrypted backup of all variables, history and tags
+    Backup {
+        /// Archive to write
+        #[arg(short, long)]
+        out: PathBuf,
+        /// Only include changes made since this earlier backup
+        #[arg(long)]
+        incremental_from: Option<PathBuf>,
+    },
+    /// Merge backups into the store (a full backup, then its incrementals)
+    Restore {
+        /// Archives to restore, in order
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Show what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Carry namespaces to a network that can't connect, as an encrypted file
+    Bundle {
+        #[command(subcommand)]
+        action: BundleAction,
     },
-    /// List all environment variables
-    List,
     /// Export variables in shell format
     Export {
-        /// Shell format (bash, zsh, powershell)
+        /// Shell format (bash, zsh, fish, powershell)
         #[arg(short, long, default_value = "bash")]
         shell: String,
+        /// Export this namespace's variables under their bare names
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Also print GitHub Actions `::add-mask::` lines (to stderr, so
+        /// eval still works) so the values never show up in build logs
+        #[arg(long)]
+        redact_log: bool,
+        /// Also unset variables exported last time that have since left
+        /// the mesh
+        #[arg(long, conflicts_with = "format")]
+        prune: bool,
+        /// Print a SOPS-encrypted YAML document, JSON for `doppler secrets
+        /// upload` or in `infisical export` form, a terraform.tfvars
+        /// document, a .env file, a flat JSON object, a systemd
+        /// EnvironmentFile or a launchd EnvironmentVariables fragment,
+        /// instead of shell lines
+        #[arg(long, value_parser = ["sops-yaml", "doppler", "infisical", "tfvars", "dotenv", "json", "systemd", "launchd"])]
+        format: Option<String>,
+        /// age public key that can decrypt the SOPS export (repeatable)
+        #[arg(long, required_if_eq("format", "sops-yaml"))]
+        age_recipient: Vec<String>,
+        /// With `--format tfvars`, print shell lines exporting each variable
+        /// under this prefix (TF_VAR_) instead of a tfvars document
+        #[arg(long, requires = "format")]
+        prefix: Option<String>,
+    },
+    /// Start a shell with the mesh's variables loaded, where `set --session`
+    /// overrides last until it exits
+    Shell {
+        /// Load this namespace's variables under their bare names
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Shell to run (default: $SHELL)
+        #[arg(long)]
+        program: Option<String>,
+    },
+    /// Run a command with each `envmesh://KEY` value in its environment
+    /// replaced by the variable it names
+    Run {
+        /// Look up references without a namespace in this one
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Add a .env file's variables (references included) to the
+        /// environment; may be repeated
+        #[arg(long = "env-file")]
+        env_files: Vec<PathBuf>,
+        /// The command and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Load mesh variables into CI jobs
+    Ci {
+        #[command(subcommand)]
+        action: CiAction,
+    },
+    /// Print GitHub Actions `::add-mask::` lines for every value, so a CI
+    /// job's log shows *** instead of mesh secrets
+    Mask {
+        /// Only this namespace's values
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Check files, or what `git commit` would add, for values stored in
+    /// the mesh; exits 1 on a match (values are compared by digest and
+    /// never printed)
+    Scan {
+        /// Files or directories to check
+        #[arg(required_unless_present = "staged")]
+        paths: Vec<PathBuf>,
+        /// Check the lines staged for commit instead
+        #[arg(long, conflicts_with = "paths")]
+        staged: bool,
+    },
+    /// Manage git hooks in the current repository
+    Hook {
+        #[command(subcommand)]
+        action: HookAction,
     },
     /// Show connected peers
-    Peers,
+    Peers {
+        /// Show the envmesh version and protocol each peer runs instead
+        #[arg(long)]
+        versions: bool,
+    },
+    /// Manage which peers this machine deals with
+    Peer {
+        #[command(subcommand)]
+        action: PeerAction,
+    },
+    /// Manage the people sharing the mesh, their roles and machines
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    /// Act on another machine by its device key
+    Device {
+        #[command(subcommand)]
+        action: DeviceAction,
+    },
+    /// Hand one variable to someone outside the mesh as an encrypted link
+    /// the cloud server hands out only once
+    Share {
+        key: String,
+        /// How long the link works: 30m, 1h, 7d (at most 7 days)
+        #[arg(long, default_value = "1h", value_parser = share::parse_expires)]
+        expires: u64,
+        /// Print a self-contained code instead (no cloud server needed),
+        /// which anyone holding it can open until it expires
+        #[arg(long)]
+        offline: bool,
+    },
+    /// Open a share code or link and print the value
+    Claim {
+        code: String,
+        /// Store the variable in this machine's mesh instead
+        #[arg(long)]
+        set: bool,
+    },
+    /// Show daemon connection, store and backup status
+    Status,
+    /// Count keys, history and storage, and who wrote how much in the last
+    /// hour
+    Stats,
+    /// Check the store for damage, duplicate rows and broken invariants
+    Fsck {
+        /// Fix what was found; a store that can't be fixed in place is
+        /// cleared and rebuilt from peers, keeping this machine's changes
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Print the daemon's public device key, for other machines'
+    /// `[security] trusted_keys`
+    DeviceKey,
     /// Trigger manual sync
-    Sync,
+    Sync {
+        /// Show what would be sent, and to which peers, without sending
+        #[arg(long)]
+        dry_run: bool,
+        /// Send every change, not only those the server hasn't had yet
+        #[arg(long)]
+        full: bool,
+        /// Then show what the cycle pushed, pulled, conflicted on and skipped
+        #[arg(long, conflicts_with = "dry_run")]
+        report: bool,
+    },
+    /// Recover a confused node: forget sync progress and pull one peer's
+    /// whole state again
+    Resync {
+        /// The peer to pull from: its machine ID or device key
+        #[arg(long)]
+        from: String,
+        /// Also discard this machine's variables and history first, so the
+        /// peer's copy replaces ours (changes only made here are lost)
+        #[arg(long)]
+        overwrite_local: bool,
+    },
+    /// Credentials that expire, from `expires:DATE` tags, JWTs and
+    /// certificates, soonest first
+    Expiring {
+        /// Only those expiring within this many days (expired ones included)
+        #[arg(long)]
+        days: Option<u64>,
+    },
+    /// Keys the daemon rotates on schedule (`[[rotations]]`)
+    Rotation {
+        #[command(subcommand)]
+        action: RotationAction,
+    },
+    /// Deleted variables, and bringing them back
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+    /// Review incoming changes held as risky (`[quarantine]`)
+    Quarantine {
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+    /// Lease single secrets through the agent socket, and answer its
+    /// prompts (`[agent]`)
+    Agent {
+        #[command(subcommand)]
+        action: AgentAction,
+    },
+    /// Show why the cloud health monitor keeps this machine on the cloud or
+    /// the LAN, and steer it
+    Health {
+        #[command(subcommand)]
+        action: Option<HealthAction>,
+    },
     /// Shutdown the daemon
     Shutdown,
+    /// Show the CLI and daemon versions
+    Version,
+    /// Check the daemon, config, database and network for common problems
+    Doctor,
+    /// Stream connection and sync events as they happen
+    Watch,
+    /// Switch between daemons and default namespaces (`[[contexts]]`)
+    Context {
+        #[command(subcommand)]
+        action: ContextAction,
+    },
+    /// Start the daemon automatically at login
+    Autostart {
+        #[command(subcommand)]
+        action: AutostartAction,
+    },
+    /// Set up a relay that hosts several teams' meshes
+    Relay {
+        #[command(subcommand)]
+        action: RelayAction,
+    },
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Only keys containing this text (case-insensitive)
+    #[arg(short, long)]
+    search: Option<String>,
+    /// Only keys starting with this prefix
+    #[arg(short, long)]
+    prefix: Option<String>,
+    /// Only keys with this tag
+    #[arg(short, long)]
+    tag: Option<String>,
+    /// Only keys in this namespace
+    #[arg(short, long)]
+    namespace: Option<String>,
+    /// Maximum number of results
+    #[arg(short, long)]
+    limit: Option<usize>,
+    /// Skip this many results
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+}
+
+impl From<ListArgs> for ListQuery {
+    fn from(args: ListArgs) -> Self {
+        Self {
+            search: args.search,
+            prefix: args.prefix,
+            tag: args.tag,
+            namespace: args.namespace,
+            limit: args.limit,
+            offset: args.offset,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum CiAction {
+    /// Write variables where the CI system hands them to later steps
+    Export {
+        /// github-env (appends to $GITHUB_ENV, values masked) or
+        /// gitlab-dotenv (a dotenv report artifact)
+        #[arg(long)]
+        format: CiFormat,
+        /// Only this namespace (profile), under bare names
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Write here instead of $GITHUB_ENV or ./envmesh.env
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum FileAction {
+    /// Store a file under NAME (synced, never exported to the shell)
+    Put {
+        name: String,
+        /// File to read
+        path: PathBuf,
+    },
+    /// Write a stored file to --out (0600 on Unix), or to stdout
+    Get {
+        name: String,
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// List stored files
+    List,
+    /// Delete a stored file
+    Rm { name: String },
+}
+
+#[derive(Subcommand)]
+enum BundleAction {
+    /// Write this machine's changes (with their sequence numbers) to a file
+    Export {
+        /// Bundle to write
+        #[arg(short, long)]
+        out: PathBuf,
+        /// Only this namespace (repeatable; default: all, or with --since
+        /// the earlier bundle's)
+        #[arg(short, long = "namespace")]
+        namespaces: Vec<String>,
+        /// Only changes made after this earlier bundle (its ID)
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// Merge a bundle from another network, and pass it on to peers here
+    Import {
+        /// Bundle to read
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum PeerAction {
+    /// Refuse a peer's connections and changes, now and after restarts
+    /// (`[security] blocked_peers`)
+    Block {
+        /// Device key (`envmesh-cli device-key` on that machine) or machine ID
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserAction {
+    /// Show users, their roles and enrolled machines
+    List,
+    /// Add a user, or change one's role and namespaces. The first user must
+    /// be an admin, and is enrolled with this machine.
+    Set {
+        name: String,
+        /// admin, writer or reader
+        #[arg(long)]
+        role: Role,
+        /// Namespace the user may use (repeatable; none: all)
+        #[arg(short, long)]
+        namespace: Vec<String>,
+    },
+    /// Enroll a machine for a user
+    Enroll {
+        name: String,
+        /// Device key (`envmesh-cli device-key` on that machine); default:
+        /// this machine's
+        device: Option<String>,
+        /// Only for this long (e.g. 7d for a contractor); afterwards every
+        /// machine treats it as unenrolled
+        #[arg(long, value_parser = clock::parse_duration)]
+        expires: Option<u64>,
+    },
+    /// Take a machine away from its user (a lost or retired laptop)
+    Unenroll { device: String },
+    /// Remove a user; their machines are no longer enrolled
+    Remove { name: String },
+}
+
+#[derive(Subcommand)]
+enum DeviceAction {
+    /// Revoke a machine's key on every machine of the mesh, for good (a
+    /// stolen laptop). Needs an admin once there are users.
+    Revoke {
+        /// Device key (`envmesh-cli device-key` on that machine)
+        device: String,
+        /// Also have the machine erase its database when it next hears
+        /// from the mesh (needs users or `[security] trusted_keys`)
+        #[arg(long)]
+        wipe: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum RelayAction {
+    /// Make a mesh token: the token goes to the team, the printed
+    /// `[[relay.meshes]]` entry into the relay's config
+    Token {
+        /// Mesh name, as the relay logs it
+        #[arg(long)]
+        mesh: String,
+        /// Account the mesh belongs to
+        #[arg(long, default_value = "")]
+        account: String,
+        /// Most clients connected to the mesh at once
+        #[arg(long, default_value_t = 50)]
+        max_connections: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum HookAction {
+    /// Make .git/hooks/pre-commit run `envmesh-cli scan --staged`
+    Install {
+        /// Replace a pre-commit hook envmesh didn't write
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum RotationAction {
+    /// Show each policy, when its key was last written and when it is due
+    List,
+    /// Show the newest rotation attempts and their errors
+    Log {
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Rotate a key now, whether it is due or not
+    Run { key: String },
+}
+
+#[derive(Subcommand)]
+enum TrashAction {
+    /// Show deleted variables, most recently deleted first
+    List,
+    /// Set a deleted variable back to its last value, on every machine
+    Restore { key: String },
+}
+
+#[derive(Subcommand)]
+enum QuarantineAction {
+    /// Show held changes and why they were held (values are not shown)
+    List,
+    /// Apply held changes
+    Approve {
+        /// IDs from `quarantine list`
+        #[arg(required_unless_present = "all")]
+        ids: Vec<i64>,
+        /// Every held change
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+    /// Drop held changes without applying them
+    Reject {
+        #[arg(required_unless_present = "all")]
+        ids: Vec<i64>,
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum AgentAction {
+    /// Print a variable leased from the agent (ENVMESH_AGENT_SOCK or
+    /// `[agent] socket`), if its rules allow
+    Get { key: String },
+    /// Show requests waiting for approval, current leases and recent
+    /// decisions
+    Status {
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Let a waiting request have its value
+    Approve { id: u64 },
+    /// Refuse a waiting request
+    Deny { id: u64 },
+}
+
+#[derive(Subcommand)]
+enum ContextAction {
+    /// List contexts, marking the current one
+    List,
+    /// Print the current context's name
+    Current,
+    /// Make NAME the current context (saved as `[ipc] context`)
+    Use { name: String },
+    /// Go back to the config's own endpoint and data directory
+    Unset,
+}
+
+#[derive(Subcommand)]
+enum AutostartAction {
+    /// Register the daemon to start at login
+    Enable,
+    /// Remove the login registration
+    Disable,
+    /// Show whether the daemon starts at login
+    Status,
+}
+
+#[derive(Subcommand)]
+enum HealthAction {
+    /// Current mode, failed checks in a row and the last check (default)
+    Status,
+    /// Check the cloud every SECS seconds until the daemon restarts
+    Interval { secs: u64 },
+    /// Keep checking, but don't fail over to the LAN or back
+    Pause,
+    /// Fail over and back again
+    Resume,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
 
-    #[cfg(unix)]
-    let data_dir = dirs::data_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("envmesh");
+    // Local commands that don't need a running daemon
+    if let Commands::Autostart { action } = &cli.command {
+        return handle_autostart(action);
+    }
+    if let Commands::Hook {
+        action: HookAction::Install { force },
+    } = &cli.command
+    {
+        return handle_hook_install(*force);
+    }
+    if let Commands::Relay {
+        action:
+            RelayAction::Token {
+                mesh,
+                account,
+                max_connections,
+            },
+    } = &cli.command
+    {
+        let token = relay::generate_token()?;
+        println!(
+            "# Give the team this token ([client] mesh_token or ${}):",
+            relay::MESH_TOKEN_ENV
+        );
+        println!("#   {}", token);
+        println!("# and add this to the relay's config:");
+        println!("[[relay.meshes]]");
+        println!("name = {:?}", mesh);
+        println!("account = {:?}", account);
+        println!("token_sha256 = \"{}\"", relay::hash_token(&token));
+        println!("max_connections = {}", max_connections);
+        return Ok(());
+    }
 
-    #[cfg(unix)]
-    let socket_path = data_dir.join("daemon.sock");
+    if let Commands::Claim { code, set: false } = &cli.command {
+        let share = claim_share(code).await?;
+        eprintln!("🔑 {}", share.key);
+        println!("{}", share.value);
+        return Ok(());
+    }
 
-    // Platform-specific connection
-    #[cfg(unix)]
-    {
-        // Check if daemon is running
-        if !socket_path.exists() {
-            eprintln!("❌ Daemon not running");
-            eprintln!("\nStart the daemon first:");
-            eprintln!("  envmesh-daemon");
-            std::process::exit(1);
+    if let Commands::Context { action } = &cli.command {
+        return handle_context(action, cli.context.as_deref());
+    }
+
+    let config = config::Config::load_default().unwrap_or_default();
+    let context = context::select(
+        &config.contexts,
+        cli.context.as_deref(),
+        config.ipc.context.as_deref(),
+    )?;
+    let mut data_dir = config::data_dir_flag(cli.data_dir.as_deref(), cli.instance.as_deref())?;
+    let mut endpoint_flag = cli.endpoint.clone();
+    if let Some(context) = context {
+        (endpoint_flag, data_dir) = context.daemon(endpoint_flag, data_dir)?;
+        if let Some(namespace) = &context.namespace {
+            default_namespace(&mut cli.command, namespace);
         }
+    }
+    let endpoint = ipc::resolve_endpoint(endpoint_flag.as_deref(), data_dir.as_deref())?;
 
-        // Connect to daemon
-        let stream = UnixStream::connect(&socket_path).await?;
-        let (reader, writer) = stream.into_split();
-        let reader = BufReader::new(reader);
+    if let Commands::Doctor = &cli.command {
+        let data_dir = config::data_dir(data_dir.as_deref(), &config);
+        return handle_doctor(&endpoint, &data_dir).await;
+    }
 
-        execute_command(cli.command, socket_path, reader, writer).await?;
+    // Leases come from the agent socket, not the control socket
+    if let Commands::Agent {
+        action: AgentAction::Get { key },
+    } = &cli.command
+    {
+        let data_dir = config::data_dir(data_dir.as_deref(), &config);
+        return handle_agent_get(&config.agent, &data_dir, key).await;
     }
 
-    #[cfg(windows)]
+    // Session overrides never reach the daemon
+    if let Commands::Set {
+        key,
+        value,
+        prompt,
+        stdin,
+        session: true,
+        ..
+    } = cli.command
     {
-        // Connect to TCP daemon
-        let stream = match TcpStream::connect("127.0.0.1:37842").await {
-            Ok(s) => s,
-            Err(_) => {
-                eprintln!("❌ Daemon not running");
-                eprintln!("\nStart the daemon first:");
-                eprintln!("  envmesh-daemon");
-                std::process::exit(1);
+        let data_dir = config::data_dir(data_dir.as_deref(), &config);
+        let (key, value) = set_args(key, value, prompt, stdin)?;
+        return handle_session_set(&data_dir, &key, &value);
+    }
+    if let Commands::Get { key, .. } = &cli.command {
+        if let Some(session) =
+            ShellSession::current(&config::data_dir(data_dir.as_deref(), &config))
+        {
+            if let Some(value) = session.overrides()?.get(key) {
+                println!("{}", value);
+                return Ok(());
             }
-        };
-
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-
-        execute_command_windows(cli.command, reader, writer).await?;
+        }
     }
 
-    Ok(())
+    let (reader, writer) = match endpoint.connect().await {
+        Ok(connection) => connection,
+        Err(_) => {
+            eprintln!("❌ Daemon not running ({})", endpoint);
+            eprintln!("\nStart the daemon first:");
+            eprintln!("  envmesh-daemon");
+            if wsl::is_wsl() {
+                eprintln!("\nTo use the Windows daemon from WSL, set in ~/.envmesh/config.toml:");
+                eprintln!("  [ipc]");
+                eprintln!("  endpoint = \"{}:37842\"", ipc::WINDOWS_HOST);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    execute_command(cli.command, &endpoint, data_dir.as_deref(), reader, writer).await
 }
 
-#[cfg(unix)]
 async fn execute_command(
     cli_command: Commands,
-    socket_path: PathBuf,
-    mut reader: BufReader<tokio::net::unix::OwnedReadHalf>,
-    mut writer: tokio::net::unix::OwnedWriteHalf,
+    endpoint: &Endpoint,
+    data_dir: Option<&str>,
+    mut reader: DaemonReader,
+    mut writer: DaemonWriter,
 ) -> anyhow::Result<()> {
     // Send command
     let command = match cli_command {
-        Commands::Get { key } => Command::Get { key },
-        Commands::Set { key, value } => {
-            // Parse KEY=value format
-            if let Some(val) = value {
-                Command::Set { key, value: val }
-            } else if let Some(eq_pos) = key.find('=') {
-                let (k, v) = key.split_at(eq_pos);
-                Command::Set {
-                    key: k.to_string(),
-                    value: v[1..].to_string(),
-                }
+        Commands::Get { key, fallback_env } => Command::Get {
+            key,
+            fallback: fallback_env,
+        },
+        Commands::Set {
+            key,
+            value,
+            prompt,
+            stdin,
+            force_unlock,
+            ..
+        } => {
+            let (key, value) = set_args(key, value, prompt, stdin)?;
+            Command::Set {
+                key,
+                value,
+                force_unlock,
+            }
+        }
+        Commands::Delete {
+            key,
+            pattern,
+            dry_run,
+            force_unlock,
+        } => {
+            let command = if pattern {
+                Command::DeleteMatching { pattern: key }
             } else {
-                eprintln!("❌ Invalid format. Use: envmesh-cli set KEY value");
-                eprintln!("   or: envmesh-cli set KEY=value");
-                std::process::exit(1);
+                Command::Delete { key, force_unlock }
+            };
+            dry_run_if(dry_run, command)
+        }
+        Commands::Rename {
+            from,
+            to,
+            namespace,
+        } => {
+            // clap requires TO or --namespace
+            let to = to.unwrap_or_else(|| {
+                let namespace = namespace.unwrap_or_default();
+                storage::namespaced_key(&namespace, storage::split_key(&from).1)
+            });
+            Command::Rename { from, to }
+        }
+        Commands::Copy {
+            from_profile,
+            to_profile,
+            keys,
+            all,
+            prefix,
+            dry_run,
+        } => dry_run_if(
+            dry_run,
+            Command::Copy {
+                from: from_profile,
+                to: to_profile,
+                keys,
+                all,
+                prefix,
+            },
+        ),
+        Commands::List(args) => Command::List { query: args.into() },
+        Commands::Tag { key, tags } => Command::Tag { key, tags },
+        Commands::Lock { key } => Command::Lock { key },
+        Commands::Unlock { key, force_unlock } => Command::Unlock {
+            key,
+            force: force_unlock,
+        },
+        Commands::Import {
+            file,
+            namespace,
+            format,
+            dry_run,
+        } => dry_run_if(
+            dry_run,
+            import_command(&file, namespace.as_deref(), &format)?,
+        ),
+        Commands::File { action } => match action {
+            FileAction::Put { name, path } => Command::PutFile {
+                name,
+                blob: FileBlob::new(&std::fs::read(&path)?),
+            },
+            FileAction::Get { name, out } => {
+                handle_file_get(endpoint, name, out.as_deref()).await?;
+                return Ok(());
+            }
+            FileAction::List => Command::ListFiles,
+            FileAction::Rm { name } => Command::DeleteFile { name },
+        },
+        Commands::Backup {
+            out,
+            incremental_from,
+        } => {
+            handle_backup(endpoint, &out, incremental_from.as_deref()).await?;
+            return Ok(());
+        }
+        Commands::Restore { files, dry_run } => {
+            handle_restore(endpoint, &files, dry_run).await?;
+            return Ok(());
+        }
+        Commands::Bundle { action } => {
+            handle_bundle(endpoint, action).await?;
+            return Ok(());
+        }
+        Commands::Export {
+            shell,
+            namespace,
+            format: Some(format),
+            prefix,
+            ..
+        } if format == "tfvars" => {
+            handle_tfvars(endpoint, namespace, prefix.as_deref(), &shell).await?;
+            return Ok(());
+        }
+        Commands::Export {
+            namespace,
+            format: Some(format),
+            age_recipient,
+            prefix,
+            ..
+        } => {
+            if prefix.is_some() {
+                anyhow::bail!("--prefix only applies to --format tfvars");
             }
+            let bare_names = namespace.is_some();
+            let vars: Vec<_> = list_vars(endpoint, namespace)
+                .await?
+                .into_iter()
+                .map(|(key, value)| {
+                    let name = if bare_names {
+                        storage::split_key(&key).1.to_string()
+                    } else {
+                        key
+                    };
+                    (name, value)
+                })
+                .collect();
+            let output = match format.as_str() {
+                "sops-yaml" => sops::encrypt_yaml(&vars, &age_recipient)?,
+                "dotenv" | "json" | "systemd" | "launchd" => {
+                    envfiles::render(format.parse()?, &vars)
+                        .map_err(|e| {
+                            anyhow::anyhow!("{} (export one namespace with --namespace)", e)
+                        })?
+                        .into_bytes()
+                }
+                vendor => saas::render(vendor.parse::<Vendor>()?, &vars)?.into_bytes(),
+            };
+            use std::io::Write;
+            std::io::stdout().write_all(&output)?;
+            return Ok(());
         }
-        Commands::Delete { key } => Command::Delete { key },
-        Commands::List => Command::List,
-        Commands::Export { shell } => {
+        Commands::Export {
+            shell,
+            namespace,
+            redact_log,
+            prune,
+            ..
+        } => {
             // Handle export locally
-            handle_export(socket_path, &shell).await?;
+            let config = config::Config::load_default().unwrap_or_default();
+            let data_dir = config::data_dir(data_dir, &config);
+            // Inside `envmesh-cli shell`, its overrides win
+            let overrides = match ShellSession::current(&data_dir) {
+                Some(session) => session.overrides()?,
+                None => BTreeMap::new(),
+            };
+            let manifest = ExportManifest::new(&data_dir, namespace.as_deref());
+            handle_export(
+                endpoint, &shell, namespace, redact_log, prune, &manifest, overrides,
+            )
+            .await?;
+            return Ok(());
+        }
+        Commands::Shell { namespace, program } => {
+            let config = config::Config::load_default().unwrap_or_default();
+            let data_dir = config::data_dir(data_dir, &config);
+            let code = handle_shell(endpoint, &data_dir, namespace, program).await?;
+            std::process::exit(code);
+        }
+        Commands::Run {
+            namespace,
+            env_files,
+            command,
+        } => {
+            let code = handle_run(endpoint, namespace, env_files, command).await?;
+            std::process::exit(code);
+        }
+        Commands::Ci {
+            action:
+                CiAction::Export {
+                    format,
+                    namespace,
+                    output,
+                },
+        } => {
+            handle_ci_export(endpoint, format, namespace, output).await?;
+            return Ok(());
+        }
+        Commands::Mask { namespace } => {
+            for (_, value) in list_vars(endpoint, namespace).await? {
+                for line in mask::mask_lines(&value) {
+                    println!("{}", line);
+                }
+            }
+            return Ok(());
+        }
+        Commands::Scan { paths, staged } => {
+            handle_scan(endpoint, &paths, staged).await?;
+            return Ok(());
+        }
+        Commands::Peers { versions: false } => Command::Peers,
+        Commands::Peers { versions: true } => {
+            handle_peer_versions(endpoint).await?;
+            return Ok(());
+        }
+        Commands::Peer {
+            action: PeerAction::Block { id },
+        } => Command::BlockPeer { id },
+        Commands::Device {
+            action: DeviceAction::Revoke { device, wipe },
+        } => Command::RevokeDevice { device, wipe },
+        Commands::User { action } => match action {
+            UserAction::List => {
+                handle_user_list(endpoint).await?;
+                return Ok(());
+            }
+            UserAction::Set {
+                name,
+                role,
+                namespace,
+            } => Command::SetUser {
+                name,
+                role,
+                namespaces: namespace,
+            },
+            UserAction::Enroll {
+                name,
+                device,
+                expires,
+            } => Command::EnrollDevice {
+                name,
+                device,
+                expires_secs: expires,
+            },
+            UserAction::Unenroll { device } => Command::UnenrollDevice { device },
+            UserAction::Remove { name } => Command::RemoveUser { name },
+        },
+        Commands::Share {
+            key,
+            expires,
+            offline,
+        } => Command::Share {
+            key,
+            expires_secs: expires,
+            offline,
+        },
+        Commands::Claim { code, .. } => {
+            let Share { key, value, .. } = claim_share(&code).await?;
+            Command::Set {
+                key,
+                value,
+                force_unlock: false,
+            }
+        }
+        Commands::Status => Command::Status,
+        Commands::Stats => Command::Stats,
+        Commands::Fsck { repair } => Command::Fsck { repair },
+        Commands::DeviceKey => {
+            handle_device_key(endpoint).await?;
+            return Ok(());
+        }
+        Commands::Generate {
+            key,
+            length,
+            charset,
+            force,
+            show,
+        } => {
+            handle_generate(endpoint, key, length, charset, force, show).await?;
+            return Ok(());
+        }
+        Commands::Sync {
+            full, report: true, ..
+        } => {
+            handle_sync_report(endpoint, full).await?;
+            return Ok(());
+        }
+        Commands::Sync { dry_run, full, .. } => {
+            let command = if full {
+                Command::FullSync
+            } else {
+                Command::Sync
+            };
+            dry_run_if(dry_run, command)
+        }
+        Commands::Resync {
+            from,
+            overwrite_local,
+        } => {
+            handle_resync(endpoint, from, overwrite_local).await?;
+            return Ok(());
+        }
+        Commands::Expiring { days } => {
+            handle_expiring(endpoint, days).await?;
+            return Ok(());
+        }
+        Commands::Rotation {
+            action: RotationAction::Run { key },
+        } => Command::Rotate { key },
+        Commands::Rotation { action } => {
+            handle_rotation(endpoint, action).await?;
             return Ok(());
         }
-        Commands::Peers => Command::Peers,
-        Commands::Sync => Command::Sync,
+        Commands::Trash {
+            action: TrashAction::List,
+        } => {
+            handle_trash_list(endpoint).await?;
+            return Ok(());
+        }
+        Commands::Trash {
+            action: TrashAction::Restore { key },
+        } => Command::RestoreTrashed { key },
+        Commands::Quarantine { action } => {
+            handle_quarantine(endpoint, action).await?;
+            return Ok(());
+        }
+        Commands::Agent {
+            action: AgentAction::Status { limit },
+        } => {
+            handle_agent_status(endpoint, limit).await?;
+            return Ok(());
+        }
+        Commands::Agent {
+            action: AgentAction::Approve { id },
+        } => Command::AnswerLease { id, allow: true },
+        Commands::Agent {
+            action: AgentAction::Deny { id },
+        } => Command::AnswerLease { id, allow: false },
+        Commands::Health { action } => match action.unwrap_or(HealthAction::Status) {
+            HealthAction::Status => Command::HealthStatus,
+            HealthAction::Interval { secs } => Command::SetHealthCheckInterval { secs },
+            HealthAction::Pause => Command::PauseFailover { paused: true },
+            HealthAction::Resume => Command::PauseFailover { paused: false },
+        },
         Commands::Shutdown => Command::Shutdown,
+        Commands::Version => Command::Version,
+        Commands::Watch => Command::Subscribe,
+        Commands::Autostart { .. }
+        | Commands::Context { .. }
+        | Commands::Doctor
+        | Commands::Agent {
+            action: AgentAction::Get { .. },
+        }
+        | Commands::Relay { .. }
+        | Commands::Hook { .. } => {
+            unreachable!("handled before connecting")
+        }
     };
 
+    if let Command::Set { key, value, .. } = &command {
+        warn_about_value(endpoint, key, value).await;
+    }
+
     let cmd_json = serde_json::to_string(&command)?;
     writer.write_all(cmd_json.as_bytes()).await?;
     writer.write_all(b"\n").await?;
 
+    if let Command::Subscribe = command {
+        return watch_events(&mut reader).await;
+    }
+
     // Read response
     let mut response_line = String::new();
     reader.read_line(&mut response_line).await?;
 
-    let response: Response = serde_json::from_str(&response_line)?;
-
     // Handle response
-    handle_response(response);
+    handle_response(parse_response(&response_line)?);
 
     Ok(())
 }
 
-#[cfg(windows)]
-async fn execute_command_windows(
-    cli_command: Commands,
-    mut reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
-    mut writer: tokio::net::tcp::OwnedWriteHalf,
-) -> anyhow::Result<()> {
-    // Send command
-    let command = match cli_command {
-        Commands::Get { key } => Command::Get { key },
-        Commands::Set { key, value } => {
-            // Parse KEY=value format
-            if let Some(val) = value {
-                Command::Set { key, value: val }
-            } else if let Some(eq_pos) = key.find('=') {
-                let (k, v) = key.split_at(eq_pos);
-                Command::Set {
-                    key: k.to_string(),
-                    value: v[1..].to_string(),
-                }
-            } else {
-                eprintln!("❌ Invalid format. Use: envmesh-cli set KEY value");
-                eprintln!("   or: envmesh-cli set KEY=value");
-                std::process::exit(1);
+/// Print events streamed by the daemon until it disconnects
+async fn watch_events<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<()> {
+    let mut line = String::new();
+
+    while reader.read_line(&mut line).await? > 0 {
+        match parse_response(&line)? {
+            Response::Event(event) => {
+                println!("[{}] {}", chrono::Local::now().format("%H:%M:%S"), event);
             }
+            other => handle_response(other),
         }
-        Commands::Delete { key } => Command::Delete { key },
-        Commands::List => Command::List,
-        Commands::Export { shell } => {
-            // Handle export locally
-            handle_export_windows(&shell).await?;
-            return Ok(());
-        }
-        Commands::Peers => Command::Peers,
-        Commands::Sync => Command::Sync,
-        Commands::Shutdown => Command::Shutdown,
-    };
-
-    let cmd_json = serde_json::to_string(&command)?;
-    writer.write_all(cmd_json.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
+        line.clear();
+    }
 
-    // Read response
-    let mut response_line = String::new();
-    reader.read_line(&mut response_line).await?;
+    Ok(())
+}
 
-    let response: Response = serde_json::from_str(&response_line)?;
+fn handle_autostart(action: &AutostartAction) -> anyhow::Result<()> {
+    let entry = AutostartEntry::daemon()?;
 
-    // Handle response
-    handle_response(response);
+    match action {
+        AutostartAction::Enable => {
+            autostart::enable(&entry)?;
+            println!("✓ Daemon will start at login ({})", entry.exec.display());
+        }
+        AutostartAction::Disable => {
+            autostart::disable(&entry)?;
+            println!("✓ Daemon will no longer start at login");
+        }
+        AutostartAction::Status => {
+            if autostart::is_enabled(&entry)? {
+                println!("Autostart: enabled");
+            } else {
+                println!("Autostart: disabled");
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn handle_response(response: Response) {
-    match response {
-        Response::Value(Some(value)) => {
-            println!("{}", value);
-        }
+fn handle_context(action: &ContextAction, flag: Option<&str>) -> anyhow::Result<()> {
+    // Loaded strictly: `use` and `unset` write the file back
+    let mut config = config::Config::load_default()?;
+    let current = context::select(&config.contexts, flag, config.ipc.context.as_deref())?
+        .map(|context| context.name.clone());
+
+    match action {
+        ContextAction::List => {
+            if config.contexts.is_empty() {
+                println!("No contexts; add [[contexts]] entries to the config");
+            }
+            for context in &config.contexts {
+                let marker = if current.as_ref() == Some(&context.name) {
+                    "*"
+                } else {
+                    " "
+                };
+                let daemon = context
+                    .endpoint
+                    .clone()
+                    .or_else(|| {
+                        context
+                            .instance
+                            .as_ref()
+                            .map(|name| format!("instance {}", name))
+                    })
+                    .or_else(|| context.data_dir.clone())
+                    .unwrap_or_else(|| "default daemon".to_string());
+                match &context.namespace {
+                    Some(namespace) => {
+                        println!(
+                            "{} {}\t{}\tnamespace {}",
+                            marker, context.name, daemon, namespace
+                        )
+                    }
+                    None => println!("{} {}\t{}", marker, context.name, daemon),
+                }
+            }
+        }
+        ContextAction::Current => match current {
+            Some(name) => println!("{}", name),
+            None => {
+                eprintln!("No current context");
+                std::process::exit(1);
+            }
+        },
+        ContextAction::Use { name } => {
+            context::find(&config.contexts, name)?;
+            config.ipc.context = Some(name.clone());
+            let path = config::Config::default_save_path();
+            config.save(&path)?;
+            println!("✓ Switched to context {} ({})", name, path.display());
+        }
+        ContextAction::Unset => {
+            config.ipc.context = None;
+            let path = config::Config::default_save_path();
+            config.save(&path)?;
+            println!("✓ No current context ({})", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill in the context's namespace where a command reads or writes a single
+/// namespace and wasn't given one
+fn default_namespace(command: &mut Commands, context_namespace: &str) {
+    let namespace = match command {
+        Commands::List(args) => &mut args.namespace,
+        Commands::Import { namespace, .. }
+        | Commands::Export { namespace, .. }
+        | Commands::Shell { namespace, .. }
+        | Commands::Run { namespace, .. }
+        | Commands::Mask { namespace, .. }
+        | Commands::Ci {
+            action: CiAction::Export { namespace, .. },
+        } => namespace,
+        _ => return,
+    };
+    namespace.get_or_insert_with(|| context_namespace.to_string());
+}
+
+fn handle_hook_install(force: bool) -> anyhow::Result<()> {
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "--git-path", "hooks"])
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        anyhow::bail!("Not in a git repository");
+    }
+    let hooks = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let path = hooks.join("pre-commit");
+
+    if let Ok(existing) = std::fs::read_to_string(&path) {
+        if !force && !existing.contains(scan::HOOK_MARKER) {
+            anyhow::bail!(
+                "{} already exists; add `envmesh-cli scan --staged` to it, or pass --force to replace it",
+                path.display()
+            );
+        }
+    }
+    std::fs::create_dir_all(&hooks)?;
+    std::fs::write(&path, scan::PRE_COMMIT_HOOK)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    println!("✓ Installed {}", path.display());
+    Ok(())
+}
+
+async fn handle_scan(endpoint: &Endpoint, paths: &[PathBuf], staged: bool) -> anyhow::Result<()> {
+    let scanner = match request(endpoint, &Command::ValueDigests).await? {
+        Response::Digests(digests) => Scanner::new(digests),
+        Response::Error(msg) => anyhow::bail!(msg),
+        _ => anyhow::bail!("This daemon predates `scan`; update it first"),
+    };
+    if scanner.is_empty() {
+        println!("✓ Nothing stored in the mesh to look for");
+        return Ok(());
+    }
+
+    // (path, line, key)
+    let mut found = Vec::new();
+    let mut scanned = 0;
+    if staged {
+        let output = std::process::Command::new("git")
+            .args([
+                "diff",
+                "--cached",
+                "--unified=0",
+                "--no-color",
+                "--no-ext-diff",
+            ])
+            .output()
+            .map_err(|e| anyhow::anyhow!("Failed to run git: {}", e))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "git diff failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        for (path, line, text) in scan::added_lines(&String::from_utf8_lossy(&output.stdout)) {
+            scanned += 1;
+            for key in scanner.scan_line(&text) {
+                found.push((path.clone(), line, key));
+            }
+        }
+    } else {
+        let mut files = Vec::new();
+        for path in paths {
+            collect_files(path, &mut files)?;
+        }
+        for file in files {
+            // Binary files can't hold a value the way it is stored
+            let Ok(text) = String::from_utf8(std::fs::read(&file)?) else {
+                continue;
+            };
+            scanned += 1;
+            for (line, key) in scanner.scan_text(&text) {
+                found.push((file.display().to_string(), line, key));
+            }
+        }
+    }
+
+    if found.is_empty() {
+        let what = if staged { "staged lines" } else { "files" };
+        println!("✓ No mesh values in {} {}", scanned, what);
+        return Ok(());
+    }
+    for (path, line, key) in &found {
+        eprintln!("❌ {}:{}: contains the value of {}", path, line, key);
+    }
+    eprintln!(
+        "\n{} mesh value(s) found; reference them through envmesh instead",
+        found.len()
+    );
+    std::process::exit(1);
+}
+
+/// Regular files under `path`, leaving out .git directories (sockets and
+/// other special files can't be read like files)
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && entry.file_name() != ".git" {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+async fn handle_doctor(endpoint: &Endpoint, data_dir: &Path) -> anyhow::Result<()> {
+    let checks = doctor::run(endpoint, data_dir).await;
+
+    for check in &checks {
+        let marker = match check.severity {
+            Severity::Ok => "✓",
+            Severity::Warn => "⚠️ ",
+            Severity::Fail => "❌",
+        };
+        println!("{} {:<9} {}", marker, check.name, check.detail);
+        if let Some(fix) = &check.fix {
+            println!("   → {}", fix);
+        }
+    }
+
+    if checks.iter().any(|check| check.severity == Severity::Fail) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn action_marker(action: ImportAction) -> &'static str {
+    match action {
+        ImportAction::Add => "+",
+        ImportAction::Update => "~",
+        ImportAction::Unchanged => "=",
+        ImportAction::Delete => "-",
+    }
+}
+
+fn print_preview(preview: &ChangePreview) {
+    for change in &preview.changes {
+        if preview.local_only.contains(&change.key) {
+            println!(
+                "{} {} (stays on this machine)",
+                action_marker(change.action),
+                change.key
+            );
+        } else {
+            println!("{} {}", action_marker(change.action), change.key);
+        }
+    }
+
+    let changed = preview
+        .changes
+        .iter()
+        .filter(|c| c.action != ImportAction::Unchanged)
+        .count();
+    println!(
+        "Dry run: {} of {} variables would change, nothing was written",
+        changed,
+        preview.changes.len()
+    );
+
+    if changed > preview.local_only.len() {
+        if preview.peers.is_empty() {
+            println!("No peers connected; changes sync when one connects");
+        } else {
+            println!("Would reach:");
+            for (id, addr) in &preview.peers {
+                println!("  {} @ {}", id, addr);
+            }
+        }
+    }
+}
+
+fn handle_response(response: Response) {
+    match response {
+        Response::Value(Some(value)) => {
+            println!("{}", value);
+        }
         Response::Value(None) => {
             eprintln!("❌ Key not found");
             std::process::exit(1);
@@ -260,99 +1557,1390 @@ fn handle_response(response: Response) {
                 }
             }
         }
-        Response::Peers(peers) => {
-            if peers.is_empty() {
+        Response::Peers(links) => {
+            if links.is_empty() {
                 println!("No connected peers");
             } else {
-                for (id, addr) in peers {
-                    println!("{} @ {}", id, addr);
+                for link in links {
+                    let rtt = link
+                        .rtt_ms
+                        .map(|ms| format!("{} ms", ms))
+                        .unwrap_or_else(|| "-".to_string());
+                    let heard = link
+                        .last_received_ms
+                        .map(|ms| {
+                            let secs = (chrono::Utc::now().timestamp_millis() - ms).max(0) / 1000;
+                            format!("{}s ago", secs)
+                        })
+                        .unwrap_or_else(|| "never".to_string());
+                    let sync = match (link.behind(), link.acked_seq, link.sent_seq) {
+                        (Some(0), _, _) => "in sync".to_string(),
+                        (Some(behind), Some(acked), Some(sent)) => {
+                            format!("behind by {} (acked {}/{})", behind, acked, sent)
+                        }
+                        _ => "sync unknown".to_string(),
+                    };
+                    println!(
+                        "{}  rtt {}  loss {:.0}% ({}/{} pings)  heard {}  {}",
+                        peer_name(&link),
+                        rtt,
+                        link.loss_percent(),
+                        link.lost,
+                        link.pings,
+                        heard,
+                        sync
+                    );
                 }
             }
         }
+        Response::Event(event) => {
+            println!("{}", event);
+        }
+        Response::Imported(changes) => {
+            for change in &changes {
+                println!("{} {}", action_marker(change.action), change.key);
+            }
+            let changed = changes
+                .iter()
+                .filter(|c| c.action != ImportAction::Unchanged)
+                .count();
+            println!("✓ {} of {} variables changed", changed, changes.len());
+        }
+        Response::Deleted(keys) => {
+            for key in &keys {
+                println!("- {}", key);
+            }
+            println!("✓ Deleted {} variables", keys.len());
+        }
+        Response::Preview(preview) => print_preview(&preview),
+        Response::Digests(digests) => println!("{} values to scan for", digests.len()),
+        Response::SyncReports(reports) => {
+            for report in &reports {
+                print_sync_report(report);
+            }
+        }
+        Response::Users(users) => print_users(&users, None),
+        Response::Trash(trash) => println!("{} deleted variables", trash.len()),
+        Response::Expiring(expiries) => println!("{} expiring credentials", expiries.len()),
+        Response::Rotations(statuses) => println!("{} rotation policies", statuses.len()),
+        Response::RotationLog(log) => println!("{} rotation attempts", log.len()),
+        Response::Quarantine(held) => println!("{} changes held", held.len()),
+        Response::Released(count) => println!("✓ {} held changes released", count),
+        Response::Agent(status) => print_agent_status(&status),
+        Response::Shared { code, expires_at } => {
+            println!("{}", code);
+            let expires = local_time(expires_at);
+            if code.starts_with(share::CODE_PREFIX) {
+                eprintln!(
+                    "Anyone with this code can read the value until {}; \
+                     `envmesh-cli claim CODE` opens it",
+                    expires
+                );
+            } else {
+                eprintln!(
+                    "The first `envmesh-cli claim LINK` before {} gets the value; \
+                     the link is useless after that",
+                    expires
+                );
+            }
+        }
+        Response::Warnings(warnings) => {
+            for warning in warnings {
+                eprintln!("⚠️  {}", warning);
+            }
+        }
+        Response::Snapshot(snapshot) => {
+            println!("Snapshot of {} variables", snapshot.vars.len());
+        }
+        Response::Bundle(bundle) => {
+            println!("Bundle of {} changes", bundle.changes.len());
+        }
+        Response::BundleImported {
+            applied,
+            total,
+            gap,
+        } => {
+            println!(
+                "✓ Applied {} of {} changes (the rest were older or already here)",
+                applied, total
+            );
+            if let Some(base) = gap {
+                eprintln!(
+                    "⚠️  This bundle continues {}, which was never imported here; \
+                     changes made before it are missing (import it, or a full bundle)",
+                    base
+                );
+            }
+        }
+        Response::Restored(count) => {
+            println!("✓ Restored {} variables", count);
+        }
+        Response::Status(status) => print_status(&status),
+        Response::Stats { machine_id, stats } => print_stats(&machine_id, &stats),
+        Response::Fsck {
+            found,
+            remaining,
+            rebuilt,
+        } => print_fsck(&found, remaining.as_ref(), rebuilt),
+        Response::File(Some(blob)) => match blob.contents() {
+            Ok(contents) => {
+                use std::io::Write;
+                if let Err(e) = std::io::stdout().write_all(&contents) {
+                    eprintln!("❌ Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("❌ Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Response::File(None) => {
+            eprintln!("❌ File not found");
+            std::process::exit(1);
+        }
+        Response::Files(files) => print_files(&files),
+        Response::Health(status) => print_health_status(&status),
+        Response::Version { protocol, daemon } => {
+            println!(
+                "envmesh-cli {} (protocol v{})",
+                env!("CARGO_PKG_VERSION"),
+                PROTOCOL_VERSION
+            );
+            println!("daemon      {} (protocol v{})", daemon, protocol);
+            if protocol != PROTOCOL_VERSION {
+                eprintln!("⚠️  Protocol mismatch: update the CLI and daemon to the same release");
+            }
+        }
     }
 }
 
-#[cfg(unix)]
-async fn handle_export(socket_path: PathBuf, shell: &str) -> anyhow::Result<()> {
-    // Connect and get list
-    let stream = UnixStream::connect(socket_path).await?;
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
+fn print_files(files: &[FileInfo]) {
+    if files.is_empty() {
+        println!("No files");
+        return;
+    }
+    for file in files {
+        println!(
+            "{}  {} bytes  sha256:{}",
+            file.name,
+            file.size,
+            &file.sha256[..12.min(file.sha256.len())]
+        );
+    }
+}
 
-    let command = Command::List;
-    let cmd_json = serde_json::to_string(&command)?;
-    writer.write_all(cmd_json.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
+/// Fetch a file and write it to `out` (owner-only on Unix) or stdout
+async fn handle_file_get(
+    endpoint: &Endpoint,
+    name: String,
+    out: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let response = request(endpoint, &Command::GetFile { name }).await?;
+    let (Response::File(Some(blob)), Some(out)) = (&response, out) else {
+        handle_response(response);
+        return Ok(());
+    };
 
-    let mut response_line = String::new();
-    reader.read_line(&mut response_line).await?;
+    let contents = blob.contents()?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
 
-    let response: Response = serde_json::from_str(&response_line)?;
+    use std::io::Write;
+    options.open(out)?.write_all(&contents)?;
+    println!("✓ Wrote {} bytes to {}", contents.len(), out.display());
+    Ok(())
+}
 
-    match response {
-        Response::List(vars) => {
-            for (key, value) in vars {
-                match shell {
-                    "powershell" | "pwsh" => {
-                        println!("$env:{}=\"{}\"", key, value);
-                    }
-                    "fish" => {
-                        println!("set -gx {} \"{}\"", key, value);
-                    }
-                    _ => {
-                        // bash, zsh, sh
-                        println!("export {}=\"{}\"", key, value);
-                    }
+async fn handle_device_key(endpoint: &Endpoint) -> anyhow::Result<()> {
+    match request(endpoint, &Command::Status).await? {
+        Response::Status(DaemonStatus {
+            device_key: Some(key),
+            ..
+        }) => println!("{}", key),
+        Response::Status(_) => {
+            eprintln!("❌ This daemon predates device keys; update it first");
+            std::process::exit(1);
+        }
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+async fn handle_resync(
+    endpoint: &Endpoint,
+    from: String,
+    overwrite_local: bool,
+) -> anyhow::Result<()> {
+    let command = Command::Resync {
+        from: from.clone(),
+        overwrite_local,
+    };
+    match request(endpoint, &command).await? {
+        Response::Success => {
+            if overwrite_local {
+                println!("✓ Local state discarded; asked {} for its state", from);
+            } else {
+                println!("✓ Sync progress reset; asked {} for its state", from);
+            }
+            println!("  Changes arrive as it answers (`envmesh-cli watch` to follow)");
+        }
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+async fn handle_expiring(endpoint: &Endpoint, days: Option<u64>) -> anyhow::Result<()> {
+    let command = Command::Expiring { within_days: days };
+    match request(endpoint, &command).await? {
+        Response::Expiring(expiries) => {
+            if expiries.is_empty() {
+                println!("No expiring credentials");
+            }
+            let now = chrono::Utc::now().timestamp();
+            for expiry in expiries {
+                let marker = if expiry.expires_at <= now { "✗" } else { " " };
+                println!(
+                    "{} {}  {}  ({})",
+                    marker,
+                    expiry.key,
+                    local_time(expiry.expires_at),
+                    expiry.source.as_str()
+                );
+            }
+        }
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+async fn handle_rotation(endpoint: &Endpoint, action: RotationAction) -> anyhow::Result<()> {
+    let command = match action {
+        RotationAction::Log { limit } => Command::RotationLog { limit },
+        _ => Command::Rotations,
+    };
+    match request(endpoint, &command).await? {
+        Response::Rotations(statuses) => {
+            if statuses.is_empty() {
+                println!("No rotation policies (see [[rotations]] in the config)");
+            }
+            let now = chrono::Utc::now().timestamp();
+            for status in statuses {
+                let written = status
+                    .written_at
+                    .map(local_time)
+                    .unwrap_or_else(|| "never".to_string());
+                let due = if status.due_at <= now {
+                    "due now".to_string()
+                } else {
+                    format!("due {}", local_time(status.due_at))
+                };
+                println!(
+                    "{}  every {}  written {}  {}",
+                    status.key,
+                    every(status.every_secs),
+                    written,
+                    due
+                );
+                if let Some(error) = status.last_error {
+                    println!("  last attempt failed: {}", error);
                 }
             }
         }
-        Response::Error(msg) => {
-            eprintln!("# Error: {}", msg);
-            std::process::exit(1);
+        Response::RotationLog(log) => {
+            if log.is_empty() {
+                println!("No rotations yet");
+            }
+            for entry in log {
+                let outcome = match &entry.error {
+                    Some(error) => format!("failed: {}", error),
+                    None => "rotated".to_string(),
+                };
+                println!(
+                    "{}  {}  {} (on {})",
+                    local_time(entry.rotated_at),
+                    entry.key,
+                    outcome,
+                    entry.machine_id
+                );
+            }
         }
-        _ => {
-            eprintln!("# Unexpected response");
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+/// A rotation interval as written in the config (30d, 12h)
+fn every(secs: u64) -> String {
+    match secs {
+        s if s % 86_400 == 0 => format!("{}d", s / 86_400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+async fn handle_trash_list(endpoint: &Endpoint) -> anyhow::Result<()> {
+    match request(endpoint, &Command::Trash).await? {
+        Response::Trash(trash) => {
+            if trash.is_empty() {
+                println!("Trash is empty");
+            }
+            for var in trash {
+                println!(
+                    "{}  deleted {} by {}{}",
+                    var.key,
+                    local_time(var.deleted_at),
+                    var.machine_id,
+                    if var.restorable {
+                        ""
+                    } else {
+                        "  (no value to restore)"
+                    }
+                );
+            }
+        }
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+async fn handle_quarantine(endpoint: &Endpoint, action: QuarantineAction) -> anyhow::Result<()> {
+    let (command, verb) = match action {
+        QuarantineAction::List => (Command::Quarantine, ""),
+        // No IDs (--all) means every held change
+        QuarantineAction::Approve { ids, .. } => (Command::ApproveQuarantined { ids }, "Applied"),
+        QuarantineAction::Reject { ids, .. } => (Command::RejectQuarantined { ids }, "Dropped"),
+    };
+    match request(endpoint, &command).await? {
+        Response::Quarantine(held) => {
+            if held.is_empty() {
+                println!("No changes held");
+            }
+            for change in held {
+                println!(
+                    "{:>4}  {} {}  from {}  {}  ({})",
+                    change.id,
+                    if change.deleted { "-" } else { "~" },
+                    change.key,
+                    change.machine_id,
+                    local_time(change.received_at),
+                    change.reason
+                );
+            }
+        }
+        Response::Released(count) => println!("✓ {} {} held changes", verb, count),
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+async fn handle_agent_get(config: &AgentConfig, data_dir: &Path, key: &str) -> anyhow::Result<()> {
+    let socket = match std::env::var_os(agent::AGENT_SOCK_ENV) {
+        Some(socket) => PathBuf::from(socket),
+        None => config.socket_path(data_dir).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No agent socket: set {} or [agent] socket",
+                agent::AGENT_SOCK_ENV
+            )
+        })?,
+    };
+    let lease = AgentRequest::Lease {
+        key: key.to_string(),
+    };
+    match agent::request(&socket, &lease).await? {
+        AgentReply::Granted {
+            lease,
+            value,
+            expires_at,
+        } => {
+            eprintln!("🎫 Lease {} until {}", lease, local_time(expires_at));
+            println!("{}", value);
+        }
+        AgentReply::Denied { reason } => {
+            eprintln!("❌ {}: {}", key, reason);
             std::process::exit(1);
         }
+        AgentReply::Released => {}
     }
+    Ok(())
+}
 
+async fn handle_agent_status(endpoint: &Endpoint, limit: usize) -> anyhow::Result<()> {
+    match request(endpoint, &Command::Agent { limit }).await? {
+        Response::Agent(status) => print_agent_status(&status),
+        other => handle_response(other),
+    }
     Ok(())
 }
 
-#[cfg(windows)]
-async fn handle_export_windows(shell: &str) -> anyhow::Result<()> {
-    // Connect and get list
-    let stream = TcpStream::connect("127.0.0.1:37842").await?;
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
+fn print_agent_status(status: &AgentStatus) {
+    if status.pending.is_empty() {
+        println!("No requests waiting");
+    }
+    for pending in &status.pending {
+        println!(
+            "{:>4}  ? {}  from {}  {}  (agent approve|deny {})",
+            pending.id,
+            pending.key,
+            pending.process,
+            local_time(pending.requested_at),
+            pending.id
+        );
+    }
+    if !status.leases.is_empty() {
+        println!("\nLeases:");
+    }
+    for lease in &status.leases {
+        println!(
+            "{:>4}  {}  to {}  until {}",
+            lease.id,
+            lease.key,
+            lease.process,
+            local_time(lease.expires_at)
+        );
+    }
+    if !status.log.is_empty() {
+        println!("\nRecent decisions:");
+    }
+    for entry in &status.log {
+        println!(
+            "{}  {} {}  {}  ({})",
+            local_time(entry.at),
+            if entry.granted { "✓" } else { "✗" },
+            entry.key,
+            entry.process,
+            entry.detail
+        );
+    }
+}
 
-    let command = Command::List;
-    let cmd_json = serde_json::to_string(&command)?;
+async fn handle_peer_versions(endpoint: &Endpoint) -> anyhow::Result<()> {
+    let links = match request(endpoint, &Command::Peers).await? {
+        Response::Peers(links) => links,
+        other => {
+            handle_response(other);
+            return Ok(());
+        }
+    };
+    if links.is_empty() {
+        println!("No connected peers");
+    }
+    for link in links {
+        let Some(version) = &link.version else {
+            println!("{}  unknown (predates version reporting)", peer_name(&link));
+            continue;
+        };
+        let note = match version.compatibility() {
+            Compatibility::Compatible => "",
+            Compatibility::Outdated => "  ⚠️  older release",
+            Compatibility::Ahead => "  ⚠️  newer protocol: upgrade this machine",
+            Compatibility::Incompatible => "  ❌ incompatible",
+        };
+        println!("{}  envmesh {}{}", peer_name(&link), version, note);
+    }
+    Ok(())
+}
+
+/// `kind [id] @ address`, the ID being what a client named itself by (the
+/// ID `peer block` takes)
+fn peer_name(link: &PeerLink) -> String {
+    match &link.peer_id {
+        Some(id) => format!("{} {} @ {}", link.kind.as_str(), id, link.address),
+        None => format!("{} @ {}", link.kind.as_str(), link.address),
+    }
+}
+
+async fn handle_user_list(endpoint: &Endpoint) -> anyhow::Result<()> {
+    let own = match request(endpoint, &Command::Status).await? {
+        Response::Status(status) => status.device_key,
+        _ => None,
+    };
+    match request(endpoint, &Command::Users).await? {
+        Response::Users(users) => print_users(&users, own.as_deref()),
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+/// Open a share code or link, trusting the servers our `[tls]` trusts
+async fn claim_share(code: &str) -> anyhow::Result<Share> {
+    let tls = config::Config::load_default()
+        .map(|config| config.tls)
+        .unwrap_or_default();
+    share::claim(code, &tls).await
+}
+
+fn print_users(users: &[UserConfig], own_device: Option<&str>) {
+    if users.is_empty() {
+        println!("No users; every machine on the mesh may change everything");
+        return;
+    }
+    for user in users {
+        let namespaces = if user.role == Role::Admin || user.namespaces.is_empty() {
+            "all namespaces".to_string()
+        } else {
+            user.namespaces.join(", ")
+        };
+        println!("{} ({}: {})", user.name, user.role.as_str(), namespaces);
+        for device in &user.devices {
+            let mut notes = Vec::new();
+            if Some(device.as_str()) == own_device {
+                notes.push("this machine".to_string());
+            }
+            match user.expires.get(device) {
+                Some(expires_at) if *expires_at <= clock::now() => {
+                    notes.push("expired".to_string())
+                }
+                Some(expires_at) => notes.push(format!("until {}", local_time(*expires_at))),
+                None => {}
+            }
+            if notes.is_empty() {
+                println!("  {}", device);
+            } else {
+                println!("  {} ({})", device, notes.join(", "));
+            }
+        }
+    }
+}
+
+async fn handle_generate(
+    endpoint: &Endpoint,
+    key: String,
+    length: usize,
+    charset: Charset,
+    force: bool,
+    show: bool,
+) -> anyhow::Result<()> {
+    if !force {
+        if let Response::Value(Some(_)) = request(
+            endpoint,
+            &Command::Get {
+                key: key.clone(),
+                fallback: false,
+            },
+        )
+        .await?
+        {
+            anyhow::bail!("{} already exists; pass --force to replace it", key);
+        }
+    }
+
+    let value = secretgen::generate(length, charset)?;
+    let set = Command::Set {
+        key: key.clone(),
+        value: value.clone(),
+        force_unlock: false,
+    };
+    match request(endpoint, &set).await? {
+        Response::Success => println!("✓ Generated {} ({} characters)", key, length),
+        other => handle_response(other),
+    }
+    if show {
+        println!("{}", value);
+    }
+
+    if let Response::Error(e) = request(endpoint, &Command::Sync).await? {
+        eprintln!("⚠️  Stored locally but not synced: {}", e);
+    }
+    Ok(())
+}
+
+/// Sync, then print the report the daemon recorded for the cycle
+async fn handle_sync_report(endpoint: &Endpoint, full: bool) -> anyhow::Result<()> {
+    let command = if full {
+        Command::FullSync
+    } else {
+        Command::Sync
+    };
+    match request(endpoint, &command).await? {
+        Response::Success => {}
+        other => {
+            handle_response(other);
+            return Ok(());
+        }
+    }
+
+    match request(endpoint, &Command::SyncReports { limit: 1 }).await? {
+        Response::SyncReports(reports) if reports.is_empty() => {
+            println!("✓ Synced (no report recorded)");
+        }
+        other => handle_response(other),
+    }
+    Ok(())
+}
+
+fn print_sync_report(report: &SyncReport) {
+    let time = chrono::DateTime::from_timestamp_millis(report.finished_ms)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| report.finished_ms.to_string());
+    println!(
+        "Sync #{} at {}{} to {}",
+        report.id,
+        time,
+        if report.full { " (full)" } else { "" },
+        report.peer.as_deref().unwrap_or("nobody (offline)")
+    );
+    for (label, keys) in [
+        ("Pushed", &report.pushed),
+        ("Pulled", &report.pulled),
+        ("Conflicted", &report.conflicted),
+        ("Skipped", &report.skipped),
+    ] {
+        if keys.is_empty() {
+            println!("  {:<11} 0", label);
+        } else {
+            println!("  {:<11} {}: {}", label, keys.len(), keys.join(", "));
+        }
+    }
+}
+
+fn print_health_status(status: &HealthStatus) {
+    println!("{}", status.mode);
+    if status.lan_hold {
+        println!("Failed over by hand: stays on the LAN until reconnected to the cloud");
+    }
+    if status.paused {
+        println!("Failover paused (`envmesh-cli health resume`)");
+    }
+    println!(
+        "Failed checks: {}/{}",
+        status.consecutive_failures, status.failure_threshold
+    );
+    match status.last_check {
+        Some(at) => println!(
+            "Last check: {} ({})",
+            local_time(at),
+            status.last_error.as_deref().unwrap_or("healthy")
+        ),
+        None => println!("Last check: none yet"),
+    }
+    println!("Checking every {}s", status.check_interval_secs);
+}
+
+/// Unix seconds as local time, to the minute
+fn local_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn print_status(status: &DaemonStatus) {
+    println!("Machine:     {}", status.machine_id);
+    println!("Connection:  {}", status.connection);
+    println!("Variables:   {}", status.variables);
+    println!("Conflicts:   {}", status.conflicts);
+    if let Some(skew) = status.clock_skew.filter(|skew| *skew != 0) {
+        println!("Clock skew:  {:+}s vs LAN server", skew);
+    }
+    if let Some(key) = &status.device_key {
+        println!("Device key:  {}", key);
+    }
+    for warning in &status.version_warnings {
+        println!("⚠️  Peer {}", warning);
+    }
+    if status.rate_limited > 0 || !status.banned_peers.is_empty() {
+        println!("Rate limited: {} changes dropped", status.rate_limited);
+        for peer in &status.banned_peers {
+            println!("Banned:      {}", peer);
+        }
+    }
+    match (status.last_backup, status.next_backup) {
+        (_, None) => println!("Backups:     not scheduled"),
+        (last, Some(next)) => {
+            let last = last.map_or_else(|| "never".to_string(), local_time);
+            println!("Last backup: {}", last);
+            println!("Next backup: {}", local_time(next));
+        }
+    }
+}
+
+fn print_stats(machine_id: &str, stats: &StoreStats) {
+    println!("Variables:   {}", stats.variables);
+    for (namespace, count) in &stats.namespaces {
+        println!("  {:<24} {}", namespace, count);
+    }
+    println!("Files:       {}", stats.files);
+    println!("Tombstones:  {}", stats.tombstones);
+    match &stats.deepest_history {
+        Some((key, depth)) => println!(
+            "History:     {} entries (deepest: {}, {})",
+            stats.history_entries, key, depth
+        ),
+        None => println!("History:     {} entries", stats.history_entries),
+    }
+    println!("Storage:     {:.1} KiB", stats.size_bytes as f64 / 1024.0);
+    println!("Last hour:   {} changes", stats.recent_changes);
+
+    // Busiest first, so a runaway writer tops the list
+    let mut writes: Vec<_> = stats.writes.iter().collect();
+    writes.sort_by_key(|(_, counts)| std::cmp::Reverse((counts.recent, counts.total)));
+    println!("Writes by machine (last hour / total):");
+    for (machine, counts) in writes {
+        let this = if machine == machine_id {
+            " (this machine)"
+        } else {
+            ""
+        };
+        println!(
+            "  {:<36} {:>6} / {}{}",
+            machine, counts.recent, counts.total, this
+        );
+    }
+}
+
+fn print_fsck(found: &FsckReport, remaining: Option<&FsckReport>, rebuilt: bool) {
+    if found.is_clean() {
+        println!("✓ Store is consistent");
+        return;
+    }
+    print_fsck_problems(found);
+    let Some(remaining) = remaining else {
+        eprintln!("Run `envmesh-cli fsck --repair` to fix them");
+        std::process::exit(1);
+    };
+    if rebuilt {
+        println!("⚠️  The database was damaged beyond repair in place: cleared it, kept this");
+        println!("   machine's changes, and asked peers for theirs. A LAN server's clients");
+        println!("   resend theirs with `envmesh-cli sync --full`.");
+    }
+    if remaining.is_clean() {
+        println!("✓ Repaired");
+    } else {
+        eprintln!("❌ Still wrong after repair (restore a backup):");
+        print_fsck_problems(remaining);
+        std::process::exit(1);
+    }
+}
+
+fn print_fsck_problems(report: &FsckReport) {
+    for (heading, problems) in [
+        ("Damage", &report.integrity),
+        ("Duplicate rows", &report.duplicates),
+        ("Broken invariants", &report.violations),
+    ] {
+        if problems.is_empty() {
+            continue;
+        }
+        println!("{} ({}):", heading, problems.len());
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
+}
+
+/// `command`, or a request to preview it under `--dry-run`
+fn dry_run_if(dry_run: bool, command: Command) -> Command {
+    if dry_run {
+        Command::Preview {
+            command: Box::new(command),
+        }
+    } else {
+        command
+    }
+}
+
+/// Parse a .env file locally so syntax errors are reported before anything
+/// is sent to the daemon
+fn import_command(file: &str, namespace: Option<&str>, format: &str) -> anyhow::Result<Command> {
+    if format == "sops" && file == "-" {
+        anyhow::bail!("SOPS files are imported from a path, not stdin");
+    }
+    let text = || -> anyhow::Result<String> {
+        Ok(if file == "-" {
+            std::io::read_to_string(std::io::stdin())?
+        } else {
+            std::fs::read_to_string(file)?
+        })
+    };
+    let parsed = match format {
+        "sops" => sops::decrypt(Path::new(file))?,
+        "dotenv" => envmesh::dotenv::parse(&text()?)?,
+        vendor => saas::parse(vendor.parse()?, &text()?)?,
+    };
+
+    let namespace = namespace.unwrap_or(storage::DEFAULT_NAMESPACE);
+    let vars = parsed
+        .into_iter()
+        .map(|(key, value)| (storage::namespaced_key(namespace, &key), value))
+        .collect();
+
+    Ok(Command::Import { vars })
+}
+
+/// Send one command on a fresh connection and wait for its response
+async fn request(endpoint: &Endpoint, command: &Command) -> anyhow::Result<Response> {
+    let (mut reader, mut writer) = endpoint.connect().await?;
+
+    let cmd_json = serde_json::to_string(command)?;
     writer.write_all(cmd_json.as_bytes()).await?;
     writer.write_all(b"\n").await?;
 
     let mut response_line = String::new();
     reader.read_line(&mut response_line).await?;
 
-    let response: Response = serde_json::from_str(&response_line)?;
+    parse_response(&response_line)
+}
 
-    match response {
-        Response::List(vars) => {
-            for (key, value) in vars {
-                match shell {
-                    "powershell" | "pwsh" => {
-                        println!("$env:{}=\"{}\"", key, value);
-                    }
-                    "fish" => {
-                        println!("set -gx {} \"{}\"", key, value);
+/// Decode a daemon response line; failures usually mean the daemon is from
+/// a release with a different protocol
+fn parse_response(line: &str) -> anyhow::Result<Response> {
+    serde_json::from_str(line).map_err(|e| {
+        anyhow::anyhow!(
+            "Unreadable daemon response ({}). This CLI speaks protocol v{}; \
+             check `envmesh-cli version`",
+            e,
+            PROTOCOL_VERSION
+        )
+    })
+}
+
+/// ENVMESH_BACKUP_PASSPHRASE, or ask on the terminal (twice when creating)
+/// Print the daemon's `[checks]` warnings for a value; daemons too old to
+/// check answer with an error, which is ignored
+async fn warn_about_value(endpoint: &Endpoint, key: &str, value: &str) {
+    let check = Command::CheckValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    if let Ok(Response::Warnings(warnings)) = request(endpoint, &check).await {
+        for warning in warnings {
+            eprintln!("⚠️  {}", warning);
+        }
+    }
+}
+
+/// The key and value `set` was given, as `KEY value`, `KEY=value`, or KEY
+/// with `--prompt`/`--stdin`
+fn set_args(
+    key: String,
+    value: Option<String>,
+    prompt: bool,
+    stdin: bool,
+) -> anyhow::Result<(String, String)> {
+    if prompt {
+        let value = prompt_value(&key)?;
+        Ok((key, value))
+    } else if stdin {
+        Ok((key, stdin_value()?))
+    } else if let Some(val) = value {
+        Ok((key, val))
+    } else if let Some(eq_pos) = key.find('=') {
+        let (k, v) = key.split_at(eq_pos);
+        Ok((k.to_string(), v[1..].to_string()))
+    } else {
+        eprintln!("❌ Invalid format. Use: envmesh-cli set KEY value");
+        eprintln!("   or: envmesh-cli set KEY=value");
+        std::process::exit(1);
+    }
+}
+
+/// Read a value without echoing it, asking twice so a typo isn't stored
+fn prompt_value(key: &str) -> anyhow::Result<String> {
+    let value = rpassword::prompt_password(format!("Value for {}: ", key))?;
+    if rpassword::prompt_password("Repeat value: ")? != value {
+        anyhow::bail!("Values do not match");
+    }
+    Ok(value)
+}
+
+fn stdin_value() -> anyhow::Result<String> {
+    read_value(std::io::stdin())
+}
+
+/// All of `reader` as a value, less one trailing newline (`echo` adds one)
+fn read_value(reader: impl std::io::Read) -> anyhow::Result<String> {
+    let mut value = std::io::read_to_string(reader)?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(value)
+}
+
+/// ENVMESH_BACKUP_PASSPHRASE, or ask on the terminal (twice when creating)
+fn backup_passphrase(confirm: bool) -> anyhow::Result<String> {
+    passphrase("ENVMESH_BACKUP_PASSPHRASE", "Backup", confirm)
+}
+
+/// The passphrase from `env_var`, or asked for on the terminal without
+/// echoing it
+fn passphrase(env_var: &str, label: &str, confirm: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return Ok(passphrase);
+    }
+
+    let prompt = |label: &str| -> anyhow::Result<String> {
+        Ok(rpassword::prompt_password(format!("{}: ", label))?)
+    };
+
+    let passphrase = prompt(&format!("{} passphrase", label))?;
+    if passphrase.is_empty() {
+        anyhow::bail!("{} passphrase must not be empty", label);
+    }
+    if confirm && prompt("Repeat passphrase")? != passphrase {
+        anyhow::bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+/// Snapshot on the daemon, encrypt locally: the passphrase never leaves the CLI
+async fn handle_backup(
+    endpoint: &Endpoint,
+    out: &std::path::Path,
+    incremental_from: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let passphrase = backup_passphrase(incremental_from.is_none())?;
+
+    let since = match incremental_from {
+        Some(base) => Some(Snapshot::decrypt(&std::fs::read(base)?, &passphrase)?.base()?),
+        None => None,
+    };
+
+    match request(endpoint, &Command::Backup { since }).await? {
+        Response::Snapshot(snapshot) => {
+            std::fs::write(out, snapshot.encrypt(&passphrase)?)?;
+            let kind = if snapshot.since.is_some() {
+                "Incremental"
+            } else {
+                "Full"
+            };
+            println!(
+                "✓ {} backup of {} variables written to {}",
+                kind,
+                snapshot.vars.len(),
+                out.display()
+            );
+        }
+        other => handle_response(other),
+    }
+
+    Ok(())
+}
+
+async fn handle_restore(
+    endpoint: &Endpoint,
+    files: &[PathBuf],
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let passphrase = backup_passphrase(false)?;
+
+    // Decrypt everything first so a bad passphrase or file restores nothing
+    let mut snapshots = Vec::new();
+    for file in files {
+        let snapshot = Snapshot::decrypt(&std::fs::read(file)?, &passphrase)
+            .map_err(|e| anyhow::anyhow!("{}: {}", file.display(), e))?;
+        snapshots.push((file, snapshot));
+    }
+
+    if let Some((file, snapshot)) = snapshots.first() {
+        if snapshot.since.is_some() {
+            eprintln!(
+                "⚠️  {} is incremental; restore its full backup first for a complete store",
+                file.display()
+            );
+        }
+    }
+
+    if dry_run {
+        // Each archive is compared with the store as it is now, not as the
+        // ones before it would leave it
+        for (file, snapshot) in snapshots {
+            println!("{}:", file.display());
+            handle_response(
+                request(endpoint, &dry_run_if(true, Command::Restore { snapshot })).await?,
+            );
+        }
+        return Ok(());
+    }
+
+    for (file, snapshot) in snapshots {
+        match request(endpoint, &Command::Restore { snapshot }).await? {
+            Response::Restored(count) => {
+                println!("✓ {}: restored {} variables", file.display(), count)
+            }
+            other => handle_response(other),
+        }
+    }
+    println!("Run `envmesh-cli sync` to push restored variables to peers");
+
+    Ok(())
+}
+
+/// Bundles, like backups, are encrypted and decrypted here: the passphrase
+/// never leaves the CLI
+async fn handle_bundle(endpoint: &Endpoint, action: BundleAction) -> anyhow::Result<()> {
+    match action {
+        BundleAction::Export {
+            out,
+            namespaces,
+            since,
+        } => {
+            let passphrase = passphrase("ENVMESH_BUNDLE_PASSPHRASE", "Bundle", true)?;
+            match request(endpoint, &Command::ExportBundle { namespaces, since }).await? {
+                Response::Bundle(bundle) => {
+                    std::fs::write(&out, bundle.encrypt(&passphrase)?)?;
+                    println!(
+                        "✓ Bundle {} of {} changes written to {}",
+                        bundle.id,
+                        bundle.changes.len(),
+                        out.display()
+                    );
+                }
+                other => handle_response(other),
+            }
+        }
+        BundleAction::Import { file } => {
+            let passphrase = passphrase("ENVMESH_BUNDLE_PASSPHRASE", "Bundle", false)?;
+            let bundle = Bundle::decrypt(&std::fs::read(&file)?, &passphrase)
+                .map_err(|e| anyhow::anyhow!("{}: {}", file.display(), e))?;
+            println!(
+                "Bundle {} from {} ({}{})",
+                bundle.id,
+                bundle.machine_id,
+                envmesh::bundle::describe(&bundle.namespaces),
+                bundle
+                    .since
+                    .as_deref()
+                    .map(|base| format!(", changes since {}", base))
+                    .unwrap_or_default()
+            );
+            handle_response(request(endpoint, &Command::ImportBundle { bundle }).await?);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_export(
+    endpoint: &Endpoint,
+    shell: &str,
+    namespace: Option<String>,
+    redact_log: bool,
+    prune: bool,
+    manifest: &ExportManifest,
+    mut overrides: BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let bare_names = namespace.is_some();
+    let mut exported = BTreeSet::new();
+
+    let mut vars: Vec<_> = list_vars(endpoint, namespace)
+        .await?
+        .into_iter()
+        .map(|(key, value)| {
+            let name = if bare_names {
+                storage::split_key(&key).1.to_string()
+            } else {
+                key.clone()
+            };
+            let value = overrides.remove(&name).unwrap_or(value);
+            (key, name, value)
+        })
+        .collect();
+    // Overrides of variables the mesh doesn't have
+    vars.extend(
+        overrides
+            .into_iter()
+            .map(|(name, value)| (name.clone(), name, value)),
+    );
+
+    for (key, name, value) in vars {
+        // The runner reads workflow commands from stderr too
+        if redact_log {
+            for line in mask::mask_lines(&value) {
+                eprintln!("{}", line);
+            }
+        }
+        match keys::export_line(shell, &name, &value) {
+            Some(line) => {
+                println!("{}", line);
+                exported.insert(name);
+            }
+            // Stderr, so `eval "$(envmesh-cli export)"` still works
+            None => eprintln!("# Skipping {}: not a valid shell variable name", key),
+        }
+    }
+
+    if prune {
+        match manifest.load() {
+            Ok(previous) => {
+                for name in manifest::removed(&previous, &exported) {
+                    if let Some(line) = keys::unset_line(shell, name) {
+                        println!("{}", line);
                     }
-                    _ => {
-                        // bash, zsh, sh
-                        println!("export {}=\"{}\"", key, value);
+                }
+            }
+            Err(e) => eprintln!("# Not pruning: {:#}", e),
+        }
+    }
+    // Saved on every export, so the first `--prune` has something to compare
+    if let Err(e) = manifest.save(&exported) {
+        eprintln!("# Failed to record this export: {:#}", e);
+    }
+
+    Ok(())
+}
+
+/// `export --format tfvars`: a terraform.tfvars document, or with `prefix`
+/// shell lines exporting TF_VAR_-style variables. `tf:number` and `tf:bool`
+/// tags type the values.
+async fn handle_tfvars(
+    endpoint: &Endpoint,
+    namespace: Option<String>,
+    prefix: Option<&str>,
+    shell: &str,
+) -> anyhow::Result<()> {
+    let mut types = HashMap::new();
+    for ty in [TfType::Number, TfType::Bool] {
+        let query = ListQuery {
+            namespace: namespace.clone(),
+            tag: Some(format!("{}{}", tfvars::TF_TAG_PREFIX, ty.as_str())),
+            ..Default::default()
+        };
+        match request(endpoint, &Command::List { query }).await? {
+            Response::List(vars) => types.extend(vars.into_iter().map(|(key, _)| (key, ty))),
+            other => {
+                handle_response(other);
+                return Ok(());
+            }
+        }
+    }
+
+    let bare_names = namespace.is_some();
+    let mut vars = Vec::new();
+    for (key, value) in list_vars(endpoint, namespace).await? {
+        let ty = types.get(&key).copied().unwrap_or_default();
+        let name = if bare_names {
+            storage::split_key(&key).1.to_string()
+        } else {
+            key.clone()
+        };
+        if dotenv::is_valid_key(&name) {
+            vars.push((name, value, ty));
+        } else {
+            eprintln!("# Skipping {}: not a valid Terraform variable name", key);
+        }
+    }
+
+    let Some(prefix) = prefix else {
+        print!("{}", tfvars::render(&vars)?);
+        return Ok(());
+    };
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (name, value, ty) in vars {
+        let name = format!("{}{}", prefix, name);
+        match ty.normalize(&value) {
+            Ok(value) => lines.extend(keys::export_line(shell, &name, &value)),
+            Err(e) => errors.push(format!("{} (tf:{}): {}", name, ty.as_str(), e)),
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!(errors.join("\n"));
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Run a shell with the mesh's variables in its environment, returning its
+/// exit code
+async fn handle_shell(
+    endpoint: &Endpoint,
+    data_dir: &Path,
+    namespace: Option<String>,
+    program: Option<String>,
+) -> anyhow::Result<i32> {
+    let bare_names = namespace.is_some();
+    let mut shell =
+        tokio::process::Command::new(program.unwrap_or_else(shellsession::default_shell));
+
+    let mut loaded = 0;
+    for (key, value) in list_vars(endpoint, namespace.clone()).await? {
+        let name = if bare_names {
+            storage::split_key(&key).1
+        } else {
+            key.as_str()
+        };
+        if dotenv::is_valid_key(name) {
+            shell.env(name, value);
+            loaded += 1;
+        } else {
+            eprintln!("# Skipping {}: not a valid shell variable name", key);
+        }
+    }
+
+    let session = ShellSession::create(data_dir, namespace.as_deref())?;
+    shell.env(shellsession::SESSION_ENV, session.id());
+    eprintln!(
+        "🐚 {} variables loaded; `envmesh-cli set --session KEY value` overrides one until this shell exits",
+        loaded
+    );
+    let status = shell.status().await;
+    session.end()?;
+    Ok(status?.code().unwrap_or(1))
+}
+
+/// `run`: resolve the references in our environment and the env files, then
+/// start the command with the values in place
+async fn handle_run(
+    endpoint: &Endpoint,
+    namespace: Option<String>,
+    env_files: Vec<PathBuf>,
+    command: Vec<String>,
+) -> anyhow::Result<i32> {
+    // Variables that aren't UTF-8 can't be references; the child still
+    // inherits them
+    let mut vars: Vec<(String, String)> = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect();
+    let mut from_files = Vec::new();
+    for path in &env_files {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let parsed =
+            dotenv::parse(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        from_files.extend(parsed);
+    }
+    vars.extend(from_files.iter().cloned());
+
+    let namespace = namespace.as_deref();
+    let mut values = HashMap::new();
+    for key in refs::referenced(&vars, namespace) {
+        let get = Command::Get {
+            key: key.clone(),
+            fallback: false,
+        };
+        match request(endpoint, &get).await? {
+            Response::Value(Some(value)) => {
+                values.insert(key, value);
+            }
+            Response::Value(None) => {}
+            Response::Error(e) => anyhow::bail!("Failed to get {}: {}", key, e),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        }
+    }
+    let resolved = refs::resolve(&vars, namespace, &values)?;
+
+    let mut child = tokio::process::Command::new(&command[0]);
+    child.args(&command[1..]).envs(from_files).envs(resolved);
+    let status = child
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", command[0], e))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// `set --session`: override a variable for the rest of the running
+/// `envmesh-cli shell`
+fn handle_session_set(data_dir: &Path, key: &str, value: &str) -> anyhow::Result<()> {
+    let Some(session) = ShellSession::current(data_dir) else {
+        anyhow::bail!("Not in an envmesh session; start one with `envmesh-cli shell`");
+    };
+    if !dotenv::is_valid_key(key) {
+        anyhow::bail!("{} is not a valid shell variable name", key);
+    }
+    session.set(key, value)?;
+
+    let export = match session.namespace()? {
+        Some(namespace) => format!("envmesh-cli export --namespace {}", namespace),
+        None => "envmesh-cli export".to_string(),
+    };
+    println!("✓ {} set for this session only (not stored or synced)", key);
+    println!("  Load it into this shell: eval \"$({})\"", export);
+    Ok(())
+}
+
+async fn handle_ci_export(
+    endpoint: &Endpoint,
+    format: CiFormat,
+    namespace: Option<String>,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let path = match (output, format) {
+        (Some(path), _) => path,
+        (None, CiFormat::GithubEnv) => std::env::var_os("GITHUB_ENV")
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "GITHUB_ENV is not set; run this in a GitHub Actions step or pass --output"
+                )
+            })?,
+        (None, CiFormat::GitlabDotenv) => PathBuf::from("envmesh.env"),
+    };
+
+    let bare_names = namespace.is_some();
+    let mut contents = String::new();
+    let mut written = 0;
+    for (key, value) in list_vars(endpoint, namespace).await? {
+        let name = if bare_names {
+            storage::split_key(&key).1
+        } else {
+            key.as_str()
+        };
+        match ci::entry(format, name, &value) {
+            Ok(entry) => {
+                // Masks must be registered before any later step can echo the value
+                if format == CiFormat::GithubEnv {
+                    for line in mask::mask_lines(&value) {
+                        println!("{}", line);
                     }
                 }
+                contents.push_str(&entry);
+                written += 1;
             }
+            Err(e) => eprintln!("⚠️  Skipping {}: {}", key, e),
         }
+    }
+
+    match format {
+        CiFormat::GithubEnv => {
+            use std::io::Write;
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?
+                .write_all(contents.as_bytes())?;
+        }
+        CiFormat::GitlabDotenv => {
+            std::fs::write(&path, contents)?;
+            eprintln!("⚠️  GitLab can't mask dotenv variables at runtime; mark them masked in the project's CI/CD settings");
+        }
+    }
+    eprintln!("✓ Wrote {} variables to {}", written, path.display());
+    Ok(())
+}
+
+/// Every variable (in `namespace`, if given), exiting with the error printed
+/// as a shell comment if the daemon can't list them
+async fn list_vars(
+    endpoint: &Endpoint,
+    namespace: Option<String>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let command = Command::List {
+        query: ListQuery {
+            namespace,
+            ..Default::default()
+        },
+    };
+
+    match request(endpoint, &command).await? {
+        Response::List(vars) => Ok(vars),
         Response::Error(msg) => {
             eprintln!("# Error: {}", msg);
             std::process::exit(1);
@@ -362,6 +2950,24 @@ async fn handle_export_windows(shell: &str) -> anyhow::Result<()> {
             std::process::exit(1);
         }
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_args_and_stdin_values() {
+        let (key, value) = set_args("API_KEY=a=b".to_string(), None, false, false).unwrap();
+        assert_eq!((key.as_str(), value.as_str()), ("API_KEY", "a=b"));
+        let (key, value) =
+            set_args("API_KEY".to_string(), Some("v".to_string()), false, false).unwrap();
+        assert_eq!((key.as_str(), value.as_str()), ("API_KEY", "v"));
+
+        assert_eq!(read_value("secret\n".as_bytes()).unwrap(), "secret");
+        assert_eq!(read_value("secret\r\n".as_bytes()).unwrap(), "secret");
+        assert_eq!(read_value("line\n\n".as_bytes()).unwrap(), "line\n");
+        assert_eq!(read_value("no newline".as_bytes()).unwrap(), "no newline");
+        assert_eq!(read_value("".as_bytes()).unwrap(), "");
+    }
 }
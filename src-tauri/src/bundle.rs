@@ -0,0 +1,373 @@
+// Bundles (`envmesh-cli bundle export/import`): changes carried by hand
+// between networks that can never connect. A bundle holds the exporting
+// machine's signed changes for some namespaces, like a snapshot sent to a
+// new LAN client, and is imported through the sync engine, so conflicts and
+// sequence numbers are handled as if it had arrived over the wire. A
+// differential bundle holds only what changed since an earlier one; the
+// importer flags it when that one never arrived.
+use aes_gcm::aead::OsRng;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::client::SyncMessage;
+use crate::clock;
+use crate::crypto::Crypto;
+use crate::pool::StoragePool;
+use crate::sync::SyncEngine;
+use crate::topics::Topics;
+
+/// File layout: MAGIC, then the Argon2 salt, then nonce + AES-GCM ciphertext
+/// of the bundle as JSON (as for backup archives)
+const MAGIC: &[u8; 8] = b"EMBUNDLE";
+const SALT_LEN: usize = 16;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    /// Random, to tell bundles apart
+    pub id: String,
+    pub created_at: i64,
+    /// The exporting machine
+    pub machine_id: String,
+    /// Namespaces it holds (empty = all)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<String>,
+    /// The bundle this one continues, holding only changes made after it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    /// Sequence points the changes cover, per machine. Only a bundle of
+    /// every namespace covers them; otherwise each change still carries its
+    /// own sequence number.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub through: BTreeMap<String, u64>,
+    /// Signed by the exporting machine, tombstones included
+    pub changes: Vec<SyncMessage>,
+}
+
+impl Bundle {
+    /// Everything `sync` would send a peer, limited to `namespaces`, or
+    /// with `since` only what changed after that bundle (and in its
+    /// namespaces, unless others are given)
+    pub async fn export(
+        sync: &SyncEngine,
+        storage: &StoragePool,
+        machine_id: &str,
+        mut namespaces: Vec<String>,
+        since: Option<String>,
+    ) -> Result<Self> {
+        let base_history_id = match &since {
+            Some(base) => {
+                let id = base.clone();
+                let (history_id, base_namespaces) = storage
+                    .read(move |s| s.bundle_export(&id))
+                    .await?
+                    .ok_or_else(|| anyhow!("No bundle {} was exported here", base))?;
+                if namespaces.is_empty() {
+                    namespaces = base_namespaces;
+                } else if namespaces != base_namespaces {
+                    return Err(anyhow!(
+                        "Bundle {} holds {}; a bundle continuing it must hold the same",
+                        base,
+                        describe(&base_namespaces)
+                    ));
+                }
+                history_id
+            }
+            None => 0,
+        };
+
+        // Sequence points and history first: whatever is numbered or written
+        // after them is still in the changes
+        let (through, history_id) = storage
+            .read(|s| Ok((s.seq_points()?, s.latest_history_id()?)))
+            .await?;
+        let through = if namespaces.is_empty() {
+            through
+        } else {
+            BTreeMap::new()
+        };
+        let topics = Topics::new(&namespaces);
+        let changes = sync
+            .changes_after(base_history_id)
+            .await?
+            .into_iter()
+            .filter(|msg| topics.wants_key(&msg.key))
+            .collect();
+
+        let bundle = Self {
+            version: FORMAT_VERSION,
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            created_at: clock::now(),
+            machine_id: machine_id.to_string(),
+            namespaces,
+            since,
+            through,
+            changes,
+        };
+        let (id, recorded) = (bundle.id.clone(), bundle.namespaces.clone());
+        storage
+            .write(move |s| s.record_bundle_export(&id, history_id, &recorded))
+            .await?;
+        Ok(bundle)
+    }
+
+    /// Apply through `sync`, as changes from the exporting machine. Returns
+    /// how many applied (the others were older, conflicted or refused) and,
+    /// if the bundle continues one never imported here, that one's ID: the
+    /// changes between them are missing.
+    pub async fn import(
+        self,
+        sync: &SyncEngine,
+        storage: &StoragePool,
+    ) -> Result<(usize, Option<String>)> {
+        let gap = match self.since.clone() {
+            Some(base) => {
+                let id = base.clone();
+                let imported = storage.read(move |s| s.bundle_imported(&id)).await?;
+                (!imported).then_some(base)
+            }
+            None => None,
+        };
+        // With a gap, changes below the sequence points are still missing
+        let through = match &gap {
+            Some(base) => {
+                tracing::warn!(
+                    "Bundle {} continues {}, which was never imported here",
+                    self.id,
+                    base
+                );
+                BTreeMap::new()
+            }
+            None => self.through,
+        };
+
+        // Recorded with the changes, so the next bundle's continuity check
+        // sees exactly what was applied
+        let (id, machine_id, since) = (self.id.clone(), self.machine_id.clone(), self.since);
+        let applied = sync
+            .apply_batch(&self.changes, through, move |s| {
+                s.record_bundle_import(&id, &machine_id, since.as_deref())
+            })
+            .await?;
+        tracing::info!(
+            "Applied {} of {} changes from bundle {} ({})",
+            applied,
+            self.changes.len(),
+            self.id,
+            self.machine_id
+        );
+        Ok((applied, gap))
+    }
+
+    pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let crypto = Crypto::with_salt(passphrase, &salt)?;
+        let ciphertext = crypto.encrypt(&serde_json::to_vec(self)?)?;
+
+        let mut file = Vec::with_capacity(MAGIC.len() + SALT_LEN + ciphertext.len());
+        file.extend_from_slice(MAGIC);
+        file.extend_from_slice(&salt);
+        file.extend_from_slice(&ciphertext);
+        Ok(file)
+    }
+
+    /// Open a bundle file; AES-GCM fails on any change to it, so a bundle
+    /// that decrypts is the one exported
+    pub fn decrypt(file: &[u8], passphrase: &str) -> Result<Self> {
+        let body = file
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| anyhow!("Not an EnvMesh bundle"))?;
+        if body.len() < SALT_LEN {
+            return Err(anyhow!("Bundle is truncated"));
+        }
+
+        let (salt, ciphertext) = body.split_at(SALT_LEN);
+        let plaintext = Crypto::with_salt(passphrase, salt)?
+            .decrypt(ciphertext)
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted bundle"))?;
+
+        let bundle: Self = serde_json::from_slice(&plaintext)?;
+        if bundle.version > FORMAT_VERSION {
+            return Err(anyhow!(
+                "Bundle format v{} is newer than this EnvMesh (v{})",
+                bundle.version,
+                FORMAT_VERSION
+            ));
+        }
+        Ok(bundle)
+    }
+}
+
+/// "namespaces a, b", or "all namespaces"
+pub fn describe(namespaces: &[String]) -> String {
+    if namespaces.is_empty() {
+        "all namespaces".to_string()
+    } else {
+        format!("namespaces {}", namespaces.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::node::{EnvMeshNode, NodeConfig, ServerMode};
+    use crate::signing::DeviceKey;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    async fn machine(machine_id: &str) -> (SyncEngine, StoragePool) {
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let events = EventBus::new();
+        let config = NodeConfig {
+            enable_cloud: false,
+            lan_port: 0,
+            server_mode: ServerMode::ServerPreferred,
+            ..Default::default()
+        };
+        let node = EnvMeshNode::new(config, events.clone()).await.unwrap();
+        let sync = SyncEngine::new(
+            storage.clone(),
+            Arc::new(Mutex::new(node)),
+            events,
+            Default::default(),
+        );
+        sync.set_machine_id(machine_id);
+        sync.set_device_key(DeviceKey::generate());
+        (sync, storage)
+    }
+
+    #[tokio::test]
+    async fn test_bundle_carries_namespaces_between_networks() {
+        let (a, a_storage) = machine("machine-a").await;
+        a_storage
+            .write(|s| {
+                s.set("prod/DB_URL", "postgres://db", "machine-a")?;
+                s.set("dev/DEBUG", "1", "machine-a")?;
+                s.set("prod/OLD", "x", "machine-a")?;
+                s.delete("prod/OLD", "machine-a")
+            })
+            .await
+            .unwrap();
+
+        let bundle = Bundle::export(&a, &a_storage, "machine-a", vec!["prod".to_string()], None)
+            .await
+            .unwrap();
+        assert!(bundle.through.is_empty());
+        assert_eq!(bundle.changes.len(), 2);
+        let file = bundle.encrypt("correct horse").unwrap();
+        assert!(Bundle::decrypt(&file, "wrong horse").is_err());
+        let mut tampered = file.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Bundle::decrypt(&tampered, "correct horse").is_err());
+
+        let (b, b_storage) = machine("machine-b").await;
+        b_storage
+            .write(|s| s.apply_remote("prod/OLD", "x", 1, "machine-a", false))
+            .await
+            .unwrap();
+        let bundle = Bundle::decrypt(&file, "correct horse").unwrap();
+        assert_eq!(bundle.import(&b, &b_storage).await.unwrap(), (2, None));
+        let (db_url, debug, old) = b_storage
+            .read(|s| {
+                Ok((
+                    s.get("prod/DB_URL")?,
+                    s.get("dev/DEBUG")?,
+                    s.get("prod/OLD")?,
+                ))
+            })
+            .await
+            .unwrap();
+        assert_eq!(db_url.unwrap().0, "postgres://db");
+        assert!(debug.is_none(), "only the bundled namespace is carried");
+        assert!(old.is_none(), "deletes are carried too");
+
+        // A bundle of everything covers a's sequence points, so b asks
+        // nobody for changes below them
+        let full = Bundle::export(&a, &a_storage, "machine-a", Vec::new(), None)
+            .await
+            .unwrap();
+        let through = *full.through.get("machine-a").unwrap();
+        assert_eq!(full.import(&b, &b_storage).await.unwrap(), (1, None));
+        let missing = b_storage
+            .read(move |s| s.missing_seqs("machine-a", through + 1, 10))
+            .await
+            .unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_differential_bundles_flag_gaps() {
+        let (a, a_storage) = machine("machine-a").await;
+        let set = |key: &'static str, value: &'static str| {
+            a_storage.write(move |s| s.set(key, value, "machine-a"))
+        };
+        set("prod/DB_URL", "postgres://db").await.unwrap();
+        set("dev/DEBUG", "1").await.unwrap();
+
+        let first = Bundle::export(&a, &a_storage, "machine-a", vec!["prod".to_string()], None)
+            .await
+            .unwrap();
+        set("prod/API_KEY", "k1").await.unwrap();
+        set("dev/DEBUG", "0").await.unwrap();
+        let second = Bundle::export(
+            &a,
+            &a_storage,
+            "machine-a",
+            Vec::new(),
+            Some(first.id.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.namespaces, vec!["prod".to_string()]);
+        assert_eq!(second.changes.len(), 1, "only what changed since, in prod");
+        assert!(Bundle::export(
+            &a,
+            &a_storage,
+            "machine-a",
+            vec!["dev".to_string()],
+            Some(first.id.clone())
+        )
+        .await
+        .is_err());
+        assert!(Bundle::export(
+            &a,
+            &a_storage,
+            "machine-a",
+            Vec::new(),
+            Some("unknown".to_string())
+        )
+        .await
+        .is_err());
+
+        // In order, nothing is missing
+        let (b, b_storage) = machine("machine-b").await;
+        assert_eq!(
+            first.clone().import(&b, &b_storage).await.unwrap(),
+            (1, None)
+        );
+        assert_eq!(
+            second.clone().import(&b, &b_storage).await.unwrap(),
+            (1, None)
+        );
+
+        // Without the first, its changes are
+        let (c, c_storage) = machine("machine-c").await;
+        assert_eq!(
+            second.import(&c, &c_storage).await.unwrap(),
+            (1, Some(first.id))
+        );
+        assert!(c_storage
+            .read(|s| s.get("prod/DB_URL"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
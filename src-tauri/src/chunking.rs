@@ -0,0 +1,356 @@
+// Value size limits, and chunked transfer of large values (and snapshots)
+// over the mesh
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::client::{SyncMessage, WireMessage};
+use crate::replay::MessageId;
+use crate::signing::MessageSignature;
+use crate::snapshot::MAX_SNAPSHOT_BYTES;
+
+/// Default `[limits] max_value_bytes`
+pub const DEFAULT_MAX_VALUE_BYTES: usize = 64 * 1024;
+
+/// Values longer than this travel as several `sync_chunk` frames
+pub const CHUNK_BYTES: usize = 16 * 1024;
+
+/// Largest value accepted from the network, whatever the local limit
+pub const MAX_WIRE_VALUE_BYTES: usize = 1024 * 1024;
+
+/// Snapshot data longer than this travels as `snapshot_chunk` frames ahead
+/// of the `snapshot` frame, well inside the WebSocket frame limit
+pub const SNAPSHOT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+/// Transfers missing chunks for this long are dropped
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Reject values over the configured limit before they are stored
+pub fn check_value_size(key: &str, value: &str, max_bytes: usize) -> Result<()> {
+    if value.len() > max_bytes {
+        return Err(anyhow!(
+            "Value for {} is {} bytes, over the {} byte limit ([limits] max_value_bytes)",
+            key,
+            value.len(),
+            max_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Split `text` into pieces of at most `max` bytes on char boundaries
+fn split_str(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces.push(rest);
+    pieces
+}
+
+/// Frames for one change: a single `sync` frame, or `sync_chunk` frames for
+/// a large value
+pub fn split(msg: &SyncMessage) -> Vec<WireMessage> {
+    if msg.value.len() <= CHUNK_BYTES {
+        return vec![WireMessage::Sync(msg.clone())];
+    }
+
+    let pieces = split_str(&msg.value, CHUNK_BYTES);
+    let total = pieces.len() as u32;
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| WireMessage::SyncChunk {
+            key: msg.key.clone(),
+            timestamp: msg.timestamp,
+            machine_id: msg.machine_id.clone(),
+            index: index as u32,
+            total,
+            data: data.to_string(),
+            id: msg.id.clone(),
+            seq: msg.seq,
+            signature: msg.signature.clone(),
+        })
+        .collect()
+}
+
+/// Frames for a snapshot: a `snapshot` frame, preceded by `snapshot_chunk`
+/// frames for all but the last piece of large `data`; `resync` is the name
+/// a resync asked for, when answering one
+pub fn split_snapshot(
+    machine_id: &str,
+    through: BTreeMap<String, u64>,
+    data: &str,
+    resync: Option<&str>,
+) -> Vec<WireMessage> {
+    let mut pieces = split_str(data, SNAPSHOT_CHUNK_BYTES);
+    let last = pieces.pop().unwrap_or_default().to_string();
+    let mut frames: Vec<WireMessage> = pieces
+        .iter()
+        .enumerate()
+        .map(|(index, data)| WireMessage::SnapshotChunk {
+            index: index as u32,
+            data: data.to_string(),
+        })
+        .collect();
+    frames.push(WireMessage::Snapshot {
+        machine_id: machine_id.to_string(),
+        through,
+        data: last,
+        resync: resync.map(str::to_string),
+        chunks: pieces.len() as u32,
+    });
+    frames
+}
+
+struct PendingValue {
+    parts: Vec<Option<String>>,
+    received: usize,
+    bytes: usize,
+    started: Instant,
+    message_id: Option<MessageId>,
+    seq: Option<u64>,
+    signature: Option<MessageSignature>,
+}
+
+/// Collects `sync_chunk` frames back into whole changes, and
+/// `snapshot_chunk` frames into their snapshot
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<(String, String, i64), PendingValue>,
+    /// Data of the snapshot being received, in order
+    snapshot: Vec<String>,
+    snapshot_bytes: usize,
+}
+
+impl Reassembler {
+    /// Feed one frame. Chunks are held until their change (or snapshot) is
+    /// complete, which is then returned whole; other frames pass straight
+    /// through.
+    pub fn accept(&mut self, msg: WireMessage) -> Option<WireMessage> {
+        let msg = match msg {
+            WireMessage::SnapshotChunk { index, data } => {
+                return self.accept_snapshot_chunk(index, data);
+            }
+            WireMessage::Snapshot {
+                machine_id,
+                through,
+                data,
+                resync,
+                chunks,
+            } => return self.finish_snapshot(machine_id, through, data, resync, chunks),
+            msg => msg,
+        };
+        let WireMessage::SyncChunk {
+            key,
+            timestamp,
+            machine_id,
+            index,
+            total,
+            data,
+            id: message_id,
+            seq,
+            signature,
+        } = msg
+        else {
+            return Some(msg);
+        };
+
+        self.pending
+            .retain(|_, pending| pending.started.elapsed() < CHUNK_TIMEOUT);
+
+        let max_chunks = MAX_WIRE_VALUE_BYTES.div_ceil(CHUNK_BYTES);
+        if total == 0 || total as usize > max_chunks || index >= total {
+            tracing::warn!("Dropping invalid chunk {}/{} for {}", index, total, key);
+            return None;
+        }
+
+        let id = (machine_id, key, timestamp);
+        let pending = self
+            .pending
+            .entry(id.clone())
+            .or_insert_with(|| PendingValue {
+                parts: vec![None; total as usize],
+                received: 0,
+                bytes: 0,
+                started: Instant::now(),
+                message_id,
+                seq,
+                signature,
+            });
+        if pending.parts.len() != total as usize {
+            tracing::warn!("Chunk count changed mid-transfer for {}", id.1);
+            self.pending.remove(&id);
+            return None;
+        }
+
+        let slot = &mut pending.parts[index as usize];
+        if slot.is_none() {
+            pending.received += 1;
+            pending.bytes += data.len();
+            *slot = Some(data);
+        }
+
+        if pending.bytes > MAX_WIRE_VALUE_BYTES {
+            tracing::warn!("Dropping oversized value for {}", id.1);
+            self.pending.remove(&id);
+            return None;
+        }
+        if pending.received < pending.parts.len() {
+            return None;
+        }
+
+        let pending = self.pending.remove(&id)?;
+        let (machine_id, key, timestamp) = id;
+        Some(WireMessage::Sync(SyncMessage {
+            key,
+            value: pending.parts.into_iter().flatten().collect(),
+            timestamp,
+            machine_id,
+            deleted: false,
+            id: pending.message_id,
+            seq: pending.seq,
+            signature: pending.signature,
+        }))
+    }
+
+    /// Hold a piece of snapshot data; a piece out of order drops the
+    /// snapshot, as the server sends them in order on one connection
+    fn accept_snapshot_chunk(&mut self, index: u32, data: String) -> Option<WireMessage> {
+        if index == 0 {
+            self.snapshot.clear();
+            self.snapshot_bytes = 0;
+        } else if index as usize != self.snapshot.len() {
+            tracing::warn!("Dropping snapshot: chunk {} arrived out of order", index);
+            self.snapshot.clear();
+            self.snapshot_bytes = 0;
+            return None;
+        }
+        self.snapshot_bytes += data.len();
+        self.snapshot.push(data);
+        if self.snapshot_bytes > MAX_SNAPSHOT_BYTES {
+            tracing::warn!("Dropping snapshot: over {} bytes", MAX_SNAPSHOT_BYTES);
+            self.snapshot.clear();
+            self.snapshot_bytes = 0;
+        }
+        None
+    }
+
+    /// The snapshot `data` ends, whole once the `chunks` pieces held before
+    /// it are put in front
+    fn finish_snapshot(
+        &mut self,
+        machine_id: String,
+        through: BTreeMap<String, u64>,
+        data: String,
+        resync: Option<String>,
+        chunks: u32,
+    ) -> Option<WireMessage> {
+        let pieces = std::mem::take(&mut self.snapshot);
+        self.snapshot_bytes = 0;
+        if pieces.len() != chunks as usize {
+            tracing::warn!(
+                "Dropping snapshot from {}: {} of {} chunks arrived",
+                machine_id,
+                pieces.len(),
+                chunks
+            );
+            return None;
+        }
+        Some(WireMessage::Snapshot {
+            machine_id,
+            through,
+            data: pieces.into_iter().chain([data]).collect(),
+            resync,
+            chunks: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(value: String) -> SyncMessage {
+        SyncMessage::new(
+            "TLS_CERT".to_string(),
+            value,
+            100,
+            "machine-a".to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_large_value_round_trip_out_of_order() {
+        // Multi-byte chars so chunk edges must respect char boundaries
+        let value = "-----BEGIN CERTIFICATE-----é".repeat(2000);
+        let frames = split(&message(value.clone()));
+        assert!(frames.len() > 1);
+
+        let mut reassembler = Reassembler::default();
+        let mut assembled = None;
+        for frame in frames.into_iter().rev() {
+            assert!(assembled.is_none());
+            assembled = reassembler.accept(frame);
+        }
+
+        match assembled {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.value, value),
+            other => panic!("expected reassembled change, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_large_snapshot_round_trip() {
+        let data = "QUJD".repeat(SNAPSHOT_CHUNK_BYTES / 2 + 1);
+        let through = BTreeMap::from([("machine-a".to_string(), 7)]);
+        let frames = split_snapshot("machine-a", through.clone(), &data, None);
+        assert_eq!(frames.len(), 3);
+
+        let mut reassembler = Reassembler::default();
+        let mut assembled = Vec::new();
+        for frame in frames.iter().cloned() {
+            assembled.extend(reassembler.accept(frame));
+        }
+        match assembled.as_slice() {
+            [WireMessage::Snapshot {
+                through: got,
+                data: whole,
+                chunks: 0,
+                ..
+            }] => {
+                assert_eq!(got, &through);
+                assert!(whole == &data);
+            }
+            other => panic!("expected the whole snapshot, got {:?}", other),
+        }
+
+        // A missing piece drops the snapshot rather than passing on half
+        for frame in frames.into_iter().skip(1) {
+            assert!(reassembler.accept(frame).is_none());
+        }
+        let small = split_snapshot("machine-a", through, "QUJD", None);
+        assert!(matches!(
+            small.as_slice(),
+            [WireMessage::Snapshot { chunks: 0, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_value_size_limit() {
+        assert!(check_value_size("KEY", "short", 16).is_ok());
+        let err = check_value_size("KEY", &"x".repeat(17), 16).unwrap_err();
+        assert!(err.to_string().contains("17 bytes"));
+
+        let small = split(&message("small".to_string()));
+        assert!(matches!(small.as_slice(), [WireMessage::Sync(_)]));
+    }
+}
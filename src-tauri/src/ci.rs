@@ -0,0 +1,80 @@
+// `envmesh-cli ci export`: variables in the files CI systems load between
+// steps ($GITHUB_ENV, GitLab dotenv report artifacts)
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+use crate::dotenv;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CiFormat {
+    /// Appended to `$GITHUB_ENV`; later steps see the variables
+    GithubEnv,
+    /// A `KEY=value` file for `artifacts: reports: dotenv:`
+    GitlabDotenv,
+}
+
+impl FromStr for CiFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "github-env" => Ok(Self::GithubEnv),
+            "gitlab-dotenv" => Ok(Self::GitlabDotenv),
+            other => Err(anyhow!(
+                "Unknown CI format '{}' (github-env, gitlab-dotenv)",
+                other
+            )),
+        }
+    }
+}
+
+/// One variable in `format`. Errors name why it can't be written: GitLab
+/// dotenv has no multi-line values, and both need a valid variable name.
+pub fn entry(format: CiFormat, name: &str, value: &str) -> Result<String> {
+    if !dotenv::is_valid_key(name) {
+        return Err(anyhow!("not a valid variable name"));
+    }
+
+    match format {
+        // Always the delimiter form, so a newline in the value can't start
+        // a variable of its own
+        CiFormat::GithubEnv => {
+            let mut delimiter = format!("ENVMESH_{}", uuid::Uuid::new_v4().simple());
+            while value.contains(&delimiter) {
+                delimiter = format!("ENVMESH_{}", uuid::Uuid::new_v4().simple());
+            }
+            Ok(format!(
+                "{}<<{}\n{}\n{}\n",
+                name, delimiter, value, delimiter
+            ))
+        }
+        CiFormat::GitlabDotenv if value.contains(['\n', '\r']) => Err(anyhow!(
+            "GitLab dotenv reports can't hold multi-line values"
+        )),
+        CiFormat::GitlabDotenv => Ok(format!("{}={}\n", name, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github_env_uses_delimiters() {
+        let entry = entry(CiFormat::GithubEnv, "CERT", "line1\nINJECTED=1").unwrap();
+        let lines: Vec<_> = entry.lines().collect();
+        assert!(lines[0].starts_with("CERT<<ENVMESH_"));
+        assert_eq!(lines[1..3], ["line1", "INJECTED=1"]);
+        assert_eq!(lines[3], &lines[0]["CERT<<".len()..]);
+    }
+
+    #[test]
+    fn test_gitlab_dotenv_rejects_multiline_values() {
+        assert_eq!(
+            entry(CiFormat::GitlabDotenv, "DB_URL", "postgres://db").unwrap(),
+            "DB_URL=postgres://db\n"
+        );
+        assert!(entry(CiFormat::GitlabDotenv, "CERT", "a\nb").is_err());
+        assert!(entry(CiFormat::GitlabDotenv, "prod/DB_URL", "x").is_err());
+    }
+}
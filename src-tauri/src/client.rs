@@ -1,76 +1,632 @@
 // WebSocket client for connecting to cloud or LAN servers
 use anyhow::{anyhow, Result};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header::AUTHORIZATION, HeaderName, HeaderValue};
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::{client_async, WebSocketStream};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::access::{self, CHALLENGE_HEADER, DEVICE_KEY_HEADER};
+use crate::chunking::{self, Reassembler};
+use crate::faults::{FaultConfig, FaultInjector};
+use crate::links::{LinkTracker, PeerKind, PeerLink};
+use crate::redact::ValueRedacted;
+use crate::replay::MessageId;
+use crate::session::{RESUME_HEADER, SESSION_HEADER};
+use crate::signing::{DeviceKey, MessageSignature};
+use crate::throttle::{NetworkConfig, Throttle};
+use crate::tls::{self, TlsConfig, Transport};
+use crate::topics::{Topics, TOPICS_HEADER};
+use crate::version::{PeerVersion, VERSION_HEADER};
+use crate::wire::{self, WireFormat, WIRE_FORMAT_HEADER};
+
+type WsSink = SplitSink<WebSocketStream<Transport>, Message>;
+
+/// Shared handle on a client's incoming messages, so a receive loop can wait
+/// on it without holding the node lock
+pub type IncomingHandle = Arc<Mutex<mpsc::UnboundedReceiver<WireMessage>>>;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SyncMessage {
     pub key: String,
     pub value: String,
     pub timestamp: i64,
     pub machine_id: String,
     pub deleted: bool,
+    /// Set when the change is sent; absent from peers older than message IDs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+    /// Number of the change in its machine's sequence of sent changes;
+    /// absent from peers older than sequence numbers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Sender's device signature over everything but `seq`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<MessageSignature>,
+}
+
+impl SyncMessage {
+    /// A change as written: its ID, sequence number and signature are added
+    /// as it is sent
+    pub fn new(
+        key: impl Into<String>,
+        value: impl Into<String>,
+        timestamp: i64,
+        machine_id: impl Into<String>,
+        deleted: bool,
+    ) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+            timestamp,
+            machine_id: machine_id.into(),
+            deleted,
+            id: None,
+            seq: None,
+            signature: None,
+        }
+    }
+}
+
+// Hand-written so the value can't end up in a log
+impl fmt::Debug for SyncMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncMessage")
+            .field("key", &self.key)
+            .field("value", &ValueRedacted(&self.value))
+            .field("timestamp", &self.timestamp)
+            .field("machine_id", &self.machine_id)
+            .field("deleted", &self.deleted)
+            .field("id", &self.id)
+            .field("seq", &self.seq)
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
+/// Envelope for everything sent over the client-server WebSocket
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WireMessage {
+    /// A variable change to apply
+    Sync(SyncMessage),
+    /// One piece of a change whose value is too large for a single frame
+    SyncChunk {
+        key: String,
+        timestamp: i64,
+        machine_id: String,
+        index: u32,
+        total: u32,
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<MessageId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<MessageSignature>,
+    },
+    /// The LAN server is stepping down; clients should re-run failover
+    ServerShutdown { reason: String },
+    /// A peer started with an empty store and asks everyone for their state,
+    /// or only the machine `from` (a machine ID or device key) when resyncing
+    SyncRequest {
+        machine_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        /// Stamped by the relay, as for `SeqRequest`: `from` answers with a
+        /// snapshot to that connection alone
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        asker: Option<u64>,
+    },
+    /// A peer found gaps in a machine's sequence and asks for those changes
+    SeqRequest {
+        machine_id: String,
+        seqs: Vec<u64>,
+        /// Stamped by the relay: the connection that asked, for answers to
+        /// go back to it alone (see `Reply`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        asker: Option<u64>,
+    },
+    /// An answer to the `SeqRequest` stamped with asker `to`: the relay
+    /// passes `frame` (a change, or a piece of one) on to that connection
+    /// only
+    Reply { to: u64, frame: Box<WireMessage> },
+    /// The LAN server's state for a new client, as of the sequence number
+    /// per machine in `through`; `data` is the compressed changes (see
+    /// `snapshot.rs`), and only later changes follow. Also a peer's answer
+    /// to a `SyncRequest` naming it, with that name in `resync`.
+    Snapshot {
+        machine_id: String,
+        through: BTreeMap<String, u64>,
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resync: Option<String>,
+        /// `snapshot_chunk` frames sent ahead with the rest of `data`, for
+        /// snapshots too large for one frame
+        #[serde(default)]
+        chunks: u32,
+    },
+    /// A piece of a large snapshot's data, ahead of its `Snapshot` frame
+    SnapshotChunk { index: u32, data: String },
+    /// First frame from a client the server challenged (see
+    /// `access::CHALLENGE_HEADER`): its device signature over the nonce
+    Proof { signature: String },
+    /// First frame from the LAN server: its clock, for skew detection, and
+    /// its build
+    Hello {
+        time_ms: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<PeerVersion>,
+        /// Our clock (uncorrected) when the frame arrived
+        #[serde(skip)]
+        received_ms: i64,
+    },
+}
+
+// Hand-written so chunk and snapshot data (pieces of values) can't end up
+// in a log
+impl fmt::Debug for WireMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sync(msg) => f.debug_tuple("Sync").field(msg).finish(),
+            Self::SyncChunk {
+                key,
+                timestamp,
+                machine_id,
+                index,
+                total,
+                data,
+                id,
+                seq,
+                signature,
+            } => f
+                .debug_struct("SyncChunk")
+                .field("key", key)
+                .field("timestamp", timestamp)
+                .field("machine_id", machine_id)
+                .field("index", index)
+                .field("total", total)
+                .field("data", &ValueRedacted(data))
+                .field("id", id)
+                .field("seq", seq)
+                .field("signature", signature)
+                .finish(),
+            Self::Proof { .. } => f.debug_struct("Proof").finish_non_exhaustive(),
+            Self::ServerShutdown { reason } => f
+                .debug_struct("ServerShutdown")
+                .field("reason", reason)
+                .finish(),
+            Self::SyncRequest {
+                machine_id,
+                from,
+                asker,
+            } => f
+                .debug_struct("SyncRequest")
+                .field("machine_id", machine_id)
+                .field("from", from)
+                .field("asker", asker)
+                .finish(),
+            Self::SeqRequest {
+                machine_id,
+                seqs,
+                asker,
+            } => f
+                .debug_struct("SeqRequest")
+                .field("machine_id", machine_id)
+                .field("seqs", seqs)
+                .field("asker", asker)
+                .finish(),
+            Self::Reply { to, frame } => f
+                .debug_struct("Reply")
+                .field("to", to)
+                .field("frame", frame)
+                .finish(),
+            Self::Snapshot {
+                machine_id,
+                through,
+                data,
+                resync,
+                chunks,
+            } => f
+                .debug_struct("Snapshot")
+                .field("machine_id", machine_id)
+                .field("through", through)
+                .field("data", &ValueRedacted(data))
+                .field("resync", resync)
+                .field("chunks", chunks)
+                .finish(),
+            Self::SnapshotChunk { index, data } => f
+                .debug_struct("SnapshotChunk")
+                .field("index", index)
+                .field("data", &ValueRedacted(data))
+                .finish(),
+            Self::Hello {
+                time_ms,
+                version,
+                received_ms,
+            } => f
+                .debug_struct("Hello")
+                .field("time_ms", time_ms)
+                .field("version", version)
+                .field("received_ms", received_ms)
+                .finish(),
+        }
+    }
+}
+
+/// How to connect, beyond the URL
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Presented as a bearer token; relays hosting several teams ask for one
+    pub token: Option<String>,
+    /// Asked for at the handshake; JSON unless the server agrees
+    pub wire_format: WireFormat,
+    /// Namespaces to receive changes for
+    pub topics: Topics,
+    /// Bandwidth limits for this connection
+    pub network: NetworkConfig,
+    /// Trust for wss:// servers
+    pub tls: TlsConfig,
+    /// Injected into what the server sends us
+    pub faults: Option<FaultConfig>,
+    /// Proven at the handshake, for servers with peer allow/block lists or
+    /// users; without it we connect anonymously
+    pub signer: Option<Arc<DeviceKey>>,
+    /// Session token from an earlier connection to the same server, to get
+    /// only the changes sent while we were away
+    pub resume: Option<String>,
 }
 
 pub struct WebSocketClient {
-    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// Shared with `Pinger`s
+    sink: Arc<Mutex<WsSink>>,
+    incoming: IncomingHandle,
+    reader: JoinHandle<()>,
     server_url: String,
+    /// What the server agreed to at the handshake
+    format: WireFormat,
+    link: LinkTracker,
+    upload: Option<Throttle>,
+    /// Our session on a LAN server, if it gave us one
+    session: Option<String>,
 }
 
 impl WebSocketClient {
     pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with_token(url, None).await
+    }
+
+    /// Connect presenting a mesh token, as relays hosting several teams ask for
+    pub async fn connect_with_token(url: &str, token: Option<&str>) -> Result<Self> {
+        let options = ConnectOptions {
+            token: token.map(str::to_string),
+            ..Default::default()
+        };
+        Self::connect_with(url, &options).await
+    }
+
+    pub async fn connect_with(url: &str, options: &ConnectOptions) -> Result<Self> {
         tracing::info!("Connecting to server: {}", url);
 
-        let (stream, _) = connect_async(url)
+        let mut request = url.into_client_request()?;
+        if let Some(token) = &options.token {
+            request.headers_mut().insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token))?,
+            );
+        }
+        if options.wire_format != WireFormat::Json {
+            request.headers_mut().insert(
+                HeaderName::from_static(WIRE_FORMAT_HEADER),
+                HeaderValue::from_static(options.wire_format.as_str()),
+            );
+        }
+        if let Some(topics) = options.topics.header_value() {
+            request.headers_mut().insert(
+                HeaderName::from_static(TOPICS_HEADER),
+                HeaderValue::from_str(&topics)?,
+            );
+        }
+        if let Some(signer) = &options.signer {
+            request.headers_mut().insert(
+                HeaderName::from_static(DEVICE_KEY_HEADER),
+                HeaderValue::from_str(&signer.public_key())?,
+            );
+            request.headers_mut().insert(
+                HeaderName::from_static(CHALLENGE_HEADER),
+                HeaderValue::from_static("1"),
+            );
+        }
+        request.headers_mut().insert(
+            HeaderName::from_static(VERSION_HEADER),
+            HeaderValue::from_str(&PeerVersion::ours().header_value())?,
+        );
+        if let Some(token) = &options.resume {
+            request.headers_mut().insert(
+                HeaderName::from_static(RESUME_HEADER),
+                HeaderValue::from_str(token)?,
+            );
+        }
+        let transport = tls::connect(request.uri(), &options.tls)
             .await
             .map_err(|e| anyhow!("Failed to connect to {}: {}", url, e))?;
+        let (mut stream, response) = client_async(request, transport)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to {}: {}", url, e))?;
+
+        let format = response
+            .headers()
+            .get(WIRE_FORMAT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(WireFormat::parse)
+            .unwrap_or_default();
+        let session = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        // Servers that check who we are challenge us; the first frame they
+        // send back says whether they let us in
+        let challenge = response
+            .headers()
+            .get(CHALLENGE_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let mut first = None;
+        if let (Some(nonce), Some(signer)) = (challenge, &options.signer) {
+            let payload = access::challenge_payload(nonce, &signer.public_key());
+            let proof = WireMessage::Proof {
+                signature: signer.sign_bytes(&payload),
+            };
+            stream
+                .send(Message::Text(serde_json::to_string(&proof)?))
+                .await
+                .map_err(|e| anyhow!("Failed to prove our key to {}: {}", url, e))?;
+            match tokio::time::timeout(access::PROOF_TIMEOUT, stream.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    let reason = frame.map(|frame| frame.reason.to_string());
+                    return Err(anyhow!(
+                        "Refused by {}: {}",
+                        url,
+                        reason.as_deref().unwrap_or("connection closed")
+                    ));
+                }
+                Ok(Some(Ok(frame))) => first = Some(Ok(frame)),
+                Ok(Some(Err(e))) => return Err(anyhow!("Failed to connect to {}: {}", url, e)),
+                Ok(None) => return Err(anyhow!("Refused by {}: connection closed", url)),
+                Err(_) => return Err(anyhow!("Timed out joining {}", url)),
+            }
+        }
+        tracing::info!("Connected to server: {} ({} frames)", url, format.as_str());
+
+        let (sink, stream) = stream.split();
+        // The frame that told us we were in is read like any other
+        let mut stream = futures_util::stream::iter(first).chain(stream);
+        let (tx, rx) = mpsc::unbounded_channel();
 
-        tracing::info!("Connected to server: {}", url);
+        // Read in the background; the channel closes when the server goes away
+        let reader_url = url.to_string();
+        let link = LinkTracker::new();
+        let reader_link = link.clone();
+        let download = options.network.download();
+        let mut faults = options.faults.as_ref().and_then(FaultInjector::new);
+        let reader = tokio::spawn(async move {
+            let mut chunks = Reassembler::default();
+            while let Some(frame) = stream.next().await {
+                reader_link.received();
+                match frame {
+                    Ok(Message::Close(_)) => {
+                        tracing::warn!("Server closed connection: {}", reader_url);
+                        break;
+                    }
+                    Ok(Message::Pong(payload)) => reader_link.pong(&payload),
+                    Ok(frame) => {
+                        if let Some(download) = &download {
+                            download.take(frame.len()).await;
+                        }
+                        match wire::decode(&frame) {
+                            None => {}
+                            Some(Ok(WireMessage::Hello {
+                                time_ms, version, ..
+                            })) => {
+                                let received_ms = chrono::Utc::now().timestamp_millis();
+                                if let Some(version) = &version {
+                                    if let Some(warning) = version.warning() {
+                                        tracing::warn!("Server {} {}", reader_url, warning);
+                                    }
+                                    reader_link.set_version(version.clone());
+                                }
+                                if tx
+                                    .send(WireMessage::Hello {
+                                        time_ms,
+                                        version,
+                                        received_ms,
+                                    })
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                            Some(Ok(msg)) => {
+                                // Large values arrive in chunks; pass on whole changes only
+                                let Some(msg) = chunks.accept(msg) else {
+                                    continue;
+                                };
+                                if let Some(faults) = &mut faults {
+                                    for (delay, msg) in faults.apply(msg) {
+                                        if delay.is_zero() {
+                                            let _ = tx.send(msg);
+                                            continue;
+                                        }
+                                        let tx = tx.clone();
+                                        tokio::spawn(async move {
+                                            tokio::time::sleep(delay).await;
+                                            let _ = tx.send(msg);
+                                        });
+                                    }
+                                    continue;
+                                }
+                                if tx.send(msg).is_err() {
+                                    break;
+                                }
+                            }
+                            Some(Err(e)) => tracing::warn!("Ignoring malformed message: {}", e),
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("WebSocket error from {}: {}", reader_url, e);
+                        break;
+                    }
+                }
+            }
+        });
 
         Ok(Self {
-            stream,
+            sink: Arc::new(Mutex::new(sink)),
+            incoming: Arc::new(Mutex::new(rx)),
+            reader,
             server_url: url.to_string(),
+            format,
+            link,
+            upload: options.network.upload(),
+            session,
         })
     }
 
     pub async fn send(&mut self, msg: SyncMessage) -> Result<()> {
-        let json = serde_json::to_string(&msg)?;
-        self.stream
-            .send(Message::Text(json))
+        for frame in chunking::split(&msg) {
+            self.send_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `msg` to the connection that asked for it (`SeqRequest` asker
+    /// `to`) rather than to everyone
+    pub async fn reply(&mut self, to: u64, msg: SyncMessage) -> Result<()> {
+        self.reply_frames(to, chunking::split(&msg)).await
+    }
+
+    /// Send `frames` (a change or a snapshot, in pieces) to the connection
+    /// `to` alone, through the relay
+    pub async fn reply_frames(&mut self, to: u64, frames: Vec<WireMessage>) -> Result<()> {
+        for frame in frames {
+            self.send_frame(&WireMessage::Reply {
+                to,
+                frame: Box::new(frame),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_frame(&mut self, frame: &WireMessage) -> Result<()> {
+        let message = wire::encode(frame, self.format)?;
+        if let Some(upload) = &self.upload {
+            upload.take(message.len()).await;
+        }
+        self.sink
+            .lock()
+            .await
+            .send(message)
             .await
             .map_err(|e| anyhow!("Failed to send message: {}", e))?;
+        self.link.sent();
         Ok(())
     }
 
-    pub async fn receive(&mut self) -> Result<Option<SyncMessage>> {
-        match self.stream.next().await {
-            Some(Ok(Message::Text(text))) => {
-                let msg: SyncMessage = serde_json::from_str(&text)?;
-                Ok(Some(msg))
-            }
-            Some(Ok(Message::Close(_))) => {
-                tracing::warn!("Server closed connection");
-                Ok(None)
-            }
-            Some(Err(e)) => Err(anyhow!("WebSocket error: {}", e)),
-            None => Ok(None),
-            _ => Ok(None),
-        }
+    /// Next message from the server, or None once the connection is closed
+    pub async fn receive(&mut self) -> Result<Option<WireMessage>> {
+        Ok(self.incoming.lock().await.recv().await)
+    }
+
+    pub fn incoming(&self) -> IncomingHandle {
+        Arc::clone(&self.incoming)
     }
 
     pub fn server_url(&self) -> &str {
         &self.server_url
     }
 
+    pub fn wire_format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Token to resume this session with after a drop
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Round-trip time, lost pings and confirmed frames to the server,
+    /// which is a `kind` peer
+    pub fn link(&self, kind: PeerKind) -> PeerLink {
+        self.link.snapshot(kind, &self.server_url)
+    }
+
+    /// Ping the server; its pong updates `link()`
     pub async fn ping(&mut self) -> Result<()> {
-        self.stream
-            .send(Message::Ping(vec![]))
+        self.pinger().ping().await
+    }
+
+    /// A handle that pings the server without borrowing the client, so the
+    /// node's lock needn't be held while the ping goes out
+    pub fn pinger(&self) -> Pinger {
+        Pinger {
+            sink: Arc::clone(&self.sink),
+            link: self.link.clone(),
+        }
+    }
+
+    /// When the server last answered a ping
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.link.last_pong()
+    }
+
+    /// Say goodbye with a close frame instead of just dropping the socket
+    pub async fn close(self) -> Result<()> {
+        self.sink
+            .lock()
             .await
-            .map_err(|e| anyhow!("Ping failed: {}", e))?;
-        Ok(())
+            .send(Message::Close(None))
+            .await
+            .map_err(|e| anyhow!("Failed to close connection: {}", e))
+    }
+}
+
+/// Pings a client's server (`WebSocketClient::pinger()`)
+#[derive(Clone)]
+pub struct Pinger {
+    sink: Arc<Mutex<WsSink>>,
+    link: LinkTracker,
+}
+
+impl Pinger {
+    /// Ping the server; its pong updates the client's `link()`
+    pub async fn ping(&self) -> Result<()> {
+        self.sink
+            .lock()
+            .await
+            .send(Message::Ping(self.link.next_ping()))
+            .await
+            .map_err(|e| anyhow!("Ping failed: {}", e))
+    }
+
+    /// When the server last answered a ping
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.link.last_pong()
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        self.reader.abort();
     }
 }
 
@@ -80,13 +636,13 @@ mod tests {
 
     #[tokio::test]
     async fn test_sync_message_serialization() {
-        let msg = SyncMessage {
-            key: "TEST_KEY".to_string(),
-            value: "test_value".to_string(),
-            timestamp: 1234567890,
-            machine_id: "machine-1".to_string(),
-            deleted: false,
-        };
+        let msg = SyncMessage::new(
+            "TEST_KEY".to_string(),
+            "test_value".to_string(),
+            1234567890,
+            "machine-1".to_string(),
+            false,
+        );
 
         let json = serde_json::to_string(&msg).unwrap();
         let deserialized: SyncMessage = serde_json::from_str(&json).unwrap();
@@ -94,4 +650,19 @@ mod tests {
         assert_eq!(msg.key, deserialized.key);
         assert_eq!(msg.value, deserialized.value);
     }
+
+    #[test]
+    fn test_server_shutdown_wire_format() {
+        let msg = WireMessage::ServerShutdown {
+            reason: "failback".to_string(),
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"type\":\"server_shutdown\""));
+
+        match serde_json::from_str::<WireMessage>(&json).unwrap() {
+            WireMessage::ServerShutdown { reason } => assert_eq!(reason, "failback"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
 }
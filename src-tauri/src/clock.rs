@@ -0,0 +1,111 @@
+// Clock used for change timestamps, and skew against the LAN server's clock
+// measured when connecting to it
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Seconds added to the system clock for every timestamp we write
+static OFFSET_SECS: AtomicI64 = AtomicI64::new(0);
+
+/// What to do when the LAN server's clock disagrees with ours
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkewPolicy {
+    /// Log a warning and raise a `ClockSkew` event
+    #[default]
+    Warn,
+    /// Also stamp our changes with the server's time instead of ours
+    Compensate,
+}
+
+/// `[clock]`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClockConfig {
+    pub skew: SkewPolicy,
+    /// Differences up to this are ignored
+    pub max_skew_secs: i64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            skew: SkewPolicy::Warn,
+            max_skew_secs: 5,
+        }
+    }
+}
+
+/// "90s", "30m", "1h", "7d" or plain seconds, in seconds (more than zero)
+pub fn parse_duration(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let invalid = || anyhow!("Invalid duration {:?} (e.g. 30m, 1h, 7d)", s);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "" | "s" => number,
+        "m" => number.saturating_mul(60),
+        "h" => number.saturating_mul(3600),
+        "d" => number.saturating_mul(24 * 3600),
+        _ => return Err(invalid()),
+    };
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(secs)
+}
+
+/// Unix time for change timestamps: the system clock, corrected by the
+/// offset in force (zero unless compensating)
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp() + offset()
+}
+
+pub fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis() + offset() * 1000
+}
+
+pub fn offset() -> i64 {
+    OFFSET_SECS.load(Ordering::Relaxed)
+}
+
+pub fn set_offset(secs: i64) {
+    OFFSET_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// How far the server's clock is ahead of ours, from its `hello` time and
+/// when we received it. One-way latency is negligible next to the threshold.
+pub fn measure_skew(server_millis: i64, received_millis: i64) -> i64 {
+    (server_millis - received_millis) / 1000
+}
+
+/// The offset to use after measuring `skew`: the server's, once it is past
+/// the threshold and we compensate, otherwise none
+pub fn offset_for(skew: i64, config: &ClockConfig) -> i64 {
+    if config.skew == SkewPolicy::Compensate && skew.abs() > config.max_skew_secs {
+        skew
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compensates_only_past_threshold() {
+        let warn = ClockConfig::default();
+        let compensate = ClockConfig {
+            skew: SkewPolicy::Compensate,
+            ..warn
+        };
+
+        // Our clock is 60s behind the server's
+        let skew = measure_skew(1_000_060_000, 1_000_000_000);
+        assert_eq!(skew, 60);
+        assert_eq!(offset_for(skew, &warn), 0);
+        assert_eq!(offset_for(skew, &compensate), 60);
+        assert_eq!(offset_for(-3, &compensate), 0);
+    }
+}
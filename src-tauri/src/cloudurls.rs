@@ -0,0 +1,195 @@
+// Cloud server failover list (`[client] cloud_url` as a list): servers are
+// tried in order, except that those that failed recently are tried last.
+// Only when every one fails does the node drop to the LAN. A node on a
+// lower server moves back up once the health monitor sees one listed ahead
+// of it answer, so every node ends up on the same relay again.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How long a server that failed stays at the back of the list
+pub const FAILURE_MEMORY: Duration = Duration::from_secs(300);
+
+/// `[client] cloud_url`: one URL, or a list tried in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloudUrls(Vec<String>);
+
+impl CloudUrls {
+    pub fn new(urls: Vec<String>) -> Result<Self> {
+        if urls.is_empty() {
+            return Err(anyhow!("cloud_url needs at least one URL"));
+        }
+        Ok(Self(urls))
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl From<String> for CloudUrls {
+    fn from(url: String) -> Self {
+        Self(vec![url])
+    }
+}
+
+/// Comma-separated, as the GUI settings edit it
+impl FromStr for CloudUrls {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(
+            s.split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for CloudUrls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join(", "))
+    }
+}
+
+/// A single URL is written back as a string, so existing configs keep
+/// their shape
+impl Serialize for CloudUrls {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [url] => serializer.serialize_str(url),
+            urls => urls.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CloudUrls {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+        match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(url) => Ok(Self(vec![url])),
+            OneOrMany::Many(urls) => Self::new(urls).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Which cloud server to try next
+#[derive(Debug, Clone)]
+pub struct CloudServers {
+    urls: Vec<String>,
+    /// The server we are (or were last) connected to
+    current: Option<String>,
+    /// When each server last failed to connect
+    failed: HashMap<String, Instant>,
+}
+
+impl CloudServers {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            current: None,
+            failed: HashMap::new(),
+        }
+    }
+
+    /// The servers in the order to try them at `now`: those that haven't
+    /// failed within `FAILURE_MEMORY` in list order, then the rest, longest
+    /// since failing first
+    pub fn order(&self, now: Instant) -> Vec<String> {
+        let mut order = self.urls.clone();
+        order.sort_by_key(|url| {
+            self.failed
+                .get(url)
+                .filter(|at| now.duration_since(**at) < FAILURE_MEMORY)
+                .copied()
+        });
+        order
+    }
+
+    /// The servers listed ahead of the current one, in list order: the ones
+    /// to move back to as soon as they answer
+    pub fn preferred(&self) -> &[String] {
+        let current = self
+            .current
+            .as_ref()
+            .and_then(|current| self.urls.iter().position(|url| url == current))
+            .unwrap_or(0);
+        &self.urls[..current]
+    }
+
+    pub fn connected(&mut self, url: &str) {
+        self.failed.remove(url);
+        self.current = Some(url.to_string());
+    }
+
+    /// A server that fails loses its place at the front
+    pub fn failed(&mut self, url: &str, now: Instant) {
+        self.failed.insert(url.to_string(), now);
+        if self.current.as_deref() == Some(url) {
+            self.current = None;
+        }
+    }
+
+    /// The server we last connected to, else the first in the list
+    pub fn current(&self) -> &str {
+        self.current
+            .as_deref()
+            .or(self.urls.first().map(String::as_str))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_url_list_order() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Client {
+            cloud_url: CloudUrls,
+        }
+        let one: Client = toml::from_str(r#"cloud_url = "wss://a""#).unwrap();
+        assert_eq!(one.cloud_url.urls(), ["wss://a"]);
+        assert_eq!(toml::to_string(&one).unwrap(), "cloud_url = \"wss://a\"\n");
+        let many: Client = toml::from_str(r#"cloud_url = ["wss://a", "wss://b"]"#).unwrap();
+        assert_eq!(many.cloud_url.to_string(), "wss://a, wss://b");
+        assert_eq!(
+            "wss://a, wss://b".parse::<CloudUrls>().unwrap(),
+            many.cloud_url
+        );
+        assert!(toml::from_str::<Client>("cloud_url = []").is_err());
+
+        let urls = ["wss://a", "wss://b", "wss://c"].map(String::from).to_vec();
+        let mut servers = CloudServers::new(urls.clone());
+        let start = Instant::now();
+        assert_eq!(servers.order(start), urls);
+        assert_eq!(servers.current(), "wss://a");
+
+        // a is down: b, then c, then a
+        servers.failed("wss://a", start);
+        assert_eq!(servers.order(start), ["wss://b", "wss://c", "wss://a"]);
+        // Connected to c: a and b are the ones to move back to
+        servers.connected("wss://c");
+        assert_eq!(servers.current(), "wss://c");
+        assert_eq!(servers.preferred(), ["wss://a", "wss://b"]);
+        // Once a has recovered it is first again, not c
+        let later = start + FAILURE_MEMORY;
+        assert_eq!(servers.order(later), urls);
+        // Losing c sends it to the back
+        servers.failed("wss://c", later);
+        assert_eq!(servers.order(later), ["wss://a", "wss://b", "wss://c"]);
+        servers.connected("wss://a");
+        assert!(servers.preferred().is_empty());
+    }
+}
@@ -1,9 +1,43 @@
 // Configuration module for EnvMesh
 use anyhow::{Context, Result};
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::access::{self, PeerAccess};
+use crate::agent::AgentConfig;
+use crate::backup::BackupConfig;
+use crate::beacon::DiscoveryConfig;
+use crate::clock::ClockConfig;
+use crate::cloudurls::CloudUrls;
+use crate::context::ContextConfig;
+use crate::editor::EditorConfig;
+use crate::election::StrategyKind;
+use crate::envfiles::EnvFileConfig;
+use crate::expiry::ExpiryConfig;
+use crate::fallback::FallbackConfig;
+use crate::faults::FaultConfig;
+use crate::gitsync::GitSyncConfig;
+use crate::health::HealthConfig;
+use crate::hooks::HookConfig;
+use crate::keys::KeyPolicy;
+use crate::netif::InterfaceFilter;
 use crate::node::{NodeConfig, ServerMode};
+use crate::quarantine::QuarantineConfig;
+use crate::ratelimit::RateLimit;
+use crate::relay::{self, RelayConfig};
+use crate::replication::ReplicationConfig;
+use crate::rotation::RotationConfig;
+use crate::secretcheck::CheckConfig;
+use crate::signing;
+use crate::sync::SyncFilter;
+use crate::throttle::NetworkConfig;
+use crate::tls::TlsConfig;
+use crate::users::{UserConfig, Users};
+use crate::vaults::VaultConfig;
+use crate::webhooks::WebhookConfig;
+use crate::wire::WireFormat;
+use crate::wsl;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
@@ -12,6 +46,125 @@ pub struct Config {
 
     #[serde(default)]
     pub client: ClientConfig,
+
+    #[serde(default)]
+    pub election: ElectionConfig,
+
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+
+    #[serde(default)]
+    pub sync: SyncConfig,
+
+    #[serde(default)]
+    pub security: SecurityConfig,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    #[serde(default)]
+    pub keys: KeysConfig,
+
+    #[serde(default)]
+    pub ipc: IpcConfig,
+
+    /// Where the daemon keeps its database, socket and device key
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    #[serde(default)]
+    pub clock: ClockConfig,
+
+    /// Bandwidth limits for sync traffic
+    #[serde(default)]
+    pub network: NetworkConfig,
+
+    /// CA bundle, pins and insecure mode for wss:// servers
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    /// UDP broadcast discovery, for networks that block mDNS
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+
+    /// How the health monitor probes the cloud server
+    #[serde(default)]
+    pub health: HealthConfig,
+
+    #[serde(default)]
+    pub checks: CheckConfig,
+
+    /// When to warn about credentials that are about to expire
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+
+    /// Where `get` looks for keys the mesh doesn't have
+    #[serde(default)]
+    pub fallback: FallbackConfig,
+
+    /// JSON-RPC endpoint for editor extensions
+    #[serde(default)]
+    pub editor: EditorConfig,
+
+    /// Socket processes lease single secrets from, under `[[agent.rules]]`
+    #[serde(default)]
+    pub agent: AgentConfig,
+
+    /// Meshes this machine relays for other teams while it is the server
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    /// Scheduled encrypted backups written by the daemon (off when absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<BackupConfig>,
+
+    /// Replication through a git repository (off when absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_sync: Option<GitSyncConfig>,
+
+    /// Snapshots in an S3-compatible bucket (off when absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replication: Option<ReplicationConfig>,
+
+    /// Risky incoming changes held for approval (off when absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine: Option<QuarantineConfig>,
+
+    /// Dropped, delayed, duplicated and reordered messages for rehearsing
+    /// failures (needs the `faults` build feature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub faults: Option<FaultConfig>,
+
+    /// Commands the daemon runs when matching variables change
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookConfig>,
+
+    /// Environment files (.env, JSON, systemd, launchd) the daemon keeps up
+    /// to date
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub writers: Vec<EnvFileConfig>,
+
+    /// Keys the daemon gives new values on a schedule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rotations: Vec<RotationConfig>,
+
+    /// Namespaces mirrored to Azure Key Vault or GCP Secret Manager
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vaults: Vec<VaultConfig>,
+
+    /// Signed HTTP notifications the daemon sends for changes and conflicts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// People sharing the mesh, their roles and enrolled machines
+    /// (`envmesh-cli user`); roles are enforced once there are any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+
+    /// Daemons and default namespaces the CLI can switch between
+    /// (`envmesh-cli context`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contexts: Vec<ContextConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,20 +173,30 @@ pub struct ServerConfig {
     #[serde(default)]
     pub mode: String,
 
-    /// Address to listen on (e.g., "127.0.0.1" for local, "0.0.0.0" for public)
+    /// Address to listen on (e.g., "127.0.0.1" for local, "0.0.0.0" for public,
+    /// "::" for every IPv4 and IPv6 address)
     #[serde(default = "default_listen_addr")]
     pub listen: String,
 
     /// Port to listen on
     #[serde(default = "default_lan_port")]
     pub port: u16,
+
+    /// Listen only on these interfaces (e.g. ["eth0", "en*"]) instead of `listen`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<String>,
+
+    /// Never listen on these interfaces (e.g. ["tun*", "docker*"])
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_interfaces: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientConfig {
-    /// URL of cloud server to connect to
+    /// URL of cloud server to connect to, or a list of them to fail over
+    /// between
     #[serde(default = "default_cloud_url")]
-    pub cloud_url: String,
+    pub cloud_url: CloudUrls,
 
     /// Enable cloud server connection
     #[serde(default = "default_true")]
@@ -42,6 +205,205 @@ pub struct ClientConfig {
     /// Enable LAN server discovery and creation
     #[serde(default = "default_true")]
     pub enable_lan: bool,
+
+    /// Token for a relay that hosts several meshes (ENVMESH_MESH_TOKEN
+    /// overrides it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh_token: Option<String>,
+
+    /// "binary" sends changes as compact binary frames to servers that
+    /// support them; "json" (default) works with every server
+    #[serde(default)]
+    pub wire_format: WireFormat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ElectionConfig {
+    /// Election strategy: peer-id, priority, uptime, or resource-aware
+    #[serde(default = "default_election_strategy")]
+    pub strategy: String,
+
+    /// Priority used by the "priority" strategy (higher wins)
+    #[serde(default)]
+    pub priority: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    /// Show native notifications for peer, failover, and conflict events
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// Only sync keys matching these patterns (empty = all keys)
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Never sync keys matching these patterns
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Only sync these namespaces (empty = all); servers then send us
+    /// nothing from the others
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    /// Largest value accepted for local writes; larger values are rejected
+    /// (values over 16 KiB are chunked when synced)
+    #[serde(default = "default_max_value_bytes")]
+    pub max_value_bytes: usize,
+
+    /// Incoming changes accepted per sending machine before they are dropped
+    /// and the sender is banned
+    #[serde(default)]
+    pub sync_rate: RateLimit,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeysConfig {
+    /// Key name validation: strict, standard, or off
+    #[serde(default)]
+    pub policy: KeyPolicy,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// Device public keys (`envmesh-cli device-key` on each machine) allowed
+    /// to send changes. When set, unsigned changes and other keys are rejected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_keys: Vec<String>,
+
+    /// Device keys of the only peers to accept connections and changes
+    /// from, or to connect to (empty = everyone)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_peers: Vec<String>,
+
+    /// Device keys never to deal with (`envmesh-cli peer block`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_peers: Vec<String>,
+}
+
+/// How the CLI reaches the daemon, and extra daemon listeners
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IpcConfig {
+    /// Daemon endpoint for the CLI: "unix:/path", "pipe:NAME" (Windows),
+    /// "host:port", or "windows-host:port" to reach a Windows daemon from WSL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+
+    /// Additional TCP address for the daemon to accept CLI connections on
+    /// (e.g. "127.0.0.1:37842"). Addresses other hosts can reach, such as
+    /// "0.0.0.0:37842" for WSL, need ENVMESH_IPC_TOKEN set on both ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+
+    /// `[[contexts]]` entry the CLI uses unless told otherwise
+    /// (`envmesh-cli context use`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// Data directory, for the database (`envmesh.db`), control socket and
+    /// device key; Windows paths are translated inside WSL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// Purge variables deleted longer ago than this, tombstone and history
+    /// (0 = keep them, restorable with `envmesh-cli trash restore`)
+    #[serde(default)]
+    pub trash_retention_days: u64,
+}
+
+/// Where the daemon keeps its database, socket and device key unless
+/// `--data-dir`, ENVMESH_DATA_DIR or `[storage] path` says otherwise
+pub fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("envmesh")
+}
+
+/// Data directory of the daemon instance `name` (`--instance`), next to
+/// the default one's data
+pub fn instance_data_dir(name: &str) -> Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!(
+            "Invalid instance name {:?}: use letters, digits, '-' and '_'",
+            name
+        );
+    }
+    Ok(default_data_dir().join("instances").join(name))
+}
+
+/// The data directory `--data-dir` or `--instance` point at, if either was
+/// given (clap keeps them apart)
+pub fn data_dir_flag(data_dir: Option<&str>, instance: Option<&str>) -> Result<Option<String>> {
+    match instance {
+        Some(name) => Ok(Some(
+            instance_data_dir(name)?.to_string_lossy().into_owned(),
+        )),
+        None => Ok(data_dir.map(str::to_string)),
+    }
+}
+
+/// The data directory asked for: `flag` (--data-dir), then ENVMESH_DATA_DIR,
+/// then `[storage] path` (None: the platform's, or the GUI's app directory)
+pub fn configured_data_dir(flag: Option<&str>, config: &Config) -> Option<PathBuf> {
+    flag.map(str::to_string)
+        .or_else(|| std::env::var("ENVMESH_DATA_DIR").ok())
+        .or_else(|| config.storage.path.clone())
+        .filter(|dir| !dir.trim().is_empty())
+        .map(|dir| wsl::translate_path(&dir))
+}
+
+/// `configured_data_dir()`, or `default_data_dir()`
+pub fn data_dir(flag: Option<&str>, config: &Config) -> PathBuf {
+    configured_data_dir(flag, config).unwrap_or_else(default_data_dir)
+}
+
+/// User-facing settings exposed to the GUI
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    /// Comma-separated when there are several
+    pub cloud_url: String,
+    pub server_mode: String,
+    pub enable_cloud: bool,
+    pub enable_lan: bool,
+    pub sync_include: Vec<String>,
+    pub sync_exclude: Vec<String>,
+    pub notifications_enabled: bool,
+}
+
+/// Partial settings change; `None` leaves a setting untouched
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SettingsUpdate {
+    pub cloud_url: Option<String>,
+    pub server_mode: Option<String>,
+    pub enable_cloud: Option<bool>,
+    pub enable_lan: Option<bool>,
+    pub sync_include: Option<Vec<String>>,
+    pub sync_exclude: Option<Vec<String>>,
+    pub notifications_enabled: Option<bool>,
+}
+
+impl SettingsUpdate {
+    /// Whether applying this update requires the node to reconnect
+    pub fn affects_connection(&self) -> bool {
+        self.cloud_url.is_some()
+            || self.server_mode.is_some()
+            || self.enable_cloud.is_some()
+            || self.enable_lan.is_some()
+    }
 }
 
 impl Default for ServerConfig {
@@ -50,6 +412,8 @@ impl Default for ServerConfig {
             mode: "auto".to_string(),
             listen: default_listen_addr(),
             port: default_lan_port(),
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
         }
     }
 }
@@ -60,10 +424,40 @@ impl Default for ClientConfig {
             cloud_url: default_cloud_url(),
             enable_cloud: true,
             enable_lan: true,
+            mesh_token: None,
+            wire_format: WireFormat::default(),
+        }
+    }
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: default_election_strategy(),
+            priority: 0,
+        }
+    }
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_value_bytes: default_max_value_bytes(),
+            sync_rate: RateLimit::default(),
         }
     }
 }
 
+fn default_max_value_bytes() -> usize {
+    crate::chunking::DEFAULT_MAX_VALUE_BYTES
+}
+
 fn default_listen_addr() -> String {
     "127.0.0.1".to_string()
 }
@@ -72,8 +466,12 @@ fn default_lan_port() -> u16 {
     8765
 }
 
-fn default_cloud_url() -> String {
-    "ws://localhost:8080".to_string()
+fn default_cloud_url() -> CloudUrls {
+    CloudUrls::from("ws://localhost:8080".to_string())
+}
+
+fn default_election_strategy() -> String {
+    "peer-id".to_string()
 }
 
 fn default_true() -> bool {
@@ -93,44 +491,235 @@ impl Config {
 
     /// Try to load configuration from default locations
     pub fn load_default() -> Result<Self> {
-        // Try ~/.envmesh/config.toml
-        if let Some(home_dir) = dirs::home_dir() {
-            let config_path = home_dir.join(".envmesh").join("config.toml");
-            if config_path.exists() {
+        match Self::find_default_path() {
+            Some(config_path) => {
                 tracing::info!("Loading config from {}", config_path.display());
-                return Self::from_file(&config_path);
+                Self::from_file(&config_path)
+            }
+            None => {
+                // Return default config if no file found
+                tracing::info!("No config file found, using defaults");
+                Ok(Self::default())
             }
         }
+    }
 
-        // Try system config directory
-        if let Some(config_dir) = dirs::config_dir() {
-            let config_path = config_dir.join("envmesh").join("config.toml");
-            if config_path.exists() {
-                tracing::info!("Loading config from {}", config_path.display());
-                return Self::from_file(&config_path);
+    /// First existing config file: ~/.envmesh/config.toml, then the system
+    /// config directory, then (inside WSL) the Windows user's config
+    pub fn find_default_path() -> Option<PathBuf> {
+        let candidates = [
+            dirs::home_dir().map(|home| home.join(".envmesh").join("config.toml")),
+            dirs::config_dir().map(|dir| dir.join("envmesh").join("config.toml")),
+            crate::wsl::windows_profile_dir()
+                .map(|profile| profile.join(".envmesh").join("config.toml")),
+        ];
+
+        candidates.into_iter().flatten().find(|path| path.exists())
+    }
+
+    /// Where settings changes are written: the loaded file if there is one,
+    /// otherwise ~/.envmesh/config.toml
+    pub fn default_save_path() -> PathBuf {
+        Self::find_default_path().unwrap_or_else(|| {
+            dirs::home_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join(".envmesh")
+                .join("config.toml")
+        })
+    }
+
+    /// Write configuration back to a TOML file
+    pub fn save(&self, path: &PathBuf) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+
+        // It can hold tokens and webhook secrets: write a private copy and
+        // swap it in, so a crash never leaves a truncated config behind
+        let contents = toml::to_string_pretty(self).context("Failed to serialize config")?;
+        let partial = path.with_extension("partial");
+        let _ = std::fs::remove_file(&partial);
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&partial)
+                .and_then(|mut file| {
+                    file.write_all(contents.as_bytes())?;
+                    file.sync_all()
+                })
+                .context(format!(
+                    "Failed to write config file: {}",
+                    partial.display()
+                ))?;
+        }
+        #[cfg(not(unix))]
+        std::fs::write(&partial, contents).context(format!(
+            "Failed to write config file: {}",
+            partial.display()
+        ))?;
+        std::fs::rename(&partial, path)
+            .context(format!("Failed to write config file: {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// `[fallback]`, kept away from the environment variables this config
+    /// names as holding secrets
+    pub fn fallback(&self) -> FallbackConfig {
+        let mut hidden = Vec::new();
+        if let Some(backup) = &self.backup {
+            hidden.push(backup.passphrase_env.clone());
+        }
+        if let Some(git_sync) = &self.git_sync {
+            hidden.push(git_sync.passphrase_env.clone());
+        }
+        if let Some(replication) = &self.replication {
+            hidden.extend([
+                replication.passphrase_env.clone(),
+                replication.access_key_env.clone(),
+                replication.secret_key_env.clone(),
+                replication.session_token_env.clone(),
+            ]);
+        }
+        for vault in &self.vaults {
+            if let crate::vaults::BackendConfig::Azure {
+                client_secret_env, ..
+            } = &vault.backend
+            {
+                hidden.push(client_secret_env.clone());
+            }
+        }
+        FallbackConfig {
+            hidden,
+            ..self.fallback.clone()
+        }
+    }
+
+    pub fn sync_filter(&self) -> SyncFilter {
+        SyncFilter {
+            include: self.sync.include.clone(),
+            exclude: self.sync.exclude.clone(),
+            namespaces: self.sync.namespaces.clone(),
+        }
+    }
+
+    pub fn trusted_keys(&self) -> Result<Vec<VerifyingKey>> {
+        self.security
+            .trusted_keys
+            .iter()
+            .map(|key| signing::parse_public_key(key).context("[security] trusted_keys"))
+            .collect()
+    }
+
+    pub fn users(&self) -> Users {
+        Users::new(self.users.clone())
+    }
+
+    /// Entries other than device keys (machine IDs, from older configs)
+    /// match nobody: an allowlist of them lets no one in
+    pub fn peer_access(&self) -> PeerAccess {
+        for (list, ids) in [
+            ("allowed_peers", &self.security.allowed_peers),
+            ("blocked_peers", &self.security.blocked_peers),
+        ] {
+            for id in ids.iter().filter(|id| !access::is_device_key(id)) {
+                tracing::warn!(
+                    "[security] {}: {} is not a device key, ignoring it",
+                    list,
+                    id
+                );
             }
         }
+        PeerAccess {
+            allowed: self.security.allowed_peers.clone(),
+            blocked: self.security.blocked_peers.clone(),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            cloud_url: self.client.cloud_url.to_string(),
+            server_mode: self.server.mode.clone(),
+            enable_cloud: self.client.enable_cloud,
+            enable_lan: self.client.enable_lan,
+            sync_include: self.sync.include.clone(),
+            sync_exclude: self.sync.exclude.clone(),
+            notifications_enabled: self.notifications.enabled,
+        }
+    }
 
-        // Return default config if no file found
-        tracing::info!("No config file found, using defaults");
-        Ok(Self::default())
+    /// Apply a settings change in memory (call `save` to persist it)
+    pub fn apply_settings(&mut self, update: SettingsUpdate) -> Result<()> {
+        if let Some(cloud_url) = update.cloud_url {
+            self.client.cloud_url = cloud_url.parse()?;
+        }
+        if let Some(server_mode) = update.server_mode {
+            self.server.mode = server_mode;
+        }
+        if let Some(enable_cloud) = update.enable_cloud {
+            self.client.enable_cloud = enable_cloud;
+        }
+        if let Some(enable_lan) = update.enable_lan {
+            self.client.enable_lan = enable_lan;
+        }
+        if let Some(include) = update.sync_include {
+            self.sync.include = include;
+        }
+        if let Some(exclude) = update.sync_exclude {
+            self.sync.exclude = exclude;
+        }
+        if let Some(enabled) = update.notifications_enabled {
+            self.notifications.enabled = enabled;
+        }
+
+        Ok(())
     }
 
     /// Convert to NodeConfig
     pub fn to_node_config(&self) -> NodeConfig {
-        let server_mode = match self.server.mode.to_lowercase().as_str() {
-            "server-preferred" | "server_preferred" => ServerMode::ServerPreferred,
-            "client-only" | "client_only" => ServerMode::ClientOnly,
-            _ => ServerMode::Auto,
+        let server_mode: ServerMode = self.server.mode.parse().unwrap_or_default();
+
+        let election_strategy = match self.election.strategy.to_lowercase().as_str() {
+            "priority" => StrategyKind::Priority(self.election.priority),
+            "uptime" | "uptime-weighted" => StrategyKind::Uptime,
+            "resource-aware" | "resource_aware" => StrategyKind::ResourceAware,
+            _ => StrategyKind::PeerId,
         };
 
         NodeConfig {
-            cloud_url: self.client.cloud_url.clone(),
+            cloud_urls: self.client.cloud_url.urls().to_vec(),
             lan_port: self.server.port,
             listen_addr: self.server.listen.clone(),
             enable_cloud: self.client.enable_cloud,
             enable_lan: self.client.enable_lan,
             server_mode,
+            election_strategy,
+            mesh_token: std::env::var(relay::MESH_TOKEN_ENV)
+                .ok()
+                .or_else(|| self.client.mesh_token.clone()),
+            relay: self.relay.clone(),
+            faults: self.faults.clone(),
+            wire_format: self.client.wire_format,
+            namespaces: self.sync.namespaces.clone(),
+            network: self.network,
+            tls: self.tls.clone(),
+            interfaces: InterfaceFilter {
+                include: self.server.interfaces.clone(),
+                exclude: self.server.exclude_interfaces.clone(),
+            },
+            discovery: self.discovery,
+            identity: None,
+            signer: None,
+            access: self.peer_access(),
+            users: self.users(),
+            // Held as revocations are enforced (`revoke::run`)
+            wipes: Vec::new(),
         }
     }
 }
@@ -147,6 +736,7 @@ mod tests {
         assert_eq!(config.server.port, 8765);
         assert!(config.client.enable_cloud);
         assert!(config.client.enable_lan);
+        assert!(config.notifications.enabled);
     }
 
     #[test]
@@ -162,4 +752,119 @@ mod tests {
         let node_config = config.to_node_config();
         assert_eq!(node_config.server_mode, ServerMode::ServerPreferred);
     }
+
+    #[test]
+    fn test_data_dir_flag_beats_config() {
+        let config: Config = toml::from_str("[storage]\npath = \"/srv/envmesh\"").unwrap();
+        assert_eq!(
+            data_dir(Some("/tmp/mesh"), &config),
+            PathBuf::from("/tmp/mesh")
+        );
+        assert_eq!(
+            data_dir_flag(None, Some("acme")).unwrap(),
+            Some(
+                default_data_dir()
+                    .join("instances")
+                    .join("acme")
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+        assert!(instance_data_dir("../acme").is_err());
+        if std::env::var_os("ENVMESH_DATA_DIR").is_none() {
+            assert_eq!(data_dir(None, &config), PathBuf::from("/srv/envmesh"));
+            assert_eq!(data_dir(None, &Config::default()), default_data_dir());
+        }
+    }
+
+    #[test]
+    fn test_save_replaces_the_file_privately() {
+        let dir = std::env::temp_dir().join(format!("envmesh-config-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.toml");
+        let mut config = Config::default();
+        config.save(&path).unwrap();
+        config.notifications.enabled = !config.notifications.enabled;
+        config.save(&path).unwrap();
+
+        let reloaded: Config = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(reloaded.notifications.enabled, config.notifications.enabled);
+        assert!(!path.with_extension("partial").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_apply_settings_round_trip() {
+        let mut config = Config::default();
+        config
+            .apply_settings(SettingsUpdate {
+                cloud_url: Some("wss://relay.example.com, wss://backup.example.com".to_string()),
+                sync_exclude: Some(vec!["LOCAL_*".to_string()]),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let settings = config.settings();
+        assert_eq!(
+            settings.cloud_url,
+            "wss://relay.example.com, wss://backup.example.com"
+        );
+        assert_eq!(settings.sync_exclude, vec!["LOCAL_*".to_string()]);
+        assert!(settings.enable_lan);
+
+        let toml = toml::to_string_pretty(&config).unwrap();
+        let reloaded: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(
+            reloaded.client.cloud_url.urls(),
+            ["wss://relay.example.com", "wss://backup.example.com"]
+        );
+        assert!(reloaded
+            .sync_filter()
+            .exclude
+            .contains(&"LOCAL_*".to_string()));
+    }
+
+    #[test]
+    fn test_hooks_parsing() {
+        let toml = r#"
+            [[hooks]]
+            pattern = "KUBECONFIG*"
+            command = "kubectl config view"
+
+            [[hooks]]
+            pattern = "AWS_*"
+            command = "aws sts get-caller-identity"
+            timeout_secs = 5
+        "#;
+
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.hooks.len(), 2);
+        assert_eq!(config.hooks[0].timeout_secs, 30);
+        assert_eq!(config.hooks[1].timeout_secs, 5);
+        assert!(toml::to_string_pretty(&config).is_ok());
+    }
+
+    #[test]
+    fn test_election_strategy_parsing() {
+        let config: Config = toml::from_str(
+            r#"
+            [election]
+            strategy = "priority"
+            priority = 100
+            "#,
+        )
+        .unwrap();
+
+        let node_config = config.to_node_config();
+        assert_eq!(node_config.election_strategy, StrategyKind::Priority(100));
+        assert_eq!(
+            Config::default().to_node_config().election_strategy,
+            StrategyKind::PeerId
+        );
+    }
 }
@@ -0,0 +1,132 @@
+// Named CLI contexts (`[[contexts]]`): which daemon `envmesh-cli` talks to
+// and the namespace it defaults to, so one config covers several meshes (a
+// work relay, the home LAN). `envmesh-cli context use NAME` makes one current
+// (`[ipc] context`); `--context NAME` or ENVMESH_CONTEXT pick one for a
+// single command. Explicit flags still win over the context's values.
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Picks a context for one command instead of `[ipc] context`
+pub const CONTEXT_ENV: &str = "ENVMESH_CONTEXT";
+
+/// `[[contexts]]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContextConfig {
+    pub name: String,
+
+    /// Daemon endpoint, as `--endpoint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+
+    /// The daemon's data directory, as `--data-dir`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<String>,
+
+    /// Daemon started with `envmesh-daemon --instance NAME`, as `--instance`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+
+    /// Namespace for commands that take `--namespace` and weren't given one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+impl ContextConfig {
+    /// The daemon to talk to, as an endpoint and a data directory: the
+    /// ones given by flags (`--endpoint`; `--data-dir` or `--instance`,
+    /// resolved), or, if neither was given, the context's. The two are
+    /// never mixed, so a flag naming another daemon isn't overridden by
+    /// the context's endpoint.
+    pub fn daemon(
+        &self,
+        endpoint: Option<String>,
+        data_dir: Option<String>,
+    ) -> Result<(Option<String>, Option<String>)> {
+        if endpoint.is_some() || data_dir.is_some() {
+            return Ok((endpoint, data_dir));
+        }
+        Ok((
+            self.endpoint.clone(),
+            config::data_dir_flag(self.data_dir.as_deref(), self.instance.as_deref())?,
+        ))
+    }
+}
+
+/// The context to use: `flag` (--context), then ENVMESH_CONTEXT, then
+/// `current` (`[ipc] context`); None if none is named
+pub fn select<'a>(
+    contexts: &'a [ContextConfig],
+    flag: Option<&str>,
+    current: Option<&str>,
+) -> Result<Option<&'a ContextConfig>> {
+    let env = std::env::var(CONTEXT_ENV).ok();
+    match flag.or(env.as_deref()).or(current) {
+        Some(name) => find(contexts, name).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn find<'a>(contexts: &'a [ContextConfig], name: &str) -> Result<&'a ContextConfig> {
+    match contexts.iter().find(|context| context.name == name) {
+        Some(context) => Ok(context),
+        None => bail!(
+            "No context named {:?} (see `envmesh-cli context list`)",
+            name
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contexts_are_selected_by_name() {
+        let contexts = vec![
+            ContextConfig {
+                name: "work".to_string(),
+                endpoint: Some("relay.example.com:37842".to_string()),
+                namespace: Some("work".to_string()),
+                ..Default::default()
+            },
+            ContextConfig {
+                name: "home".to_string(),
+                instance: Some("home".to_string()),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(find(&contexts, "home").unwrap().name, "home");
+        assert!(find(&contexts, "play").is_err());
+        assert_eq!(
+            select(&contexts, Some("work"), Some("home"))
+                .unwrap()
+                .map(|context| context.name.as_str()),
+            Some("work")
+        );
+    }
+
+    #[test]
+    fn test_flags_naming_a_daemon_win_over_the_context() {
+        let work = ContextConfig {
+            name: "work".to_string(),
+            endpoint: Some("relay.example.com:37842".to_string()),
+            data_dir: Some("/srv/envmesh".to_string()),
+            ..Default::default()
+        };
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            work.daemon(None, None).unwrap(),
+            (some("relay.example.com:37842"), some("/srv/envmesh"))
+        );
+        assert_eq!(
+            work.daemon(None, some("/tmp/other")).unwrap(),
+            (None, some("/tmp/other"))
+        );
+        assert_eq!(
+            work.daemon(some("localhost:1"), None).unwrap(),
+            (some("localhost:1"), None)
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Minimal cron expressions ("minute hour day-of-month month day-of-week")
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, TimeZone, Timelike};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Cron quirk: when both day fields are restricted, either may match
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+/// Parse one field (`*`, `5`, `1-5`, `*/15`, `0,30`) into a bitmask
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().context("invalid step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(anyhow!("step must be positive"));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let value = range.parse()?;
+            (value, if part.contains('/') { max } else { value })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(anyhow!("'{}' is outside {}-{}", part, min, max));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+impl Schedule {
+    /// Five cron fields, or one of `@hourly`, `@daily`, `@weekly`, `@monthly`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = match spec.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow!("expected 5 fields in schedule '{}'", spec));
+        };
+
+        let field = |name: &str, value: &str, min, max| {
+            parse_field(value, min, max)
+                .with_context(|| format!("invalid {} field '{}' in '{}'", name, value, spec))
+        };
+
+        // Both 0 and 7 mean Sunday
+        let mut weekdays = field("day-of-week", weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: field("minute", minute, 0, 59)?,
+            hours: field("hour", hour, 0, 23)?,
+            days: field("day-of-month", day, 1, 31)?,
+            months: field("month", month, 1, 12)?,
+            weekdays,
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+        })
+    }
+
+    fn matches(&self, time: &DateTime<Local>) -> bool {
+        let bit = |mask: u64, value: u32| mask & (1 << value) != 0;
+
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+
+        day_matches
+            && bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && bit(self.months, time.month())
+    }
+
+    /// First matching minute strictly after `after`, searching up to a year
+    /// (times skipped by a DST change never match)
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)?;
+
+        (1..=366 * 24 * 60)
+            .map(|minutes| start + Duration::minutes(minutes))
+            .filter_map(|naive| Local.from_local_datetime(&naive).earliest())
+            .find(|time| self.matches(time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn test_next_after() {
+        let daily = Schedule::parse("30 3 * * *").unwrap();
+        assert_eq!(
+            daily.next_after(local(2026, 3, 10, 12, 0)),
+            Some(local(2026, 3, 11, 3, 30))
+        );
+
+        // 2026-03-14 is a Saturday; weekdays only
+        let weekdays = Schedule::parse("0 9 * * 1-5").unwrap();
+        assert_eq!(
+            weekdays.next_after(local(2026, 3, 13, 9, 0)),
+            Some(local(2026, 3, 16, 9, 0))
+        );
+
+        let quarter = Schedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(
+            quarter.next_after(local(2026, 3, 10, 12, 7)),
+            Some(local(2026, 3, 10, 12, 15))
+        );
+        assert_eq!(
+            Schedule::parse("@daily").unwrap(),
+            Schedule::parse("0 0 * * *").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        assert!(Schedule::parse("0 3 * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("0 25 * * *").is_err());
+    }
+}
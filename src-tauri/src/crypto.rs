@@ -6,6 +6,7 @@ use aes_gcm::{
 use anyhow::{anyhow, Result};
 use argon2::password_hash::{rand_core::RngCore, SaltString};
 use argon2::{Argon2, PasswordHasher};
+use sha2::{Digest, Sha256};
 
 pub struct Crypto {
     cipher: Aes256Gcm,
@@ -31,6 +32,27 @@ impl Crypto {
         Ok(Self { cipher })
     }
 
+    /// Derive the key from a password and a known salt, so data encrypted
+    /// earlier (e.g. a backup archive) can be decrypted again
+    pub fn with_salt(password: &str, salt: &[u8]) -> Result<Self> {
+        let mut key_bytes = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), salt, &mut key_bytes)
+            .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+
+        let cipher = Aes256Gcm::new_from_slice(&key_bytes)
+            .map_err(|e| anyhow!("Key generation failed: {}", e))?;
+
+        Ok(Self { cipher })
+    }
+
+    /// Use a random key as is, e.g. a share's one-time key
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
         // Generate random nonce
         let mut nonce_bytes = [0u8; 12];
@@ -72,6 +94,27 @@ impl Crypto {
     }
 }
 
+/// HMAC-SHA256 (RFC 2104)
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,4 +129,14 @@ mod tests {
 
         assert_eq!(plaintext, decrypted.as_slice());
     }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
 }
@@ -0,0 +1,1865 @@
+// Headless daemon: mesh sync plus the CLI control socket (envmesh-daemon, envmesh --headless)
+use crate::access::{self, PeerIdentity};
+use crate::agent::Agent;
+use crate::backup::{self, BackupScheduler, Snapshot};
+use crate::bundle::Bundle;
+use crate::chunking;
+use crate::clock;
+use crate::config::{self, Config};
+use crate::editor::{self, EditorConfig};
+use crate::envfiles::EnvFileWriter;
+use crate::events::{EventBus, MeshEvent};
+use crate::expiry;
+use crate::fallback::FallbackConfig;
+use crate::faults;
+use crate::files::{self, FileBlob};
+use crate::gitsync::GitSync;
+use crate::health::{HealthControl, HealthMonitor};
+use crate::hooks::HookRunner;
+use crate::keys::{self, KeyPolicy};
+use crate::links;
+use crate::locks;
+use crate::machineid;
+use crate::merge;
+use crate::node::{EnvMeshNode, ServerMode};
+use crate::pool::StoragePool;
+use crate::protocol::{ChangePreview, Command, DaemonStatus, Response, PROTOCOL_VERSION};
+use crate::redact::{self, ValueRedacted};
+use crate::replication::Replicator;
+use crate::revoke;
+use crate::rotation::Rotator;
+use crate::scan;
+use crate::secretcheck::{self, CheckConfig};
+use crate::share::{self, Share};
+use crate::signing::DeviceKey;
+use crate::storage::{self, ImportAction, ImportChange, ListQuery};
+use crate::sync::SyncEngine;
+use crate::tfvars;
+use crate::trash;
+use crate::users::{self, Role, Users};
+use crate::vaults;
+use crate::webhooks::WebhookDispatcher;
+use crate::wsl;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+const NO_HEALTH_MONITOR: &str = "No health monitor: the cloud is off ([client] enable_cloud)";
+
+struct DaemonState {
+    storage: StoragePool,
+    node: Arc<Mutex<EnvMeshNode>>,
+    machine_id: String,
+    events: EventBus,
+    sync: SyncEngine,
+    backups: Option<BackupScheduler>,
+    /// `[[rotations]]`
+    rotator: Rotator,
+    /// `[limits] max_value_bytes`
+    max_value_bytes: usize,
+    /// `[keys] policy`
+    key_policy: KeyPolicy,
+    /// `[checks]`
+    checks: CheckConfig,
+    /// `[fallback]`
+    fallback: FallbackConfig,
+    /// Where `peer block` records blocked peers (None when ephemeral)
+    config_path: Option<PathBuf>,
+    /// `[agent]` (None when off)
+    agent: Option<Arc<Agent>>,
+    /// The cloud health monitor (None without a cloud server)
+    health: Option<Arc<HealthControl>>,
+}
+
+impl DaemonState {
+    /// Announce a local write (hooks and webhooks subscribe to these)
+    fn changed(&self, key: String, deleted: bool) {
+        self.events.emit(MeshEvent::VarChanged {
+            key,
+            machine_id: self.machine_id.clone(),
+            deleted,
+        });
+    }
+}
+
+/// Where the daemon keeps its state (both default to the platform locations)
+#[derive(Debug, Default)]
+pub struct DaemonOptions {
+    /// Path to configuration file
+    pub config: Option<String>,
+    /// Directory for the database and control socket, over ENVMESH_DATA_DIR
+    /// and `[storage] path` (Windows paths are translated inside WSL)
+    pub data_dir: Option<String>,
+    /// Run as the named instance, with a data directory (and so a socket)
+    /// of its own; alternative to `data_dir`
+    pub instance: Option<String>,
+    /// Keep variables in memory only, seeded from peers; nothing but the
+    /// control socket is written to disk
+    pub ephemeral: bool,
+    /// Log variable values instead of redacting them (debug builds only)
+    pub log_values: bool,
+}
+
+/// Run the daemon until the process exits: sync with the mesh and serve CLI
+/// commands on the control socket
+pub async fn run(options: DaemonOptions) -> anyhow::Result<()> {
+    println!("🚀 EnvMesh Daemon Starting...");
+    if options.log_values && redact::set_log_values(true) {
+        println!("   ⚠️  Logging variable values (--log-values)");
+    }
+
+    // Load configuration
+    let (config, config_path) = if let Some(config_path) = options.config {
+        let config_path = wsl::translate_path(&config_path);
+        println!("📄 Loading config from: {}", config_path.display());
+        (Config::from_file(&config_path)?, config_path)
+    } else {
+        (Config::load_default()?, Config::default_save_path())
+    };
+
+    // --data-dir or --instance, ENVMESH_DATA_DIR or [storage] path (Windows
+    // paths are accepted inside WSL)
+    let data_dir_flag =
+        config::data_dir_flag(options.data_dir.as_deref(), options.instance.as_deref())?;
+    let data_dir = config::data_dir(data_dir_flag.as_deref(), &config);
+    if let Some(instance) = &options.instance {
+        println!("🏷️  Instance: {}", instance);
+    }
+    std::fs::create_dir_all(&data_dir)?;
+    let db_path = if options.ephemeral {
+        PathBuf::from(":memory:")
+    } else {
+        data_dir.join("envmesh.db")
+    };
+
+    if options.ephemeral {
+        println!("📁 Database: in memory (ephemeral)");
+    } else {
+        println!("📁 Database: {}", db_path.display());
+    }
+
+    #[cfg(unix)]
+    let socket_path = data_dir.join("daemon.sock");
+
+    #[cfg(unix)]
+    {
+        // Remove old socket if exists
+        let _ = std::fs::remove_file(&socket_path);
+        println!("🔌 Socket: {}", socket_path.display());
+    }
+
+    // On Windows TCP is the only transport; elsewhere it is an extra listener
+    #[cfg(windows)]
+    let tcp_listen = Some(
+        config
+            .ipc
+            .listen
+            .clone()
+            .unwrap_or_else(|| crate::ipc::tcp_endpoint_for(&data_dir)),
+    );
+    #[cfg(unix)]
+    let tcp_listen = config.ipc.listen.clone();
+    let ipc_token = crate::ipc::ipc_token();
+
+    if let Some(addr) = &tcp_listen {
+        println!("🔌 IPC: TCP {}", addr);
+        // The protocol reads and writes every value, so other hosts must
+        // present the shared token
+        if !crate::ipc::is_loopback(addr) && ipc_token.is_none() {
+            return Err(anyhow::anyhow!(
+                "[ipc] listen = \"{}\" is reachable from other hosts; set {} (here and for the CLI) or listen on 127.0.0.1",
+                addr,
+                crate::ipc::IPC_TOKEN_ENV
+            ));
+        }
+    }
+    let editor_listen = config.editor.listen_addr()?;
+    if let Some(addr) = &editor_listen {
+        println!("✏️  Editor API: {}", addr);
+    }
+
+    // Initialize storage and node
+    let storage = StoragePool::open(db_path.clone())?;
+    // Like the device key, an ephemeral daemon uses the machine's ID but
+    // never creates it
+    let id_path = data_dir.join(machineid::MACHINE_ID_FILE);
+    let machine_id = match machineid::load(&id_path)? {
+        Some(id) => id,
+        None if options.ephemeral => uuid::Uuid::new_v4().to_string(),
+        None => machineid::load_or_create(&id_path)?,
+    };
+
+    let replicator = match &config.replication {
+        Some(_) if !cfg!(feature = "replication") => {
+            println!("⚠️  [replication] ignored: built without the `replication` feature");
+            None
+        }
+        Some(replication) => match std::env::var(&replication.passphrase_env) {
+            Ok(passphrase) => {
+                match Replicator::new(replication, passphrase, storage.clone(), machine_id.clone())
+                {
+                    Ok(replicator) => Some(replicator),
+                    Err(e) => {
+                        println!("⚠️  [replication] disabled: {}", e);
+                        None
+                    }
+                }
+            }
+            Err(_) => {
+                println!(
+                    "⚠️  [replication] disabled: set {} to the snapshot passphrase",
+                    replication.passphrase_env
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    // A brand-new machine starts from the bucket rather than joining the
+    // mesh empty
+    if let Some(replicator) = &replicator {
+        println!("☁️  Replication: {}", replicator.location());
+        let empty = storage
+            .read(|s| Ok(s.get_changes_since(0)?.is_empty()))
+            .await?;
+        if empty && config.replication.as_ref().is_some_and(|r| r.bootstrap) {
+            match replicator.bootstrap().await {
+                Ok(Some(count)) => {
+                    println!("   Bootstrapped {} variable(s) from the snapshot", count)
+                }
+                Ok(None) => println!("   No snapshot to bootstrap from yet"),
+                Err(e) => println!("   ⚠️  Bootstrap failed: {}", e),
+            }
+        }
+    }
+    // Ephemeral daemons use the machine's key if it has one, but never create it
+    let key_path = data_dir.join("device.key");
+    let device_key = Arc::new(match DeviceKey::load(&key_path)? {
+        Some(key) => key,
+        None if options.ephemeral => DeviceKey::generate(),
+        None => DeviceKey::load_or_create(&key_path)?,
+    });
+
+    let mut node_config = config.to_node_config();
+    // An empty store must never become the server others sync from
+    if options.ephemeral {
+        node_config.server_mode = ServerMode::ClientOnly;
+    }
+    node_config.identity = Some(PeerIdentity {
+        machine_id: machine_id.clone(),
+        device_key: device_key.public_key(),
+    });
+    node_config.signer = Some(Arc::clone(&device_key));
+    // What this device created under earlier machine IDs stays its own
+    {
+        let (machine_id, device) = (machine_id.clone(), device_key.public_key());
+        storage
+            .write(move |s| s.note_own_machine(&machine_id, &device))
+            .await?;
+    }
+
+    println!("⚙️  Configuration:");
+    println!("   Server mode: {:?}", node_config.server_mode);
+    println!(
+        "   Listen address: {}:{}",
+        node_config.listen_addr, node_config.lan_port
+    );
+    println!("   Election strategy: {:?}", node_config.election_strategy);
+    println!("   Cloud enabled: {}", node_config.enable_cloud);
+    if node_config.enable_cloud {
+        println!("   Cloud URL: {}", node_config.cloud_urls.join(", "));
+    }
+    if let Some(injected) = node_config.faults.as_ref().filter(|f| f.is_active()) {
+        if faults::ENABLED {
+            println!("   ⚠️  Injecting faults: {:?}", injected);
+        } else {
+            println!("   ⚠️  [faults] ignored: built without the `faults` feature");
+        }
+    }
+    if node_config.tls.insecure && node_config.tls.pins.is_empty() {
+        println!("   ⚠️  [tls] insecure: server certificates are NOT verified; add pins");
+    }
+
+    // Fails over to the LAN when the cloud goes down, and back once it returns
+    let health_monitor = node_config.enable_cloud.then(|| {
+        HealthMonitor::new(node_config.cloud_urls.clone())
+            .with_probe(config.health.probe)
+            .with_tls(node_config.tls.clone())
+    });
+
+    let events = EventBus::new();
+    let node = EnvMeshNode::new(node_config, events.clone()).await?;
+
+    let node = Arc::new(Mutex::new(node));
+    let health = health_monitor.map(|monitor| {
+        let control = monitor.control();
+        monitor.start_monitoring(Arc::clone(&node));
+        control
+    });
+    let sync = SyncEngine::new(
+        storage.clone(),
+        Arc::clone(&node),
+        events.clone(),
+        config.sync_filter(),
+    );
+    sync.set_key_policy(config.keys.policy);
+    sync.set_quarantine(config.quarantine.clone());
+    sync.set_rate_limit(config.limits.sync_rate);
+    sync.set_clock_config(config.clock);
+    sync.set_trusted_keys(config.trusted_keys()?);
+    if config.security.trusted_keys.is_empty() {
+        println!("⚠️  [security] trusted_keys is empty: changes from any machine are applied, unsigned ones too");
+    }
+    sync.set_peer_access(config.peer_access());
+    sync.set_users(config.users());
+    sync.set_device_key(device_key);
+    sync.set_machine_id(&machine_id);
+    println!("🔑 Device key: {}", sync.device_public_key());
+    let users = config.users();
+    if users.is_enabled() {
+        match users.user_of(&[sync.device_public_key()]) {
+            Some(user) => println!(
+                "👥 Users: {} (this machine: {}, {})",
+                users.users.len(),
+                user.name,
+                user.role.as_str()
+            ),
+            None => println!(
+                "👥 Users: {} (⚠️  this machine is not enrolled, so it can't change anything)",
+                users.users.len()
+            ),
+        }
+    }
+
+    let rotator = Rotator::new(&config.rotations, sync.clone(), machine_id.clone())?;
+    if !rotator.is_empty() {
+        println!("🔁 Rotating {} key(s) on schedule", config.rotations.len());
+    }
+
+    let backups = match &config.backup {
+        Some(_) if options.ephemeral => {
+            println!("⚠️  [backup] disabled: ephemeral mode never writes secrets to disk");
+            None
+        }
+        Some(backup_config) => match std::env::var(&backup_config.passphrase_env) {
+            Ok(passphrase) => {
+                let scheduler = BackupScheduler::new(
+                    backup_config,
+                    passphrase,
+                    storage.clone(),
+                    machine_id.clone(),
+                )?;
+                println!(
+                    "💾 Backups: {} ({}, keep {})",
+                    scheduler.dir().display(),
+                    backup_config.schedule,
+                    backup_config.keep
+                );
+                Some(scheduler)
+            }
+            Err(_) => {
+                println!(
+                    "⚠️  [backup] disabled: set {} to the archive passphrase",
+                    backup_config.passphrase_env
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let git_sync = match &config.git_sync {
+        Some(_) if options.ephemeral => {
+            println!("⚠️  [git_sync] disabled: ephemeral mode never writes secrets to disk");
+            None
+        }
+        Some(git_config) => match std::env::var(&git_config.passphrase_env) {
+            Ok(passphrase) => {
+                let git_sync = GitSync::new(
+                    git_config,
+                    passphrase,
+                    storage.clone(),
+                    sync.clone(),
+                    machine_id.clone(),
+                )?;
+                println!(
+                    "🗂️  Git sync: {} in {} (every {}s)",
+                    git_config.namespace,
+                    git_sync.repo().display(),
+                    git_config.interval_secs
+                );
+                Some(git_sync)
+            }
+            Err(_) => {
+                println!(
+                    "⚠️  [git_sync] disabled: set {} to the snapshot passphrase",
+                    git_config.passphrase_env
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let agent = match config.agent.socket_path(&data_dir) {
+        Some(_) if cfg!(windows) => {
+            println!("⚠️  [agent] ignored: the lease agent needs a Unix socket");
+            None
+        }
+        Some(path) => {
+            println!(
+                "🎫 Lease agent: {} ({} rule(s))",
+                path.display(),
+                config.agent.rules.len()
+            );
+            Some(Arc::new(Agent::new(
+                config.agent.clone(),
+                storage.clone(),
+                events.clone(),
+            )))
+        }
+        None => None,
+    };
+
+    let state = Arc::new(DaemonState {
+        storage,
+        node,
+        machine_id,
+        events,
+        sync,
+        backups,
+        rotator,
+        max_value_bytes: config.limits.max_value_bytes,
+        key_policy: config.keys.policy,
+        checks: config.checks,
+        fallback: config.fallback(),
+        config_path: (!options.ephemeral).then_some(config_path),
+        agent,
+        health,
+    });
+
+    if let Some(scheduler) = &state.backups {
+        tokio::spawn(scheduler.clone().run());
+    }
+    if let Some(git_sync) = git_sync {
+        tokio::spawn(git_sync.run());
+    }
+    if let Some(replicator) = replicator {
+        tokio::spawn(replicator.run());
+    }
+    for vault in &config.vaults {
+        if !cfg!(feature = "vaults") {
+            println!("⚠️  [[vaults]] ignored: built without the `vaults` feature");
+            break;
+        }
+        match vaults::spawn(vault, state.sync.clone(), state.machine_id.clone()) {
+            Ok(described) => println!(
+                "🔐 Vault: {} ↔ {} ({})",
+                vault.namespace,
+                described,
+                vault.direction.as_str()
+            ),
+            Err(e) => println!("⚠️  [[vaults]] {} disabled: {}", vault.namespace, e),
+        }
+    }
+
+    // Apply incoming changes from the network, and seed clients that join
+    tokio::spawn(state.sync.clone().run_incoming());
+    tokio::spawn(state.sync.clone().run_seeding());
+    tokio::spawn(links::run_pings(Arc::clone(&state.node)));
+    tokio::spawn(users::run_expiry(Arc::clone(&state.node)));
+    if config.expiry.warn_days > 0 {
+        tokio::spawn(expiry::run(
+            state.storage.clone(),
+            state.events.clone(),
+            config.expiry.clone(),
+        ));
+    }
+    if !state.rotator.is_empty() {
+        tokio::spawn(state.rotator.clone().run());
+    }
+    if config.storage.trash_retention_days > 0 {
+        tokio::spawn(trash::run_purge(
+            state.storage.clone(),
+            config.storage.trash_retention_days,
+        ));
+    }
+    let revocations = revoke::run(
+        state.sync.clone(),
+        state.storage.clone(),
+        Arc::clone(&state.node),
+        state.events.clone(),
+        (!options.ephemeral).then_some(db_path),
+    );
+    tokio::spawn(async move {
+        // Only returns once this machine was revoked with --wipe
+        match revocations.await {
+            Ok(()) => println!("🧨 This machine was revoked and its database wiped; exiting"),
+            Err(e) => println!("🧨 This machine was revoked, but wiping it failed: {}", e),
+        }
+        std::process::exit(0);
+    });
+
+    if options.ephemeral {
+        // A LAN server seeds us on connect; peers behind a relay need asking
+        let requested = state
+            .node
+            .lock()
+            .await
+            .request_state(&state.machine_id, None)
+            .await;
+        if let Err(e) = requested {
+            println!("⚠️  Failed to request state from peers: {}", e);
+        }
+    }
+
+    if !config.hooks.is_empty() {
+        println!("🪝 {} change hook(s) configured", config.hooks.len());
+        let runner = HookRunner::new(config.hooks.clone(), state.storage.clone());
+        tokio::spawn(runner.run(state.events.clone()));
+    }
+
+    if !config.writers.is_empty() && options.ephemeral {
+        println!("⚠️  [[writers]] disabled: ephemeral mode never writes secrets to disk");
+    } else if !config.writers.is_empty() {
+        println!(
+            "📄 {} environment file(s) kept up to date",
+            config.writers.len()
+        );
+        let writer = EnvFileWriter::new(&config.writers, state.storage.clone());
+        tokio::spawn(writer.run(state.events.clone()));
+    }
+
+    if !config.webhooks.is_empty() && !cfg!(feature = "webhooks") {
+        println!("⚠️  [[webhooks]] ignored: built without the `webhooks` feature");
+    } else if !config.webhooks.is_empty() {
+        println!("📨 {} webhook(s) configured", config.webhooks.len());
+        let dispatcher = WebhookDispatcher::new(config.webhooks.clone());
+        tokio::spawn(dispatcher.run(state.events.clone()));
+    }
+
+    println!("✓ Storage initialized");
+    println!("✓ Node initialized with failover support");
+    println!("\n📡 Daemon running. Use 'envmesh-cli' to interact.");
+    println!("Press Ctrl+C to stop.\n");
+
+    let tcp_listener = match &tcp_listen {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    if let Some(addr) = editor_listen {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(serve_editor(
+            listener,
+            Arc::clone(&state),
+            config.editor.clone(),
+        ));
+    }
+
+    #[cfg(windows)]
+    {
+        let pipe_name = crate::ipc::pipe_name_for(&data_dir);
+        println!("🔌 IPC: pipe {}", pipe_name);
+        tokio::spawn(serve_pipe(pipe_name, Arc::clone(&state)));
+        if let Some(listener) = tcp_listener {
+            serve_tcp(listener, state, ipc_token).await;
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(listener) = tcp_listener {
+            tokio::spawn(serve_tcp(listener, Arc::clone(&state), ipc_token));
+        }
+
+        if let (Some(agent), Some(path)) = (&state.agent, config.agent.socket_path(&data_dir)) {
+            // Only our user may ask for secrets
+            let _ = std::fs::remove_file(&path);
+            let listener = bind_private(&path)?;
+            tokio::spawn(crate::agent::serve(Arc::clone(agent), listener));
+        }
+
+        // It reads every value and answers agent prompts: our user only
+        let listener = bind_private(&socket_path)?;
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        let (reader, writer) = stream.into_split();
+                        if let Err(e) = handle_connection(reader, writer, state, None).await {
+                            tracing::error!("Connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    tracing::error!("Accept error: {}", e);
+                }
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    Ok(())
+}
+
+/// Bind a socket only our user can open. The umask applies at creation, so
+/// there is no moment in which another user could connect.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    // SAFETY: umask has no preconditions; it swaps the process-wide mask,
+    // which is put back straight after the bind
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    listener
+}
+
+/// With `token`, every connection must open with a matching `Command::Auth`
+async fn serve_tcp(listener: TcpListener, state: Arc<DaemonState>, token: Option<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tracing::debug!("IPC connection from {}", peer);
+                let (state, token) = (Arc::clone(&state), token.clone());
+                tokio::spawn(async move {
+                    let (reader, writer) = stream.into_split();
+                    if let Err(e) = handle_connection(reader, writer, state, token).await {
+                        tracing::error!("Connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::error!("Accept error: {}", e);
+            }
+        }
+    }
+}
+
+/// JSON-RPC for editor extensions (see editor.rs), on its own port
+async fn serve_editor(listener: TcpListener, state: Arc<DaemonState>, config: EditorConfig) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tracing::debug!("Editor connection from {}", peer);
+                let (state, config) = (Arc::clone(&state), config.clone());
+                tokio::spawn(async move {
+                    let (reader, writer) = stream.into_split();
+                    if let Err(e) = handle_editor(reader, writer, &state, &config).await {
+                        tracing::error!("Editor connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::error!("Accept error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_editor<R, W>(
+    reader: R,
+    mut writer: W,
+    state: &DaemonState,
+    config: &EditorConfig,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<editor::Request>(&line) {
+            Ok(request) => {
+                let outcome = match editor::command(&request) {
+                    Ok(cmd) => editor::result(&request, handle_command(cmd, state).await, config),
+                    Err(e) => Err(e),
+                };
+                // Notifications get no reply
+                let Some(id) = request.id else {
+                    continue;
+                };
+                editor::reply(id, outcome)
+            }
+            Err(e) => {
+                // Hang up, so a web page can't get requests past its HTTP
+                // headers by putting them in a POST body
+                let reply = serde_json::to_string(&editor::parse_error(&e))?;
+                writer.write_all(reply.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                return Ok(());
+            }
+        };
+        writer
+            .write_all(serde_json::to_string(&reply)?.as_bytes())
+            .await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Named pipe listener: one pipe instance per client, with the next instance
+/// created before a connected one is handed off
+#[cfg(windows)]
+async fn serve_pipe(name: String, state: Arc<DaemonState>) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let name = name.as_str();
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(name) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Failed to create named pipe {}: {}", name, e);
+            return;
+        }
+    };
+
+    loop {
+        if let Err(e) = server.connect().await {
+            tracing::error!("Pipe accept error: {}", e);
+            continue;
+        }
+        let next = match ServerOptions::new().create(name) {
+            Ok(next) => next,
+            Err(e) => {
+                tracing::error!("Failed to create named pipe {}: {}", name, e);
+                return;
+            }
+        };
+        let connected = std::mem::replace(&mut server, next);
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(connected);
+            if let Err(e) = handle_connection(reader, writer, state, None).await {
+                tracing::error!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Serve commands until the client hangs up. While `token` is Some, only a
+/// `Command::Auth` carrying it is accepted.
+async fn handle_connection<R, W>(
+    reader: R,
+    mut writer: W,
+    state: Arc<DaemonState>,
+    mut token: Option<String>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    while reader.read_line(&mut line).await? > 0 {
+        let cmd: Command = match serde_json::from_str(&line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                let resp = Response::Error(format!(
+                    "Invalid command for protocol v{}: {}",
+                    PROTOCOL_VERSION, e
+                ));
+                writer
+                    .write_all(serde_json::to_string(&resp)?.as_bytes())
+                    .await?;
+                writer.write_all(b"\n").await?;
+                line.clear();
+                continue;
+            }
+        };
+
+        if let Command::Auth { token: presented } = &cmd {
+            if token
+                .as_ref()
+                .is_some_and(|token| !crate::relay::same(token.as_bytes(), presented.as_bytes()))
+            {
+                return refuse(&mut writer, "IPC token rejected").await;
+            }
+            token = None;
+            line.clear();
+            continue;
+        }
+        if token.is_some() {
+            let reason = format!("This daemon needs {}", crate::ipc::IPC_TOKEN_ENV);
+            return refuse(&mut writer, &reason).await;
+        }
+
+        if let Command::Subscribe = cmd {
+            return stream_events(&mut writer, &state).await;
+        }
+
+        let response = handle_command(cmd, &state).await;
+        writer
+            .write_all(serde_json::to_string(&response)?.as_bytes())
+            .await?;
+        writer.write_all(b"\n").await?;
+
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Answer with `reason` and hang up
+async fn refuse<W: AsyncWrite + Unpin>(writer: &mut W, reason: &str) -> anyhow::Result<()> {
+    let resp = Response::Error(reason.to_string());
+    writer
+        .write_all(serde_json::to_string(&resp)?.as_bytes())
+        .await?;
+    writer.write_all(b"\n").await?;
+    Ok(())
+}
+
+/// Stream mesh events to a subscriber until it disconnects
+async fn stream_events<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    state: &DaemonState,
+) -> anyhow::Result<()> {
+    let mut events = state.events.subscribe();
+
+    loop {
+        let response = match events.recv().await {
+            Ok(event) => Response::Event(event),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Event subscriber missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        writer
+            .write_all(serde_json::to_string(&response)?.as_bytes())
+            .await?;
+        writer.write_all(b"\n").await?;
+    }
+}
+
+/// When to stamp a local write to `key`, and whether its lock was removed
+/// for it (`force_unlock`). A write after an unlock is stamped later than
+/// the unlock, so peers receive that first.
+fn write_time(
+    s: &storage::EnvStorage,
+    key: &str,
+    machine_id: &str,
+    force_unlock: bool,
+) -> anyhow::Result<(i64, bool)> {
+    let unlocked = if force_unlock {
+        s.unlock(key, machine_id, None)?
+    } else {
+        None
+    };
+    let now = clock::now();
+    Ok((
+        unlocked.map_or(now, |at| now.max(at + 1)),
+        unlocked.is_some(),
+    ))
+}
+
+/// Refuse a command that would change anything our user's role doesn't
+/// allow (`[[users]]`)
+async fn check_role(command: &Command, state: &DaemonState) -> anyhow::Result<()> {
+    if !state.sync.users().is_enabled() {
+        return Ok(());
+    }
+    let keys = match command {
+        Command::Set { key, .. }
+        | Command::Delete { key, .. }
+        | Command::Lock { key }
+        | Command::Unlock { key, .. }
+        | Command::RestoreTrashed { key }
+        | Command::Rotate { key } => vec![key.trim().to_string()],
+        Command::Rename { from, to } => vec![from.clone(), to.trim().to_string()],
+        Command::PutFile { name, .. } | Command::DeleteFile { name } => {
+            vec![files::file_key(name)]
+        }
+        Command::Import { .. }
+        | Command::DeleteMatching { .. }
+        | Command::Copy { .. }
+        | Command::Restore { .. } => preview(command.clone(), state)
+            .await?
+            .changes
+            .into_iter()
+            .filter(|c| c.action != ImportAction::Unchanged)
+            .map(|c| c.key)
+            .collect(),
+        _ => Vec::new(),
+    };
+    keys.iter().try_for_each(|key| state.sync.check_write(key))
+}
+
+async fn handle_command(cmd: Command, state: &DaemonState) -> Response {
+    if let Err(e) = check_role(&cmd, state).await {
+        return Response::Error(e.to_string());
+    }
+    match cmd {
+        Command::Get { key, fallback } => {
+            let k = key.clone();
+            match state
+                .storage
+                .read(move |s| Ok((s.get(&k)?, s.is_deleted(&k)?)))
+                .await
+            {
+                Ok((Some((value, _, _)), _)) => Response::Value(Some(value)),
+                // A key deleted in the mesh stays deleted, whatever value the
+                // environment or .env file still has for it
+                Ok((None, false)) if fallback || state.fallback.enabled => {
+                    match state.fallback.lookup(&key) {
+                        Ok(value) => Response::Value(value),
+                        Err(e) => Response::Error(format!("Failed to get: {}", e)),
+                    }
+                }
+                Ok(_) => Response::Value(None),
+                Err(e) => Response::Error(format!("Failed to get: {}", e)),
+            }
+        }
+        Command::Set {
+            key,
+            value,
+            force_unlock,
+        } => {
+            let key = match keys::prepare(&key, state.key_policy) {
+                Ok(key) => key,
+                Err(e) => return Response::Error(e.to_string()),
+            };
+            if let Err(e) = chunking::check_value_size(&key, &value, state.max_value_bytes) {
+                return Response::Error(e.to_string());
+            }
+            tracing::debug!("Setting {} = {}", key, ValueRedacted(&value));
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| {
+                    let (at, unlocked) = write_time(s, &k, &machine_id, force_unlock)?;
+                    s.set_at(&k, &value, &machine_id, at)?;
+                    Ok(unlocked)
+                })
+                .await
+            {
+                Ok(unlocked) => {
+                    if unlocked {
+                        state.changed(locks::lock_key(&key), true);
+                    }
+                    state.changed(key, false);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to set: {}", e)),
+            }
+        }
+        Command::Delete { key, force_unlock } => {
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| {
+                    let (at, unlocked) = write_time(s, &k, &machine_id, force_unlock)?;
+                    s.delete_at(&k, &machine_id, at)?;
+                    Ok(unlocked)
+                })
+                .await
+            {
+                Ok(unlocked) => {
+                    if unlocked {
+                        state.changed(locks::lock_key(&key), true);
+                    }
+                    state.changed(key, true);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to delete: {}", e)),
+            }
+        }
+        Command::Lock { key } => {
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            let owner = state.sync.device_public_key();
+            match state
+                .storage
+                .write(move |s| s.lock(&k, &machine_id, &owner))
+                .await
+            {
+                Ok(locked) => {
+                    if locked {
+                        state.changed(locks::lock_key(&key), false);
+                    }
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to lock: {}", e)),
+            }
+        }
+        Command::Unlock { key, force } => {
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            let owner = (!force).then(|| state.sync.device_public_key());
+            match state
+                .storage
+                .write(move |s| s.unlock(&k, &machine_id, owner.as_deref()))
+                .await
+            {
+                Ok(Some(_)) => {
+                    state.changed(locks::lock_key(&key), true);
+                    Response::Success
+                }
+                Ok(None) => Response::Error(format!("{} is not locked", key)),
+                Err(e) => Response::Error(format!("Failed to unlock: {}", e)),
+            }
+        }
+        Command::DeleteMatching { pattern } => {
+            let machine_id = state.machine_id.clone();
+            match state
+                .storage
+                .write(move |s| s.delete_matching(&pattern, &machine_id, false))
+                .await
+            {
+                Ok(changes) => {
+                    let keys: Vec<String> = changes.into_iter().map(|c| c.key).collect();
+                    for key in &keys {
+                        state.changed(key.clone(), true);
+                    }
+                    Response::Deleted(keys)
+                }
+                Err(e) => Response::Error(format!("Failed to delete: {}", e)),
+            }
+        }
+        Command::CheckValue { key, value } => {
+            if !state.checks.enabled {
+                return Response::Warnings(Vec::new());
+            }
+            let (v, k) = (value.clone(), key.clone());
+            match state.storage.read(move |s| s.keys_with_value(&v, &k)).await {
+                Ok(reused_by) => Response::Warnings(secretcheck::check(&key, &value, &reused_by)),
+                Err(e) => Response::Error(format!("Failed to check: {}", e)),
+            }
+        }
+        Command::Rename { from, to } => {
+            let to = match keys::prepare(&to, state.key_policy) {
+                Ok(to) => to,
+                Err(e) => return Response::Error(e.to_string()),
+            };
+            let (f, t, machine_id) = (from.clone(), to.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| s.rename(&f, &t, &machine_id))
+                .await
+            {
+                Ok(_) => {
+                    state.changed(to, false);
+                    state.changed(from, true);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to rename: {}", e)),
+            }
+        }
+        Command::Copy {
+            from,
+            to,
+            keys,
+            all,
+            prefix,
+        } => {
+            let selection = CopySelection { keys, all, prefix };
+            match copy_vars(from, to, selection, state, false).await {
+                Ok(changes) => {
+                    for change in &changes {
+                        if change.action != ImportAction::Unchanged {
+                            state.changed(change.key.clone(), false);
+                        }
+                    }
+                    Response::Imported(changes)
+                }
+                Err(e) => Response::Error(format!("Failed to copy: {}", e)),
+            }
+        }
+        Command::List { query } => match state.storage.read(move |s| s.list(&query)).await {
+            Ok(page) => {
+                let list: Vec<(String, String)> =
+                    page.vars.into_iter().map(|v| (v.key, v.value)).collect();
+                Response::List(list)
+            }
+            Err(e) => Response::Error(format!("Failed to list: {}", e)),
+        },
+        Command::Import { vars } => {
+            let vars = match prepare_import(vars, state) {
+                Ok(vars) => vars,
+                Err(e) => return Response::Error(e.to_string()),
+            };
+            let machine_id = state.machine_id.clone();
+            match state
+                .storage
+                .write(move |s| s.import(&vars, &machine_id, false))
+                .await
+            {
+                Ok(changes) => {
+                    for change in &changes {
+                        if change.action != ImportAction::Unchanged {
+                            state.changed(change.key.clone(), false);
+                        }
+                    }
+                    Response::Imported(changes)
+                }
+                Err(e) => Response::Error(format!("Failed to import: {}", e)),
+            }
+        }
+        Command::PutFile { name, blob } => {
+            let key = files::file_key(&name);
+            let checked = files::validate_name(&name)
+                .and_then(|_| blob.contents())
+                .and_then(|_| blob.to_value())
+                .and_then(|value| {
+                    chunking::check_value_size(&key, &value, state.max_value_bytes)?;
+                    Ok(value)
+                });
+            let value = match checked {
+                Ok(value) => value,
+                Err(e) => return Response::Error(e.to_string()),
+            };
+
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| s.set(&k, &value, &machine_id))
+                .await
+            {
+                Ok(_) => {
+                    state.changed(key, false);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to store file: {}", e)),
+            }
+        }
+        Command::GetFile { name } => {
+            let key = files::file_key(&name);
+            match state.storage.read(move |s| s.get(&key)).await {
+                Ok(Some((value, _, _))) => match FileBlob::from_value(&value) {
+                    Ok(blob) => Response::File(Some(blob)),
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                Ok(None) => Response::File(None),
+                Err(e) => Response::Error(format!("Failed to get file: {}", e)),
+            }
+        }
+        Command::DeleteFile { name } => {
+            let key = files::file_key(&name);
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| s.delete(&k, &machine_id))
+                .await
+            {
+                Ok(_) => {
+                    state.changed(key, true);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to delete file: {}", e)),
+            }
+        }
+        Command::ListFiles => match state.storage.read(|s| s.list_files()).await {
+            Ok(files) => Response::Files(files),
+            Err(e) => Response::Error(format!("Failed to list files: {}", e)),
+        },
+        Command::Backup { since } => {
+            let machine_id = state.machine_id.clone();
+            match state
+                .storage
+                .read(move |s| Snapshot::capture(s, &machine_id, since.as_ref()))
+                .await
+            {
+                Ok(snapshot) => Response::Snapshot(snapshot),
+                Err(e) => Response::Error(format!("Failed to back up: {}", e)),
+            }
+        }
+        Command::Restore { snapshot } => {
+            match state.storage.write(move |s| snapshot.restore(s)).await {
+                Ok(count) => Response::Restored(count),
+                Err(e) => Response::Error(format!("Failed to restore: {}", e)),
+            }
+        }
+        Command::ExportBundle { namespaces, since } => {
+            match Bundle::export(
+                &state.sync,
+                &state.storage,
+                &state.machine_id,
+                namespaces,
+                since,
+            )
+            .await
+            {
+                Ok(bundle) => Response::Bundle(bundle),
+                Err(e) => Response::Error(format!("Failed to export bundle: {}", e)),
+            }
+        }
+        Command::ImportBundle { bundle } => {
+            let total = bundle.changes.len();
+            match bundle.import(&state.sync, &state.storage).await {
+                Ok((applied, gap)) => {
+                    // On to the peers here, as if they had arrived over the wire
+                    if let Err(e) = state.sync.push_all().await {
+                        tracing::warn!("Failed to push imported bundle: {}", e);
+                    }
+                    Response::BundleImported {
+                        applied,
+                        total,
+                        gap,
+                    }
+                }
+                Err(e) => Response::Error(format!("Failed to import bundle: {}", e)),
+            }
+        }
+        Command::Tag { key, tags } => {
+            let valid = merge::validate_tags(&tags)
+                .and(expiry::validate_tags(&tags))
+                .and(tfvars::validate_tags(&tags));
+            if let Err(e) = valid {
+                return Response::Error(e.to_string());
+            }
+            let k = key.clone();
+            match state.storage.write(move |s| s.set_tags(&k, &tags)).await {
+                // Writers filtering by tag may now take the key or drop it
+                Ok(_) => {
+                    state.changed(key, false);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to tag: {}", e)),
+            }
+        }
+        Command::Peers => Response::Peers(state.node.lock().await.peer_links()),
+        Command::ValueDigests => {
+            match state.storage.read(|s| s.list(&ListQuery::default())).await {
+                Ok(page) => {
+                    let vars: Vec<(String, String)> =
+                        page.vars.into_iter().map(|v| (v.key, v.value)).collect();
+                    Response::Digests(scan::digests(&vars))
+                }
+                Err(e) => Response::Error(format!("Failed to list: {}", e)),
+            }
+        }
+        Command::BlockPeer { id } => match block_peer(state, &id).await {
+            Ok(dropped) => {
+                if dropped > 0 {
+                    println!("🚫 Blocked {} and dropped {} connection(s)", id, dropped);
+                } else {
+                    println!("🚫 Blocked {}", id);
+                }
+                Response::Success
+            }
+            Err(e) => Response::Error(format!("Failed to block {}: {}", id, e)),
+        },
+        Command::RevokeDevice { device, wipe } => match revoke_device(state, &device, wipe).await {
+            Ok(()) => {
+                let wiped = if wipe {
+                    " and told it to wipe itself"
+                } else {
+                    ""
+                };
+                println!("🧨 Revoked {}{}", device.trim(), wiped);
+                Response::Success
+            }
+            Err(e) => Response::Error(format!("Failed to revoke {}: {}", device.trim(), e)),
+        },
+        Command::Users => Response::Users(state.sync.users().users),
+        Command::SetUser {
+            name,
+            role,
+            namespaces,
+        } => {
+            let changed = manage_users(state, |users, own| {
+                let first = !users.is_enabled();
+                if first && role != Role::Admin {
+                    return Err(anyhow::anyhow!("the first user must be an admin"));
+                }
+                users.set(&name, role, namespaces)?;
+                if first {
+                    users.enroll(&name, own, None)?;
+                }
+                Ok(())
+            });
+            match changed.await {
+                Ok(()) => Response::Success,
+                Err(e) => Response::Error(format!("Failed to set user {}: {}", name, e)),
+            }
+        }
+        Command::EnrollDevice {
+            name,
+            device,
+            expires_secs,
+        } => {
+            let expires_at = expires_secs.map(|secs| clock::now() + secs as i64);
+            let enrolled = manage_users(state, |users, own| {
+                users.enroll(&name, device.as_deref().unwrap_or(own), expires_at)
+            });
+            match enrolled.await {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error(format!("Failed to enroll: {}", e)),
+            }
+        }
+        Command::UnenrollDevice { device } => {
+            let unenrolled = manage_users(state, |users, _| {
+                users
+                    .unenroll(&device)
+                    .ok_or_else(|| anyhow::anyhow!("{} is not enrolled", device))
+            });
+            match unenrolled.await {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error(format!("Failed to unenroll: {}", e)),
+            }
+        }
+        Command::RemoveUser { name } => {
+            match manage_users(state, |users, _| users.remove(&name)).await {
+                Ok(()) => Response::Success,
+                Err(e) => Response::Error(format!("Failed to remove user {}: {}", name, e)),
+            }
+        }
+        Command::Share {
+            key,
+            expires_secs,
+            offline,
+        } => match share_variable(state, &key, expires_secs, offline).await {
+            Ok(shared) => {
+                println!("🔗 Shared {} for {}s", key, expires_secs);
+                shared
+            }
+            Err(e) => Response::Error(format!("Failed to share {}: {}", key, e)),
+        },
+        Command::Status => {
+            let counts = state
+                .storage
+                .read(|s| Ok((s.list(&ListQuery::default())?.total, s.conflicts()?.len())))
+                .await;
+            let (variables, conflicts) = match counts {
+                Ok(counts) => counts,
+                Err(e) => return Response::Error(format!("Failed to read status: {}", e)),
+            };
+
+            let (rate_stats, banned_peers) = state.sync.rate_limit_status();
+            let (connection, links) = {
+                let node = state.node.lock().await;
+                (node.connection_info(), node.peer_links())
+            };
+            let version_warnings = links
+                .iter()
+                .filter_map(|link| {
+                    let warning = link.version.as_ref()?.warning()?;
+                    Some(format!("{}: {}", link.address, warning))
+                })
+                .collect();
+            Response::Status(DaemonStatus {
+                machine_id: state.machine_id.clone(),
+                connection,
+                variables,
+                conflicts,
+                last_backup: state
+                    .backups
+                    .as_ref()
+                    .and_then(|scheduler| backup::last_backup(scheduler.dir())),
+                next_backup: state
+                    .backups
+                    .as_ref()
+                    .and_then(|scheduler| scheduler.next_run())
+                    .map(|next| next.timestamp()),
+                rate_limited: rate_stats.dropped,
+                banned_peers,
+                device_key: Some(state.sync.device_public_key()),
+                clock_skew: state.sync.clock_skew(),
+                version_warnings,
+            })
+        }
+        Command::Stats => {
+            let since = clock::now() - 3600;
+            match state.storage.read(move |s| s.stats(since)).await {
+                Ok(stats) => Response::Stats {
+                    machine_id: state.machine_id.clone(),
+                    stats,
+                },
+                Err(e) => Response::Error(format!("Failed to read stats: {}", e)),
+            }
+        }
+        Command::Fsck { repair } => match check_store(state, repair).await {
+            Ok(response) => response,
+            Err(e) => Response::Error(format!("Failed to check the store: {}", e)),
+        },
+        Command::Sync => match state.sync.push_all().await {
+            Ok(_) => Response::Success,
+            Err(e) => Response::Error(format!("Failed to sync: {}", e)),
+        },
+        Command::FullSync => match state.sync.push_full().await {
+            Ok(_) => Response::Success,
+            Err(e) => Response::Error(format!("Failed to sync: {}", e)),
+        },
+        Command::Resync {
+            from,
+            overwrite_local,
+        } => match state.sync.resync_from(&from, overwrite_local).await {
+            Ok(_) => Response::Success,
+            Err(e) => Response::Error(format!("Failed to resync from {}: {}", from, e)),
+        },
+        Command::Expiring { within_days } => match expiry::scan(&state.storage).await {
+            Ok(mut expiries) => {
+                if let Some(days) = within_days {
+                    let until = chrono::Utc::now().timestamp() + (days * 86_400) as i64;
+                    expiries.retain(|expiry| expiry.expires_at <= until);
+                }
+                Response::Expiring(expiries)
+            }
+            Err(e) => Response::Error(format!("Failed to check expiry: {}", e)),
+        },
+        Command::Rotations => match state.rotator.status().await {
+            Ok(statuses) => Response::Rotations(statuses),
+            Err(e) => Response::Error(format!("Failed to read rotations: {}", e)),
+        },
+        Command::RotationLog { limit } => {
+            match state.storage.read(move |s| s.rotation_log(limit)).await {
+                Ok(log) => Response::RotationLog(log),
+                Err(e) => Response::Error(format!("Failed to read rotation log: {}", e)),
+            }
+        }
+        Command::Rotate { key } => match state.rotator.rotate(&key).await {
+            Ok(()) => Response::Success,
+            Err(e) => Response::Error(format!("Failed to rotate {}: {}", key, e)),
+        },
+        Command::Trash => match state.storage.read(|s| s.trash()).await {
+            Ok(trash) => Response::Trash(trash),
+            Err(e) => Response::Error(format!("Failed to read trash: {}", e)),
+        },
+        Command::RestoreTrashed { key } => {
+            let (k, machine_id) = (key.clone(), state.machine_id.clone());
+            match state
+                .storage
+                .write(move |s| {
+                    let Some(value) = s.trashed_value(&k)? else {
+                        anyhow::bail!("{} is not in the trash, or has no value to restore", k);
+                    };
+                    let (at, _) = write_time(s, &k, &machine_id, false)?;
+                    s.set_at(&k, &value, &machine_id, at)
+                })
+                .await
+            {
+                Ok(()) => {
+                    state.changed(key, false);
+                    Response::Success
+                }
+                Err(e) => Response::Error(format!("Failed to restore: {}", e)),
+            }
+        }
+        Command::Quarantine => match state.sync.quarantined().await {
+            Ok(held) => Response::Quarantine(held),
+            Err(e) => Response::Error(format!("Failed to read quarantine: {}", e)),
+        },
+        Command::ApproveQuarantined { ids } => match state.sync.approve_quarantined(ids).await {
+            Ok(applied) => Response::Released(applied),
+            Err(e) => Response::Error(format!("Failed to approve held changes: {}", e)),
+        },
+        Command::RejectQuarantined { ids } => match state.sync.reject_quarantined(ids).await {
+            Ok(dropped) => Response::Released(dropped),
+            Err(e) => Response::Error(format!("Failed to reject held changes: {}", e)),
+        },
+        Command::Agent { limit } => match &state.agent {
+            Some(agent) => Response::Agent(agent.status(limit)),
+            None => Response::Error("The lease agent is off ([agent] socket)".to_string()),
+        },
+        Command::AnswerLease { id, allow } => match &state.agent {
+            Some(agent) => match agent.answer(id, allow) {
+                Ok(()) => Response::Success,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            None => Response::Error("The lease agent is off ([agent] socket)".to_string()),
+        },
+        Command::SyncReports { limit } => match state.sync.reports(limit).await {
+            Ok(reports) => Response::SyncReports(reports),
+            Err(e) => Response::Error(format!("Failed to read sync log: {}", e)),
+        },
+        Command::HealthStatus => match &state.health {
+            Some(health) => {
+                let (mode, lan_hold) = {
+                    let node = state.node.lock().await;
+                    (node.connection_info(), node.lan_hold())
+                };
+                Response::Health(health.status(mode, lan_hold))
+            }
+            None => Response::Error(NO_HEALTH_MONITOR.to_string()),
+        },
+        Command::SetHealthCheckInterval { secs } => match &state.health {
+            Some(health) => match health.set_check_interval(Duration::from_secs(secs)) {
+                Ok(()) => {
+                    println!("🩺 Health check interval: {}s", secs);
+                    Response::Success
+                }
+                Err(e) => Response::Error(e.to_string()),
+            },
+            None => Response::Error(NO_HEALTH_MONITOR.to_string()),
+        },
+        Command::PauseFailover { paused } => match &state.health {
+            Some(health) => {
+                health.set_paused(paused);
+                println!("🩺 Failover {}", if paused { "paused" } else { "resumed" });
+                Response::Success
+            }
+            None => Response::Error(NO_HEALTH_MONITOR.to_string()),
+        },
+        Command::Shutdown => {
+            std::process::exit(0);
+        }
+        Command::Subscribe => {
+            Response::Error("Subscribe is only valid as the first command".to_string())
+        }
+        Command::Auth { .. } => {
+            Response::Error("Auth is only valid as the first command".to_string())
+        }
+        Command::Version => Response::Version {
+            protocol: PROTOCOL_VERSION,
+            daemon: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        Command::Preview { command } => match preview(*command, state).await {
+            Ok(preview) => Response::Preview(preview),
+            Err(e) => Response::Error(format!("Failed to preview: {}", e)),
+        },
+    }
+}
+
+/// Block `id` from now on, and in `[security] blocked_peers` for later
+/// runs; returns how many connections it had to our LAN server
+async fn block_peer(state: &DaemonState, id: &str) -> anyhow::Result<usize> {
+    let id = id.trim();
+    if id.is_empty() {
+        return Err(anyhow::anyhow!("no peer ID given"));
+    }
+    if !access::is_device_key(id) {
+        return Err(anyhow::anyhow!(
+            "{} is not a device key (`envmesh-cli device-key` on that machine)",
+            id
+        ));
+    }
+    if let Some(path) = &state.config_path {
+        let mut config = if path.exists() {
+            Config::from_file(path)?
+        } else {
+            Config::default()
+        };
+        if !config
+            .security
+            .blocked_peers
+            .iter()
+            .any(|blocked| blocked == id)
+        {
+            config.security.blocked_peers.push(id.to_string());
+            config.save(path)?;
+        }
+    }
+
+    let mut node = state.node.lock().await;
+    let mut access = node.peer_access().clone();
+    access.block(id);
+    state.sync.set_peer_access(access.clone());
+    Ok(node.set_peer_access(access))
+}
+
+/// Check the store and, with `repair`, fix it: in place where that works,
+/// otherwise by rebuilding it from every row that can still be read (kept
+/// as a backup archive first, with `[backup]`) and asking peers for the
+/// rest.
+async fn check_store(state: &DaemonState, repair: bool) -> anyhow::Result<Response> {
+    let found = state.storage.read(|s| s.fsck()).await?;
+    if !repair || found.is_clean() {
+        return Ok(Response::Fsck {
+            found,
+            remaining: None,
+            rebuilt: false,
+        });
+    }
+
+    let remaining = state.storage.write(|s| s.repair()).await?;
+    if remaining.integrity.is_empty() {
+        return Ok(Response::Fsck {
+            found,
+            remaining: Some(remaining),
+            rebuilt: false,
+        });
+    }
+
+    tracing::warn!("Store still damaged after repair, rebuilding it");
+    // Nothing is cleared unless all of it could be read
+    let machine_id = state.machine_id.clone();
+    let snapshot = state
+        .storage
+        .read(move |s| Snapshot::capture(s, &machine_id, None))
+        .await?;
+    if let Some(scheduler) = &state.backups {
+        let path = scheduler.archive(&snapshot)?;
+        println!(
+            "💾 Backed up the store to {} before rebuilding it",
+            path.display()
+        );
+    }
+    let remaining = state
+        .storage
+        .write(move |s| {
+            snapshot.rebuild(s)?;
+            s.fsck()
+        })
+        .await?;
+    // A LAN server's clients only resend on their next full sync
+    state
+        .node
+        .lock()
+        .await
+        .request_state(&state.machine_id, None)
+        .await?;
+    Ok(Response::Fsck {
+        found,
+        remaining: Some(remaining),
+        rebuilt: true,
+    })
+}
+
+/// Store a revocation of `device` and send it out at once; `revoke::run`
+/// blocks the device as it would one from a peer
+async fn revoke_device(state: &DaemonState, device: &str, wipe: bool) -> anyhow::Result<()> {
+    let device = device.trim();
+    let ours = state.sync.device_public_key();
+    if device == ours {
+        return Err(anyhow::anyhow!("it is this machine's own device key"));
+    }
+    let revocation = state.sync.revocation(device, wipe)?;
+    let (key, machine_id) = (revoke::revoked_key(device), state.machine_id.clone());
+    // Refused here just as every peer would refuse it
+    revocation.check(&key, &state.sync.users(), &state.sync.trusted_keys())?;
+
+    let k = key.clone();
+    state
+        .storage
+        .write(move |s| s.set(&k, &revocation.to_value(), &machine_id))
+        .await?;
+    state.changed(key, false);
+    state.sync.push_all().await?;
+    Ok(())
+}
+
+/// Leave `key`'s value, sealed, with the cloud server and return the link
+/// that claims it once, or with `offline` seal it into a code
+async fn share_variable(
+    state: &DaemonState,
+    key: &str,
+    expires_secs: u64,
+    offline: bool,
+) -> anyhow::Result<Response> {
+    if expires_secs == 0 || expires_secs > share::MAX_EXPIRES_SECS {
+        return Err(anyhow::anyhow!("shares expire after 1s to 7d"));
+    }
+    let k = key.to_string();
+    let (value, _, _) = state
+        .storage
+        .read(move |s| s.get(&k))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("no such variable"))?;
+    let share = Share {
+        key: key.to_string(),
+        value,
+        expires_at: clock::now() + expires_secs as i64,
+    };
+
+    let code = if offline {
+        share.code()?
+    } else {
+        let (config, cloud_url) = {
+            let node = state.node.lock().await;
+            (node.config().clone(), node.cloud_url().to_string())
+        };
+        if !config.enable_cloud {
+            return Err(anyhow::anyhow!(
+                "a link needs a cloud server ([client] enable_cloud); --offline prints a code instead"
+            ));
+        }
+        let signer = config
+            .signer
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("no device key to sign the share with"))?;
+        share
+            .upload(
+                &cloud_url,
+                &config.tls,
+                config.mesh_token.as_deref(),
+                signer,
+            )
+            .await?
+    };
+    Ok(Response::Shared {
+        code,
+        expires_at: share.expires_at,
+    })
+}
+
+/// Change the mesh's users, in the config file as well as at runtime.
+/// Only admins may, once there are users, and only so that this machine
+/// stays enrolled by one; `change` is passed our device key.
+async fn manage_users<T>(
+    state: &DaemonState,
+    change: impl FnOnce(&mut Users, &str) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let own = state.sync.device_public_key();
+    let mut config = match &state.config_path {
+        Some(path) if path.exists() => Some(Config::from_file(path)?),
+        Some(_) => Some(Config::default()),
+        None => None,
+    };
+    let mut users = match &config {
+        Some(config) => config.users(),
+        None => state.sync.users(),
+    };
+    let ids = [own.clone()];
+    users.check_manage(&ids)?;
+    let result = change(&mut users, &own)?;
+    users
+        .check_manage(&ids)
+        .map_err(|e| anyhow::anyhow!("{} (this machine must stay enrolled by an admin)", e))?;
+
+    if let (Some(path), Some(config)) = (&state.config_path, config.as_mut()) {
+        config.users = users.users.clone();
+        config.save(path)?;
+    }
+    state.sync.set_users(users.clone());
+    let dropped = state.node.lock().await.set_users(users);
+    if dropped > 0 {
+        println!("👥 Users changed; dropped {} connection(s)", dropped);
+    }
+    Ok(result)
+}
+
+/// Normalize and check imported variables against `[keys]` and `[limits]`
+fn prepare_import(
+    vars: Vec<(String, String)>,
+    state: &DaemonState,
+) -> anyhow::Result<Vec<(String, String)>> {
+    vars.into_iter()
+        .map(|(key, value)| {
+            let key = keys::prepare(&key, state.key_policy)?;
+            chunking::check_value_size(&key, &value, state.max_value_bytes)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Which variables of the source namespace `Command::Copy` copies
+struct CopySelection {
+    keys: Vec<String>,
+    all: bool,
+    prefix: Option<String>,
+}
+
+/// Copy variables between namespaces under the same names, in one transaction
+async fn copy_vars(
+    from: String,
+    to: String,
+    selection: CopySelection,
+    state: &DaemonState,
+    dry_run: bool,
+) -> anyhow::Result<Vec<ImportChange>> {
+    let (key_policy, machine_id) = (state.key_policy, state.machine_id.clone());
+    state
+        .storage
+        .write(move |s| {
+            let names = if selection.all {
+                let query = ListQuery {
+                    namespace: Some(from.clone()),
+                    prefix: selection.prefix,
+                    ..Default::default()
+                };
+                s.list(&query)?
+                    .vars
+                    .into_iter()
+                    .map(|var| storage::split_key(&var.key).1.to_string())
+                    .collect()
+            } else {
+                selection.keys
+            };
+            let pairs = names
+                .iter()
+                .map(|name| {
+                    let target = keys::prepare(&storage::namespaced_key(&to, name), key_policy)?;
+                    Ok((storage::namespaced_key(&from, name), target))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            s.copy(&pairs, &machine_id, dry_run)
+        })
+        .await
+}
+
+/// Dry run of `command`: the changes it would make and the peers they
+/// would reach, with nothing written or sent
+async fn preview(command: Command, state: &DaemonState) -> anyhow::Result<ChangePreview> {
+    let machine_id = state.machine_id.clone();
+    let changes = match command {
+        Command::Import { vars, .. } => {
+            let vars = prepare_import(vars, state)?;
+            state
+                .storage
+                .write(move |s| s.import(&vars, &machine_id, true))
+                .await?
+        }
+        Command::Delete { key, .. } => {
+            let lookup = key.clone();
+            let old = state.storage.read(move |s| s.get(&lookup)).await?;
+            old.map(|(old_value, ..)| ImportChange {
+                key,
+                action: ImportAction::Delete,
+                old_value: Some(old_value),
+                value: String::new(),
+            })
+            .into_iter()
+            .collect()
+        }
+        Command::DeleteMatching { pattern } => {
+            state
+                .storage
+                .write(move |s| s.delete_matching(&pattern, &machine_id, true))
+                .await?
+        }
+        Command::Copy {
+            from,
+            to,
+            keys,
+            all,
+            prefix,
+        } => {
+            let selection = CopySelection { keys, all, prefix };
+            copy_vars(from, to, selection, state, true).await?
+        }
+        Command::Restore { snapshot } => state.storage.read(move |s| snapshot.preview(s)).await?,
+        // Receivers keep whichever copy is newer
+        Command::Sync | Command::FullSync => {
+            let since = match command {
+                Command::FullSync => 0,
+                _ => state.sync.watermark().await?,
+            };
+            state
+                .storage
+                .read(move |s| s.outgoing_changes(since))
+                .await?
+        }
+        _ => anyhow::bail!("only import, delete, copy, restore and sync can be previewed"),
+    };
+
+    let local_only = changes
+        .iter()
+        .filter(|c| c.action != ImportAction::Unchanged && !state.sync.allows(&c.key))
+        .map(|c| c.key.clone())
+        .collect();
+    let peers = state
+        .node
+        .lock()
+        .await
+        .peer_links()
+        .into_iter()
+        .map(|link| (link.kind.as_str().to_string(), link.address))
+        .collect();
+    Ok(ChangePreview {
+        changes,
+        peers,
+        local_only,
+    })
+}
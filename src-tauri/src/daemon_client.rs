@@ -0,0 +1,251 @@
+// Typed async client for the daemon control protocol, with pooled
+// connections and retries on transport errors
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::events::MeshEvent;
+use crate::ipc::{DaemonReader, DaemonWriter, Endpoint};
+use crate::protocol::{Command, Response};
+use crate::storage::ListQuery;
+
+/// Idle connections kept for reuse
+const MAX_IDLE: usize = 4;
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+struct Connection {
+    reader: DaemonReader,
+    writer: DaemonWriter,
+}
+
+impl Connection {
+    async fn open(endpoint: &Endpoint) -> Result<Self> {
+        let (reader, writer) = endpoint
+            .connect()
+            .await
+            .map_err(|e| anyhow!("Daemon not running ({}): {}", endpoint, e))?;
+        Ok(Self { reader, writer })
+    }
+
+    async fn send(&mut self, command: &Command) -> Result<()> {
+        let json = serde_json::to_string(command)?;
+        self.writer.write_all(json.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        Ok(())
+    }
+
+    /// Next response line, or None once the daemon closed the connection
+    async fn receive(&mut self) -> Result<Option<Response>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
+
+/// Client for one daemon endpoint (Unix socket, named pipe or TCP). Cheap
+/// to clone; clones share the connection pool.
+#[derive(Clone)]
+pub struct DaemonClient {
+    endpoint: Endpoint,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl DaemonClient {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    /// Retry failed connections and dropped requests `retries` times, `delay`
+    /// apart (doubling each time)
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Send one command and wait for its response. `Response::Error` is
+    /// returned as is; only transport failures are retried, so commands
+    /// sent through here should be safe to repeat.
+    pub async fn request(&self, command: &Command) -> Result<Response> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.try_request(command).await {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < self.retries => {
+                    tracing::debug!("Daemon request failed ({}), retrying", e);
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn try_request(&self, command: &Command) -> Result<Response> {
+        let pooled = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let mut conn = match pooled {
+            Some(conn) => conn,
+            None => Connection::open(&self.endpoint).await?,
+        };
+
+        conn.send(command).await?;
+        let response = conn
+            .receive()
+            .await?
+            .ok_or_else(|| anyhow!("Daemon closed the connection"))?;
+
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+        Ok(response)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let command = Command::Get {
+            key: key.to_string(),
+            fallback: false,
+        };
+        match self.request(&command).await? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<()> {
+        let command = Command::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            force_unlock: false,
+        };
+        match self.request(&command).await? {
+            Response::Success => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let command = Command::Delete {
+            key: key.to_string(),
+            force_unlock: false,
+        };
+        match self.request(&command).await? {
+            Response::Success => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Variables matching `query`, as (key, value) pairs
+    pub async fn list(&self, query: ListQuery) -> Result<Vec<(String, String)>> {
+        match self.request(&Command::List { query }).await? {
+            Response::List(vars) => Ok(vars),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Stream daemon events on a connection of their own
+    pub async fn watch(&self) -> Result<EventStream> {
+        let mut conn = Connection::open(&self.endpoint).await?;
+        conn.send(&Command::Subscribe).await?;
+        Ok(EventStream { conn })
+    }
+}
+
+/// Events from `DaemonClient::watch`
+pub struct EventStream {
+    conn: Connection,
+}
+
+impl EventStream {
+    /// Next event, or None once the daemon goes away
+    pub async fn next(&mut self) -> Result<Option<MeshEvent>> {
+        match self.conn.receive().await? {
+            Some(Response::Event(event)) => Ok(Some(event)),
+            Some(other) => Err(unexpected(other)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> anyhow::Error {
+    match response {
+        Response::Error(msg) => anyhow!(msg),
+        other => anyhow!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    /// Answers every `get` with its key; hangs up on the very first
+    /// connection without answering
+    async fn fake_daemon() -> (Endpoint, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap().to_string());
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let count = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                    continue;
+                }
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).await.unwrap() > 0 {
+                        let response = match serde_json::from_str(&line).unwrap() {
+                            Command::Get { key, .. } => Response::Value(Some(key)),
+                            _ => Response::Error("unsupported".to_string()),
+                        };
+                        let json = serde_json::to_string(&response).unwrap();
+                        writer.write_all(json.as_bytes()).await.unwrap();
+                        writer.write_all(b"\n").await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        (endpoint, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_reuses_connections() {
+        let (endpoint, accepted) = fake_daemon().await;
+        let client = DaemonClient::new(endpoint).with_retries(2, Duration::from_millis(10));
+
+        // First connection is dropped, so this only succeeds on retry
+        assert_eq!(client.get("A").await.unwrap().as_deref(), Some("A"));
+        assert_eq!(client.get("B").await.unwrap().as_deref(), Some("B"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let err = client.delete("A").await.unwrap_err();
+        assert_eq!(err.to_string(), "unsupported");
+    }
+}
@@ -0,0 +1,388 @@
+// `envmesh-cli doctor`: checks the usual causes of a broken setup and says
+// how to fix each one
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::config::Config;
+use crate::ipc::Endpoint;
+use crate::netif;
+use crate::protocol::{Command, Response, PROTOCOL_VERSION};
+use crate::storage::{EnvStorage, ListQuery};
+
+/// How long network checks wait before giving up
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Peer timestamps further ahead of our clock than this are reported
+const MAX_CLOCK_SKEW_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub detail: String,
+    /// What to do about it (for warnings and failures)
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Warn,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Fail,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Run every check against the daemon at `endpoint` and the data in `data_dir`
+pub async fn run(endpoint: &Endpoint, data_dir: &Path) -> Vec<Check> {
+    let (config_check, config) = check_config(Config::find_default_path());
+    let mut checks = vec![config_check];
+
+    let daemon_check = check_daemon(endpoint).await;
+    let daemon_running = daemon_check.severity != Severity::Fail;
+    checks.push(daemon_check);
+    // A missing socket is already reported as the daemon not running
+    #[cfg(unix)]
+    if let Endpoint::Unix(path) = endpoint {
+        if path.exists() {
+            checks.push(check_socket(path));
+        }
+    }
+
+    let db_path = data_dir.join("envmesh.db");
+    checks.push(check_database(&db_path));
+
+    if config.client.enable_lan {
+        checks.push(check_lan_port(&config, daemon_running).await);
+    }
+    if config.client.enable_cloud {
+        for url in config.client.cloud_url.urls() {
+            checks.push(check_cloud(url).await);
+        }
+    }
+    checks.push(check_clock_skew(&db_path, chrono::Utc::now().timestamp()));
+    checks
+}
+
+fn check_config(path: Option<PathBuf>) -> (Check, Config) {
+    let Some(path) = path else {
+        return (
+            Check::ok("Config", "no config file, using defaults"),
+            Config::default(),
+        );
+    };
+
+    match Config::from_file(&path) {
+        Ok(config) => (Check::ok("Config", path.display().to_string()), config),
+        Err(e) => (
+            Check::fail(
+                "Config",
+                format!("{}: {:#}", path.display(), e),
+                "Fix the line reported above; until then the daemon falls back to defaults or refuses to start",
+            ),
+            Config::default(),
+        ),
+    }
+}
+
+async fn check_daemon(endpoint: &Endpoint) -> Check {
+    let connected = tokio::time::timeout(CHECK_TIMEOUT, endpoint.connect()).await;
+    let (mut reader, mut writer) = match connected {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            let denied = e
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied);
+            if denied {
+                return Check::fail(
+                    "Daemon",
+                    format!("permission denied on {}", endpoint),
+                    "The daemon runs as another user; run envmesh-daemon as yourself",
+                );
+            }
+            return Check::fail(
+                "Daemon",
+                format!("not reachable at {} ({})", endpoint, e),
+                "Start it with `envmesh-daemon`, or `envmesh-cli autostart enable` to start it at login",
+            );
+        }
+        Err(_) => {
+            return Check::fail(
+                "Daemon",
+                format!(
+                    "no answer from {} within {}s",
+                    endpoint,
+                    CHECK_TIMEOUT.as_secs()
+                ),
+                "Check the endpoint is an envmesh daemon (ENVMESH_ENDPOINT, [ipc] endpoint)",
+            )
+        }
+    };
+
+    let exchange = async {
+        let json = serde_json::to_string(&Command::Version)?;
+        writer.write_all(json.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        anyhow::Ok(serde_json::from_str::<Response>(&line)?)
+    };
+    match tokio::time::timeout(CHECK_TIMEOUT, exchange).await {
+        Ok(Ok(Response::Version { protocol, daemon })) if protocol == PROTOCOL_VERSION => {
+            Check::ok("Daemon", format!("{} at {}", daemon, endpoint))
+        }
+        Ok(Ok(Response::Version { protocol, daemon })) => Check::warn(
+            "Daemon",
+            format!(
+                "{} speaks protocol v{}, this CLI v{}",
+                daemon, protocol, PROTOCOL_VERSION
+            ),
+            "Install the same EnvMesh release for the CLI and daemon",
+        ),
+        _ => Check::warn(
+            "Daemon",
+            format!("running at {} but too old to report its version", endpoint),
+            "Upgrade the daemon to match this CLI",
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_socket(path: &Path) -> Check {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.permissions().mode() & 0o077 != 0 => Check::warn(
+            "Socket",
+            format!(
+                "{} is mode {:o}; other users can control the daemon",
+                path.display(),
+                meta.permissions().mode() & 0o777
+            ),
+            format!("chmod 600 {}", path.display()),
+        ),
+        Ok(_) => Check::ok("Socket", format!("{} (owner only)", path.display())),
+        Err(e) => Check::fail(
+            "Socket",
+            format!("can't inspect {}: {}", path.display(), e),
+            "Make the data directory readable by you",
+        ),
+    }
+}
+
+fn check_database(path: &Path) -> Check {
+    if !path.exists() {
+        return Check::warn(
+            "Database",
+            format!("{} does not exist yet", path.display()),
+            "Start the daemon once to create it (or pass its --data-dir)",
+        );
+    }
+
+    if let Err(e) = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        return Check::fail(
+            "Database",
+            format!("{} is not writable: {}", path.display(), e),
+            format!("Make it owned by you: chown $USER {}", path.display()),
+        );
+    }
+
+    let readable = EnvStorage::open_reader(path).and_then(|s| s.list(&ListQuery::default()));
+    match readable {
+        Ok(page) => Check::ok(
+            "Database",
+            format!("{} ({} variables)", path.display(), page.total),
+        ),
+        Err(e) => Check::fail(
+            "Database",
+            format!("{} can't be read: {}", path.display(), e),
+            "Restore it from a backup (`envmesh-cli restore`) or move it aside to start fresh",
+        ),
+    }
+}
+
+async fn check_lan_port(config: &Config, daemon_running: bool) -> Check {
+    let node = config.to_node_config();
+    let ips = match netif::bind_addrs(&node.listen_addr, &node.interfaces) {
+        Ok(ips) => ips,
+        Err(e) => {
+            return Check::fail(
+                "LAN port",
+                e.to_string(),
+                "Check [server] listen and interfaces",
+            )
+        }
+    };
+    // Every address the server would listen on must be free
+    let mut free = Vec::new();
+    for ip in ips {
+        let addr = std::net::SocketAddr::new(ip, config.server.port);
+        let check = check_lan_addr(addr, daemon_running).await;
+        if check.severity != Severity::Ok {
+            return check;
+        }
+        free.push(check.detail);
+    }
+    Check::ok("LAN port", free.join(", "))
+}
+
+async fn check_lan_addr(addr: std::net::SocketAddr, daemon_running: bool) -> Check {
+    match tokio::net::TcpListener::bind(addr).await {
+        Ok(_) => Check::ok("LAN port", format!("{} is free", addr)),
+        // Expected while our own daemon is the LAN server
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && daemon_running => {
+            Check::ok("LAN port", format!("{} in use (by the daemon)", addr))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Check::fail(
+            "LAN port",
+            format!("{} is taken by another program", addr),
+            "Free the port or set a different [server] port on every machine",
+        ),
+        Err(e) => Check::fail(
+            "LAN port",
+            format!("can't listen on {}: {}", addr, e),
+            "Check [server] listen is an address of this machine",
+        ),
+    }
+}
+
+/// `host:port` a ws:// or wss:// URL connects to
+fn cloud_host(url: &str) -> Option<String> {
+    let (rest, default_port) = if let Some(rest) = url.strip_prefix("wss://") {
+        (rest, 443)
+    } else {
+        (url.strip_prefix("ws://")?, 80)
+    };
+    let authority = rest.split(['/', '?']).next()?;
+    if authority.is_empty() {
+        return None;
+    }
+    if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+    {
+        Some(authority.to_string())
+    } else {
+        Some(format!("{}:{}", authority, default_port))
+    }
+}
+
+async fn check_cloud(url: &str) -> Check {
+    let Some(host) = cloud_host(url) else {
+        return Check::fail(
+            "Cloud",
+            format!("{} is not a ws:// or wss:// URL", url),
+            "Fix [client] cloud_url, or set enable_cloud = false",
+        );
+    };
+
+    let resolved = tokio::time::timeout(CHECK_TIMEOUT, tokio::net::lookup_host(host.as_str()))
+        .await
+        .is_ok_and(|addrs| addrs.is_ok_and(|mut addrs| addrs.next().is_some()));
+    if resolved {
+        Check::ok("Cloud", format!("{} resolves", host))
+    } else {
+        Check::fail(
+            "Cloud",
+            format!("{} does not resolve", host),
+            "Check DNS and [client] cloud_url, or set enable_cloud = false for LAN-only use",
+        )
+    }
+}
+
+/// Changes stamped in our future mean the writer's clock (or ours) is off,
+/// which skews last-write-wins
+fn check_clock_skew(db_path: &Path, now: i64) -> Check {
+    let newest = match EnvStorage::open_reader(db_path).and_then(|s| s.newest_change_by_machine()) {
+        Ok(newest) => newest,
+        Err(_) => return Check::ok("Clock", "no synced changes to compare against"),
+    };
+
+    let ahead = newest
+        .into_iter()
+        .filter(|(_, timestamp)| timestamp - now > MAX_CLOCK_SKEW_SECS)
+        .max_by_key(|(_, timestamp)| *timestamp);
+    match ahead {
+        Some((machine, timestamp)) => Check::warn(
+            "Clock",
+            format!(
+                "machine {} wrote a change {}s in our future",
+                machine,
+                timestamp - now
+            ),
+            "Enable NTP on every machine (e.g. `timedatectl set-ntp true`); newer-looking changes win conflicts",
+        ),
+        None => Check::ok("Clock", "no peer changes from the future"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_host() {
+        assert_eq!(
+            cloud_host("wss://relay.example.com/mesh").as_deref(),
+            Some("relay.example.com:443")
+        );
+        assert_eq!(
+            cloud_host("ws://localhost:8080").as_deref(),
+            Some("localhost:8080")
+        );
+        assert_eq!(cloud_host("http://relay.example.com"), None);
+        assert_eq!(cloud_host("ws:///path"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reports_missing_daemon_and_future_changes() {
+        let dir = std::env::temp_dir().join(format!("envmesh-doctor-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("envmesh.db");
+        let storage = EnvStorage::new(db_path.clone()).unwrap();
+        storage
+            .apply_remote("KEY", "v", 10_000, "machine-b", false)
+            .unwrap();
+
+        let daemon = check_daemon(&Endpoint::Tcp("127.0.0.1:1".to_string())).await;
+        assert_eq!(daemon.severity, Severity::Fail);
+        assert_eq!(check_database(&db_path).severity, Severity::Ok);
+        assert_eq!(check_clock_skew(&db_path, 9_000).severity, Severity::Warn);
+        assert_eq!(check_clock_skew(&db_path, 10_000).severity, Severity::Ok);
+
+        drop(storage);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
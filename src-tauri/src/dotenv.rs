@@ -0,0 +1,122 @@
+// Parser for .env-style text (CLI `import` and GUI paste import)
+use anyhow::{anyhow, Result};
+
+/// Environment variable names: letters, digits and underscores, not starting
+/// with a digit
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse `KEY=value` lines. Supports comments, blank lines, an optional
+/// `export ` prefix, single quotes (literal) and double quotes (with `\n`,
+/// `\r`, `\"`, `\\` escapes). All problems are reported together, one per line.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(var)) => vars.push(var),
+            Ok(None) => {}
+            Err(e) => errors.push(format!("line {}: {}", index + 1, e)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(vars)
+    } else {
+        Err(anyhow!(errors.join("\n")))
+    }
+}
+
+fn parse_line(line: &str) -> Result<Option<(String, String)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, raw_value) = line
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=value"))?;
+
+    let key = key.trim();
+    if !is_valid_key(key) {
+        return Err(anyhow!("invalid key '{}'", key));
+    }
+
+    Ok(Some((key.to_string(), parse_value(raw_value.trim())?)))
+}
+
+fn parse_value(raw: &str) -> Result<String> {
+    if let Some(rest) = raw.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| anyhow!("unterminated single quote"))?;
+        return Ok(rest[..end].to_string());
+    }
+
+    if let Some(rest) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Ok(value),
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(other) => value.push(other),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        return Err(anyhow!("unterminated double quote"));
+    }
+
+    // Unquoted: a ` #` starts an inline comment
+    let value = raw.split(" #").next().unwrap_or_default();
+    Ok(value.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let text = r#"
+# database
+export DB_HOST=localhost
+DB_PASS='p@ss # not a comment'
+GREETING="hello\nworld"
+PORT=5432 # inline comment
+EMPTY=
+"#;
+
+        let vars = parse(text).unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("DB_HOST".to_string(), "localhost".to_string()),
+                ("DB_PASS".to_string(), "p@ss # not a comment".to_string()),
+                ("GREETING".to_string(), "hello\nworld".to_string()),
+                ("PORT".to_string(), "5432".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_reports_every_bad_line() {
+        let err = parse("GOOD=1\n1BAD=2\nno equals\nQ=\"open")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2: invalid key '1BAD'"));
+        assert!(err.contains("line 3: expected KEY=value"));
+        assert!(err.contains("line 4: unterminated double quote"));
+    }
+}
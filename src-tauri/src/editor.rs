@@ -0,0 +1,281 @@
+// Editor integration (`[editor] listen`): JSON-RPC 2.0, one message per line,
+// on a loopback port, so extensions can complete keys in .env files, show
+// values on hover and set them. Methods map onto daemon commands but keep
+// their own shape, which doesn't change when the daemon protocol does.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+
+use crate::protocol::{Command, Response};
+use crate::secretcheck;
+use crate::storage::ListQuery;
+
+/// Bump only for changes that break extensions written against this API
+pub const EDITOR_API_VERSION: u32 = 1;
+
+/// Shown on hover in place of values that look secret
+pub const MASK: &str = "••••••••";
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The daemon refused or failed the command
+const DAEMON_ERROR: i64 = -32000;
+
+/// `[editor]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EditorConfig {
+    /// Loopback address to serve editors on, e.g. "127.0.0.1:37843" (off
+    /// when unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    /// Show secret-looking values on hover instead of masking them
+    pub reveal_secrets: bool,
+}
+
+impl EditorConfig {
+    /// The address to listen on; anything but loopback is refused, as every
+    /// value can be read through it
+    pub fn listen_addr(&self) -> Result<Option<SocketAddr>> {
+        let Some(listen) = &self.listen else {
+            return Ok(None);
+        };
+        let addr: SocketAddr = listen
+            .parse()
+            .map_err(|_| anyhow!("Invalid [editor] listen address: {:?}", listen))?;
+        if !addr.ip().is_loopback() {
+            return Err(anyhow!(
+                "[editor] listen must be a loopback address, not {}",
+                addr.ip()
+            ));
+        }
+        Ok(Some(addr))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    /// Absent for notifications, which get no reply
+    #[serde(default)]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// One line of reply
+pub fn reply(id: Value, outcome: Result<Value, RpcError>) -> Value {
+    match outcome {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+/// The reply to a line that isn't a request
+pub fn parse_error(e: &serde_json::Error) -> Value {
+    reply(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct KeysParams {
+    namespace: Option<String>,
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyParams {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetParams {
+    key: String,
+    value: String,
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: &Value) -> Result<T, RpcError> {
+    // Methods without required params may be called with none
+    let params = if params.is_null() {
+        json!({})
+    } else {
+        params.clone()
+    };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// The daemon command a request stands for
+pub fn command(request: &Request) -> Result<Command, RpcError> {
+    match request.method.as_str() {
+        "version" => Ok(Command::Version),
+        // Completion in .env files
+        "keys" => {
+            let KeysParams { namespace, prefix } = params(&request.params)?;
+            Ok(Command::List {
+                query: ListQuery {
+                    namespace,
+                    prefix,
+                    ..Default::default()
+                },
+            })
+        }
+        // Hover
+        "resolve" => {
+            let KeyParams { key } = params(&request.params)?;
+            Ok(Command::Get {
+                key,
+                fallback: false,
+            })
+        }
+        "set" => {
+            let SetParams { key, value } = params(&request.params)?;
+            Ok(Command::Set {
+                key,
+                value,
+                force_unlock: false,
+            })
+        }
+        other => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {:?}", other),
+        )),
+    }
+}
+
+/// The result of `request` from the daemon's `response`
+pub fn result(
+    request: &Request,
+    response: Response,
+    config: &EditorConfig,
+) -> Result<Value, RpcError> {
+    match response {
+        Response::Error(message) => Err(RpcError::new(DAEMON_ERROR, message)),
+        Response::Version { daemon, .. } => Ok(json!({
+            "api": EDITOR_API_VERSION,
+            "daemon": daemon,
+        })),
+        Response::List(vars) => Ok(vars
+            .iter()
+            .map(|(key, value)| {
+                json!({ "key": key, "secret": secretcheck::looks_secret(key, value) })
+            })
+            .collect()),
+        Response::Value(value) => {
+            let key = params::<KeyParams>(&request.params)?.key;
+            let masked = !config.reveal_secrets
+                && value
+                    .as_deref()
+                    .is_some_and(|value| secretcheck::looks_secret(&key, value));
+            Ok(json!({
+                "key": key,
+                "found": value.is_some(),
+                "value": if masked { Some(MASK.to_string()) } else { value },
+                "masked": masked,
+            }))
+        }
+        Response::Success => Ok(Value::Bool(true)),
+        _ => Err(RpcError::new(DAEMON_ERROR, "Unexpected daemon response")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str) -> Request {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn test_requests_map_to_commands() {
+        let keys = request(r#"{"jsonrpc":"2.0","id":1,"method":"keys"}"#);
+        assert!(matches!(
+            command(&keys),
+            Ok(Command::List { query }) if query.namespace.is_none()
+        ));
+        let set =
+            request(r#"{"jsonrpc":"2.0","id":2,"method":"set","params":{"key":"A","value":"1"}}"#);
+        assert!(matches!(command(&set), Ok(Command::Set { key, .. }) if key == "A"));
+
+        let missing = request(r#"{"jsonrpc":"2.0","id":3,"method":"resolve"}"#);
+        assert_eq!(command(&missing).unwrap_err().code, INVALID_PARAMS);
+        let unknown = request(r#"{"jsonrpc":"2.0","id":4,"method":"delete"}"#);
+        assert_eq!(command(&unknown).unwrap_err().code, METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_hover_masks_secrets() {
+        let config = EditorConfig::default();
+        let hover = |key: &str, value: &str, config: &EditorConfig| {
+            let line = format!(
+                r#"{{"jsonrpc":"2.0","id":1,"method":"resolve","params":{{"key":"{}"}}}}"#,
+                key
+            );
+            result(
+                &request(&line),
+                Response::Value(Some(value.to_string())),
+                config,
+            )
+            .unwrap()
+        };
+
+        let plain = hover("LOG_LEVEL", "debug", &config);
+        assert_eq!(plain["value"], "debug");
+        assert_eq!(plain["masked"], false);
+        let secret = hover("DB_PASSWORD", "hunter2hunter2", &config);
+        assert_eq!(secret["value"], MASK);
+        assert_eq!(secret["masked"], true);
+
+        let reveal = EditorConfig {
+            listen: None,
+            reveal_secrets: true,
+        };
+        assert_eq!(
+            hover("DB_PASSWORD", "hunter2hunter2", &reveal)["value"],
+            "hunter2hunter2"
+        );
+
+        let keys = request(r#"{"jsonrpc":"2.0","id":2,"method":"keys"}"#);
+        let vars = vec![
+            ("API_TOKEN".to_string(), "x".to_string()),
+            ("PORT".to_string(), "80".to_string()),
+        ];
+        assert_eq!(
+            result(&keys, Response::List(vars), &config).unwrap(),
+            json!([
+                { "key": "API_TOKEN", "secret": true },
+                { "key": "PORT", "secret": false },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_listen_addr_must_be_loopback() {
+        let config = |listen: &str| EditorConfig {
+            listen: Some(listen.to_string()),
+            reveal_secrets: false,
+        };
+        assert!(EditorConfig::default().listen_addr().unwrap().is_none());
+        assert!(config("127.0.0.1:37843").listen_addr().unwrap().is_some());
+        assert!(config("[::1]:37843").listen_addr().unwrap().is_some());
+        assert!(config("0.0.0.0:37843").listen_addr().is_err());
+        assert!(config("localhost:37843").listen_addr().is_err());
+    }
+}
@@ -1,31 +1,274 @@
 // Leader election for LAN server using mDNS discovery
 use anyhow::Result;
 use std::net::IpAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::access::PeerIdentity;
+use crate::beacon::{self, DiscoveryConfig, Responder};
 
 pub type PeerId = String;
 
+/// A node taking part in an election. Highest score wins; ties fall back to
+/// the highest peer ID so every node reaches the same result.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candidate {
+    pub score: u64,
+    pub peer_id: PeerId,
+}
+
+/// Decides how strongly this node bids for the LAN server role
+pub trait ElectionStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn score(&self) -> u64;
+}
+
+/// Strategy selection, parsed from the `[election]` config section
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum StrategyKind {
+    /// Highest peer ID wins (original behavior)
+    #[default]
+    PeerId,
+    /// Fixed priority from config, e.g. desktops high, laptops low
+    Priority(u32),
+    /// Longest-running machine wins
+    Uptime,
+    /// Machines on AC power beat machines on battery
+    ResourceAware,
+}
+
+impl StrategyKind {
+    pub fn build(&self) -> Box<dyn ElectionStrategy> {
+        match self {
+            Self::PeerId => Box::new(PeerIdStrategy),
+            Self::Priority(priority) => Box::new(PriorityStrategy {
+                priority: *priority,
+            }),
+            Self::Uptime => Box::new(UptimeStrategy::new()),
+            Self::ResourceAware => Box::new(ResourceAwareStrategy),
+        }
+    }
+}
+
+pub struct PeerIdStrategy;
+
+impl ElectionStrategy for PeerIdStrategy {
+    fn name(&self) -> &'static str {
+        "peer-id"
+    }
+
+    fn score(&self) -> u64 {
+        0
+    }
+}
+
+pub struct PriorityStrategy {
+    pub priority: u32,
+}
+
+impl ElectionStrategy for PriorityStrategy {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn score(&self) -> u64 {
+        u64::from(self.priority)
+    }
+}
+
+pub struct UptimeStrategy {
+    started: Instant,
+}
+
+impl UptimeStrategy {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Default for UptimeStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ElectionStrategy for UptimeStrategy {
+    fn name(&self) -> &'static str {
+        "uptime"
+    }
+
+    fn score(&self) -> u64 {
+        // Prefer system uptime; fall back to how long this node has run
+        system_uptime()
+            .unwrap_or_else(|| self.started.elapsed())
+            .as_secs()
+    }
+}
+
+pub struct ResourceAwareStrategy;
+
+impl ElectionStrategy for ResourceAwareStrategy {
+    fn name(&self) -> &'static str {
+        "resource-aware"
+    }
+
+    fn score(&self) -> u64 {
+        match detect_power_source() {
+            PowerSource::Ac => 2,
+            PowerSource::Unknown => 1,
+            PowerSource::Battery => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+    Unknown,
+}
+
+#[cfg(target_os = "linux")]
+fn system_uptime() -> Option<Duration> {
+    let contents = std::fs::read_to_string("/proc/uptime").ok()?;
+    let secs: f64 = contents.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_secs_f64(secs))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_uptime() -> Option<Duration> {
+    None
+}
+
+/// Detect whether this machine currently runs on mains power
+#[cfg(target_os = "linux")]
+pub fn detect_power_source() -> PowerSource {
+    let Ok(entries) = std::fs::read_dir("/sys/class/power_supply") else {
+        return PowerSource::Unknown;
+    };
+
+    let mut has_battery = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let kind = std::fs::read_to_string(path.join("type")).unwrap_or_default();
+        match kind.trim() {
+            "Mains" => {
+                let online = std::fs::read_to_string(path.join("online")).unwrap_or_default();
+                if online.trim() == "1" {
+                    return PowerSource::Ac;
+                }
+            }
+            "Battery" => has_battery = true,
+            _ => {}
+        }
+    }
+
+    // No battery at all means a desktop or server
+    if has_battery {
+        PowerSource::Battery
+    } else {
+        PowerSource::Ac
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn detect_power_source() -> PowerSource {
+    let Ok(output) = std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+    else {
+        return PowerSource::Unknown;
+    };
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    if text.contains("AC Power") {
+        PowerSource::Ac
+    } else if text.contains("Battery Power") {
+        PowerSource::Battery
+    } else {
+        PowerSource::Unknown
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn detect_power_source() -> PowerSource {
+    PowerSource::Unknown
+}
+
 pub struct ServerInfo {
     pub peer_id: PeerId,
     pub address: IpAddr,
     pub port: u16,
+    /// Who runs it, if it said
+    pub identity: Option<PeerIdentity>,
 }
 
 pub struct Election {
     my_peer_id: PeerId,
     election_timeout: Duration,
+    strategy: Box<dyn ElectionStrategy>,
+    discovery: DiscoveryConfig,
+    identity: Option<PeerIdentity>,
+}
+
+/// Keeps this node announced as the LAN server until dropped
+pub struct Announcement {
+    _beacon: Option<Responder>,
 }
 
 impl Election {
     pub fn new(peer_id: PeerId) -> Self {
+        Self::with_strategy(peer_id, Box::new(PeerIdStrategy))
+    }
+
+    pub fn with_strategy(peer_id: PeerId, strategy: Box<dyn ElectionStrategy>) -> Self {
         Self {
             my_peer_id: peer_id,
             election_timeout: Duration::from_secs(3),
+            strategy,
+            discovery: DiscoveryConfig::default(),
+            identity: None,
         }
     }
 
-    /// Discover if there's already a LAN server running via mDNS
+    /// Announce who runs the server, so clients can check their peer lists
+    pub fn with_identity(mut self, identity: Option<PeerIdentity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Use `[discovery]`, e.g. to fall back to UDP broadcast
+    pub fn with_discovery(mut self, discovery: DiscoveryConfig) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// This node's bid, as announced to other candidates
+    pub fn candidate(&self) -> Candidate {
+        Candidate {
+            score: self.strategy.score(),
+            peer_id: self.my_peer_id.clone(),
+        }
+    }
+
+    /// Discover if there's already a LAN server running via mDNS, or by UDP
+    /// broadcast if mDNS finds none and `[discovery] broadcast` is on
     pub async fn discover_lan_server(&self) -> Result<Option<ServerInfo>> {
+        if let Some(server) = self.discover_mdns().await? {
+            return Ok(Some(server));
+        }
+        if !self.discovery.broadcast {
+            return Ok(None);
+        }
+        tracing::debug!(
+            "Discovering LAN servers by UDP broadcast on port {}...",
+            self.discovery.broadcast_port
+        );
+        beacon::discover(self.discovery.broadcast_port, beacon::BEACON_WAIT).await
+    }
+
+    async fn discover_mdns(&self) -> Result<Option<ServerInfo>> {
         // TODO: Implement mDNS discovery
         // For now, return None (no server found)
         // In full implementation, this would:
@@ -42,7 +285,10 @@ impl Election {
 
     /// Run election to determine if this node should become the LAN server
     pub async fn should_become_server(&self) -> Result<bool> {
-        tracing::info!("Starting leader election");
+        tracing::info!(
+            "Starting leader election (strategy: {})",
+            self.strategy.name()
+        );
 
         // Announce candidacy
         self.announce_candidate().await?;
@@ -58,37 +304,48 @@ impl Election {
             return Ok(true);
         }
 
-        // Highest peer ID wins (deterministic)
-        let max_candidate = candidates.iter().max().unwrap();
+        Ok(Self::wins(&self.candidate(), &candidates))
+    }
 
-        if *max_candidate < self.my_peer_id {
+    /// Highest (score, peer ID) wins (deterministic)
+    fn wins(me: &Candidate, competitors: &[Candidate]) -> bool {
+        let Some(max_candidate) = competitors.iter().max() else {
+            return true;
+        };
+
+        if max_candidate < me {
             tracing::info!(
-                "I won election (my ID: {} > max competitor: {})",
-                self.my_peer_id,
-                max_candidate
+                "I won election (me: {} @ {} > max competitor: {} @ {})",
+                me.peer_id,
+                me.score,
+                max_candidate.peer_id,
+                max_candidate.score
             );
-            Ok(true)
+            true
         } else {
             tracing::info!(
-                "Lost election (my ID: {} < winner: {})",
-                self.my_peer_id,
-                max_candidate
+                "Lost election (me: {} @ {} < winner: {} @ {})",
+                me.peer_id,
+                me.score,
+                max_candidate.peer_id,
+                max_candidate.score
             );
-            Ok(false)
+            false
         }
     }
 
     async fn announce_candidate(&self) -> Result<()> {
         // TODO: Implement mDNS announcement
-        // Announce "_envmesh-election._tcp" service with peer ID
-        tracing::debug!("Announcing candidacy: {}", self.my_peer_id);
+        // Announce "_envmesh-election._tcp" service with peer ID and score
+        let me = self.candidate();
+        tracing::debug!("Announcing candidacy: {} (score {})", me.peer_id, me.score);
         Ok(())
     }
 
-    async fn discover_candidates(&self) -> Result<Vec<PeerId>> {
+    async fn discover_candidates(&self) -> Result<Vec<Candidate>> {
         // TODO: Implement mDNS query for candidates
         // Query for "_envmesh-election._tcp" service
-        // Return list of peer IDs
+        // Return list of candidates with their scores
 
         tracing::debug!("Discovering election candidates");
 
@@ -96,12 +353,23 @@ impl Election {
         Ok(Vec::new())
     }
 
-    /// Announce this node as the LAN server via mDNS
-    pub async fn announce_as_server(&self, port: u16) -> Result<()> {
+    /// Announce this node as the LAN server via mDNS, and answer broadcast
+    /// queries if `[discovery] broadcast` is on
+    pub async fn announce_as_server(&self, port: u16, addrs: &[IpAddr]) -> Result<Announcement> {
         // TODO: Implement mDNS announcement
-        // Announce "_envmesh._tcp" service on the specified port
-        tracing::info!("Announcing as LAN server on port {}", port);
-        Ok(())
+        // Announce "_envmesh._tcp" service on the specified port and addresses
+        tracing::info!("Announcing as LAN server on port {} at {:?}", port, addrs);
+        let beacon = if self.discovery.broadcast {
+            Some(Responder::start(
+                self.discovery.broadcast_port,
+                self.my_peer_id.clone(),
+                self.identity.clone(),
+                port,
+            )?)
+        } else {
+            None
+        };
+        Ok(Announcement { _beacon: beacon })
     }
 }
 
@@ -132,4 +400,41 @@ mod tests {
         // With no other candidates, should become server
         assert!(result);
     }
+
+    #[test]
+    fn test_score_beats_peer_id() {
+        let desktop = Candidate {
+            score: 100,
+            peer_id: "aaaa".to_string(),
+        };
+        let laptop = Candidate {
+            score: 10,
+            peer_id: "ffff".to_string(),
+        };
+
+        assert!(Election::wins(&desktop, std::slice::from_ref(&laptop)));
+        assert!(!Election::wins(&laptop, &[desktop]));
+    }
+
+    #[test]
+    fn test_equal_scores_fall_back_to_peer_id() {
+        let low = Candidate {
+            score: 5,
+            peer_id: "aaaa".to_string(),
+        };
+        let high = Candidate {
+            score: 5,
+            peer_id: "ffff".to_string(),
+        };
+
+        assert!(Election::wins(&high, std::slice::from_ref(&low)));
+        assert!(!Election::wins(&low, &[high]));
+    }
+
+    #[test]
+    fn test_priority_strategy_score() {
+        let election =
+            Election::with_strategy(generate_peer_id(), StrategyKind::Priority(42).build());
+        assert_eq!(election.candidate().score, 42);
+    }
 }
@@ -0,0 +1,211 @@
+// Embedding API: read and write mesh variables from other Rust programs,
+// through the running daemon or with a node inside the calling process
+use anyhow::Result;
+use std::path::PathBuf;
+
+use crate::access::PeerIdentity;
+use crate::chunking;
+use crate::config::Config;
+use crate::daemon_client::DaemonClient;
+use crate::events::{EventBus, MeshEvent};
+use crate::ipc::{self, Endpoint};
+use crate::keys::{self, KeyPolicy};
+use crate::machineid;
+use crate::node::EnvMeshNode;
+use crate::pool::StoragePool;
+use crate::signing::DeviceKey;
+use crate::storage::ListQuery;
+use crate::sync::SyncEngine;
+
+/// Handle on the mesh's variables.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let mesh = envmesh::EnvMeshClient::daemon()?;
+/// if let Some(url) = mesh.get("prod/DB_URL").await? {
+///     println!("{}", url);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct EnvMeshClient {
+    backend: Backend,
+}
+
+enum Backend {
+    /// Requests on the daemon's control socket
+    Daemon(DaemonClient),
+    /// Our own storage and node, synced like a daemon would be
+    InProcess(LocalNode),
+}
+
+struct LocalNode {
+    storage: StoragePool,
+    sync: SyncEngine,
+    events: EventBus,
+    machine_id: String,
+    key_policy: KeyPolicy,
+    max_value_bytes: usize,
+}
+
+impl EnvMeshClient {
+    /// Use the daemon `envmesh-cli` would talk to (ENVMESH_ENDPOINT,
+    /// `[ipc] endpoint`, or the socket in the data directory)
+    pub fn daemon() -> Result<Self> {
+        Ok(Self::connect(ipc::resolve_endpoint(None, None)?))
+    }
+
+    /// Use the daemon listening on `endpoint`. No connection is made until
+    /// the first call.
+    pub fn connect(endpoint: Endpoint) -> Self {
+        Self {
+            backend: Backend::Daemon(DaemonClient::new(endpoint)),
+        }
+    }
+
+    /// Run a node in this process with its own database (`:memory:` for
+    /// none), joining the mesh as `config` describes. For programs that run
+    /// where no daemon does. The device key is kept next to the database.
+    pub async fn in_process(db_path: PathBuf, config: &Config) -> Result<Self> {
+        let (device_key, machine_id) = if db_path.as_os_str() == ":memory:" {
+            (DeviceKey::generate(), uuid::Uuid::new_v4().to_string())
+        } else {
+            (
+                DeviceKey::load_or_create(&db_path.with_file_name("device.key"))?,
+                machineid::load_or_create(&db_path.with_file_name(machineid::MACHINE_ID_FILE))?,
+            )
+        };
+        let device_key = std::sync::Arc::new(device_key);
+        let storage = StoragePool::open(db_path)?;
+        let events = EventBus::new();
+        let mut node_config = config.to_node_config();
+        node_config.identity = Some(PeerIdentity {
+            machine_id: machine_id.clone(),
+            device_key: device_key.public_key(),
+        });
+        node_config.signer = Some(std::sync::Arc::clone(&device_key));
+        let node = EnvMeshNode::new(node_config, events.clone()).await?;
+        let sync = SyncEngine::new(
+            storage.clone(),
+            std::sync::Arc::new(tokio::sync::Mutex::new(node)),
+            events.clone(),
+            config.sync_filter(),
+        );
+        sync.set_key_policy(config.keys.policy);
+        sync.set_quarantine(config.quarantine.clone());
+        sync.set_rate_limit(config.limits.sync_rate);
+        sync.set_clock_config(config.clock);
+        sync.set_trusted_keys(config.trusted_keys()?);
+        sync.set_peer_access(config.peer_access());
+        sync.set_users(config.users());
+        sync.set_device_key(device_key);
+        sync.set_machine_id(&machine_id);
+
+        tokio::spawn(sync.clone().run_incoming());
+        tokio::spawn(sync.clone().run_seeding());
+
+        Ok(Self {
+            backend: Backend::InProcess(LocalNode {
+                storage,
+                sync,
+                events,
+                machine_id,
+                key_policy: config.keys.policy,
+                max_value_bytes: config.limits.max_value_bytes,
+            }),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        match &self.backend {
+            Backend::Daemon(daemon) => daemon.get(key).await,
+            Backend::InProcess(local) => {
+                let key = key.to_string();
+                let found = local.storage.read(move |s| s.get(&key)).await?;
+                Ok(found.map(|(value, ..)| value))
+            }
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<()> {
+        match &self.backend {
+            Backend::Daemon(daemon) => daemon.set(key, value).await,
+            Backend::InProcess(local) => local.write(key, value, false).await,
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        match &self.backend {
+            Backend::Daemon(daemon) => daemon.delete(key).await,
+            Backend::InProcess(local) => local.write(key, "", true).await,
+        }
+    }
+
+    /// Variables matching `query`, as (key, value) pairs
+    pub async fn list(&self, query: ListQuery) -> Result<Vec<(String, String)>> {
+        match &self.backend {
+            Backend::Daemon(daemon) => daemon.list(query).await,
+            Backend::InProcess(local) => {
+                let page = local.storage.read(move |s| s.list(&query)).await?;
+                Ok(page.vars.into_iter().map(|v| (v.key, v.value)).collect())
+            }
+        }
+    }
+}
+
+impl LocalNode {
+    /// Store a local change, announce it and send it to the mesh
+    async fn write(&self, key: &str, value: &str, deleted: bool) -> Result<()> {
+        let key = if deleted {
+            key.to_string()
+        } else {
+            let key = keys::prepare(key, self.key_policy)?;
+            chunking::check_value_size(&key, value, self.max_value_bytes)?;
+            key
+        };
+        self.sync.check_write(&key)?;
+
+        let (k, v, machine_id) = (key.clone(), value.to_string(), self.machine_id.clone());
+        self.storage
+            .write(move |s| {
+                if deleted {
+                    s.delete(&k, &machine_id)
+                } else {
+                    s.set(&k, &v, &machine_id)
+                }
+            })
+            .await?;
+        self.events.emit(MeshEvent::VarChanged {
+            key: key.clone(),
+            machine_id: self.machine_id.clone(),
+            deleted,
+        });
+        self.sync.publish_stored(&key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_process_round_trip() {
+        let mut config = Config::default();
+        config.client.enable_cloud = false;
+        config.server.mode = "server-preferred".to_string();
+        config.server.port = 0;
+
+        let mesh = EnvMeshClient::in_process(PathBuf::from(":memory:"), &config)
+            .await
+            .unwrap();
+        mesh.set(" prod/DB_URL ", "postgres://db").await.unwrap();
+        assert_eq!(
+            mesh.get("prod/DB_URL").await.unwrap().as_deref(),
+            Some("postgres://db")
+        );
+        assert!(mesh.set("BAD KEY", "v").await.is_err());
+
+        mesh.delete("prod/DB_URL").await.unwrap();
+        assert!(mesh.list(ListQuery::default()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+// Environment files for applications and service managers: .env, JSON, a
+// systemd `EnvironmentFile` or a launchd `EnvironmentVariables` plist
+// fragment (`envmesh-cli export --format ...`), and `[[writers]]` the daemon
+// rewrites whenever one of their variables changes
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::dotenv;
+use crate::events::{EventBus, MeshEvent};
+use crate::pattern;
+use crate::pool::StoragePool;
+use crate::storage::{self, ListQuery};
+
+/// Wait this long after a change for the rest of a batch before rewriting
+const SETTLE: Duration = Duration::from_millis(500);
+
+fn default_namespace() -> String {
+    storage::DEFAULT_NAMESPACE.to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvFileFormat {
+    /// `KEY='value'` lines, as `envmesh-cli import` and dotenv libraries read
+    Dotenv,
+    /// A flat JSON object
+    Json,
+    /// `KEY="value"` lines for `EnvironmentFile=`
+    Systemd,
+    /// `<key>EnvironmentVariables</key><dict>..</dict>` for a launchd plist
+    Launchd,
+}
+
+impl EnvFileFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dotenv => "dotenv",
+            Self::Json => "json",
+            Self::Systemd => "systemd",
+            Self::Launchd => "launchd",
+        }
+    }
+}
+
+impl FromStr for EnvFileFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dotenv" => Ok(Self::Dotenv),
+            "json" => Ok(Self::Json),
+            "systemd" => Ok(Self::Systemd),
+            "launchd" => Ok(Self::Launchd),
+            other => Err(anyhow!(
+                "Unknown format {:?} (dotenv, json, systemd or launchd)",
+                other
+            )),
+        }
+    }
+}
+
+/// `[[writers]]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvFileConfig {
+    /// Where to write it (Windows paths are translated inside WSL)
+    pub path: String,
+
+    pub format: EnvFileFormat,
+
+    /// Namespace whose variables it holds, under their bare names
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+
+    /// Only names matching these patterns (empty = all)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
+    /// Never names matching these patterns (wins over include)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// Only variables with this tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl EnvFileConfig {
+    fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || pattern::matches_any(&self.include, name))
+            && !pattern::matches_any(&self.exclude, name)
+            && (self.format == EnvFileFormat::Json || dotenv::is_valid_key(name))
+    }
+}
+
+/// A .env value: single-quoted (literal to every dotenv reader) unless it
+/// holds a quote or line break, then double-quoted with escapes
+fn dotenv_quote(value: &str) -> String {
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+/// A value inside systemd's double quotes: `\`, `"`, `$` and backticks are
+/// escaped; newlines are kept as they are (systemd reads them as part of
+/// the value)
+fn systemd_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn xml_escape(value: &str) -> Result<String> {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => bail!("control character U+{:04X}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    Ok(escaped)
+}
+
+/// `vars` in `format`
+pub fn render(format: EnvFileFormat, vars: &[(String, String)]) -> Result<String> {
+    let invalid: Vec<&str> = vars
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !dotenv::is_valid_key(name))
+        .collect();
+    if !invalid.is_empty() && format != EnvFileFormat::Json {
+        bail!("Invalid variable names: {}", invalid.join(", "));
+    }
+
+    match format {
+        EnvFileFormat::Dotenv => Ok(vars
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, dotenv_quote(value)))
+            .collect()),
+        EnvFileFormat::Json => {
+            let object: serde_json::Map<String, serde_json::Value> = vars
+                .iter()
+                .map(|(name, value)| (name.clone(), serde_json::Value::String(value.clone())))
+                .collect();
+            Ok(serde_json::to_string_pretty(&object)? + "\n")
+        }
+        EnvFileFormat::Systemd => Ok(vars
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, systemd_quote(value)))
+            .collect()),
+        EnvFileFormat::Launchd => {
+            let mut fragment = String::from("<key>EnvironmentVariables</key>\n<dict>\n");
+            for (name, value) in vars {
+                let value = xml_escape(value).map_err(|e| anyhow!("{}: {}", name, e))?;
+                fragment.push_str(&format!(
+                    "    <key>{}</key>\n    <string>{}</string>\n",
+                    name, value
+                ));
+            }
+            fragment.push_str("</dict>\n");
+            Ok(fragment)
+        }
+    }
+}
+
+/// Replace `path` with `contents` by writing a sibling and renaming it, so
+/// a service starting mid-write never reads half a file. The file is only
+/// readable by its owner.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let mut partial_name = file_name.to_os_string();
+    partial_name.push(".partial");
+    let partial = path.with_file_name(partial_name);
+    // Left by a crash, maybe with other permissions
+    let _ = std::fs::remove_file(&partial);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&partial)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Keeps `[[writers]]` in step with their namespaces
+pub struct EnvFileWriter {
+    files: Vec<(PathBuf, EnvFileConfig)>,
+    storage: StoragePool,
+}
+
+impl EnvFileWriter {
+    pub fn new(files: &[EnvFileConfig], storage: StoragePool) -> Self {
+        Self {
+            files: files
+                .iter()
+                .map(|file| (crate::wsl::translate_path(&file.path), file.clone()))
+                .collect(),
+            storage,
+        }
+    }
+
+    /// Rewrite the files in `namespace` (all of them for None) whose
+    /// contents changed
+    pub async fn write(&self, namespace: Option<&str>) {
+        for (path, file) in &self.files {
+            if namespace.is_some_and(|ns| ns != file.namespace) {
+                continue;
+            }
+            if let Err(e) = self.write_file(path, file).await {
+                tracing::error!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    async fn write_file(&self, path: &Path, file: &EnvFileConfig) -> Result<()> {
+        let query = ListQuery {
+            namespace: Some(file.namespace.clone()),
+            tag: file.tag.clone(),
+            ..Default::default()
+        };
+        let vars: Vec<(String, String)> = self
+            .storage
+            .read(move |s| s.list(&query))
+            .await?
+            .vars
+            .into_iter()
+            .filter_map(|var| {
+                let name = storage::split_key(&var.key).1.to_string();
+                file.allows(&name).then_some((name, var.value))
+            })
+            .collect();
+        let contents = render(file.format, &vars)?;
+        if std::fs::read_to_string(path).ok().as_deref() == Some(contents.as_str()) {
+            return Ok(());
+        }
+        write_atomic(path, &contents)?;
+        tracing::info!("Wrote {} ({} variables)", path.display(), vars.len());
+        Ok(())
+    }
+
+    /// Write every file, then rewrite them as their variables change until
+    /// the event bus closes
+    pub async fn run(self, events: EventBus) {
+        let mut rx = events.subscribe();
+        self.write(None).await;
+
+        loop {
+            let namespace = match rx.recv().await {
+                Ok(MeshEvent::VarChanged { key, .. }) => {
+                    Some(storage::split_key(&key).0.to_string())
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => return,
+            };
+
+            // Imports and syncs change many keys at once: write once after
+            let mut namespaces = vec![namespace];
+            let deadline = tokio::time::sleep(SETTLE);
+            tokio::pin!(deadline);
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    event = rx.recv() => match event {
+                        Ok(MeshEvent::VarChanged { key, .. }) => {
+                            namespaces.push(Some(storage::split_key(&key).0.to_string()));
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(_)) => namespaces.push(None),
+                        Err(RecvError::Closed) => return,
+                    },
+                }
+            }
+
+            if namespaces.contains(&None) {
+                self.write(None).await;
+                continue;
+            }
+            namespaces.sort();
+            namespaces.dedup();
+            for namespace in namespaces.into_iter().flatten() {
+                self.write(Some(&namespace)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_file_formats() {
+        let vars = vec![
+            ("DB_URL".to_string(), "postgres://a&b@db/\"x\"".to_string()),
+            ("GREETING".to_string(), "hi $USER\nbye `now`".to_string()),
+        ];
+        assert_eq!(
+            render(EnvFileFormat::Systemd, &vars).unwrap(),
+            "DB_URL=\"postgres://a&b@db/\\\"x\\\"\"\n\
+             GREETING=\"hi \\$USER\nbye \\`now\\`\"\n"
+        );
+        assert_eq!(
+            render(EnvFileFormat::Launchd, &vars).unwrap(),
+            "<key>EnvironmentVariables</key>\n<dict>\n    \
+             <key>DB_URL</key>\n    <string>postgres://a&amp;b@db/&quot;x&quot;</string>\n    \
+             <key>GREETING</key>\n    <string>hi $USER\nbye `now`</string>\n</dict>\n"
+        );
+        assert_eq!(
+            render(EnvFileFormat::Dotenv, &vars).unwrap(),
+            "DB_URL='postgres://a&b@db/\"x\"'\nGREETING=\"hi $USER\\nbye `now`\"\n"
+        );
+        let dotenv = render(EnvFileFormat::Dotenv, &vars).unwrap();
+        assert_eq!(dotenv::parse(&dotenv).unwrap(), vars);
+        // A bare carriage return would end the line for most readers
+        let crlf = vec![("CERT".to_string(), "a\r\nb\r".to_string())];
+        let dotenv = render(EnvFileFormat::Dotenv, &crlf).unwrap();
+        assert_eq!(dotenv, "CERT=\"a\\r\\nb\\r\"\n");
+        assert_eq!(dotenv::parse(&dotenv).unwrap(), crlf);
+        let json = render(EnvFileFormat::Json, &vars).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()["GREETING"],
+            "hi $USER\nbye `now`"
+        );
+
+        let writer: EnvFileConfig = toml::from_str(
+            r#"
+            path = "app.env"
+            format = "dotenv"
+            include = ["APP_*"]
+            exclude = ["*_DEBUG"]
+            "#,
+        )
+        .unwrap();
+        assert!(writer.allows("APP_PORT"));
+        assert!(!writer.allows("APP_DEBUG"));
+        assert!(!writer.allows("DB_URL"));
+
+        let bell = vec![("BELL".to_string(), "\u{7}".to_string())];
+        assert!(render(EnvFileFormat::Launchd, &bell).is_err());
+        let namespaced = vec![("prod/DB_URL".to_string(), "x".to_string())];
+        assert!(render(EnvFileFormat::Systemd, &namespaced).is_err());
+
+        let dir = std::env::temp_dir().join(format!("envmesh-envfiles-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.env");
+        write_atomic(&path, "A=\"1\"\n").unwrap();
+        write_atomic(&path, "A=\"2\"\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "A=\"2\"\n");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
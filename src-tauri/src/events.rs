@@ -0,0 +1,274 @@
+// Event bus for connection and sync state changes (GUI, tray, CLI watchers)
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::sync::broadcast;
+
+use crate::merge::MergeStrategy;
+use crate::node::NodeMode;
+
+/// Slow subscribers that fall further behind than this start losing events
+const EVENT_BUS_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MeshEvent {
+    /// The node switched role (cloud client, LAN client, LAN server)
+    ModeChanged { mode: NodeMode },
+    /// A server was reached, or a client connected to our LAN server
+    PeerConnected { peer: String },
+    /// A client came back to our LAN server in time to get only the
+    /// changes it missed
+    PeerResumed { peer: String, missed: usize },
+    /// A client of our LAN server asked the machine `from` to resync it
+    /// (`envmesh-cli resync`); we answer if that is us
+    StateRequested { peer: String, from: String },
+    /// A server or client connection went away
+    PeerLost { peer: String },
+    /// A variable was written or deleted, locally or by an incoming change
+    VarChanged {
+        key: String,
+        machine_id: String,
+        deleted: bool,
+    },
+    /// A sync cycle pushed local changes to the network
+    SyncCompleted { changes: usize },
+    /// An incoming change lost last-write-wins against a different local value
+    ConflictDetected {
+        key: String,
+        local_machine: String,
+        remote_machine: String,
+    },
+    /// A conflict was settled by choosing one side
+    ConflictResolved { key: String },
+    /// An incoming change that lost last-write-wins was merged into the
+    /// local value by the key's `merge:` strategy instead
+    ConflictMerged {
+        key: String,
+        strategy: MergeStrategy,
+    },
+    /// A peer kept exceeding its rate limit and is ignored for a while
+    PeerBanned { peer: String, secs: u64 },
+    /// The LAN server's clock is ahead of ours by `skew_secs` (negative:
+    /// behind), past `[clock] max_skew_secs`
+    ClockSkew {
+        peer: String,
+        skew_secs: i64,
+        compensated: bool,
+    },
+    /// An incoming change looked risky and waits for approval
+    ChangeQuarantined {
+        key: String,
+        machine_id: String,
+        reason: String,
+    },
+    /// A stored credential expires within `[expiry] warn_days`, or has
+    /// expired (`expires_at` is in the past)
+    CredentialExpiring { key: String, expires_at: i64 },
+    /// A machine is deleting `percent` of the store; its deletes wait for
+    /// approval until it stops
+    DeleteStorm {
+        machine_id: String,
+        count: usize,
+        percent: u64,
+    },
+    /// A process asked the lease agent for `key` and waits for
+    /// `envmesh-cli agent approve ID`
+    LeaseRequested {
+        id: u64,
+        key: String,
+        process: String,
+    },
+}
+
+impl fmt::Display for MeshEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ModeChanged { mode } => match mode {
+                NodeMode::CloudClient => write!(f, "Connected to cloud server"),
+                NodeMode::LanClient { server_addr } => {
+                    write!(f, "Switched to LAN server {}", server_addr)
+                }
+                NodeMode::LanServer { port } => {
+                    write!(f, "Now running as LAN server on port {}", port)
+                }
+            },
+            Self::PeerConnected { peer } => write!(f, "Peer {} connected", peer),
+            Self::PeerResumed { peer, missed } => {
+                write!(f, "Peer {} resumed ({} missed changes)", peer, missed)
+            }
+            Self::StateRequested { peer, from } => {
+                write!(f, "Peer {} asked {} for its state", peer, from)
+            }
+            Self::PeerLost { peer } => write!(f, "Peer {} lost", peer),
+            Self::VarChanged { key, deleted, .. } => {
+                if *deleted {
+                    write!(f, "{} deleted", key)
+                } else {
+                    write!(f, "{} changed", key)
+                }
+            }
+            Self::SyncCompleted { changes } => write!(f, "Sync completed ({} changes)", changes),
+            Self::ConflictDetected {
+                key,
+                local_machine,
+                remote_machine,
+            } => write!(
+                f,
+                "Conflict on {} (local {} vs remote {})",
+                key, local_machine, remote_machine
+            ),
+            Self::ConflictResolved { key } => write!(f, "Conflict on {} resolved", key),
+            Self::ConflictMerged { key, strategy } => {
+                write!(f, "Conflict on {} merged ({})", key, strategy.as_str())
+            }
+            Self::PeerBanned { peer, secs } => {
+                write!(f, "Peer {} banned for {}s (rate limit)", peer, secs)
+            }
+            Self::ClockSkew {
+                peer,
+                skew_secs,
+                compensated,
+            } => {
+                write!(f, "Clock is {:+}s off from {}", skew_secs, peer)?;
+                if *compensated {
+                    write!(f, " (compensating)")?;
+                }
+                Ok(())
+            }
+            Self::ChangeQuarantined {
+                key,
+                machine_id,
+                reason,
+            } => write!(
+                f,
+                "Change to {} from {} held for approval ({})",
+                key, machine_id, reason
+            ),
+            Self::CredentialExpiring { key, expires_at } => {
+                let days = (expires_at - chrono::Utc::now().timestamp()).div_euclid(86_400);
+                if days < 0 {
+                    write!(f, "{} has expired", key)
+                } else {
+                    write!(f, "{} expires in {} days", key, days)
+                }
+            }
+            Self::DeleteStorm {
+                machine_id,
+                count,
+                percent,
+            } => write!(
+                f,
+                "{} sent {} deletes ({}% of the keys); held for approval",
+                machine_id, count, percent
+            ),
+            Self::LeaseRequested { id, key, process } => write!(
+                f,
+                "{} asks for {} (envmesh-cli agent approve {})",
+                process, key, id
+            ),
+        }
+    }
+}
+
+impl MeshEvent {
+    /// Whether the event deserves a native notification rather than just a
+    /// UI refresh
+    pub fn is_notable(&self) -> bool {
+        matches!(
+            self,
+            Self::ModeChanged { .. }
+                | Self::PeerConnected { .. }
+                | Self::ConflictDetected { .. }
+                | Self::PeerBanned { .. }
+                | Self::ClockSkew { .. }
+                | Self::ChangeQuarantined { .. }
+                | Self::DeleteStorm { .. }
+                | Self::CredentialExpiring { .. }
+                | Self::LeaseRequested { .. }
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<MeshEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { tx }
+    }
+
+    /// Publish an event; having no subscribers is not an error
+    pub fn emit(&self, event: MeshEvent) {
+        tracing::debug!("Event: {:?}", event);
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MeshEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_receive_events() {
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe();
+
+        bus.emit(MeshEvent::SyncCompleted { changes: 3 });
+
+        match rx.recv().await.unwrap() {
+            MeshEvent::SyncCompleted { changes } => assert_eq!(changes, 3),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = MeshEvent::ModeChanged {
+            mode: NodeMode::LanServer { port: 8765 },
+        };
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains("\"event\":\"mode_changed\""));
+        assert!(json.contains("\"kind\":\"lan_server\""));
+    }
+
+    #[test]
+    fn test_notable_events() {
+        let failover = MeshEvent::ModeChanged {
+            mode: NodeMode::LanServer { port: 8765 },
+        };
+        assert!(failover.is_notable());
+        assert_eq!(
+            failover.to_string(),
+            "Now running as LAN server on port 8765"
+        );
+        let conflict = MeshEvent::ConflictDetected {
+            key: "AWS_SECRET_KEY".to_string(),
+            local_machine: "laptop".to_string(),
+            remote_machine: "desktop-01".to_string(),
+        };
+        assert!(conflict.is_notable());
+
+        // Routine traffic stays out of the notification center
+        assert!(!MeshEvent::SyncCompleted { changes: 3 }.is_notable());
+        assert!(!MeshEvent::VarChanged {
+            key: "API_KEY".to_string(),
+            machine_id: "laptop".to_string(),
+            deleted: false,
+        }
+        .is_notable());
+    }
+}
@@ -0,0 +1,236 @@
+// Expiry warnings: when a stored credential runs out, read from an
+// `expires:DATE` tag or from the value itself (a JWT's `exp` claim, a
+// certificate's notAfter, also in file secrets). The daemon checks hourly and
+// emits `CredentialExpiring` for those within `[expiry] warn_days`.
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use x509_cert::der::{Decode, DecodePem};
+use x509_cert::Certificate;
+
+use crate::events::{EventBus, MeshEvent};
+use crate::files::{self, FileBlob};
+use crate::pool::StoragePool;
+
+/// Tag giving a key's expiry by hand: `expires:2026-11-01` or an RFC 3339 time
+pub const EXPIRES_TAG_PREFIX: &str = "expires:";
+
+/// How often the daemon looks for credentials about to expire
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+fn default_warn_days() -> u64 {
+    14
+}
+
+/// `[expiry]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpiryConfig {
+    /// Warn this many days ahead (0 = never warn)
+    #[serde(default = "default_warn_days")]
+    pub warn_days: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            warn_days: default_warn_days(),
+        }
+    }
+}
+
+/// Where an expiry was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpirySource {
+    Tag,
+    Jwt,
+    Certificate,
+}
+
+impl ExpirySource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tag => "tag",
+            Self::Jwt => "jwt",
+            Self::Certificate => "certificate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expiry {
+    pub key: String,
+    /// Unix seconds
+    pub expires_at: i64,
+    pub source: ExpirySource,
+}
+
+/// Parse an `expires:` tag's date (midnight UTC) or RFC 3339 time
+pub fn parse_tag(tag: &str) -> Option<Result<i64>> {
+    let when = tag.strip_prefix(EXPIRES_TAG_PREFIX)?;
+    let parsed = match NaiveDate::parse_from_str(when, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()),
+        Err(_) => DateTime::parse_from_rfc3339(when)
+            .map(|time| time.timestamp())
+            .map_err(|_| {
+                anyhow!(
+                    "Invalid expiry {:?} (use YYYY-MM-DD or an RFC 3339 time)",
+                    when
+                )
+            }),
+    };
+    Some(parsed)
+}
+
+/// Refuse `expires:` tags that aren't dates, which would never warn
+pub fn validate_tags(tags: &[String]) -> Result<()> {
+    for tag in tags {
+        if let Some(parsed) = parse_tag(tag) {
+            parsed?;
+        }
+    }
+    Ok(())
+}
+
+/// When `value` expires, if it is a JWT or a certificate (PEM or DER)
+pub fn detect(value: &[u8]) -> Option<(i64, ExpirySource)> {
+    if let Some(exp) = std::str::from_utf8(value).ok().and_then(jwt_exp) {
+        return Some((exp, ExpirySource::Jwt));
+    }
+    let cert = Certificate::from_pem(value)
+        .or_else(|_| Certificate::from_der(value))
+        .ok()?;
+    let not_after = cert.tbs_certificate.validity.not_after.to_unix_duration();
+    Some((not_after.as_secs() as i64, ExpirySource::Certificate))
+}
+
+/// The `exp` claim of a JWT (not verified: it only decides when to warn)
+fn jwt_exp(token: &str) -> Option<i64> {
+    let mut parts = token.trim().split('.');
+    let (_, payload, _) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let claims: serde_json::Value =
+        serde_json::from_slice(&BASE64URL.decode(payload).ok()?).ok()?;
+    claims.get("exp")?.as_i64()
+}
+
+/// When the variable `key` expires: its `expires:` tag, else what its value
+/// says (for file secrets, their contents)
+pub fn expiry_of(key: &str, value: &str, tags: &[String]) -> Option<Expiry> {
+    let tagged = tags
+        .iter()
+        .find_map(|tag| parse_tag(tag).and_then(Result::ok))
+        .map(|at| (at, ExpirySource::Tag));
+    let (expires_at, source) = tagged.or_else(|| match files::file_name(key) {
+        Some(_) => detect(&FileBlob::from_value(value).ok()?.contents().ok()?),
+        None => detect(value.as_bytes()),
+    })?;
+    Some(Expiry {
+        key: key.to_string(),
+        expires_at,
+        source,
+    })
+}
+
+/// Every stored credential with a known expiry, soonest first
+pub async fn scan(storage: &StoragePool) -> Result<Vec<Expiry>> {
+    let (vars, tags) = storage.read(|s| Ok((s.list_all()?, s.all_tags()?))).await?;
+    let mut tags_by_key: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, tag) in tags {
+        tags_by_key.entry(key).or_default().push(tag);
+    }
+    let mut expiries: Vec<Expiry> = vars
+        .iter()
+        .filter_map(|(key, value, ..)| {
+            let tags = tags_by_key.get(key).map(Vec::as_slice).unwrap_or_default();
+            expiry_of(key, value, tags)
+        })
+        .collect();
+    expiries.sort_by_key(|expiry| expiry.expires_at);
+    Ok(expiries)
+}
+
+/// Check every `CHECK_INTERVAL` and warn once per expiry as it comes within
+/// `warn_days`, and again once it has passed
+pub async fn run(storage: StoragePool, events: EventBus, config: ExpiryConfig) {
+    let warn_secs = (config.warn_days * 86_400) as i64;
+    // Key -> (expiry warned about, whether it had passed)
+    let mut warned: HashMap<String, (i64, bool)> = HashMap::new();
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let expiries = match scan(&storage).await {
+            Ok(expiries) => expiries,
+            Err(e) => {
+                tracing::warn!("Failed to check credential expiry: {}", e);
+                continue;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        for expiry in expiries {
+            if expiry.expires_at - now > warn_secs {
+                continue;
+            }
+            let expired = expiry.expires_at <= now;
+            if warned.get(&expiry.key) == Some(&(expiry.expires_at, expired)) {
+                continue;
+            }
+            tracing::warn!(
+                "{} {} at {} ({})",
+                expiry.key,
+                if expired { "expired" } else { "expires" },
+                expiry.expires_at,
+                expiry.source.as_str()
+            );
+            warned.insert(expiry.key.clone(), (expiry.expires_at, expired));
+            events.emit(MeshEvent::CredentialExpiring {
+                key: expiry.key,
+                expires_at: expiry.expires_at,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_from_tags_and_values() {
+        let claims = BASE64URL.encode(br#"{"sub":"ci","exp":1893456000}"#);
+        let jwt = format!("eyJhbGciOiJIUzI1NiJ9.{}.c2ln", claims);
+        assert_eq!(
+            expiry_of("CI_TOKEN", &jwt, &[]),
+            Some(Expiry {
+                key: "CI_TOKEN".to_string(),
+                expires_at: 1_893_456_000,
+                source: ExpirySource::Jwt,
+            })
+        );
+        assert_eq!(expiry_of("PLAIN", "hunter2.not.jwt", &[]), None);
+
+        // A tag wins over the value
+        let tags = vec!["prod".to_string(), "expires:2030-01-02".to_string()];
+        let tagged = expiry_of("CI_TOKEN", &jwt, &tags).unwrap();
+        assert_eq!(tagged.source, ExpirySource::Tag);
+        assert_eq!(tagged.expires_at, 1_893_542_400);
+        assert!(validate_tags(&["expires:2030-01-02T08:00:00Z".to_string()]).is_ok());
+        assert!(validate_tags(&["expires:soon".to_string()]).is_err());
+
+        let pem = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/tls/relay.pem"
+        ))
+        .unwrap();
+        let file = FileBlob::new(&pem).to_value().unwrap();
+        let cert = expiry_of(&files::file_key("relay.pem"), &file, &[]).unwrap();
+        assert_eq!(cert.source, ExpirySource::Certificate);
+        assert!(cert.expires_at > 1_700_000_000);
+    }
+}
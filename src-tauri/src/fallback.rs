@@ -0,0 +1,123 @@
+// Read-through fallback for `get`: keys not in the mesh yet are looked up in
+// the daemon's own environment and then a .env file, for teams moving over
+// from plain dotenv files one variable at a time
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::dotenv;
+use crate::storage;
+
+/// The daemon's own settings and secrets live under this prefix
+const OWN_PREFIX: &str = "ENVMESH_";
+
+/// `[fallback]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FallbackConfig {
+    /// Fall back on every `get`, not only `get --fallback-env`
+    pub enabled: bool,
+    /// .env file read after the daemon's environment (re-read on each miss)
+    pub dotenv: Option<PathBuf>,
+    /// Environment variables holding the daemon's secrets (backup
+    /// passphrases, vault credentials...), never handed out; see
+    /// `Config::fallback`
+    #[serde(skip)]
+    pub hidden: Vec<String>,
+}
+
+impl FallbackConfig {
+    /// `key`'s value outside the mesh, if it has one. Outside the mesh
+    /// there are no namespaces: `prod/DB_URL` is looked up as `DB_URL`.
+    pub fn lookup(&self, key: &str) -> Result<Option<String>> {
+        let (_, key) = storage::split_key(key);
+        if !self.is_hidden(key) {
+            if let Ok(value) = std::env::var(key) {
+                return Ok(Some(value));
+            }
+        }
+        let Some(path) = &self.dotenv else {
+            return Ok(None);
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        };
+        let vars = dotenv::parse(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        // As when sourcing the file, the last assignment wins
+        Ok(vars
+            .into_iter()
+            .rev()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Whether the daemon's environment variable `name` is off limits
+    fn is_hidden(&self, name: &str) -> bool {
+        name.starts_with(OWN_PREFIX) || self.hidden.iter().any(|hidden| hidden == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_prefers_environment_over_dotenv() {
+        // Setting variables races other tests, so borrow one already set
+        let inherited = std::env::vars_os().find_map(|(name, value)| {
+            let name = name.into_string().ok()?;
+            let plain =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            Some((plain.then_some(name)?, value.into_string().ok()?))
+        });
+        let path = std::env::temp_dir().join(format!("envmesh-fallback-{}", uuid::Uuid::new_v4()));
+        let shadowed = inherited
+            .as_ref()
+            .map(|(name, _)| format!("{}=file\n", name))
+            .unwrap_or_default();
+        std::fs::write(
+            &path,
+            format!("{}DB_URL=old\nDB_URL=postgres://db\n", shadowed),
+        )
+        .unwrap();
+        let config = FallbackConfig {
+            dotenv: Some(path.clone()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.lookup("DB_URL").unwrap(),
+            Some("postgres://db".to_string())
+        );
+        assert_eq!(config.lookup("MISSING_KEY_FOR_TEST").unwrap(), None);
+        if let Some((name, value)) = &inherited {
+            assert_eq!(config.lookup(name).unwrap().as_ref(), Some(value));
+            let namespaced = storage::namespaced_key("prod", name);
+            assert_eq!(config.lookup(&namespaced).unwrap().as_ref(), Some(value));
+        }
+
+        std::fs::write(&path, "not a line\n").unwrap();
+        assert!(config.lookup("DB_URL").is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.lookup("DB_URL").unwrap(), None);
+    }
+
+    #[test]
+    fn test_lookup_skips_the_daemons_own_secrets() {
+        let inherited = std::env::vars_os()
+            .find_map(|(name, _)| name.into_string().ok().filter(|name| !name.is_empty()));
+        let config = FallbackConfig {
+            hidden: inherited.iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        if let Some(name) = &inherited {
+            assert_eq!(config.lookup(name).unwrap(), None);
+        }
+        assert!(config.is_hidden("ENVMESH_BACKUP_PASSPHRASE"));
+        assert!(config.is_hidden(crate::relay::MESH_TOKEN_ENV));
+        assert!(!config.is_hidden("DB_URL"));
+    }
+}
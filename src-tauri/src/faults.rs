@@ -0,0 +1,142 @@
+// Fault injection for WebSocket traffic (`[faults]`, `faults` build feature):
+// drop, delay, duplicate and reorder messages to rehearse failover,
+// reconnection and dedup before a rollout
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Whether this build injects faults; without the `faults` feature `[faults]`
+/// is read but ignored
+pub const ENABLED: bool = cfg!(feature = "faults");
+
+/// `[faults]`. Rates are chances per message, from 0.0 to 1.0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    /// Chance a message is held back and sent after the next one
+    pub reorder_rate: f64,
+    /// Messages are delayed by up to this much (uniformly)
+    pub max_delay_ms: u64,
+    /// Fixed seed for repeatable runs; random otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl FaultConfig {
+    pub fn is_active(&self) -> bool {
+        self.drop_rate > 0.0
+            || self.duplicate_rate > 0.0
+            || self.reorder_rate > 0.0
+            || self.max_delay_ms > 0
+    }
+}
+
+/// Small deterministic PRNG (SplitMix64), so fault injection and
+/// simulations (`testkit`) need no rand crate
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `low..=high`
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    /// True with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+}
+
+/// Decides what happens to each message on one connection
+pub struct FaultInjector<T> {
+    config: FaultConfig,
+    rng: SimRng,
+    /// Held back for reordering until the next message goes out
+    held: Option<T>,
+}
+
+impl<T: Clone> FaultInjector<T> {
+    /// An injector for `config`, or None if it injects nothing or this build
+    /// has no `faults` feature
+    pub fn new(config: &FaultConfig) -> Option<Self> {
+        if !ENABLED || !config.is_active() {
+            return None;
+        }
+        let seed = config
+            .seed
+            .unwrap_or_else(|| chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64);
+        Some(Self {
+            config: config.clone(),
+            rng: SimRng::new(seed),
+            held: None,
+        })
+    }
+
+    /// What to send in place of `msg`, each after its delay. Empty when the
+    /// message is dropped or held back; a held message follows the next one.
+    pub fn apply(&mut self, msg: T) -> Vec<(Duration, T)> {
+        if self.rng.chance(self.config.drop_rate) {
+            tracing::debug!("Fault injection: dropping message");
+            return Vec::new();
+        }
+        if self.held.is_none() && self.rng.chance(self.config.reorder_rate) {
+            tracing::debug!("Fault injection: holding message back");
+            self.held = Some(msg);
+            return Vec::new();
+        }
+
+        let delay = Duration::from_millis(self.rng.range(0, self.config.max_delay_ms));
+        let mut out = vec![(delay, msg.clone())];
+        if self.rng.chance(self.config.duplicate_rate) {
+            tracing::debug!("Fault injection: duplicating message");
+            out.push((delay, msg));
+        }
+        if let Some(held) = self.held.take() {
+            out.push((delay, held));
+        }
+        out
+    }
+}
+
+#[cfg(all(test, feature = "faults"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults_drop_duplicate_and_reorder() {
+        let config = |drop_rate, duplicate_rate, reorder_rate| FaultConfig {
+            drop_rate,
+            duplicate_rate,
+            reorder_rate,
+            max_delay_ms: 0,
+            seed: Some(1),
+        };
+        assert!(FaultInjector::<u32>::new(&FaultConfig::default()).is_none());
+
+        let mut drop = FaultInjector::new(&config(1.0, 0.0, 0.0)).unwrap();
+        assert!(drop.apply(1).is_empty());
+
+        let mut dup = FaultInjector::new(&config(0.0, 1.0, 0.0)).unwrap();
+        let sent: Vec<u32> = dup.apply(1).into_iter().map(|(_, m)| m).collect();
+        assert_eq!(sent, vec![1, 1]);
+
+        let mut reorder = FaultInjector::new(&config(0.0, 0.0, 1.0)).unwrap();
+        assert!(reorder.apply(1).is_empty());
+        let sent: Vec<u32> = reorder.apply(2).into_iter().map(|(_, m)| m).collect();
+        assert_eq!(sent, vec![2, 1]);
+    }
+}
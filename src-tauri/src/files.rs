@@ -0,0 +1,110 @@
+// File secrets (certificates, kubeconfigs, SSH keys) stored as synced blobs
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Storage keys of files start with this. `@` can't start a variable name,
+/// so files never collide with variables and are left out of listings and
+/// shell export.
+pub const FILE_KEY_PREFIX: &str = "@file/";
+
+pub fn file_key(name: &str) -> String {
+    format!("{}{}", FILE_KEY_PREFIX, name)
+}
+
+/// File name for a storage key, or None for a variable key
+pub fn file_name(key: &str) -> Option<&str> {
+    key.strip_prefix(FILE_KEY_PREFIX)
+}
+
+/// A file as stored in a variable value (JSON)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileBlob {
+    /// Hex SHA-256 of the contents
+    pub sha256: String,
+    pub size: usize,
+    /// Base64 contents
+    pub data: String,
+}
+
+/// A stored file without its contents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub sha256: String,
+    pub size: usize,
+    pub timestamp: i64,
+}
+
+impl FileBlob {
+    pub fn new(contents: &[u8]) -> Self {
+        Self {
+            sha256: hex::encode(Sha256::digest(contents)),
+            size: contents.len(),
+            data: BASE64.encode(contents),
+        }
+    }
+
+    /// Decode the contents, checking them against the recorded hash
+    pub fn contents(&self) -> Result<Vec<u8>> {
+        let contents = BASE64.decode(&self.data)?;
+        if contents.len() != self.size || hex::encode(Sha256::digest(&contents)) != self.sha256 {
+            return Err(anyhow!("File contents do not match their SHA-256"));
+        }
+        Ok(contents)
+    }
+
+    pub fn to_value(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_value(value: &str) -> Result<Self> {
+        serde_json::from_str(value).map_err(|e| anyhow!("Not a stored file: {}", e))
+    }
+}
+
+/// File names: no path separators or control characters, so a name can be
+/// used as-is for a file on any platform
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control());
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid file name '{}'", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_round_trip_and_integrity() {
+        let pem = b"-----BEGIN CERTIFICATE-----\nMIIB\x00\xff\n-----END CERTIFICATE-----\n";
+        let blob = FileBlob::new(pem);
+        let stored = FileBlob::from_value(&blob.to_value().unwrap()).unwrap();
+        assert_eq!(stored.contents().unwrap(), pem);
+
+        let tampered = FileBlob {
+            data: BASE64.encode(b"something else"),
+            ..stored
+        };
+        assert!(tampered.contents().is_err());
+    }
+
+    #[test]
+    fn test_file_keys_and_names() {
+        assert_eq!(file_name(&file_key("kubeconfig")), Some("kubeconfig"));
+        assert_eq!(file_name("KUBECONFIG"), None);
+        assert!(validate_name("prod-ca.pem").is_ok());
+        assert!(validate_name("../etc/passwd").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
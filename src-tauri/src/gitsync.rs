@@ -0,0 +1,393 @@
+// Git replication for networks that block peers and relays: each machine
+// commits an encrypted snapshot of one namespace to a shared repository and
+// merges the snapshots other machines pushed
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backup::Snapshot;
+use crate::pool::StoragePool;
+use crate::storage;
+use crate::sync::SyncEngine;
+
+/// Snapshots live in `<repo>/envmesh/<namespace>/<device>.bak`; each machine
+/// only ever writes its own file, so pulls never conflict
+const SNAPSHOT_DIR: &str = "envmesh";
+const SNAPSHOT_SUFFIX: &str = ".bak";
+
+fn default_namespace() -> String {
+    storage::DEFAULT_NAMESPACE.to_string()
+}
+
+fn default_remote() -> String {
+    "origin".to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+fn default_passphrase_env() -> String {
+    "ENVMESH_GIT_PASSPHRASE".to_string()
+}
+
+/// `[git_sync]`: replicate a namespace through a git repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitSyncConfig {
+    /// Local clone of the shared repository (Windows paths are translated
+    /// inside WSL)
+    pub repo: String,
+
+    /// Namespace to replicate
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+
+    /// Remote to pull from and push to; without it commits stay local
+    #[serde(default = "default_remote")]
+    pub remote: String,
+
+    /// Seconds between rounds of pull, merge, commit and push
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Environment variable holding the snapshot passphrase, shared by
+    /// every machine on the repository
+    #[serde(default = "default_passphrase_env")]
+    pub passphrase_env: String,
+}
+
+/// What one round did
+#[derive(Debug, Default, PartialEq)]
+pub struct GitSyncReport {
+    /// Variables merged from other machines' snapshots
+    pub merged: usize,
+    /// Whether our own snapshot changed and was committed
+    pub committed: bool,
+}
+
+#[derive(Clone)]
+pub struct GitSync {
+    repo: PathBuf,
+    namespace: String,
+    remote: String,
+    interval: Duration,
+    passphrase: String,
+    storage: StoragePool,
+    sync: SyncEngine,
+    machine_id: String,
+    /// Stable per machine (from the device key), unlike `machine_id`
+    device: String,
+}
+
+impl GitSync {
+    pub fn new(
+        config: &GitSyncConfig,
+        passphrase: String,
+        storage: StoragePool,
+        sync: SyncEngine,
+        machine_id: String,
+    ) -> Result<Self> {
+        let repo = crate::wsl::translate_path(&config.repo);
+        if !repo.join(".git").exists() {
+            return Err(anyhow!(
+                "[git_sync] repo {} is not a git clone",
+                repo.display()
+            ));
+        }
+
+        let device = hex::encode(&Sha256::digest(sync.device_public_key().as_bytes())[..8]);
+        Ok(Self {
+            repo,
+            namespace: config.namespace.clone(),
+            remote: config.remote.clone(),
+            interval: Duration::from_secs(config.interval_secs.max(10)),
+            passphrase,
+            storage,
+            sync,
+            machine_id,
+            device,
+        })
+    }
+
+    pub fn repo(&self) -> &Path {
+        &self.repo
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.repo.join(SNAPSHOT_DIR).join(&self.namespace)
+    }
+
+    fn own_path(&self) -> PathBuf {
+        self.dir()
+            .join(format!("{}{}", self.device, SNAPSHOT_SUFFIX))
+    }
+
+    /// Run a round every interval, forever
+    pub async fn run(self) {
+        loop {
+            match self.sync_now().await {
+                Ok(report) if report.merged > 0 || report.committed => tracing::info!(
+                    "Git sync: merged {} variable(s), committed: {}",
+                    report.merged,
+                    report.committed
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::error!("Git sync failed: {:#}", e),
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Pull, merge the other machines' snapshots, then commit and push ours
+    /// if it changed
+    pub async fn sync_now(&self) -> Result<GitSyncReport> {
+        let tracking = self
+            .git(&["rev-parse", "--abbrev-ref", "@{upstream}"])
+            .await
+            .is_ok();
+        if tracking {
+            self.git(&["pull", "--rebase", "--quiet"]).await?;
+        }
+
+        let merged = self.merge().await?;
+        if merged > 0 {
+            // Peers on the live mesh (if any) get what came in through git
+            if let Err(e) = self.sync.push_all().await {
+                tracing::warn!("Git sync: could not push merged changes to peers: {}", e);
+            }
+        }
+
+        // Also retries commits an earlier round failed to push
+        let committed = self.commit().await?;
+        let unpushed = if tracking {
+            self.git(&["rev-list", "--count", "@{upstream}..HEAD"])
+                .await?
+                .trim()
+                != "0"
+        } else {
+            self.has_remote().await
+        };
+        if unpushed {
+            self.git(&["push", "--quiet", "-u", &self.remote, "HEAD"])
+                .await?;
+        }
+
+        Ok(GitSyncReport { merged, committed })
+    }
+
+    /// Restore every other machine's snapshot; ones we can't read (another
+    /// passphrase, a newer format) are skipped with a warning
+    async fn merge(&self) -> Result<usize> {
+        let own = self.own_path();
+        let mut merged = 0;
+        let entries = match std::fs::read_dir(self.dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            if path == own || !path.to_string_lossy().ends_with(SNAPSHOT_SUFFIX) {
+                continue;
+            }
+
+            let snapshot = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|archive| Snapshot::decrypt(&archive, &self.passphrase));
+            let mut snapshot = match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    tracing::warn!("Git sync: skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            retain_namespace(&mut snapshot, &self.namespace);
+            merged += self.storage.write(move |s| snapshot.restore(s)).await?;
+        }
+        Ok(merged)
+    }
+
+    /// Write our snapshot and commit it, unless its contents are unchanged
+    async fn commit(&self) -> Result<bool> {
+        let machine_id = self.machine_id.clone();
+        let mut snapshot = self
+            .storage
+            .read(move |s| Snapshot::capture(s, &machine_id, None))
+            .await?;
+        retain_namespace(&mut snapshot, &self.namespace);
+
+        // Every archive has a fresh salt, so compare contents, not bytes
+        let path = self.own_path();
+        let unchanged = match std::fs::read(&path) {
+            Ok(archive) => Snapshot::decrypt(&archive, &self.passphrase)
+                .is_ok_and(|old| old.vars == snapshot.vars && old.tags == snapshot.tags),
+            // Nothing to share yet
+            Err(_) => snapshot.vars.is_empty(),
+        };
+        if unchanged {
+            return Ok(false);
+        }
+
+        std::fs::create_dir_all(self.dir())?;
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, snapshot.encrypt(&self.passphrase)?)?;
+        std::fs::rename(&partial, &path)?;
+
+        let file = path.to_string_lossy().into_owned();
+        self.git(&["add", "--", &file]).await?;
+        let message = format!(
+            "envmesh: {} from {} ({} variables)",
+            self.namespace,
+            self.device,
+            snapshot.vars.iter().filter(|v| !v.deleted).count()
+        );
+        self.git(&["commit", "--quiet", "-m", &message, "--", &file])
+            .await?;
+        Ok(true)
+    }
+
+    async fn has_remote(&self) -> bool {
+        self.git(&["remote", "get-url", &self.remote]).await.is_ok()
+    }
+
+    async fn git(&self, args: &[&str]) -> Result<String> {
+        let output = tokio::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .await
+            .context("Running git")?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(anyhow!(
+                "git {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+/// Drop everything outside `namespace` (a snapshot file is only trusted
+/// for the namespace it is filed under)
+fn retain_namespace(snapshot: &mut Snapshot, namespace: &str) {
+    let inside = |key: &str| storage::split_key(key).0 == namespace;
+    snapshot.vars.retain(|var| inside(&var.key));
+    snapshot.history.retain(|entry| inside(&entry.key));
+    snapshot.tags.retain(|(key, _)| inside(key));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::node::{EnvMeshNode, NodeConfig, ServerMode};
+    use std::process::Command;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    async fn machine(repo: &Path) -> (GitSync, StoragePool) {
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let events = EventBus::new();
+        let node_config = NodeConfig {
+            enable_cloud: false,
+            lan_port: 0,
+            server_mode: ServerMode::ServerPreferred,
+            ..Default::default()
+        };
+        let node = EnvMeshNode::new(node_config, events.clone()).await.unwrap();
+        let sync = SyncEngine::new(
+            storage.clone(),
+            Arc::new(Mutex::new(node)),
+            events,
+            Default::default(),
+        );
+        sync.set_device_key(crate::signing::DeviceKey::generate());
+
+        let config = GitSyncConfig {
+            repo: repo.to_string_lossy().into_owned(),
+            namespace: "prod".to_string(),
+            remote: default_remote(),
+            interval_secs: default_interval_secs(),
+            passphrase_env: default_passphrase_env(),
+        };
+        let git_sync = GitSync::new(
+            &config,
+            "team passphrase".into(),
+            storage.clone(),
+            sync,
+            uuid::Uuid::new_v4().to_string(),
+        )
+        .unwrap();
+        (git_sync, storage)
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        assert!(Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .success());
+    }
+
+    fn clone(remote: &Path, dir: &Path) {
+        let (remote, dir) = (remote.to_str().unwrap(), dir.to_str().unwrap());
+        git(Path::new("."), &["clone", "--quiet", remote, dir]);
+        git(Path::new(dir), &["config", "user.name", "EnvMesh Test"]);
+        git(
+            Path::new(dir),
+            &["config", "user.email", "test@envmesh.invalid"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_machines_merge_through_a_remote() {
+        let root = std::env::temp_dir().join(format!("envmesh-git-{}", uuid::Uuid::new_v4()));
+        let remote = root.join("remote.git");
+        std::fs::create_dir_all(&remote).unwrap();
+        git(&remote, &["init", "--quiet", "--bare"]);
+
+        clone(&remote, &root.join("a"));
+        let (a, a_storage) = machine(&root.join("a")).await;
+        a_storage
+            .write(|s| {
+                s.set("prod/DB_URL", "postgres://db", "a")?;
+                s.set("dev/DEBUG", "1", "a")
+            })
+            .await
+            .unwrap();
+        assert!(a.sync_now().await.unwrap().committed);
+        assert_eq!(
+            a.sync_now().await.unwrap(),
+            GitSyncReport::default(),
+            "an unchanged snapshot is not committed again"
+        );
+
+        clone(&remote, &root.join("b"));
+        let (b, b_storage) = machine(&root.join("b")).await;
+        let report = b.sync_now().await.unwrap();
+        assert_eq!(report.merged, 1);
+        let (db_url, dev) = b_storage
+            .read(|s| Ok((s.get("prod/DB_URL")?, s.get("dev/DEBUG")?)))
+            .await
+            .unwrap();
+        assert_eq!(db_url.unwrap().0, "postgres://db");
+        assert!(dev.is_none(), "only the configured namespace is replicated");
+
+        // b's snapshot now holds the merged variable and reached the remote
+        assert!(report.committed);
+        assert_eq!(a.sync_now().await.unwrap().merged, 0);
+        assert_eq!(root.join("a/envmesh/prod").read_dir().unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+// Tauri desktop app: window, tray icon, and command handlers
+use crate::config::{self, Config};
+use crate::state::AppState;
+use crate::{api, links, revoke, tray, users};
+use std::sync::atomic::Ordering;
+use tauri::{
+    menu::{Menu, MenuItem},
+    tray::{TrayIconBuilder, TrayIconEvent},
+    Emitter, Manager,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Start the desktop app; `data_dir` (--data-dir), ENVMESH_DATA_DIR or
+/// `[storage] path` moves the database out of the app data directory
+pub fn run(data_dir: Option<String>) {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_notification::init())
+        .setup(move |app| {
+            let config = Config::load_default().unwrap_or_else(|e| {
+                tracing::warn!("Ignoring config: {}", e);
+                Config::default()
+            });
+            let app_data_dir = match config::configured_data_dir(data_dir.as_deref(), &config) {
+                Some(dir) => dir,
+                None => app
+                    .path()
+                    .app_data_dir()
+                    .expect("Failed to get app data directory"),
+            };
+
+            std::fs::create_dir_all(&app_data_dir).expect("Failed to create app data directory");
+
+            let db_path = app_data_dir.join("envmesh.db");
+
+            tracing::info!("Database path: {}", db_path.display());
+
+            // Initialize app state
+            let state = tauri::async_runtime::block_on(async {
+                AppState::new(db_path.clone())
+                    .await
+                    .expect("Failed to initialize app state")
+            });
+
+            // Apply incoming changes from the network, and seed clients that join
+            tauri::async_runtime::spawn(state.sync.clone().run_incoming());
+            tauri::async_runtime::spawn(state.sync.clone().run_seeding());
+            tauri::async_runtime::spawn(links::run_pings(state.node.clone()));
+            tauri::async_runtime::spawn(users::run_expiry(state.node.clone()));
+            let revocations = revoke::run(
+                state.sync.clone(),
+                state.storage.clone(),
+                state.node.clone(),
+                state.events.clone(),
+                Some(db_path),
+            );
+            tauri::async_runtime::spawn(async move {
+                // Only returns once this machine was revoked with --wipe
+                if let Err(e) = revocations.await {
+                    tracing::error!("Wiping this revoked machine failed: {}", e);
+                }
+                std::process::exit(0);
+            });
+
+            let initial_mode =
+                tauri::async_runtime::block_on(async { state.node.lock().await.current_mode() });
+            let initial_status = tray::TrayStatus::new(initial_mode.clone());
+
+            // Forward mesh events to the frontend, tray icon, and notifications
+            let mut events = state.events.subscribe();
+            let notifications_enabled = state.notifications_enabled.clone();
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let mut status = tray::TrayStatus::new(initial_mode);
+
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            if let Err(e) = handle.emit("mesh-event", &event) {
+                                tracing::warn!("Failed to forward event to frontend: {}", e);
+                            }
+                            if status.apply(&event) {
+                                tray::refresh(&handle, &status);
+                            }
+                            if notifications_enabled.load(Ordering::Relaxed) {
+                                tray::notify(&handle, &event);
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!("Frontend event forwarder missed {} events", missed);
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+
+            app.manage(state);
+
+            // Create system tray menu
+            let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
+            let show = MenuItem::with_id(app, "show", "Show", true, None::<&str>)?;
+            let sync = MenuItem::with_id(app, "sync", "Sync Now", true, None::<&str>)?;
+
+            let menu = Menu::with_items(app, &[&show, &sync, &quit])?;
+
+            let mut tray_builder = TrayIconBuilder::with_id(tray::TRAY_ID).menu(&menu);
+            if let Some(icon) = app.default_window_icon() {
+                tray_builder = tray_builder.icon(icon.clone());
+            }
+
+            let _tray = tray_builder
+                .on_menu_event(|app, event| match event.id().as_ref() {
+                    "quit" => {
+                        app.exit(0);
+                    }
+                    "show" => {
+                        if let Some(window) = app.get_webview_window("main") {
+                            let _ = window.show();
+                            let _ = window.set_focus();
+                        }
+                    }
+                    "sync" => {
+                        tracing::info!("Manual sync triggered");
+                        let handle = app.clone();
+                        tauri::async_runtime::spawn(async move {
+                            let state = handle.state::<AppState>();
+                            let message = tray::sync_message(&state.sync.push_all().await);
+                            tray::report(
+                                &handle,
+                                &message,
+                                state.notifications_enabled.load(Ordering::Relaxed),
+                            );
+                        });
+                    }
+                    _ => {}
+                })
+                .on_tray_icon_event(|tray, event| {
+                    if let TrayIconEvent::Click { .. } = event {
+                        if let Some(app) = tray.app_handle().get_webview_window("main") {
+                            let _ = app.show();
+                            let _ = app.set_focus();
+                        }
+                    }
+                })
+                .build(app)?;
+
+            tray::refresh(app.handle(), &initial_status);
+
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            api::get_env_var,
+            api::set_env_var,
+            api::delete_env_var,
+            api::list_env_vars,
+            api::set_env_var_tags,
+            api::import_env_text,
+            api::get_var_history,
+            api::get_conflicts,
+            api::resolve_conflict,
+            api::get_peers,
+            api::connection_status,
+            api::force_failover_to_lan,
+            api::force_reconnect_cloud,
+            api::set_server_mode,
+            api::trigger_sync,
+            api::resync_from,
+            api::get_sync_reports,
+            api::set_notifications_enabled,
+            api::set_autostart,
+            api::get_settings,
+            api::update_settings
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
@@ -1,27 +1,187 @@
 // Health monitoring and automatic failover/failback
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::time::interval;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify};
+use tokio_tungstenite::tungstenite::http::Uri;
 
+use crate::client::{Pinger, WebSocketClient};
 use crate::node::{EnvMeshNode, NodeMode};
+use crate::tls::{self, TlsConfig};
 
-pub struct HealthMonitor {
-    cloud_url: String,
-    check_interval: Duration,
+/// Answered by every server without a WebSocket upgrade
+pub const HEALTHZ_PATH: &str = "/healthz";
+
+/// How long a probe may take before the server counts as down
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a ping probe looks for its pong
+const PONG_POLL: Duration = Duration::from_millis(100);
+/// Longest health check request read before answering
+const MAX_PROBE_REQUEST: u64 = 8 * 1024;
+/// Shortest interval `set_check_interval` accepts
+pub const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the cloud server is probed (`[health] probe`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthProbe {
+    /// Ping over our cloud connection, or GET /healthz while we have none
+    #[default]
+    Ping,
+    /// GET /healthz only
+    Http,
+    /// Open (and close) a fresh WebSocket connection each time
+    Connect,
+}
+
+/// `[health]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub probe: HealthProbe,
+}
+
+/// Answer to `HealthStatus`: why the node is (or isn't) on the cloud
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// The node's connection, as `status` describes it
+    pub mode: String,
+    /// Failed over by hand: the monitor won't fail back until reconnected
+    pub lan_hold: bool,
+    /// Checks failed in a row while on the cloud
+    pub consecutive_failures: u32,
+    pub failure_threshold: u32,
+    /// Unix time of the last check (None: none yet)
+    pub last_check: Option<i64>,
+    /// Why the last check failed (None if it passed)
+    pub last_error: Option<String>,
+    pub check_interval_secs: u64,
+    /// Checks still run, but the node doesn't fail over or back
+    pub paused: bool,
+}
+
+/// What the monitor found, and the knobs the daemon can turn while it runs
+#[derive(Debug)]
+pub struct HealthControl {
+    /// Failed checks in a row before failing over
     failure_threshold: u32,
+    state: std::sync::Mutex<ControlState>,
+    /// Wakes the monitor when its interval changes
+    interval_changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    check_interval: Duration,
+    paused: bool,
+    consecutive_failures: u32,
+    last_check: Option<i64>,
+    last_error: Option<String>,
+}
+
+impl HealthControl {
+    fn state(&self) -> std::sync::MutexGuard<'_, ControlState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes effect at once: the monitor checks, then waits `interval`
+    pub fn set_check_interval(&self, interval: Duration) -> Result<()> {
+        if interval < MIN_CHECK_INTERVAL {
+            bail!(
+                "Health check interval must be at least {}s",
+                MIN_CHECK_INTERVAL.as_secs()
+            );
+        }
+        self.state().check_interval = interval;
+        self.interval_changed.notify_one();
+        Ok(())
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.state().paused = paused;
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        self.state().check_interval
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state().paused
+    }
+
+    /// Failures in a row after one more, capped at the threshold: while
+    /// failover is paused the count would otherwise climb past it
+    fn failed_again(&self, failures: u32) -> u32 {
+        (failures + 1).min(self.failure_threshold)
+    }
+
+    fn record(&self, result: &Result<()>, consecutive_failures: u32) {
+        let mut state = self.state();
+        state.last_check = Some(chrono::Utc::now().timestamp());
+        state.last_error = result.as_ref().err().map(|e| e.to_string());
+        state.consecutive_failures = consecutive_failures;
+    }
+
+    /// `mode` and `lan_hold` come from the node, the rest from the last check
+    pub fn status(&self, mode: String, lan_hold: bool) -> HealthStatus {
+        let state = self.state();
+        HealthStatus {
+            mode,
+            lan_hold,
+            consecutive_failures: state.consecutive_failures,
+            failure_threshold: self.failure_threshold,
+            last_check: state.last_check,
+            last_error: state.last_error.clone(),
+            check_interval_secs: state.check_interval.as_secs(),
+            paused: state.paused,
+        }
+    }
+}
+
+pub struct HealthMonitor {
+    /// The cloud counts as up while any of these answers
+    cloud_urls: Vec<String>,
+    probe: HealthProbe,
+    tls: TlsConfig,
+    control: Arc<HealthControl>,
 }
 
 impl HealthMonitor {
-    pub fn new(cloud_url: String) -> Self {
+    pub fn new(cloud_urls: Vec<String>) -> Self {
         Self {
-            cloud_url,
-            check_interval: Duration::from_secs(30),
-            failure_threshold: 3,
+            cloud_urls,
+            probe: HealthProbe::default(),
+            tls: TlsConfig::default(),
+            control: Arc::new(HealthControl {
+                failure_threshold: 3,
+                state: std::sync::Mutex::new(ControlState {
+                    check_interval: Duration::from_secs(30),
+                    ..Default::default()
+                }),
+                interval_changed: Notify::new(),
+            }),
         }
     }
 
+    pub fn with_probe(mut self, probe: HealthProbe) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Trust for a wss:// cloud server, as the node connects with
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Shared with the daemon, to report on and steer the monitor
+    pub fn control(&self) -> Arc<HealthControl> {
+        Arc::clone(&self.control)
+    }
+
     /// Start monitoring in the background
     pub fn start_monitoring(self, node: Arc<Mutex<EnvMeshNode>>) {
         tokio::spawn(async move {
@@ -30,42 +190,56 @@ impl HealthMonitor {
     }
 
     async fn monitor_loop(&self, node: Arc<Mutex<EnvMeshNode>>) {
-        let mut interval = interval(self.check_interval);
         let mut failure_count = 0;
 
         loop {
-            interval.tick().await;
-
-            let current_mode = {
+            let (current_mode, lan_hold) = {
                 let n = node.lock().await;
-                n.current_mode()
+                (n.current_mode(), n.lan_hold())
             };
+            let paused = self.control.is_paused();
 
             match current_mode {
                 NodeMode::CloudClient => {
                     // Check if cloud is still healthy
-                    if !self.is_cloud_healthy().await {
-                        failure_count += 1;
+                    let result = self.check_cloud(&node).await;
+                    if result.is_err() {
+                        failure_count = self.control.failed_again(failure_count);
                         tracing::warn!(
                             "Cloud server health check failed ({}/{})",
                             failure_count,
-                            self.failure_threshold
+                            self.control.failure_threshold
                         );
+                    } else {
+                        failure_count = 0;
+                    }
+                    self.control.record(&result, failure_count);
 
-                        if failure_count >= self.failure_threshold {
-                            tracing::error!("Cloud server down, initiating failover");
-                            if let Err(e) = self.failover_to_lan(Arc::clone(&node)).await {
-                                tracing::error!("Failover failed: {}", e);
-                            }
-                            failure_count = 0;
+                    if result.is_ok() && !paused {
+                        self.return_to_preferred(&node).await;
+                    }
+
+                    if failure_count >= self.control.failure_threshold && paused {
+                        tracing::warn!("Cloud server down, but failover is paused");
+                    } else if failure_count >= self.control.failure_threshold {
+                        tracing::error!("Cloud server down, initiating failover");
+                        if let Err(e) = self.failover_to_lan(Arc::clone(&node)).await {
+                            tracing::error!("Failover failed: {}", e);
                         }
-                    } else {
                         failure_count = 0;
+                        self.control.record(&result, failure_count);
                     }
                 }
+                // Manually failed over: stay until told to reconnect
+                NodeMode::LanClient { .. } | NodeMode::LanServer { .. } if lan_hold => {}
                 NodeMode::LanClient { .. } | NodeMode::LanServer { .. } => {
                     // Check if cloud came back online
-                    if self.is_cloud_healthy().await {
+                    failure_count = 0;
+                    let result = self.check_cloud(&node).await;
+                    self.control.record(&result, failure_count);
+                    if result.is_ok() && paused {
+                        tracing::debug!("Cloud server restored, but failback is paused");
+                    } else if result.is_ok() {
                         tracing::info!("Cloud server restored, initiating failback");
                         if let Err(e) = self.failback_to_cloud(Arc::clone(&node)).await {
                             tracing::error!("Failback failed: {}", e);
@@ -73,30 +247,94 @@ impl HealthMonitor {
                     }
                 }
             }
+
+            let check_interval = self.control.check_interval();
+            tokio::select! {
+                _ = tokio::time::sleep(check_interval) => {}
+                _ = self.control.interval_changed.notified() => {}
+            }
         }
     }
 
-    async fn is_cloud_healthy(&self) -> bool {
-        // Try to connect to cloud server with timeout
-        match tokio::time::timeout(
-            Duration::from_secs(5),
-            crate::client::WebSocketClient::connect(&self.cloud_url),
-        )
-        .await
-        {
-            Ok(Ok(_)) => {
-                tracing::debug!("Cloud server is healthy");
-                true
-            }
-            Ok(Err(e)) => {
-                tracing::debug!("Cloud server unreachable: {}", e);
-                false
+    #[cfg(test)]
+    async fn is_cloud_healthy(&self, node: &Arc<Mutex<EnvMeshNode>>) -> bool {
+        self.check_cloud(node).await.is_ok()
+    }
+
+    async fn check_cloud(&self, node: &Arc<Mutex<EnvMeshNode>>) -> Result<()> {
+        let result = match self.probe {
+            HealthProbe::Ping => match Self::cloud_pinger(node).await {
+                Some(pinger) => Self::ping(&pinger).await,
+                // No cloud connection to ping over
+                None => self.probe_servers(&self.cloud_urls).await,
+            },
+            HealthProbe::Http | HealthProbe::Connect => self.probe_servers(&self.cloud_urls).await,
+        };
+        match &result {
+            Ok(()) => tracing::debug!("Cloud server is healthy"),
+            Err(e) => tracing::debug!("Cloud server unhealthy: {}", e),
+        }
+        result
+    }
+
+    /// Not inline in the match, where the lock would be held until its end
+    async fn cloud_pinger(node: &Arc<Mutex<EnvMeshNode>>) -> Option<Pinger> {
+        node.lock().await.cloud_pinger()
+    }
+
+    /// Ping and wait for a pong; any pong since the ping will do, including
+    /// one to the link pinger's
+    async fn ping(pinger: &Pinger) -> Result<()> {
+        let sent = Instant::now();
+        pinger.ping().await?;
+        tokio::time::timeout(PROBE_TIMEOUT, async {
+            while pinger.last_pong().is_none_or(|at| at < sent) {
+                tokio::time::sleep(PONG_POLL).await;
             }
-            Err(_) => {
-                tracing::debug!("Cloud server connection timeout");
-                false
+        })
+        .await
+        .map_err(|_| anyhow!("No pong within {:?}", PROBE_TIMEOUT))
+    }
+
+    /// On a server listed after others, move back to the first of them that
+    /// answers, so the mesh doesn't stay split across relays
+    async fn return_to_preferred(&self, node: &Arc<Mutex<EnvMeshNode>>) {
+        let preferred = node.lock().await.preferred_cloud_urls();
+        if preferred.is_empty() || self.probe_servers(&preferred).await.is_err() {
+            return;
+        }
+        tracing::info!("Preferred cloud server is up again, moving back to it");
+        if let Err(e) = node.lock().await.return_to_preferred_cloud().await {
+            tracing::warn!("Could not move back to the preferred cloud server: {}", e);
+        }
+    }
+
+    /// Probe each of `urls` in turn until one answers
+    async fn probe_servers(&self, urls: &[String]) -> Result<()> {
+        let mut result = Err(anyhow!("No cloud server configured"));
+        for url in urls {
+            result = match self.probe {
+                HealthProbe::Connect => self.probe_connect(url).await,
+                _ => self.probe_http(url).await,
+            };
+            if result.is_ok() {
+                break;
             }
         }
+        result
+    }
+
+    async fn probe_http(&self, url: &str) -> Result<()> {
+        tokio::time::timeout(PROBE_TIMEOUT, check_healthz(url, &self.tls))
+            .await
+            .map_err(|_| anyhow!("Health check timeout"))?
+    }
+
+    async fn probe_connect(&self, url: &str) -> Result<()> {
+        let client = tokio::time::timeout(PROBE_TIMEOUT, WebSocketClient::connect(url))
+            .await
+            .map_err(|_| anyhow!("Connection timeout"))??;
+        client.close().await
     }
 
     async fn failover_to_lan(&self, node: Arc<Mutex<EnvMeshNode>>) -> Result<()> {
@@ -115,21 +353,143 @@ impl HealthMonitor {
             // TODO: Implement state sync
         }
 
-        // Reconnect to cloud
+        // Reconnect to cloud (a LAN server resigns and notifies its clients)
         n.reconnect_with_failover().await?;
         tracing::info!("Failback to cloud completed");
         Ok(())
     }
 }
 
+/// GET /healthz from the server at `url` (ws:// or wss://)
+pub async fn check_healthz(url: &str, tls: &TlsConfig) -> Result<()> {
+    let uri: Uri = url.parse()?;
+    let host = uri
+        .authority()
+        .ok_or_else(|| anyhow!("No host in {}", url))?
+        .to_string();
+    let mut transport = tls::connect(&uri, tls).await?;
+    transport
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                HEALTHZ_PATH, host
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let mut status = String::new();
+    BufReader::new(transport.take(MAX_PROBE_REQUEST))
+        .read_line(&mut status)
+        .await?;
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(anyhow!("Health check answered {:?}", status.trim())),
+    }
+}
+
+/// Answer a health check instead of a WebSocket handshake. Returns whether
+/// `stream` was one (and has been answered); otherwise nothing has been read
+/// from it.
+pub async fn answer_healthz(stream: &mut TcpStream) -> Result<bool> {
+    let request_line = format!("GET {} ", HEALTHZ_PATH);
+    let mut start = vec![0; request_line.len()];
+    let peeked = tokio::time::timeout(PROBE_TIMEOUT, stream.peek(&mut start))
+        .await
+        .map_err(|_| anyhow!("Nothing sent within {:?}", PROBE_TIMEOUT))??;
+    if start[..peeked] != *request_line.as_bytes() {
+        return Ok(false);
+    }
+
+    // Read the rest of the request so closing doesn't reset the connection
+    let mut reader = BufReader::new(&mut *stream).take(MAX_PROBE_REQUEST);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = tokio::time::timeout(PROBE_TIMEOUT, reader.read_line(&mut line))
+            .await
+            .map_err(|_| anyhow!("Incomplete health check request"))??;
+        if read == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n",
+        )
+        .await?;
+    stream.shutdown().await?;
+    Ok(true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::EventBus;
+    use crate::node::NodeConfig;
+    use crate::server::EmbeddedServer;
 
     #[test]
     fn test_health_monitor_creation() {
-        let monitor = HealthMonitor::new("ws://localhost:8080".to_string());
-        assert_eq!(monitor.failure_threshold, 3);
-        assert_eq!(monitor.check_interval, Duration::from_secs(30));
+        let monitor = HealthMonitor::new(vec!["ws://localhost:8080".to_string()]);
+        assert_eq!(monitor.control.failure_threshold, 3);
+        assert_eq!(monitor.control.check_interval(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_health_control() {
+        let monitor = HealthMonitor::new(vec!["ws://localhost:8080".to_string()]);
+        let control = monitor.control();
+        assert!(control.set_check_interval(Duration::ZERO).is_err());
+        control.set_check_interval(Duration::from_secs(5)).unwrap();
+        control.set_paused(true);
+        control.record(&Err(anyhow!("Health check timeout")), 2);
+
+        let status = control.status("Connected to cloud: ws://localhost:8080".to_string(), false);
+        assert_eq!(status.check_interval_secs, 5);
+        assert!(status.paused);
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("Health check timeout"));
+        assert!(status.last_check.is_some());
+
+        // Paused failover keeps failing, but never past the threshold
+        assert_eq!(control.failed_again(2), 3);
+        assert_eq!(control.failed_again(3), 3);
+
+        control.record(&Ok(()), 0);
+        let status = control.status(String::new(), false);
+        assert_eq!((status.consecutive_failures, status.last_error), (0, None));
+    }
+
+    #[tokio::test]
+    async fn test_probes() {
+        let events = EventBus::new();
+        let server = EmbeddedServer::start(0, events.clone()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let config = NodeConfig {
+            cloud_urls: vec![url.clone()],
+            enable_lan: false,
+            ..Default::default()
+        };
+        let node = Arc::new(Mutex::new(EnvMeshNode::new(config, events).await.unwrap()));
+
+        for probe in [HealthProbe::Ping, HealthProbe::Http, HealthProbe::Connect] {
+            let monitor = HealthMonitor::new(vec![url.clone()]).with_probe(probe);
+            assert!(monitor.is_cloud_healthy(&node).await, "{:?}", probe);
+        }
+        // The health checks left WebSocket clients alone
+        assert!(WebSocketClient::connect(&url).await.is_ok());
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let down = format!("ws://127.0.0.1:{}", closed.local_addr().unwrap().port());
+        drop(closed);
+        assert!(check_healthz(&down, &TlsConfig::default()).await.is_err());
+        for probe in [HealthProbe::Http, HealthProbe::Connect] {
+            let monitor = HealthMonitor::new(vec![down.clone()]).with_probe(probe);
+            assert!(!monitor.is_cloud_healthy(&node).await, "{:?}", probe);
+            // Any server in the list answering will do
+            let monitor = HealthMonitor::new(vec![down.clone(), url.clone()]).with_probe(probe);
+            assert!(monitor.is_cloud_healthy(&node).await, "{:?}", probe);
+        }
     }
 }
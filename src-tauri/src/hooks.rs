@@ -0,0 +1,226 @@
+// Change hooks: run commands when matching variables change (`[[hooks]]` config)
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::{EventBus, MeshEvent};
+use crate::pattern;
+use crate::pool::StoragePool;
+use crate::redact;
+use crate::storage;
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookConfig {
+    /// Key pattern (`*`/`?` wildcards) that triggers the hook
+    pub pattern: String,
+
+    /// Shell command to run (`sh -c` on Unix, `cmd /C` on Windows)
+    pub command: String,
+
+    /// Kill the command if it runs longer than this
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+/// What a hook is told about the change, passed as environment variables
+struct HookContext {
+    key: String,
+    value: Option<String>,
+    machine_id: String,
+}
+
+impl HookContext {
+    fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("ENVMESH_KEY".to_string(), self.key.clone()),
+            ("ENVMESH_MACHINE".to_string(), self.machine_id.clone()),
+            (
+                "ENVMESH_DELETED".to_string(),
+                self.value.is_none().to_string(),
+            ),
+        ];
+
+        if let Some(value) = &self.value {
+            env.push(("ENVMESH_VALUE".to_string(), value.clone()));
+
+            // Also expose the variable under its own name (KUBECONFIG=...)
+            let (_, name) = storage::split_key(&self.key);
+            if crate::dotenv::is_valid_key(name) {
+                env.push((name.to_string(), value.clone()));
+            }
+        }
+
+        env
+    }
+}
+
+pub struct HookRunner {
+    hooks: Vec<HookConfig>,
+    storage: StoragePool,
+}
+
+impl HookRunner {
+    pub fn new(hooks: Vec<HookConfig>, storage: StoragePool) -> Self {
+        Self { hooks, storage }
+    }
+
+    /// Run hooks for every matching change until the event bus closes
+    pub async fn run(self, events: EventBus) {
+        let mut rx = events.subscribe();
+
+        loop {
+            match rx.recv().await {
+                Ok(MeshEvent::VarChanged {
+                    key,
+                    machine_id,
+                    deleted,
+                }) => {
+                    let matching: Vec<HookConfig> = self
+                        .hooks
+                        .iter()
+                        .filter(|hook| pattern::matches(&hook.pattern, &key))
+                        .cloned()
+                        .collect();
+                    if matching.is_empty() {
+                        continue;
+                    }
+
+                    let value = if deleted {
+                        None
+                    } else {
+                        let lookup = key.clone();
+                        match self.storage.read(move |s| s.get(&lookup)).await {
+                            Ok(found) => found.map(|(value, _, _)| value),
+                            Err(e) => {
+                                tracing::error!("Hook lookup for {} failed: {}", key, e);
+                                continue;
+                            }
+                        }
+                    };
+
+                    let context = Arc::new(HookContext {
+                        key,
+                        value,
+                        machine_id,
+                    });
+                    for hook in matching {
+                        let context = Arc::clone(&context);
+                        tokio::spawn(async move {
+                            if let Err(e) = run_hook(&hook, &context).await {
+                                tracing::error!(
+                                    "Hook '{}' for {}: {}",
+                                    hook.command,
+                                    context.key,
+                                    e
+                                );
+                            }
+                        });
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Hook runner missed {} events", missed);
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// `command` run by the platform shell (`sh -c`, `cmd /C`)
+pub(crate) fn shell_command(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    }
+
+    #[cfg(not(windows))]
+    {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+async fn run_hook(hook: &HookConfig, context: &HookContext) -> Result<()> {
+    tracing::info!("Running hook '{}' for {}", hook.command, context.key);
+
+    let child = shell_command(&hook.command)
+        .envs(context.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("timed out after {}s", hook.timeout_secs))??;
+
+    // The hook was handed the value, and may well print it
+    let scrub = |output: &[u8]| {
+        let output = String::from_utf8_lossy(output);
+        match &context.value {
+            Some(value) => redact::scrub(&output, value).into_owned(),
+            None => output.into_owned(),
+        }
+    };
+    for line in scrub(&output.stdout).lines() {
+        tracing::info!("[hook {}] {}", context.key, line);
+    }
+    for line in scrub(&output.stderr).lines() {
+        tracing::warn!("[hook {}] {}", context.key, line);
+    }
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("exited with {}", output.status))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            key: "prod/KUBECONFIG".to_string(),
+            value: Some("/etc/kube/prod".to_string()),
+            machine_id: "machine-a".to_string(),
+        }
+    }
+
+    fn hook(command: &str, timeout_secs: u64) -> HookConfig {
+        HookConfig {
+            pattern: "*KUBECONFIG*".to_string(),
+            command: command.to_string(),
+            timeout_secs,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hook_sees_new_value() {
+        let check =
+            r#"test "$KUBECONFIG" = /etc/kube/prod && test "$ENVMESH_KEY" = prod/KUBECONFIG"#;
+        run_hook(&hook(check, 5), &context()).await.unwrap();
+        assert!(run_hook(&hook("exit 3", 5), &context()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hook_timeout() {
+        let err = run_hook(&hook("sleep 5", 1), &context()).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+}
@@ -0,0 +1,274 @@
+// Daemon control endpoint: Unix socket or TCP (Windows, WSL bridging)
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::path::Path;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// TCP endpoint the daemon listens on by default on Windows
+pub const DEFAULT_TCP_ENDPOINT: &str = "127.0.0.1:37842";
+
+/// Named pipe the Windows daemon listens on with the default data directory
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\envmesh";
+
+/// Other data directories get a localhost port from here up (plus < 1000)
+const INSTANCE_PORT_BASE: u16 = 37843;
+
+/// Host placeholder in `[ipc] endpoint` resolving to the Windows host from WSL
+pub const WINDOWS_HOST: &str = "windows-host";
+
+/// Shared secret a daemon's TCP listeners demand and clients present
+pub const IPC_TOKEN_ENV: &str = "ENVMESH_IPC_TOKEN";
+
+/// `ENVMESH_IPC_TOKEN`, if set and not empty
+pub fn ipc_token() -> Option<String> {
+    std::env::var(IPC_TOKEN_ENV)
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Only this machine can reach a listener on `addr` ("host:port")
+pub fn is_loopback(addr: &str) -> bool {
+    match addr.rsplit_once(':') {
+        Some(("localhost", _)) => true,
+        _ => addr
+            .parse::<std::net::SocketAddr>()
+            .is_ok_and(|addr| addr.ip().is_loopback()),
+    }
+}
+
+pub type DaemonReader = Box<dyn AsyncBufRead + Unpin + Send>;
+pub type DaemonWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(windows)]
+    Pipe(String),
+    Tcp(String),
+}
+
+impl Endpoint {
+    /// Unix socket in the data directory, or localhost TCP on Windows (on a
+    /// port of its own for any but the default data directory)
+    pub fn platform_default(data_dir: &Path) -> Self {
+        #[cfg(unix)]
+        return Self::Unix(data_dir.join("daemon.sock"));
+
+        #[cfg(windows)]
+        Self::Tcp(tcp_endpoint_for(data_dir))
+    }
+
+    /// `unix:/path/to/daemon.sock`, `pipe:\\.\pipe\NAME` (Windows),
+    /// `host:port`, or `windows-host:port`
+    pub fn parse(spec: &str) -> Result<Self> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+
+            #[cfg(windows)]
+            return Err(anyhow!("Unix sockets are not supported here: {}", path));
+        }
+        if let Some(name) = spec.strip_prefix("pipe:") {
+            #[cfg(windows)]
+            return Ok(Self::Pipe(name.to_string()));
+
+            #[cfg(unix)]
+            return Err(anyhow!(
+                "Named pipes are only supported on Windows: {}",
+                name
+            ));
+        }
+
+        let (host, port) = spec
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Endpoint must be unix:PATH or HOST:PORT, got '{}'", spec))?;
+        port.parse::<u16>()
+            .with_context(|| format!("Invalid port in endpoint '{}'", spec))?;
+
+        let host = if host == WINDOWS_HOST {
+            crate::wsl::windows_host().to_string()
+        } else {
+            host.to_string()
+        };
+
+        Ok(Self::Tcp(format!("{}:{}", host, port)))
+    }
+
+    pub async fn connect(&self) -> Result<(DaemonReader, DaemonWriter)> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                let stream = UnixStream::connect(path).await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(BufReader::new(reader)), Box::new(writer)))
+            }
+            #[cfg(windows)]
+            Self::Pipe(name) => {
+                let pipe = open_pipe(name).await?;
+                let (reader, writer) = tokio::io::split(pipe);
+                Ok((Box::new(BufReader::new(reader)), Box::new(writer)))
+            }
+            Self::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                let (reader, mut writer) = stream.into_split();
+                if let Some(token) = ipc_token() {
+                    let auth = serde_json::to_string(&crate::protocol::Command::Auth { token })?;
+                    writer.write_all(auth.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                Ok((Box::new(BufReader::new(reader)), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// Open a named pipe client, waiting while every server instance is busy
+#[cfg(windows)]
+async fn open_pipe(name: &str) -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    // winerror.h ERROR_PIPE_BUSY
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    for _ in 0..50 {
+        match ClientOptions::new().open(name) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            result => return Ok(result?),
+        }
+    }
+    Err(anyhow!("Named pipe {} stayed busy", name))
+}
+
+/// Localhost TCP endpoint of the daemon keeping its data in `data_dir`:
+/// `DEFAULT_TCP_ENDPOINT` for the default directory, and a port derived from
+/// the path for any other, so several daemons (`--instance`) can run side
+/// by side
+pub fn tcp_endpoint_for(data_dir: &Path) -> String {
+    match instance_hash(data_dir) {
+        None => DEFAULT_TCP_ENDPOINT.to_string(),
+        Some(hash) => format!("127.0.0.1:{}", INSTANCE_PORT_BASE + (hash % 1000) as u16),
+    }
+}
+
+/// Named pipe of the daemon keeping its data in `data_dir`, derived like
+/// `tcp_endpoint_for()`
+pub fn pipe_name_for(data_dir: &Path) -> String {
+    match instance_hash(data_dir) {
+        None => DEFAULT_PIPE_NAME.to_string(),
+        Some(hash) => format!("{}-{:08x}", DEFAULT_PIPE_NAME, hash),
+    }
+}
+
+/// FNV-1a of `data_dir`, which unlike std's hasher is the same in every
+/// build (None for the default directory)
+fn instance_hash(data_dir: &Path) -> Option<u32> {
+    if data_dir == crate::config::default_data_dir() {
+        return None;
+    }
+    let hash = data_dir
+        .to_string_lossy()
+        .bytes()
+        .fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        });
+    Some(hash)
+}
+
+/// `flag` (e.g. --endpoint), then the daemon using the data directory
+/// `data_dir` (--data-dir or --instance), then ENVMESH_ENDPOINT, then
+/// `[ipc] endpoint`, then the daemon using the data directory
+/// `config::data_dir()` picks (Unix socket, or localhost TCP on Windows)
+pub fn resolve_endpoint(flag: Option<&str>, data_dir: Option<&str>) -> Result<Endpoint> {
+    if let Some(spec) = flag {
+        return Endpoint::parse(spec);
+    }
+    if let Some(dir) = data_dir {
+        return Ok(Endpoint::platform_default(&crate::wsl::translate_path(dir)));
+    }
+    let config = crate::config::Config::load_default().unwrap_or_else(|e| {
+        eprintln!("⚠️  Ignoring config: {}", e);
+        crate::config::Config::default()
+    });
+    match std::env::var("ENVMESH_ENDPOINT")
+        .ok()
+        .or(config.ipc.endpoint.clone())
+    {
+        Some(spec) => Endpoint::parse(&spec),
+        None => Ok(Endpoint::platform_default(&crate::config::data_dir(
+            None, &config,
+        ))),
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(windows)]
+            Self::Pipe(name) => write!(f, "pipe:{}", name),
+            Self::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoints() {
+        assert_eq!(
+            Endpoint::parse("192.168.1.5:37842").unwrap(),
+            Endpoint::Tcp("192.168.1.5:37842".to_string())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            Endpoint::parse("unix:/tmp/envmesh.sock").unwrap(),
+            Endpoint::Unix(PathBuf::from("/tmp/envmesh.sock"))
+        );
+        #[cfg(unix)]
+        assert!(Endpoint::parse(r"pipe:\\.\pipe\envmesh").is_err());
+        #[cfg(windows)]
+        assert_eq!(
+            Endpoint::parse(r"pipe:\\.\pipe\envmesh").unwrap(),
+            Endpoint::Pipe(DEFAULT_PIPE_NAME.to_string())
+        );
+        assert!(Endpoint::parse("no-port").is_err());
+        assert!(Endpoint::parse("host:notaport").is_err());
+    }
+
+    #[test]
+    fn test_instances_get_their_own_endpoints() {
+        let default = crate::config::default_data_dir();
+        assert_eq!(tcp_endpoint_for(&default), DEFAULT_TCP_ENDPOINT);
+        assert_eq!(pipe_name_for(&default), DEFAULT_PIPE_NAME);
+
+        let one = default.join("instances").join("acme");
+        let other = default.join("instances").join("globex");
+        assert_eq!(tcp_endpoint_for(&one), tcp_endpoint_for(&one));
+        assert_ne!(tcp_endpoint_for(&one), DEFAULT_TCP_ENDPOINT);
+        assert_ne!(tcp_endpoint_for(&one), tcp_endpoint_for(&other));
+        assert_ne!(pipe_name_for(&one), pipe_name_for(&other));
+    }
+
+    #[test]
+    fn test_only_loopback_listeners_skip_the_token() {
+        assert!(is_loopback(DEFAULT_TCP_ENDPOINT));
+        assert!(is_loopback("localhost:37842"));
+        assert!(is_loopback("[::1]:37842"));
+        assert!(!is_loopback("0.0.0.0:37842"));
+        assert!(!is_loopback("[::]:37842"));
+        assert!(!is_loopback("192.168.1.5:37842"));
+        assert!(!is_loopback("my-host:37842"));
+    }
+}
@@ -0,0 +1,191 @@
+// Key name validation (`[keys] policy`) and shell-safe export formatting
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{dotenv, files, locks, revoke, signing, storage};
+
+/// How strictly variable keys are checked on set, import and sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyPolicy {
+    /// Names must be shell identifiers (`[A-Za-z_][A-Za-z0-9_]*`) and
+    /// namespaces `[A-Za-z0-9_.-]`
+    Strict,
+    /// Reject whitespace, `=`, quotes, `$`, backslashes and control characters
+    #[default]
+    Standard,
+    /// Only reject empty keys and the reserved `@file/` and `@lock/` prefixes
+    Off,
+}
+
+/// Normalize a key for storage and check it against the policy. Keys are
+/// compared exactly; surrounding whitespace is the only thing normalized
+/// away (`set " KEY "` stores `KEY`).
+pub fn prepare(key: &str, policy: KeyPolicy) -> Result<String> {
+    let key = key.trim();
+    validate(key, policy)?;
+    Ok(key.to_string())
+}
+
+fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn has_unsafe_char(text: &str) -> bool {
+    text.chars().any(|c| {
+        c.is_whitespace() || c.is_control() || matches!(c, '=' | '\'' | '"' | '$' | '`' | '\\')
+    })
+}
+
+/// Check a variable key (optionally `namespace/NAME`) against the policy
+pub fn validate(key: &str, policy: KeyPolicy) -> Result<()> {
+    if key.is_empty() {
+        return Err(anyhow!("Key must not be empty"));
+    }
+    if files::file_name(key).is_some() {
+        return Err(anyhow!(
+            "Keys starting with '{}' are reserved for files",
+            files::FILE_KEY_PREFIX
+        ));
+    }
+    if locks::locked_key(key).is_some() {
+        return Err(anyhow!(
+            "Keys starting with '{}' are reserved for locks",
+            locks::LOCK_KEY_PREFIX
+        ));
+    }
+    if revoke::revoked_device(key).is_some() {
+        return Err(anyhow!(
+            "Keys starting with '{}' are reserved for revoked devices",
+            revoke::REVOKED_KEY_PREFIX
+        ));
+    }
+
+    let namespaced = key.contains('/');
+    let (namespace, name) = storage::split_key(key);
+    let problem = match policy {
+        KeyPolicy::Off => None,
+        KeyPolicy::Standard if has_unsafe_char(key) => {
+            Some("must not contain whitespace, '=', quotes, '$', '`' or '\\'")
+        }
+        KeyPolicy::Standard | KeyPolicy::Strict if name.is_empty() || name.contains('/') => {
+            Some("needs exactly one name after an optional 'namespace/'")
+        }
+        KeyPolicy::Strict if namespaced && !is_valid_namespace(namespace) => {
+            Some("namespace may only contain letters, digits, '_', '.' and '-'")
+        }
+        KeyPolicy::Strict if !dotenv::is_valid_key(name) => {
+            Some("name must be letters, digits and '_', not starting with a digit")
+        }
+        KeyPolicy::Standard | KeyPolicy::Strict => None,
+    };
+
+    match problem {
+        Some(problem) => Err(anyhow!("Invalid key '{}': {}", key, problem)),
+        None => Ok(()),
+    }
+}
+
+/// Validate a key that may also be a file (`@file/NAME`), a lock
+/// (`@lock/KEY`) or a revocation (`@revoked/DEVICE_KEY`), as received by
+/// sync
+pub fn validate_any(key: &str, policy: KeyPolicy) -> Result<()> {
+    if let Some(name) = files::file_name(key) {
+        return files::validate_name(name);
+    }
+    if let Some(device) = revoke::revoked_device(key) {
+        return signing::parse_public_key(device).map(|_| ());
+    }
+    validate(locks::locked_key(key).unwrap_or(key), policy)
+}
+
+/// One line assigning `value` to `name` in the given shell, quoted so the
+/// value is taken literally. None if `name` isn't a valid shell identifier.
+pub fn export_line(shell: &str, name: &str, value: &str) -> Option<String> {
+    if !dotenv::is_valid_key(name) {
+        return None;
+    }
+
+    Some(match shell {
+        "powershell" | "pwsh" => format!("$env:{} = '{}'", name, value.replace('\'', "''")),
+        "fish" => format!(
+            "set -gx {} '{}'",
+            name,
+            value.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        // bash, zsh, sh
+        _ => format!("export {}='{}'", name, value.replace('\'', "'\\''")),
+    })
+}
+
+/// One line removing `name` from the environment in the given shell. None
+/// if `name` isn't a valid shell identifier.
+pub fn unset_line(shell: &str, name: &str) -> Option<String> {
+    if !dotenv::is_valid_key(name) {
+        return None;
+    }
+
+    Some(match shell {
+        "powershell" | "pwsh" => format!("Remove-Item Env:{} -ErrorAction SilentlyContinue", name),
+        "fish" => format!("set -e {}", name),
+        // bash, zsh, sh
+        _ => format!("unset {}", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation_levels() {
+        assert!(validate("DB_URL", KeyPolicy::Strict).is_ok());
+        assert!(validate("prod/DB_URL", KeyPolicy::Strict).is_ok());
+        assert!(validate("my-app.v2/DB_URL", KeyPolicy::Strict).is_ok());
+        assert!(validate("db-url", KeyPolicy::Strict).is_err());
+        assert!(validate("db-url", KeyPolicy::Standard).is_ok());
+
+        for bad in ["MY KEY", "A=B", "KEY\n", "$HOME", "a/b/c", "prod/"] {
+            assert!(validate(bad, KeyPolicy::Standard).is_err(), "{:?}", bad);
+        }
+        assert!(validate("MY KEY", KeyPolicy::Off).is_ok());
+        assert!(validate("", KeyPolicy::Off).is_err());
+        assert!(validate("@file/kubeconfig", KeyPolicy::Off).is_err());
+        assert!(validate_any("@file/kubeconfig", KeyPolicy::Strict).is_ok());
+        assert!(validate("@lock/DB_URL", KeyPolicy::Off).is_err());
+        assert!(validate_any("@lock/prod/DB_URL", KeyPolicy::Strict).is_ok());
+        assert!(validate_any("@lock/db url", KeyPolicy::Standard).is_err());
+        let device = crate::signing::DeviceKey::generate().public_key();
+        assert!(validate(&format!("@revoked/{}", device), KeyPolicy::Off).is_err());
+        assert!(validate_any(&format!("@revoked/{}", device), KeyPolicy::Strict).is_ok());
+        assert!(validate_any("@revoked/not-a-key", KeyPolicy::Off).is_err());
+        assert_eq!(
+            prepare("  prod/KEY\t", KeyPolicy::Strict).unwrap(),
+            "prod/KEY"
+        );
+    }
+
+    #[test]
+    fn test_export_quoting() {
+        let value = r#"it's "$HOME" \n `x`"#;
+        assert_eq!(
+            export_line("bash", "MSG", value).unwrap(),
+            r#"export MSG='it'\''s "$HOME" \n `x`'"#
+        );
+        assert_eq!(
+            export_line("fish", "MSG", value).unwrap(),
+            r#"set -gx MSG 'it\'s "$HOME" \\n `x`'"#
+        );
+        assert_eq!(
+            export_line("powershell", "MSG", value).unwrap(),
+            r#"$env:MSG = 'it''s "$HOME" \n `x`'"#
+        );
+        assert_eq!(export_line("bash", "my-key", "v"), None);
+        assert_eq!(unset_line("zsh", "MSG").unwrap(), "unset MSG");
+        assert_eq!(unset_line("fish", "MSG").unwrap(), "set -e MSG");
+        assert_eq!(unset_line("bash", "my-key"), None);
+    }
+}
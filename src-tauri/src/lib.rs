@@ -1,19 +1,99 @@
-// Library exports for CLI and daemon binaries
+// Library exports for the CLI and daemon binaries, and for embedding EnvMesh
+// in other Rust programs (see `embed::EnvMeshClient`; depend on this crate
+// with `default-features = false` to leave out the GUI). `embed`,
+// `daemon_client`, `protocol` and `storage` are the embedding API; the rest
+// is public for the binaries. There is no separate core crate, since storage,
+// sync and the node pull in most of the other modules.
+pub mod access;
+pub mod agent;
+#[cfg(feature = "gui")]
 pub mod api;
+pub mod autostart;
+pub mod backup;
+pub mod beacon;
+pub mod bundle;
+pub mod chunking;
+pub mod ci;
 pub mod cli;
 pub mod client;
+pub mod clock;
+pub mod cloudurls;
 pub mod config;
+pub mod context;
+pub mod cron;
 pub mod crypto;
+pub mod daemon;
+pub mod daemon_client;
+pub mod doctor;
+pub mod dotenv;
+pub mod editor;
 pub mod election;
+pub mod embed;
+pub mod envfiles;
+pub mod events;
+pub mod expiry;
+pub mod fallback;
+pub mod faults;
+pub mod files;
+pub mod gitsync;
 pub mod health;
+pub mod hooks;
+pub mod ipc;
+pub mod keys;
+pub mod links;
+pub mod locks;
+pub mod machineid;
+pub mod manifest;
+pub mod mask;
+pub mod merge;
+pub mod migrations;
+pub mod netif;
 pub mod node;
+pub mod pattern;
+pub mod pool;
+pub mod protocol;
+pub mod quarantine;
+pub mod ratelimit;
+pub mod redact;
+pub mod refs;
+pub mod relay;
+pub mod replay;
+pub mod replication;
+pub mod revoke;
+pub mod rotation;
+pub mod saas;
+pub mod scan;
+pub mod secretcheck;
+pub mod secretgen;
 pub mod server;
+pub mod session;
+pub mod share;
+pub mod shellsession;
+pub mod signing;
+pub mod snapshot;
+pub mod sops;
 pub mod state;
 pub mod storage;
+pub mod sync;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod tfvars;
+pub mod throttle;
+pub mod tls;
+pub mod topics;
+pub mod trash;
+pub mod users;
+pub mod vaults;
+pub mod version;
+pub mod webhooks;
+pub mod wire;
+pub mod wsl;
 
 // Re-export for convenience
 pub use config::Config;
 pub use crypto::Crypto;
+pub use embed::EnvMeshClient;
+pub use events::{EventBus, MeshEvent};
 pub use node::{EnvMeshNode, NodeConfig};
 pub use state::AppState;
 pub use storage::EnvStorage;
@@ -0,0 +1,252 @@
+// Link quality per peer: round-trip time and lost pings, measured with
+// WebSocket pings so flaky connections show up in `envmesh-cli peers`, and
+// how far the peer has confirmed what we sent it
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::node::EnvMeshNode;
+use crate::version::PeerVersion;
+
+/// How often every link is pinged. A ping still unanswered when the next
+/// one goes out counts as lost.
+pub const LINK_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a peer is to us
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerKind {
+    /// The cloud server (or relay) we are a client of
+    Cloud,
+    /// The LAN server we are a client of
+    LanServer,
+    /// A client of our LAN server
+    LanClient,
+}
+
+impl PeerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerKind::Cloud => "cloud",
+            PeerKind::LanServer => "lan-server",
+            PeerKind::LanClient => "lan-client",
+        }
+    }
+}
+
+/// One peer's link as shown by `envmesh-cli peers`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerLink {
+    pub kind: PeerKind,
+    pub address: String,
+    /// Smoothed round-trip time (None until a ping is answered)
+    pub rtt_ms: Option<u64>,
+    pub pings: u64,
+    pub lost: u64,
+    /// When the peer last sent us anything (Unix ms)
+    pub last_received_ms: Option<i64>,
+    /// The last of our frames the peer has confirmed, by answering a ping
+    /// sent after it, and the last we sent, numbered along the link (None
+    /// where frames aren't numbered, e.g. clients of relayed meshes)
+    pub acked_seq: Option<u64>,
+    pub sent_seq: Option<u64>,
+    /// The build the peer named at connect (None: too old to say)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<PeerVersion>,
+    /// The device key a client of ours proved at connect (None for servers
+    /// and anonymous clients)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+}
+
+impl PeerLink {
+    pub fn loss_percent(&self) -> f64 {
+        if self.pings == 0 {
+            return 0.0;
+        }
+        self.lost as f64 * 100.0 / self.pings as f64
+    }
+
+    /// Frames sent but not yet confirmed (Some(0) when in sync)
+    pub fn behind(&self) -> Option<u64> {
+        Some(self.sent_seq?.saturating_sub(self.acked_seq?))
+    }
+}
+
+#[derive(Default)]
+struct LinkState {
+    seq: u64,
+    /// The ping awaiting its pong, and the frames sent before it
+    outstanding: Option<(u64, Instant, u64)>,
+    srtt: Option<Duration>,
+    pings: u64,
+    lost: u64,
+    /// When any pong last arrived, answered or not
+    last_pong: Option<Instant>,
+    last_received: Option<Instant>,
+    sent: u64,
+    acked: u64,
+    version: Option<PeerVersion>,
+    peer_id: Option<String>,
+}
+
+/// Ping bookkeeping for one connection, shared with its reader
+#[derive(Clone, Default)]
+pub struct LinkTracker {
+    state: Arc<Mutex<LinkState>>,
+}
+
+impl LinkTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LinkState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Payload for the next ping; the previous one is lost if still unanswered
+    pub fn next_ping(&self) -> Vec<u8> {
+        let mut state = self.lock();
+        if state.outstanding.is_some() {
+            state.lost += 1;
+        }
+        state.seq += 1;
+        state.pings += 1;
+        state.outstanding = Some((state.seq, Instant::now(), state.sent));
+        state.seq.to_be_bytes().to_vec()
+    }
+
+    /// We sent the peer a frame (other than a ping)
+    pub fn sent(&self) {
+        self.lock().sent += 1;
+    }
+
+    /// The peer sent us a frame
+    pub fn received(&self) {
+        self.lock().last_received = Some(Instant::now());
+    }
+
+    /// Record a pong; ones that don't answer our latest ping are ignored
+    pub fn pong(&self, payload: &[u8]) {
+        let Ok(seq) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+        let mut state = self.lock();
+        state.last_pong = Some(Instant::now());
+        let Some((expected, sent, frames)) = state.outstanding else {
+            return;
+        };
+        if seq != expected {
+            return;
+        }
+        // Smoothed like TCP's SRTT, so one slow pong doesn't swing it
+        let sample = sent.elapsed();
+        state.srtt = Some(match state.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+        state.outstanding = None;
+        // Frames arrive in order, so everything sent before the ping has too
+        state.acked = state.acked.max(frames);
+    }
+
+    /// The peer proved who it is
+    pub fn set_peer_id(&self, id: String) {
+        self.lock().peer_id = Some(id);
+    }
+
+    /// The peer named its build
+    pub fn set_version(&self, version: PeerVersion) {
+        self.lock().version = Some(version);
+    }
+
+    /// Proof the peer was alive then, whichever ping it answered
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.lock().last_pong
+    }
+
+    pub fn snapshot(&self, kind: PeerKind, address: &str) -> PeerLink {
+        let state = self.lock();
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        PeerLink {
+            kind,
+            address: address.to_string(),
+            rtt_ms: state.srtt.map(|srtt| srtt.as_millis() as u64),
+            pings: state.pings,
+            lost: state.lost,
+            last_received_ms: state
+                .last_received
+                .map(|at| now_ms - at.elapsed().as_millis() as i64),
+            acked_seq: Some(state.acked),
+            sent_seq: Some(state.sent),
+            version: state.version.clone(),
+            peer_id: state.peer_id.clone(),
+        }
+    }
+}
+
+/// Ping the node's peers every `LINK_PING_INTERVAL`
+pub async fn run_pings(node: Arc<AsyncMutex<EnvMeshNode>>) {
+    let mut ticker = tokio::time::interval(LINK_PING_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some(pinger) = node.lock().await.ping_peers() else {
+            continue;
+        };
+        if let Err(e) = pinger.ping().await {
+            tracing::debug!("Failed to ping peers: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_tracks_rtt_and_loss() {
+        let link = LinkTracker::new();
+        assert_eq!(link.snapshot(PeerKind::Cloud, "a").rtt_ms, None);
+        assert!(link.last_pong().is_none());
+
+        let first = link.next_ping();
+        link.pong(&[0; 8]);
+        assert!(link.last_pong().is_some());
+        link.pong(&first);
+        let snapshot = link.snapshot(PeerKind::Cloud, "a");
+        assert!(snapshot.rtt_ms.is_some());
+        assert_eq!((snapshot.pings, snapshot.lost), (1, 0));
+
+        // Unanswered until the next ping
+        link.next_ping();
+        link.next_ping();
+        let snapshot = link.snapshot(PeerKind::Cloud, "a");
+        assert_eq!((snapshot.pings, snapshot.lost), (3, 1));
+        assert!((snapshot.loss_percent() - 100.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_pongs_confirm_frames_sent_before_the_ping() {
+        let link = LinkTracker::new();
+        assert_eq!(link.snapshot(PeerKind::LanServer, "a").behind(), Some(0));
+        assert!(link
+            .snapshot(PeerKind::LanServer, "a")
+            .last_received_ms
+            .is_none());
+
+        link.sent();
+        link.sent();
+        let ping = link.next_ping();
+        link.sent();
+        assert_eq!(link.snapshot(PeerKind::LanServer, "a").behind(), Some(3));
+
+        link.received();
+        link.pong(&ping);
+        let snapshot = link.snapshot(PeerKind::LanServer, "a");
+        assert_eq!((snapshot.acked_seq, snapshot.sent_seq), (Some(2), Some(3)));
+        assert_eq!(snapshot.behind(), Some(1));
+        assert!(snapshot.last_received_ms.is_some());
+    }
+}
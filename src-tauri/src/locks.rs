@@ -0,0 +1,28 @@
+// Locked variables (`envmesh-cli lock`): a lock is stored and synced as an
+// `@lock/KEY` entry holding the device key of the machine that set it (as
+// machine IDs don't outlive a daemon run), the way files ride on `@file/`
+// keys. While it is live every machine refuses local writes to KEY, and
+// changes to it not signed by the lock's owner.
+use anyhow::anyhow;
+
+/// Storage keys of locks start with this; like files, they are never listed
+/// as variables
+pub const LOCK_KEY_PREFIX: &str = "@lock/";
+
+pub fn lock_key(key: &str) -> String {
+    format!("{}{}", LOCK_KEY_PREFIX, key)
+}
+
+/// The variable a lock key locks, or None for any other key
+pub fn locked_key(key: &str) -> Option<&str> {
+    key.strip_prefix(LOCK_KEY_PREFIX)
+}
+
+/// The error for writing to a locked key
+pub fn locked_error(key: &str, owner: &str) -> anyhow::Error {
+    anyhow!(
+        "{} is locked by {}; unlock it there, or pass --force-unlock",
+        key,
+        owner
+    )
+}
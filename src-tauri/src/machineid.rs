@@ -0,0 +1,53 @@
+// This machine's ID in the mesh, kept in `machine.id` next to `device.key`.
+// Sequence numbers (`machine_seqs`) and what peers have received of them
+// (`received_seqs`) are counted per machine ID, so it must outlive a restart.
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+/// The file in the data directory holding the machine ID
+pub const MACHINE_ID_FILE: &str = "machine.id";
+
+/// The machine ID stored at `path`, if there is one
+pub fn load(path: &Path) -> Result<Option<String>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+    };
+    let id = uuid::Uuid::parse_str(text.trim())
+        .map_err(|_| anyhow!("{} is not a machine ID", path.display()))?;
+    Ok(Some(id.to_string()))
+}
+
+/// Read the machine ID at `path`, creating a new one first if needed
+pub fn load_or_create(path: &Path) -> Result<String> {
+    if let Some(id) = load(path)? {
+        return Ok(id);
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    std::fs::write(path, format!("{}\n", id))
+        .with_context(|| format!("Writing {}", path.display()))?;
+    tracing::info!("Created machine ID {}", id);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_machine_id_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("envmesh-machineid-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(MACHINE_ID_FILE);
+
+        assert_eq!(load(&path).unwrap(), None);
+        let id = load_or_create(&path).unwrap();
+        assert_eq!(load_or_create(&path).unwrap(), id);
+        assert_eq!(load(&path).unwrap(), Some(id));
+
+        std::fs::write(&path, "not an id").unwrap();
+        assert!(load_or_create(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,113 +2,205 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(dead_code)] // Allow dead code during development
 
+mod access;
+mod agent;
+#[cfg(feature = "gui")]
 mod api;
+mod autostart;
+mod backup;
+mod beacon;
+mod bundle;
+mod chunking;
+mod ci;
 mod cli;
 mod client;
+mod clock;
+mod cloudurls;
 mod config;
+mod context;
+mod cron;
 mod crypto;
+mod daemon;
+mod daemon_client;
+mod doctor;
+mod dotenv;
+mod editor;
 mod election;
+mod embed;
+mod envfiles;
+mod events;
+mod expiry;
+mod fallback;
+mod faults;
+mod files;
+mod gitsync;
+#[cfg(feature = "gui")]
+mod gui;
 mod health;
+mod hooks;
+mod ipc;
+mod keys;
+mod links;
+mod locks;
+mod machineid;
+mod manifest;
+mod mask;
+mod merge;
+mod migrations;
+mod netif;
 mod node;
+mod pattern;
+mod pool;
+mod protocol;
+mod quarantine;
+mod ratelimit;
+mod redact;
+mod refs;
+mod relay;
+mod replay;
+mod replication;
+mod revoke;
+mod rotation;
+mod saas;
+mod scan;
+mod secretcheck;
+mod secretgen;
 mod server;
+mod session;
+mod share;
+mod shellsession;
+mod signing;
+mod snapshot;
+mod sops;
 mod state;
 mod storage;
-
-use state::AppState;
-use tauri::{
-    menu::{Menu, MenuItem},
-    tray::{TrayIconBuilder, TrayIconEvent},
-    Manager,
-};
-
-fn is_wsl() -> bool {
-    std::fs::read_to_string("/proc/version")
-        .map(|s| s.to_lowercase().contains("microsoft") || s.to_lowercase().contains("wsl"))
-        .unwrap_or(false)
-}
+mod sync;
+mod tfvars;
+mod throttle;
+mod tls;
+mod topics;
+mod trash;
+#[cfg(feature = "gui")]
+mod tray;
+mod users;
+mod vaults;
+mod version;
+mod webhooks;
+mod wire;
+mod wsl;
 
 fn main() {
     // Initialize logging
     tracing_subscriber::fmt::init();
 
-    // Check if running in WSL
-    if is_wsl() {
-        eprintln!("⚠️  WSL detected - GUI not supported");
-        eprintln!("EnvMesh requires a display server to run the GUI.");
-        eprintln!("\nOptions:");
-        eprintln!("1. Use WSLg (Windows 11) or X server (VcXsrv, Xming)");
-        eprintln!("2. Run on native Linux/Windows/macOS");
-        eprintln!("3. Wait for CLI-only mode (coming soon)");
-        std::process::exit(1);
-    }
+    let headless_requested = std::env::args().skip(1).any(|arg| arg == "--headless");
+    let data_dir = match envmesh_data_dir(std::env::args().skip(1).collect()) {
+        Ok(data_dir) => data_dir,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
 
-    tauri::Builder::default()
-        .setup(|app| {
-            // Get app data directory
-            let app_data_dir = app
-                .path()
-                .app_data_dir()
-                .expect("Failed to get app data directory");
+    #[cfg(feature = "gui")]
+    if !headless_requested {
+        match gui_unavailable_reason() {
+            None => {
+                gui::run(data_dir);
+                return;
+            }
+            Some(reason) => eprintln!("⚠️  {} - starting in headless mode", reason),
+        }
+    }
 
-            std::fs::create_dir_all(&app_data_dir).expect("Failed to create app data directory");
+    #[cfg(not(feature = "gui"))]
+    let _ = headless_requested;
 
-            let db_path = app_data_dir.join("envmesh.db");
+    run_headless(data_dir);
+}
 
-            tracing::info!("Database path: {}", db_path.display());
+/// The data directory `--data-dir DIR` or `--instance NAME` (either also
+/// as `--flag=VALUE`) ask for, as envmesh-daemon takes them
+fn envmesh_data_dir(args: Vec<String>) -> anyhow::Result<Option<String>> {
+    let value = |flag: &str| {
+        let prefix = format!("{}=", flag);
+        args.iter()
+            .enumerate()
+            .find_map(|(i, arg)| match arg.strip_prefix(&prefix) {
+                Some(value) => Some(value.to_string()),
+                None if arg == flag => args.get(i + 1).cloned(),
+                None => None,
+            })
+    };
+    let (data_dir, instance) = (value("--data-dir"), value("--instance"));
+    if data_dir.is_some() && instance.is_some() {
+        anyhow::bail!("--data-dir and --instance can't be used together");
+    }
+    config::data_dir_flag(data_dir.as_deref(), instance.as_deref())
+}
 
-            // Initialize app state
-            let state = tauri::async_runtime::block_on(async {
-                AppState::new(db_path)
-                    .await
-                    .expect("Failed to initialize app state")
+/// Why the GUI can't start on this machine, if it can't
+#[cfg(feature = "gui")]
+fn gui_unavailable_reason() -> Option<&'static str> {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        let has_display =
+            std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some();
+        if !has_display {
+            return Some(if wsl::is_wsl() {
+                "WSL detected without WSLg or an X server"
+            } else {
+                "No display server"
             });
+        }
+    }
 
-            app.manage(state);
+    None
+}
 
-            // Create system tray menu
-            let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
-            let show = MenuItem::with_id(app, "show", "Show", true, None::<&str>)?;
-            let sync = MenuItem::with_id(app, "sync", "Sync Now", true, None::<&str>)?;
+/// Same behavior as envmesh-daemon: sync in the background and serve
+/// envmesh-cli on the control socket
+fn run_headless(data_dir: Option<String>) {
+    if wsl::is_wsl() {
+        eprintln!(
+            "Tip: to share the Windows daemon instead, set [ipc] endpoint = \"windows-host:37842\""
+        );
+        eprintln!("     in ~/.envmesh/config.toml and use envmesh-cli (see CLI_USAGE.md)");
+    }
 
-            let menu = Menu::with_items(app, &[&show, &sync, &quit])?;
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start async runtime");
+    if let Err(e) = runtime.block_on(daemon::run(daemon::DaemonOptions {
+        data_dir,
+        ..Default::default()
+    })) {
+        eprintln!("❌ Daemon failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let _tray = TrayIconBuilder::new()
-                .menu(&menu)
-                .on_menu_event(|app, event| match event.id().as_ref() {
-                    "quit" => {
-                        app.exit(0);
-                    }
-                    "show" => {
-                        if let Some(window) = app.get_webview_window("main") {
-                            let _ = window.show();
-                            let _ = window.set_focus();
-                        }
-                    }
-                    "sync" => {
-                        tracing::info!("Manual sync triggered");
-                    }
-                    _ => {}
-                })
-                .on_tray_icon_event(|tray, event| {
-                    if let TrayIconEvent::Click { .. } = event {
-                        if let Some(app) = tray.app_handle().get_webview_window("main") {
-                            let _ = app.show();
-                            let _ = app.set_focus();
-                        }
-                    }
-                })
-                .build(app)?;
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
 
-            Ok(())
-        })
-        .invoke_handler(tauri::generate_handler![
-            api::get_env_var,
-            api::set_env_var,
-            api::delete_env_var,
-            api::list_env_vars,
-            api::get_peers,
-            api::trigger_sync
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+    #[test]
+    fn test_headless_data_dir_flags() {
+        assert_eq!(envmesh_data_dir(args(&["--headless"])).unwrap(), None);
+        assert_eq!(
+            envmesh_data_dir(args(&["--headless", "--data-dir", "/srv/envmesh"])).unwrap(),
+            Some("/srv/envmesh".to_string())
+        );
+        assert_eq!(
+            envmesh_data_dir(args(&["--data-dir=/srv/envmesh", "--headless"])).unwrap(),
+            Some("/srv/envmesh".to_string())
+        );
+        assert_eq!(
+            envmesh_data_dir(args(&["--instance=work"])).unwrap(),
+            config::data_dir_flag(None, Some("work")).unwrap()
+        );
+        assert!(envmesh_data_dir(args(&["--data-dir", "/srv", "--instance", "work"])).is_err());
+    }
 }
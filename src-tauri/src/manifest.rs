@@ -0,0 +1,73 @@
+// The names `envmesh-cli export` printed last, kept in the data directory so
+// `export --prune` can unset those that have since left the mesh
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// One manifest per namespace exported under bare names, and one for
+/// exports of everything
+pub struct ExportManifest {
+    path: PathBuf,
+}
+
+impl ExportManifest {
+    pub fn new(data_dir: &Path, namespace: Option<&str>) -> Self {
+        let file = match namespace {
+            Some(namespace) => format!("namespace-{}.json", namespace),
+            None => "all.json".to_string(),
+        };
+        Self {
+            path: data_dir.join("exports").join(file),
+        }
+    }
+
+    /// The names last exported (none before the first export)
+    pub fn load(&self) -> Result<BTreeSet<String>> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to read {}", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        }
+    }
+
+    pub fn save(&self, names: &BTreeSet<String>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec(names)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Names exported last time but not this time
+pub fn removed<'a>(
+    previous: &'a BTreeSet<String>,
+    current: &'a BTreeSet<String>,
+) -> impl Iterator<Item = &'a String> {
+    previous.difference(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_tracks_removed_names() {
+        let dir = std::env::temp_dir().join(format!("envmesh-manifest-{}", uuid::Uuid::new_v4()));
+        let manifest = ExportManifest::new(&dir, Some("prod"));
+        assert!(manifest.load().unwrap().is_empty());
+
+        let first: BTreeSet<String> = ["API_KEY", "DB_URL"].map(String::from).into();
+        manifest.save(&first).unwrap();
+        assert_eq!(manifest.load().unwrap(), first);
+        assert!(ExportManifest::new(&dir, None).load().unwrap().is_empty());
+
+        let second: BTreeSet<String> = ["DB_URL", "LOG_LEVEL"].map(String::from).into();
+        assert_eq!(
+            removed(&first, &second).collect::<Vec<_>>(),
+            vec!["API_KEY"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
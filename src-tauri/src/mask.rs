@@ -0,0 +1,44 @@
+// GitHub Actions `::add-mask::` commands, so CI logs show *** instead of
+// mesh values
+
+/// Shorter values aren't masked: hiding every `1` or `dev` ruins the log
+pub const MIN_MASK_LEN: usize = 4;
+
+/// Values too common to be secrets
+const UNMASKED: &[&str] = &["true", "false", "null", "none"];
+
+/// `::add-mask::` lines for `value`, one per line of it since the runner
+/// masks line by line
+pub fn mask_lines(value: &str) -> Vec<String> {
+    value
+        .lines()
+        .filter(|line| {
+            line.trim().len() >= MIN_MASK_LEN
+                && !UNMASKED.contains(&line.trim().to_ascii_lowercase().as_str())
+        })
+        .map(|line| format!("::add-mask::{}", escape(line)))
+        .collect()
+}
+
+/// Workflow command data escaping
+fn escape(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masks_each_line_of_long_values() {
+        assert_eq!(mask_lines("s3cr3t%x"), vec!["::add-mask::s3cr3t%25x"]);
+        assert_eq!(
+            mask_lines("-----BEGIN KEY-----\nMIIB\nab\n"),
+            vec!["::add-mask::-----BEGIN KEY-----", "::add-mask::MIIB"]
+        );
+        assert!(mask_lines("1").is_empty());
+        assert!(mask_lines("True").is_empty());
+    }
+}
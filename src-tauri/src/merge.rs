@@ -0,0 +1,212 @@
+// Content-aware conflict merges: keys tagged `merge:json-merge` or
+// `merge:list-union` combine both sides of a conflict against the last value
+// they shared, instead of last-write-wins keeping only the newer one
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Tags starting with this pick the strategy for their key
+pub const MERGE_TAG_PREFIX: &str = "merge:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Keep the newer side whole (the default)
+    #[default]
+    Lww,
+    /// Merge JSON objects field by field
+    JsonMerge,
+    /// Merge comma-separated lists item by item
+    ListUnion,
+}
+
+impl MergeStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Lww => "lww",
+            Self::JsonMerge => "json-merge",
+            Self::ListUnion => "list-union",
+        }
+    }
+
+    /// The strategy a key's tags ask for (the first `merge:` tag wins)
+    pub fn from_tags(tags: &[String]) -> Self {
+        tags.iter()
+            .find_map(|tag| tag.strip_prefix(MERGE_TAG_PREFIX)?.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Merge `local` and `remote`, both changed since `base` (None when no
+    /// shared value is known, so nothing counts as removed). Where both
+    /// sides changed the same thing, `local` (the last-write-wins winner)
+    /// is kept. None when the values can't be merged this way.
+    pub fn merge(self, base: Option<&str>, local: &str, remote: &str) -> Option<String> {
+        match self {
+            Self::Lww => None,
+            Self::JsonMerge => {
+                let local_json: Value = serde_json::from_str(local).ok()?;
+                let remote_json: Value = serde_json::from_str(remote).ok()?;
+                let base_json = base.and_then(|base| serde_json::from_str(base).ok());
+                let merged = merge_json(base_json.as_ref(), &local_json, &remote_json);
+                // Keep the local formatting when nothing came from the remote
+                if merged == local_json {
+                    Some(local.to_string())
+                } else {
+                    serde_json::to_string(&merged).ok()
+                }
+            }
+            Self::ListUnion => Some(merge_lists(base, local, remote)),
+        }
+    }
+}
+
+impl FromStr for MergeStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lww" => Ok(Self::Lww),
+            "json-merge" => Ok(Self::JsonMerge),
+            "list-union" => Ok(Self::ListUnion),
+            _ => Err(anyhow!(
+                "Unknown merge strategy {:?} (expected lww, json-merge or list-union)",
+                s
+            )),
+        }
+    }
+}
+
+/// Refuse `merge:` tags naming no strategy, which would silently mean lww
+pub fn validate_tags(tags: &[String]) -> Result<()> {
+    for tag in tags {
+        if let Some(strategy) = tag.strip_prefix(MERGE_TAG_PREFIX) {
+            strategy.parse::<MergeStrategy>()?;
+        }
+    }
+    Ok(())
+}
+
+fn merge_json(base: Option<&Value>, local: &Value, remote: &Value) -> Value {
+    if local == remote || base == Some(remote) {
+        return local.clone();
+    }
+    if base == Some(local) {
+        return remote.clone();
+    }
+    let (Value::Object(local), Value::Object(remote)) = (local, remote) else {
+        return local.clone();
+    };
+
+    let base = base.and_then(Value::as_object);
+    let mut merged = Map::new();
+    for field in local
+        .keys()
+        .chain(remote.keys().filter(|f| !local.contains_key(*f)))
+    {
+        let base_value = base.and_then(|base| base.get(field));
+        let value = match (local.get(field), remote.get(field)) {
+            (Some(l), Some(r)) => Some(merge_json(base_value, l, r)),
+            // Removed on one side: gone unless the other side changed it
+            (Some(kept), None) | (None, Some(kept)) => {
+                (base_value != Some(kept)).then(|| kept.clone())
+            }
+            (None, None) => None,
+        };
+        if let Some(value) = value {
+            merged.insert(field.clone(), value);
+        }
+    }
+    Value::Object(merged)
+}
+
+fn list_items(list: &str) -> Vec<&str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Local items in order, then the remote's new ones, less any item either
+/// side removed from the base
+fn merge_lists(base: Option<&str>, local: &str, remote: &str) -> String {
+    let local_items = list_items(local);
+    let remote_items = list_items(remote);
+    let removed: HashSet<&str> = base
+        .map(list_items)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|item| !local_items.contains(item) || !remote_items.contains(item))
+        .collect();
+
+    let mut merged: Vec<&str> = Vec::new();
+    for item in local_items.iter().chain(&remote_items) {
+        if !removed.contains(item) && !merged.contains(item) {
+            merged.push(item);
+        }
+    }
+    let separator = if local.contains(", ") { ", " } else { "," };
+    merged.join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategy_from_tags() {
+        let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+        assert_eq!(
+            MergeStrategy::from_tags(&tags(&["prod", "merge:list-union"])),
+            MergeStrategy::ListUnion
+        );
+        assert_eq!(
+            MergeStrategy::from_tags(&tags(&["prod"])),
+            MergeStrategy::Lww
+        );
+        assert!(validate_tags(&tags(&["prod", "merge:json-merge"])).is_ok());
+        assert!(validate_tags(&tags(&["merge:json"])).is_err());
+    }
+
+    #[test]
+    fn test_json_merge_keeps_both_sides() {
+        let base = r#"{"a":1,"b":{"x":1,"y":1},"gone":true}"#;
+        // Local changed b.x and added c; remote changed b.y, removed gone
+        let local = r#"{"a":1,"b":{"x":2,"y":1},"gone":true,"c":3}"#;
+        let remote = r#"{"a":1,"b":{"x":1,"y":2},"d":4}"#;
+        let merged = MergeStrategy::JsonMerge
+            .merge(Some(base), local, remote)
+            .unwrap();
+        let merged: Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(
+            merged,
+            serde_json::json!({"a":1,"b":{"x":2,"y":2},"c":3,"d":4})
+        );
+
+        // Both changed the same field: the local (newer) side wins
+        let merged = MergeStrategy::JsonMerge
+            .merge(Some(r#"{"a":1}"#), r#"{"a":2}"#, r#"{"a":3}"#)
+            .unwrap();
+        assert_eq!(merged, r#"{"a":2}"#);
+
+        assert!(MergeStrategy::JsonMerge
+            .merge(None, "not json", r#"{"a":1}"#)
+            .is_none());
+        assert!(MergeStrategy::Lww.merge(None, "a", "b").is_none());
+    }
+
+    #[test]
+    fn test_list_union_drops_removed_items() {
+        let merged = MergeStrategy::ListUnion.merge(
+            Some("web, api, worker"),
+            "web, api, worker, cron",
+            "web, worker, mail",
+        );
+        assert_eq!(merged.as_deref(), Some("web, worker, cron, mail"));
+
+        // Without a base nothing counts as removed
+        let merged = MergeStrategy::ListUnion.merge(None, "a,b", "b,c");
+        assert_eq!(merged.as_deref(), Some("a,b,c"));
+    }
+}
@@ -0,0 +1,367 @@
+// Versioned SQLite schema migrations, applied in order on startup
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Never edit or reorder a released migration; append a new one instead.
+/// The early steps use IF NOT EXISTS because databases from before this
+/// framework already have those tables.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create env_vars",
+        sql: "CREATE TABLE IF NOT EXISTS env_vars (
+                  key TEXT PRIMARY KEY,
+                  value TEXT NOT NULL,
+                  timestamp INTEGER NOT NULL,
+                  machine_id TEXT NOT NULL,
+                  deleted INTEGER NOT NULL DEFAULT 0
+              );
+              CREATE INDEX IF NOT EXISTS idx_timestamp ON env_vars(timestamp);",
+    },
+    Migration {
+        version: 2,
+        name: "create env_history",
+        sql: "CREATE TABLE IF NOT EXISTS env_history (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  key TEXT NOT NULL,
+                  value TEXT NOT NULL,
+                  timestamp INTEGER NOT NULL,
+                  machine_id TEXT NOT NULL,
+                  deleted INTEGER NOT NULL DEFAULT 0
+              );
+              CREATE INDEX IF NOT EXISTS idx_history_key ON env_history(key, timestamp);",
+    },
+    Migration {
+        version: 3,
+        name: "create env_tags",
+        sql: "CREATE TABLE IF NOT EXISTS env_tags (
+                  key TEXT NOT NULL,
+                  tag TEXT NOT NULL,
+                  PRIMARY KEY (key, tag)
+              );",
+    },
+    Migration {
+        version: 4,
+        name: "create conflicts",
+        sql: "CREATE TABLE IF NOT EXISTS conflicts (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  key TEXT NOT NULL,
+                  local_value TEXT NOT NULL,
+                  local_timestamp INTEGER NOT NULL,
+                  local_machine TEXT NOT NULL,
+                  remote_value TEXT NOT NULL,
+                  remote_timestamp INTEGER NOT NULL,
+                  remote_machine TEXT NOT NULL,
+                  remote_deleted INTEGER NOT NULL DEFAULT 0,
+                  detected_at INTEGER NOT NULL,
+                  resolved INTEGER NOT NULL DEFAULT 0
+              );",
+    },
+    Migration {
+        version: 5,
+        name: "link renamed history",
+        sql: "ALTER TABLE env_history ADD COLUMN renamed_from TEXT;
+              ALTER TABLE env_history ADD COLUMN renamed_to TEXT;",
+    },
+    Migration {
+        version: 6,
+        name: "record copy sources",
+        sql: "ALTER TABLE env_history ADD COLUMN copied_from TEXT;",
+    },
+    Migration {
+        version: 7,
+        name: "create sync_watermarks",
+        sql: "CREATE TABLE sync_watermarks (
+                  peer TEXT PRIMARY KEY,
+                  history_id INTEGER NOT NULL,
+                  updated_at INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 8,
+        name: "sequence changes",
+        sql: "ALTER TABLE env_vars ADD COLUMN seq INTEGER;
+              ALTER TABLE env_history ADD COLUMN seq INTEGER;
+              CREATE INDEX idx_history_seq ON env_history(machine_id, seq);
+              CREATE TABLE machine_seqs (
+                  machine_id TEXT PRIMARY KEY,
+                  last_seq INTEGER NOT NULL
+              );
+              CREATE TABLE received_seq_floors (
+                  machine_id TEXT PRIMARY KEY,
+                  through INTEGER NOT NULL
+              );
+              CREATE TABLE received_seqs (
+                  machine_id TEXT NOT NULL,
+                  seq INTEGER NOT NULL,
+                  PRIMARY KEY (machine_id, seq)
+              );",
+    },
+    Migration {
+        version: 9,
+        name: "create sync_log",
+        sql: "CREATE TABLE sync_log (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  started_ms INTEGER NOT NULL,
+                  finished_ms INTEGER NOT NULL,
+                  peer TEXT,
+                  full INTEGER NOT NULL DEFAULT 0,
+                  pushed TEXT NOT NULL,
+                  pulled TEXT NOT NULL,
+                  conflicted TEXT NOT NULL,
+                  skipped TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 10,
+        name: "record bundles",
+        sql: "CREATE TABLE bundle_exports (
+                  id TEXT PRIMARY KEY,
+                  history_id INTEGER NOT NULL,
+                  namespaces TEXT NOT NULL,
+                  created_at INTEGER NOT NULL
+              );
+              CREATE TABLE bundle_imports (
+                  id TEXT PRIMARY KEY,
+                  machine_id TEXT NOT NULL,
+                  since TEXT,
+                  imported_at INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 11,
+        name: "create quarantine",
+        sql: "CREATE TABLE quarantine (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  key TEXT NOT NULL,
+                  timestamp INTEGER NOT NULL,
+                  machine_id TEXT NOT NULL,
+                  deleted INTEGER NOT NULL,
+                  change TEXT NOT NULL,
+                  reason TEXT NOT NULL,
+                  received_at INTEGER NOT NULL,
+                  UNIQUE (key, timestamp, machine_id)
+              );
+              CREATE TABLE devices_seen (
+                  device TEXT PRIMARY KEY,
+                  last_seen INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 12,
+        name: "create rotation log",
+        sql: "CREATE TABLE rotation_log (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  key TEXT NOT NULL,
+                  rotated_at INTEGER NOT NULL,
+                  machine_id TEXT NOT NULL,
+                  error TEXT
+              );",
+    },
+    Migration {
+        version: 13,
+        name: "note when machines were heard from",
+        sql: "ALTER TABLE received_seq_floors ADD COLUMN heard_at INTEGER NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 14,
+        name: "create own_machines",
+        sql: "CREATE TABLE own_machines (
+                  machine_id TEXT PRIMARY KEY,
+                  device TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 15,
+        name: "create vault_digests",
+        sql: "CREATE TABLE vault_digests (
+                  vault TEXT NOT NULL,
+                  var TEXT NOT NULL,
+                  digest TEXT NOT NULL,
+                  PRIMARY KEY (vault, var)
+              );",
+    },
+];
+
+/// Highest migration this build knows about
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Version the database is at (0 for a new or pre-migration database)
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    ensure_table(conn)?;
+    Ok(conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+fn ensure_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Bring the schema up to date. Each migration runs in its own transaction,
+/// so a failure leaves the database at the last good version.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    migrate_to(conn, latest_version())
+}
+
+fn migrate_to(conn: &Connection, target: i64) -> Result<()> {
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(anyhow!(
+            "Database schema v{} is newer than this EnvMesh supports (v{}); upgrade EnvMesh",
+            current,
+            latest_version()
+        ));
+    }
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+    {
+        tracing::info!(
+            "Applying schema migration {} ({})",
+            migration.version,
+            migration.name
+        );
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.sql).map_err(|e| {
+            anyhow!(
+                "Schema migration {} ({}) failed: {}",
+                migration.version,
+                migration.name,
+                e
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?)",
+            params![migration.version, migration.name, Utc::now().timestamp()],
+        )?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT name FROM sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    /// Tables, and `table.column` for every column
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut names = Vec::new();
+        for table in tables(conn) {
+            let mut stmt = conn
+                .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+                .unwrap();
+            let columns: Vec<String> = stmt
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            names.extend(columns.iter().map(|c| format!("{}.{}", table, c)));
+            names.push(table);
+        }
+        names
+    }
+
+    #[test]
+    fn test_each_migration_applies_in_order() {
+        let conn = Connection::open_in_memory().unwrap();
+        let expected = [
+            (1, "env_vars"),
+            (2, "env_history"),
+            (3, "env_tags"),
+            (4, "conflicts"),
+            (5, "env_history.renamed_from"),
+            (6, "env_history.copied_from"),
+            (7, "sync_watermarks"),
+            (8, "env_vars.seq"),
+            (9, "sync_log"),
+            (10, "bundle_exports"),
+            (11, "quarantine"),
+            (12, "rotation_log"),
+            (13, "received_seq_floors.heard_at"),
+            (14, "own_machines"),
+            (15, "vault_digests"),
+        ];
+        assert_eq!(expected.len(), MIGRATIONS.len());
+
+        for (version, name) in expected {
+            assert!(!schema(&conn).contains(&name.to_string()));
+            migrate_to(&conn, version).unwrap();
+            assert_eq!(current_version(&conn).unwrap(), version);
+            assert!(schema(&conn).contains(&name.to_string()));
+        }
+
+        // Re-running is a no-op
+        migrate(&conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn test_upgrades_pre_migration_database() {
+        // Tables created ad hoc by older versions, with data in them
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute(
+            "INSERT INTO env_vars (key, value, timestamp, machine_id) VALUES ('K', 'v', 1, 'm')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let value: String = conn
+            .query_row("SELECT value FROM env_vars WHERE key = 'K'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(value, "v");
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, 'future', 0)",
+            params![latest_version() + 1],
+        )
+        .unwrap();
+
+        assert!(migrate(&conn).is_err());
+    }
+}
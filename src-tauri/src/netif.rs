@@ -0,0 +1,199 @@
+// Where the LAN server listens and which addresses it advertises: dual-stack
+// wildcards, or only the addresses of chosen network interfaces
+use anyhow::{anyhow, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+use crate::pattern;
+
+/// `[server] interfaces` / `exclude_interfaces`: interface names, with `*`
+/// and `?` wildcards
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterfaceFilter {
+    /// Only these interfaces (empty = use `[server] listen`)
+    pub include: Vec<String>,
+    /// Never these, e.g. `tun*` for a VPN (wins over include)
+    pub exclude: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn allows(&self, name: &str) -> bool {
+        let included = self.include.is_empty() || pattern::matches_any(&self.include, name);
+        included && !pattern::matches_any(&self.exclude, name)
+    }
+}
+
+/// This machine's addresses by interface, leaving out loopback and IPv6
+/// link-local ones (which need a scope to be dialled)
+fn interface_addrs() -> Vec<(String, IpAddr)> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter(|iface| !(iface.ip().is_ipv6() && iface.is_link_local()))
+            .map(|iface| {
+                let ip = iface.ip();
+                (iface.name, ip)
+            })
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Failed to list network interfaces: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Addresses to bind for `listen` ("::" means every IPv4 and IPv6 address).
+/// Chosen interfaces replace `listen` with their own addresses, as does
+/// excluding interfaces from a wildcard, so excluded ones are never
+/// listened on.
+pub fn bind_addrs(listen: &str, filter: &InterfaceFilter) -> Result<Vec<IpAddr>> {
+    let listen: IpAddr = listen
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid [server] listen address: {:?}", listen))?;
+    if filter.include.is_empty() && (!listen.is_unspecified() || filter.exclude.is_empty()) {
+        return Ok(wildcard_addrs(listen));
+    }
+
+    // "0.0.0.0" keeps to IPv4
+    let ipv4_only = listen == IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let chosen: Vec<IpAddr> = interface_addrs()
+        .into_iter()
+        .filter(|(name, ip)| filter.allows(name) && (ip.is_ipv4() || !ipv4_only))
+        .map(|(_, ip)| ip)
+        .collect();
+    if chosen.is_empty() {
+        return Err(anyhow!(
+            "No addresses on the interfaces chosen by [server] interfaces/exclude_interfaces"
+        ));
+    }
+    Ok(chosen)
+}
+
+/// "::" is dual-stack: an IPv6 wildcard socket plus an IPv4 one
+fn wildcard_addrs(listen: IpAddr) -> Vec<IpAddr> {
+    if listen == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        vec![listen, IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+    } else {
+        vec![listen]
+    }
+}
+
+fn same_family(a: &IpAddr, b: &IpAddr) -> bool {
+    a.is_ipv4() == b.is_ipv4()
+}
+
+/// Addresses clients can reach a server bound to `bound` on, for
+/// announcements: wildcards stand for every allowed interface address of
+/// their family
+pub fn advertised(bound: &[IpAddr], filter: &InterfaceFilter) -> Vec<IpAddr> {
+    let interfaces = interface_addrs();
+    let mut addrs = Vec::new();
+    for ip in bound {
+        let reachable: Vec<IpAddr> = if ip.is_unspecified() {
+            interfaces
+                .iter()
+                .filter(|(name, addr)| filter.allows(name) && same_family(ip, addr))
+                .map(|(_, addr)| *addr)
+                .collect()
+        } else {
+            vec![*ip]
+        };
+        for addr in reachable {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// Listen on every address in `addrs` on `port`; with port 0 the first
+/// listener picks one and the rest share it
+pub fn bind(addrs: &[IpAddr], port: u16) -> Result<Vec<TcpListener>> {
+    let mut port = port;
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for ip in addrs {
+        let addr = SocketAddr::new(*ip, port);
+        let listener = bind_one(addr).map_err(|e| anyhow!("Failed to bind to {}: {}", addr, e))?;
+        port = listener.local_addr()?.port();
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn bind_one(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As tokio's own bind, so a restarted server gets its port straight back
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    // The IPv4 wildcard gets its own socket, whatever the OS default
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_addrs() {
+        let none = InterfaceFilter::default();
+        assert_eq!(
+            bind_addrs("::", &none).unwrap(),
+            vec![
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            ]
+        );
+        assert_eq!(
+            bind_addrs("127.0.0.1", &none).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        );
+        assert!(bind_addrs("localhost", &none).is_err());
+
+        let nothing = InterfaceFilter {
+            include: vec!["no-such-interface*".to_string()],
+            exclude: Vec::new(),
+        };
+        assert!(bind_addrs("0.0.0.0", &nothing).is_err());
+        assert!(bind_addrs("127.0.0.1", &nothing).is_err());
+
+        // Exclusions only narrow wildcards; loopback is never an interface
+        // address, so excluding everything leaves nothing to bind
+        let everything = InterfaceFilter {
+            include: Vec::new(),
+            exclude: vec!["*".to_string()],
+        };
+        assert!(bind_addrs("0.0.0.0", &everything).is_err());
+        assert_eq!(
+            bind_addrs("127.0.0.1", &everything).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listeners_share_a_port() {
+        let addrs = [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        // Not every sandbox has IPv6
+        let Ok(listeners) = bind(&addrs, 0) else {
+            return;
+        };
+        let port = listeners[0].local_addr().unwrap().port();
+        assert_eq!(listeners[1].local_addr().unwrap().port(), port);
+        assert!(tokio::net::TcpStream::connect(("::1", port)).await.is_ok());
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok());
+    }
+}
@@ -1,25 +1,54 @@
 // EnvMeshNode - Unified node that can be client or server
 use anyhow::{anyhow, Result};
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::client::{SyncMessage, WebSocketClient};
-use crate::election::{generate_peer_id, Election};
-use crate::server::EmbeddedServer;
+use crate::access::{PeerAccess, PeerIdentity};
+use crate::beacon::DiscoveryConfig;
+use crate::chunking;
+use crate::client::{
+    ConnectOptions, IncomingHandle, Pinger, SyncMessage, WebSocketClient, WireMessage,
+};
+use crate::cloudurls::CloudServers;
+use crate::election::{generate_peer_id, Announcement, Election, StrategyKind};
+use crate::events::{EventBus, MeshEvent};
+use crate::faults::FaultConfig;
+use crate::links::{PeerKind, PeerLink};
+use crate::netif::{self, InterfaceFilter};
+use crate::relay::RelayConfig;
+use crate::server::{Broadcaster, EmbeddedServer, ServerOptions};
+use crate::session::RESUME_WINDOW;
+use crate::signing::DeviceKey;
+use crate::snapshot;
+use crate::throttle::NetworkConfig;
+use crate::tls::TlsConfig;
+use crate::topics::Topics;
+use crate::users::Users;
+use crate::wire::WireFormat;
 
 const DEFAULT_LAN_PORT: u16 = 8765;
 const CLOUD_CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
 const LAN_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
 
-#[derive(Debug, Clone)]
+/// Sync watermark name for the clients of our LAN server that proved no
+/// device key, tracked as one peer
+const LAN_CLIENTS_PEER: &str = "lan-clients";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum NodeMode {
     CloudClient,
     LanClient { server_addr: String },
     LanServer { port: u16 },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ServerMode {
     /// Automatically decide role based on network (default)
+    #[default]
     Auto,
     /// Prefer being a server (for cloud/VPS machines)
     ServerPreferred,
@@ -27,46 +56,142 @@ pub enum ServerMode {
     ClientOnly,
 }
 
-impl Default for ServerMode {
-    fn default() -> Self {
-        Self::Auto
+impl ServerMode {
+    /// As written in `[server] mode`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerMode::Auto => "auto",
+            ServerMode::ServerPreferred => "server-preferred",
+            ServerMode::ClientOnly => "client-only",
+        }
     }
 }
 
+impl std::str::FromStr for ServerMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ServerMode::Auto),
+            "server-preferred" | "server_preferred" => Ok(ServerMode::ServerPreferred),
+            "client-only" | "client_only" => Ok(ServerMode::ClientOnly),
+            other => Err(anyhow!(
+                "Unknown server mode {:?} (expected auto, server-preferred or client-only)",
+                other
+            )),
+        }
+    }
+}
+
+/// What the GUI shows of the node's connection
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub mode: NodeMode,
+    pub server_mode: ServerMode,
+    /// Clients of our LAN server (0 unless we are one)
+    pub clients: usize,
+    /// Kept on the LAN by `force_failover_to_lan`: the health monitor won't
+    /// fail back to the cloud on its own
+    pub lan_hold: bool,
+    /// e.g. "LAN server (3 clients)"
+    pub summary: String,
+}
+
 pub struct EnvMeshNode {
     mode: NodeMode,
     client: Option<WebSocketClient>,
     server: Option<EmbeddedServer>,
+    /// Held while we are the LAN server
+    announcement: Option<Announcement>,
+    /// The session we had with the server we lost, and when
+    resume: Option<LostSession>,
+    /// Set by a manual failover to the LAN until we next reach the cloud
+    lan_hold: bool,
+    /// Which of `config.cloud_urls` to try first
+    cloud: CloudServers,
     config: NodeConfig,
     peer_id: String,
+    events: EventBus,
+}
+
+struct LostSession {
+    url: String,
+    token: String,
+    lost_at: Instant,
 }
 
 #[derive(Clone)]
 pub struct NodeConfig {
-    pub cloud_url: String,
+    /// Cloud servers, tried in order (see `cloudurls`)
+    pub cloud_urls: Vec<String>,
     pub lan_port: u16,
     pub listen_addr: String,
     pub enable_cloud: bool,
     pub enable_lan: bool,
     pub server_mode: ServerMode,
+    pub election_strategy: StrategyKind,
+    /// Presented to servers; relays hosting several meshes require one
+    pub mesh_token: Option<String>,
+    /// Meshes relayed for other teams while we are the server
+    pub relay: RelayConfig,
+    /// Injected into our connections (`faults` build feature only)
+    pub faults: Option<FaultConfig>,
+    /// Asked of servers we connect to; as a server we accept either
+    pub wire_format: WireFormat,
+    /// Namespaces we sync (empty = all); servers send us only these
+    pub namespaces: Vec<String>,
+    /// Bandwidth limits, as client and as server
+    pub network: NetworkConfig,
+    /// Trust for wss:// servers
+    pub tls: TlsConfig,
+    /// Interfaces the LAN server listens on (empty = `listen_addr`)
+    pub interfaces: InterfaceFilter,
+    /// How LAN servers are found and announced
+    pub discovery: DiscoveryConfig,
+    /// Named in our own announcements
+    pub identity: Option<PeerIdentity>,
+    /// Proves our device key to the servers we join
+    pub signer: Option<Arc<DeviceKey>>,
+    /// Peers we neither accept nor dial
+    pub access: PeerAccess,
+    /// Who may join our LAN server, and what each is sent
+    pub users: Users,
+    /// Revocations to hand revoked machines that connect to our LAN server,
+    /// so they wipe themselves
+    pub wipes: Vec<SyncMessage>,
 }
 
 impl Default for NodeConfig {
     fn default() -> Self {
         Self {
-            cloud_url: "ws://localhost:8080".to_string(),
+            cloud_urls: vec!["ws://localhost:8080".to_string()],
             lan_port: DEFAULT_LAN_PORT,
             listen_addr: "127.0.0.1".to_string(),
             enable_cloud: true,
             enable_lan: true,
             server_mode: ServerMode::default(),
+            election_strategy: StrategyKind::default(),
+            mesh_token: None,
+            relay: RelayConfig::default(),
+            faults: None,
+            wire_format: WireFormat::default(),
+            namespaces: Vec::new(),
+            network: NetworkConfig::default(),
+            tls: TlsConfig::default(),
+            interfaces: InterfaceFilter::default(),
+            discovery: DiscoveryConfig::default(),
+            identity: None,
+            signer: None,
+            access: PeerAccess::default(),
+            users: Users::default(),
+            wipes: Vec::new(),
         }
     }
 }
 
 impl EnvMeshNode {
     /// Create a new node with automatic failover
-    pub async fn new(config: NodeConfig) -> Result<Self> {
+    pub async fn new(config: NodeConfig, events: EventBus) -> Result<Self> {
         let peer_id = generate_peer_id();
         tracing::info!("Initializing EnvMesh node: {}", peer_id);
 
@@ -74,8 +199,13 @@ impl EnvMeshNode {
             mode: NodeMode::CloudClient,
             client: None,
             server: None,
+            announcement: None,
+            resume: None,
+            lan_hold: false,
+            cloud: CloudServers::new(config.cloud_urls.clone()),
             config,
             peer_id,
+            events,
         };
 
         // Try to connect with failover
@@ -84,118 +214,313 @@ impl EnvMeshNode {
         Ok(node)
     }
 
+    /// A node that never connects: in-process simulations move its changes
+    /// themselves (see `testkit`)
+    pub fn offline(config: NodeConfig, events: EventBus) -> Self {
+        Self {
+            mode: NodeMode::CloudClient,
+            client: None,
+            server: None,
+            announcement: None,
+            resume: None,
+            lan_hold: false,
+            cloud: CloudServers::new(config.cloud_urls.clone()),
+            config,
+            peer_id: generate_peer_id(),
+            events,
+        }
+    }
+
     /// Try to connect with automatic failover logic
     pub async fn reconnect_with_failover(&mut self) -> Result<()> {
-        // Step 1: Try cloud server (if enabled)
-        if self.config.enable_cloud {
-            tracing::info!("Attempting to connect to cloud server...");
-            match tokio::time::timeout(
+        // Step 1: Try cloud server (if enabled, and not held on the LAN)
+        if self.config.enable_cloud && !self.lan_hold {
+            match self.connect_cloud().await {
+                Ok(()) => return Ok(()),
+                Err(e) => tracing::warn!("{}", e),
+            }
+        }
+
+        // Steps 2 and 3: LAN server or election (if enabled)
+        if self.config.enable_lan {
+            return self.connect_lan().await;
+        }
+
+        Err(anyhow!(
+            "Failed to connect to any server and LAN mode is disabled"
+        ))
+    }
+
+    /// Connect to the first cloud server that answers, leaving the current
+    /// connection alone if none can be reached
+    async fn connect_cloud(&mut self) -> Result<()> {
+        let urls = self.cloud.order(Instant::now());
+        self.connect_cloud_to(urls).await
+    }
+
+    /// Cloud servers listed ahead of the one we are a client of (empty when
+    /// we are on the first, or not on the cloud)
+    pub fn preferred_cloud_urls(&self) -> Vec<String> {
+        if !matches!(self.mode, NodeMode::CloudClient) {
+            return Vec::new();
+        }
+        self.cloud.preferred().to_vec()
+    }
+
+    /// Move to the first cloud server listed ahead of ours that answers, so
+    /// nodes that failed over to different servers meet again on the same
+    /// one. Stays put if none does.
+    pub async fn return_to_preferred_cloud(&mut self) -> Result<()> {
+        let urls = self.preferred_cloud_urls();
+        if urls.is_empty() {
+            return Ok(());
+        }
+        self.connect_cloud_to(urls).await
+    }
+
+    async fn connect_cloud_to(&mut self, urls: Vec<String>) -> Result<()> {
+        let mut errors = Vec::new();
+        for url in urls {
+            tracing::info!("Attempting to connect to cloud server {}...", url);
+            let client = match tokio::time::timeout(
                 CLOUD_CONNECTION_TIMEOUT,
-                WebSocketClient::connect(&self.config.cloud_url),
+                WebSocketClient::connect_with(&url, &self.connect_options(&url)),
             )
             .await
             {
-                Ok(Ok(client)) => {
-                    tracing::info!("Connected to cloud server");
-                    self.mode = NodeMode::CloudClient;
-                    self.client = Some(client);
-                    self.server = None;
-                    return Ok(());
-                }
+                Ok(Ok(client)) => client,
                 Ok(Err(e)) => {
-                    tracing::warn!("Cloud server connection failed: {}", e);
+                    errors.push(format!("{}: {}", url, e));
+                    self.cloud.failed(&url, Instant::now());
+                    continue;
                 }
                 Err(_) => {
-                    tracing::warn!("Cloud server connection timeout");
+                    errors.push(format!("{}: timeout", url));
+                    self.cloud.failed(&url, Instant::now());
+                    continue;
                 }
-            }
+            };
+
+            tracing::info!("Connected to cloud server {}", url);
+            self.cloud.connected(&url);
+            self.resume = None;
+            self.lan_hold = false;
+            self.resign_server("failing back to cloud server").await;
+            self.events.emit(MeshEvent::PeerConnected { peer: url });
+            self.set_mode(NodeMode::CloudClient);
+            self.client = Some(client);
+            return Ok(());
         }
+        Err(anyhow!(
+            "Cloud server connection failed: {}",
+            errors.join("; ")
+        ))
+    }
 
-        // Step 2: Try to discover LAN server (if enabled)
-        if self.config.enable_lan {
-            tracing::info!("Searching for LAN server...");
-            let election = Election::new(self.peer_id.clone());
+    /// Whether we may use a LAN server: the peer is allowed and, once the
+    /// mesh has users, enrolled (and not expired)
+    fn permits_server(&self, identity: Option<&PeerIdentity>) -> bool {
+        let ids = identity.map(PeerIdentity::ids).unwrap_or_default();
+        let users = &self.config.users;
+        self.config.access.permits(&ids) && (!users.is_enabled() || users.user_of(&ids).is_some())
+    }
 
-            match tokio::time::timeout(LAN_DISCOVERY_TIMEOUT, election.discover_lan_server()).await
-            {
-                Ok(Ok(Some(server_info))) => {
-                    let lan_url = format!("ws://{}:{}", server_info.address, server_info.port);
-                    tracing::info!("Found LAN server at {}", lan_url);
-
-                    match WebSocketClient::connect(&lan_url).await {
-                        Ok(client) => {
-                            tracing::info!("Connected to LAN server");
-                            self.mode = NodeMode::LanClient {
-                                server_addr: lan_url.clone(),
-                            };
-                            self.client = Some(client);
-                            self.server = None;
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            tracing::warn!("Failed to connect to LAN server: {}", e);
-                        }
+    /// Connect to a LAN server, or become one if there is none and
+    /// `server_mode` allows it
+    async fn connect_lan(&mut self) -> Result<()> {
+        tracing::info!("Searching for LAN server...");
+        let election =
+            Election::with_strategy(self.peer_id.clone(), self.config.election_strategy.build())
+                .with_discovery(self.config.discovery)
+                .with_identity(self.config.identity.clone());
+
+        match tokio::time::timeout(LAN_DISCOVERY_TIMEOUT, election.discover_lan_server()).await {
+            Ok(Ok(Some(server_info))) if !self.permits_server(server_info.identity.as_ref()) => {
+                tracing::warn!(
+                    "Not connecting to LAN server at {}: peer is not allowed",
+                    server_info.address
+                );
+            }
+            Ok(Ok(Some(server_info))) => {
+                let lan_url = format!("ws://{}:{}", server_info.address, server_info.port);
+                tracing::info!("Found LAN server at {}", lan_url);
+
+                let options = self.connect_options(&lan_url);
+                match WebSocketClient::connect_with(&lan_url, &options).await {
+                    Ok(client) => {
+                        tracing::info!("Connected to LAN server");
+                        self.resume = None;
+                        self.resign_server("another LAN server took over").await;
+                        self.events.emit(MeshEvent::PeerConnected {
+                            peer: lan_url.clone(),
+                        });
+                        self.set_mode(NodeMode::LanClient {
+                            server_addr: lan_url.clone(),
+                        });
+                        self.client = Some(client);
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to connect to LAN server: {}", e);
                     }
-                }
-                Ok(Ok(None)) => {
-                    tracing::info!("No LAN server found");
-                }
-                Ok(Err(e)) => {
-                    tracing::warn!("LAN server discovery error: {}", e);
-                }
-                Err(_) => {
-                    tracing::debug!("LAN server discovery timeout");
                 }
             }
-
-            // Step 3: Become LAN server (if allowed by server_mode)
-            if self.config.server_mode == ServerMode::ClientOnly {
-                return Err(anyhow!("No server available and server_mode is ClientOnly"));
+            Ok(Ok(None)) => {
+                tracing::info!("No LAN server found");
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("LAN server discovery error: {}", e);
+            }
+            Err(_) => {
+                tracing::debug!("LAN server discovery timeout");
             }
+        }
 
-            tracing::info!("No server available, running election...");
+        // Step 3: Become LAN server (if allowed by server_mode)
+        if self.config.server_mode == ServerMode::ClientOnly {
+            return Err(anyhow!("No server available and server_mode is ClientOnly"));
+        }
 
-            // ServerPreferred mode: Always try to become server
-            let should_become_server = if self.config.server_mode == ServerMode::ServerPreferred {
-                tracing::info!("ServerPreferred mode: becoming server immediately");
-                true
-            } else {
-                // Auto mode: Run election
-                match election.should_become_server().await {
-                    Ok(result) => result,
-                    Err(e) => {
-                        return Err(anyhow!("Election failed: {}", e));
-                    }
+        tracing::info!("No server available, running election...");
+
+        // ServerPreferred mode: Always try to become server
+        let should_become_server = if self.config.server_mode == ServerMode::ServerPreferred {
+            tracing::info!("ServerPreferred mode: becoming server immediately");
+            true
+        } else {
+            // Auto mode: Run election
+            match election.should_become_server().await {
+                Ok(result) => result,
+                Err(e) => {
+                    return Err(anyhow!("Election failed: {}", e));
                 }
+            }
+        };
+
+        if should_become_server {
+            tracing::info!("Elected as LAN server");
+            let listen = netif::bind_addrs(&self.config.listen_addr, &self.config.interfaces)?;
+            let options = ServerOptions {
+                relay: self.config.relay.clone(),
+                faults: self.config.faults.clone(),
+                network: self.config.network,
+                listen: listen.clone(),
+                access: self.config.access.clone(),
+                users: self.config.users.clone(),
+                wipes: self.config.wipes.clone(),
             };
+            let server =
+                EmbeddedServer::start_with(self.config.lan_port, self.events.clone(), options)
+                    .await?;
+            let port = server.port();
 
-            if should_become_server {
-                tracing::info!("Elected as LAN server");
-                let bind_addr = format!("{}:{}", self.config.listen_addr, self.config.lan_port);
-                let server = EmbeddedServer::start(self.config.lan_port).await?;
-                let port = server.port();
-
-                // Announce via mDNS
-                election.announce_as_server(port).await?;
-
-                self.mode = NodeMode::LanServer { port };
-                self.server = Some(server);
-                self.client = None;
-
-                tracing::info!("Now running as LAN server on {} (port {})", bind_addr, port);
-                return Ok(());
-            } else {
-                tracing::info!("Lost election, another node is the server");
-                // Wait a bit and retry discovery
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                // Use Box::pin to handle recursive call
-                return Box::pin(self.reconnect_with_failover()).await;
-            }
+            // Announce via mDNS
+            let advertised = netif::advertised(&listen, &self.config.interfaces);
+            let announcement = election.announce_as_server(port, &advertised).await?;
+
+            self.set_mode(NodeMode::LanServer { port });
+            self.server = Some(server);
+            self.announcement = Some(announcement);
+            self.client = None;
+
+            tracing::info!("Now running as LAN server on port {}", port);
+            Ok(())
+        } else {
+            tracing::info!("Lost election, another node is the server");
+            // Wait a bit and retry discovery
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            // Use Box::pin to handle recursive call
+            Box::pin(self.connect_lan()).await
         }
+    }
 
-        Err(anyhow!(
-            "Failed to connect to any server and LAN mode is disabled"
-        ))
+    /// Options for connecting to `url`, resuming our session there if we
+    /// lost it recently enough
+    fn connect_options(&self, url: &str) -> ConnectOptions {
+        let resume = self
+            .resume
+            .as_ref()
+            .filter(|lost| lost.url == url && lost.lost_at.elapsed() < RESUME_WINDOW)
+            .map(|lost| lost.token.clone());
+        ConnectOptions {
+            token: self.config.mesh_token.clone(),
+            wire_format: self.config.wire_format,
+            topics: Topics::new(&self.config.namespaces),
+            network: self.config.network,
+            tls: self.config.tls.clone(),
+            faults: self.config.faults.clone(),
+            signer: self.config.signer.clone(),
+            resume,
+        }
+    }
+
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    /// The cloud server we last connected to, else the first configured
+    pub fn cloud_url(&self) -> &str {
+        self.cloud.current()
+    }
+
+    pub fn peer_access(&self) -> &PeerAccess {
+        &self.config.access
+    }
+
+    /// Replace the peer allow and block lists, dropping clients of our LAN
+    /// server that they now refuse; returns how many were dropped
+    pub fn set_peer_access(&mut self, access: PeerAccess) -> usize {
+        self.config.access = access.clone();
+        match &self.server {
+            Some(server) => server.set_peer_access(access),
+            None => 0,
+        }
+    }
+
+    /// Hold a (sealed) revocation for the machine it wipes, replacing any
+    /// held for it before
+    pub fn hold_wipe(&mut self, msg: SyncMessage) {
+        self.config.wipes.retain(|held| held.key != msg.key);
+        self.config.wipes.push(msg.clone());
+        if let Some(server) = &self.server {
+            server.hold_wipe(msg);
+        }
+    }
+
+    /// Drop clients of our LAN server whose enrollment expired; returns how
+    /// many were dropped
+    pub fn drop_expired_users(&self) -> usize {
+        match &self.server {
+            Some(server) => server.drop_expired_users(),
+            None => 0,
+        }
+    }
+
+    /// Replace the mesh's users, dropping clients of our LAN server whose
+    /// user changed (they reconnect under the new role); returns how many
+    /// were dropped
+    pub fn set_users(&mut self, users: Users) -> usize {
+        self.config.users = users.clone();
+        match &self.server {
+            Some(server) => server.set_users(users),
+            None => 0,
+        }
+    }
+
+    fn set_mode(&mut self, mode: NodeMode) {
+        self.mode = mode.clone();
+        self.events.emit(MeshEvent::ModeChanged { mode });
+    }
+
+    /// Step down as LAN server (if running one), telling connected clients
+    /// to re-run failover instead of silently dropping them
+    async fn resign_server(&mut self, reason: &str) {
+        self.announcement = None;
+        if let Some(server) = self.server.take() {
+            if let Err(e) = server.shutdown(reason).await {
+                tracing::warn!("Failed to notify clients of server shutdown: {}", e);
+            }
+        }
     }
 
     /// Send an update to peers (broadcast if server, send if client)
@@ -215,14 +540,189 @@ impl EnvMeshNode {
         Ok(())
     }
 
-    /// Receive updates from the network
-    pub async fn receive_update(&mut self) -> Result<Option<SyncMessage>> {
+    /// Our LAN server's broadcaster, when updates go out through one
+    /// rather than to a server we are a client of. Broadcasting through it
+    /// lets the caller release the node first.
+    pub fn broadcaster(&self) -> Option<Broadcaster> {
+        match (&self.client, &self.server) {
+            (None, Some(server)) => Some(server.broadcaster()),
+            _ => None,
+        }
+    }
+
+    /// Answer a `SeqRequest`: to the connection that asked, when the relay
+    /// named it (`asker`), or else to everyone
+    pub async fn send_reply(&mut self, asker: Option<u64>, msg: &SyncMessage) -> Result<()> {
+        match (&mut self.client, asker) {
+            (Some(client), Some(to)) => client.reply(to, msg.clone()).await,
+            _ => self.send_update(msg).await,
+        }
+    }
+
+    /// Send changes to a single client of our LAN server; returns false if
+    /// we aren't serving or `peer` isn't one of our clients
+    pub async fn send_to_peer(&self, peer: &str, msgs: &[SyncMessage]) -> Result<bool> {
+        match &self.server {
+            Some(server) => server.send_to(peer, msgs).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Send a snapshot of `msgs` to one of our LAN server's clients (as the
+    /// answer to its resync naming `resync`, if any); false if we are not
+    /// serving it
+    pub async fn send_snapshot_to_peer(
+        &self,
+        peer: &str,
+        machine_id: &str,
+        through: BTreeMap<String, u64>,
+        msgs: &[SyncMessage],
+        resync: Option<&str>,
+    ) -> Result<bool> {
+        match &self.server {
+            Some(server) => {
+                server
+                    .send_snapshot(peer, machine_id, through, msgs, resync)
+                    .await
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Answer the resync naming `resync` that the relay stamped with asker
+    /// `to`: a snapshot of `msgs` for that connection alone
+    pub async fn send_snapshot_reply(
+        &mut self,
+        to: u64,
+        machine_id: &str,
+        through: BTreeMap<String, u64>,
+        msgs: &[SyncMessage],
+        resync: &str,
+    ) -> Result<()> {
+        let Some(client) = &mut self.client else {
+            return Err(anyhow!("not connected to a server"));
+        };
+        let data = snapshot::encode(msgs)?;
+        let frames = chunking::split_snapshot(machine_id, through, &data, Some(resync));
+        client.reply_frames(to, frames).await
+    }
+
+    /// Ask the peers behind our server (or only the machine `from`) to send
+    /// us their full state (a LAN server sends it to new clients unasked)
+    pub async fn request_state(&mut self, machine_id: &str, from: Option<&str>) -> Result<()> {
         if let Some(client) = &mut self.client {
-            client.receive().await
-        } else {
-            // Server mode doesn't receive from network, only broadcasts
-            Ok(None)
+            client
+                .send_frame(&WireMessage::SyncRequest {
+                    machine_id: machine_id.to_string(),
+                    from: from.map(str::to_string),
+                    asker: None,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Ask the peers behind our server for the changes `machine_id` numbered
+    /// `seqs`, which never reached us
+    pub async fn request_seqs(&mut self, machine_id: &str, seqs: Vec<u64>) -> Result<()> {
+        if let Some(client) = &mut self.client {
+            client
+                .send_frame(&WireMessage::SeqRequest {
+                    machine_id: machine_id.to_string(),
+                    seqs,
+                    asker: None,
+                })
+                .await?;
         }
+        Ok(())
+    }
+
+    /// Incoming messages from the server we're connected to (None in server
+    /// mode, which only broadcasts)
+    pub fn incoming(&self) -> Option<IncomingHandle> {
+        self.client.as_ref().map(|client| client.incoming())
+    }
+
+    /// The server went away (or announced its shutdown): drop the connection
+    /// and re-run failover
+    pub async fn handle_server_lost(&mut self) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            self.resume = client.session().map(|token| LostSession {
+                url: client.server_url().to_string(),
+                token: token.to_string(),
+                lost_at: Instant::now(),
+            });
+            self.events.emit(MeshEvent::PeerLost {
+                peer: client.server_url().to_string(),
+            });
+        }
+        self.reconnect_with_failover().await
+    }
+
+    /// Swap in new connection settings and reconnect from scratch
+    pub async fn update_config(&mut self, config: NodeConfig) -> Result<()> {
+        self.cloud = CloudServers::new(config.cloud_urls.clone());
+        self.config = config;
+        self.lan_hold = false;
+        self.client = None;
+        self.resign_server("configuration changed").await;
+        self.reconnect_with_failover().await
+    }
+
+    /// Leave the cloud for the LAN now rather than when the health monitor
+    /// gives up on it, and stay there until `force_reconnect_cloud`. Keeps
+    /// the cloud connection if no LAN server can be found or become one.
+    pub async fn force_failover_to_lan(&mut self) -> Result<()> {
+        if !self.config.enable_lan {
+            return Err(anyhow!("LAN mode is disabled"));
+        }
+        if matches!(self.mode, NodeMode::CloudClient) {
+            self.connect_lan().await?;
+        }
+        self.lan_hold = true;
+        Ok(())
+    }
+
+    /// Fail back to the cloud now; if it still can't be reached we stay on
+    /// the LAN as before, held there if we were (`connect_cloud` lifts the
+    /// hold only once connected)
+    pub async fn force_reconnect_cloud(&mut self) -> Result<()> {
+        if !self.config.enable_cloud {
+            return Err(anyhow!("Cloud server is disabled"));
+        }
+        self.connect_cloud().await
+    }
+
+    /// Change `server_mode` in place. Only a LAN server switched to
+    /// client-only acts on it at once (resigning and reconnecting); other
+    /// changes apply from the next failover. If there is nothing to connect
+    /// to as a client, the previous mode is restored and we reconnect in it.
+    pub async fn set_server_mode(&mut self, mode: ServerMode) -> Result<()> {
+        let previous = std::mem::replace(&mut self.config.server_mode, mode);
+        if self.config.server_mode != ServerMode::ClientOnly || self.server.is_none() {
+            return Ok(());
+        }
+        self.resign_server("switched to client-only mode").await;
+        let Err(e) = self.reconnect_with_failover().await else {
+            return Ok(());
+        };
+        tracing::warn!(
+            "No server to join in client-only mode ({}), staying {}",
+            e,
+            previous.as_str()
+        );
+        self.config.server_mode = previous;
+        self.reconnect_with_failover().await?;
+        Err(anyhow!(
+            "Kept {} mode: no server to join as a client ({})",
+            self.config.server_mode.as_str(),
+            e
+        ))
+    }
+
+    /// Whether a manual failover keeps us on the LAN
+    pub fn lan_hold(&self) -> bool {
+        self.lan_hold
     }
 
     /// Get current node mode
@@ -230,33 +730,107 @@ impl EnvMeshNode {
         self.mode.clone()
     }
 
+    /// Clients of our LAN server (0 unless we are one)
+    fn client_count(&self) -> usize {
+        self.server
+            .as_ref()
+            .map_or(0, |server| server.links().len())
+    }
+
+    /// Mode and role for the GUI
+    pub fn connection_status(&self) -> ConnectionStatus {
+        let clients = self.client_count();
+        let summary = match &self.mode {
+            NodeMode::CloudClient => "Cloud client".to_string(),
+            NodeMode::LanClient { .. } => "LAN client".to_string(),
+            NodeMode::LanServer { .. } if clients == 1 => "LAN server (1 client)".to_string(),
+            NodeMode::LanServer { .. } => format!("LAN server ({} clients)", clients),
+        };
+        ConnectionStatus {
+            mode: self.mode.clone(),
+            server_mode: self.config.server_mode.clone(),
+            clients,
+            lan_hold: self.lan_hold,
+            summary,
+        }
+    }
+
     /// Get connection info for display
     pub fn connection_info(&self) -> String {
         match &self.mode {
-            NodeMode::CloudClient => format!("Connected to cloud: {}", self.config.cloud_url),
+            NodeMode::CloudClient => format!("Connected to cloud: {}", self.cloud.current()),
             NodeMode::LanClient { server_addr } => {
                 format!("Connected to LAN server: {}", server_addr)
             }
-            NodeMode::LanServer { port } => {
-                let active = self.server.as_ref().map(|_| 0).unwrap_or(0);
-                format!(
-                    "Running as LAN server on port {} ({} clients)",
-                    port, active
-                )
-            }
+            NodeMode::LanServer { port } => format!(
+                "Running as LAN server on port {} ({} clients)",
+                port,
+                self.client_count()
+            ),
         }
     }
 
-    /// Get list of connected peers (for UI)
-    pub fn get_peers(&self) -> Vec<(String, String)> {
-        match &self.mode {
-            NodeMode::CloudClient => vec![("cloud".to_string(), self.config.cloud_url.clone())],
-            NodeMode::LanClient { server_addr } => {
-                vec![("lan-server".to_string(), server_addr.clone())]
-            }
-            NodeMode::LanServer { port } => {
-                vec![("self".to_string(), format!("LAN Server on port {}", port))]
-            }
+    /// Ping our LAN clients, for `peer_links()`, and return a pinger for
+    /// the server we are a client of, to ping once the node is unlocked
+    pub fn ping_peers(&self) -> Option<Pinger> {
+        if let Some(server) = &self.server {
+            server.ping_all();
+        }
+        self.client.as_ref().map(WebSocketClient::pinger)
+    }
+
+    /// A pinger for the cloud server, for the health monitor (None unless
+    /// we are its client)
+    pub fn cloud_pinger(&self) -> Option<Pinger> {
+        if !matches!(self.mode, NodeMode::CloudClient) {
+            return None;
+        }
+        self.client.as_ref().map(WebSocketClient::pinger)
+    }
+
+    /// Connected peers with their link quality and how far they are in sync
+    pub fn peer_links(&self) -> Vec<PeerLink> {
+        if let Some(server) = &self.server {
+            return server.links();
+        }
+        self.client_link().into_iter().collect()
+    }
+
+    fn client_link(&self) -> Option<PeerLink> {
+        let client = self.client.as_ref()?;
+        Some(match &self.mode {
+            NodeMode::LanClient { .. } => client.link(PeerKind::LanServer),
+            _ => client.link(PeerKind::Cloud),
+        })
+    }
+
+    /// The links our pushes go out on, by sync watermark name: the server we
+    /// are connected to, or each client of our LAN server by the device key
+    /// it proved (those that proved none share `LAN_CLIENTS_PEER`)
+    pub fn sync_links(&self) -> Vec<(String, PeerLink)> {
+        if let (Some(client), Some(link)) = (&self.client, self.client_link()) {
+            return vec![(client.server_url().to_string(), link)];
+        }
+        let Some(server) = &self.server else {
+            return Vec::new();
+        };
+        server
+            .links()
+            .into_iter()
+            .map(|link| {
+                let peer = link.peer_id.clone();
+                (peer.unwrap_or_else(|| LAN_CLIENTS_PEER.to_string()), link)
+            })
+            .collect()
+    }
+
+    /// Who our pushes reach, for sync reports: the server we are connected
+    /// to, or our LAN clients (None when neither)
+    pub fn sync_peer(&self) -> Option<String> {
+        match (&self.client, &self.server) {
+            (Some(client), _) => Some(client.server_url().to_string()),
+            (None, Some(_)) => Some(LAN_CLIENTS_PEER.to_string()),
+            (None, None) => None,
         }
     }
 }
@@ -272,4 +846,107 @@ mod tests {
         assert!(config.enable_lan);
         assert_eq!(config.lan_port, DEFAULT_LAN_PORT);
     }
+
+    #[test]
+    fn test_server_mode_from_str() {
+        assert_eq!("auto".parse::<ServerMode>().unwrap(), ServerMode::Auto);
+        assert_eq!(
+            "Server_Preferred".parse::<ServerMode>().unwrap(),
+            ServerMode::ServerPreferred
+        );
+        assert_eq!(
+            "client-only".parse::<ServerMode>().unwrap(),
+            ServerMode::ClientOnly
+        );
+        assert!("server".parse::<ServerMode>().is_err());
+        for mode in [ServerMode::Auto, ServerMode::ClientOnly] {
+            assert_eq!(mode.as_str().parse::<ServerMode>().unwrap(), mode);
+        }
+        assert_eq!(
+            serde_json::to_string(&ServerMode::ServerPreferred).unwrap(),
+            "\"server-preferred\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manual_mode_control() {
+        let config = NodeConfig {
+            enable_cloud: false,
+            lan_port: 0,
+            server_mode: ServerMode::ServerPreferred,
+            ..Default::default()
+        };
+        let mut node = EnvMeshNode::new(config, EventBus::new()).await.unwrap();
+        let status = node.connection_status();
+        assert!(matches!(status.mode, NodeMode::LanServer { .. }));
+        assert_eq!(status.clients, 0);
+        assert_eq!(status.summary, "LAN server (0 clients)");
+
+        // Already on the LAN: only the hold changes
+        node.force_failover_to_lan().await.unwrap();
+        assert!(node.connection_status().lan_hold);
+        assert!(node.force_reconnect_cloud().await.is_err());
+        assert!(matches!(node.current_mode(), NodeMode::LanServer { .. }));
+
+        // A client-only node can't stay the server, and there is no other:
+        // it goes back to serving as before
+        node.set_server_mode(ServerMode::ClientOnly)
+            .await
+            .unwrap_err();
+        assert!(node.server.is_some());
+        let status = node.connection_status();
+        assert!(matches!(status.mode, NodeMode::LanServer { .. }));
+        assert_eq!(status.server_mode, ServerMode::ServerPreferred);
+    }
+
+    #[tokio::test]
+    async fn test_failed_cloud_reconnect_keeps_the_lan_hold() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NodeConfig {
+            cloud_urls: vec![format!("ws://{}", closed.local_addr().unwrap())],
+            lan_port: 0,
+            server_mode: ServerMode::ServerPreferred,
+            ..Default::default()
+        };
+        drop(closed);
+        let mut node = EnvMeshNode::new(config, EventBus::new()).await.unwrap();
+        node.force_failover_to_lan().await.unwrap();
+
+        assert!(node.force_reconnect_cloud().await.is_err());
+        assert!(node.lan_hold());
+        assert!(matches!(node.current_mode(), NodeMode::LanServer { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_cloud_failover_list() {
+        let events = EventBus::new();
+        let server = EmbeddedServer::start(0, events.clone()).await.unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let down = format!("ws://127.0.0.1:{}", closed.local_addr().unwrap().port());
+        drop(closed);
+        let up = format!("ws://127.0.0.1:{}", server.port());
+
+        let config = NodeConfig {
+            cloud_urls: vec![down.clone(), up.clone()],
+            enable_lan: false,
+            ..Default::default()
+        };
+        let mut node = EnvMeshNode::new(config, events).await.unwrap();
+        assert!(matches!(node.current_mode(), NodeMode::CloudClient));
+        assert_eq!(node.cloud_url(), up);
+        // The server that failed is tried last for a while
+        assert_eq!(node.cloud.order(Instant::now()), [up.clone(), down.clone()]);
+        assert_eq!(node.preferred_cloud_urls(), std::slice::from_ref(&down));
+
+        // Still down: we stay where we are
+        assert!(node.return_to_preferred_cloud().await.is_err());
+        assert_eq!(node.cloud_url(), up);
+        // Back up: we move to it
+        let port = down.rsplit(':').next().unwrap().parse().unwrap();
+        let _primary = EmbeddedServer::start(port, EventBus::new()).await.unwrap();
+        node.return_to_preferred_cloud().await.unwrap();
+        assert!(matches!(node.current_mode(), NodeMode::CloudClient));
+        assert_eq!(node.cloud_url(), down);
+        assert!(node.preferred_cloud_urls().is_empty());
+    }
 }
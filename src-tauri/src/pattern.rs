@@ -0,0 +1,67 @@
+// Wildcard key patterns (`*` matches any run of characters, `?` exactly one)
+
+/// Match a key against a wildcard pattern, e.g. `AWS_*` or `DB_?_HOST`
+pub fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+
+    let (mut p, mut k) = (0, 0);
+    // Position of the last `*` and the key index it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, star_k)) => {
+                    p = star + 1;
+                    k = star_k + 1;
+                    backtrack = Some((star, star_k + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// True if the key matches any of the patterns
+pub fn matches_any<S: AsRef<str>>(patterns: &[S], key: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| matches(pattern.as_ref(), key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("AWS_*", "AWS_SECRET_KEY"));
+        assert!(matches("*_KEY", "AWS_SECRET_KEY"));
+        assert!(matches("*SECRET*", "AWS_SECRET_KEY"));
+        assert!(matches("DB_?", "DB_1"));
+        assert!(matches("EXACT", "EXACT"));
+        assert!(matches("*", ""));
+
+        assert!(!matches("AWS_*", "GCP_KEY"));
+        assert!(!matches("DB_?", "DB_10"));
+        assert!(!matches("EXACT", "EXACTLY"));
+    }
+
+    #[test]
+    fn test_matches_any() {
+        assert!(matches_any(&["GCP_*", "AWS_*"], "AWS_KEY"));
+        assert!(!matches_any::<&str>(&[], "AWS_KEY"));
+    }
+}
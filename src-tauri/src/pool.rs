@@ -0,0 +1,137 @@
+// Async access to EnvStorage: one writer plus a pool of WAL readers, each
+// call running on a blocking thread so SQLite never stalls the runtime
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+use crate::storage::EnvStorage;
+
+/// Read-only connections kept open next to the writer
+const READERS: usize = 4;
+
+#[derive(Clone)]
+pub struct StoragePool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    writer: Mutex<EnvStorage>,
+    /// Empty for in-memory databases
+    readers: Mutex<Vec<EnvStorage>>,
+    /// One permit per idle reader connection
+    reader_permits: Arc<Semaphore>,
+    writer_only: bool,
+}
+
+impl StoragePool {
+    /// Open (and migrate) the database. In-memory databases are private to
+    /// one connection, so there every call goes through the writer.
+    pub fn open(db_path: PathBuf) -> Result<Self> {
+        let writer = EnvStorage::new(db_path.clone())?;
+
+        let readers = if db_path.as_os_str() == ":memory:" {
+            Vec::new()
+        } else {
+            (0..READERS)
+                .map(|_| EnvStorage::open_reader(&db_path))
+                .collect::<Result<_>>()?
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(writer),
+                reader_permits: Arc::new(Semaphore::new(readers.len())),
+                writer_only: readers.is_empty(),
+                readers: Mutex::new(readers),
+            }),
+        })
+    }
+
+    /// Run a read-only query on a reader connection; reads run concurrently
+    /// with each other and with the writer
+    pub async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&EnvStorage) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.inner.writer_only {
+            return self.write(f).await;
+        }
+
+        let permit = Arc::clone(&self.inner.reader_permits)
+            .acquire_owned()
+            .await?;
+        let inner = Arc::clone(&self.inner);
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let reader = lock(&inner.readers)?
+                .pop()
+                .ok_or_else(|| anyhow!("No idle storage reader"))?;
+            let result = f(&reader);
+            lock(&inner.readers)?.push(reader);
+            result
+        })
+        .await?
+    }
+
+    /// Run a query that may write; writes are serialized on one connection
+    pub async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&EnvStorage) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || f(&*lock(&inner.writer)?)).await?
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| anyhow!("Storage connection lock poisoned"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reads_do_not_wait_for_writer() {
+        let dir = std::env::temp_dir().join(format!("envmesh-pool-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = StoragePool::open(dir.join("envmesh.db")).unwrap();
+        pool.write(|s| s.set("KEY", "value", "machine-a"))
+            .await
+            .unwrap();
+
+        // Park a write on the writer connection until released
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let busy = pool.clone();
+        let writer = tokio::spawn(async move {
+            busy.write(move |_| {
+                started_tx.send(()).unwrap();
+                release_rx.recv().ok();
+                Ok(())
+            })
+            .await
+        });
+        tokio::task::spawn_blocking(move || started_rx.recv().unwrap())
+            .await
+            .unwrap();
+
+        let found = tokio::time::timeout(Duration::from_secs(5), pool.read(|s| s.get("KEY")))
+            .await
+            .expect("read waited for the writer")
+            .unwrap();
+        assert_eq!(found.unwrap().0, "value");
+
+        release_tx.send(()).unwrap();
+        writer.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+// Daemon control protocol shared by the daemon, envmesh-cli and DaemonClient
+use serde::{Deserialize, Serialize};
+
+use crate::agent::AgentStatus;
+use crate::backup::{BackupBase, Snapshot};
+use crate::bundle::Bundle;
+use crate::events::MeshEvent;
+use crate::expiry::Expiry;
+use crate::files::{FileBlob, FileInfo};
+use crate::health::HealthStatus;
+use crate::links::PeerLink;
+use crate::rotation::RotationStatus;
+use crate::scan::ValueDigest;
+use crate::storage::{
+    FsckReport, ImportChange, ListQuery, QuarantinedChange, RotationEntry, StoreStats, SyncReport,
+    TrashedVar,
+};
+use crate::users::{Role, UserConfig};
+
+/// Bump when a change would make old clients and daemons misread each
+/// other (renaming or removing a variant or field). Adding variants, or
+/// fields with `#[serde(default)]`, is compatible and needs no bump.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// A request from a client, one JSON line per command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    Get {
+        key: String,
+        /// Fall back to the daemon's environment and `[fallback] dotenv`
+        /// if the key isn't stored
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        fallback: bool,
+    },
+    Set {
+        key: String,
+        value: String,
+        /// Remove the key's lock first, whoever set it
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        force_unlock: bool,
+    },
+    Delete {
+        key: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        force_unlock: bool,
+    },
+    /// Delete every variable whose key matches a wildcard pattern
+    DeleteMatching {
+        pattern: String,
+    },
+    /// Ask for `[checks]` warnings about a value before setting it
+    CheckValue {
+        key: String,
+        value: String,
+    },
+    /// Move a variable to a new key, keeping its value, tags and history
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Copy variables from one namespace to another: the named `keys`, or
+    /// with `all` every variable (starting with `prefix`, if given)
+    Copy {
+        from: String,
+        to: String,
+        keys: Vec<String>,
+        all: bool,
+        prefix: Option<String>,
+    },
+    List {
+        query: ListQuery,
+    },
+    Tag {
+        key: String,
+        tags: Vec<String>,
+    },
+    Import {
+        vars: Vec<(String, String)>,
+    },
+    PutFile {
+        name: String,
+        blob: FileBlob,
+    },
+    GetFile {
+        name: String,
+    },
+    DeleteFile {
+        name: String,
+    },
+    ListFiles,
+    /// A full snapshot, or with `since` only what was written after that
+    /// backup of ours
+    Backup {
+        since: Option<BackupBase>,
+    },
+    Restore {
+        snapshot: Snapshot,
+    },
+    /// This machine's changes to `namespaces` (empty = all), for carrying to
+    /// a network that can't connect; with `since`, only those made after
+    /// that earlier bundle
+    ExportBundle {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        namespaces: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<String>,
+    },
+    ImportBundle {
+        bundle: Bundle,
+    },
+    /// Connected peers with link quality and sync status
+    Peers,
+    /// Digests of the stored values, for `envmesh-cli scan`
+    ValueDigests,
+    /// Refuse changes to a key (from here, or other machines) until unlocked;
+    /// only the machine that created it may lock it
+    Lock {
+        key: String,
+    },
+    /// Remove a lock; with `force` even one another machine set
+    Unlock {
+        key: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        force: bool,
+    },
+    /// Refuse a device key or machine ID from now on (and in the config)
+    BlockPeer {
+        id: String,
+    },
+    /// Revoke a device key mesh-wide for good; with `wipe` the device
+    /// erases its database when told
+    RevokeDevice {
+        device: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        wipe: bool,
+    },
+    /// Everyone on the mesh (`[[users]]`)
+    Users,
+    /// Add a user, or change one's role and namespaces. Once there are
+    /// users only admins may; the first is enrolled with this machine.
+    SetUser {
+        name: String,
+        role: Role,
+        namespaces: Vec<String>,
+    },
+    /// Enroll a machine for a user by device key (None: this machine), for
+    /// `expires_secs` if given
+    EnrollDevice {
+        name: String,
+        device: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_secs: Option<u64>,
+    },
+    /// Take a machine away from whoever enrolled it
+    UnenrollDevice {
+        device: String,
+    },
+    RemoveUser {
+        name: String,
+    },
+    /// Seal a variable for someone outside the mesh: a link to a copy the
+    /// cloud server hands out once, or with `offline` a code that opens
+    /// until it expires
+    Share {
+        key: String,
+        expires_secs: u64,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        offline: bool,
+    },
+    Status,
+    /// Store counts and who wrote how much in the last hour
+    Stats,
+    /// Check the store for damage and broken invariants; with `repair`,
+    /// fix them, rebuilding from peers what can't be fixed in place
+    Fsck {
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        repair: bool,
+    },
+    /// Push what our peers haven't been sent yet
+    Sync,
+    /// Push every change, whatever peers were sent before
+    FullSync,
+    /// Forget sync progress (with `overwrite_local`, local state too) and
+    /// pull the machine `from`'s whole state
+    Resync {
+        from: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        overwrite_local: bool,
+    },
+    /// Credentials with a known expiry (tag, JWT or certificate), soonest
+    /// first; with `within_days`, only those expiring by then
+    Expiring {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within_days: Option<u64>,
+    },
+    /// `[[rotations]]` policies and when each key is due
+    Rotations,
+    /// The newest `limit` rotation attempts, newest first
+    RotationLog {
+        limit: usize,
+    },
+    /// Rotate a key with a policy now, whether it is due or not
+    Rotate {
+        key: String,
+    },
+    /// Deleted variables whose tombstones are still kept
+    Trash,
+    /// Set a deleted variable back to its last value
+    RestoreTrashed {
+        key: String,
+    },
+    /// Incoming changes held for approval (`[quarantine]`)
+    Quarantine,
+    /// Apply the held changes `ids` (every one if empty)
+    ApproveQuarantined {
+        ids: Vec<i64>,
+    },
+    /// Drop the held changes `ids` (every one if empty)
+    RejectQuarantined {
+        ids: Vec<i64>,
+    },
+    /// Requests waiting for approval, unexpired leases and the newest
+    /// `limit` decisions of the lease agent (`[agent]`)
+    Agent {
+        limit: usize,
+    },
+    /// Approve or refuse the waiting lease request `id`
+    AnswerLease {
+        id: u64,
+        allow: bool,
+    },
+    /// The newest `limit` sync reports, newest first
+    SyncReports {
+        limit: usize,
+    },
+    /// What the health monitor last found, and whether failover is paused
+    HealthStatus,
+    /// Check the cloud every `secs` seconds from now on (until restarted)
+    SetHealthCheckInterval {
+        secs: u64,
+    },
+    /// Keep checking, but stop (or with `paused: false`, resume) failing
+    /// over to the LAN and back
+    PauseFailover {
+        paused: bool,
+    },
+    Shutdown,
+    Subscribe,
+    /// First line on a TCP connection to a daemon holding an IPC token
+    /// (`ENVMESH_IPC_TOKEN`); answered only when rejected
+    Auth {
+        token: String,
+    },
+    /// Ask which protocol version the daemon speaks
+    Version,
+    /// Report what `command` would change, and where, without running it
+    /// (Import, Delete, DeleteMatching, Copy, Restore and Sync)
+    Preview {
+        command: Box<Command>,
+    },
+}
+
+/// The daemon's answer to a command, one JSON line each (`Subscribe` gets a
+/// stream of `Event`s instead)
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Value(Option<String>),
+    Success,
+    Error(String),
+    List(Vec<(String, String)>),
+    Peers(Vec<PeerLink>),
+    Event(MeshEvent),
+    Imported(Vec<ImportChange>),
+    Snapshot(Snapshot),
+    Restored(usize),
+    Bundle(Bundle),
+    BundleImported {
+        applied: usize,
+        total: usize,
+        /// The bundle it continues, if that was never imported here
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gap: Option<String>,
+    },
+    Status(DaemonStatus),
+    Stats {
+        machine_id: String,
+        stats: StoreStats,
+    },
+    Fsck {
+        found: FsckReport,
+        /// What was still wrong after repairing (None without `repair`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remaining: Option<FsckReport>,
+        /// The store was cleared and peers were asked for their state
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        rebuilt: bool,
+    },
+    File(Option<FileBlob>),
+    Files(Vec<FileInfo>),
+    Version {
+        protocol: u32,
+        daemon: String,
+    },
+    /// Keys removed by `DeleteMatching`
+    Deleted(Vec<String>),
+    /// Answer to `CheckValue` (empty: nothing suspicious, or checks are off)
+    Warnings(Vec<String>),
+    Preview(ChangePreview),
+    /// Answer to `ValueDigests`
+    Digests(Vec<ValueDigest>),
+    SyncReports(Vec<SyncReport>),
+    Expiring(Vec<Expiry>),
+    Rotations(Vec<RotationStatus>),
+    RotationLog(Vec<RotationEntry>),
+    Trash(Vec<TrashedVar>),
+    Quarantine(Vec<QuarantinedChange>),
+    /// Answer to `ApproveQuarantined` and `RejectQuarantined`: how many
+    /// held changes were applied or dropped
+    Released(usize),
+    Users(Vec<UserConfig>),
+    Agent(AgentStatus),
+    /// Answer to `Share`: the code or link, and when it stops working
+    Shared {
+        code: String,
+        expires_at: i64,
+    },
+    Health(HealthStatus),
+}
+
+/// Answer to `Preview`: the dry run of a command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangePreview {
+    pub changes: Vec<ImportChange>,
+    /// Connected peers the changes would be sent to (id, address)
+    pub peers: Vec<(String, String)>,
+    /// Changed keys the `[sync]` filter keeps on this machine
+    pub local_only: Vec<String>,
+}
+
+/// Answer to `envmesh-cli status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub machine_id: String,
+    pub connection: String,
+    pub variables: usize,
+    pub conflicts: usize,
+    /// Unix time of the newest scheduled backup (None if none yet)
+    pub last_backup: Option<i64>,
+    /// Unix time of the next scheduled backup (None if not configured)
+    pub next_backup: Option<i64>,
+    /// Incoming changes dropped by the rate limit
+    #[serde(default)]
+    pub rate_limited: u64,
+    /// Machines whose changes are ignored until their ban runs out
+    #[serde(default)]
+    pub banned_peers: Vec<String>,
+    /// Public key this daemon signs changes with
+    #[serde(default)]
+    pub device_key: Option<String>,
+    /// Seconds the LAN server's clock is ahead of ours (None: not measured)
+    #[serde(default)]
+    pub clock_skew: Option<i64>,
+    /// Connected peers running an incompatible or much older build, as
+    /// "address: what is wrong"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub version_warnings: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The wire format is pinned: if one of these fails, older CLIs or
+    /// daemons can no longer talk to this build. Bump PROTOCOL_VERSION
+    /// rather than editing the expected JSON.
+    #[test]
+    fn test_command_wire_format() {
+        let cases = [
+            (
+                Command::Get {
+                    key: "K".to_string(),
+                    fallback: false,
+                },
+                r#"{"Get":{"key":"K"}}"#,
+            ),
+            (
+                Command::Set {
+                    key: "K".to_string(),
+                    value: "v".to_string(),
+                    force_unlock: false,
+                },
+                r#"{"Set":{"key":"K","value":"v"}}"#,
+            ),
+            (
+                Command::Import {
+                    vars: vec![("K".to_string(), "v".to_string())],
+                },
+                r#"{"Import":{"vars":[["K","v"]]}}"#,
+            ),
+            (Command::ListFiles, r#""ListFiles""#),
+            (
+                Command::Preview {
+                    command: Box::new(Command::Sync),
+                },
+                r#"{"Preview":{"command":"Sync"}}"#,
+            ),
+            (Command::Subscribe, r#""Subscribe""#),
+        ];
+
+        for (command, json) in cases {
+            assert_eq!(serde_json::to_string(&command).unwrap(), json);
+            let parsed: Command = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+
+        // Older clients leave out optional query fields
+        let list: Command = serde_json::from_str(r#"{"List":{"query":{}}}"#).unwrap();
+        assert!(matches!(list, Command::List { query } if query.namespace.is_none()));
+    }
+
+    #[test]
+    fn test_response_wire_format() {
+        let cases = [
+            (Response::Value(Some("v".to_string())), r#"{"Value":"v"}"#),
+            (Response::Value(None), r#"{"Value":null}"#),
+            (Response::Success, r#""Success""#),
+            (Response::Error("bad".to_string()), r#"{"Error":"bad"}"#),
+            (
+                Response::List(vec![("K".to_string(), "v".to_string())]),
+                r#"{"List":[["K","v"]]}"#,
+            ),
+            (
+                Response::Version {
+                    protocol: 1,
+                    daemon: "0.1.0".to_string(),
+                },
+                r#"{"Version":{"protocol":1,"daemon":"0.1.0"}}"#,
+            ),
+        ];
+
+        for (response, json) in cases {
+            assert_eq!(serde_json::to_string(&response).unwrap(), json);
+            let parsed: Response = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
+}
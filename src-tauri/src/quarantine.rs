@@ -0,0 +1,358 @@
+// Quarantine (`[quarantine]`): incoming changes that look risky are held for
+// approval instead of applied. Held are deletes past a burst from one
+// machine, changes to locked keys or keys with a protected tag, and changes
+// signed by a device not heard from in months. `envmesh-cli quarantine`
+// lists them and approves or rejects them. The delete storm guard (one
+// machine deleting a large share of the store at once) is on even without
+// the section.
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::client::SyncMessage;
+use crate::revoke;
+
+fn default_mass_delete() -> usize {
+    20
+}
+
+fn default_mass_delete_secs() -> u64 {
+    60
+}
+
+fn default_delete_storm_percent() -> u64 {
+    25
+}
+
+fn default_delete_storm_min() -> usize {
+    10
+}
+
+fn default_protected_tags() -> Vec<String> {
+    vec!["prod".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_dormant_days() -> u64 {
+    90
+}
+
+/// `[quarantine]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantineConfig {
+    /// Deletes one machine may send within `mass_delete_secs` before the
+    /// rest are held (0 = no limit)
+    #[serde(default = "default_mass_delete")]
+    pub mass_delete: usize,
+
+    /// Window for `mass_delete` and the delete storm guard
+    #[serde(default = "default_mass_delete_secs")]
+    pub mass_delete_secs: u64,
+
+    /// Hold further deletes from a machine once those within
+    /// `mass_delete_secs` reach this share of the store's keys (0 = off)
+    #[serde(default = "default_delete_storm_percent")]
+    pub delete_storm_percent: u64,
+
+    /// Fewer deletes than this are never a storm, however small the store
+    #[serde(default = "default_delete_storm_min")]
+    pub delete_storm_min: usize,
+
+    /// Hold changes to keys carrying any of these tags
+    #[serde(default = "default_protected_tags")]
+    pub protected_tags: Vec<String>,
+
+    /// Hold changes to locked keys, even from the lock's owner
+    #[serde(default = "default_true")]
+    pub locked: bool,
+
+    /// Hold changes signed by a device last heard from longer ago than this
+    /// (0 = never)
+    #[serde(default = "default_dormant_days")]
+    pub dormant_days: u64,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            mass_delete: default_mass_delete(),
+            mass_delete_secs: default_mass_delete_secs(),
+            delete_storm_percent: default_delete_storm_percent(),
+            delete_storm_min: default_delete_storm_min(),
+            protected_tags: default_protected_tags(),
+            locked: default_true(),
+            dormant_days: default_dormant_days(),
+        }
+    }
+}
+
+impl QuarantineConfig {
+    /// Without `[quarantine]`: only the delete storm guard
+    pub fn storm_guard() -> Self {
+        Self {
+            mass_delete: 0,
+            protected_tags: Vec::new(),
+            locked: false,
+            dormant_days: 0,
+            ..Default::default()
+        }
+    }
+}
+
+/// What is known locally about an arriving change's key and signer
+#[derive(Debug, Default)]
+pub struct Facts {
+    pub tags: Vec<String>,
+    /// The key is locked by the change's signer (others' changes to it are
+    /// refused anyway)
+    pub locked: bool,
+    /// When the signing device was last heard from (unix seconds)
+    pub last_seen: Option<i64>,
+    /// Variables currently in the store
+    pub live_keys: usize,
+}
+
+/// Why a change was held
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hold {
+    MassDelete { count: usize, secs: u64 },
+    DeleteStorm { count: usize, percent: u64 },
+    Locked,
+    Protected { tag: String },
+    Dormant { days: i64 },
+}
+
+impl fmt::Display for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MassDelete { count, secs } => {
+                write!(f, "delete #{} from one machine within {}s", count, secs)
+            }
+            Self::DeleteStorm { count, percent } => write!(
+                f,
+                "delete storm: {} deletes from one machine, {}% of the keys",
+                count, percent
+            ),
+            Self::Locked => write!(f, "changes a locked key"),
+            Self::Protected { tag } => write!(f, "changes a key tagged {}", tag),
+            Self::Dormant { days } => write!(f, "signer last heard from {} days ago", days),
+        }
+    }
+}
+
+pub struct Quarantine {
+    config: QuarantineConfig,
+    /// Recent deletes per sending machine (unix seconds)
+    deletes: HashMap<String, VecDeque<i64>>,
+    /// Machines whose deletes are being held as a storm
+    storming: HashSet<String>,
+}
+
+impl Quarantine {
+    pub fn new(config: QuarantineConfig) -> Self {
+        Self {
+            config,
+            deletes: HashMap::new(),
+            storming: HashSet::new(),
+        }
+    }
+
+    /// Whether a `DeleteStorm` hold for `machine_id` is the first of its
+    /// storm, the one worth raising an alert for
+    pub fn storm_began(&mut self, machine_id: &str) -> bool {
+        self.storming.insert(machine_id.to_string())
+    }
+
+    /// Why `msg` should be held, if it should; counts it if it is a delete
+    pub fn check(&mut self, msg: &SyncMessage, facts: &Facts, now: i64) -> Option<Hold> {
+        // Every machine has to hear of a revoked device at once
+        if revoke::revoked_device(&msg.key).is_some() {
+            return None;
+        }
+
+        if msg.deleted {
+            let count = self.count_delete(&msg.machine_id, now);
+            if let Some(storm) = self.storm(&msg.machine_id, count, facts.live_keys) {
+                return Some(storm);
+            }
+
+            if self.config.mass_delete > 0 && count > self.config.mass_delete {
+                return Some(Hold::MassDelete {
+                    count,
+                    secs: self.config.mass_delete_secs,
+                });
+            }
+        }
+
+        if self.config.locked && facts.locked {
+            return Some(Hold::Locked);
+        }
+        if let Some(tag) = facts
+            .tags
+            .iter()
+            .find(|tag| self.config.protected_tags.contains(tag))
+        {
+            return Some(Hold::Protected { tag: tag.clone() });
+        }
+        if self.config.dormant_days > 0 {
+            if let Some(seen) = facts.last_seen {
+                let days = (now - seen) / 86_400;
+                if days >= self.config.dormant_days as i64 {
+                    return Some(Hold::Dormant { days });
+                }
+            }
+        }
+        None
+    }
+
+    /// `check()` for a change arriving in a batch (snapshot or bundle),
+    /// which only the delete storm guard applies to: whether the delete
+    /// `msg` of a key we hold is part of a storm, `live_keys` being left
+    pub fn check_storm(&mut self, msg: &SyncMessage, live_keys: usize, now: i64) -> Option<Hold> {
+        if !msg.deleted || revoke::revoked_device(&msg.key).is_some() {
+            return None;
+        }
+        let count = self.count_delete(&msg.machine_id, now);
+        self.storm(&msg.machine_id, count, live_keys)
+    }
+
+    /// Note a delete from `machine_id`; how many it sent within the window
+    fn count_delete(&mut self, machine_id: &str, now: i64) -> usize {
+        let window = self.config.mass_delete_secs as i64;
+        let recent = self.deletes.entry(machine_id.to_string()).or_default();
+        recent.push_back(now);
+        while recent.front().is_some_and(|at| now - at >= window) {
+            recent.pop_front();
+        }
+        recent.len()
+    }
+
+    /// A `DeleteStorm` hold if `count` recent deletes from `machine_id` make
+    /// one, with `live_keys` variables left
+    fn storm(&mut self, machine_id: &str, count: usize, live_keys: usize) -> Option<Hold> {
+        // Deletes already applied have left the store, so count them in
+        let percent = (count * 100 / (live_keys + count).max(1)) as u64;
+        let storm = self.config.delete_storm_percent > 0
+            && count >= self.config.delete_storm_min
+            && percent >= self.config.delete_storm_percent;
+        if storm || self.storming.contains(machine_id) && count > 1 {
+            return Some(Hold::DeleteStorm { count, percent });
+        }
+        self.storming.remove(machine_id);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(key: &str, deleted: bool) -> SyncMessage {
+        SyncMessage::new(
+            key.to_string(),
+            String::new(),
+            1,
+            "machine-b".to_string(),
+            deleted,
+        )
+    }
+
+    #[test]
+    fn test_risky_changes_are_held() {
+        let mut quarantine = Quarantine::new(QuarantineConfig {
+            mass_delete: 2,
+            ..Default::default()
+        });
+        let plain = Facts::default();
+        let now = 1_800_000_000;
+
+        assert_eq!(quarantine.check(&change("A", true), &plain, now), None);
+        assert_eq!(quarantine.check(&change("B", true), &plain, now), None);
+        assert_eq!(
+            quarantine.check(&change("C", true), &plain, now + 1),
+            Some(Hold::MassDelete { count: 3, secs: 60 })
+        );
+        // The window moves on
+        assert_eq!(quarantine.check(&change("D", true), &plain, now + 61), None);
+
+        let tagged = Facts {
+            tags: vec!["team".to_string(), "prod".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            quarantine.check(&change("prod/DB_URL", false), &tagged, now),
+            Some(Hold::Protected {
+                tag: "prod".to_string()
+            })
+        );
+        let locked = Facts {
+            locked: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            quarantine.check(&change("KEY", false), &locked, now),
+            Some(Hold::Locked)
+        );
+
+        let dormant = Facts {
+            last_seen: Some(now - 100 * 86_400),
+            ..Default::default()
+        };
+        assert_eq!(
+            quarantine.check(&change("KEY", false), &dormant, now),
+            Some(Hold::Dormant { days: 100 })
+        );
+        let recent = Facts {
+            last_seen: Some(now - 86_400),
+            ..Default::default()
+        };
+        assert_eq!(quarantine.check(&change("KEY", false), &recent, now), None);
+    }
+
+    #[test]
+    fn test_delete_storm_holds_until_it_stops() {
+        let mut quarantine = Quarantine::new(QuarantineConfig::storm_guard());
+        let store = Facts {
+            live_keys: 20,
+            ..Default::default()
+        };
+        let now = 1_800_000_000;
+
+        // 9 of 29 keys: short of the minimum
+        for i in 0..9 {
+            assert_eq!(
+                quarantine.check(&change(&i.to_string(), true), &store, now),
+                None
+            );
+        }
+        let held = quarantine.check(&change("9", true), &store, now);
+        assert_eq!(
+            held,
+            Some(Hold::DeleteStorm {
+                count: 10,
+                percent: 33
+            })
+        );
+        assert!(quarantine.storm_began("machine-b"));
+        assert!(!quarantine.storm_began("machine-b"));
+        // Held deletes stay in the store, but the storm goes on
+        assert!(quarantine
+            .check(&change("10", true), &store, now + 1)
+            .is_some());
+        // Other changes from the machine still apply
+        assert_eq!(
+            quarantine.check(&change("11", false), &store, now + 1),
+            None
+        );
+
+        // Once it has stopped for a window, a lone delete goes through
+        assert_eq!(
+            quarantine.check(&change("12", true), &store, now + 61),
+            None
+        );
+        assert!(quarantine.storm_began("machine-b"));
+    }
+}
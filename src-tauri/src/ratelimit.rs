@@ -0,0 +1,217 @@
+// Per-peer token buckets with temporary bans, guarding sync ingestion and
+// LAN server connections against floods
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Limits for one kind of traffic (`[limits.sync_rate]` for incoming changes)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Sustained events per second
+    pub rate: f64,
+    /// Events allowed in one burst above the sustained rate
+    pub burst: u32,
+    /// Peers dropped this many times in a row are banned
+    pub ban_after: u32,
+    /// How long a ban lasts
+    pub ban_secs: u64,
+}
+
+/// Incoming changes, per sending machine. The burst covers a peer seeding
+/// a whole store at once.
+pub const SYNC_RATE_LIMIT: RateLimit = RateLimit {
+    rate: 100.0,
+    burst: 5000,
+    ban_after: 1000,
+    ban_secs: 300,
+};
+
+/// New LAN server connections, per client address
+pub const CONNECT_RATE_LIMIT: RateLimit = RateLimit {
+    rate: 1.0,
+    burst: 20,
+    ban_after: 20,
+    ban_secs: 300,
+};
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        SYNC_RATE_LIMIT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit; this event is dropped
+    Drop,
+    /// Over the limit for long enough that the peer is now banned
+    Ban,
+    /// Still serving a ban
+    Banned,
+}
+
+/// Counters for `envmesh-cli status`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    pub dropped: u64,
+    pub bans: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// Drops since the last allowed event
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, Bucket>,
+    stats: RateLimitStats,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub fn check(&mut self, peer: &K) -> Verdict {
+        self.check_at(peer, Instant::now())
+    }
+
+    fn check_at(&mut self, peer: &K, now: Instant) -> Verdict {
+        let limit = self.limit;
+        // Buckets that have refilled completely carry no state worth keeping
+        if self.buckets.len() > 1024 {
+            self.buckets.retain(|_, bucket| {
+                bucket.banned_until.is_some_and(|until| until > now)
+                    || bucket.tokens + elapsed(bucket.refilled, now) * limit.rate
+                        < limit.burst as f64
+            });
+        }
+
+        let bucket = self.buckets.entry(peer.clone()).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            refilled: now,
+            strikes: 0,
+            banned_until: None,
+        });
+
+        if let Some(until) = bucket.banned_until {
+            if now < until {
+                self.stats.dropped += 1;
+                return Verdict::Banned;
+            }
+            bucket.banned_until = None;
+            bucket.strikes = 0;
+            bucket.tokens = limit.burst as f64;
+            bucket.refilled = now;
+        }
+
+        let refill = elapsed(bucket.refilled, now) * limit.rate;
+        bucket.tokens = (bucket.tokens + refill).min(limit.burst as f64);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.strikes = 0;
+            return Verdict::Allow;
+        }
+
+        self.stats.dropped += 1;
+        bucket.strikes += 1;
+        if bucket.strikes >= limit.ban_after {
+            bucket.banned_until = Some(now + Duration::from_secs(limit.ban_secs));
+            self.stats.bans += 1;
+            return Verdict::Ban;
+        }
+        Verdict::Drop
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+
+    /// Peers currently serving a ban
+    pub fn banned(&self) -> Vec<K> {
+        let now = Instant::now();
+        self.buckets
+            .iter()
+            .filter(|(_, bucket)| bucket.banned_until.is_some_and(|until| until > now))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+}
+
+fn elapsed(since: Instant, now: Instant) -> f64 {
+    now.saturating_duration_since(since).as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        rate: 10.0,
+        burst: 5,
+        ban_after: 3,
+        ban_secs: 60,
+    };
+
+    #[test]
+    fn test_bucket_refills_at_rate() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(&"peer", start), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(&"peer", start), Verdict::Drop);
+        // Other peers have buckets of their own
+        assert_eq!(limiter.check_at(&"other", start), Verdict::Allow);
+
+        // 10/s: one more token after 100ms
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.check_at(&"peer", later), Verdict::Allow);
+        assert_eq!(limiter.check_at(&"peer", later), Verdict::Drop);
+        assert_eq!(limiter.stats().dropped, 2);
+    }
+
+    #[test]
+    fn test_sustained_flood_is_banned_temporarily() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            limiter.check_at(&"peer", start);
+        }
+        assert_eq!(limiter.check_at(&"peer", start), Verdict::Drop);
+        assert_eq!(limiter.check_at(&"peer", start), Verdict::Drop);
+        assert_eq!(limiter.check_at(&"peer", start), Verdict::Ban);
+        assert_eq!(limiter.banned(), vec!["peer"]);
+
+        // Refilled tokens don't help while banned
+        let during = start + Duration::from_secs(30);
+        assert_eq!(limiter.check_at(&"peer", during), Verdict::Banned);
+
+        let after = start + Duration::from_secs(61);
+        assert_eq!(limiter.check_at(&"peer", after), Verdict::Allow);
+        assert_eq!(limiter.stats().bans, 1);
+    }
+}
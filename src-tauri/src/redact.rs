@@ -0,0 +1,100 @@
+// Keeping variable values out of logs. Whatever is logged about a value goes
+// through `ValueRedacted`, which shows only its length; errors that could
+// quote one (JSON parse errors quote the offending string) are reduced to
+// where they happened. A debug build started with `--log-values` logs values
+// as they are, for chasing sync bugs; release builds ignore it.
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::mask;
+
+static LOG_VALUES: AtomicBool = AtomicBool::new(false);
+
+/// Log values in plain text (`envmesh-daemon --log-values`); false, and
+/// nothing changes, in release builds
+pub fn set_log_values(on: bool) -> bool {
+    if !cfg!(debug_assertions) {
+        return false;
+    }
+    LOG_VALUES.store(on, Ordering::Relaxed);
+    true
+}
+
+pub fn log_values() -> bool {
+    LOG_VALUES.load(Ordering::Relaxed)
+}
+
+/// A value as it may appear in logs
+pub struct ValueRedacted<'a>(pub &'a str);
+
+impl fmt::Display for ValueRedacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if log_values() {
+            write!(f, "{:?}", self.0)
+        } else {
+            write!(f, "<redacted, {} bytes>", self.0.len())
+        }
+    }
+}
+
+impl fmt::Debug for ValueRedacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A JSON parse error without the input it quotes
+pub fn json_error(e: serde_json::Error) -> anyhow::Error {
+    if log_values() {
+        return e.into();
+    }
+    anyhow!(
+        "{:?} error at line {} column {}",
+        e.classify(),
+        e.line(),
+        e.column()
+    )
+}
+
+/// `text` (such as a hook's output) with `value`, and each line of it,
+/// replaced wherever they appear; what is too short to be a secret is left,
+/// as in CI masks
+pub fn scrub<'a>(text: &'a str, value: &str) -> Cow<'a, str> {
+    let mut text = Cow::Borrowed(text);
+    if log_values() {
+        return text;
+    }
+    for secret in std::iter::once(value).chain(value.lines()) {
+        if secret.trim().len() >= mask::MIN_MASK_LEN && text.contains(secret) {
+            text = Cow::Owned(text.replace(secret, &ValueRedacted(secret).to_string()));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_are_redacted() {
+        assert_eq!(ValueRedacted("hunter2").to_string(), "<redacted, 7 bytes>");
+        assert_eq!(
+            scrub("token=hunter2; again hunter2", "hunter2"),
+            "token=<redacted, 7 bytes>; again <redacted, 7 bytes>"
+        );
+        assert_eq!(scrub("debug=1", "1"), "debug=1");
+        assert_eq!(
+            scrub("line two\n", "line one\nline two"),
+            "<redacted, 8 bytes>\n"
+        );
+
+        let e = serde_json::from_str::<u64>(r#""hunter2""#).unwrap_err();
+        assert!(e.to_string().contains("hunter2"));
+        let redacted = json_error(e).to_string();
+        assert!(!redacted.contains("hunter2"), "{}", redacted);
+        assert!(redacted.contains("line 1"));
+    }
+}
@@ -0,0 +1,113 @@
+// `envmesh://KEY` references (`envmesh-cli run`): .env files, compose files
+// and service units name a variable instead of holding its value, and `run`
+// swaps in the value as it starts the process, so the secret reaches its
+// environment without being written to disk.
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::storage;
+
+pub const SCHEME: &str = "envmesh://";
+
+/// The key `value` refers to, if it is a reference. A key without a
+/// namespace is looked up in `namespace` (`envmesh://default/KEY` names one
+/// outside any).
+pub fn reference(value: &str, namespace: Option<&str>) -> Option<String> {
+    let key = value.trim().strip_prefix(SCHEME)?;
+    if key.is_empty() {
+        return None;
+    }
+    Some(match key.split_once(storage::NAMESPACE_SEPARATOR) {
+        Some((namespace, name)) => storage::namespaced_key(namespace, name),
+        None => storage::namespaced_key(namespace.unwrap_or_default(), key),
+    })
+}
+
+/// Every key the values of `vars` refer to
+pub fn referenced(vars: &[(String, String)], namespace: Option<&str>) -> BTreeSet<String> {
+    vars.iter()
+        .filter_map(|(_, value)| reference(value, namespace))
+        .collect()
+}
+
+/// The variables of `vars` that are references, with the values of the keys
+/// they name. Every reference to a key `values` lacks is reported together.
+pub fn resolve(
+    vars: &[(String, String)],
+    namespace: Option<&str>,
+    values: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>> {
+    let mut resolved = Vec::new();
+    let mut missing = Vec::new();
+    for (name, value) in vars {
+        let Some(key) = reference(value, namespace) else {
+            continue;
+        };
+        match values.get(&key) {
+            Some(value) => resolved.push((name.clone(), value.clone())),
+            None => missing.push(format!("{} ({}{})", name, SCHEME, key)),
+        }
+    }
+    if !missing.is_empty() {
+        bail!("Not set in the mesh: {}", missing.join(", "));
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_references_resolve() {
+        assert_eq!(
+            reference("envmesh://DB_URL", None),
+            Some("DB_URL".to_string())
+        );
+        assert_eq!(
+            reference(" envmesh://DB_URL ", Some("prod")),
+            Some("prod/DB_URL".to_string())
+        );
+        assert_eq!(
+            reference("envmesh://staging/DB_URL", Some("prod")),
+            Some("staging/DB_URL".to_string())
+        );
+        assert_eq!(
+            reference("envmesh://default/DB_URL", Some("prod")),
+            Some("DB_URL".to_string())
+        );
+        assert_eq!(reference("envmesh://", None), None);
+        assert_eq!(reference("postgres://db", None), None);
+
+        let vars = vec![
+            ("DATABASE_URL".to_string(), "envmesh://DB_URL".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            (
+                "API_KEY".to_string(),
+                "envmesh://staging/API_KEY".to_string(),
+            ),
+        ];
+        assert_eq!(
+            referenced(&vars, Some("prod"))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["prod/DB_URL".to_string(), "staging/API_KEY".to_string()]
+        );
+
+        let mut values = HashMap::new();
+        values.insert("prod/DB_URL".to_string(), "postgres://db".to_string());
+        let err = resolve(&vars, Some("prod"), &values).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Not set in the mesh: API_KEY (envmesh://staging/API_KEY)"
+        );
+        values.insert("staging/API_KEY".to_string(), "k".to_string());
+        assert_eq!(
+            resolve(&vars, Some("prod"), &values).unwrap(),
+            vec![
+                ("DATABASE_URL".to_string(), "postgres://db".to_string()),
+                ("API_KEY".to_string(), "k".to_string())
+            ]
+        );
+    }
+}
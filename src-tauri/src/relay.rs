@@ -0,0 +1,216 @@
+// Multi-tenant relaying for a server that hosts several teams: mesh tokens,
+// per-mesh isolation and connection quotas (`[relay]`)
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::users::{UserConfig, Users};
+
+/// Prefix of generated mesh tokens, so they are recognisable in configs
+pub const TOKEN_PREFIX: &str = "emt_";
+
+/// Overrides `[client] mesh_token`, to keep the token out of the config file
+pub const MESH_TOKEN_ENV: &str = "ENVMESH_MESH_TOKEN";
+
+/// The host's own mesh: every client's without `[relay]`, and otherwise
+/// that of clients presenting `host_token_sha256`'s token
+pub const HOST_MESH: &str = "";
+
+fn default_mesh_connections() -> usize {
+    50
+}
+
+/// `[relay]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Meshes relayed for other teams. With none, every client shares the
+    /// host's mesh and no token is asked for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub meshes: Vec<MeshConfig>,
+
+    /// Most connections over all meshes together (0 = unlimited)
+    #[serde(default)]
+    pub max_connections: usize,
+
+    /// Hex SHA-256 of the token the host's own machines present to join
+    /// its mesh. Once meshes are listed, clients without a token are
+    /// refused, so without this only the host itself is in its mesh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_token_sha256: Option<String>,
+}
+
+/// `[[relay.meshes]]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshConfig {
+    pub name: String,
+
+    /// Who the mesh belongs to, for logs
+    #[serde(default)]
+    pub account: String,
+
+    /// Hex SHA-256 of the mesh token (`envmesh-cli relay token` prints both)
+    pub token_sha256: String,
+
+    /// Most clients connected to this mesh at once
+    #[serde(default = "default_mesh_connections")]
+    pub max_connections: usize,
+
+    /// The team's users: with any, only machines they enrolled may join,
+    /// and each is relayed only what its role allows
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+}
+
+/// Whether a connection may join, and which mesh it joins
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Admit(String),
+    /// HTTP status and reason for the refused handshake
+    Reject(u16, &'static str),
+}
+
+/// What gets stored in `token_sha256`
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A fresh random mesh token
+pub fn generate_token() -> anyhow::Result<String> {
+    let random = crate::secretgen::generate(40, crate::secretgen::Charset::Alphanumeric)?;
+    Ok(format!("{}{}", TOKEN_PREFIX, random))
+}
+
+/// Compare without stopping at the first differing byte
+pub(crate) fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl RelayConfig {
+    /// Relaying for other meshes is on
+    pub fn is_multi_tenant(&self) -> bool {
+        !self.meshes.is_empty()
+    }
+
+    /// Admit a client presenting `token`, given how many connections each
+    /// mesh already has
+    pub fn admit(&self, token: Option<&str>, connected: &HashMap<String, usize>) -> Admission {
+        if !self.is_multi_tenant() {
+            return Admission::Admit(HOST_MESH.to_string());
+        }
+
+        let total: usize = connected.values().sum();
+        if self.max_connections > 0 && total >= self.max_connections {
+            return Admission::Reject(503, "relay connection limit reached");
+        }
+        let Some(token) = token else {
+            return Admission::Reject(401, "this relay needs a mesh token");
+        };
+        let hash = hash_token(token);
+        if let Some(host) = &self.host_token_sha256 {
+            if same(host.as_bytes(), hash.as_bytes()) {
+                return Admission::Admit(HOST_MESH.to_string());
+            }
+        }
+        let Some(mesh) = self
+            .meshes
+            .iter()
+            .find(|mesh| same(mesh.token_sha256.as_bytes(), hash.as_bytes()))
+        else {
+            return Admission::Reject(401, "unknown mesh token");
+        };
+
+        if connected.get(&mesh.name).copied().unwrap_or(0) >= mesh.max_connections {
+            return Admission::Reject(429, "mesh connection quota reached");
+        }
+        Admission::Admit(mesh.name.clone())
+    }
+
+    /// The users of a mesh we relay for
+    pub fn users(&self, mesh: &str) -> Users {
+        self.meshes
+            .iter()
+            .find(|m| m.name == mesh)
+            .map(|m| Users::new(m.users.clone()))
+            .unwrap_or_default()
+    }
+
+    /// The account a mesh belongs to, for logs
+    pub fn account(&self, mesh: &str) -> Option<&str> {
+        self.meshes
+            .iter()
+            .find(|m| m.name == mesh)
+            .map(|m| m.account.as_str())
+    }
+}
+
+/// `Authorization: Bearer <token>` value, as clients send it
+pub fn bearer_token(header: &str) -> Option<&str> {
+    header
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_pick_a_mesh_within_quota() {
+        let token = generate_token().unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+
+        let relay = RelayConfig {
+            meshes: vec![MeshConfig {
+                name: "team-a".to_string(),
+                account: "acme".to_string(),
+                token_sha256: hash_token(&token),
+                max_connections: 2,
+                users: Vec::new(),
+            }],
+            max_connections: 3,
+            host_token_sha256: Some(hash_token("emt_host")),
+        };
+        let mut connected = HashMap::new();
+
+        assert_eq!(
+            relay.admit(Some(&token), &connected),
+            Admission::Admit("team-a".to_string())
+        );
+        // Anonymous clients are refused; the host's own present its token
+        assert!(matches!(
+            relay.admit(None, &connected),
+            Admission::Reject(401, _)
+        ));
+        assert_eq!(
+            relay.admit(Some("emt_host"), &connected),
+            Admission::Admit(HOST_MESH.to_string())
+        );
+        assert!(matches!(
+            relay.admit(Some("emt_guess"), &connected),
+            Admission::Reject(401, _)
+        ));
+
+        connected.insert("team-a".to_string(), 2);
+        assert!(matches!(
+            relay.admit(Some(&token), &connected),
+            Admission::Reject(429, _)
+        ));
+        connected.insert("team-b".to_string(), 1);
+        assert!(matches!(
+            relay.admit(Some(&token), &connected),
+            Admission::Reject(503, _)
+        ));
+        assert!(matches!(
+            relay.admit(Some("emt_host"), &connected),
+            Admission::Reject(503, _)
+        ));
+
+        assert_eq!(
+            RelayConfig::default().admit(None, &HashMap::new()),
+            Admission::Admit(HOST_MESH.to_string())
+        );
+        assert_eq!(bearer_token("Bearer emt_x"), Some("emt_x"));
+        assert_eq!(bearer_token("Basic abc"), None);
+    }
+}
@@ -0,0 +1,258 @@
+// Revoked devices (`envmesh-cli device revoke`): a revocation is stored and
+// synced as an `@revoked/DEVICE_KEY` entry, the way locks ride on `@lock/`
+// keys, and can't be undone. It carries its revoker's signature, as relays
+// re-sign the changes they pass on. Every machine that takes it blocks the
+// device; with `wipe`, the device itself erases its database, and a LAN
+// server holds the instruction for it in case it only connects later.
+use anyhow::{anyhow, Result};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::client::SyncMessage;
+use crate::clock;
+use crate::events::{EventBus, MeshEvent};
+use crate::node::EnvMeshNode;
+use crate::pool::StoragePool;
+use crate::signing::{self, DeviceKey};
+use crate::sync::SyncEngine;
+use crate::users::Users;
+
+/// Storage keys of revocations start with this; like locks, they are never
+/// listed as variables
+pub const REVOKED_KEY_PREFIX: &str = "@revoked/";
+
+pub fn revoked_key(device: &str) -> String {
+    format!("{}{}", REVOKED_KEY_PREFIX, device)
+}
+
+/// The device a revocation key revokes, or None for any other key
+pub fn revoked_device(key: &str) -> Option<&str> {
+    key.strip_prefix(REVOKED_KEY_PREFIX)
+}
+
+/// The value stored under `revoked_key(device)`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revocation {
+    /// Device key of the revoked machine
+    pub device: String,
+    /// Whether the machine is to erase its database
+    pub wipe: bool,
+    pub revoked_at: i64,
+    /// Device key of the machine that revoked it
+    pub by: String,
+    /// `by`'s signature over the fields above (base64)
+    pub sig: String,
+}
+
+impl Revocation {
+    pub fn new(device: &str, wipe: bool, key: &DeviceKey) -> Result<Self> {
+        let device = device.trim();
+        signing::parse_public_key(device)?;
+        let mut revocation = Self {
+            device: device.to_string(),
+            wipe,
+            revoked_at: clock::now(),
+            by: key.public_key(),
+            sig: String::new(),
+        };
+        revocation.sig = key.sign_bytes(&revocation.payload());
+        Ok(revocation)
+    }
+
+    pub fn parse(value: &str) -> Result<Self> {
+        serde_json::from_str(value).map_err(|e| anyhow!("malformed revocation: {}", e))
+    }
+
+    pub fn to_value(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Why the revocation stored under `key` may not be taken, if it may
+    /// not: it must be for that key's device and signed by its revoker, who
+    /// must be an admin (with users) and trusted (with trusted keys). A mesh
+    /// with neither has no admin, so nobody there may revoke anybody.
+    pub fn check(&self, key: &str, users: &Users, trusted: &[VerifyingKey]) -> Result<()> {
+        if revoked_device(key) != Some(self.device.as_str()) {
+            return Err(anyhow!("{} holds a revocation of {}", key, self.device));
+        }
+        signing::verify_bytes(&self.by, &self.payload(), &self.sig)
+            .map_err(|e| anyhow!("revocation of {}: {}", self.device, e))?;
+        if !users.is_enabled() && trusted.is_empty() {
+            return Err(anyhow!(
+                "revocation of {}: revoking takes an admin, and this mesh has no users or [security] trusted_keys",
+                self.device
+            ));
+        }
+        users.check_manage(std::slice::from_ref(&self.by))?;
+        if !trusted.is_empty() && !trusted.contains(&signing::parse_public_key(&self.by)?) {
+            return Err(anyhow!(
+                "revocation of {} by untrusted key {}",
+                self.device,
+                self.by
+            ));
+        }
+        Ok(())
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.device, self.wipe, self.revoked_at, &self.by))
+            .unwrap_or_default()
+    }
+}
+
+/// Enforce revocations, those already stored and then each as it arrives:
+/// block revoked devices and hold their wipe instructions, or erase this
+/// machine's database (`db_path`, None when in memory) if it is the one
+/// revoked with `wipe`. Returns once it has been, so the caller can exit.
+pub async fn run(
+    sync: SyncEngine,
+    storage: StoragePool,
+    node: Arc<AsyncMutex<EnvMeshNode>>,
+    events: EventBus,
+    db_path: Option<PathBuf>,
+) -> Result<()> {
+    // Subscribed first, so nothing arriving while we catch up is missed
+    let mut events = events.subscribe();
+    let mut pending = storage.read(|s| s.revocations()).await?;
+    loop {
+        for (key, value, timestamp, machine_id) in pending.drain(..) {
+            // Checked again here, as users and trusted keys may have changed
+            // since it was stored
+            let checked = Revocation::parse(&value).and_then(|revocation| {
+                revocation.check(&key, &sync.users(), &sync.trusted_keys())?;
+                Ok(revocation)
+            });
+            let revocation = match checked {
+                Ok(revocation) => revocation,
+                Err(e) => {
+                    tracing::warn!("Ignoring {}: {}", key, e);
+                    continue;
+                }
+            };
+            if revocation.device != sync.device_public_key() {
+                let msg = SyncMessage::new(key, value, timestamp, machine_id, false);
+                block(&revocation, msg, &sync, &node).await;
+            } else if revocation.wipe {
+                tracing::warn!("This machine was revoked by {}; wiping it", revocation.by);
+                storage.write(|s| s.wipe()).await?;
+                return remove_database(db_path.as_deref());
+            } else {
+                tracing::warn!("This machine was revoked by {}", revocation.by);
+            }
+        }
+
+        let key = match events.recv().await {
+            Ok(MeshEvent::VarChanged {
+                key,
+                deleted: false,
+                ..
+            }) if revoked_device(&key).is_some() => key,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(_)) => {
+                // Start over from storage; blocking again changes nothing
+                pending = storage.read(|s| s.revocations()).await?;
+                continue;
+            }
+            // Nobody left to enforce anything for
+            Err(RecvError::Closed) => std::future::pending().await,
+        };
+        let stored = storage.read(move |s| s.get(&key).map(|v| (key, v))).await?;
+        if let (key, Some((value, timestamp, machine_id))) = stored {
+            pending.push((key, value, timestamp, machine_id));
+        }
+    }
+}
+
+/// Block the revoked device here and on our LAN server, which also hands it
+/// `msg` (the revocation) should it connect for a wipe
+async fn block(
+    revocation: &Revocation,
+    msg: SyncMessage,
+    sync: &SyncEngine,
+    node: &AsyncMutex<EnvMeshNode>,
+) {
+    let mut node = node.lock().await;
+    if revocation.wipe {
+        node.hold_wipe(sync.seal(msg));
+    }
+    let mut access = node.peer_access().clone();
+    if access.block(&revocation.device) {
+        sync.set_peer_access(access.clone());
+        let dropped = node.set_peer_access(access);
+        tracing::warn!(
+            "Blocked revoked device {} ({} connection(s) dropped)",
+            revocation.device,
+            dropped
+        );
+    }
+}
+
+/// Delete the (already erased) database and its journal files
+fn remove_database(db_path: Option<&Path>) -> Result<()> {
+    let Some(db_path) = db_path else {
+        return Ok(());
+    };
+    for suffix in ["", "-wal", "-shm"] {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(suffix);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Deleting {:?}: {}", path, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::Role;
+
+    #[test]
+    fn test_revocation_needs_an_admin_signature() {
+        let (admin, writer, stolen) = (
+            DeviceKey::generate(),
+            DeviceKey::generate(),
+            DeviceKey::generate().public_key(),
+        );
+        let key = revoked_key(&stolen);
+        let revocation = Revocation::new(&stolen, true, &admin).unwrap();
+        assert_eq!(
+            Revocation::parse(&revocation.to_value()).unwrap(),
+            revocation
+        );
+        assert!(Revocation::new("not a key", true, &admin).is_err());
+
+        // Open mesh: there is no admin, so nobody may revoke
+        assert!(revocation.check(&key, &Users::default(), &[]).is_err());
+        let trusted = [admin.verifying_key()];
+        assert!(revocation.check(&key, &Users::default(), &trusted).is_ok());
+        assert!(revocation
+            .check(&revoked_key("other"), &Users::default(), &trusted)
+            .is_err());
+        let mut forged = revocation.clone();
+        forged.wipe = false;
+        assert!(forged.check(&key, &Users::default(), &trusted).is_err());
+
+        let mut users = Users::default();
+        users.set("ada", Role::Admin, Vec::new()).unwrap();
+        users.set("bob", Role::Writer, Vec::new()).unwrap();
+        users.enroll("ada", &admin.public_key(), None).unwrap();
+        users.enroll("bob", &writer.public_key(), None).unwrap();
+        assert!(revocation.check(&key, &users, &[]).is_ok());
+        let by_writer = Revocation::new(&stolen, true, &writer).unwrap();
+        assert!(by_writer.check(&key, &users, &[]).is_err());
+
+        assert!(revocation
+            .check(&key, &Users::default(), &[admin.verifying_key()])
+            .is_ok());
+        assert!(by_writer
+            .check(&key, &Users::default(), &[admin.verifying_key()])
+            .is_err());
+    }
+}
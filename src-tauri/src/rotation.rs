@@ -0,0 +1,347 @@
+// Scheduled rotation (`[[rotations]]`): once a key's value is older than
+// its policy's `every`, the daemon replaces it with what the policy's
+// generator command prints (or a random value), stores it and syncs it.
+// Every attempt lands in the rotation log (`envmesh-cli rotation log`).
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock;
+use crate::events::MeshEvent;
+use crate::hooks;
+use crate::redact;
+use crate::secretgen::{self, Charset};
+use crate::sync::SyncEngine;
+
+/// How often the daemon looks for keys due for rotation
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wait this long before retrying a rotation that failed
+const RETRY_SECS: i64 = 15 * 60;
+
+fn default_length() -> usize {
+    32
+}
+
+fn default_charset() -> String {
+    "alphanumeric".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+/// `[[rotations]]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotationConfig {
+    pub key: String,
+
+    /// Rotate once the value is older than this: 12h, 30d
+    pub every: String,
+
+    /// Prints the new value on stdout (trailing newline dropped), with
+    /// ENVMESH_KEY and the current value in ENVMESH_VALUE; without it the
+    /// value is `length` random `charset` characters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+
+    #[serde(default = "default_length")]
+    pub length: usize,
+
+    /// alphanumeric, hex, base64 or base64url
+    #[serde(default = "default_charset")]
+    pub charset: String,
+
+    /// Kill the command if it runs longer than this
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+/// A policy, and when its key was last written
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotationStatus {
+    pub key: String,
+    pub every_secs: u64,
+    /// When the current value was written (None: the key doesn't exist)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_at: Option<i64>,
+    pub due_at: i64,
+    /// The newest attempt failed with this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct Policy {
+    config: RotationConfig,
+    every_secs: u64,
+    charset: Charset,
+}
+
+#[derive(Clone)]
+pub struct Rotator {
+    policies: Arc<Vec<Policy>>,
+    sync: SyncEngine,
+    machine_id: String,
+    /// When rotating each key last failed
+    failed: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl Rotator {
+    pub fn new(configs: &[RotationConfig], sync: SyncEngine, machine_id: String) -> Result<Self> {
+        let mut policies = Vec::new();
+        for config in configs {
+            let every_secs = clock::parse_duration(&config.every)
+                .map_err(|e| anyhow!("[[rotations]] {}: {}", config.key, e))?;
+            let charset = config
+                .charset
+                .parse()
+                .map_err(|e| anyhow!("[[rotations]] {}: {}", config.key, e))?;
+            if config.length == 0 || config.length > secretgen::MAX_LENGTH {
+                bail!(
+                    "[[rotations]] {}: length must be 1-{}",
+                    config.key,
+                    secretgen::MAX_LENGTH
+                );
+            }
+            policies.push(Policy {
+                config: config.clone(),
+                every_secs,
+                charset,
+            });
+        }
+        Ok(Self {
+            policies: Arc::new(policies),
+            sync,
+            machine_id,
+            failed: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Every policy, and when its key is due
+    pub async fn status(&self) -> Result<Vec<RotationStatus>> {
+        let mut statuses = Vec::new();
+        for policy in self.policies.iter() {
+            let written_at = self.written_at(&policy.config.key).await?;
+            let key = policy.config.key.clone();
+            let last_error = self
+                .sync
+                .storage
+                .read(move |s| {
+                    let log = s.rotation_log(crate::storage::ROTATION_LOG_SIZE)?;
+                    Ok(log.into_iter().find(|entry| entry.key == key))
+                })
+                .await?
+                .and_then(|entry| entry.error);
+            statuses.push(RotationStatus {
+                key: policy.config.key.clone(),
+                every_secs: policy.every_secs,
+                written_at,
+                due_at: written_at.map_or(0, |at| at + policy.every_secs as i64),
+                last_error,
+            });
+        }
+        Ok(statuses)
+    }
+
+    /// Rotate `key` now, whether it is due or not
+    pub async fn rotate(&self, key: &str) -> Result<()> {
+        let Some(policy) = self.policies.iter().find(|p| p.config.key == key) else {
+            bail!("{} has no rotation policy in [[rotations]]", key);
+        };
+        self.rotate_policy(policy).await
+    }
+
+    /// Rotate whatever is due every `CHECK_INTERVAL`, forever
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for policy in self.policies.iter() {
+                match self.is_due(policy).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        if let Err(e) = self.rotate_policy(policy).await {
+                            tracing::warn!("Failed to rotate {}: {}", policy.config.key, e);
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to check {}: {}", policy.config.key, e),
+                }
+            }
+        }
+    }
+
+    async fn written_at(&self, key: &str) -> Result<Option<i64>> {
+        let key = key.to_string();
+        Ok(self
+            .sync
+            .storage
+            .read(move |s| s.get(&key))
+            .await?
+            .map(|(_, timestamp, _)| timestamp))
+    }
+
+    async fn is_due(&self, policy: &Policy) -> Result<bool> {
+        let now = clock::now();
+        let failed = self
+            .failed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&policy.config.key)
+            .copied();
+        if failed.is_some_and(|at| now - at < RETRY_SECS) {
+            return Ok(false);
+        }
+        Ok(match self.written_at(&policy.config.key).await? {
+            Some(at) => now - at >= policy.every_secs as i64,
+            None => true,
+        })
+    }
+
+    async fn rotate_policy(&self, policy: &Policy) -> Result<()> {
+        let key = policy.config.key.clone();
+        let result = self.store_new_value(policy).await;
+
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let (k, machine_id, at) = (key.clone(), self.machine_id.clone(), clock::now());
+        self.sync
+            .storage
+            .write(move |s| s.record_rotation(&k, at, &machine_id, error.as_deref()))
+            .await?;
+
+        let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Ok(()) => {
+                failed.remove(&key);
+                tracing::info!("Rotated {}", key);
+            }
+            Err(_) => {
+                failed.insert(key, at);
+            }
+        }
+        result
+    }
+
+    async fn store_new_value(&self, policy: &Policy) -> Result<()> {
+        let key = policy.config.key.clone();
+        let k = key.clone();
+        let old = self
+            .sync
+            .storage
+            .read(move |s| s.get(&k))
+            .await?
+            .map(|(value, ..)| value);
+        let value = match &policy.config.command {
+            Some(command) => generate(command, &policy.config, old.as_deref()).await?,
+            None => secretgen::generate(policy.config.length, policy.charset)?,
+        };
+
+        let (k, machine_id) = (key.clone(), self.machine_id.clone());
+        self.sync
+            .storage
+            .write(move |s| s.set(&k, &value, &machine_id))
+            .await?;
+        self.sync.events.emit(MeshEvent::VarChanged {
+            key,
+            machine_id: self.machine_id.clone(),
+            deleted: false,
+        });
+        // Stored either way; peers that miss it get it on the next sync
+        if let Err(e) = self.sync.push_all().await {
+            tracing::warn!("Failed to push rotated value: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Run a generator command and take its output as the new value
+async fn generate(command: &str, config: &RotationConfig, old: Option<&str>) -> Result<String> {
+    let child = hooks::shell_command(command)
+        .env("ENVMESH_KEY", &config.key)
+        .env("ENVMESH_VALUE", old.unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("generator timed out after {}s", config.timeout_secs))??;
+
+    let value = String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("generator printed something that isn't UTF-8"))?;
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    let value = value.strip_suffix('\r').unwrap_or(value);
+    // The generator was handed the old value and may have printed the new
+    // one before failing
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut stderr = redact::scrub(stderr.trim(), value).into_owned();
+    if let Some(old) = old {
+        stderr = redact::scrub(&stderr, old).into_owned();
+    }
+
+    if !output.status.success() {
+        bail!("generator exited with {}: {}", output.status, stderr);
+    }
+    if value.is_empty() {
+        bail!("generator printed nothing");
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn policy(command: &str) -> RotationConfig {
+        RotationConfig {
+            key: "prod/API_TOKEN".to_string(),
+            every: "30d".to_string(),
+            command: Some(command.to_string()),
+            length: default_length(),
+            charset: default_charset(),
+            timeout_secs: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generator_output_becomes_the_value() {
+        let config =
+            policy(r#"test "$ENVMESH_VALUE" = old-token-value && echo "new-$ENVMESH_KEY""#);
+        assert_eq!(
+            generate(
+                config.command.as_deref().unwrap(),
+                &config,
+                Some("old-token-value")
+            )
+            .await
+            .unwrap(),
+            "new-prod/API_TOKEN"
+        );
+
+        let config = policy("echo \"$ENVMESH_VALUE\" >&2; exit 3");
+        let err = generate(
+            config.command.as_deref().unwrap(),
+            &config,
+            Some("old-token-value"),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("exit status: 3"), "{}", err);
+        assert!(!err.contains("old-token-value"), "{}", err);
+
+        let config = policy("true");
+        assert!(generate(config.command.as_deref().unwrap(), &config, None)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,203 @@
+// Doppler and Infisical export formats, for teams moving a project into
+// envmesh (`envmesh-cli import --format doppler|infisical`) or back out
+// (`envmesh-cli export --format doppler|infisical`)
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::dotenv;
+
+/// Keys Doppler adds to every download to say where it came from
+const DOPPLER_METADATA: &[&str] = &["DOPPLER_PROJECT", "DOPPLER_CONFIG", "DOPPLER_ENVIRONMENT"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Doppler,
+    Infisical,
+}
+
+impl Vendor {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Doppler => "doppler",
+            Self::Infisical => "infisical",
+        }
+    }
+}
+
+impl FromStr for Vendor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "doppler" => Ok(Self::Doppler),
+            "infisical" => Ok(Self::Infisical),
+            other => Err(anyhow!("Unknown format {:?} (doppler or infisical)", other)),
+        }
+    }
+}
+
+/// Variables from an export: `doppler secrets download --format json` or
+/// Doppler's secrets API response, `infisical export --format json` or
+/// Infisical's raw secrets API response
+pub fn parse(vendor: Vendor, text: &str) -> Result<Vec<(String, String)>> {
+    let document: Value = serde_json::from_str(text)
+        .map_err(|e| anyhow!("Not a {} JSON export: {}", vendor.as_str(), e))?;
+    let vars = match vendor {
+        Vendor::Doppler => doppler_vars(document)?,
+        Vendor::Infisical => infisical_vars(document)?,
+    };
+    check_names(&vars)?;
+    Ok(vars)
+}
+
+/// Both vendors name secrets like environment variables
+fn check_names(vars: &[(String, String)]) -> Result<()> {
+    let invalid: Vec<&str> = vars
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .filter(|key| !dotenv::is_valid_key(key))
+        .collect();
+    if !invalid.is_empty() {
+        bail!("Invalid variable names: {}", invalid.join(", "));
+    }
+    Ok(())
+}
+
+/// `{"KEY": "value"}`, or the API's `{"secrets": {"KEY": {"computed": ..}}}`
+/// (references resolved, since envmesh has none)
+fn doppler_vars(document: Value) -> Result<Vec<(String, String)>> {
+    let Value::Object(mut map) = document else {
+        bail!("Doppler export is not a JSON object");
+    };
+    if let Some(Value::Object(secrets)) = map.remove("secrets") {
+        map = secrets;
+    }
+
+    let mut vars = Vec::new();
+    for (key, value) in map {
+        if DOPPLER_METADATA.contains(&key.as_str()) {
+            continue;
+        }
+        let value = match value {
+            Value::String(value) => value,
+            Value::Object(secret) => secret
+                .get("computed")
+                .or_else(|| secret.get("raw"))
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("{} has no value", key))?
+                .to_string(),
+            other => bail!("{} is {}, not a string", key, other),
+        };
+        vars.push((key, value));
+    }
+    Ok(vars)
+}
+
+/// `[{"key", "value", "type"}]`, or the API's `{"secrets": [{"secretKey",
+/// "secretValue", "type"}]}`. Personal overrides are skipped where a shared
+/// value exists: the shared one is the team's.
+fn infisical_vars(document: Value) -> Result<Vec<(String, String)>> {
+    let secrets = match document {
+        Value::Array(secrets) => secrets,
+        Value::Object(mut map) => match map.remove("secrets") {
+            Some(Value::Array(secrets)) => secrets,
+            _ => bail!("Infisical export has no secrets array"),
+        },
+        _ => bail!("Infisical export is not a JSON array"),
+    };
+
+    let mut entries = Vec::new();
+    for secret in &secrets {
+        let field = |names: [&str; 2]| {
+            names
+                .iter()
+                .find_map(|name| secret.get(*name).and_then(Value::as_str))
+        };
+        let key = field(["key", "secretKey"]).ok_or_else(|| anyhow!("A secret has no key"))?;
+        let value =
+            field(["value", "secretValue"]).ok_or_else(|| anyhow!("{} has no value", key))?;
+        let personal = secret.get("type").and_then(Value::as_str) == Some("personal");
+        entries.push((key, value, personal));
+    }
+
+    let shared: HashSet<&str> = entries
+        .iter()
+        .filter(|(_, _, personal)| !personal)
+        .map(|(key, ..)| *key)
+        .collect();
+    Ok(entries
+        .into_iter()
+        .filter(|(key, _, personal)| !personal || !shared.contains(key))
+        .map(|(key, value, _)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+/// `vars` in the vendor's export format: a JSON object `doppler secrets
+/// upload` takes, or the array `infisical export --format json` writes
+pub fn render(vendor: Vendor, vars: &[(String, String)]) -> Result<String> {
+    check_names(vars).map_err(|e| anyhow!("{} (export one namespace with --namespace)", e))?;
+    let document = match vendor {
+        Vendor::Doppler => Value::Object(
+            vars.iter()
+                .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                .collect::<Map<_, _>>(),
+        ),
+        Vendor::Infisical => Value::Array(
+            vars.iter()
+                .map(|(key, value)| json!({ "key": key, "value": value, "type": "shared" }))
+                .collect(),
+        ),
+    };
+    Ok(serde_json::to_string_pretty(&document)? + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendor_formats_round_trip() {
+        let download =
+            r#"{"DB_URL": "postgres://db", "DOPPLER_CONFIG": "prd", "DOPPLER_PROJECT": "api"}"#;
+        assert_eq!(
+            parse(Vendor::Doppler, download).unwrap(),
+            vec![("DB_URL".to_string(), "postgres://db".to_string())]
+        );
+        let api = r#"{"secrets": {"URL": {"raw": "${HOST}/v1", "computed": "api.acme.dev/v1"}}}"#;
+        assert_eq!(
+            parse(Vendor::Doppler, api).unwrap(),
+            vec![("URL".to_string(), "api.acme.dev/v1".to_string())]
+        );
+
+        let export = r#"[
+            {"key": "TOKEN", "value": "team", "type": "shared"},
+            {"key": "TOKEN", "value": "mine", "type": "personal"},
+            {"key": "DEBUG", "value": "1", "type": "personal"}
+        ]"#;
+        assert_eq!(
+            parse(Vendor::Infisical, export).unwrap(),
+            vec![
+                ("TOKEN".to_string(), "team".to_string()),
+                ("DEBUG".to_string(), "1".to_string())
+            ]
+        );
+        let api =
+            r#"{"secrets": [{"secretKey": "TOKEN", "secretValue": "team", "type": "shared"}]}"#;
+        assert_eq!(parse(Vendor::Infisical, api).unwrap().len(), 1);
+        assert!(parse(Vendor::Infisical, r#"[{"key": "not valid", "value": "x"}]"#).is_err());
+        assert!(parse(Vendor::Doppler, "KEY=value").is_err());
+        let namespaced = [("prod/DB_URL".to_string(), "x".to_string())];
+        assert!(render(Vendor::Doppler, &namespaced).is_err());
+
+        let vars = vec![
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "line\nbreak".to_string()),
+        ];
+        for vendor in [Vendor::Doppler, Vendor::Infisical] {
+            let text = render(vendor, &vars).unwrap();
+            assert_eq!(parse(vendor, &text).unwrap(), vars);
+        }
+    }
+}
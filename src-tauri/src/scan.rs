@@ -0,0 +1,205 @@
+// Leak scanning for `envmesh-cli scan` and its pre-commit hook: finds values
+// stored in the mesh in files or staged changes by SHA-256 digest, so the
+// scanner never holds (or prints) the secrets themselves
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+/// Shorter values aren't looked for: ports, flags and `true` are everywhere
+pub const MIN_SCAN_LEN: usize = 8;
+
+/// Marks hooks written by `envmesh-cli hook install`, so reinstalling may
+/// replace them but never someone else's
+pub const HOOK_MARKER: &str = "# Installed by envmesh-cli hook install";
+
+pub const PRE_COMMIT_HOOK: &str = "#!/bin/sh
+# Installed by envmesh-cli hook install
+# Refuses commits that add values stored in the mesh (skip: git commit --no-verify)
+exec envmesh-cli scan --staged
+";
+
+/// One line of a stored value, as `Command::ValueDigests` hands it out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueDigest {
+    pub key: String,
+    pub len: usize,
+    pub sha256: String,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Digests of every line of each value long enough to look for; a
+/// multi-line value (a PEM key) is found by any of its lines
+pub fn digests(vars: &[(String, String)]) -> Vec<ValueDigest> {
+    let mut digests = Vec::new();
+    for (key, value) in vars {
+        for line in value.lines().map(str::trim) {
+            if line.len() >= MIN_SCAN_LEN {
+                digests.push(ValueDigest {
+                    key: key.clone(),
+                    len: line.len(),
+                    sha256: sha256_hex(line.as_bytes()),
+                });
+            }
+        }
+    }
+    digests
+}
+
+/// Looks for digested values in text
+pub struct Scanner {
+    /// Keys by value digest, grouped by value length
+    by_len: BTreeMap<usize, HashMap<String, Vec<String>>>,
+}
+
+impl Scanner {
+    pub fn new(digests: Vec<ValueDigest>) -> Self {
+        let mut by_len: BTreeMap<usize, HashMap<String, Vec<String>>> = BTreeMap::new();
+        for digest in digests {
+            let keys = by_len
+                .entry(digest.len)
+                .or_default()
+                .entry(digest.sha256)
+                .or_default();
+            if !keys.contains(&digest.key) {
+                keys.push(digest.key);
+            }
+        }
+        Self { by_len }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_len.is_empty()
+    }
+
+    /// Keys whose values appear in `line`. Only substrings starting a word
+    /// (at the line start or after punctuation or whitespace) are compared,
+    /// which is where values sit in assignments, URLs and quotes.
+    pub fn scan_line(&self, line: &str) -> Vec<String> {
+        let mut found: Vec<String> = Vec::new();
+        let starts = line.char_indices().filter(|(i, _)| {
+            line[..*i]
+                .chars()
+                .next_back()
+                .is_none_or(|prev| !prev.is_alphanumeric())
+        });
+        for (start, _) in starts {
+            for (len, values) in self.by_len.range(..=line.len() - start) {
+                let Some(window) = line.get(start..start + len) else {
+                    continue;
+                };
+                if let Some(keys) = values.get(&sha256_hex(window.as_bytes())) {
+                    for key in keys {
+                        if !found.contains(key) {
+                            found.push(key.clone());
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// (line number, key) for each stored value found in `text`
+    pub fn scan_text(&self, text: &str) -> Vec<(usize, String)> {
+        text.lines()
+            .enumerate()
+            .flat_map(|(index, line)| {
+                self.scan_line(line)
+                    .into_iter()
+                    .map(move |key| (index + 1, key))
+            })
+            .collect()
+    }
+}
+
+/// Lines a unified diff (`git diff --cached --unified=0`) adds, as
+/// (path, line number in the new file, text)
+pub fn added_lines(diff: &str) -> Vec<(String, usize, String)> {
+    let mut added = Vec::new();
+    let mut path = String::new();
+    let mut line_no = 0;
+    for line in diff.lines() {
+        if let Some(new_path) = line.strip_prefix("+++ ") {
+            path = new_path.strip_prefix("b/").unwrap_or(new_path).to_string();
+        } else if let Some(hunk) = line.strip_prefix("@@ ") {
+            // @@ -a,b +c,d @@
+            line_no = hunk
+                .split_whitespace()
+                .find_map(|part| part.strip_prefix('+'))
+                .and_then(|range| range.split(',').next()?.parse().ok())
+                .unwrap_or(0);
+        } else if let Some(text) = line.strip_prefix('+') {
+            added.push((path.clone(), line_no, text.to_string()));
+            line_no += 1;
+        } else if line.starts_with(' ') {
+            line_no += 1;
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner(vars: &[(&str, &str)]) -> Scanner {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Scanner::new(digests(&vars))
+    }
+
+    #[test]
+    fn test_scanner_finds_stored_values() {
+        let scanner = scanner(&[
+            ("DB_PASSWORD", "hunter2hunter2"),
+            ("PORT", "8080"),
+            ("TLS_KEY", "-----BEGIN KEY-----\nMIIEvQIBADANBgkqhkiG9w0B\n"),
+        ]);
+        // Too short to look for
+        assert!(scanner.scan_line("PORT=8080").is_empty());
+
+        assert_eq!(
+            scanner.scan_line("DATABASE_URL=postgres://app:hunter2hunter2@db/app"),
+            vec!["DB_PASSWORD".to_string()]
+        );
+        assert_eq!(
+            scanner.scan_line(r#"  password: "hunter2hunter2""#),
+            vec!["DB_PASSWORD".to_string()]
+        );
+        // Inside a longer word it is some other value
+        assert!(scanner.scan_line("xhunter2hunter2").is_empty());
+        assert!(scanner.scan_line("héllo wörld ✓").is_empty());
+
+        let text = "first\nkey = MIIEvQIBADANBgkqhkiG9w0B\n";
+        assert_eq!(scanner.scan_text(text), vec![(2, "TLS_KEY".to_string())]);
+    }
+
+    #[test]
+    fn test_added_lines_from_staged_diff() {
+        let diff = "diff --git a/.env b/.env
+--- a/.env
++++ b/.env
+@@ -2,0 +3,2 @@ FOO=1
++TOKEN=abc
++OTHER=1
+diff --git a/new.txt b/new.txt
+--- /dev/null
++++ b/new.txt
+@@ -0,0 +1 @@
++hello
+";
+        assert_eq!(
+            added_lines(diff),
+            vec![
+                (".env".to_string(), 3, "TOKEN=abc".to_string()),
+                (".env".to_string(), 4, "OTHER=1".to_string()),
+                ("new.txt".to_string(), 1, "hello".to_string()),
+            ]
+        );
+    }
+}
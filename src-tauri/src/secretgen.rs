@@ -0,0 +1,92 @@
+// Random secret values for `envmesh-cli generate`
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::str::FromStr;
+
+/// Longest value `generate` will make
+pub const MAX_LENGTH: usize = 4096;
+
+/// Characters a generated value is drawn from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Charset {
+    /// A-Z, a-z, 0-9
+    Alphanumeric,
+    /// 0-9, a-f
+    Hex,
+    /// The standard base64 alphabet (A-Z, a-z, 0-9, +, /)
+    Base64,
+    /// The URL-safe base64 alphabet (A-Z, a-z, 0-9, -, _)
+    Base64Url,
+}
+
+impl Charset {
+    fn alphabet(self) -> &'static [u8] {
+        match self {
+            Self::Alphanumeric => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+            Self::Hex => b"0123456789abcdef",
+            Self::Base64 => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/",
+            Self::Base64Url => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_",
+        }
+    }
+}
+
+impl FromStr for Charset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "alphanumeric" | "alnum" => Ok(Self::Alphanumeric),
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            "base64url" => Ok(Self::Base64Url),
+            other => Err(anyhow!(
+                "Unknown charset '{}' (alphanumeric, hex, base64, base64url)",
+                other
+            )),
+        }
+    }
+}
+
+/// `length` characters drawn uniformly from `charset` with the OS RNG
+pub fn generate(length: usize, charset: Charset) -> Result<String> {
+    if length == 0 || length > MAX_LENGTH {
+        return Err(anyhow!("Length must be between 1 and {}", MAX_LENGTH));
+    }
+
+    let alphabet = charset.alphabet();
+    // Reject bytes past the last whole multiple of the alphabet so every
+    // character is equally likely
+    let limit = 256 - 256 % alphabet.len();
+    let mut value = String::with_capacity(length);
+    let mut buf = [0u8; 64];
+    while value.len() < length {
+        OsRng.fill_bytes(&mut buf);
+        for &byte in &buf {
+            if (byte as usize) < limit && value.len() < length {
+                value.push(alphabet[byte as usize % alphabet.len()] as char);
+            }
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generates_from_charset() {
+        let hex = generate(48, Charset::Hex).unwrap();
+        assert_eq!(hex.len(), 48);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let url = generate(100, "base64url".parse().unwrap()).unwrap();
+        assert!(url
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(url, generate(100, Charset::Base64Url).unwrap());
+
+        assert!(generate(0, Charset::Hex).is_err());
+        assert!("rot13".parse::<Charset>().is_err());
+    }
+}
@@ -1,51 +1,515 @@
-// Embedded WebSocket server that runs when client becomes the LAN server
+// Embedded WebSocket server that runs when client becomes the LAN server,
+// or relays for several teams' meshes when `[relay]` lists them
 use anyhow::{anyhow, Result};
-use futures_util::SinkExt;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::{
+    self,
+    header::{HeaderValue, AUTHORIZATION},
+};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
 
-use crate::client::SyncMessage;
+use crate::access::{self, PeerAccess, CHALLENGE_HEADER, DEVICE_KEY_HEADER};
+use crate::chunking;
+use crate::client::{SyncMessage, WireMessage};
+use crate::clock;
+use crate::events::{EventBus, MeshEvent};
+use crate::faults::{FaultConfig, FaultInjector};
+use crate::health;
+use crate::links::{LinkTracker, PeerKind, PeerLink};
+use crate::netif;
+use crate::ratelimit::{RateLimiter, Verdict, CONNECT_RATE_LIMIT};
+use crate::relay::{self, Admission, RelayConfig};
+use crate::revoke;
+use crate::session::{self, ReplayBuffer, Sessions, RESUME_HEADER, SESSION_HEADER};
+use crate::share::{self, ShareStore};
+use crate::snapshot;
+use crate::throttle::{NetworkConfig, Throttle};
+use crate::topics::{self, Topics, TOPICS_HEADER};
+use crate::users::{UserConfig, Users};
+use crate::version::{PeerVersion, VERSION_HEADER};
+use crate::wire::{self, WireFormat, WIRE_FORMAT_HEADER};
 
 type WsStream = WebSocketStream<TcpStream>;
 
+/// Frames queued for one client. Broadcasts wait for room in a full queue,
+/// so `[network]` limits slow the sender down rather than cost clients.
+const SEND_QUEUE_LEN: usize = 256;
+
+/// A client whose full queue takes no frame for this long is stuck, and is
+/// dropped rather than holding up everyone else's broadcasts (a second in
+/// tests)
+const SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(if cfg!(test) { 1 } else { 30 });
+
+/// Clients that haven't finished the WebSocket handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the server keeps of a client; its socket belongs to the client's
+/// reader and writer tasks
+struct Connection {
+    addr: SocketAddr,
+    /// `relay::HOST_MESH` unless the client joined another team's mesh
+    mesh: String,
+    /// Agreed at the handshake
+    format: WireFormat,
+    /// Namespaces the client asked for at the handshake
+    topics: Topics,
+    /// Frames for the connection's writer task
+    tx: mpsc::Sender<Message>,
+    /// Updated by the connection's reader as pongs arrive
+    link: LinkTracker,
+    /// The device key the client proved at the handshake (empty if it
+    /// proved none); allow and block lists and users go by nothing else
+    ids: Vec<String>,
+    /// Who enrolled the client (None while its mesh has no users)
+    user: Option<UserConfig>,
+    /// Resume token; None for meshes we relay
+    session: Option<String>,
+}
+
+/// A frame encoded once per format, ready for any connection
+#[derive(Clone)]
+struct Encoded {
+    json: Message,
+    binary: Message,
+    /// The frame's namespace; None for control frames
+    topic: Option<String>,
+}
+
+impl Encoded {
+    fn new(frame: &WireMessage) -> Result<Self> {
+        Ok(Self {
+            json: wire::encode(frame, WireFormat::Json)?,
+            binary: wire::encode(frame, WireFormat::Binary)?,
+            topic: topics::topic_of(frame).map(str::to_string),
+        })
+    }
+
+    fn get(&self, format: WireFormat) -> &Message {
+        match format {
+            WireFormat::Json => &self.json,
+            WireFormat::Binary => &self.binary,
+        }
+    }
+}
+
+/// Live connections by ID. The lock is never held across an await: sending
+/// only queues frames for the writer tasks.
+#[derive(Clone)]
+struct Connections {
+    inner: Arc<RwLock<HashMap<u64, Connection>>>,
+    next_id: Arc<AtomicU64>,
+    events: EventBus,
+    /// `[network]` limits, shared by every writer (upload) and reader
+    /// (download)
+    upload: Option<Throttle>,
+    download: Option<Throttle>,
+    /// `[security] allowed_peers` / `blocked_peers`
+    access: Arc<RwLock<PeerAccess>>,
+    /// `[[users]]` of our own mesh
+    users: Arc<RwLock<Users>>,
+    /// Revocations for revoked machines that still connect (`revoke`)
+    wipes: Arc<RwLock<Vec<SyncMessage>>>,
+    /// Our recent broadcasts, for clients resuming a session. Held while
+    /// broadcasting, so frames are numbered in the order clients get them;
+    /// taken before `sessions` or the connection map, never after.
+    history: Arc<Mutex<ReplayBuffer<Encoded>>>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Connections {
+    fn new(
+        events: EventBus,
+        network: &NetworkConfig,
+        access: PeerAccess,
+        users: Users,
+        wipes: Vec<SyncMessage>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            events,
+            upload: network.upload(),
+            download: network.download(),
+            access: Arc::new(RwLock::new(access)),
+            users: Arc::new(RwLock::new(users)),
+            wipes: Arc::new(RwLock::new(wipes)),
+            history: Arc::new(Mutex::new(ReplayBuffer::new(SEND_QUEUE_LEN))),
+            sessions: Arc::new(Mutex::new(Sessions::new())),
+        }
+    }
+
+    fn history(&self) -> std::sync::MutexGuard<'_, ReplayBuffer<Encoded>> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn permits(&self, ids: &[String]) -> bool {
+        self.access
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .permits(ids)
+    }
+
+    fn users(&self) -> Users {
+        self.users.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether a client of `mesh` proved `device_key` at its handshake
+    fn has_proven(&self, mesh: &str, device_key: &str) -> bool {
+        self.read()
+            .values()
+            .any(|conn| conn.mesh == mesh && conn.ids.iter().any(|id| id == device_key))
+    }
+
+    /// The revocation telling the machine known by `ids` to wipe itself,
+    /// handed out once: a machine that won't obey is refused afterwards
+    /// rather than let in again and again
+    fn take_wipe(&self, ids: &[String]) -> Option<SyncMessage> {
+        let mut wipes = self.wipes.write().unwrap_or_else(|e| e.into_inner());
+        let index = wipes.iter().position(|msg| {
+            revoke::revoked_device(&msg.key).is_some_and(|d| ids.iter().any(|id| id == d))
+        })?;
+        Some(wipes.remove(index))
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<u64, Connection>> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<u64, Connection>> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register a connection. One resuming a session (after frame
+    /// `resume_from`) is first sent the broadcasts it missed, if we still
+    /// have them all; otherwise it gets seeded like a new client.
+    fn add(&self, conn: Connection, resume_from: Option<u64>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let peer = conn.addr.to_string();
+
+        // No broadcast can slip in between the replay and registering
+        let history = self.history();
+        let missed = resume_from
+            .and_then(|seq| history.since(seq))
+            .map(|frames| {
+                let wanted: Vec<Encoded> = frames
+                    .into_iter()
+                    .filter(|frame| match &frame.topic {
+                        Some(namespace) => conn.topics.contains(namespace),
+                        None => true,
+                    })
+                    .collect();
+                for frame in &wanted {
+                    // The queue is new and as long as the history
+                    let _ = conn.tx.try_send(frame.get(conn.format).clone());
+                }
+                wanted.len()
+            });
+        if let Some(token) = &conn.session {
+            let confirmed = match missed {
+                Some(_) => resume_from.unwrap_or_default(),
+                None => history.last_seq(),
+            };
+            self.sessions().open(token, confirmed);
+        }
+        self.write().insert(id, conn);
+        drop(history);
+
+        match missed {
+            Some(missed) => self.events.emit(MeshEvent::PeerResumed { peer, missed }),
+            None => self.events.emit(MeshEvent::PeerConnected { peer }),
+        }
+        id
+    }
+
+    /// Forget a connection, reporting it lost unless it already was
+    fn remove(&self, id: u64) {
+        let removed = self.write().remove(&id);
+        if let Some(conn) = removed {
+            if let Some(token) = &conn.session {
+                self.sessions().lost(token, Instant::now());
+            }
+            self.events.emit(MeshEvent::PeerLost {
+                peer: conn.addr.to_string(),
+            });
+        }
+    }
+
+    /// Send one of our own frames to the host mesh, keeping it for clients
+    /// that resume
+    async fn broadcast(&self, message: &Encoded) {
+        self.send_where(message, |_, conn| conn.mesh == relay::HOST_MESH, true)
+            .await;
+    }
+
+    fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Clients connected per mesh
+    fn per_mesh(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for conn in self.read().values() {
+            *counts.entry(conn.mesh.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Queue `message` for the connections `wanted` picks (by ID and
+    /// connection) that subscribe to
+    /// its namespace, each in its own format, kept in `history` if `keep`.
+    /// Waits for room in full queues first, all at once, so stuck clients
+    /// cost one `SLOW_CLIENT_TIMEOUT` between them; connections that have
+    /// closed, or stay full that long, are dropped.
+    async fn send_where(
+        &self,
+        message: &Encoded,
+        wanted: impl Fn(u64, &Connection) -> bool,
+        keep: bool,
+    ) {
+        let subscribed = |conn: &Connection| match &message.topic {
+            Some(namespace) => conn.topics.contains(namespace),
+            None => true,
+        };
+        let targets: Vec<(u64, SocketAddr, mpsc::Sender<Message>)> = self
+            .read()
+            .iter()
+            .filter(|(id, conn)| subscribed(conn) && wanted(**id, conn))
+            .map(|(id, conn)| (*id, conn.addr, conn.tx.clone()))
+            .collect();
+
+        let reserved =
+            futures_util::future::join_all(targets.into_iter().map(|(id, addr, tx)| async move {
+                let permit = tokio::time::timeout(SLOW_CLIENT_TIMEOUT, tx.reserve_owned()).await;
+                (id, addr, permit)
+            }))
+            .await;
+        let mut permits = HashMap::new();
+        let mut dead = Vec::new();
+        for (id, addr, permit) in reserved {
+            match permit {
+                Ok(Ok(permit)) => {
+                    permits.insert(id, permit);
+                }
+                Ok(Err(_)) => {
+                    tracing::warn!("Client {} went away, removing", addr);
+                    dead.push(id);
+                }
+                Err(_) => {
+                    tracing::warn!("Client {} is stuck, dropping it", addr);
+                    dead.push(id);
+                }
+            }
+        }
+
+        // Queued and kept under the history lock, so every client gets
+        // frames in the order they are numbered
+        let mut sent = 0;
+        {
+            let mut history = self.history();
+            if keep {
+                history.push(message.clone());
+            }
+            for (id, conn) in self
+                .read()
+                .iter()
+                .filter(|(id, conn)| subscribed(conn) && wanted(**id, conn))
+            {
+                let frame = message.get(conn.format).clone();
+                let queued = match permits.remove(id) {
+                    Some(permit) => {
+                        permit.send(frame);
+                        true
+                    }
+                    // Connected while we waited: its queue is new
+                    None if !dead.contains(id) => conn.tx.try_send(frame).is_ok(),
+                    None => false,
+                };
+                if queued {
+                    sent += 1;
+                }
+            }
+        }
+        // Dropping the queue ends the writer, which closes the connection
+        for id in dead {
+            self.remove(id);
+        }
+        tracing::debug!("Queued for {} clients", sent);
+    }
+}
+
+/// How to serve, beyond the port
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Meshes relayed for other teams as well as our own
+    pub relay: RelayConfig,
+    /// Injected into our broadcasts
+    pub faults: Option<FaultConfig>,
+    /// Bandwidth limits over all connections
+    pub network: NetworkConfig,
+    /// Addresses to listen on, from `netif::bind_addrs` (empty = 0.0.0.0)
+    pub listen: Vec<IpAddr>,
+    /// Peers refused at the handshake
+    pub access: PeerAccess,
+    /// Who may join our own mesh, and what each is sent
+    pub users: Users,
+    /// Revocations handed to the revoked machines they wipe, should they
+    /// connect
+    pub wipes: Vec<SyncMessage>,
+}
+
 pub struct EmbeddedServer {
-    connections: Arc<Mutex<Vec<WsStream>>>,
+    connections: Connections,
     port: u16,
-    _shutdown_tx: tokio::sync::broadcast::Sender<()>,
+    addrs: Vec<SocketAddr>,
+    shutdown_tx: tokio::sync::broadcast::Sender<()>,
+    /// Applied to what we broadcast
+    faults: Option<Arc<std::sync::Mutex<FaultInjector<Encoded>>>>,
+}
+
+/// The part of a server that broadcasts, to use without holding the node
+/// (`EnvMeshNode::broadcaster`): a broadcast may wait on full client queues
+#[derive(Clone)]
+pub struct Broadcaster {
+    connections: Connections,
+    faults: Option<Arc<std::sync::Mutex<FaultInjector<Encoded>>>>,
+}
+
+impl Broadcaster {
+    /// As `EmbeddedServer::broadcast`
+    pub async fn broadcast(&self, msg: &SyncMessage) -> Result<()> {
+        for frame in crate::chunking::split(msg) {
+            let message = Encoded::new(&frame)?;
+            for (delay, message) in self.inject(message) {
+                if delay.is_zero() {
+                    self.connections.broadcast(&message).await;
+                    continue;
+                }
+                let connections = self.connections.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    connections.broadcast(&message).await;
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// What to send in place of `message`, each after its delay
+    fn inject(&self, message: Encoded) -> Vec<(Duration, Encoded)> {
+        match &self.faults {
+            Some(faults) => faults
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .apply(message),
+            None => vec![(Duration::ZERO, message)],
+        }
+    }
 }
 
 impl EmbeddedServer {
-    pub async fn start(port: u16) -> Result<Self> {
-        let addr = format!("0.0.0.0:{}", port);
-        let listener = TcpListener::bind(&addr)
-            .await
-            .map_err(|e| anyhow!("Failed to bind to {}: {}", addr, e))?;
+    pub async fn start(port: u16, events: EventBus) -> Result<Self> {
+        Self::start_with_relay(port, events, RelayConfig::default()).await
+    }
+
+    /// Start, relaying for the meshes in `relay` as well as serving our own
+    pub async fn start_with_relay(port: u16, events: EventBus, relay: RelayConfig) -> Result<Self> {
+        let options = ServerOptions {
+            relay,
+            ..Default::default()
+        };
+        Self::start_with(port, events, options).await
+    }
+
+    pub async fn start_with(port: u16, events: EventBus, options: ServerOptions) -> Result<Self> {
+        let ServerOptions {
+            relay,
+            faults,
+            network,
+            mut listen,
+            access,
+            users,
+            wipes,
+        } = options;
+        if listen.is_empty() {
+            listen.push(IpAddr::from([0, 0, 0, 0]));
+        }
+        let listeners = netif::bind(&listen, port)?;
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
 
         // Get the actual bound port (important when port=0 for random port)
-        let actual_port = listener.local_addr()?.port();
+        let actual_port = addrs[0].port();
 
-        tracing::info!("LAN server listening on 0.0.0.0:{}", actual_port);
+        for addr in &addrs {
+            tracing::info!("LAN server listening on {}", addr);
+        }
+        if relay.is_multi_tenant() {
+            tracing::info!("Relaying for {} mesh(es)", relay.meshes.len());
+        }
+        let relay = Arc::new(relay);
 
-        let connections = Arc::new(Mutex::new(Vec::new()));
+        let connections = Connections::new(events.clone(), &network, access, users, wipes);
         let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        // One-time shares left with us (`envmesh-cli share --relay`)
+        let shares = Arc::new(Mutex::new(ShareStore::default()));
 
         // Spawn connection acceptor
-        let conns = Arc::clone(&connections);
+        let conns = connections.clone();
+        let conn_events = events.clone();
         let mut shutdown_rx = shutdown_tx.subscribe();
         tokio::spawn(async move {
+            let mut limiter = RateLimiter::new(CONNECT_RATE_LIMIT);
             loop {
+                // Whichever listener has a client first
+                let accept = futures_util::future::select_all(
+                    listeners.iter().map(|listener| Box::pin(listener.accept())),
+                );
                 tokio::select! {
-                    result = listener.accept() => {
+                    (result, _, _) = accept => {
                         match result {
                             Ok((stream, addr)) => {
-                                tracing::info!("Client connected: {}", addr);
-                                if let Err(e) = Self::handle_connection(stream, addr, Arc::clone(&conns)).await {
-                                    tracing::error!("Connection error: {}", e);
+                                match limiter.check(&addr.ip()) {
+                                    Verdict::Allow => {}
+                                    Verdict::Ban => {
+                                        tracing::warn!("Banning {}: too many connections", addr.ip());
+                                        conn_events.emit(MeshEvent::PeerBanned {
+                                            peer: addr.ip().to_string(),
+                                            secs: CONNECT_RATE_LIMIT.ban_secs,
+                                        });
+                                        continue;
+                                    }
+                                    Verdict::Drop | Verdict::Banned => {
+                                        tracing::debug!("Refusing connection from {} (rate limited)", addr);
+                                        continue;
+                                    }
                                 }
+                                // Each in its own task, so a client that is slow
+                                // to send its request holds up no one else
+                                tokio::spawn(Self::serve(
+                                    stream,
+                                    addr,
+                                    conns.clone(),
+                                    Arc::clone(&relay),
+                                    Arc::clone(&shares),
+                                ));
                             }
                             Err(e) => {
                                 tracing::error!("Accept error: {}", e);
@@ -63,74 +527,1698 @@ impl EmbeddedServer {
         Ok(Self {
             connections,
             port: actual_port,
-            _shutdown_tx: shutdown_tx,
+            addrs,
+            shutdown_tx,
+            faults: faults
+                .as_ref()
+                .and_then(FaultInjector::new)
+                .map(|faults| Arc::new(std::sync::Mutex::new(faults))),
         })
     }
 
+    /// Answer one accepted connection: a health check, a share, or else a
+    /// client to hand to `handle_connection`
+    async fn serve(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        connections: Connections,
+        relay: Arc<RelayConfig>,
+        shares: Arc<Mutex<ShareStore>>,
+    ) {
+        match health::answer_healthz(&mut stream).await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                tracing::debug!("Dropping {}: {}", addr, e);
+                return;
+            }
+        }
+        let connected = |mesh: &str, device_key: &str| connections.has_proven(mesh, device_key);
+        match share::answer_share(&mut stream, &shares, &relay, connected).await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                tracing::debug!("Dropping {}: {}", addr, e);
+                return;
+            }
+        }
+        tracing::info!("Client connected: {}", addr);
+        if let Err(e) = Self::handle_connection(stream, addr, &connections, &relay).await {
+            tracing::error!("Connection error: {}", e);
+        }
+    }
+
     async fn handle_connection(
         stream: TcpStream,
         addr: SocketAddr,
-        connections: Arc<Mutex<Vec<WsStream>>>,
+        connections: &Connections,
+        relay: &RelayConfig,
     ) -> Result<()> {
-        let ws_stream = accept_async(stream)
+        let connected = connections.per_mesh();
+
+        // The mesh token comes with the WebSocket handshake, so refused
+        // clients never see a frame
+        let mut admission = None;
+        // Binary frames if the client asks for them; the echoed header tells
+        // it we agreed
+        let mut format = WireFormat::Json;
+        let mut topics = Topics::default();
+        // Clients that can prove a device key are challenged to
+        let mut challenge = None;
+        // Our own clients get a session, resumed if they present a live one
+        let mut session = None;
+        let mut resume_from = None;
+        let mut version = None;
+        // The error type is tungstenite's
+        #[allow(clippy::result_large_err)]
+        let check = |request: &Request, mut response: Response| {
+            let token = request
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(relay::bearer_token);
+            let verdict = relay.admit(token, &connected);
+            let device_key = request
+                .headers()
+                .get(DEVICE_KEY_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if let (Some(device_key), true) =
+                (device_key, request.headers().contains_key(CHALLENGE_HEADER))
+            {
+                let nonce = access::new_challenge();
+                if let Ok(value) = HeaderValue::from_str(&nonce) {
+                    response.headers_mut().insert(CHALLENGE_HEADER, value);
+                    challenge = Some((nonce, device_key.to_string()));
+                }
+            }
+            version = request
+                .headers()
+                .get(VERSION_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(PeerVersion::parse_header);
+            if let Some(value) = request
+                .headers()
+                .get(TOPICS_HEADER)
+                .and_then(|value| value.to_str().ok())
+            {
+                topics = Topics::parse(value);
+            }
+            if let Some(WireFormat::Binary) = request
+                .headers()
+                .get(WIRE_FORMAT_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(WireFormat::parse)
+            {
+                format = WireFormat::Binary;
+                response.headers_mut().insert(
+                    WIRE_FORMAT_HEADER,
+                    HeaderValue::from_static(WireFormat::Binary.as_str()),
+                );
+            }
+            if matches!(&verdict, Admission::Admit(mesh) if mesh == relay::HOST_MESH) {
+                let claimed = request
+                    .headers()
+                    .get(RESUME_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.trim().to_string());
+                resume_from = claimed
+                    .as_deref()
+                    .and_then(|token| connections.sessions().resume(token, Instant::now()));
+                let token = match claimed {
+                    Some(token) if resume_from.is_some() => token,
+                    _ => session::new_token(),
+                };
+                if let Ok(value) = HeaderValue::from_str(&token) {
+                    response.headers_mut().insert(SESSION_HEADER, value);
+                }
+                session = Some(token);
+            }
+            let result = match &verdict {
+                Admission::Admit(_) => Ok(response),
+                Admission::Reject(status, reason) => Err(http::Response::builder()
+                    .status(*status)
+                    .body(Some(reason.to_string()))
+                    .expect("static response")),
+            };
+            admission = Some(verdict);
+            result
+        };
+        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_hdr_async(stream, check))
             .await
-            .map_err(|e| anyhow!("WebSocket handshake failed: {}", e))?;
+            .map_err(|_| {
+                anyhow!(
+                    "No WebSocket handshake from {} within {:?}",
+                    addr,
+                    HANDSHAKE_TIMEOUT
+                )
+            })?;
+
+        let (mut ws_stream, mesh) = match (handshake, admission) {
+            (Ok(ws_stream), Some(Admission::Admit(mesh))) => (ws_stream, mesh),
+            (Err(_), Some(Admission::Reject(_, reason))) => {
+                tracing::warn!("Refused {}: {}", addr, reason);
+                return Ok(());
+            }
+            (Err(e), _) => return Err(anyhow!("WebSocket handshake failed: {}", e)),
+            (Ok(_), _) => return Err(anyhow!("Handshake completed without admission")),
+        };
 
-        tracing::info!("WebSocket connection established: {}", addr);
+        // Only a device key the client proved can be allowed, blocked or
+        // enrolled; a client that proves nothing is anonymous
+        let ids = match challenge {
+            Some((nonce, device_key)) => match read_proof(&mut ws_stream).await {
+                Some(signature)
+                    if access::verify_proof(&nonce, &device_key, &signature).is_ok() =>
+                {
+                    vec![device_key]
+                }
+                _ => return refuse(ws_stream, addr, "device key not proven").await,
+            },
+            None => Vec::new(),
+        };
 
-        // Add to connections list
-        connections.lock().await.push(ws_stream);
+        if !connections.permits(&ids) {
+            // A revoked machine gets its revocation and nothing else
+            let Some(msg) = connections.take_wipe(&ids) else {
+                return refuse(ws_stream, addr, "peer is not allowed").await;
+            };
+            tracing::warn!("Telling revoked {} to wipe itself", addr);
+            ws_stream
+                .send(Message::Text(serde_json::to_string(&WireMessage::Sync(
+                    msg,
+                ))?))
+                .await
+                .map_err(|e| anyhow!("Failed to send wipe to {}: {}", addr, e))?;
+            let _ = ws_stream.close(None).await;
+            return Ok(());
+        }
+
+        // With users, only machines one of them enrolled may join, and
+        // enrollment counts only for a proven key: naming one grants nothing
+        let users = if mesh == relay::HOST_MESH {
+            connections.users()
+        } else {
+            relay.users(&mesh)
+        };
+        let user = users.user_of(&ids).cloned();
+        if users.is_enabled() && user.is_none() {
+            return refuse(ws_stream, addr, "machine is not enrolled by any user").await;
+        }
+
+        // A client is sent only what its user may read, whatever it asked for
+        if let Some(user) = &user {
+            topics = topics.within(&user.topics());
+        }
+
+        match relay.account(&mesh) {
+            Some(account) => tracing::info!(
+                "WebSocket connection established: {} (mesh {}, account {})",
+                addr,
+                mesh,
+                account
+            ),
+            None => tracing::info!("WebSocket connection established: {}", addr),
+        }
+
+        let hello = WireMessage::Hello {
+            time_ms: clock::now_millis(),
+            version: Some(PeerVersion::ours()),
+            received_ms: 0,
+        };
+        ws_stream
+            .send(Message::Text(serde_json::to_string(&hello)?))
+            .await
+            .map_err(|e| anyhow!("Failed to greet {}: {}", addr, e))?;
+
+        // Each client gets its own reader and writer task, so a slow one
+        // holds up nobody else
+        let (sink, stream) = ws_stream.split();
+        let (tx, rx) = mpsc::channel(SEND_QUEUE_LEN);
+        let link = LinkTracker::new();
+        if let Some(version) = version {
+            if let Some(warning) = version.warning() {
+                tracing::warn!("Client {} {}", addr, warning);
+            }
+            link.set_version(version);
+        }
+        if let Some(device_key) = ids.first() {
+            link.set_peer_id(device_key.clone());
+        }
+        let id = connections.add(
+            Connection {
+                addr,
+                mesh: mesh.clone(),
+                format,
+                topics,
+                tx,
+                link: link.clone(),
+                ids,
+                user: user.clone(),
+                session: session.clone(),
+            },
+            resume_from,
+        );
+        tokio::spawn(write_frames(sink, rx, id, addr, connections.clone()));
+        tokio::spawn(read_frames(
+            stream,
+            id,
+            addr,
+            mesh,
+            user,
+            link,
+            session,
+            connections.clone(),
+        ));
 
         Ok(())
     }
 
+    /// Send one of our changes to the clients of our own mesh (never to
+    /// meshes we relay for)
     pub async fn broadcast(&self, msg: &SyncMessage) -> Result<()> {
-        let json = serde_json::to_string(msg)?;
-        let message = Message::Text(json);
+        self.broadcaster().broadcast(msg).await
+    }
 
-        let mut conns = self.connections.lock().await;
-        let mut i = 0;
+    pub fn broadcaster(&self) -> Broadcaster {
+        Broadcaster {
+            connections: self.connections.clone(),
+            faults: self.faults.clone(),
+        }
+    }
 
-        // Remove closed connections and send to active ones
-        while i < conns.len() {
-            match conns[i].send(message.clone()).await {
-                Ok(_) => {
-                    i += 1;
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to send to client, removing: {}", e);
-                    conns.remove(i);
+    /// Send changes to one client only (`peer` as in `PeerConnected`).
+    /// Returns false if no such client is connected. Unlike broadcasts,
+    /// this waits for room in the client's queue, as seeding a client can
+    /// be far more than the queue holds.
+    pub async fn send_to(&self, peer: &str, msgs: &[SyncMessage]) -> Result<bool> {
+        let Some((tx, format, topics)) = self.host_client(peer) else {
+            return Ok(false);
+        };
+
+        let wanted = msgs.iter().filter(|msg| topics.wants_key(&msg.key));
+        for frame in wanted.flat_map(crate::chunking::split) {
+            tx.send(wire::encode(&frame, format)?)
+                .await
+                .map_err(|_| anyhow!("Failed to send to {}: connection closed", peer))?;
+        }
+        Ok(true)
+    }
+
+    /// Send one client the changes its topics want as a compressed
+    /// `snapshot` frame (split into chunks when large), taken at the
+    /// sequence points in `through`
+    pub async fn send_snapshot(
+        &self,
+        peer: &str,
+        machine_id: &str,
+        through: BTreeMap<String, u64>,
+        msgs: &[SyncMessage],
+        resync: Option<&str>,
+    ) -> Result<bool> {
+        let Some((tx, format, topics)) = self.host_client(peer) else {
+            return Ok(false);
+        };
+
+        let wanted: Vec<SyncMessage> = msgs
+            .iter()
+            .filter(|msg| topics.wants_key(&msg.key))
+            .cloned()
+            .collect();
+        let data = snapshot::encode(&wanted)?;
+        for frame in chunking::split_snapshot(machine_id, through, &data, resync) {
+            tx.send(wire::encode(&frame, format)?)
+                .await
+                .map_err(|_| anyhow!("Failed to send to {}: connection closed", peer))?;
+        }
+        Ok(true)
+    }
+
+    /// Queue, wire format and topics of our own client `peer`
+    fn host_client(&self, peer: &str) -> Option<(mpsc::Sender<Message>, WireFormat, Topics)> {
+        self.connections
+            .read()
+            .values()
+            .find(|conn| conn.addr.to_string() == peer && conn.mesh == relay::HOST_MESH)
+            .map(|conn| (conn.tx.clone(), conn.format, conn.topics.clone()))
+    }
+
+    async fn send_to_all(&self, msg: &WireMessage) -> Result<()> {
+        self.connections
+            .send_where(&Encoded::new(msg)?, |_, _| true, false)
+            .await;
+        Ok(())
+    }
+
+    /// Ping our own clients; a client whose queue is full just misses one
+    pub fn ping_all(&self) {
+        // Each pong also confirms the broadcasts queued before its ping
+        let history = self.connections.history();
+        let mut pinged = Vec::new();
+        for conn in self.connections.read().values() {
+            if conn.mesh == relay::HOST_MESH {
+                let payload = conn.link.next_ping();
+                if conn.tx.try_send(Message::Ping(payload.clone())).is_ok() {
+                    pinged.extend(conn.session.clone().map(|token| (token, payload)));
                 }
             }
         }
+        let mut sessions = self.connections.sessions();
+        for (token, payload) in pinged {
+            sessions.pinged(&token, payload, history.last_seq());
+        }
+    }
 
-        tracing::debug!("Broadcasted to {} clients", conns.len());
-        Ok(())
+    /// Our own clients' links, by address. Frames are numbered as
+    /// broadcasts, which each session confirms with its pongs.
+    pub fn links(&self) -> Vec<PeerLink> {
+        let last_seq = self.connections.history().last_seq();
+        let links: Vec<(PeerLink, Option<String>)> = self
+            .connections
+            .read()
+            .values()
+            .filter(|conn| conn.mesh == relay::HOST_MESH)
+            .map(|conn| {
+                let link = conn
+                    .link
+                    .snapshot(PeerKind::LanClient, &conn.addr.to_string());
+                (link, conn.session.clone())
+            })
+            .collect();
+        let sessions = self.connections.sessions();
+        links
+            .into_iter()
+            .map(|(mut link, session)| {
+                link.acked_seq = session.and_then(|token| sessions.confirmed(&token));
+                link.sent_seq = link.acked_seq.map(|_| last_seq);
+                link
+            })
+            .collect()
+    }
+
+    /// Replace the allow and block lists, dropping clients they now refuse;
+    /// returns how many were dropped
+    pub fn set_peer_access(&self, access: PeerAccess) -> usize {
+        *self
+            .connections
+            .access
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = access;
+        let refused: Vec<u64> = self
+            .connections
+            .read()
+            .iter()
+            .filter(|(_, conn)| !self.connections.permits(&conn.ids))
+            .map(|(id, _)| *id)
+            .collect();
+        // Dropping the queue ends the writer, which closes the connection
+        for id in &refused {
+            self.connections.remove(*id);
+        }
+        refused.len()
+    }
+
+    /// Replace our own mesh's users, dropping clients whose user changed
+    /// (so they reconnect under the new role, judged by their proven device
+    /// key); returns how many were dropped
+    pub fn set_users(&self, users: Users) -> usize {
+        let changed: Vec<u64> = self
+            .connections
+            .read()
+            .iter()
+            .filter(|(_, conn)| {
+                conn.mesh == relay::HOST_MESH && users.user_of(&conn.ids) != conn.user.as_ref()
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        *self
+            .connections
+            .users
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = users;
+        for id in &changed {
+            self.connections.remove(*id);
+        }
+        changed.len()
+    }
+
+    /// Hand `msg`, a revocation with `wipe`, to its device if it connects
+    pub fn hold_wipe(&self, msg: SyncMessage) {
+        let mut wipes = self
+            .connections
+            .wipes
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        wipes.retain(|held| held.key != msg.key);
+        wipes.push(msg);
+    }
+
+    /// Drop clients, in any mesh, whose enrollment expired since they
+    /// connected; returns how many
+    pub fn drop_expired_users(&self) -> usize {
+        let expired: Vec<u64> = self
+            .connections
+            .read()
+            .iter()
+            .filter(|(_, conn)| {
+                conn.user
+                    .as_ref()
+                    .is_some_and(|user| !conn.ids.iter().any(|id| user.is_enrolled(id)))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            self.connections.remove(*id);
+        }
+        expired.len()
     }
 
     pub async fn active_connections(&self) -> usize {
-        self.connections.lock().await.len()
+        self.connections.len()
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// Every address the server listens on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Resign as LAN server: tell clients to re-run failover, close their
+    /// connections and stop accepting new ones
+    pub async fn shutdown(&self, reason: &str) -> Result<()> {
+        tracing::info!("Resigning as LAN server: {}", reason);
+
+        self.send_to_all(&WireMessage::ServerShutdown {
+            reason: reason.to_string(),
+        })
+        .await?;
+
+        // Writers close their connection once the notice is flushed; the
+        // clients were told, so they aren't reported lost
+        self.connections.write().clear();
+
+        // No receivers just means the acceptor already stopped
+        let _ = self.shutdown_tx.send(());
+        Ok(())
+    }
+}
+
+/// The signature a challenged client answers with, if it does in time
+async fn read_proof(ws_stream: &mut WsStream) -> Option<String> {
+    let frame = tokio::time::timeout(access::PROOF_TIMEOUT, ws_stream.next())
+        .await
+        .ok()??
+        .ok()?;
+    match wire::decode(&frame)? {
+        Ok(WireMessage::Proof { signature }) => Some(signature),
+        _ => None,
+    }
+}
+
+/// Turn away a client after the upgrade, telling it why
+async fn refuse(mut ws_stream: WsStream, addr: SocketAddr, reason: &str) -> Result<()> {
+    tracing::warn!("Refused {}: {}", addr, reason);
+    let frame = CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.to_string().into(),
+    };
+    let _ = ws_stream.close(Some(frame)).await;
+    Ok(())
+}
+
+/// Write one client's queued frames until the queue closes (the client was
+/// dropped or the server shut down) or the connection fails
+async fn write_frames(
+    mut sink: SplitSink<WsStream, Message>,
+    mut rx: mpsc::Receiver<Message>,
+    id: u64,
+    addr: SocketAddr,
+    connections: Connections,
+) {
+    while let Some(message) = rx.recv().await {
+        if let Some(upload) = &connections.upload {
+            upload.take(message.len()).await;
+        }
+        if let Err(e) = sink.send(message).await {
+            tracing::debug!("Failed to write to {}: {}", addr, e);
+            connections.remove(id);
+            return;
+        }
+    }
+    if let Err(e) = sink.close().await {
+        tracing::debug!("Failed to close connection to {}: {}", addr, e);
+    }
+}
+
+/// Read one client's frames until it disconnects, so a client that goes
+/// away is noticed at once rather than at the next broadcast. Relayed
+/// clients' changes are passed on to the rest of their mesh (and only
+/// there), if their `user` may make them, and answers to gap requests
+/// only to the client that asked; the host applies nothing its own clients
+/// send, and only hears their resyncs naming it (`StateRequested`).
+#[allow(clippy::too_many_arguments)]
+async fn read_frames(
+    mut stream: SplitStream<WsStream>,
+    id: u64,
+    addr: SocketAddr,
+    mesh: String,
+    user: Option<UserConfig>,
+    link: LinkTracker,
+    session: Option<String>,
+    connections: Connections,
+) {
+    // Snapshots answering a resync, passed on once whole
+    let mut snapshots = chunking::Reassembler::default();
+    while let Some(frame) = stream.next().await {
+        link.received();
+        let message = match frame {
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(Message::Pong(payload)) => {
+                link.pong(&payload);
+                if let Some(token) = &session {
+                    connections.sessions().ponged(token, &payload);
+                }
+                continue;
+            }
+            Ok(message) => message,
+        };
+        if let Some(download) = &connections.download {
+            download.take(message.len()).await;
+        }
+        let Some(decoded) = wire::decode(&message) else {
+            continue;
+        };
+        if mesh == relay::HOST_MESH {
+            // Except a resync naming the host, which it answers itself
+            if let Ok(WireMessage::SyncRequest {
+                from: Some(from), ..
+            }) = decoded
+            {
+                connections.events.emit(MeshEvent::StateRequested {
+                    peer: addr.to_string(),
+                    from,
+                });
+            }
+            continue;
+        }
+        // Answers go to the connection that asked for them alone
+        let (decoded, to) = match decoded {
+            Ok(WireMessage::Reply { to, frame }) => (Ok(*frame), Some(to)),
+            Ok(WireMessage::SeqRequest {
+                machine_id, seqs, ..
+            }) => (
+                Ok(WireMessage::SeqRequest {
+                    machine_id,
+                    seqs,
+                    asker: Some(id),
+                }),
+                None,
+            ),
+            Ok(WireMessage::SyncRequest {
+                machine_id, from, ..
+            }) => (
+                Ok(WireMessage::SyncRequest {
+                    machine_id,
+                    from,
+                    asker: Some(id),
+                }),
+                None,
+            ),
+            other => (other, None),
+        };
+        let decoded = match (decoded, to) {
+            (
+                Ok(frame @ (WireMessage::Snapshot { .. } | WireMessage::SnapshotChunk { .. })),
+                Some(to),
+            ) => {
+                if let Some(snapshot) = snapshots.accept(frame) {
+                    relay_snapshot(snapshot, to, &mesh, addr, user.as_ref(), &connections).await;
+                }
+                continue;
+            }
+            (decoded, _) => decoded,
+        };
+        match decoded {
+            Ok(
+                frame @ (WireMessage::Sync(_)
+                | WireMessage::SyncChunk { .. }
+                | WireMessage::SyncRequest { .. }
+                | WireMessage::SeqRequest { .. }),
+            ) => {
+                if to.is_some()
+                    && !matches!(frame, WireMessage::Sync(_) | WireMessage::SyncChunk { .. })
+                {
+                    tracing::warn!("Not relaying reply from {}: not a change", addr);
+                    continue;
+                }
+                let key = match &frame {
+                    WireMessage::Sync(msg) => Some(msg.key.as_str()),
+                    WireMessage::SyncChunk { key, .. } => Some(key.as_str()),
+                    _ => None,
+                };
+                if let Some(Err(e)) = user.as_ref().zip(key).map(|(u, k)| u.check_write(k)) {
+                    tracing::warn!("Not relaying change from {}: {}", addr, e);
+                    continue;
+                }
+                // Re-encoded, as the rest of the mesh may not share the
+                // sender's format
+                match Encoded::new(&frame) {
+                    // Waiting for room slows this sender down to what the
+                    // rest of its mesh takes
+                    Ok(encoded) => {
+                        connections
+                            .send_where(
+                                &encoded,
+                                |conn_id, conn| {
+                                    conn.mesh == mesh
+                                        && conn.addr != addr
+                                        && to.is_none_or(|to| conn_id == to)
+                                },
+                                false,
+                            )
+                            .await
+                    }
+                    Err(e) => tracing::warn!("Failed to relay frame from {}: {}", addr, e),
+                }
+            }
+            Ok(_) => tracing::debug!("Not relaying control frame from {}", addr),
+            Err(e) => tracing::warn!("Ignoring malformed frame from {}: {}", addr, e),
+        }
+    }
+
+    connections.remove(id);
+    tracing::info!("Client {} disconnected", addr);
+}
+
+/// Pass a relayed client's snapshot, answering a resync, on to the
+/// connection `to` in its mesh, unless it holds a change its `user` may not
+/// make
+async fn relay_snapshot(
+    snapshot: WireMessage,
+    to: u64,
+    mesh: &str,
+    addr: SocketAddr,
+    user: Option<&UserConfig>,
+    connections: &Connections,
+) {
+    let WireMessage::Snapshot {
+        machine_id,
+        through,
+        data,
+        resync,
+        ..
+    } = snapshot
+    else {
+        return;
+    };
+    if let Some(user) = user {
+        let checked = snapshot::decode(&data).and_then(|changes| {
+            changes
+                .iter()
+                .try_for_each(|msg| user.check_write(&msg.key))
+        });
+        if let Err(e) = checked {
+            tracing::warn!("Not relaying snapshot from {}: {}", addr, e);
+            return;
+        }
+    }
+    for frame in chunking::split_snapshot(&machine_id, through, &data, resync.as_deref()) {
+        match Encoded::new(&frame) {
+            Ok(encoded) => {
+                connections
+                    .send_where(
+                        &encoded,
+                        |conn_id, conn| conn_id == to && conn.mesh == mesh,
+                        false,
+                    )
+                    .await
+            }
+            Err(e) => {
+                tracing::warn!("Failed to relay snapshot from {}: {}", addr, e);
+                return;
+            }
+        }
+    }
 }
 
 impl Drop for EmbeddedServer {
     fn drop(&mut self) {
         tracing::info!("Embedded server shutting down");
+        let _ = self.shutdown_tx.send(());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::ConnectOptions;
+    use crate::signing::DeviceKey;
+
+    /// Options for a client that proves `key` at the handshake
+    fn signed_by(key: &Arc<DeviceKey>) -> ConnectOptions {
+        ConnectOptions {
+            signer: Some(Arc::clone(key)),
+            ..Default::default()
+        }
+    }
 
     #[tokio::test]
     async fn test_server_starts() {
-        let server = EmbeddedServer::start(0).await.unwrap(); // Port 0 = random
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap(); // Port 0 = random
         assert!(server.port() > 0);
         assert_eq!(server.active_connections().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_silent_connection_holds_up_no_one() {
+        use crate::client::WebSocketClient;
+
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let _silent = TcpStream::connect(("127.0.0.1", server.port()))
+            .await
+            .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let client = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            WebSocketClient::connect(&url),
+        )
+        .await
+        .expect("held up by a client that sent nothing");
+        assert!(client.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_clients() {
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+
+        // The acceptor registers the connection after the handshake completes
+        while server.active_connections().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.links()[0].version, Some(PeerVersion::ours()));
+
+        server.shutdown("test").await.unwrap();
+        assert_eq!(server.active_connections().await, 0);
+
+        // Every connection opens with the server's clock and build
+        match client.receive().await.unwrap() {
+            Some(WireMessage::Hello {
+                time_ms,
+                version,
+                received_ms,
+            }) => {
+                assert!((received_ms - time_ms).abs() < 5_000);
+                assert_eq!(version, Some(PeerVersion::ours()));
+            }
+            other => panic!("expected hello, got {:?}", other),
+        }
+        match client.receive().await.unwrap() {
+            Some(WireMessage::ServerShutdown { reason }) => assert_eq!(reason, "test"),
+            other => panic!("expected shutdown notice, got {:?}", other),
+        }
+
+        // The listener is gone once the acceptor sees the shutdown signal
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(crate::client::WebSocketClient::connect(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_to_reaches_one_client() {
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let server = EmbeddedServer::start(0, events).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+
+        let peer = match rx.recv().await.unwrap() {
+            MeshEvent::PeerConnected { peer } => peer,
+            other => panic!("expected connect event, got {:?}", other),
+        };
+        let msg = SyncMessage::new(
+            "KEY".to_string(),
+            "value".to_string(),
+            100,
+            "machine-a".to_string(),
+            false,
+        );
+        assert!(server.send_to(&peer, &[msg]).await.unwrap());
+        assert!(!server.send_to("10.0.0.1:1", &[]).await.unwrap());
+
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(WireMessage::Hello { .. })
+        ));
+        match client.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "KEY"),
+            other => panic!("expected change, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_noticed_without_traffic() {
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let server = EmbeddedServer::start(0, events).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        let client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+        let peer = match rx.recv().await.unwrap() {
+            MeshEvent::PeerConnected { peer } => peer,
+            other => panic!("expected connect event, got {:?}", other),
+        };
+        drop(client);
+
+        match rx.recv().await.unwrap() {
+            MeshEvent::PeerLost { peer: lost } => assert_eq!(lost, peer),
+            other => panic!("expected lost event, got {:?}", other),
+        }
+        assert_eq!(server.active_connections().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_client_is_dropped_and_others_still_served() {
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let server = EmbeddedServer::start(0, events).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        // Never read, so their socket buffers and then their queues fill up
+        let mut stalled = Vec::new();
+        let mut _sockets = Vec::new();
+        for _ in 0..3 {
+            _sockets.push(tokio_tungstenite::connect_async(&url).await.unwrap());
+            match rx.recv().await.unwrap() {
+                MeshEvent::PeerConnected { peer } => stalled.push(peer),
+                other => panic!("expected connect event, got {:?}", other),
+            }
+        }
+        let mut fast = crate::client::WebSocketClient::connect(&url).await.unwrap();
+        while server.active_connections().await < 4 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let value = "x".repeat(15 * 1024);
+        let total = 2_000;
+        let mut slowest = Duration::ZERO;
+        for i in 0..total {
+            let msg = SyncMessage::new(
+                format!("KEY_{}", i),
+                value.clone(),
+                100,
+                "machine-a".to_string(),
+                false,
+            );
+            let started = Instant::now();
+            server.broadcast(&msg).await.unwrap();
+            slowest = slowest.max(started.elapsed());
+            tokio::task::yield_now().await;
+        }
+
+        // The stuck clients are waited for together, not one after another
+        assert!(slowest < SLOW_CLIENT_TIMEOUT * 2, "waited {:?}", slowest);
+        assert_eq!(server.active_connections().await, 1);
+        let mut received = 0;
+        while received < total {
+            if let Some(WireMessage::Sync(_)) = fast.receive().await.unwrap() {
+                received += 1;
+            }
+        }
+        let mut lost = Vec::new();
+        while lost.len() < stalled.len() {
+            if let MeshEvent::PeerLost { peer } = rx.recv().await.unwrap() {
+                lost.push(peer);
+            }
+        }
+        lost.sort();
+        stalled.sort();
+        assert_eq!(lost, stalled);
+    }
+
+    #[cfg(feature = "faults")]
+    #[tokio::test]
+    async fn test_broadcast_injects_faults() {
+        let faults = FaultConfig {
+            duplicate_rate: 1.0,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let server = EmbeddedServer::start_with(
+            0,
+            EventBus::new(),
+            ServerOptions {
+                faults: Some(faults),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+        while server.active_connections().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let msg = SyncMessage::new(
+            "KEY".to_string(),
+            "value".to_string(),
+            100,
+            "machine-a".to_string(),
+            false,
+        );
+        server.broadcast(&msg).await.unwrap();
+
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(WireMessage::Hello { .. })
+        ));
+        for _ in 0..2 {
+            match client.receive().await.unwrap() {
+                Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "KEY"),
+                other => panic!("expected duplicated change, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peers_are_refused_and_dropped() {
+        use crate::client::WebSocketClient;
+
+        let (a, b) = (
+            Arc::new(DeviceKey::generate()),
+            Arc::new(DeviceKey::generate()),
+        );
+        let server = EmbeddedServer::start_with(
+            0,
+            EventBus::new(),
+            ServerOptions {
+                access: PeerAccess {
+                    allowed: Vec::new(),
+                    blocked: vec![b.public_key()],
+                },
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        assert!(WebSocketClient::connect_with(&url, &signed_by(&b))
+            .await
+            .is_err());
+        // b can't get around the block by not proving its key
+        let mut anonymous = WebSocketClient::connect(&url).await.unwrap();
+        assert!(anonymous.receive().await.unwrap().is_none());
+        let _a = WebSocketClient::connect_with(&url, &signed_by(&a))
+            .await
+            .unwrap();
+        let _c = WebSocketClient::connect_with(&url, &signed_by(&Arc::new(DeviceKey::generate())))
+            .await
+            .unwrap();
+        while server.active_connections().await < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let dropped = server.set_peer_access(PeerAccess {
+            allowed: Vec::new(),
+            blocked: vec![b.public_key(), a.public_key()],
+        });
+        assert_eq!(dropped, 1);
+        assert_eq!(server.active_connections().await, 1);
+
+        // A revoked machine told to wipe is let in for just that
+        server.hold_wipe(SyncMessage::new(
+            revoke::revoked_key(&b.public_key()),
+            "{}".to_string(),
+            100,
+            "machine-c".to_string(),
+            false,
+        ));
+        let mut revoked = WebSocketClient::connect_with(&url, &signed_by(&b))
+            .await
+            .unwrap();
+        match revoked.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => {
+                assert_eq!(msg.key, revoke::revoked_key(&b.public_key()))
+            }
+            other => panic!("expected the wipe, got {:?}", other),
+        }
+        assert!(revoked.receive().await.unwrap().is_none());
+        assert_eq!(server.active_connections().await, 1);
+        assert!(WebSocketClient::connect_with(&url, &signed_by(&b))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_device_keys_must_be_proven() {
+        use crate::client::WebSocketClient;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let a = Arc::new(DeviceKey::generate());
+        let server = EmbeddedServer::start_with(
+            0,
+            EventBus::new(),
+            ServerOptions {
+                access: PeerAccess {
+                    allowed: vec![a.public_key()],
+                    blocked: Vec::new(),
+                },
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        let _a = WebSocketClient::connect_with(&url, &signed_by(&a))
+            .await
+            .unwrap();
+        while server.active_connections().await < 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.links()[0].peer_id, Some(a.public_key()));
+
+        // Anonymous clients can't be on the allowlist: closed before hello
+        let mut anonymous = WebSocketClient::connect(&url).await.unwrap();
+        assert!(anonymous.receive().await.unwrap().is_none());
+
+        // Naming a's key takes a's signature over our own nonce
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            DEVICE_KEY_HEADER,
+            HeaderValue::from_str(&a.public_key()).unwrap(),
+        );
+        request
+            .headers_mut()
+            .insert(CHALLENGE_HEADER, HeaderValue::from_static("1"));
+        let (mut impostor, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let nonce = response.headers()[CHALLENGE_HEADER].to_str().unwrap();
+        let payload = access::challenge_payload(nonce, &a.public_key());
+        let proof = WireMessage::Proof {
+            signature: DeviceKey::generate().sign_bytes(&payload),
+        };
+        impostor
+            .send(Message::Text(serde_json::to_string(&proof).unwrap()))
+            .await
+            .unwrap();
+        match impostor.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        assert_eq!(server.active_connections().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resumed_client_gets_only_missed_changes() {
+        use crate::client::WebSocketClient;
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let server = EmbeddedServer::start(0, events).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let change = |key: &str| {
+            SyncMessage::new(
+                key.to_string(),
+                "value".to_string(),
+                100,
+                "machine-a".to_string(),
+                false,
+            )
+        };
+
+        let mut client = WebSocketClient::connect(&url).await.unwrap();
+        let token = client.session().unwrap().to_string();
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            MeshEvent::PeerConnected { .. }
+        ));
+        server.broadcast(&change("SEEN")).await.unwrap();
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(WireMessage::Hello { .. })
+        ));
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(WireMessage::Sync(_))
+        ));
+        // The pong confirms SEEN arrived
+        server.ping_all();
+        while server.links()[0].rtt_ms.is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        drop(client);
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            MeshEvent::PeerLost { .. }
+        ));
+        server.broadcast(&change("MISSED")).await.unwrap();
+
+        let resume = ConnectOptions {
+            resume: Some(token.clone()),
+            ..Default::default()
+        };
+        let mut client = WebSocketClient::connect_with(&url, &resume).await.unwrap();
+        assert_eq!(client.session(), Some(token.as_str()));
+        match rx.recv().await.unwrap() {
+            MeshEvent::PeerResumed { missed, .. } => assert_eq!(missed, 1),
+            other => panic!("expected resume event, got {:?}", other),
+        }
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(WireMessage::Hello { .. })
+        ));
+        match client.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "MISSED"),
+            other => panic!("expected the missed change, got {:?}", other),
+        }
+
+        // A session is claimed once; a second try starts afresh
+        drop(client);
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            MeshEvent::PeerLost { .. }
+        ));
+        let _first = WebSocketClient::connect_with(&url, &resume).await.unwrap();
+        let second = WebSocketClient::connect_with(&url, &resume).await.unwrap();
+        assert_ne!(second.session(), Some(token.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_binary_format_is_negotiated_per_client() {
+        use crate::client::WebSocketClient;
+
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let binary = ConnectOptions {
+            wire_format: WireFormat::Binary,
+            ..Default::default()
+        };
+        let mut fast = WebSocketClient::connect_with(&url, &binary).await.unwrap();
+        let mut plain = WebSocketClient::connect(&url).await.unwrap();
+        assert_eq!(fast.wire_format(), WireFormat::Binary);
+        assert_eq!(plain.wire_format(), WireFormat::Json);
+        while server.active_connections().await < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let msg = SyncMessage::new(
+            "KEY".to_string(),
+            "value".to_string(),
+            100,
+            "machine-a".to_string(),
+            false,
+        );
+        server.broadcast(&msg).await.unwrap();
+        for client in [&mut fast, &mut plain] {
+            assert!(matches!(
+                client.receive().await.unwrap(),
+                Some(WireMessage::Hello { .. })
+            ));
+            match client.receive().await.unwrap() {
+                Some(WireMessage::Sync(received)) => assert_eq!(received.value, "value"),
+                other => panic!("expected change, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_only_get_subscribed_namespaces() {
+        use crate::client::WebSocketClient;
+
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let prod_only = ConnectOptions {
+            topics: Topics::new(&["prod".to_string()]),
+            ..Default::default()
+        };
+        let mut prod = WebSocketClient::connect_with(&url, &prod_only)
+            .await
+            .unwrap();
+        let mut all = WebSocketClient::connect(&url).await.unwrap();
+        while server.active_connections().await < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let change = |key: &str| {
+            SyncMessage::new(
+                key.to_string(),
+                "value".to_string(),
+                100,
+                "machine-a".to_string(),
+                false,
+            )
+        };
+        server.broadcast(&change("dev/DB_URL")).await.unwrap();
+        server.broadcast(&change("prod/DB_URL")).await.unwrap();
+
+        for (client, expected) in [
+            (&mut prod, &["prod/DB_URL"][..]),
+            (&mut all, &["dev/DB_URL", "prod/DB_URL"][..]),
+        ] {
+            assert!(matches!(
+                client.receive().await.unwrap(),
+                Some(WireMessage::Hello { .. })
+            ));
+            for key in expected {
+                match client.receive().await.unwrap() {
+                    Some(WireMessage::Sync(msg)) => assert_eq!(&msg.key, key),
+                    other => panic!("expected {}, got {:?}", key, other),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_limit_who_joins_and_what_they_get() {
+        use crate::client::WebSocketClient;
+        use crate::users::Role;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let keys: HashMap<&str, Arc<DeviceKey>> = ["a", "b", "c", "g"]
+            .into_iter()
+            .map(|name| (name, Arc::new(DeviceKey::generate())))
+            .collect();
+        let identity = |name: &str| signed_by(&keys[name]);
+        let user = |name: &str, role: Role, namespaces: &[&str]| UserConfig {
+            name: name.to_string(),
+            role,
+            namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+            devices: vec![keys[name].public_key()],
+            expires: Default::default(),
+        };
+        let users = Users::new(vec![
+            user("a", Role::Admin, &[]),
+            user("b", Role::Reader, &["prod"]),
+        ]);
+        let server = EmbeddedServer::start_with(
+            0,
+            EventBus::new(),
+            ServerOptions {
+                users: users.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        assert!(WebSocketClient::connect_with(&url, &identity("c"))
+            .await
+            .is_err());
+        let mut anonymous = WebSocketClient::connect(&url).await.unwrap();
+        assert!(anonymous.receive().await.unwrap().is_none());
+        // Naming an enrolled key without proving it is joining anonymously
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            DEVICE_KEY_HEADER,
+            HeaderValue::from_str(&keys["a"].public_key()).unwrap(),
+        );
+        let (mut impostor, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        match impostor.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        let mut a = WebSocketClient::connect_with(&url, &identity("a"))
+            .await
+            .unwrap();
+        // Asks for everything, but a reader of prod gets only prod
+        let mut b = WebSocketClient::connect_with(&url, &identity("b"))
+            .await
+            .unwrap();
+        while server.active_connections().await < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let change = |key: &str| {
+            SyncMessage::new(
+                key.to_string(),
+                "value".to_string(),
+                100,
+                "machine-a".to_string(),
+                false,
+            )
+        };
+        server.broadcast(&change("dev/DB_URL")).await.unwrap();
+        server.broadcast(&change("prod/DB_URL")).await.unwrap();
+        for (client, expected) in [
+            (&mut b, &["prod/DB_URL"][..]),
+            (&mut a, &["dev/DB_URL", "prod/DB_URL"][..]),
+        ] {
+            assert!(matches!(
+                client.receive().await.unwrap(),
+                Some(WireMessage::Hello { .. })
+            ));
+            for key in expected {
+                match client.receive().await.unwrap() {
+                    Some(WireMessage::Sync(msg)) => assert_eq!(&msg.key, key),
+                    other => panic!("expected {}, got {:?}", key, other),
+                }
+            }
+        }
+
+        // Only clients whose user changed are dropped
+        let mut fewer = users.clone();
+        fewer.remove("b").unwrap();
+        assert_eq!(server.set_users(fewer), 1);
+        assert_eq!(server.active_connections().await, 1);
+
+        // A guest is dropped once its enrollment runs out, and refused after
+        let expires_at = clock::now() + 1;
+        let mut guest = user("g", Role::Writer, &[]);
+        guest.expires.insert(keys["g"].public_key(), expires_at);
+        let mut with_guest = users.clone();
+        with_guest.users.push(guest);
+        server.set_users(with_guest);
+        let _g = WebSocketClient::connect_with(&url, &identity("g"))
+            .await
+            .unwrap();
+        while server.active_connections().await < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.drop_expired_users(), 0);
+        while clock::now() < expires_at {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(server.drop_expired_users(), 1);
+        assert!(WebSocketClient::connect_with(&url, &identity("g"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pings_measure_links_both_ways() {
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let key = Arc::new(DeviceKey::generate());
+        let mut client = crate::client::WebSocketClient::connect_with(&url, &signed_by(&key))
+            .await
+            .unwrap();
+        while server.active_connections().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // A client is known by the device key it proved, its server by nothing
+        assert_eq!(server.links()[0].peer_id, Some(key.public_key()));
+        assert_eq!(client.link(PeerKind::LanServer).peer_id, None);
+
+        // Frames sent before each ping are confirmed by its pong
+        let msg = SyncMessage::new("A".to_string(), "1".to_string(), 1, "m".to_string(), false);
+        server.broadcast(&msg).await.unwrap();
+        client.send(msg).await.unwrap();
+        assert_eq!(server.links()[0].behind(), Some(1));
+        assert_eq!(client.link(PeerKind::LanServer).behind(), Some(1));
+
+        server.ping_all();
+        client.ping().await.unwrap();
+        let answered = |link: &PeerLink| {
+            link.rtt_ms.is_some()
+                && link.pings == 1
+                && link.behind() == Some(0)
+                && link.last_received_ms.is_some()
+        };
+        for _ in 0..100 {
+            if answered(&server.links()[0]) && answered(&client.link(PeerKind::LanServer)) {
+                assert_eq!(server.links()[0].acked_seq, Some(1));
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!(
+            "pings unanswered: {:?} / {:?}",
+            server.links(),
+            client.link(PeerKind::LanServer)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_limit_spaces_out_frames() {
+        // 8 kbps: 1000 bytes a second, after a first second's worth
+        let options = ServerOptions {
+            network: NetworkConfig {
+                max_upload_kbps: 8,
+                max_download_kbps: 0,
+            },
+            ..Default::default()
+        };
+        let server = EmbeddedServer::start_with(0, EventBus::new(), options)
+            .await
+            .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+        while server.active_connections().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let started = std::time::Instant::now();
+        for i in 0..3 {
+            let msg = SyncMessage::new(
+                format!("KEY_{}", i),
+                "x".repeat(400),
+                100,
+                "machine-a".to_string(),
+                false,
+            );
+            server.broadcast(&msg).await.unwrap();
+        }
+        // Hello plus three changes is well over the first second's 1000 bytes
+        for _ in 0..4 {
+            client.receive().await.unwrap().unwrap();
+        }
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_upload_limit_holds_broadcasts_back_instead_of_dropping() {
+        // 800 kbps: far slower than broadcasting, so the queue fills
+        let options = ServerOptions {
+            network: NetworkConfig {
+                max_upload_kbps: 800,
+                max_download_kbps: 0,
+            },
+            ..Default::default()
+        };
+        let server = EmbeddedServer::start_with(0, EventBus::new(), options)
+            .await
+            .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut client = crate::client::WebSocketClient::connect(&url).await.unwrap();
+        while server.active_connections().await == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let count = SEND_QUEUE_LEN + 100;
+        let receiver = tokio::spawn(async move {
+            let mut received = 0;
+            while let Ok(Some(frame)) = client.receive().await {
+                if matches!(frame, WireMessage::Sync(_)) {
+                    received += 1;
+                    if received == count {
+                        break;
+                    }
+                }
+            }
+            received
+        });
+        for i in 0..count {
+            let msg = SyncMessage::new(
+                format!("KEY_{}", i),
+                "x".repeat(200),
+                100,
+                "machine-a".to_string(),
+                false,
+            );
+            server.broadcast(&msg).await.unwrap();
+        }
+        assert_eq!(server.active_connections().await, 1);
+        assert_eq!(receiver.await.unwrap(), count);
+    }
+
+    #[tokio::test]
+    async fn test_relay_isolates_meshes() {
+        use crate::client::WebSocketClient;
+        use crate::relay::{hash_token, MeshConfig};
+
+        let mesh = |name: &str, token: &str| MeshConfig {
+            name: name.to_string(),
+            account: String::new(),
+            token_sha256: hash_token(token),
+            max_connections: 5,
+            users: Vec::new(),
+        };
+        let relay = RelayConfig {
+            meshes: vec![mesh("team-a", "emt_a"), mesh("team-b", "emt_b")],
+            max_connections: 0,
+            host_token_sha256: Some(hash_token("emt_host")),
+        };
+        let server = EmbeddedServer::start_with_relay(0, EventBus::new(), relay)
+            .await
+            .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+
+        assert!(WebSocketClient::connect_with_token(&url, Some("emt_guess"))
+            .await
+            .is_err());
+        assert!(WebSocketClient::connect(&url).await.is_err());
+        let mut host = WebSocketClient::connect_with_token(&url, Some("emt_host"))
+            .await
+            .unwrap();
+        let mut a1 = WebSocketClient::connect_with_token(&url, Some("emt_a"))
+            .await
+            .unwrap();
+        let mut a2 = WebSocketClient::connect_with_token(&url, Some("emt_a"))
+            .await
+            .unwrap();
+        let mut b1 = WebSocketClient::connect_with_token(&url, Some("emt_b"))
+            .await
+            .unwrap();
+        for client in [&mut host, &mut a1, &mut a2, &mut b1] {
+            assert!(matches!(
+                client.receive().await.unwrap(),
+                Some(WireMessage::Hello { .. })
+            ));
+        }
+        while server.active_connections().await < 4 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let change = |key: &str| {
+            SyncMessage::new(
+                key.to_string(),
+                "value".to_string(),
+                100,
+                "machine-a".to_string(),
+                false,
+            )
+        };
+        a1.send(change("TEAM_A_KEY")).await.unwrap();
+        match a2.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "TEAM_A_KEY"),
+            other => panic!("expected relayed change, got {:?}", other),
+        }
+
+        // The host's own changes and team A's never reach team B
+        server.broadcast(&change("HOST_KEY")).await.unwrap();
+        b1.send(change("TEAM_B_KEY")).await.unwrap();
+        a1.send(change("TEAM_A_LATER")).await.unwrap();
+        match a2.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "TEAM_A_LATER"),
+            other => panic!("expected relayed change, got {:?}", other),
+        }
+        let nothing =
+            tokio::time::timeout(std::time::Duration::from_millis(200), b1.receive()).await;
+        assert!(nothing.is_err(), "team B received {:?}", nothing);
+
+        // The host's mesh gets the host's changes, and no team's
+        match host.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.key, "HOST_KEY"),
+            other => panic!("expected the host's change, got {:?}", other),
+        }
+        let nothing =
+            tokio::time::timeout(std::time::Duration::from_millis(200), host.receive()).await;
+        assert!(nothing.is_err(), "the host's mesh received {:?}", nothing);
+    }
+
+    #[tokio::test]
+    async fn test_relay_answers_go_to_the_asker_only() {
+        use crate::client::WebSocketClient;
+        use crate::relay::{hash_token, MeshConfig};
+
+        let relay = RelayConfig {
+            meshes: vec![MeshConfig {
+                name: "team-a".to_string(),
+                account: String::new(),
+                token_sha256: hash_token("emt_a"),
+                max_connections: 5,
+                users: Vec::new(),
+            }],
+            max_connections: 0,
+            host_token_sha256: None,
+        };
+        let server = EmbeddedServer::start_with_relay(0, EventBus::new(), relay)
+            .await
+            .unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = WebSocketClient::connect_with_token(&url, Some("emt_a"))
+                .await
+                .unwrap();
+            assert!(matches!(
+                client.receive().await.unwrap(),
+                Some(WireMessage::Hello { .. })
+            ));
+            clients.push(client);
+        }
+        while server.active_connections().await < 3 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let [asker, answerer, bystander] = &mut clients[..] else {
+            unreachable!()
+        };
+
+        asker
+            .send_frame(&WireMessage::SeqRequest {
+                machine_id: "machine-b".to_string(),
+                seqs: vec![7],
+                asker: None,
+            })
+            .await
+            .unwrap();
+        let to = match answerer.receive().await.unwrap() {
+            Some(WireMessage::SeqRequest {
+                asker: Some(to), ..
+            }) => to,
+            other => panic!("expected a stamped request, got {:?}", other),
+        };
+        assert!(matches!(
+            bystander.receive().await.unwrap(),
+            Some(WireMessage::SeqRequest { .. })
+        ));
+
+        let mut answer = SyncMessage::new("KEY", "value", 100, "machine-b", false);
+        answer.seq = Some(7);
+        answerer.reply(to, answer).await.unwrap();
+        match asker.receive().await.unwrap() {
+            Some(WireMessage::Sync(msg)) => assert_eq!(msg.seq, Some(7)),
+            other => panic!("expected the answer, got {:?}", other),
+        }
+
+        // So is the snapshot answering a resync
+        asker
+            .send_frame(&WireMessage::SyncRequest {
+                machine_id: "machine-a".to_string(),
+                from: Some("machine-b".to_string()),
+                asker: None,
+            })
+            .await
+            .unwrap();
+        let to = match answerer.receive().await.unwrap() {
+            Some(WireMessage::SyncRequest {
+                asker: Some(to), ..
+            }) => to,
+            other => panic!("expected a stamped request, got {:?}", other),
+        };
+        assert!(matches!(
+            bystander.receive().await.unwrap(),
+            Some(WireMessage::SyncRequest { .. })
+        ));
+        let state = vec![SyncMessage::new("KEY", "value", 100, "machine-b", false)];
+        let data = snapshot::encode(&state).unwrap();
+        let frames =
+            chunking::split_snapshot("machine-b", BTreeMap::new(), &data, Some("machine-b"));
+        answerer.reply_frames(to, frames).await.unwrap();
+        match asker.receive().await.unwrap() {
+            Some(WireMessage::Snapshot { resync, .. }) => {
+                assert_eq!(resync.as_deref(), Some("machine-b"))
+            }
+            other => panic!("expected the snapshot, got {:?}", other),
+        }
+        let nothing =
+            tokio::time::timeout(std::time::Duration::from_millis(200), bystander.receive()).await;
+        assert!(nothing.is_err(), "the bystander received {:?}", nothing);
+    }
 }
@@ -0,0 +1,194 @@
+// Session resume: the LAN server names each client's session at the
+// handshake, and a client back within `RESUME_WINDOW` gets only the changes
+// it missed instead of the server's whole state
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Response header carrying the client's session token
+pub const SESSION_HEADER: &str = "x-envmesh-session";
+/// Request header a reconnecting client presents its old token in
+pub const RESUME_HEADER: &str = "x-envmesh-resume";
+
+/// How long a dropped session can be resumed
+pub const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+pub fn new_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// The last frames sent to every client, numbered from 1
+pub struct ReplayBuffer<T> {
+    frames: VecDeque<(u64, T)>,
+    last_seq: u64,
+    capacity: usize,
+}
+
+impl<T: Clone> ReplayBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            last_seq: 0,
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, frame: T) -> u64 {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.last_seq += 1;
+        self.frames.push_back((self.last_seq, frame));
+        self.last_seq
+    }
+
+    /// Sequence number of the newest frame (0 before the first)
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Every frame after `seq`, or None if some of them are gone already
+    pub fn since(&self, seq: u64) -> Option<Vec<T>> {
+        let oldest = self
+            .frames
+            .front()
+            .map_or(self.last_seq + 1, |(seq, _)| *seq);
+        if seq + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.frames
+                .iter()
+                .filter(|(frame_seq, _)| *frame_seq > seq)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+}
+
+struct Session {
+    /// Frames up to here are known to have reached the client
+    confirmed: u64,
+    /// Ping payload awaiting its pong, and the frames queued before it
+    ping: Option<(Vec<u8>, u64)>,
+    /// When the connection went away; None while connected
+    lost_at: Option<Instant>,
+}
+
+/// Sessions by token. A client proves it received frames by answering a
+/// ping queued after them, since WebSocket frames arrive in order.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a connected session whose client has every frame up to `confirmed`
+    pub fn open(&mut self, token: &str, confirmed: u64) {
+        self.sessions.insert(
+            token.to_string(),
+            Session {
+                confirmed,
+                ping: None,
+                lost_at: None,
+            },
+        );
+    }
+
+    /// Claim a dropped session for a reconnecting client: the sequence
+    /// number to replay after, or None if it is unknown, live or expired
+    pub fn resume(&mut self, token: &str, now: Instant) -> Option<u64> {
+        self.prune(now);
+        let session = self.sessions.get(token)?;
+        session.lost_at?;
+        self.sessions.remove(token).map(|session| session.confirmed)
+    }
+
+    /// A ping with `payload` was queued after frame `seq`
+    pub fn pinged(&mut self, token: &str, payload: Vec<u8>, seq: u64) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.ping = Some((payload, seq));
+        }
+    }
+
+    /// The last frame the session's client has confirmed
+    pub fn confirmed(&self, token: &str) -> Option<u64> {
+        self.sessions.get(token).map(|session| session.confirmed)
+    }
+
+    pub fn ponged(&mut self, token: &str, payload: &[u8]) {
+        let Some(session) = self.sessions.get_mut(token) else {
+            return;
+        };
+        if let Some((expected, seq)) = &session.ping {
+            if expected == payload {
+                session.confirmed = session.confirmed.max(*seq);
+                session.ping = None;
+            }
+        }
+    }
+
+    /// The session's connection went away; it can be resumed until
+    /// `RESUME_WINDOW` has passed
+    pub fn lost(&mut self, token: &str, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.lost_at = Some(now);
+            session.ping = None;
+        }
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| {
+            session
+                .lost_at
+                .is_none_or(|lost_at| now.duration_since(lost_at) < RESUME_WINDOW)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_buffer_knows_what_it_lost() {
+        let mut buffer = ReplayBuffer::new(3);
+        assert_eq!(buffer.since(0), Some(vec![]));
+        for frame in ["a", "b", "c", "d"] {
+            buffer.push(frame);
+        }
+        assert_eq!(buffer.last_seq(), 4);
+        assert_eq!(buffer.since(2), Some(vec!["c", "d"]));
+        assert_eq!(buffer.since(1), Some(vec!["b", "c", "d"]));
+        assert_eq!(buffer.since(4), Some(vec![]));
+        // "a" is gone
+        assert_eq!(buffer.since(0), None);
+    }
+
+    #[test]
+    fn test_sessions_resume_within_the_window() {
+        let now = Instant::now();
+        let mut sessions = Sessions::new();
+        sessions.open("t", 5);
+        // Still connected
+        assert_eq!(sessions.resume("t", now), None);
+
+        // A pong confirms the frames queued before its ping
+        sessions.pinged("t", vec![1], 9);
+        sessions.ponged("t", &[2]);
+        sessions.ponged("t", &[1]);
+        sessions.lost("t", now);
+        assert_eq!(sessions.resume("unknown", now), None);
+        assert_eq!(sessions.resume("t", now), Some(9));
+        // Claimed once
+        assert_eq!(sessions.resume("t", now), None);
+
+        sessions.open("t", 3);
+        sessions.lost("t", now);
+        assert_eq!(sessions.resume("t", now + RESUME_WINDOW), None);
+    }
+}
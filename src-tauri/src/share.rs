@@ -0,0 +1,490 @@
+// One-time shares (`envmesh-cli share`): one variable sealed with a fresh key
+// for someone outside the mesh. A link leaves the sealed value with the cloud
+// server, which hands it out once, and carries only its ID and the key (after
+// `#`, which is never sent to the server). An offline code carries the sealed
+// value and its key, so it opens any number of times until it expires.
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+
+use crate::access::DEVICE_KEY_HEADER;
+use crate::clock;
+use crate::crypto::Crypto;
+use crate::relay::{Admission, RelayConfig};
+use crate::signing::{self, DeviceKey};
+use crate::tls::{self, TlsConfig};
+
+/// Codes start with this
+pub const CODE_PREFIX: &str = "ems1.";
+
+/// Where servers keep shares, by ID
+pub const SHARE_PATH: &str = "/share/";
+
+/// The sharer's signature over `put_payload()`, next to its device key in
+/// `DEVICE_KEY_HEADER`
+pub const SIGNATURE_HEADER: &str = "x-envmesh-share-signature";
+
+/// Longest a share stays claimable
+pub const MAX_EXPIRES_SECS: u64 = 7 * 24 * 3600;
+
+/// Largest sealed value a server holds
+pub const MAX_SEALED_BYTES: usize = 128 * 1024;
+
+/// Most shares a server holds at once
+pub const MAX_SHARES: usize = 1000;
+
+/// How long a share request may take, either side
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request or status line and headers read
+const MAX_HEAD_BYTES: u64 = 8 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Share {
+    pub key: String,
+    pub value: String,
+    /// Unix seconds
+    pub expires_at: i64,
+}
+
+/// What a sharer signs to leave `sealed` with a server under `id`
+pub fn put_payload(id: &str, expires_at: i64, sealed: &[u8]) -> Vec<u8> {
+    format!(
+        "envmesh-share\n{}\n{}\n{}",
+        id,
+        expires_at,
+        hex::encode(Sha256::digest(sealed))
+    )
+    .into_bytes()
+}
+
+/// A duration (`clock::parse_duration()`) of up to `MAX_EXPIRES_SECS`
+pub fn parse_expires(s: &str) -> Result<u64> {
+    let secs = clock::parse_duration(s)?;
+    if secs > MAX_EXPIRES_SECS {
+        return Err(anyhow!("Shares expire after at most 7d, not {}", s.trim()));
+    }
+    Ok(secs)
+}
+
+impl Share {
+    /// Encrypt with a fresh key; the sealed bytes and the key
+    pub fn seal(&self) -> Result<(Vec<u8>, [u8; 32])> {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let sealed = Crypto::from_key(&key).encrypt(&serde_json::to_vec(self)?)?;
+        Ok((sealed, key))
+    }
+
+    /// Decrypt, refusing shares past their expiry
+    pub fn open(sealed: &[u8], key: &[u8]) -> Result<Self> {
+        let key: [u8; 32] = key.try_into().map_err(|_| anyhow!("Invalid share key"))?;
+        let plaintext = Crypto::from_key(&key)
+            .decrypt(sealed)
+            .map_err(|_| anyhow!("The share doesn't match its key"))?;
+        let share: Self = serde_json::from_slice(&plaintext)?;
+        if clock::now() >= share.expires_at {
+            return Err(anyhow!("This share has expired"));
+        }
+        Ok(share)
+    }
+
+    /// A self-contained code: whoever holds it can open it until it expires
+    pub fn code(&self) -> Result<String> {
+        let (sealed, key) = self.seal()?;
+        Ok(format!(
+            "{}{}.{}",
+            CODE_PREFIX,
+            BASE64.encode(sealed),
+            BASE64.encode(key)
+        ))
+    }
+
+    /// Leave the share with the server at `server_url` (ws:// or wss://),
+    /// signed by `signer`, whose machine must be connected to it; the link
+    /// that claims it, once
+    pub async fn upload(
+        &self,
+        server_url: &str,
+        tls: &TlsConfig,
+        mesh_token: Option<&str>,
+        signer: &DeviceKey,
+    ) -> Result<String> {
+        let (sealed, key) = self.seal()?;
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let id = hex::encode(id);
+        let url = format!("{}{}{}", server_url.trim_end_matches('/'), SHARE_PATH, id);
+
+        let mut headers = vec![
+            format!("x-envmesh-expires: {}", self.expires_at),
+            format!("{}: {}", DEVICE_KEY_HEADER, signer.public_key()),
+            format!(
+                "{}: {}",
+                SIGNATURE_HEADER,
+                signer.sign_bytes(&put_payload(&id, self.expires_at, &sealed))
+            ),
+        ];
+        if let Some(token) = mesh_token {
+            headers.push(format!("Authorization: Bearer {}", token));
+        }
+        let (status, body) = request(&url.parse()?, tls, "PUT", &headers, &sealed).await?;
+        if status != 201 {
+            return Err(anyhow!(
+                "The server refused the share ({}: {})",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        Ok(format!("{}#{}", url, BASE64.encode(key)))
+    }
+}
+
+/// Open a code, or claim a link's share from its server (which then forgets
+/// it)
+pub async fn claim(code: &str, tls: &TlsConfig) -> Result<Share> {
+    let code = code.trim();
+    if let Some(code) = code.strip_prefix(CODE_PREFIX) {
+        let (sealed, key) = code
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Invalid share code"))?;
+        return Share::open(&decode(sealed)?, &decode(key)?);
+    }
+
+    let (url, key) = code
+        .split_once('#')
+        .filter(|(url, _)| url.contains(SHARE_PATH))
+        .ok_or_else(|| anyhow!("Not a share code or link"))?;
+    let (status, body) = request(&url.parse()?, tls, "GET", &[], &[]).await?;
+    match status {
+        200 => Share::open(&body, &decode(key)?),
+        404 => Err(anyhow!("This share was already claimed, or has expired")),
+        _ => Err(anyhow!("The server answered {}", status)),
+    }
+}
+
+fn decode(part: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(part)
+        .map_err(|_| anyhow!("Invalid share code"))
+}
+
+/// One request over a fresh connection; the status and body
+async fn request(
+    uri: &Uri,
+    tls: &TlsConfig,
+    method: &str,
+    headers: &[String],
+    body: &[u8],
+) -> Result<(u16, Vec<u8>)> {
+    let host = uri
+        .authority()
+        .ok_or_else(|| anyhow!("No host in {}", uri))?
+        .to_string();
+    let exchange = async {
+        let mut transport = tls::connect(uri, tls).await?;
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            uri.path(),
+            host,
+            body.len()
+        );
+        for header in headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        transport.write_all(head.as_bytes()).await?;
+        transport.write_all(body).await?;
+
+        let mut reader = BufReader::new(transport);
+        let (status_line, headers) = read_head(&mut reader).await?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("Not an HTTP response: {:?}", status_line))?;
+        let body = read_body(&mut reader, &headers).await?;
+        Ok((status, body))
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("{} didn't answer within {:?}", host, REQUEST_TIMEOUT))?
+}
+
+/// The first line and the headers (names lowercased) of a request or
+/// response
+async fn read_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<(String, HashMap<String, String>)> {
+    let mut reader = reader.take(MAX_HEAD_BYTES);
+    let mut first = String::new();
+    reader.read_line(&mut first).await?;
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    Ok((first.trim().to_string(), headers))
+}
+
+async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    headers: &HashMap<String, String>,
+) -> Result<Vec<u8>> {
+    let length: usize = headers
+        .get("content-length")
+        .map(|length| length.parse())
+        .transpose()?
+        .unwrap_or(0);
+    if length > MAX_SEALED_BYTES {
+        return Err(anyhow!("Share is over {} bytes", MAX_SEALED_BYTES));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Shares a server holds until they are claimed or expire
+#[derive(Debug, Default)]
+pub struct ShareStore {
+    /// Sealed value and expiry by ID
+    shares: HashMap<String, (Vec<u8>, i64)>,
+}
+
+impl ShareStore {
+    /// Hold a share; false when the store is full
+    pub fn put(&mut self, id: &str, sealed: Vec<u8>, expires_at: i64) -> bool {
+        let now = clock::now();
+        self.shares.retain(|_, (_, expires_at)| *expires_at > now);
+        if self.shares.len() >= MAX_SHARES {
+            return false;
+        }
+        self.shares.insert(id.to_string(), (sealed, expires_at));
+        true
+    }
+
+    /// Hand a share out, once
+    pub fn take(&mut self, id: &str) -> Option<Vec<u8>> {
+        let (sealed, expires_at) = self.shares.remove(id)?;
+        (expires_at > clock::now()).then_some(sealed)
+    }
+}
+
+/// Answer a share request instead of a WebSocket handshake. Returns whether
+/// `stream` was one (and has been answered); otherwise nothing has been read
+/// from it. Only a machine `connected(mesh, device_key)` (one that proved
+/// its key at a handshake, in the mesh its token names) may leave a share.
+pub async fn answer_share(
+    stream: &mut TcpStream,
+    store: &Mutex<ShareStore>,
+    relay: &RelayConfig,
+    connected: impl Fn(&str, &str) -> bool,
+) -> Result<bool> {
+    let mut start = [0u8; 4 + SHARE_PATH.len()];
+    let peeked = tokio::time::timeout(REQUEST_TIMEOUT, stream.peek(&mut start))
+        .await
+        .map_err(|_| anyhow!("Nothing sent within {:?}", REQUEST_TIMEOUT))??;
+    let start = &start[..peeked];
+    if !start.ends_with(SHARE_PATH.as_bytes())
+        || !(start.starts_with(b"GET ") || start.starts_with(b"PUT "))
+    {
+        return Ok(false);
+    }
+
+    let mut reader = BufReader::new(&mut *stream);
+    let (status, body) = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let (request_line, headers) = read_head(&mut reader).await?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let id = parts
+            .next()
+            .and_then(|path| path.strip_prefix(SHARE_PATH))
+            .unwrap_or_default()
+            .to_string();
+        if method == "GET" {
+            let taken = store.lock().unwrap_or_else(|e| e.into_inner()).take(&id);
+            return Ok::<_, anyhow::Error>(match taken {
+                Some(sealed) => (200, sealed),
+                None => (404, b"no such share".to_vec()),
+            });
+        }
+
+        let token = headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let mesh = match relay.admit(token, &HashMap::new()) {
+            Admission::Admit(mesh) => mesh,
+            Admission::Reject(status, reason) => return Ok((status, reason.as_bytes().to_vec())),
+        };
+        let (Some(sharer), Some(signature)) = (
+            headers.get(DEVICE_KEY_HEADER),
+            headers.get(SIGNATURE_HEADER),
+        ) else {
+            return Ok((401, b"unsigned share".to_vec()));
+        };
+        let expires_at = headers
+            .get("x-envmesh-expires")
+            .and_then(|expires| expires.parse::<i64>().ok())
+            .filter(|expires| *expires <= clock::now() + MAX_EXPIRES_SECS as i64);
+        let Some(expires_at) = expires_at else {
+            return Ok((400, b"missing or invalid x-envmesh-expires".to_vec()));
+        };
+        if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok((400, b"invalid share ID".to_vec()));
+        }
+        let sealed = match read_body(&mut reader, &headers).await {
+            Ok(sealed) => sealed,
+            Err(_) => return Ok((413, b"share too large".to_vec())),
+        };
+        if signing::verify_bytes(sharer, &put_payload(&id, expires_at, &sealed), signature).is_err()
+        {
+            return Ok((401, b"bad share signature".to_vec()));
+        }
+        if !connected(&mesh, sharer) {
+            return Ok((
+                403,
+                b"only machines connected to this server may share".to_vec(),
+            ));
+        }
+        let held = store
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(&id, sealed, expires_at);
+        Ok(if held {
+            (201, Vec::new())
+        } else {
+            (503, b"too many shares held".to_vec())
+        })
+    })
+    .await
+    .map_err(|_| anyhow!("Incomplete share request"))??;
+
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::server::EmbeddedServer;
+
+    fn share(expires_in: i64) -> Share {
+        Share {
+            key: "DB_PASSWORD".to_string(),
+            value: "hunter2".to_string(),
+            expires_at: clock::now() + expires_in,
+        }
+    }
+
+    #[test]
+    fn test_parse_expires() {
+        assert_eq!(parse_expires("90").unwrap(), 90);
+        assert_eq!(parse_expires("30m").unwrap(), 1800);
+        assert_eq!(parse_expires("1h").unwrap(), 3600);
+        assert_eq!(parse_expires("7d").unwrap(), MAX_EXPIRES_SECS);
+        for bad in ["", "0", "1w", "h", "8d"] {
+            assert!(parse_expires(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codes_open_until_they_expire() {
+        let tls = TlsConfig::default();
+        let code = share(3600).code().unwrap();
+        assert!(code.starts_with(CODE_PREFIX));
+        assert!(!code.contains("hunter2"));
+        assert_eq!(claim(&code, &tls).await.unwrap().value, "hunter2");
+
+        let expired = share(-1).code().unwrap();
+        assert!(claim(&expired, &tls).await.is_err());
+        // Another share's key doesn't open it
+        let other = share(3600).code().unwrap();
+        let mixed = format!(
+            "{}.{}",
+            code.rsplit_once('.').unwrap().0,
+            other.rsplit_once('.').unwrap().1
+        );
+        assert!(claim(&mixed, &tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_links_are_claimed_once() {
+        use crate::client::{ConnectOptions, WebSocketClient};
+        use std::sync::Arc;
+
+        let tls = TlsConfig::default();
+        let server = EmbeddedServer::start(0, EventBus::new()).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}", server.port());
+        let key = Arc::new(DeviceKey::generate());
+
+        // Only a machine connected to the server, as the key it signs with
+        assert!(share(3600).upload(&url, &tls, None, &key).await.is_err());
+        let options = ConnectOptions {
+            signer: Some(Arc::clone(&key)),
+            ..Default::default()
+        };
+        let _client = WebSocketClient::connect_with(&url, &options).await.unwrap();
+        while server.active_connections().await < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stranger = DeviceKey::generate();
+        assert!(share(3600)
+            .upload(&url, &tls, None, &stranger)
+            .await
+            .is_err());
+        let unsigned = request(
+            &format!("{}{}{}", url, SHARE_PATH, "0".repeat(32))
+                .parse()
+                .unwrap(),
+            &tls,
+            "PUT",
+            &[format!("x-envmesh-expires: {}", clock::now() + 60)],
+            b"sealed",
+        )
+        .await
+        .unwrap();
+        assert_eq!(unsigned.0, 401);
+
+        let link = share(3600).upload(&url, &tls, None, &key).await.unwrap();
+        assert!(link.starts_with(&format!("{}{}", url, SHARE_PATH)));
+        assert_eq!(claim(&link, &tls).await.unwrap().key, "DB_PASSWORD");
+        assert!(claim(&link, &tls).await.is_err());
+
+        // Without the key the server's copy is no use
+        let link = share(3600).upload(&url, &tls, None, &key).await.unwrap();
+        let (without_key, _) = link.split_once('#').unwrap();
+        assert!(claim(without_key, &tls).await.is_err());
+
+        let mut store = ShareStore::default();
+        assert!(store.put("a", vec![1], clock::now() - 1));
+        assert!(store.take("a").is_none());
+    }
+}
@@ -0,0 +1,252 @@
+// `envmesh-cli shell` sessions: a subshell started with the mesh's variables
+// in its environment. Overrides made in it (`set --session`) live in a file
+// under the data directory until the shell exits; the daemon never sees
+// them, so they are neither stored nor synced. The file is readable by its
+// owner only, and one left by a shell that died is removed when the next
+// session starts.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+/// Set in the subshell to the session's ID
+pub const SESSION_ENV: &str = "ENVMESH_SESSION";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ShellSessionFile {
+    /// Namespace loaded under bare names, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// Variable name -> value, as the shell sees them
+    #[serde(default)]
+    overrides: BTreeMap<String, String>,
+}
+
+pub struct ShellSession {
+    id: String,
+    path: PathBuf,
+    /// Held by the process running the shell for as long as it lives, on
+    /// `<id>.lock`: a session whose lock is free has ended
+    lock: Option<File>,
+}
+
+impl ShellSession {
+    /// Start a session and record it, first removing sessions whose shell
+    /// died without ending them
+    pub fn create(data_dir: &Path, namespace: Option<&str>) -> Result<Self> {
+        let dir = sessions_dir(data_dir);
+        std::fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        remove_ended(&dir);
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let lock = owner_only()
+            .create_new(true)
+            .open(lock_path(&dir, &id))
+            .with_context(|| format!("Failed to create a session in {}", dir.display()))?;
+        lock.lock()?;
+        let session = Self {
+            path: dir.join(format!("{}.json", id)),
+            id,
+            lock: Some(lock),
+        };
+        let file = ShellSessionFile {
+            namespace: namespace.map(str::to_string),
+            ..Default::default()
+        };
+        owner_only()
+            .create_new(true)
+            .open(&session.path)
+            .and_then(|mut out| std::io::Write::write_all(&mut out, &serde_json::to_vec(&file)?))
+            .with_context(|| format!("Failed to write {}", session.path.display()))?;
+        Ok(session)
+    }
+
+    /// A running session by ID (None if it doesn't exist or has ended)
+    pub fn open(data_dir: &Path, id: &str) -> Option<Self> {
+        // IDs come from the environment: never let one name another path
+        let id = uuid::Uuid::parse_str(id).ok()?.to_string();
+        let path = sessions_dir(data_dir).join(format!("{}.json", id));
+        path.exists().then_some(Self {
+            id,
+            path,
+            lock: None,
+        })
+    }
+
+    /// The session this process runs in, if any
+    pub fn current(data_dir: &Path) -> Option<Self> {
+        Self::open(data_dir, &std::env::var(SESSION_ENV).ok()?)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn namespace(&self) -> Result<Option<String>> {
+        Ok(self.read()?.namespace)
+    }
+
+    pub fn overrides(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self.read()?.overrides)
+    }
+
+    /// Override `name` for the rest of the session
+    pub fn set(&self, name: &str, value: &str) -> Result<()> {
+        let mut file = self.read()?;
+        file.overrides.insert(name.to_string(), value.to_string());
+        self.write(&file)
+    }
+
+    /// Forget the session and its overrides
+    pub fn end(self) -> Result<()> {
+        std::fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove {}", self.path.display()))?;
+        if let Some(lock) = self.lock {
+            drop(lock);
+            let _ = std::fs::remove_file(self.path.with_extension("lock"));
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> Result<ShellSessionFile> {
+        let bytes = std::fs::read(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to read {}", self.path.display()))
+    }
+
+    /// Overwrite the session's file, which must still exist (so an ended
+    /// session isn't brought back)
+    fn write(&self, file: &ShellSessionFile) -> Result<()> {
+        owner_only()
+            .truncate(true)
+            .open(&self.path)
+            .and_then(|mut out| std::io::Write::write_all(&mut out, &serde_json::to_vec(file)?))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+fn sessions_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("sessions")
+}
+
+fn lock_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.lock", id))
+}
+
+/// Opens files for writing, creating them readable by their owner only
+fn owner_only() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// Remove the files of sessions nobody holds the lock of any more: their
+/// shell, or the envmesh-cli running it, was killed
+fn remove_ended(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let lock = path.with_extension("lock");
+        let ended = match File::open(&lock) {
+            Ok(file) => !matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+            Err(_) => true,
+        };
+        if ended {
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(&lock);
+        }
+    }
+}
+
+/// The shell `envmesh-cli shell` starts without `--program`
+pub fn default_shell() -> String {
+    if let Ok(shell) = std::env::var("SHELL") {
+        return shell;
+    }
+    if cfg!(windows) {
+        "powershell".to_string()
+    } else {
+        "/bin/sh".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_overrides_last_until_it_ends() {
+        let dir =
+            std::env::temp_dir().join(format!("envmesh-shellsession-{}", uuid::Uuid::new_v4()));
+        let session = ShellSession::create(&dir, Some("prod")).unwrap();
+        assert!(session.overrides().unwrap().is_empty());
+
+        let reopened = ShellSession::open(&dir, session.id()).unwrap();
+        reopened
+            .set("DB_URL", "postgres://localhost/scratch")
+            .unwrap();
+        assert_eq!(
+            session
+                .overrides()
+                .unwrap()
+                .get("DB_URL")
+                .map(String::as_str),
+            Some("postgres://localhost/scratch")
+        );
+        assert_eq!(session.namespace().unwrap().as_deref(), Some("prod"));
+        assert!(ShellSession::open(&dir, "../../etc/passwd").is_none());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&session.path), 0o600);
+            assert_eq!(mode(&sessions_dir(&dir)), 0o700);
+        }
+
+        let id = session.id().to_string();
+        session.end().unwrap();
+        assert!(ShellSession::open(&dir, &id).is_none());
+        assert!(reopened.set("DB_URL", "back again").is_err());
+        assert!(ShellSession::open(&dir, &id).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sessions_of_dead_shells_are_removed_on_the_next_start() {
+        let dir =
+            std::env::temp_dir().join(format!("envmesh-shellsession-{}", uuid::Uuid::new_v4()));
+        let live = ShellSession::create(&dir, None).unwrap();
+        let dead = ShellSession::create(&dir, None).unwrap();
+        dead.set("TOKEN", "secret").unwrap();
+        let dead_id = dead.id().to_string();
+        // Killed: its lock goes with the process, its file stays
+        drop(dead);
+        assert!(ShellSession::open(&dir, &dead_id).is_some());
+
+        let next = ShellSession::create(&dir, None).unwrap();
+        assert!(ShellSession::open(&dir, &dead_id).is_none());
+        assert!(!lock_path(&sessions_dir(&dir), &dead_id).exists());
+        assert!(ShellSession::open(&dir, live.id()).is_some());
+
+        live.end().unwrap();
+        next.end().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
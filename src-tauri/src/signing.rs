@@ -0,0 +1,222 @@
+// Device keys: every sync message is signed by the machine sending it, and
+// incoming messages are checked against the keys listed as trusted
+use anyhow::{anyhow, Context, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::client::SyncMessage;
+
+/// Signature carried by a sync message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageSignature {
+    /// Signer's public key (base64)
+    pub key: String,
+    /// Ed25519 signature over the change and its message ID (base64)
+    pub sig: String,
+}
+
+/// This machine's signing key
+pub struct DeviceKey {
+    signing: SigningKey,
+}
+
+// Hand-written so the secret half can't end up in a log
+impl std::fmt::Debug for DeviceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl DeviceKey {
+    /// A new key that lives only as long as this process
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Self {
+            signing: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Read the key at `path`, or None if there is none yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let encoded = match std::fs::read_to_string(path) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        let secret: [u8; 32] = BASE64
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("{} is not a device key", path.display()))?;
+        Ok(Some(Self {
+            signing: SigningKey::from_bytes(&secret),
+        }))
+    }
+
+    /// Read the key at `path`, creating it (readable only by us) first if needed
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if let Some(key) = Self::load(path)? {
+            return Ok(key);
+        }
+
+        let key = Self::generate();
+        let encoded = BASE64.encode(key.signing.to_bytes());
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(encoded.as_bytes()))
+                .with_context(|| format!("Writing {}", path.display()))?;
+        }
+        #[cfg(not(unix))]
+        std::fs::write(path, encoded).with_context(|| format!("Writing {}", path.display()))?;
+
+        tracing::info!("Created device key {}", path.display());
+        Ok(key)
+    }
+
+    /// Public key to list in other machines' `[security] trusted_keys`
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.verifying_key().to_bytes())
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing.verifying_key()
+    }
+
+    pub fn sign(&self, msg: &mut SyncMessage) {
+        let sig = self.signing.sign(&payload(msg));
+        msg.signature = Some(MessageSignature {
+            key: self.public_key(),
+            sig: BASE64.encode(sig.to_bytes()),
+        });
+    }
+
+    /// Sign `bytes` that travel outside a sync message (base64 signature)
+    pub fn sign_bytes(&self, bytes: &[u8]) -> String {
+        BASE64.encode(self.signing.sign(bytes).to_bytes())
+    }
+}
+
+pub fn parse_public_key(encoded: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid public key: {}", encoded))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("Invalid public key: {}", encoded))
+}
+
+/// Check `msg` was signed by one of `trusted`. With no trusted keys,
+/// unsigned messages pass but signatures present must still verify.
+pub fn verify(msg: &SyncMessage, trusted: &[VerifyingKey]) -> Result<()> {
+    let Some(signature) = &msg.signature else {
+        if trusted.is_empty() {
+            return Ok(());
+        }
+        return Err(anyhow!("unsigned change to {}", msg.key));
+    };
+
+    let key = parse_public_key(&signature.key)?;
+    if !trusted.is_empty() && !trusted.contains(&key) {
+        return Err(anyhow!(
+            "change to {} signed by untrusted key {}",
+            msg.key,
+            signature.key
+        ));
+    }
+    let sig: [u8; 64] = BASE64
+        .decode(&signature.sig)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("malformed signature on {}", msg.key))?;
+    key.verify(&payload(msg), &ed25519_dalek::Signature::from_bytes(&sig))
+        .map_err(|_| anyhow!("bad signature on {}", msg.key))
+}
+
+/// Check `sig` (base64) over `bytes` was made by the device key `key`
+pub fn verify_bytes(key: &str, bytes: &[u8], sig: &str) -> Result<()> {
+    let key = parse_public_key(key)?;
+    let sig: [u8; 64] = BASE64
+        .decode(sig)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("malformed signature"))?;
+    key.verify(bytes, &ed25519_dalek::Signature::from_bytes(&sig))
+        .map_err(|_| anyhow!("bad signature"))
+}
+
+/// The signed bytes: every field of the change except the signature
+/// `seq` is left out, so peers from before sequence numbers still verify
+/// our changes; a relay that rewrites it can only get a change dropped,
+/// which it could do anyway
+fn payload(msg: &SyncMessage) -> Vec<u8> {
+    serde_json::to_vec(&(
+        &msg.key,
+        &msg.value,
+        msg.timestamp,
+        &msg.machine_id,
+        msg.deleted,
+        &msg.id,
+    ))
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> SyncMessage {
+        SyncMessage::new(
+            "AWS_SECRET".to_string(),
+            "real".to_string(),
+            100,
+            "machine-a".to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_forged_and_untrusted_changes_are_rejected() {
+        let device = DeviceKey::generate();
+        let trusted = vec![parse_public_key(&device.public_key()).unwrap()];
+
+        let mut msg = message();
+        assert!(verify(&msg, &[]).is_ok());
+        assert!(verify(&msg, &trusted).is_err());
+
+        device.sign(&mut msg);
+        assert!(verify(&msg, &trusted).is_ok());
+
+        // A relay rewriting the value breaks the signature
+        let mut forged = msg.clone();
+        forged.value = "attacker".to_string();
+        assert!(verify(&forged, &[]).is_err());
+
+        // Re-signing with its own key doesn't get past the trust list
+        DeviceKey::generate().sign(&mut forged);
+        assert!(verify(&forged, &[]).is_ok());
+        assert!(verify(&forged, &trusted).is_err());
+    }
+
+    #[test]
+    fn test_device_key_persists() {
+        let path = std::env::temp_dir().join(format!("envmesh-key-{}", uuid::Uuid::new_v4()));
+        let created = DeviceKey::load_or_create(&path).unwrap();
+        let loaded = DeviceKey::load_or_create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+    }
+}
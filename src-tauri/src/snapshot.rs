@@ -0,0 +1,77 @@
+// State snapshots for bootstrapping new nodes: the whole state at a sequence
+// point, compressed (and sent in chunks when large, see `chunking.rs`), so
+// only the changelog after it is streamed
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::Read;
+
+use crate::client::SyncMessage;
+use crate::redact;
+
+/// Largest snapshot accepted once inflated, so a small frame can't expand
+/// without bound
+pub const MAX_SNAPSHOT_BYTES: usize = 256 * 1024 * 1024;
+
+/// Deflate the changes as JSON and base64 the result, for a `snapshot` frame
+pub fn encode(changes: &[SyncMessage]) -> Result<String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, changes)?;
+    let compressed = encoder.finish()?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(compressed))
+}
+
+pub fn decode(data: &str) -> Result<Vec<SyncMessage>> {
+    let compressed = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| anyhow!("Invalid snapshot encoding: {}", e))?;
+    let mut json = Vec::new();
+    DeflateDecoder::new(compressed.as_slice())
+        .take(MAX_SNAPSHOT_BYTES as u64 + 1)
+        .read_to_end(&mut json)?;
+    if json.len() > MAX_SNAPSHOT_BYTES {
+        return Err(anyhow!(
+            "Snapshot is over {} bytes inflated",
+            MAX_SNAPSHOT_BYTES
+        ));
+    }
+    serde_json::from_slice(&json).map_err(redact::json_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let changes: Vec<SyncMessage> = (0..200)
+            .map(|i| SyncMessage {
+                key: format!("KEY_{}", i),
+                value: "postgres://db.internal:5432/app".to_string(),
+                timestamp: 1_700_000_000_000 + i,
+                machine_id: "machine-a".to_string(),
+                deleted: false,
+                id: None,
+                seq: Some(i as u64 + 1),
+                signature: None,
+            })
+            .collect();
+        let data = encode(&changes).unwrap();
+        assert!(data.len() < serde_json::to_string(&changes).unwrap().len() / 4);
+
+        let decoded = decode(&data).unwrap();
+        assert_eq!(decoded.len(), changes.len());
+        assert_eq!(decoded[199].key, "KEY_199");
+        assert_eq!(decoded[199].seq, Some(200));
+        assert!(decode("not base64!").is_err());
+
+        // Well-formed deflate, but not a whole list of changes
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"[{\"key\":").unwrap();
+        let truncated = base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap());
+        assert!(decode(&truncated).is_err());
+    }
+}
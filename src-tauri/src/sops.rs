@@ -0,0 +1,119 @@
+// SOPS-encrypted env files, through the `sops` binary: export to YAML for
+// age recipients, and import any file sops can decrypt
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Encrypt `vars` as a SOPS YAML document readable by the age `recipients`.
+/// The plaintext only ever goes to sops' stdin.
+pub fn encrypt_yaml(vars: &[(String, String)], recipients: &[String]) -> Result<Vec<u8>> {
+    if recipients.is_empty() {
+        return Err(anyhow!("Pass at least one --age-recipient"));
+    }
+    let document: serde_json::Map<String, serde_json::Value> = vars
+        .iter()
+        .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+        .collect();
+
+    // sops wants a file path; /dev/stdin lets it read our pipe instead of
+    // a plaintext file on disk
+    if cfg!(not(unix)) {
+        return Err(anyhow!(
+            "SOPS export needs /dev/stdin; export to a file and run sops yourself"
+        ));
+    }
+
+    let mut child = Command::new("sops")
+        .args(["--encrypt", "--input-type", "json", "--output-type", "yaml"])
+        .args(["--age", &recipients.join(",")])
+        .arg("/dev/stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(not_installed)?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("sops stdin unavailable"))?
+        .write_all(&serde_json::to_vec(&document)?)?;
+    finish(child.wait_with_output()?)
+}
+
+/// Decrypt a SOPS file (YAML, JSON or dotenv) into variables. Nested
+/// documents are rejected rather than guessed at.
+pub fn decrypt(path: &Path) -> Result<Vec<(String, String)>> {
+    let output = Command::new("sops")
+        .args(["--decrypt", "--output-type", "json"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(not_installed)?;
+    let json = finish(output).with_context(|| format!("Decrypting {}", path.display()))?;
+    flat_vars(serde_json::from_slice(&json)?)
+}
+
+/// Top-level keys of a decrypted document; scalars become strings
+fn flat_vars(document: serde_json::Value) -> Result<Vec<(String, String)>> {
+    let serde_json::Value::Object(map) = document else {
+        return Err(anyhow!("SOPS document is not a mapping of variables"));
+    };
+
+    map.into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                serde_json::Value::Null => String::new(),
+                _ => {
+                    return Err(anyhow!(
+                        "{} is nested; only flat files can be imported",
+                        key
+                    ))
+                }
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn not_installed(e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        anyhow!("sops is not installed (https://github.com/getsops/sops)")
+    } else {
+        e.into()
+    }
+}
+
+fn finish(output: std::process::Output) -> Result<Vec<u8>> {
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(anyhow!(
+            "sops failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_vars() {
+        let vars = flat_vars(serde_json::json!({
+            "DB_URL": "postgres://db",
+            "PORT": 5432,
+            "DEBUG": false,
+        }))
+        .unwrap();
+        assert!(vars.contains(&("PORT".to_string(), "5432".to_string())));
+        assert!(vars.contains(&("DEBUG".to_string(), "false".to_string())));
+
+        assert!(flat_vars(serde_json::json!({"db": {"url": "x"}})).is_err());
+        assert!(flat_vars(serde_json::json!(["x"])).is_err());
+    }
+}
@@ -1,31 +1,75 @@
 // Application state management
-use crate::node::{EnvMeshNode, NodeConfig};
-use crate::storage::EnvStorage;
+use crate::access::PeerIdentity;
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::machineid;
+use crate::node::EnvMeshNode;
+use crate::pool::StoragePool;
+use crate::signing::DeviceKey;
+use crate::sync::SyncEngine;
 use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use uuid::Uuid;
 
 pub struct AppState {
-    pub storage: Arc<Mutex<EnvStorage>>,
+    pub storage: StoragePool,
     pub node: Arc<Mutex<EnvMeshNode>>,
     pub machine_id: String,
+    pub events: EventBus,
+    pub notifications_enabled: Arc<AtomicBool>,
+    pub sync: SyncEngine,
+    /// Loaded configuration; settings changes are written back to `config_path`
+    pub config: Arc<Mutex<Config>>,
+    pub config_path: PathBuf,
 }
 
 impl AppState {
     pub async fn new(db_path: std::path::PathBuf) -> Result<Self> {
-        let storage = EnvStorage::new(db_path)?;
+        let key_path = db_path.with_file_name("device.key");
+        let storage = StoragePool::open(db_path)?;
 
-        // Configure node (use default config for now)
-        let config = NodeConfig::default();
-        let node = EnvMeshNode::new(config).await?;
+        let config = Config::load_default()?;
+        let events = EventBus::new();
+        let machine_id =
+            machineid::load_or_create(&key_path.with_file_name(machineid::MACHINE_ID_FILE))?;
+        let device_key = Arc::new(DeviceKey::load_or_create(&key_path)?);
 
-        let machine_id = Uuid::new_v4().to_string();
+        let mut node_config = config.to_node_config();
+        node_config.identity = Some(PeerIdentity {
+            machine_id: machine_id.clone(),
+            device_key: device_key.public_key(),
+        });
+        node_config.signer = Some(Arc::clone(&device_key));
+        let node = EnvMeshNode::new(node_config, events.clone()).await?;
+
+        let node = Arc::new(Mutex::new(node));
+        let sync = SyncEngine::new(
+            storage.clone(),
+            node.clone(),
+            events.clone(),
+            config.sync_filter(),
+        );
+        sync.set_key_policy(config.keys.policy);
+        sync.set_quarantine(config.quarantine.clone());
+        sync.set_rate_limit(config.limits.sync_rate);
+        sync.set_clock_config(config.clock);
+        sync.set_trusted_keys(config.trusted_keys()?);
+        sync.set_peer_access(config.peer_access());
+        sync.set_users(config.users());
+        sync.set_device_key(device_key);
+        sync.set_machine_id(&machine_id);
 
         Ok(Self {
-            storage: Arc::new(Mutex::new(storage)),
-            node: Arc::new(Mutex::new(node)),
+            storage,
+            node,
             machine_id,
+            events,
+            notifications_enabled: Arc::new(AtomicBool::new(config.notifications.enabled)),
+            sync,
+            config: Arc::new(Mutex::new(config)),
+            config_path: Config::default_save_path(),
         })
     }
 }
@@ -1,12 +1,361 @@
 // Storage module for encrypted environment variables
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::Utc;
-use rusqlite::{params, Connection};
-use std::path::PathBuf;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::clock;
+use crate::files::{self, FileBlob, FileInfo};
+use crate::locks;
+use crate::merge::MergeStrategy;
+use crate::migrations;
+use crate::pattern;
+use crate::revoke;
 
 /// Type alias for change records: (key, value, timestamp, machine_id, deleted)
 pub type ChangeRecord = (String, String, i64, String, bool);
 
+/// How long a connection waits on a locked database before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Namespace of keys stored without a `namespace/` prefix
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Separates the namespace from the variable name in a stored key
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// Machines whose sequence numbers we keep track of, for our own numbering
+/// and for what each sent us. Machine IDs may be new every run, so past
+/// this the ones used or heard from longest ago are forgotten.
+const MAX_SEQ_MACHINES: usize = 1000;
+
+/// How far past a machine's floor received sequence numbers are kept while
+/// the changes below them are missing. Past this, the floor moves up over
+/// the oldest holes: those changes are not coming any more.
+const SEQ_WINDOW: u64 = 4096;
+
+/// Sync reports kept in `sync_log`; older ones are dropped as cycles run
+pub const SYNC_LOG_SIZE: usize = 100;
+
+/// Rotation attempts kept in `rotation_log`
+pub const ROTATION_LOG_SIZE: usize = 500;
+
+/// Tables whose primary key `fsck` checks, without the index that should
+/// enforce it: (table, key columns, how a key is shown)
+const UNIQUE_ROWS: &[(&str, &str, &str)] = &[
+    ("env_vars", "key", "key"),
+    ("env_tags", "key, tag", "key || ' #' || tag"),
+    ("machine_seqs", "machine_id", "machine_id"),
+    ("received_seq_floors", "machine_id", "machine_id"),
+    (
+        "received_seqs",
+        "machine_id, seq",
+        "machine_id || ' seq ' || seq",
+    ),
+];
+
+/// Invariants `fsck` checks: (what is wrong, the rows it is wrong for)
+const VIOLATIONS: &[(&str, &str)] = &[
+    (
+        "empty key",
+        "SELECT 'env_vars' FROM env_vars WHERE key = ''
+         UNION ALL SELECT 'env_history #' || id FROM env_history WHERE key = ''",
+    ),
+    (
+        "deleted flag is neither 0 nor 1",
+        "SELECT 'env_vars ' || key FROM env_vars WHERE deleted NOT IN (0, 1)
+         UNION ALL SELECT 'env_history #' || id FROM env_history WHERE deleted NOT IN (0, 1)",
+    ),
+    (
+        "received at or below its floor",
+        "SELECT 'received_seqs ' || r.machine_id || ' seq ' || r.seq
+         FROM received_seqs AS r JOIN received_seq_floors AS f USING (machine_id)
+         WHERE r.seq <= f.through",
+    ),
+    (
+        "numbered past the machine's last sequence number",
+        "SELECT 'env_history #' || h.id || ' seq ' || h.seq
+         FROM env_history AS h JOIN machine_seqs AS m USING (machine_id)
+         WHERE h.seq > m.last_seq",
+    ),
+];
+
+/// What `clear_replicated` empties
+const REPLICATED_TABLES: &[&str] = &[
+    "env_vars",
+    "env_history",
+    "env_tags",
+    "conflicts",
+    "received_seqs",
+    "received_seq_floors",
+    "sync_watermarks",
+    "quarantine",
+    // What a vault and the store last agreed on means nothing once the
+    // store is emptied
+    "vault_digests",
+];
+
+/// Full storage key for a variable in a namespace (`prod/DB_URL`)
+pub fn namespaced_key(namespace: &str, key: &str) -> String {
+    if namespace.is_empty() || namespace == DEFAULT_NAMESPACE {
+        key.to_string()
+    } else {
+        format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, key)
+    }
+}
+
+/// Split a storage key into (namespace, variable name)
+pub fn split_key(key: &str) -> (&str, &str) {
+    key.split_once(NAMESPACE_SEPARATOR)
+        .unwrap_or((DEFAULT_NAMESPACE, key))
+}
+
+/// Filters and paging for listing variables
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ListQuery {
+    /// Case-insensitive substring of the key
+    #[serde(default)]
+    pub search: Option<String>,
+    /// Key prefix (within the namespace, if one is given)
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Maximum number of results (None = all)
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VarRecord {
+    pub key: String,
+    pub value: String,
+    pub timestamp: i64,
+    pub machine_id: String,
+    pub tags: Vec<String>,
+}
+
+/// One page of a listing plus the number of matches across all pages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListPage {
+    pub vars: Vec<VarRecord>,
+    pub total: usize,
+}
+
+/// Escape `%`, `_` and the escape character itself for a LIKE pattern
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// What an import, delete or restore would do (or did) to one key
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Add,
+    Update,
+    Unchanged,
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportChange {
+    pub key: String,
+    pub action: ImportAction,
+    pub old_value: Option<String>,
+    pub value: String,
+}
+
+/// Result of applying a change received from another machine
+#[derive(Debug, PartialEq)]
+pub enum ApplyOutcome {
+    /// The remote change was newer and is now stored
+    Applied,
+    /// Nothing to do (already have it, or an older copy of our own state)
+    Ignored,
+    /// The remote change lost last-write-wins against a different local value
+    Conflict { local_machine: String },
+    /// The change looked risky and waits for approval (`[quarantine]`)
+    Quarantined,
+}
+
+/// One past state of a variable, as recorded on every local or remote change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub key: String,
+    pub value: String,
+    pub timestamp: i64,
+    pub machine_id: String,
+    pub deleted: bool,
+    /// Set on the new key's entry when this change was a rename
+    #[serde(default)]
+    pub renamed_from: Option<String>,
+    /// Set on the old key's tombstone when this change was a rename
+    #[serde(default)]
+    pub renamed_to: Option<String>,
+    /// Set when the value was copied from another key (`envmesh-cli copy`)
+    #[serde(default)]
+    pub copied_from: Option<String>,
+}
+
+fn history_entry(row: &rusqlite::Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        key: row.get(1)?,
+        value: row.get(2)?,
+        timestamp: row.get(3)?,
+        machine_id: row.get(4)?,
+        deleted: row.get::<_, i32>(5)? != 0,
+        renamed_from: row.get(6)?,
+        renamed_to: row.get(7)?,
+        copied_from: row.get(8)?,
+    })
+}
+
+/// Two machines changed the same key; the remote side lost last-write-wins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    pub id: i64,
+    pub key: String,
+    pub local_value: String,
+    pub local_timestamp: i64,
+    pub local_machine: String,
+    pub remote_value: String,
+    pub remote_timestamp: i64,
+    pub remote_machine: String,
+    pub remote_deleted: bool,
+    pub detected_at: i64,
+}
+
+/// One scheduled rotation attempt (`rotation.rs`); `error` is None if the
+/// new value was stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotationEntry {
+    pub id: i64,
+    pub key: String,
+    pub rotated_at: i64,
+    pub machine_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A deleted variable whose tombstone is still kept (`envmesh-cli trash`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedVar {
+    pub key: String,
+    pub deleted_at: i64,
+    /// The machine that deleted it
+    pub machine_id: String,
+    /// Whether history still has a value to restore
+    pub restorable: bool,
+}
+
+/// An incoming change held for approval (see `quarantine.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedChange {
+    pub id: i64,
+    pub key: String,
+    pub timestamp: i64,
+    pub machine_id: String,
+    pub deleted: bool,
+    pub reason: String,
+    pub received_at: i64,
+    /// The change as it arrived (JSON), applied on approval; never sent
+    /// to the CLI or GUI
+    #[serde(skip)]
+    pub change: String,
+}
+
+/// What one sync cycle moved: the keys we pushed, and those received since
+/// the cycle before it, by what became of them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub id: i64,
+    /// Unix ms the cycle started receiving, and when it pushed
+    pub started_ms: i64,
+    pub finished_ms: i64,
+    /// Who the changes were pushed to, as in `sync_watermarks` (None: offline)
+    pub peer: Option<String>,
+    /// Every change was pushed, not only those the peer hadn't been sent
+    pub full: bool,
+    pub pushed: Vec<String>,
+    pub pulled: Vec<String>,
+    /// Received changes that lost last-write-wins against ours
+    pub conflicted: Vec<String>,
+    /// Keys the `[sync]` filter kept from being pushed or applied
+    pub skipped: Vec<String>,
+}
+
+/// What the store holds and who has been writing to it, for `envmesh-cli
+/// stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub variables: usize,
+    /// Variables per namespace
+    pub namespaces: BTreeMap<String, usize>,
+    pub files: usize,
+    /// Deleted keys still kept, so the deletes keep syncing
+    pub tombstones: usize,
+    pub history_entries: usize,
+    /// The key with the most history entries, and how many
+    pub deepest_history: Option<(String, usize)>,
+    /// Size of the database (without its write-ahead log)
+    pub size_bytes: u64,
+    /// Changes written since `since` (by their timestamps)
+    pub since: i64,
+    pub recent_changes: usize,
+    /// History entries per machine that wrote them
+    pub writes: BTreeMap<String, MachineWrites>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MachineWrites {
+    pub total: usize,
+    /// Since `StoreStats::since`
+    pub recent: usize,
+}
+
+/// What `envmesh-cli fsck` found wrong with the store; empty lists when
+/// nothing was
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FsckReport {
+    /// What SQLite's `integrity_check` reported: damaged pages or indexes
+    pub integrity: Vec<String>,
+    /// Keys stored in more than one row, which the schema should rule out
+    pub duplicates: Vec<String>,
+    /// Rows the sync engine would misread: empty keys, flags other than 0
+    /// or 1, sequence numbers out of line
+    pub violations: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.integrity.is_empty() && self.duplicates.is_empty() && self.violations.is_empty()
+    }
+}
+
+/// Which side of a conflict to keep
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictChoice {
+    Local,
+    Remote,
+}
+
 pub struct EnvStorage {
     conn: Connection,
 }
@@ -14,23 +363,22 @@ pub struct EnvStorage {
 impl EnvStorage {
     pub fn new(db_path: PathBuf) -> Result<Self> {
         let conn = Connection::open(db_path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // WAL lets readers proceed while a write is in progress (no-op in memory)
+        conn.pragma_update(None, "journal_mode", "WAL")?;
 
-        // Create tables
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS env_vars (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL,
-                timestamp INTEGER NOT NULL,
-                machine_id TEXT NOT NULL,
-                deleted INTEGER NOT NULL DEFAULT 0
-            )",
-            [],
-        )?;
+        migrations::migrate(&conn)?;
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_timestamp ON env_vars(timestamp)",
-            [],
+        Ok(Self { conn })
+    }
+
+    /// Read-only connection to an existing, already migrated database
+    pub fn open_reader(db_path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
 
         Ok(Self { conn })
     }
@@ -52,30 +400,197 @@ impl EnvStorage {
         }
     }
 
+    /// `key`'s row as stored, tombstone or not. A local write may be stamped
+    /// after now (`stamp_after`), so this is what to publish after one.
+    pub fn stored(&self, key: &str) -> Result<Option<ChangeRecord>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT value, timestamp, machine_id, deleted FROM env_vars WHERE key = ?",
+                params![key],
+                |row| {
+                    Ok((
+                        key.to_string(),
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get::<_, i32>(3)? != 0,
+                    ))
+                },
+            )
+            .optional()?)
+    }
+
+    /// Whether `key` was deleted: its tombstone is stored
+    pub fn is_deleted(&self, key: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM env_vars WHERE key = ? AND deleted = 1",
+                params![key],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
     pub fn set(&self, key: &str, value: &str, machine_id: &str) -> Result<()> {
-        let timestamp = Utc::now().timestamp();
+        self.set_at(key, value, machine_id, clock::now())
+    }
 
+    /// `set` at a given time instead of now (simulated clocks in `testkit`)
+    pub fn set_at(&self, key: &str, value: &str, machine_id: &str, timestamp: i64) -> Result<()> {
+        self.check_unlocked(key)?;
+        let timestamp = self.stamp_after(key, timestamp)?;
         self.conn.execute(
             "INSERT OR REPLACE INTO env_vars (key, value, timestamp, machine_id, deleted)
              VALUES (?, ?, ?, ?, 0)",
             params![key, value, timestamp, machine_id],
         )?;
+        self.record_history(key, value, timestamp, machine_id, false, None)?;
 
         Ok(())
     }
 
-    pub fn delete(&self, key: &str, _machine_id: &str) -> Result<()> {
-        let timestamp = Utc::now().timestamp();
+    pub fn delete(&self, key: &str, machine_id: &str) -> Result<()> {
+        self.delete_at(key, machine_id, clock::now())
+    }
 
-        self.conn.execute(
-            "UPDATE env_vars SET deleted = 1, timestamp = ?
+    /// `delete` at a given time instead of now
+    pub fn delete_at(&self, key: &str, machine_id: &str, timestamp: i64) -> Result<()> {
+        self.check_unlocked(key)?;
+        let timestamp = self.stamp_after(key, timestamp)?;
+        let updated = self.conn.execute(
+            "UPDATE env_vars SET deleted = 1, timestamp = ?, machine_id = ?, seq = NULL
              WHERE key = ?",
-            params![timestamp, key],
+            params![timestamp, machine_id, key],
+        )?;
+        if updated > 0 {
+            self.record_history(key, "", timestamp, machine_id, true, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Move `from` to `to` in one transaction: `to` gets the value and tags,
+    /// `from` a tombstone, and their history entries name each other
+    pub fn rename(&self, from: &str, to: &str, machine_id: &str) -> Result<()> {
+        if from == to {
+            return Err(anyhow!("{} and its new name are the same", from));
+        }
+        self.check_unlocked(from)?;
+        self.check_unlocked(to)?;
+        let tx = self.conn.unchecked_transaction()?;
+
+        let (value, ..) = self
+            .get(from)?
+            .ok_or_else(|| anyhow!("No variable named {}", from))?;
+        if self.get(to)?.is_some() {
+            return Err(anyhow!("{} already exists", to));
+        }
+
+        let timestamp = clock::now();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO env_vars (key, value, timestamp, machine_id, deleted)
+             VALUES (?, ?, ?, ?, 0)",
+            params![to, value, timestamp, machine_id],
+        )?;
+        self.conn.execute(
+            "UPDATE env_vars SET deleted = 1, timestamp = ?, seq = NULL WHERE key = ?",
+            params![timestamp, from],
+        )?;
+        self.conn.execute(
+            "INSERT INTO env_history (key, value, timestamp, machine_id, deleted, renamed_from)
+             VALUES (?, ?, ?, ?, 0, ?)",
+            params![to, value, timestamp, machine_id, from],
+        )?;
+        self.conn.execute(
+            "INSERT INTO env_history (key, value, timestamp, machine_id, deleted, renamed_to)
+             VALUES (?, '', ?, ?, 1, ?)",
+            params![from, timestamp, machine_id, to],
+        )?;
+        self.conn
+            .execute("DELETE FROM env_tags WHERE key = ?", params![to])?;
+        self.conn.execute(
+            "UPDATE env_tags SET key = ? WHERE key = ?",
+            params![to, from],
         )?;
 
+        tx.commit()?;
         Ok(())
     }
 
+    /// Copy the value of each (from, to) pair in one transaction, recording
+    /// the source in the new history entries. With `dry_run` nothing is
+    /// written and the returned changes are a preview.
+    pub fn copy(
+        &self,
+        pairs: &[(String, String)],
+        machine_id: &str,
+        dry_run: bool,
+    ) -> Result<Vec<ImportChange>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let timestamp = clock::now();
+
+        let mut changes = Vec::new();
+        for (from, to) in pairs {
+            self.check_unlocked(to)?;
+            let (value, ..) = self
+                .get(from)?
+                .ok_or_else(|| anyhow!("No variable named {}", from))?;
+            let old_value = self.get(to)?.map(|(old, _, _)| old);
+            let action = match &old_value {
+                None => ImportAction::Add,
+                Some(old) if *old == value => ImportAction::Unchanged,
+                Some(_) => ImportAction::Update,
+            };
+
+            if !dry_run && action != ImportAction::Unchanged {
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO env_vars (key, value, timestamp, machine_id, deleted)
+                     VALUES (?, ?, ?, ?, 0)",
+                    params![to, value, timestamp, machine_id],
+                )?;
+                self.conn.execute(
+                    "INSERT INTO env_history (key, value, timestamp, machine_id, deleted, copied_from)
+                     VALUES (?, ?, ?, ?, 0, ?)",
+                    params![to, value, timestamp, machine_id, from],
+                )?;
+            }
+
+            changes.push(ImportChange {
+                key: to.clone(),
+                action,
+                old_value,
+                value,
+            });
+        }
+
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok(changes)
+    }
+
+    /// Other live variables holding exactly `value` (file secrets excepted)
+    pub fn keys_with_value(&self, value: &str, except: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key FROM env_vars
+             WHERE value = ? AND key != ? AND deleted = 0
+               AND instr(key, '@file/') != 1 AND instr(key, '@lock/') != 1
+               AND instr(key, '@revoked/') != 1
+             ORDER BY key",
+        )?;
+        let rows = stmt.query_map(params![value, except], |row| row.get(0))?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
     pub fn list_all(&self) -> Result<Vec<(String, String, i64, String)>> {
         let mut stmt = self.conn.prepare(
             "SELECT key, value, timestamp, machine_id FROM env_vars
@@ -94,22 +609,537 @@ impl EnvStorage {
         Ok(results)
     }
 
-    pub fn get_changes_since(&self, timestamp: i64) -> Result<Vec<ChangeRecord>> {
+    /// Write many variables in one transaction. With `dry_run` nothing is
+    /// written and the returned changes are a preview.
+    pub fn import(
+        &self,
+        vars: &[(String, String)],
+        machine_id: &str,
+        dry_run: bool,
+    ) -> Result<Vec<ImportChange>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let timestamp = clock::now();
+
+        let mut changes = Vec::new();
+        for (key, value) in vars {
+            self.check_unlocked(key)?;
+            let old_value = self.get(key)?.map(|(old, _, _)| old);
+            let action = match &old_value {
+                None => ImportAction::Add,
+                Some(old) if old == value => ImportAction::Unchanged,
+                Some(_) => ImportAction::Update,
+            };
+
+            if !dry_run && action != ImportAction::Unchanged {
+                self.write_remote(key, value, timestamp, machine_id, false, None)?;
+            }
+
+            changes.push(ImportChange {
+                key: key.clone(),
+                action,
+                old_value,
+                value: value.clone(),
+            });
+        }
+
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok(changes)
+    }
+
+    /// Delete every live variable whose key matches the wildcard `pattern`
+    /// (file secrets excepted) in one transaction. With `dry_run` nothing
+    /// is written and the returned changes are a preview.
+    pub fn delete_matching(
+        &self,
+        pattern: &str,
+        machine_id: &str,
+        dry_run: bool,
+    ) -> Result<Vec<ImportChange>> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        let mut changes = Vec::new();
+        for (key, value, ..) in self.list_all()? {
+            if key.starts_with(files::FILE_KEY_PREFIX)
+                || key.starts_with(locks::LOCK_KEY_PREFIX)
+                || key.starts_with(revoke::REVOKED_KEY_PREFIX)
+                || !pattern::matches(pattern, &key)
+            {
+                continue;
+            }
+            self.check_unlocked(&key)?;
+            if !dry_run {
+                self.delete(&key, machine_id)?;
+            }
+            changes.push(ImportChange {
+                key,
+                action: ImportAction::Delete,
+                old_value: Some(value),
+                value: String::new(),
+            });
+        }
+
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok(changes)
+    }
+
+    /// Filtered, paginated listing of live variables, ordered by key
+    pub fn list(&self, query: &ListQuery) -> Result<ListPage> {
+        let default_namespace_only = query.namespace.as_deref() == Some(DEFAULT_NAMESPACE);
+
+        // Namespace and prefix both constrain the start of the key
+        let mut key_prefix = match query.namespace.as_deref() {
+            Some(namespace) if !default_namespace_only => {
+                format!("{}{}", namespace, NAMESPACE_SEPARATOR)
+            }
+            _ => String::new(),
+        };
+        if let Some(prefix) = &query.prefix {
+            key_prefix.push_str(prefix);
+        }
+        let key_pattern =
+            (!key_prefix.is_empty()).then(|| format!("{}%", escape_like(&key_prefix)));
+
+        // File secrets, locks and revocations (files::FILE_KEY_PREFIX,
+        // locks::LOCK_KEY_PREFIX, revoke::REVOKED_KEY_PREFIX) are never
+        // listed as variables
+        let filter = "deleted = 0
+             AND instr(key, '@file/') != 1
+             AND instr(key, '@lock/') != 1
+             AND instr(key, '@revoked/') != 1
+             AND (?1 IS NULL OR key LIKE ?1 ESCAPE '\\')
+             AND (?2 IS NULL OR instr(lower(key), lower(?2)) > 0)
+             AND (?3 = 0 OR instr(key, '/') = 0)
+             AND (?4 IS NULL OR key IN (SELECT key FROM env_tags WHERE tag = ?4))";
+
+        let total: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM env_vars WHERE {}", filter),
+            params![key_pattern, query.search, default_namespace_only, query.tag],
+            |row| row.get(0),
+        )?;
+
+        // SQLite treats a negative limit as "no limit"
+        let limit = query.limit.map(|limit| limit as i64).unwrap_or(-1);
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT key, value, timestamp, machine_id,
+                    (SELECT group_concat(tag, ',') FROM env_tags WHERE env_tags.key = env_vars.key)
+             FROM env_vars WHERE {} ORDER BY key LIMIT ?5 OFFSET ?6",
+            filter
+        ))?;
+
+        let rows = stmt.query_map(
+            params![
+                key_pattern,
+                query.search,
+                default_namespace_only,
+                query.tag,
+                limit,
+                query.offset as i64
+            ],
+            |row| {
+                let tags: Option<String> = row.get(4)?;
+                Ok(VarRecord {
+                    key: row.get(0)?,
+                    value: row.get(1)?,
+                    timestamp: row.get(2)?,
+                    machine_id: row.get(3)?,
+                    tags: tags
+                        .map(|tags| tags.split(',').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                })
+            },
+        )?;
+
+        let mut vars = Vec::new();
+        for row in rows {
+            vars.push(row?);
+        }
+
+        Ok(ListPage {
+            vars,
+            total: total as usize,
+        })
+    }
+
+    /// Stored files, without their contents, ordered by name
+    pub fn list_files(&self) -> Result<Vec<FileInfo>> {
         let mut stmt = self.conn.prepare(
-            "SELECT key, value, timestamp, machine_id, deleted FROM env_vars
-             WHERE timestamp > ? ORDER BY timestamp",
+            "SELECT key, value, timestamp FROM env_vars
+             WHERE deleted = 0 AND instr(key, ?) = 1 ORDER BY key",
         )?;
 
-        let rows = stmt.query_map(params![timestamp], |row| {
+        let rows = stmt.query_map(params![files::FILE_KEY_PREFIX], |row| {
             Ok((
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-                row.get::<_, i32>(4)? != 0,
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
             ))
         })?;
 
+        let mut results = Vec::new();
+        for row in rows {
+            let (key, value, timestamp) = row?;
+            let (Some(name), Ok(blob)) = (files::file_name(&key), FileBlob::from_value(&value))
+            else {
+                tracing::warn!("Skipping unreadable file entry {}", key);
+                continue;
+            };
+            results.push(FileInfo {
+                name: name.to_string(),
+                sha256: blob.sha256,
+                size: blob.size,
+                timestamp,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Replace the tags on a key (tags are local labels and are not synced)
+    pub fn set_tags(&self, key: &str, tags: &[String]) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM env_tags WHERE key = ?", params![key])?;
+        for tag in tags {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO env_tags (key, tag) VALUES (?, ?)",
+                params![key, tag],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn tags(&self, key: &str) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT tag FROM env_tags WHERE key = ? ORDER BY tag")?;
+        let rows = stmt.query_map(params![key], |row| row.get(0))?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// The device key that locked `key`, if it is locked
+    pub fn lock_owner(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.get(&locks::lock_key(key))?.map(|(owner, ..)| owner))
+    }
+
+    fn check_unlocked(&self, key: &str) -> Result<()> {
+        match self.lock_owner(key)? {
+            Some(owner) => Err(locks::locked_error(key, &owner)),
+            None => Ok(()),
+        }
+    }
+
+    /// Stored revocations (`revoke::REVOKED_KEY_PREFIX`) as key, value,
+    /// timestamp and machine ID
+    pub fn revocations(&self) -> Result<Vec<(String, String, i64, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, timestamp, machine_id FROM env_vars
+             WHERE deleted = 0 AND instr(key, ?) = 1 ORDER BY key",
+        )?;
+
+        let rows = stmt.query_map(params![revoke::REVOKED_KEY_PREFIX], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Erase every table, overwriting what the rows took up on disk, ahead
+    /// of deleting the database (`device revoke --wipe`)
+    pub fn wipe(&self) -> Result<()> {
+        self.conn.pragma_update(None, "secure_delete", "ON")?;
+        let tables: Vec<String> = self
+            .conn
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for table in tables {
+            self.conn
+                .execute(&format!("DELETE FROM \"{}\"", table), [])?;
+        }
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// The machine that first wrote `key`
+    pub fn creator(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT machine_id FROM env_history WHERE key = ?
+                 ORDER BY timestamp, id LIMIT 1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Note that this store writes as `machine_id` for the device key
+    /// `device`. Machine IDs are new every run; this is how what we created
+    /// under earlier ones stays ours. One row per daemon start, never sent
+    /// to peers.
+    pub fn note_own_machine(&self, machine_id: &str, device: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO own_machines (machine_id, device) VALUES (?, ?)",
+            params![machine_id, device],
+        )?;
+        Ok(())
+    }
+
+    /// The device key of the machine that first wrote `key`, if that was
+    /// this store (see `note_own_machine`)
+    pub fn creator_device(&self, key: &str) -> Result<Option<String>> {
+        let Some(creator) = self.creator(key)? else {
+            return Ok(None);
+        };
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT device FROM own_machines WHERE machine_id = ?",
+                params![creator],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Lock `key` against changes, owned by the device key `owner`; only
+    /// the device that created it may, under whichever machine ID. False if
+    /// we had locked it already.
+    pub fn lock(&self, key: &str, machine_id: &str, owner: &str) -> Result<bool> {
+        if self.get(key)?.is_none() {
+            return Err(anyhow!("No variable named {}", key));
+        }
+        match self.lock_owner(key)? {
+            Some(locked_by) if locked_by == owner => return Ok(false),
+            Some(owner) => return Err(locks::locked_error(key, &owner)),
+            None => {}
+        }
+        if self.creator_device(key)?.as_deref() != Some(owner) {
+            return Err(anyhow!(
+                "Only {}'s creator ({}) can lock it",
+                key,
+                self.creator(key)?.as_deref().unwrap_or("unknown")
+            ));
+        }
+        self.set(&locks::lock_key(key), owner, machine_id)?;
+        Ok(true)
+    }
+
+    /// Remove `key`'s lock: only its `owner` may, or anyone when that is
+    /// None (`--force-unlock`). Returns when it was removed (None if it
+    /// wasn't locked); a change to `key` that goes with it must be stamped
+    /// later, so peers apply the unlock first.
+    pub fn unlock(&self, key: &str, machine_id: &str, owner: Option<&str>) -> Result<Option<i64>> {
+        let Some(locked_by) = self.lock_owner(key)? else {
+            return Ok(None);
+        };
+        if owner.is_some_and(|owner| owner != locked_by) {
+            return Err(anyhow!(
+                "{} was locked by {}; only it can unlock it (or pass --force-unlock)",
+                key,
+                locked_by
+            ));
+        }
+        let lock_key = locks::lock_key(key);
+        self.delete(&lock_key, machine_id)?;
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT timestamp FROM env_vars WHERE key = ?",
+                params![lock_key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Apply a change from the network using last-write-wins on timestamp,
+    /// with the machine ID as a deterministic tie-breaker
+    pub fn apply_remote(
+        &self,
+        key: &str,
+        value: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+    ) -> Result<ApplyOutcome> {
+        self.apply_change(key, value, timestamp, machine_id, deleted, None)
+    }
+
+    /// `apply_remote` for a change numbered `seq` in its machine's
+    /// sequence: each is applied once, however often it arrives
+    pub fn apply_remote_seq(
+        &self,
+        key: &str,
+        value: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+        seq: Option<u64>,
+    ) -> Result<ApplyOutcome> {
+        let Some(seq) = seq else {
+            return self.apply_remote(key, value, timestamp, machine_id, deleted);
+        };
+        // Inside `transaction()` (a batch) it is atomic already
+        let tx = if self.conn.is_autocommit() {
+            Some(self.conn.unchecked_transaction()?)
+        } else {
+            None
+        };
+        if self.seq_received(machine_id, seq)? {
+            return Ok(ApplyOutcome::Ignored);
+        }
+        let outcome = self.apply_change(key, value, timestamp, machine_id, deleted, Some(seq))?;
+        self.record_seq(machine_id, seq)?;
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
+        Ok(outcome)
+    }
+
+    /// Run `f` in one transaction: everything it writes is stored, or (if
+    /// it fails, or the process dies first) nothing. Inside another, `f` is
+    /// simply part of that one.
+    pub fn transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        if !self.conn.is_autocommit() {
+            return f(self);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    fn apply_change(
+        &self,
+        key: &str,
+        value: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+        seq: Option<u64>,
+    ) -> Result<ApplyOutcome> {
+        let local = self.conn.query_row(
+            "SELECT value, timestamp, machine_id, deleted FROM env_vars WHERE key = ?",
+            params![key],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i32>(3)? != 0,
+                ))
+            },
+        );
+
+        let (local_value, local_ts, local_machine, local_deleted) = match local {
+            Ok(row) => row,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                self.write_remote(key, value, timestamp, machine_id, deleted, seq)?;
+                return Ok(ApplyOutcome::Applied);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if (timestamp, machine_id) > (local_ts, local_machine.as_str()) {
+            self.write_remote(key, value, timestamp, machine_id, deleted, seq)?;
+            return Ok(ApplyOutcome::Applied);
+        }
+
+        let same_state = local_deleted == deleted && (deleted || local_value == value);
+        if same_state || local_machine == machine_id {
+            return Ok(ApplyOutcome::Ignored);
+        }
+
+        // Full syncs resend every change, so only report each conflict once
+        let already_recorded = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM conflicts
+                 WHERE key = ? AND remote_timestamp = ? AND remote_machine = ?",
+                params![key, timestamp, machine_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if already_recorded {
+            return Ok(ApplyOutcome::Ignored);
+        }
+
+        self.conn.execute(
+            "INSERT INTO conflicts (key, local_value, local_timestamp, local_machine,
+                                    remote_value, remote_timestamp, remote_machine,
+                                    remote_deleted, detected_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                key,
+                local_value,
+                local_ts,
+                local_machine,
+                value,
+                timestamp,
+                machine_id,
+                deleted as i32,
+                Utc::now().timestamp()
+            ],
+        )?;
+
+        Ok(ApplyOutcome::Conflict { local_machine })
+    }
+
+    /// Past states of a key, newest first
+    pub fn history(&self, key: &str) -> Result<Vec<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, value, timestamp, machine_id, deleted,
+                    renamed_from, renamed_to, copied_from
+             FROM env_history WHERE key = ? ORDER BY timestamp DESC, id DESC",
+        )?;
+
+        let rows = stmt.query_map(params![key], history_entry)?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Every history entry newer than `timestamp`, oldest first (for backups)
+    /// History entries written here after entry `history_id`, oldest first
+    pub fn history_after(&self, history_id: i64) -> Result<Vec<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, value, timestamp, machine_id, deleted,
+                    renamed_from, renamed_to, copied_from
+             FROM env_history WHERE id > ? ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![history_id], history_entry)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn history_since(&self, timestamp: i64) -> Result<Vec<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, value, timestamp, machine_id, deleted,
+                    renamed_from, renamed_to, copied_from
+             FROM env_history WHERE timestamp > ? ORDER BY timestamp, id",
+        )?;
+
+        let rows = stmt.query_map(params![timestamp], history_entry)?;
+
         let mut results = Vec::new();
         for row in rows {
             results.push(row?);
@@ -117,4 +1147,1962 @@ impl EnvStorage {
 
         Ok(results)
     }
+
+    /// All (key, tag) pairs
+    pub fn all_tags(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, tag FROM env_tags ORDER BY key, tag")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Merge backed-up state in one transaction: variables by last-write-wins,
+    /// history entries and tags only where missing, so replaying the same
+    /// backup twice is a no-op. Returns the number of variables written.
+    pub fn restore(
+        &self,
+        vars: &[ChangeRecord],
+        history: &[HistoryEntry],
+        tags: &[(String, String)],
+    ) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        let mut restored = 0;
+        for (key, value, timestamp, machine_id, deleted) in vars {
+            if self.restores_over(key, *timestamp, machine_id)? {
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO env_vars (key, value, timestamp, machine_id, deleted)
+                     VALUES (?, ?, ?, ?, ?)",
+                    params![key, value, timestamp, machine_id, *deleted as i32],
+                )?;
+                restored += 1;
+            }
+        }
+
+        for entry in history {
+            self.conn.execute(
+                "INSERT INTO env_history
+                     (key, value, timestamp, machine_id, deleted,
+                      renamed_from, renamed_to, copied_from)
+                 SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM env_history
+                     WHERE key = ?1 AND value = ?2 AND timestamp = ?3
+                       AND machine_id = ?4 AND deleted = ?5
+                 )",
+                params![
+                    entry.key,
+                    entry.value,
+                    entry.timestamp,
+                    entry.machine_id,
+                    entry.deleted as i32,
+                    entry.renamed_from,
+                    entry.renamed_to,
+                    entry.copied_from
+                ],
+            )?;
+        }
+
+        for (key, tag) in tags {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO env_tags (key, tag) VALUES (?, ?)",
+                params![key, tag],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(restored)
+    }
+
+    /// What `restore` would do to each variable in `vars`, without writing
+    pub fn preview_restore(&self, vars: &[ChangeRecord]) -> Result<Vec<ImportChange>> {
+        let mut changes = Vec::new();
+        for (key, value, timestamp, machine_id, deleted) in vars {
+            let old_value = self.get(key)?.map(|(old, _, _)| old);
+            let action = if !self.restores_over(key, *timestamp, machine_id)? {
+                ImportAction::Unchanged
+            } else {
+                match (&old_value, deleted) {
+                    (Some(_), true) => ImportAction::Delete,
+                    (None, true) => ImportAction::Unchanged,
+                    (None, false) => ImportAction::Add,
+                    (Some(old), false) if old == value => ImportAction::Unchanged,
+                    (Some(_), false) => ImportAction::Update,
+                }
+            };
+            changes.push(ImportChange {
+                key: key.clone(),
+                action,
+                old_value,
+                value: value.clone(),
+            });
+        }
+        Ok(changes)
+    }
+
+    /// True if a backed-up change wins last-write-wins against what we have
+    fn restores_over(&self, key: &str, timestamp: i64, machine_id: &str) -> Result<bool> {
+        let local = self
+            .conn
+            .query_row(
+                "SELECT timestamp, machine_id FROM env_vars WHERE key = ?",
+                params![key],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        Ok(match &local {
+            Some((local_ts, local_machine)) => {
+                (timestamp, machine_id) > (*local_ts, local_machine.as_str())
+            }
+            None => true,
+        })
+    }
+
+    /// Conflicts that have not been resolved yet, oldest first
+    pub fn conflicts(&self) -> Result<Vec<ConflictRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, local_value, local_timestamp, local_machine,
+                    remote_value, remote_timestamp, remote_machine, remote_deleted, detected_at
+             FROM conflicts WHERE resolved = 0 ORDER BY detected_at, id",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(ConflictRecord {
+                id: row.get(0)?,
+                key: row.get(1)?,
+                local_value: row.get(2)?,
+                local_timestamp: row.get(3)?,
+                local_machine: row.get(4)?,
+                remote_value: row.get(5)?,
+                remote_timestamp: row.get(6)?,
+                remote_machine: row.get(7)?,
+                remote_deleted: row.get::<_, i32>(8)? != 0,
+                detected_at: row.get(9)?,
+            })
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Settle the latest unresolved conflict on a key by re-writing the chosen
+    /// side as a fresh local change. Returns the change to publish so the other
+    /// machines converge on it, or None if the key has no open conflict.
+    pub fn resolve_conflict(
+        &self,
+        key: &str,
+        choice: ConflictChoice,
+        machine_id: &str,
+    ) -> Result<Option<ChangeRecord>> {
+        let Some(conflict) = self.open_conflict(key)? else {
+            return Ok(None);
+        };
+
+        let (value, deleted) = match choice {
+            ConflictChoice::Local => {
+                // The local side may have moved on since; keep what is stored now
+                self.conn.query_row(
+                    "SELECT value, deleted FROM env_vars WHERE key = ?",
+                    params![key],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)? != 0)),
+                )?
+            }
+            ConflictChoice::Remote => (conflict.remote_value.clone(), conflict.remote_deleted),
+        };
+
+        self.settle_conflict(&conflict, value, deleted, machine_id)
+            .map(Some)
+    }
+
+    /// Settle the open conflict on `key` by the strategy its `merge:` tag
+    /// names, merging the stored value with the remote one against the last
+    /// value before the remote change. Returns the merged change to send,
+    /// or None if the key is last-write-wins, either side is a deletion or
+    /// the values don't parse.
+    pub fn merge_conflict(
+        &self,
+        key: &str,
+        machine_id: &str,
+    ) -> Result<Option<(ChangeRecord, MergeStrategy)>> {
+        let strategy = MergeStrategy::from_tags(&self.tags(key)?);
+        if strategy == MergeStrategy::Lww {
+            return Ok(None);
+        }
+        let Some(conflict) = self.open_conflict(key)? else {
+            return Ok(None);
+        };
+        let (local, local_deleted) = self.conn.query_row(
+            "SELECT value, deleted FROM env_vars WHERE key = ?",
+            params![key],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)? != 0)),
+        )?;
+        if local_deleted || conflict.remote_deleted {
+            return Ok(None);
+        }
+
+        let base = self
+            .history(key)?
+            .into_iter()
+            .find(|entry| entry.timestamp < conflict.remote_timestamp)
+            .filter(|entry| !entry.deleted)
+            .map(|entry| entry.value);
+        let Some(merged) = strategy.merge(base.as_deref(), &local, &conflict.remote_value) else {
+            return Ok(None);
+        };
+        let change = self.settle_conflict(&conflict, merged, false, machine_id)?;
+        Ok(Some((change, strategy)))
+    }
+
+    /// The newest unresolved conflict on `key`
+    fn open_conflict(&self, key: &str) -> Result<Option<ConflictRecord>> {
+        Ok(self.conflicts()?.into_iter().rev().find(|c| c.key == key))
+    }
+
+    /// Store the value that settles `conflict` as our own write and mark the
+    /// key's conflicts resolved
+    fn settle_conflict(
+        &self,
+        conflict: &ConflictRecord,
+        value: String,
+        deleted: bool,
+        machine_id: &str,
+    ) -> Result<ChangeRecord> {
+        let key = &conflict.key;
+        // Must beat both sides of the conflict under last-write-wins
+        let timestamp = clock::now()
+            .max(conflict.local_timestamp + 1)
+            .max(conflict.remote_timestamp + 1);
+        self.write_remote(key, &value, timestamp, machine_id, deleted, None)?;
+
+        self.conn.execute(
+            "UPDATE conflicts SET resolved = 1 WHERE key = ? AND resolved = 0",
+            params![key],
+        )?;
+
+        Ok((
+            key.clone(),
+            value,
+            timestamp,
+            machine_id.to_string(),
+            deleted,
+        ))
+    }
+
+    /// A timestamp for a local write to `key` that sorts after what is stored,
+    /// so a second write within the same second (or after a change stamped by
+    /// a clock ahead of ours) still wins everywhere under last-write-wins
+    fn stamp_after(&self, key: &str, timestamp: i64) -> Result<i64> {
+        let stored = self
+            .conn
+            .query_row(
+                "SELECT timestamp FROM env_vars WHERE key = ?",
+                params![key],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        Ok(stored.map_or(timestamp, |stored| timestamp.max(stored + 1)))
+    }
+
+    fn record_history(
+        &self,
+        key: &str,
+        value: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+        seq: Option<u64>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO env_history (key, value, timestamp, machine_id, deleted, seq)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![key, value, timestamp, machine_id, deleted as i32, seq],
+        )?;
+        Ok(())
+    }
+
+    fn write_remote(
+        &self,
+        key: &str,
+        value: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+        seq: Option<u64>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO env_vars (key, value, timestamp, machine_id, deleted, seq)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![key, value, timestamp, machine_id, deleted as i32, seq],
+        )?;
+        self.record_history(key, value, timestamp, machine_id, deleted, seq)?;
+        Ok(())
+    }
+
+    pub fn get_changes_since(&self, timestamp: i64) -> Result<Vec<ChangeRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, timestamp, machine_id, deleted FROM env_vars
+             WHERE timestamp > ? ORDER BY timestamp",
+        )?;
+
+        let rows = stmt.query_map(params![timestamp], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get::<_, i32>(4)? != 0,
+            ))
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Position of the newest history entry; every write adds one, so it
+    /// marks how far a peer has been sent our changes
+    pub fn latest_history_id(&self) -> Result<i64> {
+        Ok(self
+            .conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM env_history", [], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// Current state of every key changed after history entry `history_id`
+    /// (of every key for 0)
+    pub fn get_changes_after(&self, history_id: i64) -> Result<Vec<ChangeRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, timestamp, machine_id, deleted FROM env_vars
+             WHERE ?1 = 0 OR key IN (SELECT key FROM env_history WHERE id > ?1)
+             ORDER BY timestamp",
+        )?;
+
+        let rows = stmt.query_map(params![history_id], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get::<_, i32>(4)? != 0,
+            ))
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// What a sync after history entry `history_id` would send, as changes:
+    /// a key whose previous write was a delete (or that has none) is added
+    pub fn outgoing_changes(&self, history_id: i64) -> Result<Vec<ImportChange>> {
+        let mut changes = Vec::new();
+        for (key, value, _, _, deleted) in self.get_changes_after(history_id)? {
+            let old_value = self
+                .history(&key)?
+                .into_iter()
+                .nth(1)
+                .filter(|entry| !entry.deleted)
+                .map(|entry| entry.value);
+            let action = match (&old_value, deleted) {
+                (_, true) => ImportAction::Delete,
+                (None, false) => ImportAction::Add,
+                (Some(_), false) => ImportAction::Update,
+            };
+            changes.push(ImportChange {
+                key,
+                action,
+                old_value,
+                value,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// The history entry `peer` has been sent our changes up to (0 = never)
+    pub fn sync_watermark(&self, peer: &str) -> Result<i64> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT history_id FROM sync_watermarks WHERE peer = ?",
+                params![peer],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    pub fn set_sync_watermark(&self, peer: &str, history_id: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sync_watermarks (peer, history_id, updated_at)
+             VALUES (?, ?, ?)",
+            params![peer, history_id, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Store a sync report, keeping only the newest `SYNC_LOG_SIZE`
+    pub fn record_sync_report(&self, report: &SyncReport) -> Result<i64> {
+        let keys = |keys: &[String]| serde_json::to_string(keys);
+        self.conn.execute(
+            "INSERT INTO sync_log (started_ms, finished_ms, peer, full,
+                                   pushed, pulled, conflicted, skipped)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                report.started_ms,
+                report.finished_ms,
+                report.peer,
+                report.full as i32,
+                keys(&report.pushed)?,
+                keys(&report.pulled)?,
+                keys(&report.conflicted)?,
+                keys(&report.skipped)?,
+            ],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.conn.execute(
+            "DELETE FROM sync_log WHERE id <= ?",
+            params![id - SYNC_LOG_SIZE as i64],
+        )?;
+        Ok(id)
+    }
+
+    /// Record a rotation attempt, keeping only the newest `ROTATION_LOG_SIZE`
+    pub fn record_rotation(
+        &self,
+        key: &str,
+        rotated_at: i64,
+        machine_id: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO rotation_log (key, rotated_at, machine_id, error) VALUES (?, ?, ?, ?)",
+            params![key, rotated_at, machine_id, error],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.conn.execute(
+            "DELETE FROM rotation_log WHERE id <= ?",
+            params![id - ROTATION_LOG_SIZE as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The newest `limit` rotation attempts, newest first
+    pub fn rotation_log(&self, limit: usize) -> Result<Vec<RotationEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, rotated_at, machine_id, error
+             FROM rotation_log ORDER BY id DESC LIMIT ?",
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok(RotationEntry {
+                id: row.get(0)?,
+                key: row.get(1)?,
+                rotated_at: row.get(2)?,
+                machine_id: row.get(3)?,
+                error: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// The newest `limit` sync reports, newest first
+    pub fn sync_reports(&self, limit: usize) -> Result<Vec<SyncReport>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, started_ms, finished_ms, peer, full, pushed, pulled, conflicted, skipped
+             FROM sync_log ORDER BY id DESC LIMIT ?",
+        )?;
+        let keys = |row: &rusqlite::Row, index: usize| -> rusqlite::Result<Vec<String>> {
+            let json: String = row.get(index)?;
+            serde_json::from_str(&json).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    index,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })
+        };
+
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok(SyncReport {
+                id: row.get(0)?,
+                started_ms: row.get(1)?,
+                finished_ms: row.get(2)?,
+                peer: row.get(3)?,
+                full: row.get::<_, i32>(4)? != 0,
+                pushed: keys(row, 5)?,
+                pulled: keys(row, 6)?,
+                conflicted: keys(row, 7)?,
+                skipped: keys(row, 8)?,
+            })
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Counts for `envmesh-cli stats`, with writes since `since` (Unix
+    /// seconds) counted apart
+    pub fn stats(&self, since: i64) -> Result<StoreStats> {
+        let mut stats = StoreStats {
+            since,
+            ..Default::default()
+        };
+
+        let mut stmt = self.conn.prepare("SELECT key, deleted FROM env_vars")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?;
+        for row in rows {
+            let (key, deleted) = row?;
+            if deleted {
+                stats.tombstones += 1;
+            } else if key.starts_with(files::FILE_KEY_PREFIX) {
+                stats.files += 1;
+            } else if !key.starts_with(locks::LOCK_KEY_PREFIX)
+                && !key.starts_with(revoke::REVOKED_KEY_PREFIX)
+            {
+                stats.variables += 1;
+                *stats
+                    .namespaces
+                    .entry(split_key(&key).0.to_string())
+                    .or_default() += 1;
+            }
+        }
+
+        stats.history_entries =
+            self.conn
+                .query_row("SELECT COUNT(*) FROM env_history", [], |row| row.get(0))?;
+        stats.deepest_history = self
+            .conn
+            .query_row(
+                "SELECT key, COUNT(*) AS depth FROM env_history
+                 GROUP BY key ORDER BY depth DESC, key LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT machine_id, COUNT(*), SUM(timestamp >= ?) FROM env_history
+             GROUP BY machine_id",
+        )?;
+        let rows = stmt.query_map(params![since], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        for row in rows {
+            let (machine_id, total, recent) = row?;
+            stats.recent_changes += recent;
+            stats
+                .writes
+                .insert(machine_id, MachineWrites { total, recent });
+        }
+
+        let (pages, page_size): (u64, u64) = self.conn.query_row(
+            "SELECT page_count, page_size FROM pragma_page_count, pragma_page_size",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        stats.size_bytes = pages * page_size;
+        Ok(stats)
+    }
+
+    /// Check the database file and the invariants the schema and sync
+    /// engine rely on. Tables are scanned without their indexes, since a
+    /// damaged index is what hides duplicate rows.
+    pub fn fsck(&self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        report.integrity = problems.into_iter().filter(|p| p != "ok").collect();
+
+        for (table, columns, label) in UNIQUE_ROWS {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT {label}, COUNT(*) FROM {table} NOT INDEXED
+                 GROUP BY {columns} HAVING COUNT(*) > 1"
+            ))?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+            })?;
+            for row in rows {
+                let (key, count) = row?;
+                report
+                    .duplicates
+                    .push(format!("{}: {} ({} rows)", table, key, count));
+            }
+        }
+
+        for (check, sql) in VIOLATIONS {
+            let mut stmt = self.conn.prepare(sql)?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            for row in rows {
+                report.violations.push(format!("{}: {}", row?, check));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fix what `fsck` can fix in place: keep one of each duplicated row
+    /// (the newest variable, the highest of our sequence numbers, the lowest
+    /// floor of what we received), drop or correct violating rows, and
+    /// rebuild the indexes. Returns what is still wrong afterwards.
+    pub fn repair(&self) -> Result<FsckReport> {
+        self.transaction(|s| {
+            s.conn.execute_batch(
+                "DELETE FROM env_vars WHERE rowid IN (
+                     SELECT a.rowid FROM env_vars AS a NOT INDEXED
+                     JOIN env_vars AS b NOT INDEXED ON a.key = b.key
+                     WHERE a.timestamp < b.timestamp
+                        OR (a.timestamp = b.timestamp AND a.rowid < b.rowid)
+                 );
+                 DELETE FROM machine_seqs WHERE rowid IN (
+                     SELECT a.rowid FROM machine_seqs AS a NOT INDEXED
+                     JOIN machine_seqs AS b NOT INDEXED ON a.machine_id = b.machine_id
+                     WHERE a.last_seq < b.last_seq
+                        OR (a.last_seq = b.last_seq AND a.rowid < b.rowid)
+                 );
+                 DELETE FROM received_seq_floors WHERE rowid IN (
+                     SELECT a.rowid FROM received_seq_floors AS a NOT INDEXED
+                     JOIN received_seq_floors AS b NOT INDEXED ON a.machine_id = b.machine_id
+                     WHERE a.through > b.through
+                        OR (a.through = b.through AND a.rowid < b.rowid)
+                 );
+                 DELETE FROM env_tags WHERE rowid NOT IN (
+                     SELECT MAX(rowid) FROM env_tags NOT INDEXED GROUP BY key, tag
+                 );
+                 DELETE FROM received_seqs WHERE rowid NOT IN (
+                     SELECT MAX(rowid) FROM received_seqs NOT INDEXED GROUP BY machine_id, seq
+                 );
+
+                 DELETE FROM env_vars WHERE key = '';
+                 DELETE FROM env_history WHERE key = '';
+                 UPDATE env_vars SET deleted = 1 WHERE deleted NOT IN (0, 1);
+                 UPDATE env_history SET deleted = 1 WHERE deleted NOT IN (0, 1);
+                 DELETE FROM received_seqs WHERE seq <= (
+                     SELECT through FROM received_seq_floors AS f
+                     WHERE f.machine_id = received_seqs.machine_id
+                 );
+                 UPDATE machine_seqs SET last_seq = (
+                     SELECT MAX(seq) FROM env_history AS h
+                     WHERE h.machine_id = machine_seqs.machine_id
+                 )
+                 WHERE last_seq < (
+                     SELECT MAX(seq) FROM env_history AS h
+                     WHERE h.machine_id = machine_seqs.machine_id
+                 );
+
+                 REINDEX;",
+            )?;
+            Ok(())
+        })?;
+        self.fsck()
+    }
+
+    /// Empty what peers can send back (variables, history, tags, conflicts
+    /// and what we received from whom), for rebuilding a store `repair`
+    /// couldn't fix. Our own sequence numbering stays, so peers don't
+    /// mistake our next changes for ones they have.
+    pub fn clear_replicated(&self) -> Result<()> {
+        self.transaction(|s| {
+            for table in REPLICATED_TABLES {
+                s.conn.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            Ok(())
+        })?;
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Forget what was received from and sent to peers, keeping every
+    /// variable: gaps are no longer asked for, and the next push sends
+    /// everything (`envmesh-cli resync`)
+    pub fn reset_sync_state(&self) -> Result<()> {
+        self.transaction(|s| {
+            for table in ["received_seqs", "received_seq_floors", "sync_watermarks"] {
+                s.conn.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Hold an incoming change (`change`, as JSON) for approval, and count
+    /// its `seq` as received so it isn't asked for again; false if it is
+    /// held already
+    #[allow(clippy::too_many_arguments)]
+    pub fn quarantine_change(
+        &self,
+        key: &str,
+        timestamp: i64,
+        machine_id: &str,
+        deleted: bool,
+        seq: Option<u64>,
+        change: &str,
+        reason: &str,
+    ) -> Result<bool> {
+        self.transaction(|s| {
+            let held = s.conn.execute(
+                "INSERT OR IGNORE INTO quarantine
+                     (key, timestamp, machine_id, deleted, change, reason, received_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    key,
+                    timestamp,
+                    machine_id,
+                    deleted,
+                    change,
+                    reason,
+                    Utc::now().timestamp()
+                ],
+            )? > 0;
+            if let Some(seq) = seq {
+                s.record_seq(machine_id, seq)?;
+            }
+            Ok(held)
+        })
+    }
+
+    /// Held changes, oldest first
+    pub fn quarantined(&self) -> Result<Vec<QuarantinedChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, key, timestamp, machine_id, deleted, reason, received_at, change
+             FROM quarantine ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(QuarantinedChange {
+                id: row.get(0)?,
+                key: row.get(1)?,
+                timestamp: row.get(2)?,
+                machine_id: row.get(3)?,
+                deleted: row.get(4)?,
+                reason: row.get(5)?,
+                received_at: row.get(6)?,
+                change: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Remove the held changes `ids` (every one if empty) and return them
+    pub fn take_quarantined(&self, ids: &[i64]) -> Result<Vec<QuarantinedChange>> {
+        self.transaction(|s| {
+            let taken: Vec<_> = s
+                .quarantined()?
+                .into_iter()
+                .filter(|held| ids.is_empty() || ids.contains(&held.id))
+                .collect();
+            for held in &taken {
+                s.conn
+                    .execute("DELETE FROM quarantine WHERE id = ?", params![held.id])?;
+            }
+            Ok(taken)
+        })
+    }
+
+    /// Deleted variables, most recently deleted first
+    pub fn trash(&self) -> Result<Vec<TrashedVar>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, timestamp, machine_id,
+                    EXISTS (SELECT 1 FROM env_history h
+                            WHERE h.key = env_vars.key AND h.deleted = 0)
+             FROM env_vars
+             WHERE deleted = 1
+               -- Purged tombstones have no history left
+               AND EXISTS (SELECT 1 FROM env_history h WHERE h.key = env_vars.key)
+             ORDER BY timestamp DESC, key",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(TrashedVar {
+                key: row.get(0)?,
+                deleted_at: row.get(1)?,
+                machine_id: row.get(2)?,
+                restorable: row.get(3)?,
+            })
+        })?;
+        let mut trash = Vec::new();
+        for row in rows {
+            let var = row?;
+            // Variables only: not file secrets, unlocks or revocations
+            if !var.key.starts_with(files::FILE_KEY_PREFIX)
+                && !var.key.starts_with(locks::LOCK_KEY_PREFIX)
+                && !var.key.starts_with(revoke::REVOKED_KEY_PREFIX)
+            {
+                trash.push(var);
+            }
+        }
+        Ok(trash)
+    }
+
+    /// The value `key` had before it was deleted, if it is deleted and
+    /// history still has one
+    pub fn trashed_value(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT h.value FROM env_history h
+                 JOIN env_vars v ON v.key = h.key AND v.deleted = 1
+                 WHERE h.key = ? AND h.deleted = 0
+                 ORDER BY h.timestamp DESC, h.id DESC LIMIT 1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Forget the values of variables deleted before `before` for good: their
+    /// history goes, and the tombstone keeps no value. The tombstone itself
+    /// stays, so a peer that missed the delete can't bring the variable
+    /// back. Returns how many were purged.
+    pub fn purge_trash(&self, before: i64) -> Result<usize> {
+        self.transaction(|s| {
+            let keys: Vec<String> = s
+                .trash()?
+                .into_iter()
+                .filter(|var| var.deleted_at < before)
+                .map(|var| var.key)
+                .collect();
+            for key in &keys {
+                s.conn
+                    .execute("UPDATE env_vars SET value = '' WHERE key = ?", params![key])?;
+                s.conn
+                    .execute("DELETE FROM env_history WHERE key = ?", params![key])?;
+            }
+            Ok(keys.len())
+        })
+    }
+
+    /// Variables in the store, deleted ones not counted
+    pub fn variable_count(&self) -> Result<usize> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM env_vars WHERE deleted = 0",
+            [],
+            |row| row.get::<_, i64>(0),
+        )? as usize)
+    }
+
+    /// When a change signed by `device` was last let through (unix seconds)
+    pub fn device_last_seen(&self, device: &str) -> Result<Option<i64>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT last_seen FROM devices_seen WHERE device = ?",
+                params![device],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn see_device(&self, device: &str, at: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO devices_seen (device, last_seen) VALUES (?, ?)",
+            params![device, at],
+        )?;
+        Ok(())
+    }
+
+    /// Variable -> digest of the value `vault` and the store held after its
+    /// last round (`vaults.rs`)
+    pub fn vault_digests(&self, vault: &str) -> Result<HashMap<String, String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT var, digest FROM vault_digests WHERE vault = ?")?;
+        let digests = stmt
+            .query_map(params![vault], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(digests)
+    }
+
+    pub fn set_vault_digests(&self, vault: &str, digests: &[(String, String)]) -> Result<()> {
+        self.transaction(|s| {
+            for (var, digest) in digests {
+                s.conn.execute(
+                    "INSERT OR REPLACE INTO vault_digests (vault, var, digest) VALUES (?, ?, ?)",
+                    params![vault, var, digest],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Remember a bundle exported with our changes up to history entry
+    /// `history_id`, so later ones can hold only what came after it
+    pub fn record_bundle_export(
+        &self,
+        id: &str,
+        history_id: i64,
+        namespaces: &[String],
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO bundle_exports (id, history_id, namespaces, created_at)
+             VALUES (?, ?, ?, ?)",
+            params![
+                id,
+                history_id,
+                serde_json::to_string(namespaces)?,
+                clock::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The history entry and namespaces bundle `id` was exported with
+    pub fn bundle_export(&self, id: &str) -> Result<Option<(i64, Vec<String>)>> {
+        let row: Option<(i64, String)> = self
+            .conn
+            .query_row(
+                "SELECT history_id, namespaces FROM bundle_exports WHERE id = ?",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        row.map(|(history_id, namespaces)| Ok((history_id, serde_json::from_str(&namespaces)?)))
+            .transpose()
+    }
+
+    pub fn record_bundle_import(
+        &self,
+        id: &str,
+        machine_id: &str,
+        since: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO bundle_imports (id, machine_id, since, imported_at)
+             VALUES (?, ?, ?, ?)",
+            params![id, machine_id, since, clock::now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn bundle_imported(&self, id: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM bundle_imports WHERE id = ?",
+                params![id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Sequence numbers to send `changes` with: the one each is stored with,
+    /// or for an unsequenced change written by `local_machine`, the next in
+    /// its sequence. None for changes that are no longer current, or that
+    /// were never numbered (older peers, restored backups).
+    pub fn sequence(
+        &self,
+        changes: &[ChangeRecord],
+        local_machine: Option<&str>,
+    ) -> Result<Vec<Option<u64>>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut seqs = Vec::with_capacity(changes.len());
+        for (key, _, timestamp, machine_id, _) in changes {
+            let stored = self
+                .conn
+                .query_row(
+                    "SELECT seq FROM env_vars WHERE key = ? AND timestamp = ? AND machine_id = ?",
+                    params![key, timestamp, machine_id],
+                    |row| row.get::<_, Option<u64>>(0),
+                )
+                .optional()?;
+            let seq = match stored {
+                Some(None) if local_machine == Some(machine_id.as_str()) => {
+                    let seq = self.next_seq(machine_id)?;
+                    self.conn.execute(
+                        "UPDATE env_vars SET seq = ? WHERE key = ?",
+                        params![seq, key],
+                    )?;
+                    self.conn.execute(
+                        "UPDATE env_history SET seq = ? WHERE id = (
+                             SELECT MAX(id) FROM env_history
+                             WHERE key = ? AND timestamp = ? AND machine_id = ?
+                         )",
+                        params![seq, key, timestamp, machine_id],
+                    )?;
+                    Some(seq)
+                }
+                stored => stored.flatten(),
+            };
+            seqs.push(seq);
+        }
+        tx.commit()?;
+        Ok(seqs)
+    }
+
+    fn next_seq(&self, machine_id: &str) -> Result<u64> {
+        let seq = self.conn.query_row(
+            "INSERT INTO machine_seqs (machine_id, last_seq) VALUES (?, 1)
+             ON CONFLICT (machine_id) DO UPDATE SET last_seq = last_seq + 1
+             RETURNING last_seq",
+            params![machine_id],
+            |row| row.get(0),
+        )?;
+        // A machine ID we haven't numbered for before: drop the oldest
+        if seq == 1 {
+            self.conn.execute(
+                "DELETE FROM machine_seqs WHERE rowid NOT IN (
+                     SELECT rowid FROM machine_seqs ORDER BY rowid DESC LIMIT ?
+                 )",
+                params![MAX_SEQ_MACHINES],
+            )?;
+        }
+        Ok(seq)
+    }
+
+    /// Whether change `seq` from `machine_id` was applied already
+    pub fn seq_received(&self, machine_id: &str, seq: u64) -> Result<bool> {
+        if seq <= self.seq_floor(machine_id)? {
+            return Ok(true);
+        }
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM received_seqs WHERE machine_id = ? AND seq = ?",
+                params![machine_id, seq],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Everything up to here has been received from `machine_id`
+    fn seq_floor(&self, machine_id: &str) -> Result<u64> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT through FROM received_seq_floors WHERE machine_id = ?",
+                params![machine_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    /// Note `seq` as received, folding the run above the floor into it.
+    /// Also for changes we chose not to apply (excluded by our sync
+    /// filter), so they don't leave a gap to ask for.
+    pub fn record_seq(&self, machine_id: &str, seq: u64) -> Result<()> {
+        self.heard_from(machine_id)?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO received_seqs (machine_id, seq) VALUES (?, ?)",
+            params![machine_id, seq],
+        )?;
+        let floor = self.seq_floor(machine_id)?;
+        if seq > floor + SEQ_WINDOW {
+            return self.advance_seq_floor(machine_id, seq - SEQ_WINDOW);
+        }
+        self.fold_seqs(machine_id, floor)
+    }
+
+    /// Everything up to `through` from `machine_id` is reflected in our
+    /// state (a snapshot taken there was applied)
+    pub fn advance_seq_floor(&self, machine_id: &str, through: u64) -> Result<()> {
+        self.heard_from(machine_id)?;
+        if through <= self.seq_floor(machine_id)? {
+            return Ok(());
+        }
+        self.conn.execute(
+            "DELETE FROM received_seqs WHERE machine_id = ? AND seq <= ?",
+            params![machine_id, through],
+        )?;
+        self.fold_seqs(machine_id, through)
+    }
+
+    /// Move the floor up from `floor` through the received run above it
+    fn fold_seqs(&self, machine_id: &str, floor: u64) -> Result<()> {
+        let mut through = floor;
+        while self.conn.execute(
+            "DELETE FROM received_seqs WHERE machine_id = ? AND seq = ?",
+            params![machine_id, through + 1],
+        )? > 0
+        {
+            through += 1;
+        }
+        if through > self.seq_floor(machine_id)? {
+            self.conn.execute(
+                "UPDATE received_seq_floors SET through = ? WHERE machine_id = ?",
+                params![through, machine_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Note that `machine_id` sent us something now. When it is new to us,
+    /// forget what we received from the machines heard from longest ago
+    /// past `MAX_SEQ_MACHINES`.
+    fn heard_from(&self, machine_id: &str) -> Result<()> {
+        let known = self.conn.execute(
+            "UPDATE received_seq_floors SET heard_at = ? WHERE machine_id = ?",
+            params![clock::now(), machine_id],
+        )? > 0;
+        if known {
+            return Ok(());
+        }
+        self.conn.execute(
+            "INSERT INTO received_seq_floors (machine_id, through, heard_at) VALUES (?, 0, ?)",
+            params![machine_id, clock::now()],
+        )?;
+        let forgotten = "SELECT machine_id FROM received_seq_floors
+                         ORDER BY heard_at DESC, rowid DESC LIMIT -1 OFFSET ?";
+        self.conn.execute(
+            &format!(
+                "DELETE FROM received_seqs WHERE machine_id IN ({})",
+                forgotten
+            ),
+            params![MAX_SEQ_MACHINES],
+        )?;
+        self.conn.execute(
+            &format!(
+                "DELETE FROM received_seq_floors WHERE machine_id IN ({})",
+                forgotten
+            ),
+            params![MAX_SEQ_MACHINES],
+        )?;
+        Ok(())
+    }
+
+    /// For each machine, the sequence number our state reflects every change
+    /// up to: our own numbering, and the floor of what others sent us
+    pub fn seq_points(&self) -> Result<BTreeMap<String, u64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT machine_id, MAX(seq) FROM (
+                 SELECT machine_id, last_seq AS seq FROM machine_seqs
+                 UNION ALL
+                 SELECT machine_id, through AS seq FROM received_seq_floors
+                 WHERE through > 0
+             ) GROUP BY machine_id",
+        )?;
+        let points = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(points)
+    }
+
+    /// Highest sequence number received from `machine_id` (0 for none)
+    pub fn highest_seq(&self, machine_id: &str) -> Result<u64> {
+        let above: Option<u64> = self.conn.query_row(
+            "SELECT MAX(seq) FROM received_seqs WHERE machine_id = ?",
+            params![machine_id],
+            |row| row.get(0),
+        )?;
+        Ok(above.unwrap_or(0).max(self.seq_floor(machine_id)?))
+    }
+
+    /// Up to `limit` of the sequence numbers below `seq` never received from
+    /// `machine_id`, the newest first
+    pub fn missing_seqs(&self, machine_id: &str, seq: u64, limit: usize) -> Result<Vec<u64>> {
+        let floor = self.seq_floor(machine_id)?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT seq FROM received_seqs WHERE machine_id = ? AND seq < ?")?;
+        let received = stmt
+            .query_map(params![machine_id, seq], |row| row.get(0))?
+            .collect::<rusqlite::Result<std::collections::HashSet<u64>>>()?;
+        Ok((floor + 1..seq)
+            .rev()
+            .filter(|candidate| !received.contains(candidate))
+            .take(limit)
+            .collect())
+    }
+
+    /// The changes `machine_id` numbered `seqs`, as far as our history has
+    /// them
+    pub fn changes_by_seq(
+        &self,
+        machine_id: &str,
+        seqs: &[u64],
+    ) -> Result<Vec<(ChangeRecord, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, timestamp, machine_id, deleted, seq FROM env_history
+             WHERE machine_id = ? AND seq = ?",
+        )?;
+        let mut results = Vec::new();
+        for seq in seqs {
+            let found = stmt
+                .query_row(params![machine_id, seq], |row| {
+                    Ok((
+                        (
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get::<_, i32>(4)? != 0,
+                        ),
+                        row.get(5)?,
+                    ))
+                })
+                .optional()?;
+            results.extend(found);
+        }
+        Ok(results)
+    }
+
+    /// Newest change timestamp written by each machine
+    pub fn newest_change_by_machine(&self) -> Result<Vec<(String, i64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT machine_id, MAX(timestamp) FROM env_vars GROUP BY machine_id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_storage() -> EnvStorage {
+        EnvStorage::new(PathBuf::from(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn test_apply_remote_last_write_wins() {
+        let storage = memory_storage();
+
+        let outcome = storage
+            .apply_remote("KEY", "old", 100, "machine-a", false)
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+
+        let outcome = storage
+            .apply_remote("KEY", "new", 200, "machine-b", false)
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+        assert_eq!(storage.get("KEY").unwrap().unwrap().0, "new");
+
+        // Redelivery of what we already have is a no-op
+        let outcome = storage
+            .apply_remote("KEY", "new", 200, "machine-b", false)
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+    }
+
+    #[test]
+    fn test_local_writes_sort_after_stored() {
+        let storage = memory_storage();
+        storage.set_at("KEY", "first", "machine-a", 100).unwrap();
+        storage.set_at("KEY", "second", "machine-a", 100).unwrap();
+        assert_eq!(storage.get("KEY").unwrap().unwrap().1, 101);
+
+        // The tombstone carries the deleter, so peers order it correctly
+        storage.delete_at("KEY", "machine-b", 50).unwrap();
+        let changes = storage.get_changes_since(0).unwrap();
+        assert_eq!(
+            changes[0],
+            (
+                "KEY".to_string(),
+                "second".to_string(),
+                102,
+                "machine-b".to_string(),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_apply_remote_detects_conflict() {
+        let storage = memory_storage();
+        storage
+            .apply_remote("KEY", "mine", 200, "machine-a", false)
+            .unwrap();
+
+        let outcome = storage
+            .apply_remote("KEY", "theirs", 100, "machine-b", false)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            ApplyOutcome::Conflict {
+                local_machine: "machine-a".to_string()
+            }
+        );
+        assert_eq!(storage.get("KEY").unwrap().unwrap().0, "mine");
+    }
+
+    #[test]
+    fn test_resolve_conflict_keeps_chosen_side() {
+        let storage = memory_storage();
+        storage
+            .apply_remote("KEY", "mine", 200, "machine-a", false)
+            .unwrap();
+        storage
+            .apply_remote("KEY", "theirs", 100, "machine-b", false)
+            .unwrap();
+
+        // Redelivery of the same losing change is not a second conflict
+        let outcome = storage
+            .apply_remote("KEY", "theirs", 100, "machine-b", false)
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+        assert_eq!(storage.conflicts().unwrap().len(), 1);
+
+        let (_, value, timestamp, _, _) = storage
+            .resolve_conflict("KEY", ConflictChoice::Remote, "machine-a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "theirs");
+        assert!(timestamp > 200);
+        assert_eq!(storage.get("KEY").unwrap().unwrap().0, "theirs");
+        assert!(storage.conflicts().unwrap().is_empty());
+
+        let history = storage.history("KEY").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].value, "theirs");
+    }
+
+    #[test]
+    fn test_list_query_filters_and_pages() {
+        let storage = memory_storage();
+        for key in [
+            "AWS_KEY",
+            "AWS_SECRET",
+            "DB_URL",
+            "prod/AWS_KEY",
+            "prod/DB_URL",
+        ] {
+            storage.set(key, "value", "machine-a").unwrap();
+        }
+        storage
+            .set_tags("DB_URL", &["database".to_string()])
+            .unwrap();
+
+        let page = storage
+            .list(&ListQuery {
+                prefix: Some("AWS_".to_string()),
+                namespace: Some(DEFAULT_NAMESPACE.to_string()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.vars.len(), 1);
+        assert_eq!(page.vars[0].key, "AWS_KEY");
+
+        let page = storage
+            .list(&ListQuery {
+                namespace: Some("prod".to_string()),
+                search: Some("url".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.vars[0].key, "prod/DB_URL");
+
+        let page = storage
+            .list(&ListQuery {
+                tag: Some("database".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.vars[0].tags, vec!["database".to_string()]);
+    }
+
+    #[test]
+    fn test_import_preview_and_apply() {
+        let storage = memory_storage();
+        storage.set("KEEP", "same", "machine-a").unwrap();
+        storage.set("CHANGE", "old", "machine-a").unwrap();
+
+        let vars = vec![
+            ("KEEP".to_string(), "same".to_string()),
+            ("CHANGE".to_string(), "new".to_string()),
+            ("ADD".to_string(), "fresh".to_string()),
+        ];
+
+        let preview = storage.import(&vars, "machine-a", true).unwrap();
+        let actions: Vec<_> = preview.iter().map(|c| c.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                ImportAction::Unchanged,
+                ImportAction::Update,
+                ImportAction::Add
+            ]
+        );
+        assert!(storage.get("ADD").unwrap().is_none());
+
+        storage.import(&vars, "machine-a", false).unwrap();
+        assert_eq!(storage.get("CHANGE").unwrap().unwrap().0, "new");
+        assert_eq!(storage.get("ADD").unwrap().unwrap().0, "fresh");
+    }
+
+    #[test]
+    fn test_outgoing_changes_tell_adds_from_updates() {
+        let storage = memory_storage();
+        storage.set("CHANGED", "old", "machine-a").unwrap();
+        storage.set("CHANGED", "new", "machine-a").unwrap();
+        storage.set("FRESH", "v", "machine-a").unwrap();
+        storage.set("GONE", "v", "machine-a").unwrap();
+        storage.delete("GONE", "machine-a").unwrap();
+        storage.set("GONE", "back", "machine-a").unwrap();
+
+        let mut changes = storage.outgoing_changes(0).unwrap();
+        changes.sort_by(|a, b| a.key.cmp(&b.key));
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.key.as_str(), c.action, c.old_value.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("CHANGED", ImportAction::Update, Some("old")),
+                ("FRESH", ImportAction::Add, None),
+                ("GONE", ImportAction::Add, None),
+            ]
+        );
+
+        storage.delete("FRESH", "machine-a").unwrap();
+        let deleted = storage.outgoing_changes(0).unwrap();
+        let fresh = deleted.iter().find(|c| c.key == "FRESH").unwrap();
+        assert_eq!(fresh.action, ImportAction::Delete);
+    }
+
+    #[test]
+    fn test_rename_links_history() {
+        let storage = memory_storage();
+        storage.set("staging/DB_URL", "db", "machine-a").unwrap();
+        storage
+            .set_tags("staging/DB_URL", &["database".to_string()])
+            .unwrap();
+        storage.set("prod/TAKEN", "v", "machine-a").unwrap();
+
+        assert!(storage
+            .rename("staging/DB_URL", "prod/TAKEN", "machine-a")
+            .is_err());
+        assert!(storage.rename("MISSING", "prod/NEW", "machine-a").is_err());
+
+        storage
+            .rename("staging/DB_URL", "prod/DB_URL", "machine-a")
+            .unwrap();
+        assert!(storage.get("staging/DB_URL").unwrap().is_none());
+        assert_eq!(storage.get("prod/DB_URL").unwrap().unwrap().0, "db");
+        assert_eq!(
+            storage.tags("prod/DB_URL").unwrap(),
+            vec!["database".to_string()]
+        );
+
+        let moved = &storage.history("prod/DB_URL").unwrap()[0];
+        assert_eq!(moved.renamed_from.as_deref(), Some("staging/DB_URL"));
+        let tombstone = &storage.history("staging/DB_URL").unwrap()[0];
+        assert!(tombstone.deleted);
+        assert_eq!(tombstone.renamed_to.as_deref(), Some("prod/DB_URL"));
+    }
+
+    #[test]
+    fn test_copy_between_namespaces() {
+        let storage = memory_storage();
+        storage.set("dev/DB_URL", "dev-db", "machine-a").unwrap();
+        storage.set("dev/DEBUG", "1", "machine-a").unwrap();
+        storage.set("staging/DEBUG", "1", "machine-a").unwrap();
+
+        let pairs = vec![
+            ("dev/DB_URL".to_string(), "staging/DB_URL".to_string()),
+            ("dev/DEBUG".to_string(), "staging/DEBUG".to_string()),
+        ];
+        let preview = storage.copy(&pairs, "machine-a", true).unwrap();
+        let actions: Vec<_> = preview.iter().map(|c| c.action).collect();
+        assert_eq!(actions, vec![ImportAction::Add, ImportAction::Unchanged]);
+        assert!(storage.get("staging/DB_URL").unwrap().is_none());
+
+        storage.copy(&pairs, "machine-a", false).unwrap();
+        assert_eq!(storage.get("staging/DB_URL").unwrap().unwrap().0, "dev-db");
+        assert_eq!(storage.get("dev/DB_URL").unwrap().unwrap().0, "dev-db");
+        let copied = &storage.history("staging/DB_URL").unwrap()[0];
+        assert_eq!(copied.copied_from.as_deref(), Some("dev/DB_URL"));
+
+        let missing = vec![("dev/NOPE".to_string(), "staging/NOPE".to_string())];
+        assert!(storage.copy(&missing, "machine-a", false).is_err());
+    }
+
+    #[test]
+    fn test_delete_matching_preview_and_apply() {
+        let storage = memory_storage();
+        storage.set("staging/DB_URL", "db", "machine-a").unwrap();
+        storage.set("staging/API_KEY", "key", "machine-a").unwrap();
+        storage.set("prod/DB_URL", "prod-db", "machine-a").unwrap();
+
+        let preview = storage
+            .delete_matching("staging/*", "machine-a", true)
+            .unwrap();
+        let keys: Vec<_> = preview.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, vec!["staging/API_KEY", "staging/DB_URL"]);
+        assert!(preview.iter().all(|c| c.action == ImportAction::Delete));
+        assert!(storage.get("staging/DB_URL").unwrap().is_some());
+
+        storage
+            .delete_matching("staging/*", "machine-a", false)
+            .unwrap();
+        assert!(storage.get("staging/DB_URL").unwrap().is_none());
+        assert!(storage.get("prod/DB_URL").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_trash_lists_and_purges_deleted_vars() {
+        let storage = memory_storage();
+        storage.set_at("OLD", "v1", "machine-a", 100).unwrap();
+        storage.set_at("OLD", "v2", "machine-a", 110).unwrap();
+        storage.delete_at("OLD", "machine-a", 120).unwrap();
+        storage.set_at("NEW", "n", "machine-a", 100).unwrap();
+        storage.delete_at("NEW", "machine-b", 300).unwrap();
+        storage.set("KEPT", "k", "machine-a").unwrap();
+        // A delete that arrived with nothing to restore
+        storage
+            .apply_remote("REMOTE", "", 100, "machine-b", true)
+            .unwrap();
+
+        let trash = storage.trash().unwrap();
+        let keys: Vec<_> = trash.iter().map(|var| var.key.as_str()).collect();
+        assert_eq!(keys, vec!["NEW", "OLD", "REMOTE"]);
+        assert_eq!(trash[0].machine_id, "machine-b");
+        assert!(!trash[2].restorable);
+        assert_eq!(storage.trashed_value("OLD").unwrap().as_deref(), Some("v2"));
+        assert_eq!(storage.trashed_value("KEPT").unwrap(), None);
+        assert!(storage.is_deleted("OLD").unwrap());
+        assert!(!storage.is_deleted("KEPT").unwrap());
+        assert!(!storage.is_deleted("NEVER_SET").unwrap());
+
+        assert_eq!(storage.purge_trash(200).unwrap(), 2);
+        assert!(storage.history("OLD").unwrap().is_empty());
+        assert_eq!(storage.trashed_value("OLD").unwrap(), None);
+        // The tombstone stays, so an older write can't bring it back
+        assert!(storage.is_deleted("OLD").unwrap());
+        let outcome = storage
+            .apply_remote("OLD", "v1", 110, "machine-c", false)
+            .unwrap();
+        assert_ne!(outcome, ApplyOutcome::Applied);
+        assert!(storage.get("OLD").unwrap().is_none());
+        assert_eq!(storage.purge_trash(200).unwrap(), 0);
+        let keys: Vec<_> = storage
+            .trash()
+            .unwrap()
+            .into_iter()
+            .map(|var| var.key)
+            .collect();
+        assert_eq!(keys, vec!["NEW"]);
+        assert!(storage.get("KEPT").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_restore_preview_matches_restore() {
+        let storage = memory_storage();
+        storage
+            .apply_remote("OLD", "backed-up", 100, "machine-a", false)
+            .unwrap();
+        storage
+            .apply_remote("GONE", "v", 100, "machine-a", false)
+            .unwrap();
+
+        let vars = vec![
+            (
+                "OLD".to_string(),
+                "stale".to_string(),
+                50,
+                "machine-a".to_string(),
+                false,
+            ),
+            (
+                "GONE".to_string(),
+                String::new(),
+                200,
+                "machine-a".to_string(),
+                true,
+            ),
+            (
+                "NEW".to_string(),
+                "n".to_string(),
+                200,
+                "machine-a".to_string(),
+                false,
+            ),
+        ];
+        let actions: Vec<_> = storage
+            .preview_restore(&vars)
+            .unwrap()
+            .iter()
+            .map(|c| c.action)
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                ImportAction::Unchanged,
+                ImportAction::Delete,
+                ImportAction::Add
+            ]
+        );
+        assert!(storage.get("NEW").unwrap().is_none());
+
+        assert_eq!(storage.restore(&vars, &[], &[]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_files_are_not_listed_as_variables() {
+        let storage = memory_storage();
+        storage.set("KUBE_CONTEXT", "prod", "machine-a").unwrap();
+        let blob = FileBlob::new(b"apiVersion: v1\n");
+        storage
+            .set(
+                &files::file_key("kubeconfig"),
+                &blob.to_value().unwrap(),
+                "machine-a",
+            )
+            .unwrap();
+
+        let page = storage.list(&ListQuery::default()).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.vars[0].key, "KUBE_CONTEXT");
+
+        let files = storage.list_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "kubeconfig");
+        assert_eq!(files[0].sha256, blob.sha256);
+    }
+
+    #[test]
+    fn test_changes_after_watermark() {
+        let storage = memory_storage();
+        storage.set("A", "1", "machine-a").unwrap();
+        storage.set("B", "1", "machine-a").unwrap();
+        assert_eq!(storage.sync_watermark("ws://server").unwrap(), 0);
+        assert_eq!(storage.get_changes_after(0).unwrap().len(), 2);
+
+        let mark = storage.latest_history_id().unwrap();
+        storage.set_sync_watermark("ws://server", mark).unwrap();
+        assert_eq!(storage.sync_watermark("ws://server").unwrap(), mark);
+        assert!(storage.get_changes_after(mark).unwrap().is_empty());
+
+        // Remote changes count too; the current state is what gets sent
+        storage.delete("A", "machine-a").unwrap();
+        storage
+            .apply_remote("C", "2", clock::now_millis(), "machine-b", false)
+            .unwrap();
+        let changes = storage.get_changes_after(mark).unwrap();
+        let keys: Vec<&str> = changes.iter().map(|c| c.0.as_str()).collect();
+        assert_eq!(keys, vec!["A", "C"]);
+        assert!(changes[0].4);
+    }
+
+    #[test]
+    fn test_reset_sync_state_keeps_variables() {
+        let storage = memory_storage();
+        storage.set("A", "1", "machine-a").unwrap();
+        storage
+            .apply_remote_seq("B", "1", 100, "machine-b", false, Some(3))
+            .unwrap();
+        storage.advance_seq_floor("machine-c", 7).unwrap();
+        let mark = storage.latest_history_id().unwrap();
+        storage.set_sync_watermark("ws://server", mark).unwrap();
+
+        storage.reset_sync_state().unwrap();
+        assert_eq!(storage.sync_watermark("ws://server").unwrap(), 0);
+        assert_eq!(storage.highest_seq("machine-b").unwrap(), 0);
+        assert_eq!(storage.highest_seq("machine-c").unwrap(), 0);
+        assert_eq!(storage.list(&ListQuery::default()).unwrap().vars.len(), 2);
+        assert_eq!(storage.latest_history_id().unwrap(), mark);
+    }
+
+    #[test]
+    fn test_locked_keys_refuse_writes_until_unlocked() {
+        let storage = memory_storage();
+        storage.note_own_machine("machine-a", "key-a").unwrap();
+        storage
+            .set("PROD_DB_URL", "postgres://a", "machine-a")
+            .unwrap();
+        assert!(storage.lock("PROD_DB_URL", "machine-b", "key-b").is_err());
+        assert!(storage.lock("MISSING", "machine-a", "key-a").is_err());
+        // Created by a peer: not this device's to lock
+        storage
+            .apply_remote("THEIRS", "v", 100, "machine-c", false)
+            .unwrap();
+        assert!(storage.lock("THEIRS", "machine-a", "key-a").is_err());
+        // Created by this device in an earlier run, under another machine ID
+        storage.note_own_machine("machine-a2", "key-a").unwrap();
+        assert!(storage.lock("PROD_DB_URL", "machine-a2", "key-a").unwrap());
+        assert!(!storage.lock("PROD_DB_URL", "machine-a", "key-a").unwrap());
+        assert_eq!(
+            storage.lock_owner("PROD_DB_URL").unwrap().as_deref(),
+            Some("key-a")
+        );
+
+        // Even the owner has to unlock first
+        assert!(storage.set("PROD_DB_URL", "x", "machine-a").is_err());
+        assert!(storage.delete("PROD_DB_URL", "machine-a").is_err());
+        assert!(storage.rename("PROD_DB_URL", "OTHER", "machine-a").is_err());
+        let vars = vec![("PROD_DB_URL".to_string(), "x".to_string())];
+        assert!(storage.import(&vars, "machine-a", false).is_err());
+        assert!(storage
+            .delete_matching("PROD_*", "machine-a", false)
+            .is_err());
+        assert_eq!(
+            storage.get("PROD_DB_URL").unwrap().unwrap().0,
+            "postgres://a"
+        );
+        // The lock itself is not a variable
+        assert_eq!(storage.list(&ListQuery::default()).unwrap().total, 2);
+
+        assert!(storage
+            .unlock("PROD_DB_URL", "machine-b", Some("key-b"))
+            .is_err());
+        assert!(storage
+            .unlock("PROD_DB_URL", "machine-b", None)
+            .unwrap()
+            .is_some());
+        assert!(storage
+            .unlock("PROD_DB_URL", "machine-b", None)
+            .unwrap()
+            .is_none());
+        storage
+            .set("PROD_DB_URL", "postgres://b", "machine-b")
+            .unwrap();
+
+        storage.lock("PROD_DB_URL", "machine-a", "key-a").unwrap();
+        // The owner is the device key, whatever the machine ID is now
+        assert!(storage
+            .unlock("PROD_DB_URL", "machine-a2", Some("key-a"))
+            .unwrap()
+            .is_some());
+        assert!(storage.lock_owner("PROD_DB_URL").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sync_log_keeps_newest_reports() {
+        let storage = memory_storage();
+        assert!(storage.sync_reports(10).unwrap().is_empty());
+
+        for i in 0..SYNC_LOG_SIZE + 5 {
+            let report = SyncReport {
+                started_ms: i as i64,
+                finished_ms: i as i64 + 1,
+                peer: Some("ws://server".to_string()),
+                pushed: vec![format!("KEY_{}", i)],
+                skipped: vec!["LOCAL_ONLY".to_string()],
+                ..Default::default()
+            };
+            storage.record_sync_report(&report).unwrap();
+        }
+
+        let reports = storage.sync_reports(1000).unwrap();
+        assert_eq!(reports.len(), SYNC_LOG_SIZE);
+        let newest = &reports[0];
+        assert_eq!(newest.pushed, vec![format!("KEY_{}", SYNC_LOG_SIZE + 4)]);
+        assert_eq!(newest.skipped, vec!["LOCAL_ONLY".to_string()]);
+        assert_eq!(newest.peer.as_deref(), Some("ws://server"));
+        assert!(newest.pulled.is_empty());
+        assert_eq!(reports.last().unwrap().started_ms, 5);
+        assert_eq!(storage.sync_reports(2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_stats_count_keys_and_writers() {
+        let storage = memory_storage();
+        storage
+            .set_at("prod/DB_URL", "a", "machine-a", 100)
+            .unwrap();
+        storage
+            .set_at("prod/DB_URL", "b", "machine-a", 200)
+            .unwrap();
+        storage
+            .set_at("prod/API_KEY", "k", "machine-b", 300)
+            .unwrap();
+        storage
+            .set_at("LOG_LEVEL", "info", "machine-b", 300)
+            .unwrap();
+        storage.delete_at("LOG_LEVEL", "machine-b", 400).unwrap();
+        storage.set_at("DEBUG", "1", "machine-b", 400).unwrap();
+
+        let stats = storage.stats(300).unwrap();
+        assert_eq!(stats.variables, 3);
+        assert_eq!(stats.namespaces.get("prod"), Some(&2));
+        assert_eq!(stats.namespaces.get(DEFAULT_NAMESPACE), Some(&1));
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(stats.history_entries, 6);
+        assert_eq!(stats.deepest_history, Some(("LOG_LEVEL".to_string(), 2)));
+        assert_eq!(stats.recent_changes, 4);
+        assert_eq!(
+            stats.writes["machine-a"],
+            MachineWrites {
+                total: 2,
+                recent: 0
+            }
+        );
+        assert_eq!(
+            stats.writes["machine-b"],
+            MachineWrites {
+                total: 4,
+                recent: 4
+            }
+        );
+        assert!(stats.size_bytes > 0);
+    }
+
+    #[test]
+    fn test_fsck_finds_and_repairs_violations() {
+        let storage = memory_storage();
+        storage.set_at("API_KEY", "a", "machine-a", 100).unwrap();
+        let change = (
+            "API_KEY".to_string(),
+            "a".to_string(),
+            100,
+            "machine-a".to_string(),
+            false,
+        );
+        assert_eq!(
+            storage.sequence(&[change], Some("machine-a")).unwrap(),
+            vec![Some(1)]
+        );
+        storage.advance_seq_floor("machine-b", 5).unwrap();
+        assert!(storage.fsck().unwrap().is_clean());
+
+        storage
+            .conn
+            .execute_batch(
+                "INSERT INTO env_vars (key, value, timestamp, machine_id, deleted)
+                     VALUES ('', 'x', 1, 'machine-b', 0), ('DEBUG', '1', 1, 'machine-b', 2);
+                 INSERT INTO received_seqs (machine_id, seq) VALUES ('machine-b', 3);
+                 UPDATE env_history SET seq = 4 WHERE key = 'API_KEY';",
+            )
+            .unwrap();
+        let report = storage.fsck().unwrap();
+        assert!(report.integrity.is_empty());
+        assert!(report.duplicates.is_empty());
+        assert_eq!(report.violations.len(), 4, "{:?}", report.violations);
+
+        assert!(storage.repair().unwrap().is_clean());
+        assert_eq!(storage.highest_seq("machine-b").unwrap(), 5);
+        assert!(storage.get("DEBUG").unwrap().is_none());
+        assert_eq!(storage.seq_points().unwrap()["machine-a"], 4);
+
+        storage.clear_replicated().unwrap();
+        assert!(storage.list_all().unwrap().is_empty());
+        assert_eq!(storage.seq_points().unwrap()["machine-a"], 4);
+        assert!(!storage.seq_points().unwrap().contains_key("machine-b"));
+    }
+
+    #[test]
+    fn test_received_seqs_stay_bounded() {
+        let storage = memory_storage();
+
+        // Holes older than the window are given up on
+        storage.record_seq("machine-b", 1).unwrap();
+        storage.record_seq("machine-b", 3).unwrap();
+        assert_eq!(storage.missing_seqs("machine-b", 4, 10).unwrap(), vec![2]);
+        storage.record_seq("machine-b", SEQ_WINDOW + 10).unwrap();
+        assert!(storage.seq_received("machine-b", 2).unwrap());
+        assert_eq!(storage.seq_points().unwrap()["machine-b"], 10);
+
+        // Machines heard from longest ago are forgotten first
+        for n in 0..MAX_SEQ_MACHINES {
+            storage.record_seq(&format!("run-{}", n), 1).unwrap();
+        }
+        let points = storage.seq_points().unwrap();
+        assert_eq!(points.len(), MAX_SEQ_MACHINES);
+        assert!(!points.contains_key("machine-b"));
+
+        // And so are our own machine IDs of past runs
+        for n in 0..=MAX_SEQ_MACHINES {
+            storage.next_seq(&format!("us-{}", n)).unwrap();
+        }
+        let ours: usize = storage
+            .conn
+            .query_row("SELECT COUNT(*) FROM machine_seqs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ours, MAX_SEQ_MACHINES);
+    }
+
+    #[test]
+    fn test_changes_are_sequenced_once_and_applied_once() {
+        let storage = memory_storage();
+        storage.set("A", "1", "machine-a").unwrap();
+        storage.set("B", "1", "machine-a").unwrap();
+        storage
+            .apply_remote("C", "1", clock::now_millis(), "machine-b", false)
+            .unwrap();
+        let changes = storage.get_changes_after(0).unwrap();
+
+        // Only our own changes are numbered, and only once
+        let seqs = storage.sequence(&changes, Some("machine-a")).unwrap();
+        assert_eq!(seqs, vec![Some(1), Some(2), None]);
+        assert_eq!(storage.sequence(&changes, Some("machine-a")).unwrap(), seqs);
+        storage.set("A", "2", "machine-a").unwrap();
+        let changed: Vec<ChangeRecord> = storage
+            .get_changes_after(0)
+            .unwrap()
+            .into_iter()
+            .filter(|change| change.0 == "A")
+            .collect();
+        assert_eq!(
+            storage.sequence(&changed, Some("machine-a")).unwrap(),
+            vec![Some(3)]
+        );
+        // Superseded changes get none
+        assert_eq!(
+            storage
+                .sequence(
+                    &[(
+                        "A".to_string(),
+                        "1".to_string(),
+                        1,
+                        "machine-a".to_string(),
+                        false
+                    )],
+                    Some("machine-a")
+                )
+                .unwrap(),
+            vec![None]
+        );
+        assert_eq!(
+            storage.changes_by_seq("machine-a", &[1, 9]).unwrap().len(),
+            1
+        );
+
+        let apply = |key: &str, seq| {
+            storage
+                .apply_remote_seq(key, "v", clock::now_millis(), "machine-c", false, Some(seq))
+                .unwrap()
+        };
+        assert_eq!(apply("X", 1), ApplyOutcome::Applied);
+        assert_eq!(apply("X", 1), ApplyOutcome::Ignored);
+        assert_eq!(apply("Y", 4), ApplyOutcome::Applied);
+        assert_eq!(storage.highest_seq("machine-c").unwrap(), 4);
+        assert_eq!(
+            storage.missing_seqs("machine-c", 4, 10).unwrap(),
+            vec![3, 2]
+        );
+        assert_eq!(storage.missing_seqs("machine-c", 4, 1).unwrap(), vec![3]);
+
+        // Filling the gap folds everything into the floor
+        apply("Z", 2);
+        apply("Z", 3);
+        assert_eq!(storage.seq_floor("machine-c").unwrap(), 4);
+        assert!(storage.seq_received("machine-c", 2).unwrap());
+        assert!(!storage.seq_received("machine-c", 5).unwrap());
+    }
 }
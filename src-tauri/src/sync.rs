@@ -0,0 +1,2386 @@
+// Sync engine: applies incoming changes and pushes local state to the network
+use anyhow::{anyhow, Result};
+use ed25519_dalek::VerifyingKey;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::access::PeerAccess;
+use crate::client::{IncomingHandle, SyncMessage, WireMessage};
+use crate::clock::{self, ClockConfig};
+use crate::events::{EventBus, MeshEvent};
+use crate::keys::{self, KeyPolicy};
+use crate::links::PeerLink;
+use crate::merge::MergeStrategy;
+use crate::node::{EnvMeshNode, NodeMode};
+use crate::pattern;
+use crate::pool::StoragePool;
+use crate::quarantine::{Facts, Hold, Quarantine, QuarantineConfig};
+use crate::ratelimit::{RateLimit, RateLimitStats, RateLimiter, Verdict};
+use crate::redact::ValueRedacted;
+use crate::replay::{MessageIds, ReplayGuard};
+use crate::revoke::{self, Revocation};
+use crate::signing::{self, DeviceKey};
+use crate::snapshot;
+use crate::storage::{self, ApplyOutcome, EnvStorage, QuarantinedChange, SyncReport};
+use crate::users::Users;
+
+/// How often to re-check for a server connection while there is none
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most missing changes asked for (or answered) in one `seq_request`
+const MAX_SEQ_REQUEST: usize = 64;
+
+/// How long `resync_from()` waits for its peer's snapshot before giving up,
+/// having discarded nothing (a second in tests)
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(if cfg!(test) { 1 } else { 30 });
+
+/// A `resync_from()` waiting for the snapshot of the machine `from`
+struct PendingResync {
+    from: String,
+    overwrite_local: bool,
+    done: oneshot::Sender<Result<usize>>,
+}
+
+/// Which keys take part in sync (`[sync]` config section)
+#[derive(Debug, Clone, Default)]
+pub struct SyncFilter {
+    /// Only sync keys matching one of these patterns (empty = everything)
+    pub include: Vec<String>,
+    /// Never sync keys matching these patterns (wins over include)
+    pub exclude: Vec<String>,
+    /// Only sync these namespaces (empty = all)
+    pub namespaces: Vec<String>,
+}
+
+impl SyncFilter {
+    pub fn allows(&self, key: &str) -> bool {
+        // Every machine has to hear of a revoked device, whatever it syncs
+        if revoke::revoked_device(key).is_some() {
+            return true;
+        }
+        let included = self.include.is_empty() || pattern::matches_any(&self.include, key);
+        included
+            && !pattern::matches_any(&self.exclude, key)
+            && (self.namespaces.is_empty()
+                || self
+                    .namespaces
+                    .iter()
+                    .any(|ns| ns == storage::split_key(key).0))
+    }
+}
+
+/// Keys received since the last sync report, by what became of them
+struct SyncCycle {
+    started_ms: i64,
+    pulled: BTreeSet<String>,
+    conflicted: BTreeSet<String>,
+    skipped: BTreeSet<String>,
+}
+
+impl SyncCycle {
+    fn new() -> Self {
+        Self {
+            started_ms: clock::now_millis(),
+            pulled: BTreeSet::new(),
+            conflicted: BTreeSet::new(),
+            skipped: BTreeSet::new(),
+        }
+    }
+}
+
+/// What `screen()` made of an incoming change
+#[derive(Debug, PartialEq, Eq)]
+enum Screening {
+    Apply,
+    /// Excluded by our sync filter: not applied, but received all the same
+    Skip,
+    Reject,
+}
+
+/// A push some peer hasn't confirmed yet: its sync watermark moves to
+/// `through` once the link has confirmed `frame`
+struct PendingMark {
+    peer: String,
+    through: i64,
+    frame: u64,
+}
+
+/// Shared handles for moving changes between local storage and the network
+#[derive(Clone)]
+pub struct SyncEngine {
+    pub storage: StoragePool,
+    pub node: Arc<Mutex<EnvMeshNode>>,
+    pub events: EventBus,
+    filter: Arc<RwLock<SyncFilter>>,
+    key_policy: Arc<RwLock<KeyPolicy>>,
+    /// Incoming changes per sending machine
+    limiter: Arc<std::sync::Mutex<RateLimiter<String>>>,
+    /// IDs for the changes we send, and those recently received
+    ids: Arc<MessageIds>,
+    seen: Arc<std::sync::Mutex<ReplayGuard>>,
+    /// Signs what we send; incoming changes must verify against `trusted`
+    device_key: Arc<RwLock<Arc<DeviceKey>>>,
+    trusted: Arc<RwLock<Vec<VerifyingKey>>>,
+    /// Set once an unsigned change was let through for want of trusted keys
+    warned_unsigned: Arc<AtomicBool>,
+    /// Machines and device keys whose changes we refuse
+    access: Arc<RwLock<PeerAccess>>,
+    /// Whose changes to what we take, by signing key (`[[users]]`)
+    users: Arc<RwLock<Users>>,
+    clock: Arc<RwLock<ClockConfig>>,
+    /// Seconds the LAN server's clock was ahead of ours when we connected
+    skew: Arc<RwLock<Option<i64>>>,
+    /// Our machine ID; changes written under it are numbered as they are sent
+    machine_id: Arc<RwLock<Option<String>>>,
+    /// Filled in as changes arrive, and written to the sync log by `push()`
+    cycle: Arc<std::sync::Mutex<SyncCycle>>,
+    /// Pushes waiting to be confirmed before watermarks move past them
+    pending_marks: Arc<std::sync::Mutex<Vec<PendingMark>>>,
+    /// Holds risky incoming changes for approval (only delete storms
+    /// without `[quarantine]`)
+    quarantine: Arc<std::sync::Mutex<Quarantine>>,
+    /// At most one resync at a time
+    resync: Arc<std::sync::Mutex<Option<PendingResync>>>,
+}
+
+impl SyncEngine {
+    pub fn new(
+        storage: StoragePool,
+        node: Arc<Mutex<EnvMeshNode>>,
+        events: EventBus,
+        filter: SyncFilter,
+    ) -> Self {
+        Self {
+            storage,
+            node,
+            events,
+            filter: Arc::new(RwLock::new(filter)),
+            key_policy: Arc::new(RwLock::new(KeyPolicy::default())),
+            limiter: Arc::new(std::sync::Mutex::new(
+                RateLimiter::new(RateLimit::default()),
+            )),
+            ids: Arc::new(MessageIds::new()),
+            seen: Arc::new(std::sync::Mutex::new(ReplayGuard::new())),
+            device_key: Arc::new(RwLock::new(Arc::new(DeviceKey::generate()))),
+            trusted: Arc::new(RwLock::new(Vec::new())),
+            warned_unsigned: Arc::new(AtomicBool::new(false)),
+            access: Arc::new(RwLock::new(PeerAccess::default())),
+            users: Arc::new(RwLock::new(Users::default())),
+            clock: Arc::new(RwLock::new(ClockConfig::default())),
+            skew: Arc::new(RwLock::new(None)),
+            machine_id: Arc::new(RwLock::new(None)),
+            cycle: Arc::new(std::sync::Mutex::new(SyncCycle::new())),
+            pending_marks: Arc::new(std::sync::Mutex::new(Vec::new())),
+            quarantine: Arc::new(std::sync::Mutex::new(Quarantine::new(
+                QuarantineConfig::storm_guard(),
+            ))),
+            resync: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    pub fn set_clock_config(&self, config: ClockConfig) {
+        *self.clock.write().unwrap_or_else(|e| e.into_inner()) = config;
+        if let Some(skew) = self.clock_skew() {
+            clock::set_offset(clock::offset_for(skew, &config));
+        }
+    }
+
+    /// Last measured skew against the LAN server, if we connected to one
+    pub fn clock_skew(&self) -> Option<i64> {
+        *self.skew.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The server's `hello`: measure skew, warn past the threshold and
+    /// compensate if configured
+    async fn handle_hello(&self, time_ms: i64, received_ms: i64) {
+        let skew = clock::measure_skew(time_ms, received_ms);
+        let config = *self.clock.read().unwrap_or_else(|e| e.into_inner());
+        *self.skew.write().unwrap_or_else(|e| e.into_inner()) = Some(skew);
+
+        let offset = clock::offset_for(skew, &config);
+        clock::set_offset(offset);
+        if skew.abs() <= config.max_skew_secs {
+            return;
+        }
+
+        let peer = match self.node.lock().await.current_mode() {
+            NodeMode::LanClient { server_addr } => server_addr,
+            _ => "LAN server".to_string(),
+        };
+        tracing::warn!(
+            "Clock is {:+}s off from {}; conflicts may resolve the wrong way{}",
+            skew,
+            peer,
+            if offset != 0 { " (compensating)" } else { "" }
+        );
+        self.events.emit(MeshEvent::ClockSkew {
+            peer,
+            skew_secs: skew,
+            compensated: offset != 0,
+        });
+    }
+
+    /// Number the changes written as `machine_id` when sending them
+    pub fn set_machine_id(&self, machine_id: &str) {
+        *self.machine_id.write().unwrap_or_else(|e| e.into_inner()) = Some(machine_id.to_string());
+    }
+
+    fn machine_id(&self) -> Option<String> {
+        self.machine_id
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Sign outgoing changes with `key` instead of a throwaway one
+    pub fn set_device_key(&self, key: impl Into<Arc<DeviceKey>>) {
+        *self.device_key.write().unwrap_or_else(|e| e.into_inner()) = key.into();
+    }
+
+    pub fn device_public_key(&self) -> String {
+        self.signer().public_key()
+    }
+
+    /// Replace the keys incoming changes must be signed with (empty = any
+    /// signer, unsigned allowed). Our own key is always trusted.
+    pub fn set_trusted_keys(&self, keys: Vec<VerifyingKey>) {
+        *self.trusted.write().unwrap_or_else(|e| e.into_inner()) = keys;
+    }
+
+    /// Replace the peer allow and block lists incoming changes are checked
+    /// against
+    pub fn set_peer_access(&self, access: PeerAccess) {
+        *self.access.write().unwrap_or_else(|e| e.into_inner()) = access;
+    }
+
+    /// Whether the sender of `msg` is allowed, by its (verified) device
+    /// key; unsigned changes are anonymous
+    fn permits(&self, msg: &SyncMessage) -> bool {
+        let ids: Vec<String> = msg.signature.iter().map(|sig| sig.key.clone()).collect();
+        self.access
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .permits(&ids)
+    }
+
+    pub fn users(&self) -> Users {
+        self.users.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the users whose roles incoming and local changes are
+    /// checked against
+    pub fn set_users(&self, users: Users) {
+        *self.users.write().unwrap_or_else(|e| e.into_inner()) = users;
+    }
+
+    /// Whether our own user may change `key`
+    pub fn check_write(&self, key: &str) -> Result<()> {
+        self.users().check_write(&[self.device_public_key()], key)
+    }
+
+    fn signer(&self) -> Arc<DeviceKey> {
+        Arc::clone(&self.device_key.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Stamp a message ID on an outgoing change, if it has none, and sign it
+    pub(crate) fn seal(&self, mut msg: SyncMessage) -> SyncMessage {
+        msg.id.get_or_insert_with(|| self.ids.next());
+        self.signer().sign(&mut msg);
+        msg
+    }
+
+    /// A revocation of `device` signed with our device key
+    pub fn revocation(&self, device: &str, wipe: bool) -> Result<Revocation> {
+        Revocation::new(device, wipe, &self.signer())
+    }
+
+    /// `[security] trusted_keys` and our own key, or none if none are set
+    pub fn trusted_keys(&self) -> Vec<VerifyingKey> {
+        let mut trusted = self
+            .trusted
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if !trusted.is_empty() {
+            trusted.push(self.signer().verifying_key());
+        }
+        trusted
+    }
+
+    fn verify(&self, msg: &SyncMessage) -> Result<()> {
+        let trusted = self.trusted_keys();
+        if trusted.is_empty()
+            && msg.signature.is_none()
+            && !self.warned_unsigned.swap(true, Ordering::Relaxed)
+        {
+            tracing::warn!(
+                "Applying unsigned changes from {} and others: set [security] trusted_keys to refuse them",
+                msg.machine_id
+            );
+        }
+        signing::verify(msg, &trusted)
+    }
+
+    /// Replace the per-machine limit on incoming changes
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.limiter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .set_limit(limit);
+    }
+
+    /// Dropped-change counters and the machines currently banned
+    pub fn rate_limit_status(&self) -> (RateLimitStats, Vec<String>) {
+        let limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        (limiter.stats(), limiter.banned())
+    }
+
+    /// Whether to accept another change from `machine_id` right now
+    fn admit(&self, machine_id: &str) -> bool {
+        let (verdict, ban_secs) = {
+            let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+            let verdict = limiter.check(&machine_id.to_string());
+            (verdict, limiter.limit().ban_secs)
+        };
+
+        match verdict {
+            Verdict::Allow => true,
+            Verdict::Drop | Verdict::Banned => {
+                tracing::debug!("Dropping change from {} (rate limited)", machine_id);
+                false
+            }
+            Verdict::Ban => {
+                tracing::warn!("Banning {} for {}s: too many changes", machine_id, ban_secs);
+                self.events.emit(MeshEvent::PeerBanned {
+                    peer: machine_id.to_string(),
+                    secs: ban_secs,
+                });
+                false
+            }
+        }
+    }
+
+    /// Replace what incoming changes are held for (None: only delete
+    /// storms); changes held already stay held
+    pub fn set_quarantine(&self, config: Option<QuarantineConfig>) {
+        let config = config.unwrap_or_else(QuarantineConfig::storm_guard);
+        *self.quarantine.lock().unwrap_or_else(|e| e.into_inner()) = Quarantine::new(config);
+    }
+
+    /// Why `msg` should be held instead of applied, if it should
+    async fn hold(&self, msg: &SyncMessage) -> Result<Option<Hold>> {
+        let change = msg.clone();
+        let facts = self
+            .storage
+            .read(move |s| {
+                let signer = change.signature.as_ref().map(|sig| sig.key.clone());
+                Ok(Facts {
+                    tags: s.tags(&change.key)?,
+                    locked: signer.is_some() && s.lock_owner(&change.key)? == signer,
+                    last_seen: s.device_last_seen(&signing_device(&change))?,
+                    live_keys: if change.deleted {
+                        s.variable_count()?
+                    } else {
+                        0
+                    },
+                })
+            })
+            .await?;
+        let now = chrono::Utc::now().timestamp();
+        Ok(self
+            .quarantine
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .check(msg, &facts, now))
+    }
+
+    /// Log and raise the event for a change held as `hold`; one alert per
+    /// delete storm, not one per delete
+    fn report_hold(&self, msg: &SyncMessage, hold: &Hold) {
+        match hold {
+            Hold::DeleteStorm { count, percent } => {
+                let began = self
+                    .quarantine
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .storm_began(&msg.machine_id);
+                if began {
+                    tracing::warn!(
+                        "Delete storm from {} ({} deletes, {}% of the keys): holding its deletes for approval",
+                        msg.machine_id,
+                        count,
+                        percent
+                    );
+                    self.events.emit(MeshEvent::DeleteStorm {
+                        machine_id: msg.machine_id.clone(),
+                        count: *count,
+                        percent: *percent,
+                    });
+                }
+            }
+            hold => {
+                tracing::warn!(
+                    "Holding change to {} from {} for approval: {}",
+                    msg.key,
+                    msg.machine_id,
+                    hold
+                );
+                self.events.emit(MeshEvent::ChangeQuarantined {
+                    key: msg.key.clone(),
+                    machine_id: msg.machine_id.clone(),
+                    reason: hold.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Which of `changes` (screened, in a batch) to hold as part of a delete
+    /// storm. Only deletes of keys we hold count: tombstones of keys we never
+    /// had remove nothing.
+    async fn storm_holds(&self, changes: &[SyncMessage]) -> Result<Vec<Option<Hold>>> {
+        let deletes: Vec<String> = changes
+            .iter()
+            .filter(|msg| msg.deleted)
+            .map(|msg| msg.key.clone())
+            .collect();
+        if deletes.is_empty() {
+            return Ok(vec![None; changes.len()]);
+        }
+        let (live_keys, held) = self
+            .storage
+            .read(move |s| {
+                let mut held = BTreeSet::new();
+                for key in deletes {
+                    if s.get(&key)?.is_some() {
+                        held.insert(key);
+                    }
+                }
+                Ok((s.variable_count()?, held))
+            })
+            .await?;
+
+        let now = clock::now();
+        let mut quarantine = self.quarantine.lock().unwrap_or_else(|e| e.into_inner());
+        // Deletes earlier in the batch will have left the store
+        let mut removed = 0;
+        Ok(changes
+            .iter()
+            .map(|msg| {
+                if !msg.deleted || !held.contains(&msg.key) {
+                    return None;
+                }
+                let hold = quarantine.check_storm(msg, live_keys.saturating_sub(removed), now);
+                if hold.is_none() {
+                    removed += 1;
+                }
+                hold
+            })
+            .collect())
+    }
+
+    /// Changes held for approval, oldest first
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedChange>> {
+        self.storage.read(|s| s.quarantined()).await
+    }
+
+    /// Apply the held changes `ids` (every one if empty) as if they had just
+    /// arrived; the number applied
+    pub async fn approve_quarantined(&self, ids: Vec<i64>) -> Result<usize> {
+        let held = self
+            .storage
+            .write(move |s| s.take_quarantined(&ids))
+            .await?;
+        let mut applied = 0;
+        for held in held {
+            let msg: SyncMessage = serde_json::from_str(&held.change)?;
+            let change = msg.clone();
+            let outcome = self
+                .storage
+                .write(move |s| {
+                    s.see_device(&signing_device(&change), chrono::Utc::now().timestamp())?;
+                    write_change(s, &change)
+                })
+                .await?;
+            tracing::info!(
+                "Approved held change to {} from {}",
+                msg.key,
+                msg.machine_id
+            );
+            if self.settle(&msg, outcome).await? == ApplyOutcome::Applied {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Drop the held changes `ids` (every one if empty); the number dropped
+    pub async fn reject_quarantined(&self, ids: Vec<i64>) -> Result<usize> {
+        let held = self
+            .storage
+            .write(move |s| s.take_quarantined(&ids))
+            .await?;
+        for held in &held {
+            tracing::info!(
+                "Rejected held change to {} from {}",
+                held.key,
+                held.machine_id
+            );
+        }
+        Ok(held.len())
+    }
+
+    /// Replace the key policy incoming changes are checked against
+    pub fn set_key_policy(&self, policy: KeyPolicy) {
+        *self.key_policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    /// Replace the sync filter; takes effect for the next change
+    pub fn set_filter(&self, filter: SyncFilter) {
+        *self.filter.write().unwrap_or_else(|e| e.into_inner()) = filter;
+    }
+
+    /// True if the sync filter lets changes to `key` reach the network
+    pub fn allows(&self, key: &str) -> bool {
+        self.filter
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .allows(key)
+    }
+
+    /// Receive changes from whichever server the node is connected to and
+    /// apply them to local storage. Runs for the lifetime of the process.
+    pub async fn run_incoming(self) {
+        loop {
+            let incoming = self.node.lock().await.incoming();
+            let Some(incoming) = incoming else {
+                // LAN server mode or offline: nothing to read from
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                continue;
+            };
+
+            let msg = incoming.lock().await.recv().await;
+            match msg {
+                Some(WireMessage::Sync(msg)) => {
+                    if let Err(e) = self.apply_incoming(&msg).await {
+                        tracing::error!("Failed to apply change for {}: {}", msg.key, e);
+                    }
+                }
+                // The client reader reassembles chunks before they get here
+                Some(WireMessage::SyncChunk { .. } | WireMessage::SnapshotChunk { .. }) => {}
+                // Only clients prove themselves, to a server, and only
+                // relays unwrap replies
+                Some(WireMessage::Proof { .. } | WireMessage::Reply { .. }) => {}
+                Some(WireMessage::Snapshot {
+                    machine_id,
+                    through,
+                    data,
+                    resync,
+                    ..
+                }) => match resync.and_then(|from| self.take_resync(&from)) {
+                    Some(pending) => {
+                        let applied = self
+                            .finish_resync(&machine_id, through, &data, pending.overwrite_local)
+                            .await;
+                        let _ = pending.done.send(applied);
+                    }
+                    None => {
+                        if let Err(e) = self.apply_snapshot(&machine_id, through, &data).await {
+                            tracing::error!("Failed to apply snapshot from {}: {}", machine_id, e);
+                        }
+                    }
+                },
+                Some(WireMessage::SeqRequest {
+                    machine_id,
+                    seqs,
+                    asker,
+                }) => {
+                    if let Err(e) = self.answer_seq_request(&machine_id, seqs, asker).await {
+                        tracing::error!("Failed to answer sequence request: {}", e);
+                    }
+                }
+                Some(WireMessage::SyncRequest {
+                    machine_id,
+                    from,
+                    asker,
+                }) => {
+                    let answered = match (from, asker) {
+                        (Some(from), _) if !self.is_us(&from) => continue,
+                        (Some(from), Some(asker)) => {
+                            tracing::info!("{} asked us to resync it", machine_id);
+                            self.answer_resync(asker, &from).await
+                        }
+                        _ => {
+                            tracing::info!("{} asked for a full sync", machine_id);
+                            // The asker may start empty, whatever the server
+                            // was sent
+                            self.push_full().await.map(|_| ())
+                        }
+                    };
+                    if let Err(e) = answered {
+                        tracing::error!("Failed to answer sync request: {}", e);
+                    }
+                }
+                Some(WireMessage::Hello {
+                    time_ms,
+                    received_ms,
+                    ..
+                }) => self.handle_hello(time_ms, received_ms).await,
+                Some(WireMessage::ServerShutdown { reason }) => {
+                    tracing::warn!("Server is shutting down ({}), re-running failover", reason);
+                    self.handle_server_lost(&incoming).await;
+                }
+                None => self.handle_server_lost(&incoming).await,
+            }
+        }
+    }
+
+    async fn handle_server_lost(&self, incoming: &IncomingHandle) {
+        let mut node = self.node.lock().await;
+
+        // The node may already have moved on to a different server on purpose
+        let current = node.incoming();
+        if !current.is_some_and(|current| Arc::ptr_eq(&current, incoming)) {
+            return;
+        }
+
+        if let Err(e) = node.handle_server_lost().await {
+            tracing::error!("Failover after losing server failed: {}", e);
+        }
+    }
+
+    /// Apply one incoming change, reporting conflicts on the event bus
+    pub async fn apply_incoming(&self, msg: &SyncMessage) -> Result<ApplyOutcome> {
+        self.apply(msg, true).await
+    }
+
+    /// Apply a snapshot from the LAN server, then count every change up to
+    /// its sequence points as received, so only later gaps are asked for.
+    /// It arrived as one frame, so the rate limit counts it once.
+    pub async fn apply_snapshot(
+        &self,
+        machine_id: &str,
+        through: BTreeMap<String, u64>,
+        data: &str,
+    ) -> Result<usize> {
+        if !self.admit(machine_id) {
+            return Ok(0);
+        }
+        let changes = snapshot::decode(data)?;
+        let applied = self.apply_batch(&changes, through, |_| Ok(())).await?;
+        tracing::info!(
+            "Applied {} of {} changes from {}'s snapshot",
+            applied,
+            changes.len(),
+            machine_id
+        );
+        Ok(applied)
+    }
+
+    /// Apply changes that make up a machine's state up to the sequence
+    /// points in `through` (a snapshot or bundle), then count everything up
+    /// to them as received, and `ack` them (e.g. record the bundle). A
+    /// machine with changes we rejected isn't counted: only those applied
+    /// are, and the rest of its sequence stays open to ask for. It all
+    /// happens in one transaction, so a daemon dying half way leaves
+    /// neither part of the changes nor sequence floors they don't back.
+    /// Deletes in a delete storm are held, as live ones are. Returns how
+    /// many changes applied.
+    pub async fn apply_batch<F>(
+        &self,
+        changes: &[SyncMessage],
+        through: BTreeMap<String, u64>,
+        ack: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&EnvStorage) -> Result<()> + Send + 'static,
+    {
+        let mut screened = Vec::with_capacity(changes.len());
+        let mut skipped = Vec::new();
+        let mut rejected = BTreeSet::new();
+        for msg in changes {
+            match self.screen(msg, false) {
+                Screening::Apply => screened.push(msg.clone()),
+                Screening::Skip => skipped.push(msg),
+                Screening::Reject => {
+                    rejected.insert(msg.machine_id.clone());
+                }
+            }
+        }
+        let through: BTreeMap<String, u64> = through
+            .into_iter()
+            .filter(|(machine, _)| !rejected.contains(machine))
+            .collect();
+        // A snapshot or bundle can empty the store as surely as live deletes
+        let holds = self.storm_holds(&screened).await?;
+
+        let (screened, holds, outcomes) = self
+            .storage
+            .write(move |s| {
+                s.transaction(|s| {
+                    let outcomes = screened
+                        .iter()
+                        .zip(&holds)
+                        .map(|(msg, hold)| match hold {
+                            Some(hold) => {
+                                s.quarantine_change(
+                                    &msg.key,
+                                    msg.timestamp,
+                                    &msg.machine_id,
+                                    msg.deleted,
+                                    msg.seq,
+                                    &serde_json::to_string(msg)?,
+                                    &hold.to_string(),
+                                )?;
+                                Ok(ApplyOutcome::Quarantined)
+                            }
+                            None => write_change(s, msg),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    for (machine, seq) in &through {
+                        s.advance_seq_floor(machine, *seq)?;
+                    }
+                    ack(s)?;
+                    Ok(outcomes)
+                })
+                .map(|outcomes| (screened, holds, outcomes))
+            })
+            .await?;
+        // Only now, so a batch that failed isn't taken for a replay when
+        // it is tried again
+        self.remember(screened.iter().chain(skipped));
+        for (msg, hold) in screened.iter().zip(&holds) {
+            if let Some(hold) = hold {
+                self.report_hold(msg, hold);
+            }
+        }
+
+        let mut applied = 0;
+        for (msg, outcome) in screened.iter().zip(outcomes) {
+            if self.settle(msg, outcome).await? == ApplyOutcome::Applied {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+
+    /// `live` changes are rate limited and checked for sequence gaps;
+    /// snapshot entries are neither, as the snapshot covers what they skip
+    async fn apply(&self, msg: &SyncMessage, live: bool) -> Result<ApplyOutcome> {
+        match self.screen(msg, live) {
+            Screening::Apply => {}
+            Screening::Skip => {
+                // Counted as received, or it would look missing forever
+                if let Some(seq) = msg.seq {
+                    let machine_id = msg.machine_id.clone();
+                    self.storage
+                        .write(move |s| s.record_seq(&machine_id, seq))
+                        .await?;
+                }
+                self.remember([msg]);
+                return Ok(ApplyOutcome::Ignored);
+            }
+            Screening::Reject => return Ok(ApplyOutcome::Ignored),
+        }
+
+        let gap = match msg.seq {
+            // Servers send a node with topics only those namespaces, so
+            // every other change would look missing
+            Some(seq) if live && self.node.lock().await.config().namespaces.is_empty() => {
+                self.gap_before(&msg.machine_id, seq).await?
+            }
+            _ => Vec::new(),
+        };
+        let hold = if live { self.hold(msg).await? } else { None };
+        let change = msg.clone();
+        let outcome = match &hold {
+            Some(hold) => {
+                let reason = hold.to_string();
+                let json = serde_json::to_string(&change)?;
+                self.storage
+                    .write(move |s| {
+                        s.quarantine_change(
+                            &change.key,
+                            change.timestamp,
+                            &change.machine_id,
+                            change.deleted,
+                            change.seq,
+                            &json,
+                            &reason,
+                        )
+                    })
+                    .await?;
+                ApplyOutcome::Quarantined
+            }
+            None => {
+                self.storage
+                    .write(move |s| {
+                        if live {
+                            s.see_device(&signing_device(&change), chrono::Utc::now().timestamp())?;
+                        }
+                        write_change(s, &change)
+                    })
+                    .await?
+            }
+        };
+        self.remember([msg]);
+        if let Some(hold) = &hold {
+            self.report_hold(msg, hold);
+        }
+        if !gap.is_empty() {
+            tracing::info!(
+                "Missing {} changes from {}, asking peers for them",
+                gap.len(),
+                msg.machine_id
+            );
+            self.node
+                .lock()
+                .await
+                .request_seqs(&msg.machine_id, gap)
+                .await?;
+        }
+
+        self.settle(msg, outcome).await
+    }
+
+    /// Whether `msg` may be applied at all: signed as required, from an
+    /// allowed peer by someone allowed to change its key, fresh, within the
+    /// rate limit (if `live`) and for a valid key. Changes the sync filter
+    /// excludes are skipped rather than rejected.
+    fn screen(&self, msg: &SyncMessage, live: bool) -> Screening {
+        // Before anything else, so forged IDs can't poison the replay window
+        if let Err(e) = self.verify(msg) {
+            tracing::warn!("Rejecting change from {}: {}", msg.machine_id, e);
+            return Screening::Reject;
+        }
+        if !self.permits(msg) {
+            tracing::debug!("Dropping change from {} (peer not allowed)", msg.machine_id);
+            return Screening::Reject;
+        }
+        // Roles go by the (verified) signing key, as machine IDs are claims.
+        // A revocation carries its revoker's signature instead, as whoever
+        // relays it re-signs the change, and can't be taken back.
+        let signer: Vec<String> = msg.signature.iter().map(|sig| sig.key.clone()).collect();
+        let checked = match revoke::revoked_device(&msg.key) {
+            Some(device) if msg.deleted => Err(anyhow!("revocation of {} can't be undone", device)),
+            Some(_) => Revocation::parse(&msg.value).and_then(|revocation| {
+                revocation.check(&msg.key, &self.users(), &self.trusted_keys())
+            }),
+            None => self
+                .users
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .check_write(&signer, &msg.key),
+        };
+        if let Err(e) = checked {
+            tracing::warn!("Rejecting change from {}: {}", msg.machine_id, e);
+            return Screening::Reject;
+        }
+        // Changes from peers that predate message IDs can't be checked.
+        // Only checked here: `remember()` records the ID once it is written.
+        if let Some(id) = &msg.id {
+            let fresh = self
+                .seen
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_fresh(id);
+            if !fresh {
+                tracing::debug!("Dropping duplicate or replayed change to {}", msg.key);
+                return Screening::Reject;
+            }
+        }
+        if live && !self.admit(&msg.machine_id) {
+            return Screening::Reject;
+        }
+        if !self.allows(&msg.key) {
+            tracing::debug!("Skipping {} (excluded by sync filter)", msg.key);
+            self.note(|cycle| cycle.skipped.insert(msg.key.clone()));
+            return Screening::Skip;
+        }
+
+        // Deletes always apply, so keys stored before validation can be removed
+        let policy = *self.key_policy.read().unwrap_or_else(|e| e.into_inner());
+        if !msg.deleted {
+            if let Err(e) = keys::validate_any(&msg.key, policy) {
+                tracing::warn!("Rejecting change from {}: {}", msg.machine_id, e);
+                return Screening::Reject;
+            }
+        }
+        Screening::Apply
+    }
+
+    /// Record the IDs of changes that were written (or skipped), so they
+    /// are dropped as replays from now on
+    fn remember<'a>(&self, msgs: impl IntoIterator<Item = &'a SyncMessage>) {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        for id in msgs.into_iter().filter_map(|msg| msg.id.as_ref()) {
+            seen.check(id);
+        }
+    }
+
+    /// Record what became of an applied change, and settle it if it
+    /// conflicted
+    async fn settle(&self, msg: &SyncMessage, outcome: ApplyOutcome) -> Result<ApplyOutcome> {
+        if outcome == ApplyOutcome::Applied {
+            tracing::debug!(
+                "Applied {} = {} from {}",
+                msg.key,
+                ValueRedacted(&msg.value),
+                msg.machine_id
+            );
+            self.note(|cycle| cycle.pulled.insert(msg.key.clone()));
+            self.events.emit(MeshEvent::VarChanged {
+                key: msg.key.clone(),
+                machine_id: msg.machine_id.clone(),
+                deleted: msg.deleted,
+            });
+        }
+
+        if let ApplyOutcome::Conflict { local_machine } = &outcome {
+            if let Some(strategy) = self.merge_conflict(&msg.key).await? {
+                tracing::info!(
+                    "Merged conflicting change to {} from {} ({})",
+                    msg.key,
+                    msg.machine_id,
+                    strategy.as_str()
+                );
+                self.note(|cycle| cycle.pulled.insert(msg.key.clone()));
+                return Ok(outcome);
+            }
+            tracing::warn!(
+                "Conflict on {}: keeping local version from {} over {}",
+                msg.key,
+                local_machine,
+                msg.machine_id
+            );
+            self.note(|cycle| cycle.conflicted.insert(msg.key.clone()));
+            self.events.emit(MeshEvent::ConflictDetected {
+                key: msg.key.clone(),
+                local_machine: local_machine.clone(),
+                remote_machine: msg.machine_id.clone(),
+            });
+        }
+
+        Ok(outcome)
+    }
+
+    /// Settle a conflict on `key` by the merge strategy it is tagged with,
+    /// if any, and send the merged value on so the other side converges
+    async fn merge_conflict(&self, key: &str) -> Result<Option<MergeStrategy>> {
+        let Some(machine_id) = self.machine_id() else {
+            return Ok(None);
+        };
+        let key = key.to_string();
+        let merged = self
+            .storage
+            .write(move |s| s.merge_conflict(&key, &machine_id))
+            .await?;
+        let Some(((key, value, timestamp, machine_id, deleted), strategy)) = merged else {
+            return Ok(None);
+        };
+
+        self.events.emit(MeshEvent::VarChanged {
+            key: key.clone(),
+            machine_id: machine_id.clone(),
+            deleted,
+        });
+        self.events.emit(MeshEvent::ConflictMerged {
+            key: key.clone(),
+            strategy,
+        });
+        self.publish(&SyncMessage::new(
+            key, value, timestamp, machine_id, deleted,
+        ))
+        .await?;
+        Ok(Some(strategy))
+    }
+
+    fn note(&self, record: impl FnOnce(&mut SyncCycle) -> bool) {
+        record(&mut self.cycle.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// Sequence numbers skipped between the newest we have from
+    /// `machine_id` and `seq`; each gap is found once, as `seq` then becomes
+    /// the newest
+    async fn gap_before(&self, machine_id: &str, seq: u64) -> Result<Vec<u64>> {
+        let machine_id = machine_id.to_string();
+        self.storage
+            .read(move |s| {
+                if seq <= s.highest_seq(&machine_id)? + 1 {
+                    return Ok(Vec::new());
+                }
+                s.missing_seqs(&machine_id, seq, MAX_SEQ_REQUEST)
+            })
+            .await
+    }
+
+    /// Send the changes a peer found missing, if our history has them, to
+    /// the connection that asked when the relay says which one did
+    async fn answer_seq_request(
+        &self,
+        machine_id: &str,
+        mut seqs: Vec<u64>,
+        asker: Option<u64>,
+    ) -> Result<()> {
+        seqs.truncate(MAX_SEQ_REQUEST);
+        let machine = machine_id.to_string();
+        let found = self
+            .storage
+            .read(move |s| s.changes_by_seq(&machine, &seqs))
+            .await?;
+        let mut replies = Vec::new();
+        for ((key, value, timestamp, machine_id, deleted), seq) in found {
+            if !self.allows(&key) {
+                continue;
+            }
+            replies.push(self.seal(SyncMessage {
+                key,
+                value,
+                timestamp,
+                machine_id,
+                deleted,
+                id: None,
+                seq: Some(seq),
+                signature: None,
+            }));
+        }
+        self.send_all(asker, &replies).await
+    }
+
+    /// Send `msgs` as `EnvMeshNode::send_reply` does. As a LAN server the
+    /// node is released first: a broadcast may wait on a stalled client,
+    /// and that must not hold up every other use of the node.
+    async fn send_all(&self, asker: Option<u64>, msgs: &[SyncMessage]) -> Result<()> {
+        let mut node = self.node.lock().await;
+        if let Some(server) = node.broadcaster() {
+            drop(node);
+            for msg in msgs {
+                server.broadcast(msg).await?;
+            }
+            return Ok(());
+        }
+        for msg in msgs {
+            node.send_reply(asker, msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `key` as stored, after a local write: with the timestamp the
+    /// write was given and so the sequence number `publish` finds for it
+    pub async fn publish_stored(&self, key: &str) -> Result<()> {
+        let k = key.to_string();
+        let Some((key, value, timestamp, machine_id, deleted)) =
+            self.storage.read(move |s| s.stored(&k)).await?
+        else {
+            return Ok(());
+        };
+        self.publish(&SyncMessage::new(
+            key, value, timestamp, machine_id, deleted,
+        ))
+        .await
+    }
+
+    /// Send one local change to the network, unless the filter excludes it
+    pub async fn publish(&self, msg: &SyncMessage) -> Result<()> {
+        if !self.allows(&msg.key) {
+            tracing::debug!("Not publishing {} (excluded by sync filter)", msg.key);
+            return Ok(());
+        }
+
+        let mut msg = msg.clone();
+        if msg.seq.is_none() {
+            let change = vec![(
+                msg.key.clone(),
+                msg.value.clone(),
+                msg.timestamp,
+                msg.machine_id.clone(),
+                msg.deleted,
+            )];
+            let local = self.machine_id();
+            let seqs = self
+                .storage
+                .write(move |s| s.sequence(&change, local.as_deref()))
+                .await?;
+            msg.seq = seqs.into_iter().next().flatten();
+        }
+        let msg = self.seal(msg);
+        self.send_all(None, std::slice::from_ref(&msg)).await
+    }
+
+    /// Every local change the filter lets through, as sync messages
+    pub(crate) async fn local_changes(&self) -> Result<Vec<SyncMessage>> {
+        self.changes_after(0).await
+    }
+
+    /// Changes to keys written after history entry `history_id` that the
+    /// filter lets through, as sync messages
+    pub(crate) async fn changes_after(&self, history_id: i64) -> Result<Vec<SyncMessage>> {
+        Ok(self.filtered_changes_after(history_id).await?.0)
+    }
+
+    /// `changes_after()`, and the keys the filter held back
+    async fn filtered_changes_after(
+        &self,
+        history_id: i64,
+    ) -> Result<(Vec<SyncMessage>, Vec<String>)> {
+        let (changes, skipped): (Vec<_>, Vec<_>) = self
+            .storage
+            .read(move |s| s.get_changes_after(history_id))
+            .await?
+            .into_iter()
+            .partition(|(key, ..)| self.allows(key));
+        let local = self.machine_id();
+        let (changes, seqs) = self
+            .storage
+            .write(move |s| {
+                let seqs = s.sequence(&changes, local.as_deref())?;
+                Ok((changes, seqs))
+            })
+            .await?;
+        let changes = changes
+            .into_iter()
+            .zip(seqs)
+            .map(|((key, value, timestamp, machine_id, deleted), seq)| {
+                self.seal(SyncMessage {
+                    key,
+                    value,
+                    timestamp,
+                    machine_id,
+                    deleted,
+                    id: None,
+                    seq,
+                    signature: None,
+                })
+            })
+            .collect();
+        Ok((changes, skipped.into_iter().map(|(key, ..)| key).collect()))
+    }
+
+    /// Push the local changes the peers we sync with haven't been sent yet;
+    /// returns how many were sent
+    pub async fn push_all(&self) -> Result<usize> {
+        self.push(false).await
+    }
+
+    /// Push every local change, whatever was sent before (for a server that
+    /// lost its data)
+    pub async fn push_full(&self) -> Result<usize> {
+        self.push(true).await
+    }
+
+    async fn push(&self, full: bool) -> Result<usize> {
+        let node = self.node.lock().await;
+        let peer = node.sync_peer();
+        self.settle_watermarks(&node.sync_links()).await?;
+        // Taken first: anything written meanwhile is sent now and again next time
+        let through = self.storage.read(|s| s.latest_history_id()).await?;
+        let since = match full {
+            true => 0,
+            false => self.watermark_of(&node).await?,
+        };
+        let (changes, skipped) = self.filtered_changes_after(since).await?;
+        drop(node);
+
+        self.send_all(None, &changes).await?;
+        // Each peer's watermark moves once it confirms what was just sent
+        // (or anything sent after it)
+        let links = self.node.lock().await.sync_links();
+        self.await_confirmation(links, through);
+
+        let cycle = std::mem::replace(
+            &mut *self.cycle.lock().unwrap_or_else(|e| e.into_inner()),
+            SyncCycle::new(),
+        );
+        let mut skipped_keys = cycle.skipped;
+        skipped_keys.extend(skipped);
+        let pushed: BTreeSet<String> = changes.iter().map(|msg| msg.key.clone()).collect();
+        let report = SyncReport {
+            id: 0,
+            started_ms: cycle.started_ms,
+            finished_ms: clock::now_millis(),
+            peer,
+            full,
+            pushed: pushed.into_iter().collect(),
+            pulled: cycle.pulled.into_iter().collect(),
+            conflicted: cycle.conflicted.into_iter().collect(),
+            skipped: skipped_keys.into_iter().collect(),
+        };
+        if let Err(e) = self
+            .storage
+            .write(move |s| s.record_sync_report(&report))
+            .await
+        {
+            tracing::warn!("Failed to record sync report: {}", e);
+        }
+
+        self.events.emit(MeshEvent::SyncCompleted {
+            changes: changes.len(),
+        });
+        Ok(changes.len())
+    }
+
+    /// Pull the whole state of the machine `from` (machine ID or device key)
+    /// again, for a node whose store is confused: ask that peer alone for a
+    /// snapshot, and only once it arrives forget what was received and sent
+    /// (with `overwrite_local`, every variable too, so its copy wins over
+    /// ours) and apply it. Nothing is discarded if `from` doesn't answer
+    /// within `RESYNC_TIMEOUT`. Returns how many of its changes applied.
+    pub async fn resync_from(&self, from: &str, overwrite_local: bool) -> Result<usize> {
+        let mut node = self.node.lock().await;
+        if node.incoming().is_none() {
+            return Err(anyhow!(
+                "not connected to a server, so no peer can be asked (a LAN server sends its state to clients as they connect)"
+            ));
+        }
+        let (done, mut answer) = oneshot::channel();
+        {
+            let mut pending = self.resync.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(waiting) = pending.as_ref() {
+                return Err(anyhow!("still waiting for {}'s state", waiting.from));
+            }
+            *pending = Some(PendingResync {
+                from: from.to_string(),
+                overwrite_local,
+                done,
+            });
+        }
+        let requested = node
+            .request_state(&self.machine_id().unwrap_or_default(), Some(from))
+            .await;
+        drop(node);
+        if let Err(e) = requested {
+            self.take_resync(from);
+            return Err(e);
+        }
+        tracing::info!("Asked {} for its state", from);
+
+        let answer = match tokio::time::timeout(RESYNC_TIMEOUT, &mut answer).await {
+            Ok(answer) => answer,
+            // Unless it is being applied this moment
+            Err(_) if self.take_resync(from).is_some() => {
+                return Err(anyhow!(
+                    "{} sent no state within {}s, so nothing was discarded; it must be our server, or connected to the same relay mesh",
+                    from,
+                    RESYNC_TIMEOUT.as_secs()
+                ));
+            }
+            Err(_) => answer.await,
+        };
+        answer.map_err(|_| anyhow!("the resync was dropped"))?
+    }
+
+    /// The waiting resync, if it is for the machine `from`
+    fn take_resync(&self, from: &str) -> Option<PendingResync> {
+        let mut pending = self.resync.lock().unwrap_or_else(|e| e.into_inner());
+        match pending.as_ref() {
+            Some(waiting) if waiting.from == from => pending.take(),
+            _ => None,
+        }
+    }
+
+    /// Apply the snapshot answering our resync, discarding what
+    /// `resync_from()` says only now that it is here and readable
+    async fn finish_resync(
+        &self,
+        machine_id: &str,
+        through: BTreeMap<String, u64>,
+        data: &str,
+        overwrite_local: bool,
+    ) -> Result<usize> {
+        let changes = snapshot::decode(data)?;
+        self.storage
+            .write(move |s| {
+                if overwrite_local {
+                    s.clear_replicated()
+                } else {
+                    s.reset_sync_state()
+                }
+            })
+            .await?;
+        // What was received before counts for nothing now, or the snapshot
+        // would be taken for replays of it
+        *self.seen.lock().unwrap_or_else(|e| e.into_inner()) = ReplayGuard::new();
+        tracing::warn!(
+            "Resyncing from {}{}",
+            machine_id,
+            if overwrite_local {
+                ", local state discarded"
+            } else {
+                ""
+            }
+        );
+        let applied = self.apply_batch(&changes, through, |_| Ok(())).await?;
+        tracing::info!(
+            "Applied {} of {} changes from {}'s state",
+            applied,
+            changes.len(),
+            machine_id
+        );
+        Ok(applied)
+    }
+
+    /// Answer a resync naming us (`from`) that the relay stamped with
+    /// `asker`: our state to that connection alone, then the changes written
+    /// while taking it
+    async fn answer_resync(&self, asker: u64, from: &str) -> Result<()> {
+        let through = self.storage.read(|s| s.seq_points()).await?;
+        let history_id = self.storage.read(|s| s.latest_history_id()).await?;
+        let changes = self.local_changes().await?;
+        let machine_id = self.machine_id().unwrap_or_default();
+        self.node
+            .lock()
+            .await
+            .send_snapshot_reply(asker, &machine_id, through, &changes, from)
+            .await?;
+
+        let tail = self.changes_after(history_id).await?;
+        self.send_all(Some(asker), &tail).await
+    }
+
+    /// Whether `id` names this machine, by machine ID or device key
+    fn is_us(&self, id: &str) -> bool {
+        self.machine_id().as_deref() == Some(id) || self.device_public_key() == id
+    }
+
+    /// The history entry every peer we push to has confirmed our changes
+    /// up to; `push_all()` sends the keys written after it
+    pub async fn watermark(&self) -> Result<i64> {
+        let node = self.node.lock().await;
+        self.settle_watermarks(&node.sync_links()).await?;
+        self.watermark_of(&node).await
+    }
+
+    /// The lowest watermark of the peers `node` pushes to (0 with none)
+    async fn watermark_of(&self, node: &EnvMeshNode) -> Result<i64> {
+        let peers: BTreeSet<String> = node
+            .sync_links()
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        if peers.is_empty() {
+            return Ok(0);
+        }
+        self.storage
+            .read(move |s| {
+                let marks = peers.iter().map(|peer| s.sync_watermark(peer));
+                marks.collect::<Result<Vec<_>>>()
+            })
+            .await
+            .map(|marks| marks.into_iter().min().unwrap_or(0))
+    }
+
+    /// Move each peer's watermark to `through` once its link confirms the
+    /// frames sent on it so far
+    fn await_confirmation(&self, links: Vec<(String, PeerLink)>, through: i64) {
+        let mut pending = self.pending_marks.lock().unwrap_or_else(|e| e.into_inner());
+        for (peer, link) in links {
+            let Some(frame) = link.sent_seq else {
+                continue;
+            };
+            match pending
+                .iter_mut()
+                .find(|m| m.peer == peer && m.through == through)
+            {
+                Some(mark) => mark.frame = mark.frame.max(frame),
+                None => pending.push(PendingMark {
+                    peer,
+                    through,
+                    frame,
+                }),
+            }
+        }
+    }
+
+    /// Move the watermarks of peers that confirmed earlier pushes. Marks of
+    /// peers that went away, or whose link restarted its numbering, are
+    /// dropped: those changes go again next time.
+    async fn settle_watermarks(&self, links: &[(String, PeerLink)]) -> Result<()> {
+        let mut settled: BTreeMap<String, i64> = BTreeMap::new();
+        self.pending_marks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|mark| {
+                let mut links = links
+                    .iter()
+                    .filter(|(peer, _)| *peer == mark.peer)
+                    .peekable();
+                if links.peek().is_none() {
+                    return false;
+                }
+                let mut confirmed = true;
+                for (_, link) in links {
+                    if link.sent_seq.is_none_or(|sent| sent < mark.frame) {
+                        return false;
+                    }
+                    confirmed &= link.acked_seq.is_some_and(|acked| acked >= mark.frame);
+                }
+                if confirmed {
+                    let through = settled.entry(mark.peer.clone()).or_default();
+                    *through = (*through).max(mark.through);
+                }
+                !confirmed
+            });
+        if settled.is_empty() {
+            return Ok(());
+        }
+        self.storage
+            .write(move |s| {
+                for (peer, through) in &settled {
+                    if s.sync_watermark(peer)? < *through {
+                        s.set_sync_watermark(peer, *through)?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// The newest `limit` sync reports, newest first
+    pub async fn reports(&self, limit: usize) -> Result<Vec<SyncReport>> {
+        self.storage.read(move |s| s.sync_reports(limit)).await
+    }
+
+    /// While we are the LAN server, send our full state to each client as it
+    /// connects, so peers that start empty (`--ephemeral`) are seeded, and to
+    /// each client resyncing from us. Runs for the lifetime of the process.
+    pub async fn run_seeding(self) {
+        let mut rx = self.events.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(MeshEvent::PeerConnected { peer }) => {
+                    if let Err(e) = self.seed_peer(&peer, None).await {
+                        tracing::warn!("Failed to send state to {}: {}", peer, e);
+                    }
+                }
+                // A client resyncing from us: it is waiting for this snapshot
+                Ok(MeshEvent::StateRequested { peer, from }) if self.is_us(&from) => {
+                    if let Err(e) = self.seed_peer(&peer, Some(&from)).await {
+                        tracing::warn!("Failed to send state to {}: {}", peer, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Seeding missed {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// A snapshot of our state, then the changes written while taking it;
+    /// `resync` is the name a resync from us asked for
+    async fn seed_peer(&self, peer: &str, resync: Option<&str>) -> Result<()> {
+        // Sequence points first: whatever is numbered after them is still
+        // in the snapshot or the tail
+        let through = self.storage.read(|s| s.seq_points()).await?;
+        let history_id = self.storage.read(|s| s.latest_history_id()).await?;
+        let changes = self.local_changes().await?;
+        let machine_id = self.machine_id().unwrap_or_default();
+        let sent = self
+            .node
+            .lock()
+            .await
+            .send_snapshot_to_peer(peer, &machine_id, through, &changes, resync)
+            .await?;
+        if !sent {
+            return Ok(());
+        }
+
+        let tail = self.changes_after(history_id).await?;
+        self.node.lock().await.send_to_peer(peer, &tail).await?;
+        tracing::info!(
+            "Sent a snapshot of {} changes (and {} after it) to client {}",
+            changes.len(),
+            tail.len(),
+            peer
+        );
+        Ok(())
+    }
+}
+
+/// Who a change is from, for `[quarantine] dormant_days`: its signing key,
+/// or its machine ID if unsigned
+fn signing_device(msg: &SyncMessage) -> String {
+    match &msg.signature {
+        Some(sig) => sig.key.clone(),
+        None => msg.machine_id.clone(),
+    }
+}
+
+/// Store a screened change, unless its key is locked by another device: a
+/// locked key only takes changes signed by the device that locked it (the
+/// signature was verified when screening)
+fn write_change(s: &EnvStorage, msg: &SyncMessage) -> Result<ApplyOutcome> {
+    if let Some(owner) = s.lock_owner(&msg.key)? {
+        if msg.signature.as_ref().map(|sig| &sig.key) != Some(&owner) {
+            tracing::warn!(
+                "Rejecting change to locked {} from {} (locked by {})",
+                msg.key,
+                msg.machine_id,
+                owner
+            );
+            return Ok(ApplyOutcome::Ignored);
+        }
+    }
+    s.apply_remote_seq(
+        &msg.key,
+        &msg.value,
+        msg.timestamp,
+        &msg.machine_id,
+        msg.deleted,
+        msg.seq,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{NodeConfig, ServerMode};
+    use std::path::PathBuf;
+
+    async fn test_engine(filter: SyncFilter) -> SyncEngine {
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let events = EventBus::new();
+        let config = NodeConfig {
+            enable_cloud: false,
+            lan_port: 0,
+            server_mode: ServerMode::ServerPreferred,
+            ..Default::default()
+        };
+        let node = EnvMeshNode::new(config, events.clone()).await.unwrap();
+
+        SyncEngine::new(storage, Arc::new(Mutex::new(node)), events, filter)
+    }
+
+    fn remote(key: &str, value: &str, timestamp: i64) -> SyncMessage {
+        SyncMessage::new(
+            key.to_string(),
+            value.to_string(),
+            timestamp,
+            "machine-b".to_string(),
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_publish_stored_sends_the_stored_stamp() {
+        use crate::client::WebSocketClient;
+
+        let engine = test_engine(SyncFilter::default()).await;
+        engine.set_machine_id("machine-a");
+        let mut node = engine.node.lock().await;
+        node.reconnect_with_failover().await.unwrap();
+        let NodeMode::LanServer { port } = node.current_mode() else {
+            panic!("expected to serve");
+        };
+        drop(node);
+        let mut client = WebSocketClient::connect(&format!("ws://127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        while engine.node.lock().await.peer_links().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Two writes within a second: the second is stamped after the first
+        let now = clock::now();
+        let stamped = engine
+            .storage
+            .write(move |s| {
+                s.set_at("KEY", "1", "machine-a", now)?;
+                s.delete_at("KEY", "machine-a", now)?;
+                Ok(s.stored("KEY")?.unwrap())
+            })
+            .await
+            .unwrap();
+        assert_eq!(stamped.2, now + 1);
+        assert!(stamped.4);
+
+        engine.publish_stored("KEY").await.unwrap();
+        loop {
+            match client.receive().await.unwrap() {
+                Some(WireMessage::Sync(msg)) => {
+                    assert_eq!((msg.timestamp, msg.deleted), (now + 1, true));
+                    assert_eq!(msg.seq, Some(1));
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("connection closed"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_all_sends_only_unconfirmed_changes() {
+        use crate::client::{ConnectOptions, WebSocketClient};
+
+        let engine = test_engine(SyncFilter::default()).await;
+        let mut node = engine.node.lock().await;
+        node.reconnect_with_failover().await.unwrap();
+        let NodeMode::LanServer { port } = node.current_mode() else {
+            panic!("expected to serve");
+        };
+        drop(node);
+        let url = format!("ws://127.0.0.1:{}", port);
+        let connect = |key: &Arc<DeviceKey>| {
+            let options = ConnectOptions {
+                signer: Some(Arc::clone(key)),
+                ..Default::default()
+            };
+            let url = url.clone();
+            async move { WebSocketClient::connect_with(&url, &options).await.unwrap() }
+        };
+        let node = &engine.node;
+        let clients = |n: usize| async move {
+            while node.lock().await.peer_links().len() < n {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        // Sent frames are confirmed by the pong to the next ping
+        let confirm = || async {
+            let mark = engine
+                .storage
+                .read(|s| s.latest_history_id())
+                .await
+                .unwrap();
+            engine.node.lock().await.ping_peers();
+            while engine.watermark().await.unwrap() < mark {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let set = |key: &'static str| {
+            engine
+                .storage
+                .write(move |s| s.set(key, "value", "machine-a"))
+        };
+
+        let _a = connect(&Arc::new(DeviceKey::generate())).await;
+        clients(1).await;
+        set("A").await.unwrap();
+        set("B").await.unwrap();
+        assert_eq!(engine.watermark().await.unwrap(), 0);
+        assert_eq!(engine.push_all().await.unwrap(), 2);
+        // Sent, but not confirmed yet
+        assert_eq!(engine.push_all().await.unwrap(), 2);
+        confirm().await;
+        assert_eq!(engine.push_all().await.unwrap(), 0);
+
+        set("B").await.unwrap();
+        assert_eq!(engine.push_all().await.unwrap(), 1);
+        confirm().await;
+        assert_eq!(engine.push_full().await.unwrap(), 2);
+        confirm().await;
+        assert_eq!(engine.push_all().await.unwrap(), 0);
+
+        // Each peer has its own watermark: a new one is sent everything
+        let _b = connect(&Arc::new(DeviceKey::generate())).await;
+        clients(2).await;
+        assert_eq!(engine.push_all().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_covers_up_to_its_sequence_points() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let mut changes = vec![remote("A", "1", 100), remote("B", "1", 100)];
+        changes[0].seq = Some(4);
+        changes[1].seq = Some(5);
+        let data = snapshot::encode(&changes).unwrap();
+        let through = BTreeMap::from([("machine-b".to_string(), 5)]);
+
+        let applied = engine
+            .apply_snapshot("server", through, &data)
+            .await
+            .unwrap();
+        assert_eq!(applied, 2);
+        let points = engine.storage.read(|s| s.seq_points()).await.unwrap();
+        assert_eq!(points.get("machine-b"), Some(&5));
+
+        // Seqs 1-3 came with the snapshot; only what follows it is a gap
+        assert!(engine.gap_before("machine-b", 6).await.unwrap().is_empty());
+        assert_eq!(engine.gap_before("machine-b", 8).await.unwrap(), vec![7, 6]);
+        let mut old = remote("C", "1", 100);
+        old.seq = Some(2);
+        let outcome = engine.apply_incoming(&old).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_rejections_keep_their_machine_open() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let mut changes = vec![remote("A", "1", 100), remote("BAD KEY", "1", 100)];
+        changes[0].seq = Some(4);
+        changes[1].seq = Some(5);
+        let mut other = remote("C", "1", 100);
+        other.machine_id = "machine-c".to_string();
+        other.seq = Some(3);
+        changes.push(other);
+        let data = snapshot::encode(&changes).unwrap();
+        let through = BTreeMap::from([("machine-b".to_string(), 5), ("machine-c".to_string(), 3)]);
+
+        let applied = engine
+            .apply_snapshot("server", through, &data)
+            .await
+            .unwrap();
+        assert_eq!(applied, 2);
+        let points = engine.storage.read(|s| s.seq_points()).await.unwrap();
+        assert_eq!(points.get("machine-c"), Some(&3));
+        assert_eq!(points.get("machine-b"), None);
+        // Only what applied counts as received from machine-b
+        assert_eq!(
+            engine.gap_before("machine-b", 6).await.unwrap(),
+            vec![5, 3, 2, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync_discards_nothing_until_the_snapshot_arrives() {
+        let host = test_engine(SyncFilter::default()).await;
+        host.set_machine_id("machine-a");
+        let mut node = host.node.lock().await;
+        node.reconnect_with_failover().await.unwrap();
+        let NodeMode::LanServer { port } = node.current_mode() else {
+            panic!("expected to serve");
+        };
+        drop(node);
+        tokio::spawn(host.clone().run_seeding());
+        host.storage
+            .write(|s| s.set("SHARED", "1", "machine-a"))
+            .await
+            .unwrap();
+
+        let events = EventBus::new();
+        let config = NodeConfig {
+            cloud_urls: vec![format!("ws://127.0.0.1:{}", port)],
+            enable_lan: false,
+            server_mode: ServerMode::ClientOnly,
+            ..Default::default()
+        };
+        let node = EnvMeshNode::offline(config, events.clone());
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let client = SyncEngine::new(
+            storage,
+            Arc::new(Mutex::new(node)),
+            events,
+            SyncFilter::default(),
+        );
+        client.set_machine_id("machine-b");
+        let get = |key: &'static str| client.storage.read(move |s| s.get(key));
+        assert!(client.resync_from("machine-a", true).await.is_err());
+
+        client
+            .node
+            .lock()
+            .await
+            .reconnect_with_failover()
+            .await
+            .unwrap();
+        tokio::spawn(client.clone().run_incoming());
+        // Seeded as it connects
+        while get("SHARED").await.unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .storage
+            .write(|s| s.set("LOCAL", "1", "machine-b"))
+            .await
+            .unwrap();
+
+        // The host relays nothing between its clients: no one else answers
+        let err = client.resync_from("machine-c", true).await.unwrap_err();
+        assert!(err.to_string().contains("nothing was discarded"), "{}", err);
+        assert!(get("LOCAL").await.unwrap().is_some());
+
+        // The host answers itself
+        assert_eq!(client.resync_from("machine-a", true).await.unwrap(), 1);
+        assert!(get("LOCAL").await.unwrap().is_none());
+        assert!(get("SHARED").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_batch_applies_all_or_nothing() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let ids = MessageIds::new();
+        let mut changes = vec![remote("A", "1", 100), remote("B", "1", 100)];
+        for (seq, change) in changes.iter_mut().enumerate() {
+            change.seq = Some(seq as u64 + 1);
+            change.id = Some(ids.next());
+        }
+        let through = BTreeMap::from([("machine-b".to_string(), 2)]);
+
+        // Failing to acknowledge the batch takes the changes back with it,
+        // and the bundle isn't recorded as imported
+        let failed = engine
+            .apply_batch(&changes, through.clone(), |s| {
+                s.record_bundle_import("bundle-1", "machine-b", None)?;
+                Err(anyhow!("crashed"))
+            })
+            .await;
+        assert!(failed.is_err());
+        let (a, points, imported) = engine
+            .storage
+            .read(|s| Ok((s.get("A")?, s.seq_points()?, s.bundle_imported("bundle-1")?)))
+            .await
+            .unwrap();
+        assert!(a.is_none());
+        assert!(!points.contains_key("machine-b"));
+        assert!(!imported);
+
+        // The same batch (its message IDs too) applies in full once it can
+        // be acknowledged
+        let applied = engine
+            .apply_batch(&changes, through.clone(), |s| {
+                s.record_bundle_import("bundle-1", "machine-b", None)
+            })
+            .await
+            .unwrap();
+        assert_eq!(applied, 2);
+        let (points, imported) = engine
+            .storage
+            .read(|s| Ok((s.seq_points()?, s.bundle_imported("bundle-1")?)))
+            .await
+            .unwrap();
+        assert_eq!(points.get("machine-b"), Some(&2));
+        assert!(imported);
+
+        // Once applied, they are replays
+        let mut later = changes.clone();
+        for change in &mut later {
+            change.timestamp = 200;
+        }
+        let applied = engine
+            .apply_batch(&later, through, |_| Ok(()))
+            .await
+            .unwrap();
+        assert_eq!(applied, 0);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_emits_event() {
+        let engine = test_engine(SyncFilter::default()).await;
+        engine
+            .storage
+            .write(|s| s.apply_remote("KEY", "mine", 200, "machine-a", false))
+            .await
+            .unwrap();
+
+        let mut rx = engine.events.subscribe();
+        engine
+            .apply_incoming(&remote("KEY", "theirs", 100))
+            .await
+            .unwrap();
+
+        match rx.try_recv().unwrap() {
+            MeshEvent::ConflictDetected { key, .. } => assert_eq!(key, "KEY"),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tagged_conflict_is_merged() {
+        let engine = test_engine(SyncFilter::default()).await;
+        engine.set_machine_id("machine-a");
+        engine
+            .node
+            .lock()
+            .await
+            .reconnect_with_failover()
+            .await
+            .unwrap();
+        engine
+            .storage
+            .write(|s| {
+                s.apply_remote("HOSTS", "web, api", 100, "machine-b", false)?;
+                s.apply_remote("HOSTS", "web, api, cron", 300, "machine-a", false)?;
+                s.set_tags("HOSTS", &["merge:list-union".to_string()])
+            })
+            .await
+            .unwrap();
+
+        let mut rx = engine.events.subscribe();
+        engine
+            .apply_incoming(&remote("HOSTS", "web, mail", 200))
+            .await
+            .unwrap();
+        let (value, conflicts) = engine
+            .storage
+            .read(|s| Ok((s.get("HOSTS")?, s.conflicts()?)))
+            .await
+            .unwrap();
+        assert_eq!(value.unwrap().0, "web, cron, mail");
+        assert!(conflicts.is_empty());
+        let mut merged = false;
+        while let Ok(event) = rx.try_recv() {
+            merged |= matches!(
+                event,
+                MeshEvent::ConflictMerged {
+                    strategy: MergeStrategy::ListUnion,
+                    ..
+                }
+            );
+            assert!(!matches!(event, MeshEvent::ConflictDetected { .. }));
+        }
+        assert!(merged);
+
+        // Untagged keys still keep the newer side and report the conflict
+        engine
+            .storage
+            .write(|s| s.apply_remote("PLAIN", "a", 300, "machine-a", false))
+            .await
+            .unwrap();
+        engine
+            .apply_incoming(&remote("PLAIN", "b", 200))
+            .await
+            .unwrap();
+        let conflicts = engine.storage.read(|s| s.conflicts()).await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_locked_key_takes_changes_only_from_its_owner() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let owner = DeviceKey::generate();
+        let lock = owner.public_key();
+        engine
+            .storage
+            .write(move |s| {
+                s.apply_remote("PROD_DB_URL", "postgres://a", 100, "machine-b", false)?;
+                s.apply_remote("@lock/PROD_DB_URL", &lock, 100, "machine-b", false)
+            })
+            .await
+            .unwrap();
+
+        let other = DeviceKey::generate();
+        let mut change = remote("PROD_DB_URL", "postgres://c", 200);
+        other.sign(&mut change);
+        assert_eq!(
+            engine.apply_incoming(&change).await.unwrap(),
+            ApplyOutcome::Ignored
+        );
+        let unsigned = remote("PROD_DB_URL", "postgres://c", 200);
+        assert_eq!(
+            engine.apply_incoming(&unsigned).await.unwrap(),
+            ApplyOutcome::Ignored
+        );
+
+        // The owner's device, even under a new machine ID after a restart
+        let mut change = remote("PROD_DB_URL", "postgres://b", 200);
+        change.machine_id = "machine-b2".to_string();
+        owner.sign(&mut change);
+        assert_eq!(
+            engine.apply_incoming(&change).await.unwrap(),
+            ApplyOutcome::Applied
+        );
+
+        // Once the unlock arrives, anyone's changes apply again
+        let mut unlock = remote("@lock/PROD_DB_URL", "", 300);
+        unlock.deleted = true;
+        other.sign(&mut unlock);
+        engine.apply_incoming(&unlock).await.unwrap();
+        let mut change = remote("PROD_DB_URL", "postgres://c", 301);
+        other.sign(&mut change);
+        assert_eq!(
+            engine.apply_incoming(&change).await.unwrap(),
+            ApplyOutcome::Applied
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_change_waits_for_approval() {
+        let engine = test_engine(SyncFilter::default()).await;
+        engine.set_quarantine(Some(QuarantineConfig::default()));
+        engine
+            .storage
+            .write(|s| s.set_tags("DB_URL", &["prod".to_string()]))
+            .await
+            .unwrap();
+
+        let outcome = engine
+            .apply_incoming(&remote("DB_URL", "postgres://evil", 100))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Quarantined);
+        let held = engine.quarantined().await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].reason, "changes a key tagged prod");
+        assert!(engine
+            .storage
+            .read(|s| s.get("DB_URL"))
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(engine.approve_quarantined(Vec::new()).await.unwrap(), 1);
+        assert!(engine.quarantined().await.unwrap().is_empty());
+        assert!(engine
+            .storage
+            .read(|s| s.get("DB_URL"))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_delete_storm_is_held() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let mut rx = engine.events.subscribe();
+        for i in 0..20 {
+            let key = format!("KEY_{}", i);
+            engine
+                .storage
+                .write(move |s| s.set(&key, "1", "machine-a"))
+                .await
+                .unwrap();
+        }
+        // Tombstones of keys never held here remove nothing
+        let mut changes: Vec<SyncMessage> = (0..12)
+            .map(|i| SyncMessage::new(format!("GONE_{}", i), "", 100, "machine-b", true))
+            .collect();
+        changes.extend(
+            (0..20).map(|i| {
+                SyncMessage::new(format!("KEY_{}", i), "", i64::MAX / 2, "machine-b", true)
+            }),
+        );
+        let data = snapshot::encode(&changes).unwrap();
+        engine
+            .apply_snapshot("server", BTreeMap::new(), &data)
+            .await
+            .unwrap();
+
+        // The tenth delete makes a quarter of the store: it and the rest wait
+        assert_eq!(engine.quarantined().await.unwrap().len(), 11);
+        let left = engine.storage.read(|s| s.variable_count()).await.unwrap();
+        assert_eq!(left, 11);
+        let mut storms = 0;
+        while let Ok(event) = rx.try_recv() {
+            if matches!(event, MeshEvent::DeleteStorm { .. }) {
+                storms += 1;
+            }
+        }
+        assert_eq!(storms, 1);
+    }
+
+    #[tokio::test]
+    async fn test_filter_skips_excluded_keys() {
+        let filter = SyncFilter {
+            include: Vec::new(),
+            exclude: vec!["LOCAL_*".to_string()],
+            namespaces: Vec::new(),
+        };
+        let engine = test_engine(filter).await;
+
+        engine
+            .apply_incoming(&remote("LOCAL_PATH", "/tmp", 100))
+            .await
+            .unwrap();
+        engine
+            .apply_incoming(&remote("SHARED", "yes", 100))
+            .await
+            .unwrap();
+
+        let (local, shared) = engine
+            .storage
+            .read(|s| Ok((s.get("LOCAL_PATH")?, s.get("SHARED")?)))
+            .await
+            .unwrap();
+        assert!(local.is_none());
+        assert!(shared.is_some());
+
+        // A skipped change still counts as received, so it isn't missed
+        let mut skipped = remote("LOCAL_HOME", "/home", 100);
+        skipped.seq = Some(1);
+        let mut next = remote("SHARED", "again", 200);
+        next.seq = Some(2);
+        for msg in [&skipped, &next] {
+            engine.apply_incoming(msg).await.unwrap();
+        }
+        assert!(engine.gap_before("machine-b", 3).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push_records_sync_report() {
+        let engine = test_engine(SyncFilter {
+            exclude: vec!["LOCAL_*".to_string()],
+            ..Default::default()
+        })
+        .await;
+        engine
+            .node
+            .lock()
+            .await
+            .reconnect_with_failover()
+            .await
+            .unwrap();
+        engine
+            .storage
+            .write(|s| {
+                s.apply_remote("KEY", "mine", 200, "machine-a", false)?;
+                s.set("A", "1", "machine-a")?;
+                s.set("LOCAL_PATH", "/home/me", "machine-a")
+            })
+            .await
+            .unwrap();
+        for msg in [
+            remote("KEY", "theirs", 100),
+            remote("PULLED", "1", 100),
+            remote("LOCAL_HOME", "/home/them", 100),
+        ] {
+            engine.apply_incoming(&msg).await.unwrap();
+        }
+
+        engine.push_all().await.unwrap();
+        let report = engine.reports(1).await.unwrap().remove(0);
+        assert_eq!(report.pushed, vec!["A", "KEY", "PULLED"]);
+        assert_eq!(report.pulled, vec!["PULLED"]);
+        assert_eq!(report.conflicted, vec!["KEY"]);
+        assert_eq!(report.skipped, vec!["LOCAL_HOME", "LOCAL_PATH"]);
+        assert!(!report.full);
+        assert!(report.finished_ms >= report.started_ms);
+
+        // The next cycle starts empty; with no client to confirm them, our
+        // changes go again
+        engine.push_all().await.unwrap();
+        let reports = engine.reports(10).await.unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].pushed, vec!["A", "KEY", "PULLED"]);
+        assert!(reports[0].pulled.is_empty() && reports[0].conflicted.is_empty());
+        assert_eq!(reports[0].skipped, vec!["LOCAL_PATH"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_incoming_keys() {
+        let engine = test_engine(SyncFilter::default()).await;
+        engine.set_key_policy(KeyPolicy::Strict);
+
+        let outcome = engine
+            .apply_incoming(&remote("my-key", "v", 100))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+        engine
+            .apply_incoming(&remote("prod/MY_KEY", "v", 100))
+            .await
+            .unwrap();
+
+        let (invalid, valid) = engine
+            .storage
+            .read(|s| Ok((s.get("my-key")?, s.get("prod/MY_KEY")?)))
+            .await
+            .unwrap();
+        assert!(invalid.is_none());
+        assert!(valid.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_flooding_peer_is_banned() {
+        let engine = test_engine(SyncFilter::default()).await;
+        engine.set_rate_limit(RateLimit {
+            rate: 0.001,
+            burst: 2,
+            ban_after: 2,
+            ban_secs: 60,
+        });
+        let mut rx = engine.events.subscribe();
+
+        let mut outcomes = Vec::new();
+        for i in 0..5 {
+            let key = format!("KEY_{}", i);
+            outcomes.push(
+                engine
+                    .apply_incoming(&remote(&key, "v", 100))
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            outcomes
+                .iter()
+                .filter(|o| **o != ApplyOutcome::Ignored)
+                .count(),
+            2
+        );
+
+        let banned = std::iter::from_fn(|| rx.try_recv().ok()).any(
+            |event| matches!(event, MeshEvent::PeerBanned { ref peer, .. } if peer == "machine-b"),
+        );
+        assert!(banned);
+        let (stats, peers) = engine.rate_limit_status();
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(peers, vec!["machine-b".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_delete_is_dropped() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let mut delete = remote("KEY", "", 200);
+        delete.deleted = true;
+        delete.id = Some(MessageIds::new().next());
+
+        engine.apply_incoming(&delete).await.unwrap();
+        // Set again here; the old delete must not reach it (or show up as
+        // a conflict) when it is delivered a second time
+        engine
+            .storage
+            .write(|s| s.set("KEY", "v", "machine-a"))
+            .await
+            .unwrap();
+
+        let outcome = engine.apply_incoming(&delete).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+        let found = engine.storage.read(|s| s.get("KEY")).await.unwrap();
+        assert!(found.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_requires_trusted_signature() {
+        let engine = test_engine(SyncFilter::default()).await;
+        // Without trusted keys unsigned changes apply, with a warning
+        let outcome = engine
+            .apply_incoming(&remote("LOG_LEVEL", "debug", 50))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+        assert!(engine.warned_unsigned.load(Ordering::Relaxed));
+
+        let peer = DeviceKey::generate();
+        engine.set_trusted_keys(vec![peer.verifying_key()]);
+
+        let outcome = engine
+            .apply_incoming(&remote("AWS_SECRET", "attacker", 100))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+
+        let mut signed = remote("AWS_SECRET", "real", 100);
+        peer.sign(&mut signed);
+        let outcome = engine.apply_incoming(&signed).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peer_changes_are_dropped() {
+        let engine = test_engine(SyncFilter::default()).await;
+        let peer = DeviceKey::generate();
+        engine.set_peer_access(PeerAccess {
+            allowed: Vec::new(),
+            blocked: vec![peer.public_key()],
+        });
+
+        // Blocked by device key, whatever machine ID it claims
+        let mut signed = remote("KEY", "v", 100);
+        peer.sign(&mut signed);
+        let outcome = engine.apply_incoming(&signed).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+
+        // Nor by leaving its changes unsigned
+        let outcome = engine
+            .apply_incoming(&remote("KEY", "v", 100))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Ignored);
+
+        let mut other = remote("KEY", "v", 100);
+        DeviceKey::generate().sign(&mut other);
+        let outcome = engine.apply_incoming(&other).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+    }
+
+    #[tokio::test]
+    async fn test_roles_limit_incoming_changes() {
+        use crate::users::{Role, UserConfig};
+
+        let engine = test_engine(SyncFilter::default()).await;
+        let (writer, reader) = (DeviceKey::generate(), DeviceKey::generate());
+        let user = |name: &str, role: Role, device: &DeviceKey| UserConfig {
+            name: name.to_string(),
+            role,
+            namespaces: vec!["dev".to_string()],
+            devices: vec![device.public_key()],
+            expires: Default::default(),
+        };
+        engine.set_users(Users::new(vec![
+            user("bob", Role::Writer, &writer),
+            user("cy", Role::Reader, &reader),
+        ]));
+
+        let signed = |key: &DeviceKey, name: &str| {
+            let mut msg = remote(name, "v", 100);
+            key.sign(&mut msg);
+            msg
+        };
+        for (msg, expected) in [
+            (signed(&writer, "dev/DB_URL"), ApplyOutcome::Applied),
+            (signed(&writer, "prod/DB_URL"), ApplyOutcome::Ignored),
+            (signed(&reader, "dev/OTHER"), ApplyOutcome::Ignored),
+            (
+                signed(&DeviceKey::generate(), "dev/NEW"),
+                ApplyOutcome::Ignored,
+            ),
+            (remote("dev/UNSIGNED", "v", 100), ApplyOutcome::Ignored),
+        ] {
+            assert_eq!(engine.apply_incoming(&msg).await.unwrap(), expected);
+        }
+
+        // Our own writes are held to our user's role too
+        assert!(engine.check_write("dev/DB_URL").is_err());
+        engine.set_users(Users::default());
+        assert!(engine.check_write("dev/DB_URL").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revocation_goes_by_its_revokers_signature() {
+        use crate::users::{Role, UserConfig};
+
+        let engine = test_engine(SyncFilter {
+            namespaces: vec!["dev".to_string()],
+            ..Default::default()
+        })
+        .await;
+        let (admin, writer) = (DeviceKey::generate(), DeviceKey::generate());
+        let user = |name: &str, role: Role, device: &DeviceKey| UserConfig {
+            name: name.to_string(),
+            role,
+            namespaces: vec!["dev".to_string()],
+            devices: vec![device.public_key()],
+            expires: Default::default(),
+        };
+        engine.set_users(Users::new(vec![
+            user("ada", Role::Admin, &admin),
+            user("bob", Role::Writer, &writer),
+        ]));
+
+        // Relayed by a writer, who may not write it themselves
+        let stolen = DeviceKey::generate().public_key();
+        let relayed = |revocation: &Revocation| {
+            let mut msg = remote(
+                &revoke::revoked_key(&revocation.device),
+                &revocation.to_value(),
+                100,
+            );
+            writer.sign(&mut msg);
+            msg
+        };
+        let by_writer = Revocation::new(&stolen, true, &writer).unwrap();
+        assert_eq!(
+            engine.apply_incoming(&relayed(&by_writer)).await.unwrap(),
+            ApplyOutcome::Ignored
+        );
+        let by_admin = Revocation::new(&stolen, true, &admin).unwrap();
+        assert_eq!(
+            engine.apply_incoming(&relayed(&by_admin)).await.unwrap(),
+            ApplyOutcome::Applied
+        );
+
+        let mut undo = relayed(&by_admin);
+        undo.timestamp = 200;
+        undo.deleted = true;
+        admin.sign(&mut undo);
+        assert_eq!(
+            engine.apply_incoming(&undo).await.unwrap(),
+            ApplyOutcome::Ignored
+        );
+        let stored = engine.storage.read(|s| s.revocations()).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+}
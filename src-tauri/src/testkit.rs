@@ -0,0 +1,332 @@
+// In-process mesh simulation for tests: N nodes with in-memory stores and
+// real SyncEngines, joined by a simulated network with latency, drops and
+// partitions. Time is simulated too, so a run is repeatable from its seed.
+use anyhow::{anyhow, Result};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::client::SyncMessage;
+use crate::events::EventBus;
+use crate::faults::SimRng;
+use crate::node::{EnvMeshNode, NodeConfig};
+use crate::pool::StoragePool;
+use crate::sync::{SyncEngine, SyncFilter};
+
+/// Unix time the simulated clocks start at
+const EPOCH_SECS: i64 = 1_700_000_000;
+
+/// Conditions on every link between two nodes
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    /// Delivery delay, drawn uniformly from this range (milliseconds)
+    pub latency_ms: (u64, u64),
+    /// Chance that a message is lost
+    pub drop_rate: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: (5, 50),
+            drop_rate: 0.0,
+        }
+    }
+}
+
+/// What the network did with the messages it was given
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NetStats {
+    pub sent: usize,
+    pub delivered: usize,
+    /// Lost to `drop_rate`
+    pub dropped: usize,
+    /// Never sent because the nodes were partitioned
+    pub partitioned: usize,
+}
+
+pub struct SimNode {
+    pub machine_id: String,
+    pub engine: SyncEngine,
+    /// Seconds this node's clock is ahead of simulated time
+    pub clock_skew: i64,
+}
+
+/// A message on its way to a node
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Delivery {
+    at_ms: u64,
+    /// Send order, so equal delivery times stay in order
+    seq: u64,
+    to: usize,
+}
+
+/// Every node reaches every other directly, like clients of one relay
+pub struct SimMesh {
+    nodes: Vec<SimNode>,
+    link: LinkConfig,
+    /// Nodes reach each other only within the same group
+    groups: Vec<usize>,
+    queue: BinaryHeap<Reverse<Delivery>>,
+    payloads: BTreeMap<u64, SyncMessage>,
+    now_ms: u64,
+    seq: u64,
+    rng: SimRng,
+    stats: NetStats,
+}
+
+/// A node's variables: key → value, or None for a tombstone
+pub type SimState = BTreeMap<String, Option<String>>;
+
+impl SimMesh {
+    pub async fn new(nodes: usize, seed: u64) -> Result<Self> {
+        let mut mesh = Vec::with_capacity(nodes);
+        for i in 0..nodes {
+            let events = EventBus::new();
+            let node = EnvMeshNode::offline(NodeConfig::default(), events.clone());
+            let engine = SyncEngine::new(
+                StoragePool::open(PathBuf::from(":memory:"))?,
+                Arc::new(Mutex::new(node)),
+                events,
+                SyncFilter::default(),
+            );
+            let machine_id = format!("node-{}", i);
+            engine.set_machine_id(&machine_id);
+            mesh.push(SimNode {
+                machine_id,
+                engine,
+                clock_skew: 0,
+            });
+        }
+
+        Ok(Self {
+            groups: vec![0; nodes],
+            nodes: mesh,
+            link: LinkConfig::default(),
+            queue: BinaryHeap::new(),
+            payloads: BTreeMap::new(),
+            now_ms: 0,
+            seq: 0,
+            rng: SimRng::new(seed),
+            stats: NetStats::default(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, i: usize) -> &SimNode {
+        &self.nodes[i]
+    }
+
+    pub fn set_link(&mut self, link: LinkConfig) {
+        self.link = link;
+    }
+
+    pub fn set_clock_skew(&mut self, node: usize, secs: i64) {
+        self.nodes[node].clock_skew = secs;
+    }
+
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    /// Unix time on `node`'s clock
+    pub fn clock(&self, node: usize) -> i64 {
+        EPOCH_SECS + (self.now_ms / 1000) as i64 + self.nodes[node].clock_skew
+    }
+
+    /// Let simulated time pass, delivering whatever arrives meanwhile
+    pub async fn advance(&mut self, ms: u64) -> Result<()> {
+        let until = self.now_ms + ms;
+        while self
+            .queue
+            .peek()
+            .is_some_and(|Reverse(next)| next.at_ms <= until)
+        {
+            self.deliver_next().await?;
+        }
+        self.now_ms = until;
+        Ok(())
+    }
+
+    /// Deliver everything in flight
+    pub async fn run_until_idle(&mut self) -> Result<()> {
+        while !self.queue.is_empty() {
+            self.deliver_next().await?;
+        }
+        Ok(())
+    }
+
+    async fn deliver_next(&mut self) -> Result<()> {
+        let Some(Reverse(delivery)) = self.queue.pop() else {
+            return Ok(());
+        };
+        self.now_ms = self.now_ms.max(delivery.at_ms);
+        let msg = self
+            .payloads
+            .remove(&delivery.seq)
+            .ok_or_else(|| anyhow!("lost payload {}", delivery.seq))?;
+        self.nodes[delivery.to].engine.apply_incoming(&msg).await?;
+        self.stats.delivered += 1;
+        Ok(())
+    }
+
+    /// Set on `node` at its own clock and send the change to the others
+    pub async fn set(&mut self, node: usize, key: &str, value: &str) -> Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        let (machine_id, now) = (self.nodes[node].machine_id.clone(), self.clock(node));
+        let storage = self.nodes[node].engine.storage.clone();
+        let stored = key.clone();
+        storage
+            .write(move |s| s.set_at(&stored, &value, &machine_id, now))
+            .await?;
+        self.publish(node, &key).await
+    }
+
+    pub async fn delete(&mut self, node: usize, key: &str) -> Result<()> {
+        let key = key.to_string();
+        let (machine_id, now) = (self.nodes[node].machine_id.clone(), self.clock(node));
+        let storage = self.nodes[node].engine.storage.clone();
+        let stored = key.clone();
+        storage
+            .write(move |s| s.delete_at(&stored, &machine_id, now))
+            .await?;
+        self.publish(node, &key).await
+    }
+
+    /// Send `key`'s stored state from `node`, as the daemon does after a write
+    async fn publish(&mut self, node: usize, key: &str) -> Result<()> {
+        let changes = self.nodes[node].engine.local_changes().await?;
+        for msg in changes.into_iter().filter(|msg| msg.key == key) {
+            self.send(node, msg);
+        }
+        Ok(())
+    }
+
+    /// Send everything `node` holds (`envmesh-cli sync`, or a server
+    /// seeding a client that reconnects)
+    pub async fn full_sync(&mut self, node: usize) -> Result<()> {
+        for msg in self.nodes[node].engine.local_changes().await? {
+            self.send(node, msg);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, from: usize, msg: SyncMessage) {
+        for to in (0..self.nodes.len()).filter(|&to| to != from) {
+            self.stats.sent += 1;
+            if self.groups[from] != self.groups[to] {
+                self.stats.partitioned += 1;
+                continue;
+            }
+            if self.rng.chance(self.link.drop_rate) {
+                self.stats.dropped += 1;
+                continue;
+            }
+
+            let (low, high) = self.link.latency_ms;
+            let at_ms = self.now_ms + self.rng.range(low, high.max(low));
+            self.seq += 1;
+            self.payloads.insert(self.seq, msg.clone());
+            self.queue.push(Reverse(Delivery {
+                at_ms,
+                seq: self.seq,
+                to,
+            }));
+        }
+    }
+
+    /// Split the mesh: nodes only reach others in the same group, and
+    /// nodes left out of every group are cut off from everyone
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let isolated = groups.len();
+        for (i, group) in self.groups.iter_mut().enumerate() {
+            *group = groups
+                .iter()
+                .position(|members| members.contains(&i))
+                .map_or(isolated + i, |g| g);
+        }
+    }
+
+    /// Reconnect everyone. Messages lost meanwhile stay lost; `full_sync`
+    /// (or `heal_and_sync`) is what repairs the difference.
+    pub fn heal(&mut self) {
+        self.groups.fill(0);
+    }
+
+    /// Heal, have every node resend its state, and deliver it all
+    pub async fn heal_and_sync(&mut self) -> Result<()> {
+        self.heal();
+        for node in 0..self.nodes.len() {
+            self.full_sync(node).await?;
+        }
+        self.run_until_idle().await
+    }
+
+    pub async fn state(&self, node: usize) -> Result<SimState> {
+        let changes = self.nodes[node]
+            .engine
+            .storage
+            .read(|s| s.get_changes_since(i64::MIN))
+            .await?;
+        Ok(changes
+            .into_iter()
+            .map(|(key, value, _, _, deleted)| (key, (!deleted).then_some(value)))
+            .collect())
+    }
+
+    /// Ok if every node holds the same variables; otherwise the first key
+    /// two nodes disagree on
+    pub async fn converged(&self) -> Result<()> {
+        let first = self.state(0).await?;
+        for node in 1..self.nodes.len() {
+            let state = self.state(node).await?;
+            if state == first {
+                continue;
+            }
+            let key = first
+                .keys()
+                .chain(state.keys())
+                .find(|key| first.get(*key) != state.get(*key))
+                .cloned()
+                .unwrap_or_default();
+            return Err(anyhow!(
+                "node-0 and node-{} disagree on {}: {:?} vs {:?}",
+                node,
+                key,
+                first.get(&key),
+                state.get(&key)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_partitioned_nodes_miss_changes_until_synced() {
+        let mut mesh = SimMesh::new(3, 7).await.unwrap();
+        mesh.partition(&[&[0, 1], &[2]]);
+        mesh.set(0, "KEY", "v1").await.unwrap();
+        mesh.run_until_idle().await.unwrap();
+
+        assert_eq!(mesh.state(1).await.unwrap()["KEY"], Some("v1".to_string()));
+        assert!(mesh.state(2).await.unwrap().is_empty());
+        assert!(mesh.converged().await.is_err());
+        assert_eq!(mesh.stats().partitioned, 1);
+
+        mesh.heal_and_sync().await.unwrap();
+        mesh.converged().await.unwrap();
+    }
+}
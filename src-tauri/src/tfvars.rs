@@ -0,0 +1,179 @@
+// Terraform input variables (`envmesh-cli export --format tfvars`): a
+// terraform.tfvars document, or TF_VAR_-prefixed environment variables.
+// Values are strings unless a `tf:number` or `tf:bool` tag says otherwise.
+use anyhow::{anyhow, bail, Result};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Tag giving a variable's Terraform type: `tf:string`, `tf:number`, `tf:bool`
+pub const TF_TAG_PREFIX: &str = "tf:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TfType {
+    #[default]
+    String,
+    Number,
+    Bool,
+}
+
+impl TfType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Bool => "bool",
+        }
+    }
+
+    /// The type a key's tags give it (string without a `tf:` tag)
+    pub fn from_tags(tags: &[String]) -> Self {
+        tags.iter()
+            .find_map(|tag| tag.strip_prefix(TF_TAG_PREFIX)?.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// `value` as this type, in the form Terraform reads: numbers as
+    /// written, bools as `true`/`false`
+    pub fn normalize(self, value: &str) -> Result<String> {
+        match self {
+            Self::String => Ok(value.to_string()),
+            Self::Number if is_number(value.trim()) => Ok(value.trim().to_string()),
+            Self::Bool => match value.trim().to_ascii_lowercase().as_str() {
+                "true" => Ok("true".to_string()),
+                "false" => Ok("false".to_string()),
+                _ => bail!("{:?} is not true or false", value),
+            },
+            Self::Number => bail!("{:?} is not a number", value),
+        }
+    }
+}
+
+impl FromStr for TfType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "string" => Ok(Self::String),
+            "number" => Ok(Self::Number),
+            "bool" => Ok(Self::Bool),
+            other => Err(anyhow!(
+                "Unknown Terraform type {:?} (string, number or bool)",
+                other
+            )),
+        }
+    }
+}
+
+/// Refuse `tf:` tags naming a type we can't write
+pub fn validate_tags(tags: &[String]) -> Result<()> {
+    for tag in tags {
+        if let Some(ty) = tag.strip_prefix(TF_TAG_PREFIX) {
+            ty.parse::<TfType>()?;
+        }
+    }
+    Ok(())
+}
+
+/// HCL number literal: `-12`, `3.5`, `1e6`
+fn is_number(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (digits, None),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let mantissa_ok = match mantissa.split_once('.') {
+        Some((whole, fraction)) => all_digits(whole) && all_digits(fraction),
+        None => all_digits(mantissa),
+    };
+    let exponent_ok = exponent.is_none_or(|e| all_digits(e.strip_prefix(['+', '-']).unwrap_or(e)));
+    mantissa_ok && exponent_ok
+}
+
+/// A quoted HCL string; `${` and `%{` are escaped so values are never read
+/// as templates
+fn hcl_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' | '%' if chars.peek() == Some(&'{') => {
+                quoted.push(c);
+                quoted.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A terraform.tfvars document assigning each `(name, value, type)`. Every
+/// value that doesn't fit its type is reported together.
+pub fn render(vars: &[(String, String, TfType)]) -> Result<String> {
+    let mut document = String::new();
+    let mut errors = Vec::new();
+    for (name, value, ty) in vars {
+        match ty.normalize(value) {
+            Ok(value) if *ty == TfType::String => {
+                let _ = writeln!(document, "{} = {}", name, hcl_string(&value));
+            }
+            Ok(value) => {
+                let _ = writeln!(document, "{} = {}", name, value);
+            }
+            Err(e) => errors.push(format!("{} (tf:{}): {}", name, ty.as_str(), e)),
+        }
+    }
+    if !errors.is_empty() {
+        bail!(errors.join("\n"));
+    }
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tfvars_types_and_quoting() {
+        let tags = vec!["prod".to_string(), "tf:number".to_string()];
+        assert_eq!(TfType::from_tags(&tags), TfType::Number);
+        assert_eq!(TfType::from_tags(&[]), TfType::String);
+        assert!(validate_tags(&["tf:list".to_string()]).is_err());
+
+        let vars = vec![
+            (
+                "db_url".to_string(),
+                "postgres://${USER}@db \"main\"\n".to_string(),
+                TfType::String,
+            ),
+            ("replicas".to_string(), " 3 ".to_string(), TfType::Number),
+            ("ratio".to_string(), "-1.5e3".to_string(), TfType::Number),
+            ("enabled".to_string(), "TRUE".to_string(), TfType::Bool),
+        ];
+        assert_eq!(
+            render(&vars).unwrap(),
+            "db_url = \"postgres://$${USER}@db \\\"main\\\"\\n\"\n\
+             replicas = 3\n\
+             ratio = -1.5e3\n\
+             enabled = true\n"
+        );
+
+        let bad = vec![
+            ("replicas".to_string(), "three".to_string(), TfType::Number),
+            ("enabled".to_string(), "yes".to_string(), TfType::Bool),
+            ("port".to_string(), "1.".to_string(), TfType::Number),
+        ];
+        let err = render(&bad).unwrap_err().to_string();
+        assert_eq!(err.lines().count(), 3, "{}", err);
+    }
+}
@@ -0,0 +1,98 @@
+// Bandwidth limits for sync traffic (`[network]`), so a big initial sync
+// doesn't saturate a metered or slow link
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `[network]`. Limits are in kilobits per second; 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Everything this machine sends, over all its connections
+    pub max_upload_kbps: u32,
+    /// Everything this machine reads, over all its connections
+    pub max_download_kbps: u32,
+}
+
+impl NetworkConfig {
+    pub fn upload(&self) -> Option<Throttle> {
+        Throttle::new(self.max_upload_kbps)
+    }
+
+    pub fn download(&self) -> Option<Throttle> {
+        Throttle::new(self.max_download_kbps)
+    }
+}
+
+struct Bucket {
+    /// Bytes that may pass now; negative when callers are waiting
+    available: f64,
+    refilled: Instant,
+}
+
+/// A token bucket shared by the connections it limits. Up to a second's
+/// worth of traffic passes at once; beyond that callers wait their turn.
+#[derive(Clone)]
+pub struct Throttle {
+    bytes_per_sec: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Throttle {
+    /// A throttle for `kbps`, or None if unlimited
+    pub fn new(kbps: u32) -> Option<Self> {
+        if kbps == 0 {
+            return None;
+        }
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Some(Self {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                available: bytes_per_sec,
+                refilled: Instant::now(),
+            })),
+        })
+    }
+
+    /// Reserve `bytes`, and how long to wait before they may go
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_sec;
+        bucket.available = (bucket.available + refill).min(self.bytes_per_sec);
+        bucket.refilled = now;
+        // Reserving before waiting keeps concurrent callers in order
+        bucket.available -= bytes as f64;
+        if bucket.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / self.bytes_per_sec)
+        }
+    }
+
+    /// Wait until `bytes` more fit within the limit
+    pub async fn take(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_waits_once_the_burst_is_spent() {
+        assert!(Throttle::new(0).is_none());
+
+        // 8 kbps is 1000 bytes a second
+        let throttle = Throttle::new(8).unwrap();
+        assert_eq!(throttle.reserve(1000), Duration::ZERO);
+        let wait = throttle.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        // Later callers queue behind the reservation
+        assert!(throttle.reserve(500) > Duration::from_millis(950));
+    }
+}
@@ -0,0 +1,365 @@
+// TLS for wss:// servers (`[tls]`): extra CA bundles, certificate pinning by
+// public key hash, and an opt-in insecure mode for self-signed relays
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::http::Uri;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::Certificate;
+
+/// Pins are written as in curl's `--pinnedpubkey`
+pub const PIN_PREFIX: &str = "sha256/";
+
+/// `[tls]`, for wss:// cloud and relay URLs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM bundle of CAs trusted alongside the built-in roots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// `sha256/<base64>` public key hashes; when set, the server's chain
+    /// must contain one of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<String>,
+    /// Accept any certificate. Meant for self-signed relays, and only safe
+    /// together with `pins`.
+    pub insecure: bool,
+}
+
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A connection to a server, encrypted or not
+pub type Transport = Box<dyn Io>;
+
+/// Connect to `uri`'s host, over TLS for wss://
+pub async fn connect(uri: &Uri, config: &TlsConfig) -> Result<Transport> {
+    let secure = match uri.scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        other => return Err(anyhow!("Unsupported URL scheme: {:?}", other)),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("No host in {}", uri))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp = TcpStream::connect((host, port)).await?;
+    if !secure {
+        return Ok(Box::new(tcp));
+    }
+    if config.insecure {
+        if config.pins.is_empty() {
+            tracing::warn!(
+                "⚠️  [tls] insecure: NOT verifying the certificate of {} - anyone on the path can read and change synced variables",
+                host
+            );
+        } else {
+            tracing::warn!("[tls] insecure: trusting {} by pin only", host);
+        }
+    }
+    let name = ServerName::try_from(host.to_string())?;
+    let stream = connector(config)?.connect(name, tcp).await?;
+    Ok(Box::new(stream))
+}
+
+fn connector(config: &TlsConfig) -> Result<TlsConnector> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let verifier = Verifier::new(config, provider.clone())?;
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(client)))
+}
+
+/// The pin for a DER certificate's public key: `sha256/` and the base64
+/// SHA-256 of its SubjectPublicKeyInfo, as
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+/// prints it
+pub fn spki_pin(cert: &[u8]) -> Result<String> {
+    let cert = Certificate::from_der(cert).map_err(|e| anyhow!("Invalid certificate: {}", e))?;
+    let spki = cert
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(|e| anyhow!("Invalid public key: {}", e))?;
+    Ok(format!(
+        "{}{}",
+        PIN_PREFIX,
+        BASE64.encode(Sha256::digest(spki))
+    ))
+}
+
+/// Chain validation against the roots (unless insecure), then the pins
+#[derive(Debug)]
+struct Verifier {
+    /// None in insecure mode
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Verifier {
+    fn new(config: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self> {
+        for pin in &config.pins {
+            let hash = pin
+                .strip_prefix(PIN_PREFIX)
+                .and_then(|hash| BASE64.decode(hash).ok());
+            if hash.map(|hash| hash.len()) != Some(32) {
+                return Err(anyhow!(
+                    "Invalid [tls] pin {:?}: expected {}<base64 SHA-256>",
+                    pin,
+                    PIN_PREFIX
+                ));
+            }
+        }
+
+        let webpki = if config.insecure {
+            None
+        } else {
+            let mut roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            if let Some(path) = &config.ca_file {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .with_context(|| format!("Failed to read [tls] ca_file {}", path.display()))?;
+                let (added, _) = roots.add_parsable_certificates(certs);
+                if added == 0 {
+                    return Err(anyhow!("No CA certificates in {}", path.display()));
+                }
+            }
+            Some(
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()?,
+            )
+        };
+
+        Ok(Self {
+            webpki,
+            pins: config.pins.clone(),
+            provider,
+        })
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        // The server's own key only: anyone can append a pinned certificate
+        // to their chain, which proves nothing without its private key
+        let pinned = spki_pin(end_entity).is_ok_and(|pin| self.pins.contains(&pin));
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate matches none of the [tls] pins".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::WebSocketClient;
+    use std::path::Path;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+    /// From the openssl pipeline in `spki_pin`'s docs
+    const RELAY_PIN: &str = "sha256/KbondgbgtUZMcxWLD7fUAp5bzeNUpMD8TSwDriJqiQs=";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(FIXTURES).join(name)
+    }
+
+    /// A wss:// server with the fixture certificate (issued by the fixture
+    /// CA for localhost) that accepts WebSocket handshakes forever
+    async fn relay() -> u16 {
+        serve(&["relay.pem"], "relay.key").await
+    }
+
+    /// A wss:// server presenting the certificates in `chain`, leaf first
+    async fn serve(chain: &[&str], key: &str) -> u16 {
+        let certs = chain
+            .iter()
+            .map(|name| CertificateDer::from_pem_file(fixture(name)).unwrap())
+            .collect();
+        let key = PrivateKeyDer::from_pem_file(fixture(key)).unwrap();
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(tcp).await {
+                        if let Ok(_ws) = tokio_tungstenite::accept_async(stream).await {
+                            // Hold the connection open; the test ends first
+                            std::future::pending::<()>().await;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    async fn connects(port: u16, tls: TlsConfig) -> bool {
+        let options = crate::client::ConnectOptions {
+            tls,
+            ..Default::default()
+        };
+        let url = format!("wss://localhost:{}", port);
+        WebSocketClient::connect_with(&url, &options).await.is_ok()
+    }
+
+    #[test]
+    fn test_spki_pin_matches_openssl() {
+        let cert = CertificateDer::from_pem_file(fixture("relay.pem")).unwrap();
+        assert_eq!(spki_pin(&cert).unwrap(), RELAY_PIN);
+
+        let bad_pin = TlsConfig {
+            pins: vec!["KbondgbgtUZMcxWLD7fUAp5bzeNUpMD8TSwDriJqiQs=".to_string()],
+            ..Default::default()
+        };
+        let provider = Arc::new(crypto::ring::default_provider());
+        assert!(Verifier::new(&bad_pin, provider).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_trust_options() {
+        let port = relay().await;
+        let ca_file = Some(fixture("ca.pem"));
+
+        // Not issued by a public CA
+        assert!(!connects(port, TlsConfig::default()).await);
+        assert!(
+            connects(
+                port,
+                TlsConfig {
+                    ca_file: ca_file.clone(),
+                    ..Default::default()
+                }
+            )
+            .await
+        );
+        assert!(
+            !connects(
+                port,
+                TlsConfig {
+                    ca_file,
+                    pins: vec![format!("{}{}", PIN_PREFIX, BASE64.encode([0u8; 32]))],
+                    insecure: false,
+                }
+            )
+            .await
+        );
+        assert!(
+            connects(
+                port,
+                TlsConfig {
+                    pins: vec![RELAY_PIN.to_string()],
+                    insecure: true,
+                    ..Default::default()
+                }
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pins_match_the_servers_own_certificate() {
+        // A self-signed leaf of another key, with the pinned certificate
+        // appended as if it were an intermediate
+        let port = serve(&["foreign.pem", "relay.pem"], "foreign.key").await;
+        let pinned = TlsConfig {
+            pins: vec![RELAY_PIN.to_string()],
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(!connects(port, pinned).await);
+    }
+}
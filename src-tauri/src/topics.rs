@@ -0,0 +1,126 @@
+// Namespace topics: a client names the namespaces it syncs at the handshake
+// (`[sync] namespaces`) and its server only sends it changes in those
+use std::collections::BTreeSet;
+
+use crate::client::WireMessage;
+use crate::locks;
+use crate::revoke;
+use crate::storage;
+
+/// Handshake header listing the namespaces a client subscribes to,
+/// comma-separated. Without it a client gets every namespace.
+pub const TOPICS_HEADER: &str = "x-envmesh-topics";
+
+/// The namespaces one connection subscribes to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topics {
+    /// None for every namespace
+    namespaces: Option<BTreeSet<String>>,
+}
+
+impl Topics {
+    /// Subscribe to `namespaces`, or to everything if there are none
+    pub fn new(namespaces: &[String]) -> Self {
+        if namespaces.is_empty() {
+            return Self::default();
+        }
+        Self {
+            namespaces: Some(namespaces.iter().map(|ns| normalize(ns)).collect()),
+        }
+    }
+
+    /// Read a `TOPICS_HEADER` value
+    pub fn parse(value: &str) -> Self {
+        let namespaces: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|ns| !ns.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self::new(&namespaces)
+    }
+
+    /// The `TOPICS_HEADER` value, or None when subscribed to everything
+    pub fn header_value(&self) -> Option<String> {
+        self.namespaces
+            .as_ref()
+            .map(|namespaces| namespaces.iter().cloned().collect::<Vec<_>>().join(","))
+    }
+
+    pub fn contains(&self, namespace: &str) -> bool {
+        match &self.namespaces {
+            Some(namespaces) => namespaces.contains(namespace),
+            None => true,
+        }
+    }
+
+    /// Whether `key` is in a subscribed namespace (revocations are in all)
+    pub fn wants_key(&self, key: &str) -> bool {
+        revoke::revoked_device(key).is_some() || self.contains(namespace_of(key))
+    }
+
+    /// Only the namespaces `allowed` has as well
+    pub fn within(&self, allowed: &Topics) -> Topics {
+        match (&self.namespaces, &allowed.namespaces) {
+            (_, None) => self.clone(),
+            (None, Some(_)) => allowed.clone(),
+            (Some(ours), Some(allowed)) => Self {
+                namespaces: Some(ours.intersection(allowed).cloned().collect()),
+            },
+        }
+    }
+}
+
+/// The namespace `key` syncs in; a lock goes with the key it locks
+pub fn namespace_of(key: &str) -> &str {
+    storage::split_key(locks::locked_key(key).unwrap_or(key)).0
+}
+
+/// The namespace a frame belongs to; None for control frames and
+/// revocations, which go to everyone
+pub fn topic_of(frame: &WireMessage) -> Option<&str> {
+    match frame {
+        WireMessage::Sync(msg) if revoke::revoked_device(&msg.key).is_some() => None,
+        WireMessage::Sync(msg) => Some(namespace_of(&msg.key)),
+        WireMessage::SyncChunk { key, .. } => Some(namespace_of(key)),
+        _ => None,
+    }
+}
+
+/// "" is how the default namespace is written in keys and commands
+fn normalize(namespace: &str) -> String {
+    match namespace.trim() {
+        "" => storage::DEFAULT_NAMESPACE.to_string(),
+        namespace => namespace.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_round_trip_and_match() {
+        let all = Topics::new(&[]);
+        assert_eq!(all.header_value(), None);
+        assert!(all.wants_key("prod/DB_URL"));
+
+        let topics = Topics::new(&["prod".to_string(), "default".to_string()]);
+        assert_eq!(topics.header_value().as_deref(), Some("default,prod"));
+        assert_eq!(Topics::parse(" prod , default,"), topics);
+        assert!(topics.wants_key("prod/DB_URL"));
+        assert!(topics.wants_key("PLAIN_KEY"));
+        assert!(!topics.wants_key("dev/DB_URL"));
+        assert!(topics.wants_key("@lock/prod/DB_URL"));
+        assert!(!topics.wants_key("@lock/dev/DB_URL"));
+        assert!(topics.wants_key("@revoked/ZGV2aWNl"));
+
+        let dev = Topics::new(&["dev".to_string(), "prod".to_string()]);
+        assert_eq!(all.within(&topics), topics);
+        assert_eq!(topics.within(&all), topics);
+        let both = topics.within(&dev);
+        assert!(both.contains("prod"));
+        assert!(!both.contains("dev"));
+        assert!(!both.contains("default"));
+    }
+}
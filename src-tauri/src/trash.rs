@@ -0,0 +1,30 @@
+// Trash: a deleted variable keeps its tombstone (so the delete syncs) and
+// its history, so `envmesh-cli trash restore` can bring back the last value.
+// With `[storage] trash_retention_days` the daemon purges the values of
+// variables deleted longer ago than that for good; their tombstones stay.
+use std::time::Duration;
+
+use crate::clock;
+use crate::pool::StoragePool;
+
+/// How often the daemon looks for tombstones past their retention
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Purge variables deleted more than `retention_days` ago, now and every
+/// `PURGE_INTERVAL`
+pub async fn run_purge(storage: StoragePool, retention_days: u64) {
+    let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let before = clock::now() - (retention_days * 86_400) as i64;
+        match storage.write(move |s| s.purge_trash(before)).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!(
+                "Purged {} variables deleted over {} days ago",
+                purged,
+                retention_days
+            ),
+            Err(e) => tracing::warn!("Failed to purge trash: {}", e),
+        }
+    }
+}
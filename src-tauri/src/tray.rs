@@ -0,0 +1,242 @@
+// System tray status indication and native notifications
+use tauri::{image::Image, AppHandle, Runtime};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::events::MeshEvent;
+use crate::node::NodeMode;
+
+pub const TRAY_ID: &str = "main";
+
+const CLOUD_COLOR: [u8; 3] = [46, 160, 67];
+const LAN_CLIENT_COLOR: [u8; 3] = [56, 132, 244];
+const LAN_SERVER_COLOR: [u8; 3] = [230, 162, 22];
+const OFFLINE_COLOR: [u8; 3] = [128, 128, 128];
+const BADGE_COLOR: [u8; 3] = [218, 54, 51];
+
+/// What the tray icon currently reflects
+pub struct TrayStatus {
+    mode: Option<NodeMode>,
+    unresolved_conflicts: usize,
+}
+
+impl TrayStatus {
+    pub fn new(mode: NodeMode) -> Self {
+        Self {
+            mode: Some(mode),
+            unresolved_conflicts: 0,
+        }
+    }
+
+    /// Fold an event into the status; returns true if the icon should change
+    pub fn apply(&mut self, event: &MeshEvent) -> bool {
+        match event {
+            MeshEvent::ModeChanged { mode } => {
+                self.mode = Some(mode.clone());
+                true
+            }
+            MeshEvent::PeerLost { .. }
+                if !matches!(self.mode, Some(NodeMode::LanServer { .. })) =>
+            {
+                // Lost our server; failover will report the new mode
+                self.mode = None;
+                true
+            }
+            MeshEvent::ConflictDetected { .. } => {
+                self.unresolved_conflicts += 1;
+                true
+            }
+            MeshEvent::ConflictResolved { .. } => {
+                self.unresolved_conflicts = self.unresolved_conflicts.saturating_sub(1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn color(&self) -> [u8; 3] {
+        match &self.mode {
+            Some(NodeMode::CloudClient) => CLOUD_COLOR,
+            Some(NodeMode::LanClient { .. }) => LAN_CLIENT_COLOR,
+            Some(NodeMode::LanServer { .. }) => LAN_SERVER_COLOR,
+            None => OFFLINE_COLOR,
+        }
+    }
+
+    fn tooltip(&self) -> String {
+        let mode = match &self.mode {
+            Some(NodeMode::CloudClient) => "Connected to cloud".to_string(),
+            Some(NodeMode::LanClient { server_addr }) => format!("LAN client ({})", server_addr),
+            Some(NodeMode::LanServer { port }) => format!("LAN server on port {}", port),
+            None => "Offline".to_string(),
+        };
+
+        if self.unresolved_conflicts > 0 {
+            format!(
+                "EnvMesh - {} - {} conflict(s)",
+                mode, self.unresolved_conflicts
+            )
+        } else {
+            format!("EnvMesh - {}", mode)
+        }
+    }
+}
+
+/// Redraw the tray icon and tooltip for the given status
+pub fn refresh<R: Runtime>(app: &AppHandle<R>, status: &TrayStatus) {
+    let Some(tray) = app.tray_by_id(TRAY_ID) else {
+        return;
+    };
+
+    if let Some(base) = app.default_window_icon() {
+        let icon = status_icon(base, status.color(), status.unresolved_conflicts > 0);
+        if let Err(e) = tray.set_icon(Some(icon)) {
+            tracing::warn!("Failed to update tray icon: {}", e);
+        }
+    }
+
+    if let Err(e) = tray.set_tooltip(Some(status.tooltip())) {
+        tracing::warn!("Failed to update tray tooltip: {}", e);
+    }
+}
+
+/// Show a native notification for events worth interrupting the user for
+pub fn notify<R: Runtime>(app: &AppHandle<R>, event: &MeshEvent) {
+    if !event.is_notable() {
+        return;
+    }
+
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title("EnvMesh")
+        .body(event.to_string())
+        .show()
+    {
+        tracing::warn!("Failed to show notification: {}", e);
+    }
+}
+
+/// What the tray reports after Sync Now
+pub fn sync_message(result: &anyhow::Result<usize>) -> String {
+    match result {
+        Ok(changes) => format!("Sync complete ({} changes pushed)", changes),
+        Err(e) => format!("Sync failed: {}", e),
+    }
+}
+
+/// Surface the result of a user-initiated action: as a notification when
+/// enabled, otherwise in the tray tooltip until the next status refresh
+pub fn report<R: Runtime>(app: &AppHandle<R>, message: &str, notify: bool) {
+    if notify {
+        if let Err(e) = app
+            .notification()
+            .builder()
+            .title("EnvMesh")
+            .body(message)
+            .show()
+        {
+            tracing::warn!("Failed to show notification: {}", e);
+        }
+    }
+
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        if let Err(e) = tray.set_tooltip(Some(format!("EnvMesh - {}", message))) {
+            tracing::warn!("Failed to update tray tooltip: {}", e);
+        }
+    }
+}
+
+/// Tint the base icon with the status color, adding a badge dot in the
+/// top-right corner when sync needs attention
+fn status_icon(base: &Image<'_>, color: [u8; 3], badge: bool) -> Image<'static> {
+    let (width, height) = (base.width(), base.height());
+    let mut rgba = base.rgba().to_vec();
+
+    for px in rgba.chunks_exact_mut(4) {
+        for (channel, tint) in px.iter_mut().zip(color) {
+            *channel = ((u16::from(*channel) + u16::from(tint)) / 2) as u8;
+        }
+    }
+
+    if badge {
+        let radius = (width.min(height) / 5).max(2) as i64;
+        let (cx, cy) = (width as i64 - radius - 1, radius + 1);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                if (x - cx).pow(2) + (y - cy).pow(2) <= radius.pow(2) {
+                    let i = ((y * width as i64 + x) * 4) as usize;
+                    rgba[i..i + 3].copy_from_slice(&BADGE_COLOR);
+                    rgba[i + 3] = 255;
+                }
+            }
+        }
+    }
+
+    Image::new_owned(rgba, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_follows_events() {
+        let mut status = TrayStatus::new(NodeMode::CloudClient);
+        assert_eq!(status.color(), CLOUD_COLOR);
+        assert_eq!(status.tooltip(), "EnvMesh - Connected to cloud");
+
+        // Losing the cloud server shows offline until failover reports in
+        assert!(status.apply(&MeshEvent::PeerLost {
+            peer: "cloud".to_string()
+        }));
+        assert_eq!(status.color(), OFFLINE_COLOR);
+        assert!(status.apply(&MeshEvent::ModeChanged {
+            mode: NodeMode::LanServer { port: 8765 }
+        }));
+        assert_eq!(status.color(), LAN_SERVER_COLOR);
+        // A LAN server losing a client stays a LAN server
+        assert!(!status.apply(&MeshEvent::PeerLost {
+            peer: "laptop".to_string()
+        }));
+
+        let conflict = MeshEvent::ConflictDetected {
+            key: "AWS_SECRET_KEY".to_string(),
+            local_machine: "laptop".to_string(),
+            remote_machine: "desktop-01".to_string(),
+        };
+        assert!(status.apply(&conflict));
+        assert_eq!(
+            status.tooltip(),
+            "EnvMesh - LAN server on port 8765 - 1 conflict(s)"
+        );
+        assert!(status.apply(&MeshEvent::ConflictResolved {
+            key: "AWS_SECRET_KEY".to_string()
+        }));
+        assert_eq!(status.unresolved_conflicts, 0);
+        assert!(!status.apply(&MeshEvent::SyncCompleted { changes: 1 }));
+    }
+
+    #[test]
+    fn test_sync_message() {
+        assert_eq!(sync_message(&Ok(3)), "Sync complete (3 changes pushed)");
+        assert_eq!(
+            sync_message(&Err(anyhow::anyhow!("Not connected"))),
+            "Sync failed: Not connected"
+        );
+    }
+
+    #[test]
+    fn test_status_icon_tint_and_badge() {
+        let base = Image::new_owned(vec![255; 16 * 16 * 4], 16, 16);
+
+        let plain = status_icon(&base, CLOUD_COLOR, false);
+        assert_eq!((plain.width(), plain.height()), (16, 16));
+        assert_eq!(&plain.rgba()[..3], &[150, 207, 161]);
+
+        // The badge sits in the top-right corner
+        let badged = status_icon(&base, CLOUD_COLOR, true);
+        let top_right = ((3 * 16 + 12) * 4) as usize;
+        assert_eq!(&badged.rgba()[top_right..top_right + 3], &BADGE_COLOR);
+        assert_eq!(&badged.rgba()[..3], &[150, 207, 161]);
+    }
+}
@@ -0,0 +1,346 @@
+// Team users (`[[users]]`): the people sharing a mesh, as opposed to their
+// machines. Each user enrolls the machines they work from by device key and
+// has a role, limited to namespaces. With no users the mesh is open as it
+// always was; once there are some, machines nobody enrolled are refused.
+// Guests are enrolled until a time, after which every machine treats them as
+// unenrolled.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::clock;
+use crate::node::EnvMeshNode;
+use crate::topics::{self, Topics};
+
+/// How often connections of machines whose enrollment ran out are dropped
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Changes every namespace and manages users
+    Admin,
+    /// Changes its namespaces
+    Writer,
+    /// Only receives its namespaces
+    Reader,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Writer => "writer",
+            Self::Reader => "reader",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "admin" => Ok(Self::Admin),
+            "writer" => Ok(Self::Writer),
+            "reader" => Ok(Self::Reader),
+            _ => Err(anyhow!(
+                "Unknown role {:?} (expected admin, writer or reader)",
+                s
+            )),
+        }
+    }
+}
+
+/// `[[users]]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub role: Role,
+    /// Namespaces the user receives and (unless a reader) changes; empty =
+    /// all. Admins always have all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<String>,
+    /// Device keys (`envmesh-cli device-key`) of the user's machines
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<String>,
+    /// When a device's enrollment ends (Unix seconds, `user enroll
+    /// --expires`); devices not listed stay enrolled
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub expires: BTreeMap<String, i64>,
+}
+
+impl UserConfig {
+    /// Whether `device` is one of the user's machines and hasn't expired
+    pub fn is_enrolled(&self, device: &str) -> bool {
+        self.devices.iter().any(|d| d == device)
+            && self
+                .expires
+                .get(device)
+                .is_none_or(|expires_at| *expires_at > clock::now())
+    }
+
+    /// The namespaces the user's machines are sent
+    pub fn topics(&self) -> Topics {
+        match self.role {
+            Role::Admin => Topics::default(),
+            _ => Topics::new(&self.namespaces),
+        }
+    }
+
+    /// Why the user may not change `key`, if they may not
+    pub fn check_write(&self, key: &str) -> Result<()> {
+        if self.role == Role::Reader {
+            return Err(anyhow!(
+                "{} is a reader and can't change {}",
+                self.name,
+                key
+            ));
+        }
+        let namespace = topics::namespace_of(key);
+        if !self.topics().contains(namespace) {
+            return Err(anyhow!(
+                "{} can't change variables in namespace {}",
+                self.name,
+                namespace
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Everyone on the mesh, with the machines they enrolled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Users {
+    pub users: Vec<UserConfig>,
+}
+
+impl Users {
+    pub fn new(users: Vec<UserConfig>) -> Self {
+        Self { users }
+    }
+
+    /// Whether roles are enforced at all
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// The user who enrolled the machine known by `ids`
+    pub fn user_of(&self, ids: &[String]) -> Option<&UserConfig> {
+        self.users
+            .iter()
+            .find(|user| ids.iter().any(|id| user.is_enrolled(id)))
+    }
+
+    /// Whether the machine known by `ids` may change `key`
+    pub fn check_write(&self, ids: &[String], key: &str) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        match self.user_of(ids) {
+            Some(user) => user.check_write(key),
+            None => Err(anyhow!("machine is not enrolled by any user")),
+        }
+    }
+
+    /// Whether the machine known by `ids` may add, change and remove users
+    pub fn check_manage(&self, ids: &[String]) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        match self.user_of(ids) {
+            Some(user) if user.role == Role::Admin => Ok(()),
+            Some(user) => Err(anyhow!("{} is not an admin", user.name)),
+            None => Err(anyhow!("machine is not enrolled by any user")),
+        }
+    }
+
+    /// Add a user, or change an existing one's role and namespaces
+    pub fn set(&mut self, name: &str, role: Role, namespaces: Vec<String>) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(anyhow!("no user name given"));
+        }
+        match self.users.iter_mut().find(|user| user.name == name) {
+            Some(user) => {
+                user.role = role;
+                user.namespaces = namespaces;
+            }
+            None => self.users.push(UserConfig {
+                name: name.to_string(),
+                role,
+                namespaces,
+                devices: Vec::new(),
+                expires: BTreeMap::new(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// Enroll a machine by its device key, until `expires_at` (Unix seconds)
+    /// if given; false if `name` already had it, just so
+    pub fn enroll(&mut self, name: &str, device: &str, expires_at: Option<i64>) -> Result<bool> {
+        let device = device.trim();
+        crate::signing::parse_public_key(device)?;
+        if let Some(owner) = self
+            .users
+            .iter()
+            .find(|user| user.name != name && user.is_enrolled(device))
+        {
+            return Err(anyhow!("{} is enrolled by {}", device, owner.name));
+        }
+        if !self.users.iter().any(|user| user.name == name) {
+            return Err(anyhow!("No user named {}", name));
+        }
+        // Whoever had it before, it has expired there
+        for user in self.users.iter_mut().filter(|user| user.name != name) {
+            user.devices.retain(|d| d != device);
+            user.expires.remove(device);
+        }
+
+        let user = self
+            .users
+            .iter_mut()
+            .find(|user| user.name == name)
+            .expect("checked above");
+        let known = user.devices.iter().any(|d| d == device);
+        if known && user.expires.get(device).copied() == expires_at {
+            return Ok(false);
+        }
+        if !known {
+            user.devices.push(device.to_string());
+        }
+        match expires_at {
+            Some(expires_at) => user.expires.insert(device.to_string(), expires_at),
+            None => user.expires.remove(device),
+        };
+        Ok(true)
+    }
+
+    /// Remove a machine from whoever enrolled it; the user it belonged to,
+    /// if any
+    pub fn unenroll(&mut self, device: &str) -> Option<String> {
+        let device = device.trim();
+        let user = self
+            .users
+            .iter_mut()
+            .find(|user| user.devices.iter().any(|d| d == device))?;
+        user.devices.retain(|d| d != device);
+        user.expires.remove(device);
+        Some(user.name.clone())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        let before = self.users.len();
+        self.users.retain(|user| user.name != name);
+        if self.users.len() == before {
+            return Err(anyhow!("No user named {}", name));
+        }
+        Ok(())
+    }
+}
+
+/// Drop the node's connections from machines whose enrollment ran out, every
+/// `EXPIRY_CHECK_INTERVAL` (new ones are refused at the handshake anyway)
+pub async fn run_expiry(node: Arc<AsyncMutex<EnvMeshNode>>) {
+    let mut ticker = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let dropped = node.lock().await.drop_expired_users();
+        if dropped > 0 {
+            tracing::info!("Dropped {} connection(s) whose enrollment expired", dropped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::DeviceKey;
+
+    fn ids(device: &str) -> Vec<String> {
+        vec!["machine-x".to_string(), device.to_string()]
+    }
+
+    #[test]
+    fn test_roles_limit_writes_to_namespaces() {
+        let (admin, writer, reader) = (
+            DeviceKey::generate().public_key(),
+            DeviceKey::generate().public_key(),
+            DeviceKey::generate().public_key(),
+        );
+        let mut users = Users::default();
+        assert!(users.check_write(&ids("anyone"), "prod/DB_URL").is_ok());
+
+        users.set("ada", Role::Admin, Vec::new()).unwrap();
+        users
+            .set("bob", Role::Writer, vec!["dev".to_string(), "".to_string()])
+            .unwrap();
+        users
+            .set("cy", Role::Reader, vec!["dev".to_string()])
+            .unwrap();
+        assert!(users.enroll("ada", &admin, None).unwrap());
+        assert!(!users.enroll("ada", &admin, None).unwrap());
+        users.enroll("bob", &writer, None).unwrap();
+        users.enroll("cy", &reader, None).unwrap();
+        assert!(users.enroll("bob", &admin, None).is_err());
+        assert!(users.enroll("nobody", &writer, None).is_err());
+        assert!(users.enroll("bob", "not a key", None).is_err());
+
+        assert!(users.check_write(&ids(&admin), "prod/DB_URL").is_ok());
+        assert!(users.check_write(&ids(&writer), "dev/DB_URL").is_ok());
+        assert!(users.check_write(&ids(&writer), "LOG_LEVEL").is_ok());
+        assert!(users.check_write(&ids(&writer), "prod/DB_URL").is_err());
+        // A lock counts as a change to its key
+        assert!(users
+            .check_write(&ids(&writer), "@lock/prod/DB_URL")
+            .is_err());
+        assert!(users.check_write(&ids(&reader), "dev/DB_URL").is_err());
+        assert!(users.check_write(&ids("stranger"), "dev/DB_URL").is_err());
+
+        assert!(users.check_manage(&ids(&admin)).is_ok());
+        assert!(users.check_manage(&ids(&writer)).is_err());
+        assert!(users
+            .user_of(&ids(&reader))
+            .unwrap()
+            .topics()
+            .contains("dev"));
+        assert!(!users
+            .user_of(&ids(&reader))
+            .unwrap()
+            .topics()
+            .contains("prod"));
+
+        assert_eq!(users.unenroll(&writer).as_deref(), Some("bob"));
+        assert!(users.check_write(&ids(&writer), "dev/DB_URL").is_err());
+        users.remove("cy").unwrap();
+        assert!(users.remove("cy").is_err());
+    }
+
+    #[test]
+    fn test_guest_enrollment_expires() {
+        let guest = DeviceKey::generate().public_key();
+        let mut users = Users::default();
+        users.set("ada", Role::Admin, Vec::new()).unwrap();
+        users.set("zoe", Role::Writer, Vec::new()).unwrap();
+
+        let later = clock::now() + 3600;
+        assert!(users.enroll("zoe", &guest, Some(later)).unwrap());
+        assert!(!users.enroll("zoe", &guest, Some(later)).unwrap());
+        assert!(users.check_write(&ids(&guest), "KEY").is_ok());
+
+        // Re-enrolling with an expiry in the past revokes it everywhere
+        assert!(users.enroll("zoe", &guest, Some(clock::now() - 1)).unwrap());
+        assert!(users.user_of(&ids(&guest)).is_none());
+        assert!(users.check_write(&ids(&guest), "KEY").is_err());
+
+        // ...and frees the device for another user
+        assert!(users.enroll("ada", &guest, None).unwrap());
+        assert_eq!(users.user_of(&ids(&guest)).unwrap().name, "ada");
+        assert!(users.users[1].expires.is_empty());
+        assert!(users.users[1].devices.is_empty());
+    }
+}
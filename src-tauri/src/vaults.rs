@@ -0,0 +1,943 @@
+// Cloud secret managers (`[[vaults]]`): each entry maps one namespace onto
+// an Azure Key Vault or GCP Secret Manager project and pulls secrets into
+// it, pushes its variables out, or both. Providers sit behind
+// `SecretBackend`; `VaultSync` does the mapping and decides which way each
+// value goes.
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "vaults")]
+use reqwest::Method;
+
+use crate::events::MeshEvent;
+use crate::hooks;
+use crate::replication::uri_encode;
+use crate::storage;
+use crate::sync::SyncEngine;
+
+const AZURE_API_VERSION: &str = "7.4";
+const AZURE_SCOPE: &str = "https://vault.azure.net/.default";
+const GCP_API: &str = "https://secretmanager.googleapis.com/v1";
+
+/// Reuse a GCP access token this long (they last an hour)
+const GCP_TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+
+fn default_namespace() -> String {
+    storage::DEFAULT_NAMESPACE.to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+fn default_authority() -> String {
+    "https://login.microsoftonline.com".to_string()
+}
+
+fn default_client_secret_env() -> String {
+    "AZURE_CLIENT_SECRET".to_string()
+}
+
+fn default_token_command() -> String {
+    "gcloud auth print-access-token".to_string()
+}
+
+/// Which way values go between a namespace and its vault
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// The vault is the source of truth: its secrets overwrite the namespace
+    #[default]
+    Pull,
+    /// The namespace is: its variables overwrite the vault's secrets
+    Push,
+    /// Whichever side changed since the last round wins; when both did,
+    /// the vault does
+    Both,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pull => "pull",
+            Self::Push => "push",
+            Self::Both => "both",
+        }
+    }
+}
+
+/// Provider and how to reach it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum BackendConfig {
+    /// Azure Key Vault, as a service principal (client credentials)
+    Azure {
+        /// e.g. "https://team-secrets.vault.azure.net"
+        vault_url: String,
+        tenant_id: String,
+        client_id: String,
+        /// Environment variable holding the client secret
+        #[serde(default = "default_client_secret_env")]
+        client_secret_env: String,
+        /// Sign-in host; change it for national clouds
+        #[serde(default = "default_authority")]
+        authority: String,
+    },
+    /// GCP Secret Manager
+    Gcp {
+        project: String,
+        /// Prints an OAuth access token
+        #[serde(default = "default_token_command")]
+        token_command: String,
+    },
+}
+
+/// `[[vaults]]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultConfig {
+    #[serde(flatten)]
+    pub backend: BackendConfig,
+
+    /// Namespace mapped onto the vault
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+
+    /// Only secrets named with this prefix belong to the namespace (it is
+    /// dropped from variable names), so several namespaces can share a vault
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+
+    #[serde(default)]
+    pub direction: Direction,
+
+    /// Seconds between rounds
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+/// A secret manager that variables can be mirrored into
+pub trait SecretBackend: Send + Sync + 'static {
+    /// Where the secrets are, for logs and the startup banner
+    fn describe(&self) -> String;
+
+    /// The secret name for a variable name, None if the provider can't
+    /// store one under it
+    fn secret_name(&self, var: &str) -> Option<String>;
+
+    /// The variable name for a secret name (the inverse of `secret_name`)
+    fn var_name(&self, secret: &str) -> Option<String>;
+
+    /// Names of every secret
+    fn list(&self) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// A secret's current value, None if it doesn't exist
+    fn get(&self, secret: &str) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Set a secret's value, creating it if needed
+    fn put(&self, secret: &str, value: &str) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// One HTTP request; the body of a success, None for 404
+#[cfg(feature = "vaults")]
+async fn send(
+    method: reqwest::Method,
+    url: &str,
+    headers: &[(&str, String)],
+    body: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    let mut request = reqwest::Client::new()
+        .request(method, url)
+        .timeout(Duration::from_secs(30));
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    if let Some(body) = body {
+        request = request.body(body);
+    }
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let status = response.status();
+    let bytes = response.bytes().await?;
+    if !status.is_success() {
+        bail!(
+            "{} from {}: {}",
+            status,
+            url.split('?').next().unwrap_or(url),
+            String::from_utf8_lossy(&bytes).trim()
+        );
+    }
+    Ok(Some(bytes.to_vec()))
+}
+
+#[cfg(not(feature = "vaults"))]
+async fn send(
+    _method: Method,
+    _url: &str,
+    _headers: &[(&str, String)],
+    _body: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    Err(anyhow!("built without the `vaults` feature"))
+}
+
+/// Stand-in so the providers build without reqwest
+#[cfg(not(feature = "vaults"))]
+#[allow(clippy::upper_case_acronyms)]
+enum Method {
+    GET,
+    POST,
+    PUT,
+}
+
+fn json_body(value: &serde_json::Value) -> Result<Option<Vec<u8>>> {
+    Ok(Some(serde_json::to_vec(value)?))
+}
+
+fn parse(body: &[u8]) -> Result<serde_json::Value> {
+    serde_json::from_slice(body).map_err(|e| anyhow!("Unexpected response: {}", e))
+}
+
+/// Azure Key Vault. Secret names allow letters, digits and dashes, so
+/// underscores in variable names become dashes (and variables with dashes
+/// of their own are skipped).
+pub struct AzureKeyVault {
+    vault_url: String,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    /// Access token and when it stops being good
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl AzureKeyVault {
+    pub fn new(
+        vault_url: &str,
+        tenant_id: &str,
+        client_id: &str,
+        client_secret: String,
+        authority: &str,
+    ) -> Self {
+        Self {
+            vault_url: vault_url.trim_end_matches('/').to_string(),
+            token_url: format!(
+                "{}/{}/oauth2/v2.0/token",
+                authority.trim_end_matches('/'),
+                tenant_id
+            ),
+            client_id: client_id.to_string(),
+            client_secret,
+            token: Mutex::new(None),
+        }
+    }
+
+    async fn token(&self) -> Result<String> {
+        if let Some((token, until)) = &*self.token.lock().unwrap_or_else(|e| e.into_inner()) {
+            if Instant::now() < *until {
+                return Ok(token.clone());
+            }
+        }
+        let form = [
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("scope", AZURE_SCOPE),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, uri_encode(value, false)))
+        .collect::<Vec<_>>()
+        .join("&");
+        let headers = [(
+            "Content-Type",
+            "application/x-www-form-urlencoded".to_string(),
+        )];
+        let body = send(
+            Method::POST,
+            &self.token_url,
+            &headers,
+            Some(form.into_bytes()),
+        )
+        .await?
+        .ok_or_else(|| anyhow!("No such tenant: {}", self.token_url))?;
+        let response = parse(&body)?;
+        let token = response["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Azure sign-in returned no access token"))?
+            .to_string();
+        let lifetime = response["expires_in"].as_u64().unwrap_or(3600);
+        let until = Instant::now() + Duration::from_secs(lifetime.saturating_sub(300));
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some((token.clone(), until));
+        Ok(token)
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>> {
+        let mut headers = vec![("Authorization", format!("Bearer {}", self.token().await?))];
+        let body = match body {
+            Some(body) => {
+                headers.push(("Content-Type", "application/json".to_string()));
+                json_body(&body)?
+            }
+            None => None,
+        };
+        send(method, url, &headers, body)
+            .await?
+            .map(|bytes| parse(&bytes))
+            .transpose()
+    }
+
+    fn secret_url(&self, secret: &str) -> String {
+        format!(
+            "{}/secrets/{}?api-version={}",
+            self.vault_url, secret, AZURE_API_VERSION
+        )
+    }
+}
+
+impl SecretBackend for AzureKeyVault {
+    fn describe(&self) -> String {
+        self.vault_url.clone()
+    }
+
+    fn secret_name(&self, var: &str) -> Option<String> {
+        let valid = !var.is_empty()
+            && var.len() <= 127
+            && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        valid.then(|| var.replace('_', "-"))
+    }
+
+    fn var_name(&self, secret: &str) -> Option<String> {
+        Some(secret.replace('-', "_"))
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut url = format!(
+            "{}/secrets?api-version={}",
+            self.vault_url, AZURE_API_VERSION
+        );
+        loop {
+            let page = self
+                .call(Method::GET, &url, None)
+                .await?
+                .ok_or_else(|| anyhow!("No such vault: {}", self.vault_url))?;
+            for secret in page["value"].as_array().into_iter().flatten() {
+                let enabled = secret["attributes"]["enabled"].as_bool().unwrap_or(true);
+                if let (true, Some(id)) = (enabled, secret["id"].as_str()) {
+                    names.extend(id.rsplit('/').next().map(str::to_string));
+                }
+            }
+            match page["nextLink"].as_str() {
+                Some(next) if !next.is_empty() => url = next.to_string(),
+                _ => break,
+            }
+        }
+        Ok(names)
+    }
+
+    async fn get(&self, secret: &str) -> Result<Option<String>> {
+        let Some(response) = self
+            .call(Method::GET, &self.secret_url(secret), None)
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(response["value"].as_str().map(str::to_string))
+    }
+
+    async fn put(&self, secret: &str, value: &str) -> Result<()> {
+        let body = serde_json::json!({ "value": value });
+        self.call(Method::PUT, &self.secret_url(secret), Some(body))
+            .await?;
+        Ok(())
+    }
+}
+
+/// GCP Secret Manager. Secret IDs allow the same characters as variable
+/// names (plus dashes), so names map as they are.
+pub struct GcpSecretManager {
+    project: String,
+    token_command: String,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcpSecretManager {
+    pub fn new(project: &str, token_command: &str) -> Self {
+        Self {
+            project: project.to_string(),
+            token_command: token_command.to_string(),
+            token: Mutex::new(None),
+        }
+    }
+
+    async fn token(&self) -> Result<String> {
+        if let Some((token, until)) = &*self.token.lock().unwrap_or_else(|e| e.into_inner()) {
+            if Instant::now() < *until {
+                return Ok(token.clone());
+            }
+        }
+        let child = hooks::shell_command(&self.token_command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let output = tokio::time::timeout(Duration::from_secs(30), child.wait_with_output())
+            .await
+            .map_err(|_| anyhow!("{} timed out", self.token_command))??;
+        if !output.status.success() {
+            bail!(
+                "{} exited with {}: {}",
+                self.token_command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let token = String::from_utf8(output.stdout)?.trim().to_string();
+        if token.is_empty() {
+            bail!("{} printed no token", self.token_command);
+        }
+        let until = Instant::now() + GCP_TOKEN_TTL;
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some((token.clone(), until));
+        Ok(token)
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>> {
+        let url = format!("{}/projects/{}/{}", GCP_API, self.project, path);
+        let mut headers = vec![("Authorization", format!("Bearer {}", self.token().await?))];
+        let body = match body {
+            Some(body) => {
+                headers.push(("Content-Type", "application/json".to_string()));
+                json_body(&body)?
+            }
+            None => None,
+        };
+        send(method, &url, &headers, body)
+            .await?
+            .map(|bytes| parse(&bytes))
+            .transpose()
+    }
+
+    async fn add_version(&self, secret: &str, value: &str) -> Result<bool> {
+        let body = serde_json::json!({ "payload": { "data": BASE64.encode(value) } });
+        let path = format!("secrets/{}:addVersion", secret);
+        Ok(self.call(Method::POST, &path, Some(body)).await?.is_some())
+    }
+}
+
+impl SecretBackend for GcpSecretManager {
+    fn describe(&self) -> String {
+        format!("projects/{}", self.project)
+    }
+
+    fn secret_name(&self, var: &str) -> Option<String> {
+        let valid = !var.is_empty()
+            && var.len() <= 255
+            && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        valid.then(|| var.to_string())
+    }
+
+    fn var_name(&self, secret: &str) -> Option<String> {
+        // Dashes are fine in secret IDs but not in variable names
+        (!secret.contains('-')).then(|| secret.to_string())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut page_token = String::new();
+        loop {
+            let path = format!("secrets?pageSize=250&pageToken={}", page_token);
+            let page = self
+                .call(Method::GET, &path, None)
+                .await?
+                .ok_or_else(|| anyhow!("No such project: {}", self.project))?;
+            for secret in page["secrets"].as_array().into_iter().flatten() {
+                if let Some(name) = secret["name"].as_str() {
+                    names.extend(name.rsplit('/').next().map(str::to_string));
+                }
+            }
+            match page["nextPageToken"].as_str() {
+                Some(next) if !next.is_empty() => page_token = next.to_string(),
+                _ => break,
+            }
+        }
+        Ok(names)
+    }
+
+    async fn get(&self, secret: &str) -> Result<Option<String>> {
+        let path = format!("secrets/{}/versions/latest:access", secret);
+        let Some(response) = self.call(Method::GET, &path, None).await? else {
+            return Ok(None);
+        };
+        let data = response["payload"]["data"]
+            .as_str()
+            .ok_or_else(|| anyhow!("{} has no payload", secret))?;
+        let value = String::from_utf8(BASE64.decode(data)?)
+            .map_err(|_| anyhow!("{} is not UTF-8 text", secret))?;
+        Ok(Some(value))
+    }
+
+    async fn put(&self, secret: &str, value: &str) -> Result<()> {
+        if self.add_version(secret, value).await? {
+            return Ok(());
+        }
+        // New secret: create it, then give it its first version
+        let body = serde_json::json!({ "replication": { "automatic": {} } });
+        let path = format!("secrets?secretId={}", secret);
+        self.call(Method::POST, &path, Some(body)).await?;
+        if !self.add_version(secret, value).await? {
+            bail!("{} vanished while it was being created", secret);
+        }
+        Ok(())
+    }
+}
+
+/// What to do with one variable this round
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    Pull,
+    Push,
+    Skip,
+}
+
+fn digest(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+/// Which way a value goes, given both sides and the digest of what they
+/// agreed on after the last round. A side that has lost its copy is only
+/// refilled if the other side changed since, so deletes aren't undone
+/// (nor sent on: they detach the two).
+fn plan(
+    direction: Direction,
+    local: Option<&str>,
+    remote: Option<&str>,
+    agreed: Option<&str>,
+) -> Step {
+    if local == remote {
+        return Step::Skip;
+    }
+    let changed = |value: &str| agreed != Some(digest(value).as_str());
+    match (direction, local, remote) {
+        (Direction::Pull, _, Some(_)) => Step::Pull,
+        (Direction::Push, Some(_), _) => Step::Push,
+        (Direction::Both, Some(l), None) if agreed.is_none() || changed(l) => Step::Push,
+        (Direction::Both, None, Some(r)) if agreed.is_none() || changed(r) => Step::Pull,
+        (Direction::Both, Some(l), Some(r)) if changed(l) && !changed(r) => Step::Push,
+        (Direction::Both, Some(_), Some(_)) => Step::Pull,
+        _ => Step::Skip,
+    }
+}
+
+/// What one round did
+#[derive(Debug, Default, PartialEq)]
+pub struct VaultReport {
+    pub pulled: usize,
+    pub pushed: usize,
+}
+
+/// Mirrors one namespace and one vault
+pub struct VaultSync<B: SecretBackend> {
+    backend: B,
+    namespace: String,
+    prefix: String,
+    direction: Direction,
+    interval: Duration,
+    sync: SyncEngine,
+    machine_id: String,
+}
+
+impl<B: SecretBackend> VaultSync<B> {
+    pub fn new(config: &VaultConfig, backend: B, sync: SyncEngine, machine_id: String) -> Self {
+        Self {
+            backend,
+            namespace: config.namespace.clone(),
+            prefix: config.prefix.clone(),
+            direction: config.direction,
+            interval: Duration::from_secs(config.interval_secs.max(30)),
+            sync,
+            machine_id,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{}{}", self.backend.describe(), self.prefix)
+    }
+
+    pub async fn round(&self) -> Result<VaultReport> {
+        // Kept in the store, so a restart doesn't take deletes on either
+        // side for values never agreed on
+        let vault = self.describe();
+        let agreed = self
+            .sync
+            .storage
+            .read(move |s| s.vault_digests(&vault))
+            .await?;
+        let namespace = self.namespace.clone();
+        let local: HashMap<String, String> = self
+            .sync
+            .storage
+            .read(|s| s.list_all())
+            .await?
+            .into_iter()
+            .filter_map(|(key, value, ..)| {
+                let (ns, name) = storage::split_key(&key);
+                (ns == namespace).then(|| (name.to_string(), value))
+            })
+            .collect();
+
+        // Variable -> secret name, for every variable on either side
+        let mut secrets: HashMap<String, String> = HashMap::new();
+        for secret in self.backend.list().await? {
+            let var = secret
+                .strip_prefix(&self.prefix)
+                .and_then(|rest| self.backend.var_name(rest));
+            if let Some(var) = var {
+                secrets.insert(var, secret);
+            }
+        }
+        for var in local.keys() {
+            if !secrets.contains_key(var) {
+                if let Some(name) = self.backend.secret_name(var) {
+                    secrets.insert(var.clone(), format!("{}{}", self.prefix, name));
+                }
+            }
+        }
+
+        let mut report = VaultReport::default();
+        let mut agreed_now = Vec::new();
+        let vars: BTreeSet<String> = secrets.keys().cloned().collect();
+        for var in vars {
+            let secret = &secrets[&var];
+            let remote = match self.backend.get(secret).await {
+                Ok(remote) => remote,
+                Err(e) => {
+                    tracing::warn!("Skipping {} in {}: {}", secret, self.describe(), e);
+                    continue;
+                }
+            };
+            let local_value = local.get(&var).map(String::as_str);
+            let was = agreed.get(&var).map(String::as_str);
+            let value = match plan(self.direction, local_value, remote.as_deref(), was) {
+                Step::Skip if local_value == remote.as_deref() => local_value,
+                Step::Skip => continue,
+                Step::Push => {
+                    let value = local_value.unwrap_or_default();
+                    if let Err(e) = self.backend.put(secret, value).await {
+                        tracing::warn!("Failed to push {} to {}: {}", var, self.describe(), e);
+                        continue;
+                    }
+                    report.pushed += 1;
+                    local_value
+                }
+                Step::Pull => {
+                    if let Err(e) = self.pull(&var, remote.clone().unwrap_or_default()).await {
+                        tracing::warn!("Failed to pull {} from {}: {}", var, self.describe(), e);
+                        continue;
+                    }
+                    report.pulled += 1;
+                    remote.as_deref()
+                }
+            };
+            if let Some(value) = value.map(digest) {
+                if was != Some(value.as_str()) {
+                    agreed_now.push((var, value));
+                }
+            }
+        }
+        if !agreed_now.is_empty() {
+            let vault = self.describe();
+            self.sync
+                .storage
+                .write(move |s| s.set_vault_digests(&vault, &agreed_now))
+                .await?;
+        }
+
+        // Stored either way; peers that miss them get them on the next sync
+        if report.pulled > 0 {
+            if let Err(e) = self.sync.push_all().await {
+                tracing::warn!("Failed to push pulled secrets: {}", e);
+            }
+        }
+        Ok(report)
+    }
+
+    async fn pull(&self, var: &str, value: String) -> Result<()> {
+        let key = storage::namespaced_key(&self.namespace, var);
+        let (k, machine_id) = (key.clone(), self.machine_id.clone());
+        self.sync
+            .storage
+            .write(move |s| s.set(&k, &value, &machine_id))
+            .await?;
+        self.sync.events.emit(MeshEvent::VarChanged {
+            key,
+            machine_id: self.machine_id.clone(),
+            deleted: false,
+        });
+        Ok(())
+    }
+
+    /// A round every interval, forever
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            match self.round().await {
+                Ok(report) if report == VaultReport::default() => {}
+                Ok(report) => tracing::info!(
+                    "{}: pulled {}, pushed {}",
+                    self.describe(),
+                    report.pulled,
+                    report.pushed
+                ),
+                Err(e) => tracing::warn!("Vault sync with {} failed: {}", self.describe(), e),
+            }
+        }
+    }
+}
+
+/// Start a `VaultSync` for `config`, reading its credentials from the
+/// environment
+pub fn spawn(config: &VaultConfig, sync: SyncEngine, machine_id: String) -> Result<String> {
+    match &config.backend {
+        BackendConfig::Azure {
+            vault_url,
+            tenant_id,
+            client_id,
+            client_secret_env,
+            authority,
+        } => {
+            check_prefix(&config.prefix, |c| c == '-')?;
+            let client_secret = std::env::var(client_secret_env)
+                .map_err(|_| anyhow!("set {} to the client secret", client_secret_env))?;
+            let backend =
+                AzureKeyVault::new(vault_url, tenant_id, client_id, client_secret, authority);
+            let vault = VaultSync::new(config, backend, sync, machine_id);
+            let described = vault.describe();
+            tokio::spawn(vault.run());
+            Ok(described)
+        }
+        BackendConfig::Gcp {
+            project,
+            token_command,
+        } => {
+            check_prefix(&config.prefix, |c| c == '-' || c == '_')?;
+            let backend = GcpSecretManager::new(project, token_command);
+            let vault = VaultSync::new(config, backend, sync, machine_id);
+            let described = vault.describe();
+            tokio::spawn(vault.run());
+            Ok(described)
+        }
+    }
+}
+
+/// Refuse prefixes the provider can't put in a secret name
+fn check_prefix(prefix: &str, allowed: impl Fn(char) -> bool) -> Result<()> {
+    match prefix
+        .chars()
+        .find(|&c| !c.is_ascii_alphanumeric() && !allowed(c))
+    {
+        Some(c) => bail!("prefix {:?} can't contain {:?}", prefix, c),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::node::{EnvMeshNode, NodeConfig};
+    use crate::pool::StoragePool;
+    use crate::sync::SyncFilter;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// Secrets in memory, named like variables
+    #[derive(Default)]
+    struct FakeVault {
+        secrets: Arc<Mutex<HashMap<String, String>>>,
+    }
+
+    impl SecretBackend for FakeVault {
+        fn describe(&self) -> String {
+            "fake/".to_string()
+        }
+
+        fn secret_name(&self, var: &str) -> Option<String> {
+            Some(var.to_string())
+        }
+
+        fn var_name(&self, secret: &str) -> Option<String> {
+            Some(secret.to_string())
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            Ok(self.secrets.lock().unwrap().keys().cloned().collect())
+        }
+
+        async fn get(&self, secret: &str) -> Result<Option<String>> {
+            Ok(self.secrets.lock().unwrap().get(secret).cloned())
+        }
+
+        async fn put(&self, secret: &str, value: &str) -> Result<()> {
+            self.secrets
+                .lock()
+                .unwrap()
+                .insert(secret.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_mirrors_both_ways() {
+        let storage = StoragePool::open(PathBuf::from(":memory:")).unwrap();
+        let events = EventBus::new();
+        let node = EnvMeshNode::offline(NodeConfig::default(), events.clone());
+        let sync = SyncEngine::new(
+            storage.clone(),
+            Arc::new(tokio::sync::Mutex::new(node)),
+            events,
+            SyncFilter::default(),
+        );
+        let config: VaultConfig = toml::from_str(
+            r#"
+            provider = "gcp"
+            project = "acme"
+            namespace = "prod"
+            direction = "both"
+            "#,
+        )
+        .unwrap();
+        let vault = |secrets: &Arc<Mutex<HashMap<String, String>>>| {
+            let backend = FakeVault {
+                secrets: Arc::clone(secrets),
+            };
+            VaultSync::new(&config, backend, sync.clone(), "machine-a".to_string())
+        };
+        let get = |key: &'static str| storage.read(move |s| s.get(key));
+        storage
+            .write(|s| {
+                s.note_own_machine("machine-a", "key-a")?;
+                s.set("prod/OURS", "1", "machine-a")?;
+                s.set("prod/LOCKED", "1", "machine-a")
+            })
+            .await
+            .unwrap();
+        let secrets = Arc::new(Mutex::new(HashMap::from([(
+            "THEIRS".to_string(),
+            "2".to_string(),
+        )])));
+
+        let report = vault(&secrets).round().await.unwrap();
+        assert_eq!(
+            report,
+            VaultReport {
+                pulled: 1,
+                pushed: 2
+            }
+        );
+        assert_eq!(get("prod/THEIRS").await.unwrap().unwrap().0, "2");
+        assert_eq!(secrets.lock().unwrap()["OURS"], "1");
+
+        // A fresh VaultSync (a restart) still knows what was agreed: the
+        // secret deleted in the vault isn't pushed back
+        secrets.lock().unwrap().remove("OURS");
+        let report = vault(&secrets).round().await.unwrap();
+        assert_eq!(report, VaultReport::default());
+        assert!(!secrets.lock().unwrap().contains_key("OURS"));
+
+        // A value that can't be stored is skipped, not the whole round
+        storage
+            .write(|s| s.lock("prod/LOCKED", "machine-a", "key-a").map(|_| ()))
+            .await
+            .unwrap();
+        {
+            let mut secrets = secrets.lock().unwrap();
+            secrets.insert("LOCKED".to_string(), "new".to_string());
+            secrets.insert("THEIRS".to_string(), "3".to_string());
+        }
+        let report = vault(&secrets).round().await.unwrap();
+        assert_eq!(
+            report,
+            VaultReport {
+                pulled: 1,
+                pushed: 0
+            }
+        );
+        assert_eq!(get("prod/THEIRS").await.unwrap().unwrap().0, "3");
+        assert_eq!(get("prod/LOCKED").await.unwrap().unwrap().0, "1");
+    }
+
+    #[test]
+    fn test_plan_and_names() {
+        use Direction::*;
+        let agreed = digest("old");
+        let agreed = Some(agreed.as_str());
+
+        assert_eq!(plan(Pull, Some("a"), Some("b"), None), Step::Pull);
+        assert_eq!(plan(Pull, Some("a"), None, None), Step::Skip);
+        assert_eq!(plan(Push, None, Some("b"), None), Step::Skip);
+        assert_eq!(plan(Push, Some("a"), Some("b"), None), Step::Push);
+        assert_eq!(plan(Both, Some("x"), Some("x"), None), Step::Skip);
+
+        // Whichever side moved on wins, the vault when both did
+        assert_eq!(plan(Both, Some("new"), Some("old"), agreed), Step::Push);
+        assert_eq!(plan(Both, Some("old"), Some("new"), agreed), Step::Pull);
+        assert_eq!(plan(Both, Some("mine"), Some("theirs"), agreed), Step::Pull);
+        assert_eq!(plan(Both, Some("mine"), Some("theirs"), None), Step::Pull);
+
+        // Deleted on one side: left alone unless the other side changed
+        assert_eq!(plan(Both, None, Some("old"), agreed), Step::Skip);
+        assert_eq!(plan(Both, None, Some("new"), agreed), Step::Pull);
+        assert_eq!(plan(Both, Some("old"), None, agreed), Step::Skip);
+        assert_eq!(plan(Both, Some("new"), None, None), Step::Push);
+
+        let azure = AzureKeyVault::new("https://v.vault.azure.net/", "t", "c", String::new(), "");
+        assert_eq!(azure.secret_name("DB_URL").as_deref(), Some("DB-URL"));
+        assert_eq!(azure.var_name("DB-URL").as_deref(), Some("DB_URL"));
+        assert_eq!(azure.secret_name("db.url"), None);
+        assert_eq!(
+            azure.secret_url("DB-URL"),
+            "https://v.vault.azure.net/secrets/DB-URL?api-version=7.4"
+        );
+
+        let gcp = GcpSecretManager::new("p", "true");
+        assert_eq!(gcp.secret_name("DB_URL").as_deref(), Some("DB_URL"));
+        assert_eq!(gcp.var_name("db-url"), None);
+
+        assert!(check_prefix("envmesh-prod-", |c| c == '-').is_ok());
+        assert!(check_prefix("envmesh/prod", |c| c == '-').is_err());
+
+        let config: VaultConfig = toml::from_str(
+            r#"
+            provider = "gcp"
+            project = "acme"
+            namespace = "prod"
+            direction = "both"
+            "#,
+        )
+        .unwrap();
+        assert!(
+            matches!(config.backend, BackendConfig::Gcp { ref token_command, .. }
+            if token_command == "gcloud auth print-access-token")
+        );
+        assert_eq!(config.direction, Both);
+    }
+}
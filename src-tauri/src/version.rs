@@ -0,0 +1,146 @@
+// Which build each peer runs. Servers name theirs in the `hello` frame and
+// broadcast discovery answers, clients in a handshake header, so mismatched
+// peers show up in `envmesh-cli peers --versions` and `status`.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Handshake header with the client's build, as `VERSION/PROTOCOL`
+pub const VERSION_HEADER: &str = "x-envmesh-version";
+
+/// Version of what peers send each other; bump it when a change means
+/// older peers can no longer sync with this build
+pub const MESH_PROTOCOL: u32 = 1;
+
+/// Oldest mesh protocol this build still syncs with
+pub const MIN_MESH_PROTOCOL: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerVersion {
+    /// Crate version (`0.1.0`)
+    pub version: String,
+    pub protocol: u32,
+}
+
+/// How a peer's build compares to ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    Compatible,
+    /// An older minor or major release: it syncs, but lacks what came since
+    Outdated,
+    /// Speaks a newer protocol than ours, which it may no longer accept
+    Ahead,
+    /// Speaks a protocol this build dropped
+    Incompatible,
+}
+
+impl PeerVersion {
+    /// This build
+    pub fn ours() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: MESH_PROTOCOL,
+        }
+    }
+
+    pub fn header_value(&self) -> String {
+        format!("{}/{}", self.version, self.protocol)
+    }
+
+    /// Read a `VERSION_HEADER` value (None if malformed)
+    pub fn parse_header(value: &str) -> Option<Self> {
+        let (version, protocol) = value.trim().split_once('/')?;
+        if version.is_empty() {
+            return None;
+        }
+        Some(Self {
+            version: version.to_string(),
+            protocol: protocol.parse().ok()?,
+        })
+    }
+
+    pub fn compatibility(&self) -> Compatibility {
+        if self.protocol < MIN_MESH_PROTOCOL {
+            Compatibility::Incompatible
+        } else if self.protocol > MESH_PROTOCOL {
+            Compatibility::Ahead
+        } else if release(&self.version) < release(env!("CARGO_PKG_VERSION")) {
+            Compatibility::Outdated
+        } else {
+            Compatibility::Compatible
+        }
+    }
+
+    /// What to warn about this peer, if anything
+    pub fn warning(&self) -> Option<String> {
+        match self.compatibility() {
+            Compatibility::Compatible => None,
+            Compatibility::Outdated => Some(format!(
+                "runs envmesh {}, older than this machine's {}",
+                self.version,
+                env!("CARGO_PKG_VERSION")
+            )),
+            Compatibility::Ahead => Some(format!(
+                "runs envmesh {} (protocol v{}), newer than this machine's protocol v{}: upgrade here",
+                self.version, self.protocol, MESH_PROTOCOL
+            )),
+            Compatibility::Incompatible => Some(format!(
+                "runs envmesh {} (protocol v{}), too old to sync with (needs v{}+)",
+                self.version, self.protocol, MIN_MESH_PROTOCOL
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PeerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (protocol v{})", self.version, self.protocol)
+    }
+}
+
+/// Major and minor of a `MAJOR.MINOR.PATCH` version; patch releases don't
+/// count as older
+fn release(version: &str) -> (u64, u64) {
+    let mut parts = version
+        .split(['.', '-', '+'])
+        .map(|part| part.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_versions_are_compared() {
+        let ours = PeerVersion::ours();
+        assert_eq!(
+            PeerVersion::parse_header(&ours.header_value()),
+            Some(ours.clone())
+        );
+        assert_eq!(ours.compatibility(), Compatibility::Compatible);
+        assert!(ours.warning().is_none());
+        assert_eq!(PeerVersion::parse_header("0.1.0"), None);
+
+        let peer = |version: &str, protocol| PeerVersion {
+            version: version.to_string(),
+            protocol,
+        };
+        assert_eq!(
+            peer("99.0.0", MESH_PROTOCOL).compatibility(),
+            Compatibility::Compatible
+        );
+        assert_eq!(
+            peer("0.0.9", MESH_PROTOCOL).compatibility(),
+            Compatibility::Outdated
+        );
+        assert_eq!(
+            peer("99.0.0", MESH_PROTOCOL + 1).compatibility(),
+            Compatibility::Ahead
+        );
+        assert_eq!(
+            peer("0.0.1", MIN_MESH_PROTOCOL - 1).compatibility(),
+            Compatibility::Incompatible
+        );
+        assert_eq!(release("1.12.3-beta.1"), (1, 12));
+    }
+}
@@ -0,0 +1,253 @@
+// Outbound webhooks: signed JSON POSTs for variable changes and conflicts
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::crypto::hmac_sha256;
+use crate::events::{EventBus, MeshEvent};
+use crate::storage;
+
+/// Header carrying `sha256=<hex HMAC of the body>`
+pub const SIGNATURE_HEADER: &str = "X-EnvMesh-Signature";
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_events() -> Vec<WebhookEventKind> {
+    vec![
+        WebhookEventKind::Set,
+        WebhookEventKind::Delete,
+        WebhookEventKind::Conflict,
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    Set,
+    Delete,
+    Conflict,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// HMAC-SHA256 key for the signature header (unsigned if absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+
+    /// Only fire for keys in these namespaces (empty = all)
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+
+    #[serde(default = "default_events")]
+    pub events: Vec<WebhookEventKind>,
+
+    /// Delivery attempts after the first failure, with exponential backoff
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+/// Body of a webhook POST. Values are never included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub event: WebhookEventKind,
+    pub key: String,
+    pub namespace: String,
+    pub machine_id: String,
+    pub timestamp: i64,
+}
+
+impl WebhookPayload {
+    fn from_event(event: &MeshEvent) -> Option<Self> {
+        let (kind, key, machine_id) = match event {
+            MeshEvent::VarChanged {
+                key,
+                machine_id,
+                deleted,
+            } => {
+                let kind = if *deleted {
+                    WebhookEventKind::Delete
+                } else {
+                    WebhookEventKind::Set
+                };
+                (kind, key, machine_id)
+            }
+            MeshEvent::ConflictDetected {
+                key,
+                remote_machine,
+                ..
+            } => (WebhookEventKind::Conflict, key, remote_machine),
+            _ => return None,
+        };
+
+        Some(Self {
+            event: kind,
+            key: key.clone(),
+            namespace: storage::split_key(key).0.to_string(),
+            machine_id: machine_id.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+        })
+    }
+}
+
+impl WebhookConfig {
+    fn wants(&self, payload: &WebhookPayload) -> bool {
+        self.events.contains(&payload.event)
+            && (self.namespaces.is_empty() || self.namespaces.contains(&payload.namespace))
+    }
+}
+
+/// Signature header value for a body: `sha256=<hex>`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    format!(
+        "sha256={}",
+        hex::encode(hmac_sha256(secret.as_bytes(), body))
+    )
+}
+
+/// Delay before retry number `attempt` (1-based): 1s, 2s, 4s, ... capped at 60s
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << (attempt - 1).min(6)).min(MAX_BACKOFF)
+}
+
+pub struct WebhookDispatcher {
+    webhooks: Vec<Arc<WebhookConfig>>,
+}
+
+impl WebhookDispatcher {
+    pub fn new(webhooks: Vec<WebhookConfig>) -> Self {
+        Self {
+            webhooks: webhooks.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Deliver matching events until the event bus closes
+    pub async fn run(self, events: EventBus) {
+        let mut rx = events.subscribe();
+
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Webhook dispatcher missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let Some(payload) = WebhookPayload::from_event(&event) else {
+                continue;
+            };
+
+            for webhook in self.webhooks.iter().filter(|w| w.wants(&payload)) {
+                let webhook = Arc::clone(webhook);
+                let payload = payload.clone();
+                tokio::spawn(async move { deliver_with_retry(&webhook, &payload).await });
+            }
+        }
+    }
+}
+
+async fn deliver_with_retry(webhook: &WebhookConfig, payload: &WebhookPayload) {
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to encode webhook payload: {}", e);
+            return;
+        }
+    };
+    let signature = webhook.secret.as_deref().map(|secret| sign(secret, &body));
+
+    let mut attempt = 0;
+    loop {
+        match post(&webhook.url, &body, signature.as_deref()).await {
+            Ok(()) => {
+                tracing::debug!("Webhook {} delivered for {}", webhook.url, payload.key);
+                return;
+            }
+            Err(e) if attempt < webhook.max_retries => {
+                attempt += 1;
+                let delay = backoff(attempt);
+                tracing::warn!(
+                    "Webhook {} failed ({}), retry {}/{} in {:?}",
+                    webhook.url,
+                    e,
+                    attempt,
+                    webhook.max_retries,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Webhook {} gave up after {} attempts: {}",
+                    webhook.url,
+                    attempt + 1,
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "webhooks")]
+async fn post(url: &str, body: &[u8], signature: Option<&str>) -> Result<()> {
+    let mut request = reqwest::Client::new()
+        .post(url)
+        .timeout(Duration::from_secs(10))
+        .header("Content-Type", "application/json")
+        .body(body.to_vec());
+    if let Some(signature) = signature {
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webhooks"))]
+async fn post(url: &str, _body: &[u8], _signature: Option<&str>) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "cannot deliver to {}: built without the `webhooks` feature",
+        url
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_and_event_filtering() {
+        let webhook = WebhookConfig {
+            url: "https://hooks.example.com".to_string(),
+            secret: None,
+            namespaces: vec!["prod".to_string()],
+            events: vec![WebhookEventKind::Set],
+            max_retries: 0,
+        };
+
+        let set = |key: &str, deleted| {
+            WebhookPayload::from_event(&MeshEvent::VarChanged {
+                key: key.to_string(),
+                machine_id: "machine-a".to_string(),
+                deleted,
+            })
+            .unwrap()
+        };
+
+        assert!(webhook.wants(&set("prod/DB_URL", false)));
+        assert!(!webhook.wants(&set("prod/DB_URL", true)));
+        assert!(!webhook.wants(&set("DB_URL", false)));
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(20), MAX_BACKOFF);
+    }
+}
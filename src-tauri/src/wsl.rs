@@ -0,0 +1,112 @@
+// WSL detection and Windows <-> WSL path/host translation
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub fn is_wsl() -> bool {
+    std::fs::read_to_string("/proc/version")
+        .map(|s| s.to_lowercase().contains("microsoft") || s.to_lowercase().contains("wsl"))
+        .unwrap_or(false)
+}
+
+/// Address of the Windows host as seen from inside WSL2: the nameserver WSL
+/// writes into /etc/resolv.conf. With mirrored networking (or WSL1) Windows
+/// services are reachable on localhost, which is the fallback.
+pub fn windows_host() -> IpAddr {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|contents| nameserver(&contents))
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]))
+}
+
+fn nameserver(resolv_conf: &str) -> Option<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+}
+
+/// `C:\Users\me` -> `/mnt/c/Users/me`; None if not a drive-letter path
+pub fn to_wsl_path(windows_path: &str) -> Option<PathBuf> {
+    let mut chars = windows_path.chars();
+    let drive = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+
+    let rest = chars.as_str().replace('\\', "/");
+    Some(PathBuf::from(format!(
+        "/mnt/{}/{}",
+        drive.to_ascii_lowercase(),
+        rest.trim_start_matches('/')
+    )))
+}
+
+/// `/mnt/c/Users/me` -> `C:\Users\me`; None if not under a mounted drive
+pub fn to_windows_path(wsl_path: &Path) -> Option<String> {
+    let rest = wsl_path.to_str()?.strip_prefix("/mnt/")?;
+    let (drive, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    if drive.len() != 1 || !drive.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    Some(format!(
+        "{}:\\{}",
+        drive.to_ascii_uppercase(),
+        rest.replace('/', "\\")
+    ))
+}
+
+/// Accept a path in either notation: inside WSL a Windows path is mapped to
+/// its /mnt mount, everywhere else the path is used as given
+pub fn translate_path(path: &str) -> PathBuf {
+    if is_wsl() {
+        if let Some(translated) = to_wsl_path(path) {
+            return translated;
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// The Windows user's profile directory, reachable from inside WSL
+pub fn windows_profile_dir() -> Option<PathBuf> {
+    if !is_wsl() {
+        return None;
+    }
+
+    // Shared via WSLENV, or ask Windows directly
+    let profile = std::env::var("USERPROFILE").ok().or_else(|| {
+        let output = std::process::Command::new("cmd.exe")
+            .args(["/C", "echo %USERPROFILE%"])
+            .output()
+            .ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    })?;
+
+    to_wsl_path(&profile).or_else(|| Some(PathBuf::from(profile)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_translation() {
+        assert_eq!(
+            to_wsl_path(r"C:\Users\me\.envmesh"),
+            Some(PathBuf::from("/mnt/c/Users/me/.envmesh"))
+        );
+        assert_eq!(to_wsl_path("/home/me"), None);
+        assert_eq!(
+            to_windows_path(Path::new("/mnt/d/data/envmesh")),
+            Some(r"D:\data\envmesh".to_string())
+        );
+        assert_eq!(to_windows_path(Path::new("/home/me")), None);
+    }
+
+    #[test]
+    fn test_nameserver_from_resolv_conf() {
+        let conf = "# generated by WSL\nnameserver 172.29.160.1\nsearch lan\n";
+        assert_eq!(nameserver(conf), Some(IpAddr::from([172, 29, 160, 1])));
+        assert_eq!(nameserver("search lan\n"), None);
+    }
+}
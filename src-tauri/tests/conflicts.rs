@@ -0,0 +1,162 @@
+// Property tests for last-write-wins conflict resolution: random interleavings
+// of sets and deletes across skewed machines must always converge. Seeds are
+// fixed so a failure reproduces on every run.
+use envmesh::testkit::{LinkConfig, SimMesh, SimState};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, RngSeed};
+
+const KEYS: &[&str] = &["API_KEY", "DB_URL", "TOKEN", "REGION"];
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set {
+        node: usize,
+        key: usize,
+        value: u8,
+    },
+    Delete {
+        node: usize,
+        key: usize,
+    },
+    /// Cut `node` off from everyone until the next heal
+    Isolate {
+        node: usize,
+    },
+    Heal,
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    op: Op,
+    /// Simulated time before the next step
+    wait_ms: u64,
+}
+
+fn config() -> Config {
+    Config {
+        cases: 32,
+        rng_seed: RngSeed::Fixed(0x5eed),
+        failure_persistence: None,
+        ..Config::default()
+    }
+}
+
+fn op(nodes: usize, partitions: bool) -> impl Strategy<Value = Op> {
+    let key = 0..KEYS.len();
+    let write = prop_oneof![
+        4 => (0..nodes, key.clone(), any::<u8>())
+            .prop_map(|(node, key, value)| Op::Set { node, key, value }),
+        2 => (0..nodes, key).prop_map(|(node, key)| Op::Delete { node, key }),
+    ];
+    if partitions {
+        prop_oneof![
+            6 => write,
+            1 => (0..nodes).prop_map(|node| Op::Isolate { node }),
+            1 => Just(Op::Heal),
+        ]
+        .boxed()
+    } else {
+        write.boxed()
+    }
+}
+
+fn steps(
+    nodes: usize,
+    partitions: bool,
+    wait_ms: std::ops::Range<u64>,
+) -> impl Strategy<Value = Vec<Step>> {
+    prop::collection::vec(
+        (op(nodes, partitions), wait_ms).prop_map(|(op, wait_ms)| Step { op, wait_ms }),
+        1..40,
+    )
+}
+
+fn run<T>(future: impl std::future::Future<Output = T>) -> T {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Play `steps` on `mesh`, then heal and fully sync over a lossless link
+async fn play(mesh: &mut SimMesh, steps: &[Step]) -> anyhow::Result<()> {
+    for step in steps {
+        match step.op {
+            Op::Set { node, key, value } => {
+                mesh.set(node, KEYS[key], &format!("v{}", value)).await?
+            }
+            Op::Delete { node, key } => mesh.delete(node, KEYS[key]).await?,
+            Op::Isolate { node } => {
+                let others: Vec<usize> = (0..mesh.len()).filter(|&i| i != node).collect();
+                mesh.partition(&[&others, &[node]]);
+            }
+            Op::Heal => mesh.heal(),
+        }
+        mesh.advance(step.wait_ms).await?;
+    }
+    mesh.set_link(LinkConfig::default());
+    mesh.heal_and_sync().await
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    /// Whatever the order, clocks and partitions, every node ends up with
+    /// the same variables
+    #[test]
+    fn converges_under_any_interleaving(
+        (skews, steps, seed, drop_rate) in (2usize..=4).prop_flat_map(|nodes| (
+            prop::collection::vec(-90i64..=90, nodes),
+            steps(nodes, true, 0..3_000),
+            any::<u64>(),
+            prop_oneof![Just(0.0), Just(0.2)],
+        ))
+    ) {
+        run(async {
+            let mut mesh = SimMesh::new(skews.len(), seed).await.unwrap();
+            mesh.set_link(LinkConfig { latency_ms: (1, 1_500), drop_rate });
+            for (node, skew) in skews.iter().enumerate() {
+                mesh.set_clock_skew(node, *skew);
+            }
+            play(&mut mesh, &steps).await.unwrap();
+            if let Err(e) = mesh.converged().await {
+                panic!("{}", e);
+            }
+        });
+    }
+
+    /// With synchronised clocks and writes a second or more apart, the last
+    /// write wins everywhere
+    #[test]
+    fn last_write_wins_without_skew(
+        (nodes, steps, seed) in (2usize..=4).prop_flat_map(|nodes| (
+            Just(nodes),
+            steps(nodes, false, 1_000..3_000),
+            any::<u64>(),
+        ))
+    ) {
+        let mut expected = SimState::new();
+        for step in &steps {
+            match step.op {
+                Op::Set { key, value, .. } => {
+                    expected.insert(KEYS[key].to_string(), Some(format!("v{}", value)));
+                }
+                Op::Delete { key, .. } => {
+                    // Deleting a variable nobody has is a no-op
+                    if let Some(value) = expected.get_mut(KEYS[key]) {
+                        *value = None;
+                    }
+                }
+                Op::Isolate { .. } | Op::Heal => {}
+            }
+        }
+
+        let state = run(async {
+            let mut mesh = SimMesh::new(nodes, seed).await.unwrap();
+            play(&mut mesh, &steps).await.unwrap();
+            mesh.converged().await.unwrap();
+            mesh.state(0).await.unwrap()
+        });
+        prop_assert_eq!(state, expected);
+    }
+}
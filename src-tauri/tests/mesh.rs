@@ -0,0 +1,66 @@
+// End-to-end sync across simulated meshes (see `envmesh::testkit`)
+use envmesh::testkit::{LinkConfig, SimMesh};
+
+#[tokio::test]
+async fn test_converges_after_partition_heals() {
+    let mut mesh = SimMesh::new(5, 42).await.unwrap();
+    mesh.set_link(LinkConfig {
+        latency_ms: (10, 400),
+        drop_rate: 0.0,
+    });
+
+    mesh.set(0, "SHARED", "before").await.unwrap();
+    mesh.run_until_idle().await.unwrap();
+    mesh.converged().await.unwrap();
+
+    // Both sides keep writing, including to the same key
+    mesh.partition(&[&[0, 1], &[2, 3, 4]]);
+    mesh.advance(1_000).await.unwrap();
+    mesh.set(1, "SHARED", "left").await.unwrap();
+    mesh.set(0, "LEFT_ONLY", "1").await.unwrap();
+    mesh.advance(2_000).await.unwrap();
+    mesh.set(3, "SHARED", "right").await.unwrap();
+    mesh.delete(4, "LEFT_ONLY").await.unwrap();
+    mesh.set(2, "RIGHT_ONLY", "1").await.unwrap();
+    mesh.run_until_idle().await.unwrap();
+    assert!(mesh.converged().await.is_err());
+
+    mesh.advance(1_000).await.unwrap();
+    mesh.heal_and_sync().await.unwrap();
+    mesh.converged().await.unwrap();
+
+    // The later write wins on every node
+    let state = mesh.state(0).await.unwrap();
+    assert_eq!(state["SHARED"], Some("right".to_string()));
+    assert_eq!(state["LEFT_ONLY"], Some("1".to_string()));
+    assert_eq!(state["RIGHT_ONLY"], Some("1".to_string()));
+}
+
+#[tokio::test]
+async fn test_converges_despite_drops_and_skew() {
+    let mut mesh = SimMesh::new(4, 7).await.unwrap();
+    mesh.set_link(LinkConfig {
+        latency_ms: (1, 2_000),
+        drop_rate: 0.3,
+    });
+    mesh.set_clock_skew(2, 30);
+    mesh.set_clock_skew(3, -30);
+
+    for round in 0..50 {
+        let node = round % mesh.len();
+        let key = format!("KEY_{}", round % 7);
+        if round % 5 == 4 {
+            mesh.delete(node, &key).await.unwrap();
+        } else {
+            mesh.set(node, &key, &format!("v{}", round)).await.unwrap();
+        }
+        mesh.advance(300).await.unwrap();
+    }
+    mesh.run_until_idle().await.unwrap();
+    assert!(mesh.stats().dropped > 0);
+
+    // Drops are only repaired by full syncs, so keep going until they land
+    mesh.set_link(LinkConfig::default());
+    mesh.heal_and_sync().await.unwrap();
+    mesh.converged().await.unwrap();
+}
@@ -0,0 +1,71 @@
+// Variable values never reach the logs, whatever is logged about them
+use envmesh::client::{SyncMessage, WireMessage};
+use envmesh::testkit::SimMesh;
+use envmesh::wire;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+
+const SECRET: &str = "s3cr3t-p4ssw0rd";
+
+/// Everything logged, at every level
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_set_and_sync_logs_never_show_values() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_writer(move || writer.clone())
+        .init();
+
+    // Sets, a conflict and a delete, synced across a mesh
+    let mut mesh = SimMesh::new(3, 1).await.unwrap();
+    mesh.set(0, "prod/DB_PASSWORD", SECRET).await.unwrap();
+    mesh.run_until_idle().await.unwrap();
+    mesh.set(1, "prod/DB_PASSWORD", &format!("{}-old", SECRET))
+        .await
+        .unwrap();
+    mesh.set(2, "prod/DB_PASSWORD", &format!("{}-new", SECRET))
+        .await
+        .unwrap();
+    mesh.run_until_idle().await.unwrap();
+    mesh.delete(0, "prod/DB_PASSWORD").await.unwrap();
+    mesh.full_sync(1).await.unwrap();
+    mesh.run_until_idle().await.unwrap();
+
+    // A frame that doesn't parse, quoting the value where a number belongs
+    let frame = format!(
+        r#"{{"type":"sync","key":"K","value":"v","timestamp":"{}","machine_id":"m","deleted":false}}"#,
+        SECRET
+    );
+    let e = wire::decode(&Message::Text(frame)).unwrap().unwrap_err();
+    tracing::warn!("Ignoring malformed message: {}", e);
+
+    let msg = SyncMessage::new(
+        "prod/DB_PASSWORD".to_string(),
+        SECRET.to_string(),
+        0,
+        "m".to_string(),
+        false,
+    );
+    tracing::debug!("{:?}", WireMessage::Sync(msg));
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("prod/DB_PASSWORD"), "nothing was logged");
+    assert!(logs.contains("<redacted"));
+    assert!(!logs.contains(SECRET), "{}", logs);
+}
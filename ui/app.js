@@ -1,8 +1,9 @@
 const { invoke } = window.__TAURI__.core;
+const { listen } = window.__TAURI__.event;
 
 async function loadEnvVars() {
     try {
-        const vars = await invoke('list_env_vars');
+        const { vars } = await invoke('list_env_vars');
         const list = document.getElementById('env-list');
 
         if (vars.length === 0) {
@@ -26,7 +27,9 @@ async function loadPeers() {
             return;
         }
 
-        list.innerHTML = peers.map(p => '<div class="peer-item"><span class="peer-id">' + p.id + '</span><span>' + p.address + '</span></div>').join('');
+        const sync = p => p.behind === null ? '' : (p.behind === 0 ? ', in sync' : ', behind by ' + p.behind);
+        const link = p => (p.rtt_ms === null ? '-' : p.rtt_ms + ' ms') + ', ' + p.loss_percent.toFixed(0) + '% loss' + sync(p);
+        list.innerHTML = peers.map(p => '<div class="peer-item"><span class="peer-id">' + p.kind + (p.id ? ' ' + p.id : '') + '</span><span>' + p.address + '</span><span>' + link(p) + '</span></div>').join('');
     } catch (error) {
         console.error('Failed to load peers:', error);
     }
@@ -70,8 +73,41 @@ async function triggerSync() {
     }
 }
 
+async function resyncFromPeer() {
+    const from = prompt('Machine ID or device key of the peer to pull everything from:');
+    if (!from || !from.trim()) {
+        return;
+    }
+    const overwriteLocal = confirm('Also discard this machine\'s variables so the peer\'s copy replaces them?\n\nChanges only made here will be lost.');
+
+    try {
+        await invoke('resync_from', { from: from.trim(), overwriteLocal });
+        await loadEnvVars();
+    } catch (error) {
+        alert('Failed to resync: ' + error);
+    }
+}
+
 document.getElementById('add-btn').addEventListener('click', addEnvVar);
 document.getElementById('sync-btn').addEventListener('click', triggerSync);
+document.getElementById('resync-btn').addEventListener('click', resyncFromPeer);
+
+// Refresh live as the backend reports connection and sync changes
+listen('mesh-event', (event) => {
+    const payload = event.payload;
+    switch (payload.event) {
+        case 'sync_completed':
+        case 'conflict_detected':
+            loadEnvVars();
+            break;
+        case 'mode_changed':
+        case 'peer_connected':
+        case 'peer_resumed':
+        case 'peer_lost':
+            loadPeers();
+            break;
+    }
+});
 
 loadEnvVars();
 loadPeers();